@@ -0,0 +1,57 @@
+//! Build script for Sockrats
+//!
+//! Records the locked versions of key dependencies so `sockrats --version
+//! --verbose` can report them. Versions are read from `Cargo.lock` and fall
+//! back to `unknown` when no lock file is found next to the manifest (e.g.
+//! when built as a dependency of another workspace).
+
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Dependencies whose versions are reported by `build_info()`
+const KEY_DEPS: &[&str] = &["tokio", "snowstorm", "russh", "boringtun", "rfb-encodings"];
+
+fn main() {
+    let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap_or_default();
+    let lock_path = Path::new(&manifest_dir).join("Cargo.lock");
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed={}", lock_path.display());
+
+    let versions = std::fs::read_to_string(&lock_path)
+        .map(|lock| locked_versions(&lock))
+        .unwrap_or_default();
+
+    for dep in KEY_DEPS {
+        let version = versions.get(*dep).map(String::as_str).unwrap_or("unknown");
+        println!(
+            "cargo:rustc-env=SOCKRATS_DEP_{}={}",
+            dep.to_uppercase().replace('-', "_"),
+            version
+        );
+    }
+}
+
+/// Map package names to versions from the contents of a `Cargo.lock`
+///
+/// When a package is locked at several versions, the first one wins.
+fn locked_versions(lock: &str) -> HashMap<String, String> {
+    let mut versions = HashMap::new();
+    let mut name: Option<String> = None;
+
+    for line in lock.lines() {
+        let line = line.trim();
+        if line == "[[package]]" {
+            name = None;
+        } else if let Some(value) = line.strip_prefix("name = ") {
+            name = Some(value.trim_matches('"').to_string());
+        } else if let Some(value) = line.strip_prefix("version = ") {
+            if let Some(name) = name.take() {
+                versions
+                    .entry(name)
+                    .or_insert_with(|| value.trim_matches('"').to_string());
+            }
+        }
+    }
+
+    versions
+}
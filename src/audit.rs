@@ -0,0 +1,353 @@
+//! Audit logging
+//!
+//! Audit events (authentication results, connection lifecycle, commands)
+//! go to a dedicated append-only sink, separate from the operational
+//! `tracing` logs. The client installs a [`JsonlAuditLogger`] when
+//! `[client.audit]` is configured; without one, [`record`] is a no-op.
+
+use crate::services::ConnectionSummary;
+use anyhow::{Context, Result};
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+lazy_static! {
+    static ref GLOBAL_AUDIT_LOGGER: RwLock<Option<Arc<dyn AuditLogger>>> = RwLock::new(None);
+}
+
+/// An auditable event
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    /// A client authenticated
+    AuthSuccess {
+        /// Protocol authenticated against (e.g. `socks5`, `ssh`)
+        protocol: String,
+        /// Authentication method (e.g. `password`, `publickey`)
+        method: String,
+        /// Username presented by the client
+        username: String,
+    },
+    /// A client failed to authenticate
+    AuthFailure {
+        /// Protocol authenticated against (e.g. `socks5`, `ssh`)
+        protocol: String,
+        /// Authentication method (e.g. `password`, `publickey`)
+        method: String,
+        /// Username presented by the client
+        username: String,
+    },
+    /// A data channel started forwarding to a service
+    ConnectionOpen {
+        /// Service name
+        service: String,
+    },
+    /// A data channel finished forwarding
+    ConnectionClose {
+        /// Service name
+        service: String,
+        /// Target reported by the service, if any
+        #[serde(skip_serializing_if = "Option::is_none")]
+        target: Option<String>,
+        /// Bytes relayed from the client towards the target
+        bytes_up: u64,
+        /// Bytes relayed from the target back to the client
+        bytes_down: u64,
+        /// Connection duration in milliseconds
+        duration_ms: u64,
+        /// Why the connection closed
+        close_reason: String,
+    },
+    /// A client opened a channel within a session
+    ChannelOpen {
+        /// Protocol the channel belongs to (e.g. `ssh`)
+        protocol: String,
+        /// Channel number within the session
+        channel: u32,
+    },
+    /// A client requested a subsystem (e.g. `sftp`)
+    Subsystem {
+        /// Protocol the subsystem was requested over (e.g. `ssh`)
+        protocol: String,
+        /// Subsystem name
+        name: String,
+        /// Whether the subsystem was started
+        accepted: bool,
+    },
+    /// An interactive session ended
+    SessionClose {
+        /// Protocol of the session (e.g. `ssh`)
+        protocol: String,
+        /// Authenticated username, if the client got that far
+        #[serde(skip_serializing_if = "Option::is_none")]
+        username: Option<String>,
+        /// Session duration in milliseconds
+        duration_ms: u64,
+    },
+    /// A client issued a command
+    Command {
+        /// Protocol the command was issued over (e.g. `socks5`, `ssh`)
+        protocol: String,
+        /// The command (e.g. `CONNECT`, or the SSH exec command line)
+        command: String,
+        /// Command target, if any
+        #[serde(skip_serializing_if = "Option::is_none")]
+        target: Option<String>,
+    },
+}
+
+impl AuditEvent {
+    /// Build a [`AuditEvent::ConnectionClose`] for `service`
+    ///
+    /// Without a summary (the handler failed or reported none), the byte
+    /// counts and duration are zero and `error` becomes the close reason.
+    pub fn connection_close(
+        service: &str,
+        summary: Option<&ConnectionSummary>,
+        error: Option<&anyhow::Error>,
+    ) -> Self {
+        match summary {
+            Some(summary) => AuditEvent::ConnectionClose {
+                service: service.to_string(),
+                target: summary.target.clone(),
+                bytes_up: summary.bytes_up,
+                bytes_down: summary.bytes_down,
+                duration_ms: summary.duration.as_millis() as u64,
+                close_reason: summary.close_reason.to_string(),
+            },
+            None => AuditEvent::ConnectionClose {
+                service: service.to_string(),
+                target: None,
+                bytes_up: 0,
+                bytes_down: 0,
+                duration_ms: 0,
+                close_reason: match error {
+                    Some(e) => format!("error: {:#}", e),
+                    None => "completed".to_string(),
+                },
+            },
+        }
+    }
+}
+
+/// A timestamped audit event, as written to the audit log
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AuditRecord {
+    /// Milliseconds since the Unix epoch
+    pub timestamp_ms: u64,
+    /// Session the event belongs to, for protocols that have sessions
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<String>,
+    /// The event
+    #[serde(flatten)]
+    pub event: AuditEvent,
+}
+
+impl AuditRecord {
+    /// Timestamp `event` with the current time
+    pub fn now(event: AuditEvent) -> Self {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        AuditRecord {
+            timestamp_ms,
+            session_id: None,
+            event,
+        }
+    }
+
+    /// Attribute the record to session `id`
+    pub fn with_session(mut self, id: &str) -> Self {
+        self.session_id = Some(id.to_string());
+        self
+    }
+}
+
+/// A sink for audit records
+pub trait AuditLogger: Send + Sync + fmt::Debug {
+    /// Write one record; failures are the logger's to report
+    fn log(&self, record: &AuditRecord);
+}
+
+/// Audit logger appending one JSON object per line to a file
+#[derive(Debug)]
+pub struct JsonlAuditLogger {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl JsonlAuditLogger {
+    /// Open `path` for appending, creating it if needed
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open audit log {:?}", path))?;
+        Ok(JsonlAuditLogger {
+            path: path.to_path_buf(),
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl AuditLogger for JsonlAuditLogger {
+    fn log(&self, record: &AuditRecord) {
+        let mut line = match serde_json::to_string(record) {
+            Ok(line) => line,
+            Err(e) => {
+                tracing::warn!("Failed to serialize audit record: {}", e);
+                return;
+            }
+        };
+        line.push('\n');
+
+        // One write per record keeps lines whole under O_APPEND
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = file.write_all(line.as_bytes()).and_then(|_| file.flush()) {
+            tracing::warn!("Failed to write audit log {:?}: {}", self.path, e);
+        }
+    }
+}
+
+/// Install the process-wide audit logger, or remove it with `None`
+pub fn set_logger(logger: Option<Arc<dyn AuditLogger>>) {
+    *GLOBAL_AUDIT_LOGGER
+        .write()
+        .unwrap_or_else(|e| e.into_inner()) = logger;
+}
+
+/// Record an audit event with the installed logger, if any
+pub fn record(event: AuditEvent) {
+    log(&AuditRecord::now(event));
+}
+
+/// Write an already timestamped record with the installed logger, if any
+pub fn log(record: &AuditRecord) {
+    let logger = GLOBAL_AUDIT_LOGGER
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone();
+    if let Some(logger) = logger {
+        logger.log(record);
+    }
+}
+
+/// Record an authentication attempt
+pub fn record_auth(protocol: &str, method: &str, username: &str, success: bool) {
+    let protocol = protocol.to_string();
+    let method = method.to_string();
+    let username = username.to_string();
+    record(if success {
+        AuditEvent::AuthSuccess {
+            protocol,
+            method,
+            username,
+        }
+    } else {
+        AuditEvent::AuthFailure {
+            protocol,
+            method,
+            username,
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::CloseReason;
+    use std::time::Duration;
+
+    #[test]
+    fn test_record_serializes_flat() {
+        let record = AuditRecord {
+            timestamp_ms: 1700000000000,
+            session_id: None,
+            event: AuditEvent::AuthFailure {
+                protocol: "socks5".to_string(),
+                method: "password".to_string(),
+                username: "alice".to_string(),
+            },
+        };
+        let json: serde_json::Value = serde_json::to_value(&record).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "timestamp_ms": 1700000000000u64,
+                "event": "auth_failure",
+                "protocol": "socks5",
+                "method": "password",
+                "username": "alice",
+            })
+        );
+    }
+
+    #[test]
+    fn test_record_with_session() {
+        let record = AuditRecord::now(AuditEvent::SessionClose {
+            protocol: "ssh".to_string(),
+            username: None,
+            duration_ms: 42,
+        })
+        .with_session("abc");
+        let json: serde_json::Value = serde_json::to_value(&record).unwrap();
+        assert_eq!(json["session_id"], "abc");
+        assert_eq!(json["event"], "session_close");
+        assert!(json.get("username").is_none());
+    }
+
+    #[test]
+    fn test_connection_close_from_summary() {
+        let summary = ConnectionSummary::new(CloseReason::TargetClosed)
+            .with_target("example.com:443")
+            .with_bytes(10, 20)
+            .with_duration(Duration::from_millis(1500));
+        assert_eq!(
+            AuditEvent::connection_close("socks5", Some(&summary), None),
+            AuditEvent::ConnectionClose {
+                service: "socks5".to_string(),
+                target: Some("example.com:443".to_string()),
+                bytes_up: 10,
+                bytes_down: 20,
+                duration_ms: 1500,
+                close_reason: "target closed".to_string(),
+            }
+        );
+
+        let error = anyhow::anyhow!("boom");
+        match AuditEvent::connection_close("socks5", None, Some(&error)) {
+            AuditEvent::ConnectionClose { close_reason, .. } => {
+                assert_eq!(close_reason, "error: boom")
+            }
+            other => panic!("unexpected event {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_jsonl_logger_appends_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        std::fs::write(&path, "{\"existing\":true}\n").unwrap();
+
+        let logger = JsonlAuditLogger::open(&path).unwrap();
+        for service in ["a", "b"] {
+            logger.log(&AuditRecord::now(AuditEvent::ConnectionOpen {
+                service: service.to_string(),
+            }));
+        }
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 3);
+        let last: serde_json::Value = serde_json::from_str(lines[2]).unwrap();
+        assert_eq!(last["event"], "connection_open");
+        assert_eq!(last["service"], "b");
+    }
+}
@@ -0,0 +1,108 @@
+//! Reconnect backoff calculation
+//!
+//! Turns a [`ReconnectConfig`] into the sequence of delays the control
+//! channel sleeps between failed connection attempts.
+
+use crate::config::ReconnectConfig;
+use std::time::Duration;
+
+/// Exponential backoff over consecutive failures
+#[derive(Debug, Clone, Default)]
+pub struct Backoff {
+    config: ReconnectConfig,
+    /// Failures since the last reset
+    attempt: u32,
+}
+
+impl Backoff {
+    /// Start a backoff with no failures recorded
+    pub fn new(config: ReconnectConfig) -> Self {
+        Backoff { config, attempt: 0 }
+    }
+
+    /// Record a failure and return how long to wait before retrying, or
+    /// `None` once `max_retries` consecutive failures have been used up
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        self.attempt = self.attempt.saturating_add(1);
+        if self.config.max_retries != 0 && self.attempt > self.config.max_retries {
+            return None;
+        }
+        let exponent = i32::try_from(self.attempt - 1).unwrap_or(i32::MAX);
+        let delay = self.config.initial_delay_ms as f64 * self.config.multiplier.powi(exponent);
+        Some(Duration::from_millis(
+            delay.min(self.config.max_delay_ms as f64) as u64,
+        ))
+    }
+
+    /// Failures recorded since the last reset
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// Forget past failures after a successful connection
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(initial: u64, max: u64, multiplier: f64, max_retries: u32) -> ReconnectConfig {
+        ReconnectConfig {
+            initial_delay_ms: initial,
+            max_delay_ms: max,
+            multiplier,
+            max_retries,
+        }
+    }
+
+    fn delays(backoff: &mut Backoff, n: usize) -> Vec<Option<u64>> {
+        (0..n)
+            .map(|_| backoff.next_delay().map(|d| d.as_millis() as u64))
+            .collect()
+    }
+
+    #[test]
+    fn test_backoff_grows_and_clamps_at_max_delay() {
+        let mut backoff = Backoff::new(config(100, 1000, 3.0, 0));
+        assert_eq!(
+            delays(&mut backoff, 5),
+            vec![Some(100), Some(300), Some(900), Some(1000), Some(1000)]
+        );
+
+        // Far past the point where the multiplier overflows
+        let mut backoff = Backoff::new(config(100, 1000, 2.0, 0));
+        let last = delays(&mut backoff, 5000).pop().unwrap();
+        assert_eq!(last, Some(1000));
+    }
+
+    #[test]
+    fn test_backoff_gives_up_after_max_retries() {
+        let mut backoff = Backoff::new(config(10, 1000, 2.0, 3));
+        assert_eq!(
+            delays(&mut backoff, 5),
+            vec![Some(10), Some(20), Some(40), None, None]
+        );
+    }
+
+    #[test]
+    fn test_backoff_reset_starts_over() {
+        let mut backoff = Backoff::new(config(10, 1000, 2.0, 2));
+        assert_eq!(delays(&mut backoff, 2), vec![Some(10), Some(20)]);
+        backoff.reset();
+        assert_eq!(backoff.attempt(), 0);
+        assert_eq!(delays(&mut backoff, 3), vec![Some(10), Some(20), None]);
+    }
+
+    #[test]
+    fn test_default_backoff_matches_previous_policy() {
+        let mut backoff = Backoff::new(ReconnectConfig::default());
+        let delays = delays(&mut backoff, 11);
+        assert_eq!(delays[0], Some(1000));
+        assert_eq!(delays[6], Some(60_000));
+        assert_eq!(delays[9], Some(60_000));
+        assert_eq!(delays[10], None);
+    }
+}
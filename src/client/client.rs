@@ -4,12 +4,28 @@
 //! configuration, and spawns control channels for each service.
 
 use super::control_channel::ControlChannel;
+use super::events::{ClientEvent, EventListener, EventSender};
+use crate::audit::{self, JsonlAuditLogger};
+#[cfg(feature = "metrics")]
+use crate::config::ServiceType;
 use crate::config::{ClientConfig, ServiceConfig};
-use crate::services::{create_legacy_handler, create_service_handler};
-use crate::transport::Transport;
-use anyhow::Result;
+use crate::helper::spawn_named_in;
+#[cfg(feature = "socks")]
+use crate::services::create_service_handler_with_dialer;
+#[cfg(feature = "socks")]
+use crate::services::socks::TargetDialer;
+#[cfg(feature = "metrics")]
+use crate::services::MetricsServiceHandler;
+use crate::services::{
+    carry_over, create_legacy_handler, create_service_handler, ServiceHandler, ServiceRegistry,
+    SharedRegistry,
+};
+use crate::transport::{SocketOpts, Transport};
+use anyhow::{anyhow, Result};
 use std::sync::Arc;
-use tokio::sync::broadcast;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, Notify, Semaphore};
+use tokio::task::JoinSet;
 use tracing::{error, info, warn};
 
 /// Main Sockrats client
@@ -18,19 +34,96 @@ pub struct Client<T: Transport> {
     config: ClientConfig,
     /// Transport layer
     transport: Arc<T>,
+    /// Lifecycle event channel shared by all control channels
+    events: EventSender,
+    /// Dialer for SOCKS5 CONNECT targets; the host network when `None`
+    #[cfg(feature = "socks")]
+    socks_dialer: Option<Arc<dyn TargetDialer>>,
+    /// Reloaded configurations to apply while running
+    reload_rx: Option<mpsc::Receiver<ClientConfig>>,
 }
 
 impl<T: Transport + 'static> Client<T> {
     /// Create a new client with the given configuration
     pub async fn new(config: ClientConfig) -> Result<Self> {
         let transport = Arc::new(T::new(&config.transport)?);
-        Ok(Client { config, transport })
+        Ok(Client {
+            config,
+            transport,
+            events: EventSender::new(),
+            #[cfg(feature = "socks")]
+            socks_dialer: None,
+            reload_rx: None,
+        })
+    }
+
+    /// Get the client's transport
+    pub fn transport(&self) -> Arc<T> {
+        self.transport.clone()
+    }
+
+    /// Reach SOCKS5 CONNECT targets through `dialer` instead of the host
+    /// network
+    #[cfg(feature = "socks")]
+    pub fn with_socks_dialer(mut self, dialer: Arc<dyn TargetDialer>) -> Self {
+        self.socks_dialer = Some(dialer);
+        self
+    }
+
+    /// Apply configurations received on `reload_rx` while running
+    ///
+    /// A reloaded configuration must pass
+    /// [`ClientConfig::check_reload`]; its services are then rebuilt and
+    /// used for new connections without reconnecting. When the transport
+    /// re-reads a changed key file, the control channels reconnect after
+    /// `reconnect_grace` to use it. A configuration that is rejected or
+    /// fails to build is logged and the running one kept. Reloads apply in
+    /// multi-service mode only.
+    pub fn with_reload(mut self, reload_rx: mpsc::Receiver<ClientConfig>) -> Self {
+        self.reload_rx = Some(reload_rx);
+        self
+    }
+
+    /// Call `listener` for each of the client's lifecycle events
+    ///
+    /// Unlike [`subscribe_events`](Client::subscribe_events), the listener
+    /// is called synchronously and never misses an event.
+    pub fn with_event_listener(mut self, listener: Arc<dyn EventListener>) -> Self {
+        self.events = self.events.with_listener(listener);
+        self
+    }
+
+    /// Subscribe to the client's lifecycle events
+    ///
+    /// Only events emitted after subscribing are received, so subscribe
+    /// before calling [`run`](Client::run).
+    pub fn subscribe_events(&self) -> broadcast::Receiver<ClientEvent> {
+        self.events.subscribe()
     }
 
     /// Run the client until shutdown
-    pub async fn run(self, mut shutdown_rx: broadcast::Receiver<bool>) -> Result<()> {
+    ///
+    /// On shutdown, control channels stop accepting new data channels and
+    /// the in-flight ones get `shutdown_grace_secs` to finish before they
+    /// are closed.
+    pub async fn run(mut self, mut shutdown_rx: broadcast::Receiver<bool>) -> Result<()> {
         info!("Starting Sockrats client");
-        info!("Remote server: {}", self.config.remote_addr);
+        info!(
+            "Remote server: {}",
+            self.config.remote_endpoints().join(", ")
+        );
+
+        self.config.reconnect.validate().map_err(|e| anyhow!(e))?;
+        self.config.validate_client_id().map_err(|e| anyhow!(e))?;
+
+        if let Some(audit_config) = &self.config.audit {
+            audit_config.validate().map_err(|e| anyhow!(e))?;
+            let logger = JsonlAuditLogger::open(&audit_config.path)?;
+            info!("Writing audit log to {:?}", audit_config.path);
+            audit::set_logger(Some(Arc::new(logger)));
+        }
+
+        let grace = Duration::from_secs(self.config.shutdown_grace_secs);
 
         // Determine which services to run
         let services = self.config.effective_services();
@@ -39,6 +132,9 @@ impl<T: Transport + 'static> Client<T> {
             // Legacy mode: use service_name from [client] section
             warn!("No services configured, using legacy single-service mode");
             info!("Service name: {}", self.config.service_name);
+            if self.reload_rx.take().is_some() {
+                warn!("Configuration reload is not supported in legacy single-service mode");
+            }
 
             let handler = create_legacy_handler(
                 &self.config.service_name,
@@ -47,7 +143,8 @@ impl<T: Transport + 'static> Client<T> {
             );
 
             let control_channel =
-                ControlChannel::new(self.config.clone(), self.transport.clone(), handler);
+                ControlChannel::new(self.config.clone(), self.transport.clone(), handler)
+                    .with_events(self.events.clone());
 
             tokio::select! {
                 result = control_channel.run() => {
@@ -60,6 +157,7 @@ impl<T: Transport + 'static> Client<T> {
                     info!("Shutdown signal received, stopping client");
                 }
             }
+            control_channel.drain(grace).await;
         } else {
             // Multi-service mode: build handlers and spawn control channels
             info!("Running {} services", services.len());
@@ -67,29 +165,73 @@ impl<T: Transport + 'static> Client<T> {
                 info!("  - {} (type: {:?})", service.name, service.service_type);
             }
 
-            let mut handles = Vec::new();
+            let registry = SharedRegistry::new(self.build_registry(&services, None)?);
+            let mut reload_rx = self.reload_rx.take();
+
+            let mut control_channels = JoinSet::new();
+            let mut reconnects = Vec::with_capacity(services.len());
+            // One data channel limit across every service
+            let channel_slots = match self.config.max_concurrent_channels {
+                0 => None,
+                max => Some(Arc::new(Semaphore::new(max))),
+            };
 
             for service in &services {
-                let handler = create_service_handler(service)?;
+                let handler = registry
+                    .get(&service.name)
+                    .ok_or_else(|| anyhow!("No handler for service '{}'", service.name))?;
                 let config = self.create_service_config(service);
                 let transport = self.transport.clone();
+                let registry = registry.clone();
                 let shutdown_rx = shutdown_rx.resubscribe();
+                let events = self.events.clone();
+                let channel_slots = channel_slots.clone();
+                let reconnect = Arc::new(Notify::new());
+                reconnects.push(reconnect.clone());
+                let data_channel_opts = service
+                    .keepalive
+                    .as_ref()
+                    .map(SocketOpts::from_keepalive_config)
+                    .unwrap_or_else(SocketOpts::for_data_channel);
 
-                let handle = tokio::spawn(async move {
-                    let control_channel = ControlChannel::new(config, transport, handler);
-                    Self::run_service_loop(control_channel, shutdown_rx).await
+                spawn_named_in(&mut control_channels, "control-channel", async move {
+                    let control_channel = ControlChannel::new(config, transport, handler)
+                        .with_events(events)
+                        .with_data_channel_opts(data_channel_opts)
+                        .with_channel_slots(channel_slots)
+                        .with_reconnect(reconnect)
+                        .with_registry(registry);
+                    Self::run_service_loop(control_channel, shutdown_rx, grace).await
                 });
-                handles.push(handle);
             }
 
-            // Wait for shutdown or any service to fail
-            tokio::select! {
-                _ = shutdown_rx.recv() => {
-                    info!("Shutdown signal received, stopping all services");
-                }
-                result = futures::future::select_all(handles.iter_mut().map(Box::pin)) => {
-                    if let (Ok(Err(e)), _, _) = result {
-                        error!("A service control channel failed: {:#}", e);
+            // Wait for shutdown or any service to fail, applying reloads
+            loop {
+                tokio::select! {
+                    _ = shutdown_rx.recv() => {
+                        info!("Shutdown signal received, stopping all services");
+                        // Each service drains its own data channels before exiting
+                        while control_channels.join_next().await.is_some() {}
+                        break;
+                    }
+                    Some(result) = control_channels.join_next() => {
+                        if let Ok(Err(e)) = result {
+                            error!("A service control channel failed: {:#}", e);
+                        }
+                        break;
+                    }
+                    Some(config) = next_reload(&mut reload_rx) => {
+                        match self.reload(config, &registry) {
+                            Ok(false) => info!("Configuration reloaded"),
+                            Ok(true) => {
+                                info!("Configuration reloaded, reconnecting control channels");
+                                reconnects.iter().for_each(|reconnect| reconnect.notify_one());
+                            }
+                            Err(e) => error!(
+                                "Configuration reload failed, keeping the current configuration: {:#}",
+                                e
+                            ),
+                        }
                     }
                 }
             }
@@ -99,26 +241,98 @@ impl<T: Transport + 'static> Client<T> {
         Ok(())
     }
 
-    /// Run a service control channel loop with shutdown handling
+    /// Run a service control channel loop with shutdown handling, giving
+    /// its data channels `grace` to finish once shutdown is signalled
     async fn run_service_loop(
         control_channel: ControlChannel<T>,
         mut shutdown_rx: broadcast::Receiver<bool>,
+        grace: Duration,
     ) -> Result<()> {
         tokio::select! {
             result = control_channel.run() => {
                 result
             }
             _ = shutdown_rx.recv() => {
+                control_channel.drain(grace).await;
                 Ok(())
             }
         }
     }
 
+    /// Build and check the handlers for `services`
+    ///
+    /// Handlers replacing those of `previous` take over their statistics
+    /// and shared limits, matched by service name.
+    fn build_registry(
+        &self,
+        services: &[ServiceConfig],
+        previous: Option<&ServiceRegistry>,
+    ) -> Result<ServiceRegistry> {
+        let mut registry = ServiceRegistry::new();
+        for service in services {
+            #[cfg(feature = "metrics")]
+            if service.service_type == ServiceType::Metrics {
+                continue;
+            }
+            let mut handler = self.create_handler(service)?;
+            if let Some(previous) = previous.and_then(|previous| previous.get(&service.name)) {
+                carry_over(&mut handler, previous.as_ref());
+            }
+            registry.register(service.name.clone(), handler);
+        }
+        #[cfg(feature = "metrics")]
+        Self::register_metrics_handlers(&mut registry, services);
+        registry.validate_all(services)?;
+        Ok(registry)
+    }
+
+    /// Switch to `config`, storing its rebuilt handlers in `registry`
+    ///
+    /// Returns whether the transport reloaded state that the running control
+    /// channels must reconnect to use.
+    fn reload(&mut self, config: ClientConfig, registry: &SharedRegistry) -> Result<bool> {
+        self.config.check_reload(&config).map_err(|e| anyhow!(e))?;
+        let current = registry.load();
+        let rebuilt = self.build_registry(&config.effective_services(), Some(&current))?;
+        let reconnect = self.transport.reload()?;
+        registry.store(rebuilt);
+        self.config = config;
+        Ok(reconnect)
+    }
+
+    /// Register a handler for each metrics service, exporting the
+    /// statistics of every handler registered so far
+    #[cfg(feature = "metrics")]
+    fn register_metrics_handlers(registry: &mut ServiceRegistry, services: &[ServiceConfig]) {
+        let exported = registry.clone();
+        for service in services {
+            if service.service_type == ServiceType::Metrics {
+                let handler = MetricsServiceHandler::new(exported.clone());
+                registry.register(service.name.clone(), Arc::new(handler));
+            }
+        }
+    }
+
+    /// Create the handler for a service, applying the SOCKS5 dialer if set
+    fn create_handler(&self, service: &ServiceConfig) -> Result<Arc<dyn ServiceHandler>> {
+        #[cfg(feature = "socks")]
+        if let Some(dialer) = &self.socks_dialer {
+            return create_service_handler_with_dialer(service, dialer.clone());
+        }
+        create_service_handler(service)
+    }
+
     /// Create a ClientConfig for a specific service
+    ///
+    /// The service's control channel, and through its session key every
+    /// data channel, authenticates with the service token, or the client
+    /// token when the service has none.
     fn create_service_config(&self, service: &ServiceConfig) -> ClientConfig {
         let mut config = self.config.clone();
         config.service_name = service.name.clone();
-        config.token = service.token.clone();
+        if !service.token.is_empty() {
+            config.token = service.token.clone();
+        }
 
         // Apply service-specific SSH config if present
         if let Some(ssh) = &service.ssh {
@@ -134,6 +348,15 @@ impl<T: Transport + 'static> Client<T> {
     }
 }
 
+/// Wait for the next reloaded configuration; pending forever without a
+/// reload channel
+async fn next_reload(reload_rx: &mut Option<mpsc::Receiver<ClientConfig>>) -> Option<ClientConfig> {
+    match reload_rx {
+        Some(rx) => rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -143,14 +366,23 @@ mod tests {
     fn create_test_config() -> ClientConfig {
         ClientConfig {
             remote_addr: "127.0.0.1:2333".to_string(),
+            remote_addrs: Vec::new(),
+            remote_addr_ttl_secs: 0,
             service_name: "test-socks".to_string(),
             token: "test-token".to_string(),
+            client_id: None,
             transport: TransportConfig::default(),
             heartbeat_timeout: 40,
+            heartbeat_interval_secs: 0,
+            reconnect_grace: 1,
+            reconnect: Default::default(),
+            shutdown_grace_secs: 10,
+            max_concurrent_channels: 0,
             socks: SocksConfig::default(),
             ssh: SshConfig::default(),
             pool: Default::default(),
             services: Vec::new(),
+            audit: None,
             #[cfg(feature = "wireguard")]
             wireguard: None,
         }
@@ -178,4 +410,234 @@ mod tests {
             create_legacy_handler("my-ssh", &SocksConfig::default(), &SshConfig::default());
         assert_eq!(handler.service_type(), "ssh");
     }
+
+    #[tokio::test]
+    async fn test_service_config_uses_service_token() {
+        use crate::config::ServiceType;
+        use crate::transport::TcpTransport;
+
+        let service = |name: &str, token: &str| ServiceConfig {
+            name: name.to_string(),
+            service_type: ServiceType::Socks5,
+            token: token.to_string(),
+            socks: None,
+            ssh: None,
+            keepalive: None,
+            #[cfg(feature = "vncserver")]
+            vnc: None,
+        };
+        let client = Client::<TcpTransport>::new(create_test_config())
+            .await
+            .unwrap();
+
+        let config = client.create_service_config(&service("proxy-a", "token-a"));
+        assert_eq!(config.service_name, "proxy-a");
+        assert_eq!(config.token, "token-a");
+
+        let config = client.create_service_config(&service("proxy-b", ""));
+        assert_eq!(config.service_name, "proxy-b");
+        assert_eq!(config.token, "test-token");
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "socks")]
+    async fn test_run_rejects_duplicate_service_names() {
+        use crate::config::ServiceType;
+        use crate::transport::TcpTransport;
+
+        let service = ServiceConfig {
+            name: "proxy".to_string(),
+            service_type: ServiceType::Socks5,
+            token: "token".to_string(),
+            socks: None,
+            ssh: None,
+            keepalive: None,
+            #[cfg(feature = "vncserver")]
+            vnc: None,
+        };
+        let mut config = create_test_config();
+        config.services = vec![service.clone(), service];
+
+        let client = Client::<TcpTransport>::new(config).await.unwrap();
+        let (_shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        let err = client.run(shutdown_rx).await.unwrap_err();
+        assert!(err.to_string().contains("Duplicate service name"));
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "socks")]
+    async fn test_reload_replaces_handlers_or_keeps_current() {
+        use crate::config::ServiceType;
+        use crate::services::{CloseReason, ConnectionSummary};
+        use crate::transport::TcpTransport;
+
+        let mut config = create_test_config();
+        config.services = vec![ServiceConfig {
+            name: "proxy".to_string(),
+            service_type: ServiceType::Socks5,
+            token: "token".to_string(),
+            socks: None,
+            ssh: None,
+            keepalive: None,
+            #[cfg(feature = "vncserver")]
+            vnc: None,
+        }];
+        let mut client = Client::<TcpTransport>::new(config.clone()).await.unwrap();
+        let registry = SharedRegistry::new(client.build_registry(&config.services, None).unwrap());
+        let original = registry.get("proxy").unwrap();
+        original
+            .stats()
+            .unwrap()
+            .track(async { Ok(ConnectionSummary::new(CloseReason::Completed)) })
+            .await
+            .unwrap();
+
+        // Invalid service settings fail to build and change nothing
+        let mut invalid = config.clone();
+        invalid.services[0].socks = Some(SocksConfig {
+            handshake_timeout: 0,
+            ..Default::default()
+        });
+        assert!(client.reload(invalid, &registry).is_err());
+        assert!(Arc::ptr_eq(&registry.get("proxy").unwrap(), &original));
+
+        // So does a change of control channel identity
+        let mut rotated = config.clone();
+        rotated.services[0].token = "rotated".to_string();
+        assert!(client.reload(rotated, &registry).is_err());
+        assert!(Arc::ptr_eq(&registry.get("proxy").unwrap(), &original));
+
+        let mut updated = config;
+        updated.services[0].socks = Some(SocksConfig {
+            request_timeout: 30,
+            ..Default::default()
+        });
+        // Without a key file to re-read, nothing needs to reconnect
+        assert!(!client.reload(updated, &registry).unwrap());
+        let reloaded = registry.get("proxy").unwrap();
+        assert!(!Arc::ptr_eq(&reloaded, &original));
+        // The new handler keeps counting where the old one left off
+        assert_eq!(reloaded.stats().unwrap().snapshot().total_connections, 1);
+        assert_eq!(
+            client.config.services[0]
+                .socks
+                .as_ref()
+                .unwrap()
+                .request_timeout,
+            30
+        );
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "socks")]
+    async fn test_subscribe_events_mock_server() {
+        use crate::protocol::{
+            read_auth, read_hello, write_ack, write_control_cmd, write_data_cmd, write_hello, Ack,
+            ControlChannelCmd, DataChannelCmd, Hello, CURRENT_PROTO_VERSION,
+        };
+        use crate::services::socks::*;
+        use crate::services::CloseReason;
+        use crate::transport::TcpTransport;
+        use std::time::Duration;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut config = create_test_config();
+        config.remote_addr = listener.local_addr().unwrap().to_string();
+
+        // Minimal rathole server: accept the control channel, request one data
+        // channel and send a SOCKS5 BIND (which the client rejects) over it
+        let server = tokio::spawn(async move {
+            let (mut control, _) = listener.accept().await.unwrap();
+            read_hello(&mut control).await.unwrap();
+            write_hello(
+                &mut control,
+                &Hello::ControlChannelHello(CURRENT_PROTO_VERSION, [7u8; 32]),
+            )
+            .await
+            .unwrap();
+            read_auth(&mut control).await.unwrap();
+            write_ack(&mut control, &Ack::Ok).await.unwrap();
+            write_control_cmd(&mut control, &ControlChannelCmd::CreateDataChannel)
+                .await
+                .unwrap();
+
+            let (mut data, _) = listener.accept().await.unwrap();
+            read_hello(&mut data).await.unwrap();
+            write_data_cmd(&mut data, &DataChannelCmd::StartForwardTcp)
+                .await
+                .unwrap();
+            data.write_all(&[
+                SOCKS5_VERSION,
+                1,
+                SOCKS5_AUTH_METHOD_NONE,
+                SOCKS5_VERSION,
+                SOCKS5_CMD_TCP_BIND,
+                SOCKS5_RESERVED,
+                SOCKS5_ADDR_TYPE_IPV4,
+                127,
+                0,
+                0,
+                1,
+                0,
+                80,
+            ])
+            .await
+            .unwrap();
+            let mut reply = [0u8; 12];
+            data.read_exact(&mut reply).await.unwrap();
+
+            // Keep the control channel open until the test is done
+            control
+        });
+
+        let client = Client::<TcpTransport>::new(config).await.unwrap();
+        let mut events = client.subscribe_events();
+        let (shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        let client_handle = tokio::spawn(client.run(shutdown_rx));
+
+        let mut received = Vec::new();
+        while received.len() < 4 {
+            let event = tokio::time::timeout(Duration::from_secs(5), events.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            received.push(event);
+        }
+
+        let service = "test-socks".to_string();
+        assert_eq!(
+            received[0],
+            ClientEvent::Connected {
+                service: service.clone()
+            }
+        );
+        assert_eq!(
+            received[1],
+            ClientEvent::DataChannel {
+                service: service.clone()
+            }
+        );
+        assert_eq!(
+            received[2],
+            ClientEvent::ConnectionOpened {
+                service: service.clone()
+            }
+        );
+        match &received[3] {
+            ClientEvent::ConnectionClosed {
+                service: s,
+                summary: Some(summary),
+            } => {
+                assert_eq!(s, &service);
+                assert_eq!(summary.close_reason, CloseReason::Rejected);
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+
+        let _control = server.await.unwrap();
+        shutdown_tx.send(true).unwrap();
+        client_handle.await.unwrap().unwrap();
+    }
 }
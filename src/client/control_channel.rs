@@ -4,20 +4,39 @@
 //! Each control channel manages one service and spawns data channels
 //! that are routed to the appropriate [`ServiceHandler`].
 
+use super::backoff::Backoff;
 use super::data_channel::run_data_channel;
+use super::events::{ClientEvent, EventSender};
 use crate::config::ClientConfig;
+use crate::helper::spawn_named_in;
 use crate::protocol::{
-    read_ack, read_control_cmd, read_hello, write_auth, write_hello, Ack, Auth, ControlChannelCmd,
-    Digest, Hello,
+    read_ack, read_control_cmd_lenient, read_hello, write_auth, write_client_id, write_control_cmd,
+    write_hello, Ack, Auth, Capabilities, ClientId, ControlChannelCmd, Digest, Hello,
 };
-use crate::services::ServiceHandler;
+use crate::services::{ServiceHandler, SharedRegistry};
 use crate::transport::{AddrMaybeCached, SocketOpts, Transport};
 use anyhow::{bail, Context, Result};
-use std::sync::Arc;
+use futures::FutureExt;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{Notify, Semaphore};
+use tokio::task::JoinSet;
+use tokio::time::{Instant, Interval, MissedTickBehavior};
 use tracing::{debug, error, info, warn};
 
+/// Maximum number of nonce challenges answered in a single authentication
+const MAX_AUTH_CHALLENGES: usize = 3;
+
+/// How a control channel session ended without error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SessionEnd {
+    /// The reconnect trigger from
+    /// [`with_reconnect`](ControlChannel::with_reconnect) fired
+    ReconnectRequested,
+}
+
 /// Control channel for managing the connection to the rathole server
 pub struct ControlChannel<T: Transport> {
     /// Client configuration
@@ -26,65 +45,203 @@ pub struct ControlChannel<T: Transport> {
     transport: Arc<T>,
     /// Service handler for data channels spawned by this control channel
     handler: Arc<dyn ServiceHandler>,
+    /// Live registry whose handler for this service replaces `handler`
+    registry: Option<SharedRegistry>,
+    /// Lifecycle event channel
+    events: EventSender,
+    /// Socket options applied to spawned data channels
+    data_channel_opts: SocketOpts,
+    /// Remote endpoints, whose resolved addresses data channels share
+    remote_addrs: Vec<AddrMaybeCached>,
+    /// Index of the remote endpoint that last connected
+    endpoint: AtomicUsize,
+    /// Capability bits the server advertised in its last hello
+    server_caps: AtomicU32,
+    /// Signalled to close the session and reconnect
+    reconnect: Arc<Notify>,
+    /// Data channels spawned by this control channel that may still be
+    /// running
+    data_channels: Mutex<JoinSet<()>>,
+    /// Permits for running data channels, with `max_concurrent_channels`
+    /// set; shared with the control channels of the other services
+    channel_slots: Option<Arc<Semaphore>>,
 }
 
 impl<T: Transport + 'static> ControlChannel<T> {
     /// Create a new control channel with a specific service handler
     pub fn new(config: ClientConfig, transport: Arc<T>, handler: Arc<dyn ServiceHandler>) -> Self {
+        let channel_slots = match config.max_concurrent_channels {
+            0 => None,
+            max => Some(Arc::new(Semaphore::new(max))),
+        };
+        let remote_addrs = config
+            .remote_endpoints()
+            .iter()
+            .map(|endpoint| match config.remote_addr_ttl_secs {
+                0 => AddrMaybeCached::new(endpoint),
+                ttl => AddrMaybeCached::new(endpoint).with_ttl(Duration::from_secs(ttl)),
+            })
+            .collect();
         ControlChannel {
             config,
             transport,
             handler,
+            registry: None,
+            events: EventSender::new(),
+            data_channel_opts: SocketOpts::for_data_channel(),
+            remote_addrs,
+            endpoint: AtomicUsize::new(0),
+            server_caps: AtomicU32::new(0),
+            reconnect: Arc::new(Notify::new()),
+            data_channels: Mutex::new(JoinSet::new()),
+            channel_slots,
         }
     }
 
+    /// Emit lifecycle events on the given channel instead of a private one
+    pub fn with_events(mut self, events: EventSender) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Apply the given socket options to data channels instead of the
+    /// default data channel profile
+    pub fn with_data_channel_opts(mut self, opts: SocketOpts) -> Self {
+        self.data_channel_opts = opts;
+        self
+    }
+
+    /// Draw data channel permits from `slots`, shared with other control
+    /// channels, instead of a `max_concurrent_channels` pool of its own
+    pub fn with_channel_slots(mut self, slots: Option<Arc<Semaphore>>) -> Self {
+        self.channel_slots = slots;
+        self
+    }
+
+    /// Take the handler for new data channels from `registry`, so a
+    /// reloaded registry applies without reconnecting
+    ///
+    /// The handler passed to [`new`](ControlChannel::new) is used while
+    /// `registry` has none for this service.
+    pub fn with_registry(mut self, registry: SharedRegistry) -> Self {
+        self.registry = Some(registry);
+        self
+    }
+
+    /// Optional features the server advertised in its last handshake
+    ///
+    /// Servers sending the legacy hello advertise none. Optional features
+    /// are only used when the server lists them here.
+    pub fn server_capabilities(&self) -> Capabilities {
+        Capabilities::from_bits(self.server_caps.load(Ordering::Relaxed))
+    }
+
+    /// The handler for the next data channel
+    fn current_handler(&self) -> Arc<dyn ServiceHandler> {
+        self.registry
+            .as_ref()
+            .and_then(|registry| registry.get(&self.config.service_name))
+            .unwrap_or_else(|| self.handler.clone())
+    }
+
+    /// Close the current session cleanly and reconnect whenever
+    /// `reconnect` is notified
+    ///
+    /// After the close, [`run`](ControlChannel::run) waits
+    /// `reconnect_grace` seconds before reconnecting so the server has
+    /// dropped the old session by then. The rathole protocol has no
+    /// goodbye message, so the close is a TCP shutdown. A notification
+    /// made while no session is up is dropped, since the next session
+    /// connects with the current state anyway.
+    pub fn with_reconnect(mut self, reconnect: Arc<Notify>) -> Self {
+        self.reconnect = reconnect;
+        self
+    }
+
+    /// Wait up to `grace` for in-flight data channels to finish, then
+    /// abort the ones still running
+    ///
+    /// Call this once [`run`](ControlChannel::run) has been stopped, so no
+    /// new data channels are spawned meanwhile. Returns the number of data
+    /// channels that had to be aborted.
+    pub async fn drain(&self, grace: Duration) -> usize {
+        let mut data_channels = std::mem::take(&mut *self.data_channels.lock().unwrap());
+        if data_channels.is_empty() {
+            return 0;
+        }
+
+        info!(
+            "Waiting up to {:?} for {} data channel(s) to finish",
+            grace,
+            data_channels.len()
+        );
+        let finished = tokio::time::timeout(grace, async {
+            while data_channels.join_next().await.is_some() {}
+        })
+        .await;
+        if finished.is_ok() {
+            return 0;
+        }
+
+        let aborted = data_channels.len();
+        warn!(
+            "Closing {} data channel(s) still running after {:?}",
+            aborted, grace
+        );
+        data_channels.shutdown().await;
+        aborted
+    }
+
     /// Run the control channel with automatic reconnection
+    ///
+    /// Failed attempts are retried with the backoff from the `reconnect`
+    /// configuration, which starts over once a session is established.
     pub async fn run(&self) -> Result<()> {
-        let mut retry_count = 0;
-        let max_retries = 10;
-        let base_delay = Duration::from_secs(1);
-        let max_delay = Duration::from_secs(60);
+        let mut backoff = Backoff::new(self.config.reconnect.clone());
 
         loop {
-            match self.run_once().await {
-                Ok(_) => {
-                    info!("Control channel closed normally");
-                    break;
+            match self.run_once(&mut backoff).await {
+                Ok(SessionEnd::ReconnectRequested) => {
+                    let grace = Duration::from_secs(self.config.reconnect_grace);
+                    info!("Control channel closed for reconnect, waiting {:?}", grace);
+                    tokio::time::sleep(grace).await;
                 }
                 Err(e) => {
-                    retry_count += 1;
-                    if retry_count > max_retries {
+                    let Some(delay) = backoff.next_delay() else {
                         error!("Max retries exceeded, giving up");
                         return Err(e);
-                    }
-
-                    let delay =
-                        std::cmp::min(base_delay * 2u32.pow((retry_count - 1) as u32), max_delay);
+                    };
+                    let attempt = backoff.attempt();
 
-                    warn!(
-                        "Control channel error: {:#}. Reconnecting in {:?}... (attempt {}/{})",
-                        e, delay, retry_count, max_retries
-                    );
+                    match self.config.reconnect.max_retries {
+                        0 => warn!(
+                            "Control channel error: {:#}. Reconnecting in {:?}... (attempt {})",
+                            e, delay, attempt
+                        ),
+                        max_retries => warn!(
+                            "Control channel error: {:#}. Reconnecting in {:?}... (attempt {}/{})",
+                            e, delay, attempt, max_retries
+                        ),
+                    }
+                    self.events.emit(ClientEvent::Reconnecting {
+                        service: self.config.service_name.clone(),
+                        attempt,
+                        delay,
+                    });
 
                     tokio::time::sleep(delay).await;
                 }
             }
         }
-
-        Ok(())
     }
 
-    /// Run a single control channel session
-    async fn run_once(&self) -> Result<()> {
-        let remote_addr = AddrMaybeCached::new(&self.config.remote_addr);
-
-        info!("Connecting to server: {}", self.config.remote_addr);
-
-        let mut conn = self
-            .transport
-            .connect(&remote_addr)
-            .await
-            .context("Failed to connect to server")?;
+    /// Run a single control channel session, resetting `backoff` once the
+    /// handshake succeeds
+    async fn run_once(&self, backoff: &mut Backoff) -> Result<SessionEnd> {
+        // This session connects with the current state, so a reconnect
+        // requested before now is already satisfied
+        let _ = self.reconnect.notified().now_or_never();
+        let (mut conn, remote_addr) = self.connect().await?;
 
         T::hint(&conn, SocketOpts::for_control_channel());
 
@@ -95,9 +252,64 @@ impl<T: Transport + 'static> ControlChannel<T> {
             .context("Handshake failed")?;
 
         info!("Control channel established");
+        backoff.reset();
+        self.events.emit(ClientEvent::Connected {
+            service: self.config.service_name.clone(),
+        });
 
         // Listen for commands
-        self.handle_commands(conn, session_key, remote_addr).await
+        let end = self.handle_commands(conn, session_key, remote_addr).await;
+        self.events.emit(ClientEvent::Disconnected {
+            service: self.config.service_name.clone(),
+            reason: match &end {
+                Ok(SessionEnd::ReconnectRequested) => "reconnect requested".to_string(),
+                Err(e) => format!("{:#}", e),
+            },
+        });
+        end
+    }
+
+    /// Connect to the first reachable remote endpoint
+    ///
+    /// Starts from the endpoint that last connected and walks the rest in
+    /// configured order, wrapping around, so the client sticks to a
+    /// working endpoint and only fails over when it refuses. Each endpoint
+    /// is resolved afresh, so a reconnect follows DNS changes.
+    async fn connect(&self) -> Result<(T::Stream, AddrMaybeCached)> {
+        let endpoints = &self.remote_addrs;
+        if endpoints.is_empty() {
+            bail!("No remote_addr configured");
+        }
+
+        let start = self.endpoint.load(Ordering::Relaxed) % endpoints.len();
+        let mut last_err = None;
+
+        for offset in 0..endpoints.len() {
+            let index = (start + offset) % endpoints.len();
+            let remote_addr = endpoints[index].clone();
+            let endpoint = remote_addr.addr();
+            remote_addr.clear_cache().await;
+
+            info!("Connecting to server: {}", endpoint);
+
+            match self.transport.connect(&remote_addr).await {
+                Ok(conn) => {
+                    if index != start {
+                        info!("Failed over to server: {}", endpoint);
+                    }
+                    self.endpoint.store(index, Ordering::Relaxed);
+                    return Ok((conn, remote_addr));
+                }
+                Err(e) => {
+                    warn!("Failed to connect to {}: {:#}", endpoint, e);
+                    last_err = Some(e);
+                }
+            }
+        }
+
+        Err(last_err
+            .expect("at least one endpoint was tried")
+            .context("Failed to connect to server"))
     }
 
     /// Perform the control channel handshake
@@ -111,47 +323,103 @@ impl<T: Transport + 'static> ControlChannel<T> {
 
         debug!("Sent control channel hello");
 
-        // Read server's response (contains nonce)
-        let server_hello = read_hello(conn).await?;
-        let nonce = match server_hello {
-            Hello::ControlChannelHello(_, n) => n,
-            _ => bail!("Unexpected hello type from server"),
-        };
+        self.authenticate(conn).await
+    }
 
-        debug!("Received server nonce");
+    /// Answer the server's nonce challenge and return the session key
+    ///
+    /// A server advertising [`Capabilities::RECHALLENGE`] may reply to an
+    /// [`Auth`] with [`Ack::Rechallenge`] and a fresh nonce, in which case
+    /// the digest is recomputed with the new nonce, up to
+    /// [`MAX_AUTH_CHALLENGES`] times.
+    async fn authenticate<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        conn: &mut S,
+    ) -> Result<Digest> {
+        for _ in 0..MAX_AUTH_CHALLENGES {
+            // Read server's challenge (contains nonce)
+            let server_hello = read_hello(conn).await?;
+            let nonce = match server_hello {
+                Hello::ControlChannelHello(_, n) | Hello::ControlChannelHelloWithCaps(_, n, _) => n,
+                _ => bail!("Unexpected hello type from server"),
+            };
+            let caps = server_hello.capabilities();
+            self.server_caps.store(caps.bits(), Ordering::Relaxed);
+
+            debug!("Received server nonce (capabilities {:#x})", caps.bits());
 
-        // Create and send auth
-        let auth = Auth::new(&self.config.token, &nonce);
-        let session_key = auth.0;
-        write_auth(conn, &auth).await?;
+            // Create and send auth
+            let auth = Auth::new(&self.config.token, &nonce);
+            let session_key = auth.0;
+            write_auth(conn, &auth).await?;
 
-        debug!("Sent authentication");
+            debug!("Sent authentication");
 
-        // Read ack
-        let ack = read_ack(conn).await?;
-        match ack {
-            Ack::Ok => {
-                debug!("Authentication successful");
-                Ok(session_key)
+            // Only servers that asked for it know the message
+            if let Some(client_id) = &self.config.client_id {
+                if caps.contains(Capabilities::CLIENT_ID) {
+                    write_client_id(conn, &ClientId(client_id.clone())).await?;
+                    debug!("Sent client id {:?}", client_id);
+                }
             }
-            Ack::ServiceNotExist => {
-                bail!(
-                    "Service '{}' does not exist on server",
-                    self.config.service_name
-                )
+
+            // Read ack
+            let ack = read_ack(conn).await?;
+            match ack {
+                Ack::Ok => {
+                    debug!("Authentication successful");
+                    return Ok(session_key);
+                }
+                Ack::ServiceNotExist => {
+                    bail!(
+                        "Service '{}' does not exist on server",
+                        self.config.service_name
+                    )
+                }
+                Ack::AuthFailed => {
+                    self.events.emit(ClientEvent::AuthFailed {
+                        service: self.config.service_name.clone(),
+                    });
+                    bail!("Authentication failed - incorrect token")
+                }
+                Ack::Rechallenge if caps.contains(Capabilities::RECHALLENGE) => {
+                    debug!("Server rotated its nonce, re-authenticating")
+                }
+                Ack::Rechallenge => {
+                    bail!("Server sent a re-challenge without advertising support for it")
+                }
             }
-            Ack::AuthFailed => bail!("Authentication failed - incorrect token"),
         }
+
+        bail!(
+            "Authentication failed - server issued more than {} challenges",
+            MAX_AUTH_CHALLENGES
+        )
     }
 
     /// Handle commands from the server
+    ///
+    /// The session counts as dead, and fails, when nothing arrives from the
+    /// server for `heartbeat_timeout`; every command received, heartbeats
+    /// included, moves that deadline. With `heartbeat_interval_secs` set,
+    /// heartbeats are sent back on the same schedule. This loop is the only
+    /// writer to the connection, so they never interleave with other writes.
     async fn handle_commands<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
         &self,
-        mut conn: S,
-        session_key: Digest,
+        conn: S,
+        mut session_key: Digest,
         remote_addr: AddrMaybeCached,
-    ) -> Result<()> {
+    ) -> Result<SessionEnd> {
         let heartbeat_timeout = Duration::from_secs(self.config.heartbeat_timeout);
+        let mut heartbeat = match self.config.heartbeat_interval_secs {
+            0 => None,
+            secs => {
+                let period = Duration::from_secs(secs);
+                let mut ticker = tokio::time::interval_at(Instant::now() + period, period);
+                ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+                Some(ticker)
+            }
+        };
 
         info!(
             "Using service handler: {} (type: {})",
@@ -159,45 +427,124 @@ impl<T: Transport + 'static> ControlChannel<T> {
             self.handler.service_type()
         );
 
+        let (mut reader, mut writer) = tokio::io::split(conn);
+        let mut deadline = Instant::now() + heartbeat_timeout;
         loop {
-            tokio::select! {
-                cmd_result = read_control_cmd(&mut conn) => {
-                    let cmd = cmd_result.context("Failed to read control command")?;
-
-                    match cmd {
-                        ControlChannelCmd::CreateDataChannel => {
-                            debug!("Received CreateDataChannel command");
-
-                            // Spawn data channel handler with the service handler
-                            let transport = self.transport.clone();
-                            let addr = remote_addr.clone();
-                            let key = session_key;
-                            let handler = self.handler.clone();
-
-                            tokio::spawn(async move {
-                                if let Err(e) = run_data_channel(
-                                    transport,
-                                    addr,
-                                    key,
-                                    handler,
-                                ).await {
-                                    warn!("Data channel error: {:#}", e);
-                                }
-                            });
+            // The read is not cancel safe, so it stays pinned while
+            // heartbeats are sent
+            let cmd = {
+                let read = read_control_cmd_lenient(&mut reader);
+                tokio::pin!(read);
+                loop {
+                    tokio::select! {
+                        cmd_result = &mut read => {
+                            break cmd_result.context("Failed to read control command")?;
+                        }
+                        _ = tick(&mut heartbeat) => {
+                            debug!("Sending heartbeat");
+                            write_control_cmd(&mut writer, &ControlChannelCmd::HeartBeat)
+                                .await
+                                .context("Failed to send heartbeat")?;
                         }
-                        ControlChannelCmd::HeartBeat => {
-                            debug!("Received heartbeat");
+                        _ = tokio::time::sleep_until(deadline) => {
+                            bail!("Heartbeat timeout - no command received in {:?}", heartbeat_timeout);
+                        }
+                        _ = self.reconnect.notified() => {
+                            info!("Reconnect requested, closing control channel");
+                            if let Err(e) = writer.shutdown().await {
+                                debug!("Control channel shutdown failed: {}", e);
+                            }
+                            return Ok(SessionEnd::ReconnectRequested);
                         }
                     }
                 }
-                _ = tokio::time::sleep(heartbeat_timeout) => {
-                    bail!("Heartbeat timeout - no command received in {:?}", heartbeat_timeout);
+            };
+            deadline = Instant::now() + heartbeat_timeout;
+
+            match cmd {
+                // Unknown commands from newer servers are skipped
+                None => {}
+                Some(ControlChannelCmd::CreateDataChannel) => {
+                    debug!("Received CreateDataChannel command");
+                    self.events.emit(ClientEvent::DataChannel {
+                        service: self.config.service_name.clone(),
+                    });
+
+                    // Spawn data channel handler with the service handler
+                    let transport = self.transport.clone();
+                    let addr = remote_addr.clone();
+                    let key = session_key;
+                    let handler = self.current_handler();
+                    let service_name = self.config.service_name.clone();
+                    let events = self.events.clone();
+                    let socket_opts = self.data_channel_opts.clone();
+                    // At capacity the request is refused outright: queueing
+                    // it would still hold a task per request
+                    let slot = match &self.channel_slots {
+                        Some(slots) => match slots.clone().try_acquire_owned() {
+                            Ok(slot) => Some(slot),
+                            Err(_) => {
+                                warn!(
+                                    "{} data channels already running, refusing a new one for {}",
+                                    self.config.max_concurrent_channels, self.config.service_name
+                                );
+                                continue;
+                            }
+                        },
+                        None => None,
+                    };
+
+                    let mut data_channels = self.data_channels.lock().unwrap();
+                    // Forget data channels that have already finished
+                    while data_channels.try_join_next().is_some() {}
+                    spawn_named_in(&mut data_channels, "data-channel", async move {
+                        let _slot = slot;
+                        if let Err(e) = run_data_channel(
+                            transport,
+                            addr,
+                            key,
+                            handler,
+                            service_name,
+                            events,
+                            socket_opts,
+                        )
+                        .await
+                        {
+                            warn!("Data channel error: {:#}", e);
+                        }
+                    });
+                }
+                Some(ControlChannelCmd::HeartBeat) => {
+                    debug!("Received heartbeat");
+                }
+                Some(ControlChannelCmd::Rechallenge) => {
+                    if !self
+                        .server_capabilities()
+                        .contains(Capabilities::RECHALLENGE)
+                    {
+                        bail!("Server sent a re-challenge without advertising support for it");
+                    }
+                    debug!("Received re-authentication challenge");
+                    session_key = self
+                        .authenticate(&mut tokio::io::join(&mut reader, &mut writer))
+                        .await
+                        .context("Re-authentication failed")?;
                 }
             }
         }
     }
 }
 
+/// Wait for the next tick of `ticker`, forever when there is none
+async fn tick(ticker: &mut Option<Interval>) {
+    match ticker {
+        Some(ticker) => {
+            ticker.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -210,19 +557,825 @@ mod tests {
     fn create_test_config() -> ClientConfig {
         ClientConfig {
             remote_addr: "127.0.0.1:2333".to_string(),
+            remote_addrs: Vec::new(),
+            remote_addr_ttl_secs: 0,
             service_name: "test".to_string(),
             token: "secret".to_string(),
+            client_id: None,
             transport: TransportConfig::default(),
             heartbeat_timeout: 40,
+            heartbeat_interval_secs: 0,
+            reconnect_grace: 1,
+            reconnect: Default::default(),
+            shutdown_grace_secs: 10,
+            max_concurrent_channels: 0,
             socks: SocksConfig::default(),
             ssh: SshConfig::default(),
             pool: Default::default(),
             services: Vec::new(),
+            audit: None,
             #[cfg(feature = "wireguard")]
             wireguard: None,
         }
     }
 
+    fn create_test_channel() -> ControlChannel<crate::transport::TcpTransport> {
+        use crate::transport::TcpTransport;
+        let transport = Arc::new(TcpTransport::new(&TransportConfig::default()).unwrap());
+        ControlChannel::new(
+            create_test_config(),
+            transport,
+            Arc::new(SshServiceHandler::new(SshConfig::default())),
+        )
+    }
+
+    /// Addresses passed to [`CountingTransport::connect`], in order
+    static CONNECT_ATTEMPTS: std::sync::Mutex<Vec<String>> = std::sync::Mutex::new(Vec::new());
+
+    // A TCP transport that records every connect attempt
+    #[derive(Debug)]
+    struct CountingTransport(crate::transport::TcpTransport);
+
+    #[async_trait::async_trait]
+    impl Transport for CountingTransport {
+        type Stream = tokio::net::TcpStream;
+
+        fn new(config: &TransportConfig) -> Result<Self> {
+            Ok(CountingTransport(crate::transport::TcpTransport::new(
+                config,
+            )?))
+        }
+
+        fn hint(conn: &Self::Stream, opts: SocketOpts) {
+            crate::transport::TcpTransport::hint(conn, opts);
+        }
+
+        async fn connect(&self, addr: &AddrMaybeCached) -> Result<Self::Stream> {
+            CONNECT_ATTEMPTS
+                .lock()
+                .unwrap()
+                .push(addr.addr().to_string());
+            self.0.connect(addr).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connect_fails_over_to_next_endpoint() {
+        // Nothing listens on the first endpoint, so it refuses
+        let refused = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let refused_addr = refused.local_addr().unwrap().to_string();
+        drop(refused);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let working_addr = listener.local_addr().unwrap().to_string();
+
+        let mut config = create_test_config();
+        config.remote_addrs = vec![refused_addr.clone(), working_addr.clone()];
+        let transport = Arc::new(CountingTransport::new(&TransportConfig::default()).unwrap());
+        let channel = ControlChannel::new(
+            config,
+            transport,
+            Arc::new(SshServiceHandler::new(SshConfig::default())),
+        );
+
+        let (_conn, addr) = channel.connect().await.unwrap();
+        assert_eq!(addr.addr(), working_addr);
+        assert_eq!(
+            *CONNECT_ATTEMPTS.lock().unwrap(),
+            vec![refused_addr.clone(), working_addr.clone()]
+        );
+
+        // The working endpoint is tried first on the next connect
+        CONNECT_ATTEMPTS.lock().unwrap().clear();
+        let (_conn, addr) = channel.connect().await.unwrap();
+        assert_eq!(addr.addr(), working_addr);
+        assert_eq!(*CONNECT_ATTEMPTS.lock().unwrap(), vec![working_addr]);
+    }
+
+    #[tokio::test]
+    async fn test_connect_resolves_endpoint_again() {
+        let refused = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stale = refused.local_addr().unwrap();
+        drop(refused);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let mut config = create_test_config();
+        config.remote_addr = listener.local_addr().unwrap().to_string();
+        config.remote_addr_ttl_secs = 300;
+        let transport =
+            Arc::new(crate::transport::TcpTransport::new(&TransportConfig::default()).unwrap());
+        let channel = ControlChannel::new(
+            config,
+            transport,
+            Arc::new(SshServiceHandler::new(SshConfig::default())),
+        );
+
+        // An address cached by the previous session, since gone stale
+        channel.remote_addrs[0].set_cached(stale).await;
+        let (_conn, addr) = channel.connect().await.unwrap();
+        assert_eq!(
+            addr.get_cached().await,
+            Some(listener.local_addr().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handshake_recomputes_auth_on_rechallenge() {
+        use crate::protocol::{read_auth, write_ack, CURRENT_PROTO_VERSION};
+
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let channel = create_test_channel();
+
+        let server = tokio::spawn(async move {
+            let hello = read_hello(&mut server).await.unwrap();
+            assert_eq!(hello, Hello::control_channel("test"));
+
+            let first = [1u8; 32];
+            write_hello(
+                &mut server,
+                &Hello::ControlChannelHelloWithCaps(
+                    CURRENT_PROTO_VERSION,
+                    first,
+                    Capabilities::RECHALLENGE,
+                ),
+            )
+            .await
+            .unwrap();
+            let auth = read_auth(&mut server).await.unwrap();
+            assert_eq!(auth, Auth::new("secret", &first));
+
+            // Rotate the nonce before accepting
+            let second = [2u8; 32];
+            write_ack(&mut server, &Ack::Rechallenge).await.unwrap();
+            write_hello(
+                &mut server,
+                &Hello::ControlChannelHelloWithCaps(
+                    CURRENT_PROTO_VERSION,
+                    second,
+                    Capabilities::RECHALLENGE,
+                ),
+            )
+            .await
+            .unwrap();
+            let auth = read_auth(&mut server).await.unwrap();
+            assert_eq!(auth, Auth::new("secret", &second));
+            write_ack(&mut server, &Ack::Ok).await.unwrap();
+            auth
+        });
+
+        let session_key = channel.do_handshake(&mut client).await.unwrap();
+        let auth = server.await.unwrap();
+        assert_eq!(session_key, auth.0);
+        assert_eq!(channel.server_capabilities(), Capabilities::RECHALLENGE);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_rejects_rechallenge_from_legacy_server() {
+        use crate::protocol::{read_auth, write_ack, CURRENT_PROTO_VERSION};
+
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let channel = create_test_channel();
+
+        tokio::spawn(async move {
+            read_hello(&mut server).await.unwrap();
+            write_hello(
+                &mut server,
+                &Hello::ControlChannelHello(CURRENT_PROTO_VERSION, [1u8; 32]),
+            )
+            .await
+            .unwrap();
+            read_auth(&mut server).await.unwrap();
+            write_ack(&mut server, &Ack::Rechallenge).await.unwrap();
+            server
+        });
+
+        let err = channel.do_handshake(&mut client).await.unwrap_err();
+        assert!(err.to_string().contains("without advertising"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_missing_heartbeats_fail_the_session() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        let mut config = create_test_config();
+        config.heartbeat_timeout = 10;
+        let channel = ControlChannel::new(
+            config,
+            Arc::new(crate::transport::TcpTransport::new(&TransportConfig::default()).unwrap()),
+            Arc::new(SshServiceHandler::new(SshConfig::default())),
+        );
+
+        // Two heartbeats 8s apart keep the session up, then the server
+        // goes quiet without closing the connection
+        let server = tokio::spawn(async move {
+            for _ in 0..2 {
+                tokio::time::sleep(Duration::from_secs(8)).await;
+                write_control_cmd(&mut server, &ControlChannelCmd::HeartBeat)
+                    .await
+                    .unwrap();
+            }
+            server
+        });
+
+        let start = Instant::now();
+        let err = channel
+            .handle_commands(client, [0u8; 32], AddrMaybeCached::new("127.0.0.1:2333"))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("Heartbeat timeout"));
+        assert_eq!(start.elapsed(), Duration::from_secs(26));
+        let _server = server.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_client_heartbeats_follow_interval() {
+        use crate::protocol::read_control_cmd;
+
+        let (client, mut server) = tokio::io::duplex(1024);
+        let mut config = create_test_config();
+        config.heartbeat_interval_secs = 5;
+        let channel = Arc::new(ControlChannel::new(
+            config,
+            Arc::new(crate::transport::TcpTransport::new(&TransportConfig::default()).unwrap()),
+            Arc::new(SshServiceHandler::new(SshConfig::default())),
+        ));
+        let runner = channel.clone();
+        let handle = tokio::spawn(async move {
+            runner
+                .handle_commands(client, [0u8; 32], AddrMaybeCached::new("127.0.0.1:2333"))
+                .await
+        });
+
+        let start = Instant::now();
+        let mut sent_at = Vec::new();
+        for _ in 0..3 {
+            let cmd = read_control_cmd(&mut server).await.unwrap();
+            assert_eq!(cmd, ControlChannelCmd::HeartBeat);
+            sent_at.push(start.elapsed().as_secs());
+        }
+        assert_eq!(sent_at, [5, 10, 15]);
+
+        // Server commands still arrive in between and keep the session up
+        write_control_cmd(&mut server, &ControlChannelCmd::HeartBeat)
+            .await
+            .unwrap();
+        assert_eq!(
+            read_control_cmd(&mut server).await.unwrap(),
+            ControlChannelCmd::HeartBeat
+        );
+        assert!(!handle.is_finished());
+
+        drop(server);
+        assert!(handle.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_handshake_records_server_capabilities() {
+        use crate::protocol::{read_auth, write_ack, CURRENT_PROTO_VERSION};
+
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let channel = create_test_channel();
+
+        let server = tokio::spawn(async move {
+            read_hello(&mut server).await.unwrap();
+            write_hello(
+                &mut server,
+                &Hello::ControlChannelHelloWithCaps(
+                    CURRENT_PROTO_VERSION,
+                    [5u8; 32],
+                    Capabilities::MULTIPLEX,
+                ),
+            )
+            .await
+            .unwrap();
+            let auth = read_auth(&mut server).await.unwrap();
+            assert_eq!(auth, Auth::new("secret", &[5u8; 32]));
+            write_ack(&mut server, &Ack::Ok).await.unwrap();
+        });
+
+        channel.do_handshake(&mut client).await.unwrap();
+        server.await.unwrap();
+        assert_eq!(channel.server_capabilities(), Capabilities::MULTIPLEX);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_sends_client_id_when_supported() {
+        use crate::protocol::{read_auth, read_client_id, write_ack, CURRENT_PROTO_VERSION};
+        use tokio::io::AsyncReadExt;
+
+        let handshake = |caps: Capabilities| async move {
+            let (mut client, mut server) = tokio::io::duplex(1024);
+            let mut config = create_test_config();
+            config.client_id = Some("edge-01".to_string());
+            let channel = ControlChannel::new(
+                config,
+                Arc::new(crate::transport::TcpTransport::new(&TransportConfig::default()).unwrap()),
+                Arc::new(SshServiceHandler::new(SshConfig::default())),
+            );
+
+            let server = tokio::spawn(async move {
+                read_hello(&mut server).await.unwrap();
+                write_hello(
+                    &mut server,
+                    &Hello::ControlChannelHelloWithCaps(CURRENT_PROTO_VERSION, [5u8; 32], caps),
+                )
+                .await
+                .unwrap();
+                read_auth(&mut server).await.unwrap();
+                let id = if caps.contains(Capabilities::CLIENT_ID) {
+                    Some(read_client_id(&mut server).await.unwrap())
+                } else {
+                    None
+                };
+                write_ack(&mut server, &Ack::Ok).await.unwrap();
+                // Nothing else follows the auth
+                let mut rest = Vec::new();
+                server.read_to_end(&mut rest).await.unwrap();
+                (id, rest)
+            });
+
+            channel.do_handshake(&mut client).await.unwrap();
+            drop(client);
+            server.await.unwrap()
+        };
+
+        let (id, rest) = handshake(Capabilities::CLIENT_ID).await;
+        assert_eq!(id, Some(ClientId("edge-01".to_string())));
+        assert!(rest.is_empty());
+
+        let (id, rest) = handshake(Capabilities::empty()).await;
+        assert_eq!(id, None);
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_handshake_gives_up_after_max_challenges() {
+        use crate::protocol::{read_auth, write_ack, CURRENT_PROTO_VERSION};
+
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let channel = create_test_channel();
+
+        tokio::spawn(async move {
+            read_hello(&mut server).await.unwrap();
+            for i in 0..MAX_AUTH_CHALLENGES {
+                write_hello(
+                    &mut server,
+                    &Hello::ControlChannelHelloWithCaps(
+                        CURRENT_PROTO_VERSION,
+                        [i as u8; 32],
+                        Capabilities::RECHALLENGE,
+                    ),
+                )
+                .await
+                .unwrap();
+                read_auth(&mut server).await.unwrap();
+                write_ack(&mut server, &Ack::Rechallenge).await.unwrap();
+            }
+            server
+        });
+
+        let err = channel.do_handshake(&mut client).await.unwrap_err();
+        assert!(err.to_string().contains("challenges"));
+    }
+
+    #[tokio::test]
+    async fn test_recorded_handshake_replays() {
+        use crate::protocol::{read_auth, write_ack, write_control_cmd, CURRENT_PROTO_VERSION};
+        use crate::transport::{RecordingTransport, ReplayTransport, TcpTransport};
+
+        // Record a session against a stand-in rathole server
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut config = create_test_config();
+        config.remote_addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut server, _) = listener.accept().await.unwrap();
+            read_hello(&mut server).await.unwrap();
+            write_hello(
+                &mut server,
+                &Hello::ControlChannelHello(CURRENT_PROTO_VERSION, [7u8; 32]),
+            )
+            .await
+            .unwrap();
+            read_auth(&mut server).await.unwrap();
+            write_ack(&mut server, &Ack::Ok).await.unwrap();
+            write_control_cmd(&mut server, &ControlChannelCmd::HeartBeat)
+                .await
+                .unwrap();
+        });
+
+        let recorder =
+            Arc::new(RecordingTransport::<TcpTransport>::new(&config.transport).unwrap());
+        let channel = ControlChannel::new(
+            config.clone(),
+            recorder.clone(),
+            Arc::new(SshServiceHandler::new(SshConfig::default())),
+        );
+        // The server hangs up after its heartbeat
+        let err = channel.run_once(&mut Backoff::default()).await.unwrap_err();
+        assert!(format!("{:#}", err).contains("Failed to read control command"));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("handshake.json");
+        recorder.save(&path).unwrap();
+
+        // Replay it with no server at all
+        let replay = Arc::new(ReplayTransport::load(&path).unwrap());
+        let events = crate::client::EventSender::new();
+        let mut rx = events.subscribe();
+        let channel = ControlChannel::new(
+            config,
+            replay.clone(),
+            Arc::new(SshServiceHandler::new(SshConfig::default())),
+        )
+        .with_events(events);
+        let err = channel.run_once(&mut Backoff::default()).await.unwrap_err();
+        assert!(format!("{:#}", err).contains("Failed to read control command"));
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            ClientEvent::Connected {
+                service: "test".to_string()
+            }
+        );
+        assert_eq!(replay.remaining(), 0);
+    }
+
+    /// Accept a control channel and complete its handshake
+    async fn accept_control_channel(listener: &tokio::net::TcpListener) -> tokio::net::TcpStream {
+        use crate::protocol::{read_auth, write_ack, CURRENT_PROTO_VERSION};
+
+        let (mut server, _) = listener.accept().await.unwrap();
+        read_hello(&mut server).await.unwrap();
+        write_hello(
+            &mut server,
+            &Hello::ControlChannelHello(CURRENT_PROTO_VERSION, [7u8; 32]),
+        )
+        .await
+        .unwrap();
+        read_auth(&mut server).await.unwrap();
+        write_ack(&mut server, &Ack::Ok).await.unwrap();
+        server
+    }
+
+    #[tokio::test]
+    async fn test_requested_reconnect_waits_grace_period() {
+        use tokio::io::AsyncReadExt;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut config = create_test_config();
+        config.remote_addr = listener.local_addr().unwrap().to_string();
+        config.reconnect_grace = 1;
+        let transport =
+            Arc::new(crate::transport::TcpTransport::new(&TransportConfig::default()).unwrap());
+        let events = crate::client::EventSender::new();
+        let mut rx = events.subscribe();
+        let reconnect = Arc::new(Notify::new());
+        // A request made before the session starts is dropped
+        reconnect.notify_one();
+        let channel = ControlChannel::new(
+            config,
+            transport,
+            Arc::new(SshServiceHandler::new(SshConfig::default())),
+        )
+        .with_events(events)
+        .with_reconnect(reconnect.clone());
+        let handle = tokio::spawn(async move { channel.run().await });
+
+        let mut first = accept_control_channel(&listener).await;
+        assert!(matches!(
+            rx.recv().await.unwrap(),
+            ClientEvent::Connected { .. }
+        ));
+        // The stale request did not close the new session
+        assert!(tokio::time::timeout(Duration::from_millis(200), rx.recv())
+            .await
+            .is_err());
+
+        let requested = std::time::Instant::now();
+        reconnect.notify_one();
+
+        // The old session is closed cleanly before reconnecting
+        let mut buf = [0u8; 1];
+        assert_eq!(first.read(&mut buf).await.unwrap(), 0);
+
+        let _second = accept_control_channel(&listener).await;
+        assert!(requested.elapsed() >= Duration::from_secs(1));
+        assert!(matches!(
+            rx.recv().await.unwrap(),
+            ClientEvent::Disconnected { ref reason, .. } if reason == "reconnect requested"
+        ));
+        assert!(matches!(
+            rx.recv().await.unwrap(),
+            ClientEvent::Connected { .. }
+        ));
+
+        handle.abort();
+    }
+
+    fn fast_reconnect(max_retries: u32) -> crate::config::ReconnectConfig {
+        crate::config::ReconnectConfig {
+            initial_delay_ms: 10,
+            max_delay_ms: 20,
+            multiplier: 2.0,
+            max_retries,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_gives_up_after_max_retries() {
+        // Nothing listens, so every attempt is refused
+        let refused = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut config = create_test_config();
+        config.remote_addr = refused.local_addr().unwrap().to_string();
+        drop(refused);
+        config.reconnect = fast_reconnect(2);
+        let transport =
+            Arc::new(crate::transport::TcpTransport::new(&TransportConfig::default()).unwrap());
+        let events = crate::client::EventSender::new();
+        let mut rx = events.subscribe();
+        let channel = ControlChannel::new(
+            config,
+            transport,
+            Arc::new(SshServiceHandler::new(SshConfig::default())),
+        )
+        .with_events(events);
+
+        tokio::time::timeout(Duration::from_secs(5), channel.run())
+            .await
+            .unwrap()
+            .unwrap_err();
+        let mut delays = Vec::new();
+        while let Ok(ClientEvent::Reconnecting { attempt, delay, .. }) = rx.try_recv() {
+            delays.push((attempt, delay));
+        }
+        assert_eq!(
+            delays,
+            vec![
+                (1, Duration::from_millis(10)),
+                (2, Duration::from_millis(20))
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_resets_backoff_after_successful_session() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut config = create_test_config();
+        config.remote_addr = listener.local_addr().unwrap().to_string();
+        // A single retry, so only a reset lets it survive repeated drops
+        config.reconnect = fast_reconnect(1);
+        let transport =
+            Arc::new(crate::transport::TcpTransport::new(&TransportConfig::default()).unwrap());
+        let events = crate::client::EventSender::new();
+        let mut rx = events.subscribe();
+        let channel = ControlChannel::new(
+            config,
+            transport,
+            Arc::new(SshServiceHandler::new(SshConfig::default())),
+        )
+        .with_events(events);
+        let handle = tokio::spawn(async move { channel.run().await });
+
+        for _ in 0..3 {
+            // The server drops every session right after the handshake
+            drop(accept_control_channel(&listener).await);
+            assert!(matches!(
+                rx.recv().await.unwrap(),
+                ClientEvent::Connected { .. }
+            ));
+            assert!(matches!(
+                rx.recv().await.unwrap(),
+                ClientEvent::Disconnected { .. }
+            ));
+            assert!(matches!(
+                rx.recv().await.unwrap(),
+                ClientEvent::Reconnecting { attempt: 1, .. }
+            ));
+        }
+        assert!(!handle.is_finished());
+        handle.abort();
+    }
+
+    /// Echoes the first four bytes of each connection after `delay`
+    #[derive(Debug)]
+    struct SlowRelayHandler {
+        delay: Duration,
+    }
+
+    #[async_trait::async_trait]
+    impl ServiceHandler for SlowRelayHandler {
+        fn service_type(&self) -> &str {
+            "slow-relay"
+        }
+
+        async fn handle_tcp_stream(
+            &self,
+            mut stream: Box<dyn crate::services::StreamDyn>,
+        ) -> Result<crate::services::ConnectionSummary> {
+            use tokio::io::AsyncReadExt;
+
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf).await?;
+            tokio::time::sleep(self.delay).await;
+            stream.write_all(&buf).await?;
+            Ok(crate::services::ConnectionSummary::new(
+                crate::services::CloseReason::Completed,
+            ))
+        }
+    }
+
+    /// Start a relay through a control channel to a stand-in server, then
+    /// stop the control channel while the relay is in flight
+    ///
+    /// Returns the stopped channel, the server end of the control channel
+    /// and the server end of the relay's data channel.
+    async fn stop_during_relay(
+        delay: Duration,
+    ) -> (
+        Arc<ControlChannel<crate::transport::TcpTransport>>,
+        tokio::net::TcpStream,
+        tokio::net::TcpStream,
+    ) {
+        use crate::protocol::{write_control_cmd, write_data_cmd, DataChannelCmd};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut config = create_test_config();
+        config.remote_addr = listener.local_addr().unwrap().to_string();
+        let transport =
+            Arc::new(crate::transport::TcpTransport::new(&TransportConfig::default()).unwrap());
+        let channel = Arc::new(ControlChannel::new(
+            config,
+            transport,
+            Arc::new(SlowRelayHandler { delay }),
+        ));
+        let runner = channel.clone();
+        let handle = tokio::spawn(async move { runner.run().await });
+
+        let mut control = accept_control_channel(&listener).await;
+        write_control_cmd(&mut control, &ControlChannelCmd::CreateDataChannel)
+            .await
+            .unwrap();
+        let (mut data, _) = listener.accept().await.unwrap();
+        read_hello(&mut data).await.unwrap();
+        write_data_cmd(&mut data, &DataChannelCmd::StartForwardTcp)
+            .await
+            .unwrap();
+        data.write_all(b"ping").await.unwrap();
+
+        handle.abort();
+        let _ = handle.await;
+        (channel, control, data)
+    }
+
+    #[tokio::test]
+    async fn test_drain_lets_relay_finish_within_grace() {
+        use tokio::io::AsyncReadExt;
+
+        let (channel, mut control, mut data) = stop_during_relay(Duration::from_millis(200)).await;
+
+        // The stopped control channel accepts no more commands
+        let mut buf = [0u8; 4];
+        assert_eq!(control.read(&mut buf).await.unwrap(), 0);
+
+        assert_eq!(channel.drain(Duration::from_secs(5)).await, 0);
+        data.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[tokio::test]
+    async fn test_drain_closes_relay_after_grace() {
+        use tokio::io::AsyncReadExt;
+
+        let (channel, _control, mut data) = stop_during_relay(Duration::from_secs(60)).await;
+
+        let started = std::time::Instant::now();
+        assert_eq!(channel.drain(Duration::from_millis(50)).await, 1);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        let mut buf = [0u8; 4];
+        assert_eq!(data.read(&mut buf).await.unwrap(), 0);
+        assert_eq!(channel.drain(Duration::from_millis(50)).await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_data_channels_over_limit_are_refused() {
+        use crate::protocol::{write_control_cmd, write_data_cmd, DataChannelCmd};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut config = create_test_config();
+        config.remote_addr = listener.local_addr().unwrap().to_string();
+        config.max_concurrent_channels = 1;
+        let transport =
+            Arc::new(crate::transport::TcpTransport::new(&TransportConfig::default()).unwrap());
+        let channel = ControlChannel::new(
+            config,
+            transport,
+            Arc::new(SlowRelayHandler {
+                delay: Duration::from_millis(300),
+            }),
+        );
+        let handle = tokio::spawn(async move { channel.run().await });
+
+        let mut control = accept_control_channel(&listener).await;
+        for _ in 0..2 {
+            write_control_cmd(&mut control, &ControlChannelCmd::CreateDataChannel)
+                .await
+                .unwrap();
+        }
+        let (mut data, _) = listener.accept().await.unwrap();
+        read_hello(&mut data).await.unwrap();
+        write_data_cmd(&mut data, &DataChannelCmd::StartForwardTcp)
+            .await
+            .unwrap();
+        data.write_all(b"ping").await.unwrap();
+
+        // The second channel is refused, not opened once the first ends
+        let second = tokio::time::timeout(Duration::from_millis(800), listener.accept()).await;
+        assert!(second.is_err(), "data channel opened over the limit");
+
+        // With the first one finished, a new request gets its slot
+        write_control_cmd(&mut control, &ControlChannelCmd::CreateDataChannel)
+            .await
+            .unwrap();
+        let third = tokio::time::timeout(Duration::from_secs(5), listener.accept()).await;
+        assert!(third.is_ok());
+
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_channel_slots_are_shared() {
+        use crate::protocol::write_control_cmd;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut config = create_test_config();
+        config.remote_addr = listener.local_addr().unwrap().to_string();
+        let transport =
+            Arc::new(crate::transport::TcpTransport::new(&TransportConfig::default()).unwrap());
+        // The only slot is taken, as if by another service's data channel
+        let slots = Arc::new(Semaphore::new(1));
+        let taken = slots.clone().try_acquire_owned().unwrap();
+        let channel = ControlChannel::new(
+            config,
+            transport,
+            Arc::new(SshServiceHandler::new(SshConfig::default())),
+        )
+        .with_channel_slots(Some(slots));
+        let handle = tokio::spawn(async move { channel.run().await });
+
+        let mut control = accept_control_channel(&listener).await;
+        write_control_cmd(&mut control, &ControlChannelCmd::CreateDataChannel)
+            .await
+            .unwrap();
+        let refused = tokio::time::timeout(Duration::from_millis(300), listener.accept()).await;
+        assert!(refused.is_err(), "data channel opened without a free slot");
+
+        drop(taken);
+        write_control_cmd(&mut control, &ControlChannelCmd::CreateDataChannel)
+            .await
+            .unwrap();
+        let opened = tokio::time::timeout(Duration::from_secs(5), listener.accept()).await;
+        assert!(opened.is_ok());
+
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_unknown_control_command_is_skipped() {
+        use crate::protocol::write_control_cmd;
+        use tokio::io::AsyncWriteExt;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut config = create_test_config();
+        config.remote_addr = listener.local_addr().unwrap().to_string();
+        let transport =
+            Arc::new(crate::transport::TcpTransport::new(&TransportConfig::default()).unwrap());
+        let events = crate::client::EventSender::new();
+        let mut rx = events.subscribe();
+        let channel = ControlChannel::new(
+            config,
+            transport,
+            Arc::new(SshServiceHandler::new(SshConfig::default())),
+        )
+        .with_events(events);
+        let handle = tokio::spawn(async move { channel.run().await });
+
+        let mut server = accept_control_channel(&listener).await;
+        assert!(matches!(
+            rx.recv().await.unwrap(),
+            ClientEvent::Connected { .. }
+        ));
+
+        // A command from a newer protocol revision, then a known one
+        server.write_all(&[0x2a, 0, 0, 0]).await.unwrap();
+        write_control_cmd(&mut server, &ControlChannelCmd::CreateDataChannel)
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            rx.recv().await.unwrap(),
+            ClientEvent::DataChannel { .. }
+        ));
+
+        handle.abort();
+    }
+
     #[test]
     fn test_control_channel_config() {
         let config = create_test_config();
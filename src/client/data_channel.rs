@@ -4,6 +4,8 @@
 //! Routes incoming connections to the appropriate service handler
 //! (SOCKS5, SSH, etc.) via the [`ServiceHandler`] trait.
 
+use super::events::{ClientEvent, EventSender};
+use crate::audit::{self, AuditEvent};
 use crate::protocol::{read_data_cmd, write_hello, DataChannelCmd, Digest, Hello};
 use crate::services::ServiceHandler;
 use crate::transport::{AddrMaybeCached, SocketOpts, Transport};
@@ -18,19 +20,32 @@ use tracing::debug;
 /// 2. Sends data channel hello with session key
 /// 3. Receives the forward command
 /// 4. Routes to the appropriate handler via the [`ServiceHandler`] trait
+///
+/// Data channels carry no token of their own: `session_key` comes from the
+/// control channel, which authenticated with the service's token.
+///
+/// `socket_opts` are applied to the data channel connection, and
+/// `ConnectionOpened`/`ConnectionClosed` events for `service_name` are
+/// emitted on `events` around the handler call.
 pub async fn run_data_channel<T: Transport>(
     transport: Arc<T>,
     remote_addr: AddrMaybeCached,
     session_key: Digest,
     handler: Arc<dyn ServiceHandler>,
+    service_name: String,
+    events: EventSender,
+    socket_opts: SocketOpts,
 ) -> Result<()> {
-    // Connect to server
-    let mut conn = transport
-        .connect(&remote_addr)
-        .await
-        .context("Failed to connect data channel")?;
+    // Connect to server, dropping the cached address if that fails
+    let mut conn = match transport.connect(&remote_addr).await {
+        Ok(conn) => conn,
+        Err(e) => {
+            remote_addr.clear_cache().await;
+            return Err(e.context("Failed to connect data channel"));
+        }
+    };
 
-    T::hint(&conn, SocketOpts::for_data_channel());
+    T::hint(&conn, socket_opts);
 
     // Send data channel hello
     let hello = Hello::data_channel(session_key);
@@ -43,22 +58,54 @@ pub async fn run_data_channel<T: Transport>(
         .await
         .context("Failed to read data channel command")?;
 
-    match cmd {
+    events.emit(ClientEvent::ConnectionOpened {
+        service: service_name.clone(),
+    });
+    audit::record(AuditEvent::ConnectionOpen {
+        service: service_name.clone(),
+    });
+
+    let result = match cmd {
         DataChannelCmd::StartForwardTcp => {
             debug!("Starting TCP forwarding ({})", handler.service_type());
             handler
                 .handle_tcp_stream(Box::new(conn))
                 .await
-                .with_context(|| format!("{} TCP handling failed", handler.service_type()))?;
+                .map(Some)
+                .with_context(|| format!("{} TCP handling failed", handler.service_type()))
         }
         DataChannelCmd::StartForwardUdp => {
             debug!("Starting UDP forwarding ({})", handler.service_type());
             handler
                 .handle_udp_stream(Box::new(conn))
                 .await
-                .with_context(|| format!("{} UDP handling failed", handler.service_type()))?;
+                .map(|_| None)
+                .with_context(|| format!("{} UDP handling failed", handler.service_type()))
         }
+    };
+
+    let summary = result.as_ref().ok().cloned().flatten();
+    if let Some(summary) = &summary {
+        debug!(
+            "{} connection to {} closed ({}): {} bytes up, {} bytes down in {:?}",
+            handler.service_type(),
+            summary.target.as_deref().unwrap_or("-"),
+            summary.close_reason,
+            summary.bytes_up,
+            summary.bytes_down,
+            summary.duration
+        );
     }
+    audit::record(AuditEvent::connection_close(
+        &service_name,
+        summary.as_ref(),
+        result.as_ref().err(),
+    ));
+    events.emit(ClientEvent::ConnectionClosed {
+        service: service_name,
+        summary,
+    });
+    result?;
 
     debug!("Data channel completed");
     Ok(())
@@ -66,7 +113,40 @@ pub async fn run_data_channel<T: Transport>(
 
 #[cfg(test)]
 mod tests {
-    use crate::services::{ServiceHandler, StreamDyn};
+    use super::*;
+    use crate::client::EventListener;
+    use crate::config::{KeepaliveConfig, TransportConfig};
+    use crate::services::{CloseReason, ConnectionSummary, ServiceHandler, StreamDyn};
+    use crate::transport::TcpTransport;
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Keepalive time observed by [`RecordingTransport::hint`]
+    static HINTED_KEEPALIVE: Mutex<Option<Duration>> = Mutex::new(None);
+
+    // A TCP transport that records the keepalive applied by hint()
+    #[derive(Debug)]
+    struct RecordingTransport(TcpTransport);
+
+    #[async_trait::async_trait]
+    impl Transport for RecordingTransport {
+        type Stream = TcpStream;
+
+        fn new(config: &TransportConfig) -> Result<Self> {
+            Ok(RecordingTransport(TcpTransport::new(config)?))
+        }
+
+        fn hint(conn: &Self::Stream, opts: SocketOpts) {
+            TcpTransport::hint(conn, opts);
+            let keepalive = socket2::SockRef::from(conn).keepalive_time().unwrap();
+            *HINTED_KEEPALIVE.lock().unwrap() = Some(keepalive);
+        }
+
+        async fn connect(&self, addr: &AddrMaybeCached) -> Result<Self::Stream> {
+            self.0.connect(addr).await
+        }
+    }
 
     // A minimal mock service handler for data channel tests
     #[derive(Debug)]
@@ -78,11 +158,109 @@ mod tests {
             "mock"
         }
 
-        async fn handle_tcp_stream(&self, _stream: Box<dyn StreamDyn>) -> anyhow::Result<()> {
-            Ok(())
+        async fn handle_tcp_stream(
+            &self,
+            _stream: Box<dyn StreamDyn>,
+        ) -> anyhow::Result<ConnectionSummary> {
+            Ok(ConnectionSummary::new(CloseReason::Completed))
         }
     }
 
+    #[tokio::test]
+    async fn test_data_channel_applies_service_keepalive() {
+        use crate::protocol::{read_hello, write_data_cmd};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = AddrMaybeCached::new(&listener.local_addr().unwrap().to_string());
+
+        let server = tokio::spawn(async move {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            read_hello(&mut conn).await.unwrap();
+            write_data_cmd(&mut conn, &DataChannelCmd::StartForwardTcp)
+                .await
+                .unwrap();
+        });
+
+        let keepalive = KeepaliveConfig {
+            keepalive_secs: 7,
+            keepalive_interval: 3,
+        };
+        let transport = Arc::new(RecordingTransport::new(&TransportConfig::default()).unwrap());
+        run_data_channel(
+            transport,
+            addr,
+            [0u8; 32],
+            Arc::new(MockHandler),
+            "mock".to_string(),
+            EventSender::new(),
+            SocketOpts::from_keepalive_config(&keepalive),
+        )
+        .await
+        .unwrap();
+        server.await.unwrap();
+
+        assert_eq!(
+            *HINTED_KEEPALIVE.lock().unwrap(),
+            Some(Duration::from_secs(7))
+        );
+    }
+
+    /// Records the services whose data channels opened and closed
+    #[derive(Debug, Default)]
+    struct RecordingListener {
+        opened: Mutex<Vec<String>>,
+        closed: Mutex<Vec<Option<CloseReason>>>,
+    }
+
+    impl EventListener for RecordingListener {
+        fn on_data_channel_opened(&self, service: &str) {
+            self.opened.lock().unwrap().push(service.to_string());
+        }
+
+        fn on_data_channel_closed(&self, _service: &str, summary: Option<&ConnectionSummary>) {
+            self.closed
+                .lock()
+                .unwrap()
+                .push(summary.map(|s| s.close_reason.clone()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_data_channel_notifies_listener() {
+        use crate::protocol::{read_hello, write_data_cmd};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = AddrMaybeCached::new(&listener.local_addr().unwrap().to_string());
+
+        let server = tokio::spawn(async move {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            read_hello(&mut conn).await.unwrap();
+            write_data_cmd(&mut conn, &DataChannelCmd::StartForwardTcp)
+                .await
+                .unwrap();
+        });
+
+        let recorder = Arc::new(RecordingListener::default());
+        run_data_channel(
+            Arc::new(TcpTransport::new(&TransportConfig::default()).unwrap()),
+            addr,
+            [0u8; 32],
+            Arc::new(MockHandler),
+            "mock".to_string(),
+            EventSender::new().with_listener(recorder.clone()),
+            SocketOpts::for_data_channel(),
+        )
+        .await
+        .unwrap();
+        server.await.unwrap();
+
+        assert_eq!(*recorder.opened.lock().unwrap(), ["mock"]);
+        assert_eq!(
+            *recorder.closed.lock().unwrap(),
+            [Some(CloseReason::Completed)]
+        );
+    }
+
     #[test]
     fn test_mock_handler_service_type() {
         let handler = MockHandler;
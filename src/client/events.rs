@@ -0,0 +1,247 @@
+//! Client lifecycle events
+//!
+//! Embedders using sockrats as a library can follow what the client is
+//! doing by subscribing to a broadcast channel of [`ClientEvent`]s via
+//! [`Client::subscribe_events`](super::Client::subscribe_events).
+//! Emitting is fire-and-forget: with no subscribers, or subscribers that
+//! fall behind, events are simply dropped.
+//!
+//! An [`EventListener`] installed with
+//! [`Client::with_event_listener`](super::Client::with_event_listener) is
+//! instead called synchronously for every event, so it never misses one.
+
+use crate::services::ConnectionSummary;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+/// Capacity of the event broadcast channel
+pub const EVENT_CHANNEL_CAPACITY: usize = 256;
+
+/// An event emitted by the client
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientEvent {
+    /// A control channel authenticated and completed its handshake with
+    /// the server
+    Connected {
+        /// Service name
+        service: String,
+    },
+    /// The server rejected the control channel's token
+    AuthFailed {
+        /// Service name
+        service: String,
+    },
+    /// An established control channel session ended
+    Disconnected {
+        /// Service name
+        service: String,
+        /// Why the session ended
+        reason: String,
+    },
+    /// A control channel failed and will reconnect after `delay`
+    Reconnecting {
+        /// Service name
+        service: String,
+        /// Reconnection attempt number (starting at 1)
+        attempt: u32,
+        /// Delay before the next attempt
+        delay: Duration,
+    },
+    /// The server asked for a new data channel
+    DataChannel {
+        /// Service name
+        service: String,
+    },
+    /// A data channel started forwarding to the service handler
+    ConnectionOpened {
+        /// Service name
+        service: String,
+    },
+    /// A data channel finished forwarding
+    ConnectionClosed {
+        /// Service name
+        service: String,
+        /// Summary reported by the service handler, if it completed cleanly
+        summary: Option<ConnectionSummary>,
+    },
+}
+
+/// Callbacks for client lifecycle events
+///
+/// Every method defaults to a no-op, so implementors override only the
+/// events they care about, or [`on_event`](EventListener::on_event) to see
+/// them all. Methods are called from the control and data channel tasks
+/// and must not block.
+pub trait EventListener: Send + Sync {
+    /// Called for every event; dispatches to the specific methods below
+    fn on_event(&self, event: &ClientEvent) {
+        match event {
+            ClientEvent::Connected { service } => self.on_connected(service),
+            ClientEvent::AuthFailed { service } => self.on_auth_failed(service),
+            ClientEvent::Disconnected { service, reason } => self.on_disconnected(service, reason),
+            ClientEvent::Reconnecting {
+                service,
+                attempt,
+                delay,
+            } => self.on_reconnecting(service, *attempt, *delay),
+            ClientEvent::DataChannel { service } => self.on_data_channel_requested(service),
+            ClientEvent::ConnectionOpened { service } => self.on_data_channel_opened(service),
+            ClientEvent::ConnectionClosed { service, summary } => {
+                self.on_data_channel_closed(service, summary.as_ref())
+            }
+        }
+    }
+
+    /// A control channel authenticated and is up
+    fn on_connected(&self, _service: &str) {}
+
+    /// The server rejected the control channel's token
+    fn on_auth_failed(&self, _service: &str) {}
+
+    /// An established control channel session ended
+    fn on_disconnected(&self, _service: &str, _reason: &str) {}
+
+    /// A control channel will reconnect after `delay`
+    fn on_reconnecting(&self, _service: &str, _attempt: u32, _delay: Duration) {}
+
+    /// The server asked for a new data channel
+    fn on_data_channel_requested(&self, _service: &str) {}
+
+    /// A data channel started forwarding to the service handler
+    fn on_data_channel_opened(&self, _service: &str) {}
+
+    /// A data channel finished forwarding
+    fn on_data_channel_closed(&self, _service: &str, _summary: Option<&ConnectionSummary>) {}
+}
+
+/// Cloneable sending side of the client event channel
+#[derive(Clone)]
+pub struct EventSender {
+    tx: broadcast::Sender<ClientEvent>,
+    listener: Option<Arc<dyn EventListener>>,
+}
+
+impl EventSender {
+    /// Create a new event channel
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        EventSender { tx, listener: None }
+    }
+
+    /// Also hand every event to `listener`
+    pub fn with_listener(mut self, listener: Arc<dyn EventListener>) -> Self {
+        self.listener = Some(listener);
+        self
+    }
+
+    /// Subscribe to events emitted from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<ClientEvent> {
+        self.tx.subscribe()
+    }
+
+    /// Emit an event to all current subscribers
+    pub fn emit(&self, event: ClientEvent) {
+        if let Some(listener) = &self.listener {
+            listener.on_event(&event);
+        }
+        // An error only means nobody is listening
+        let _ = self.tx.send(event);
+    }
+}
+
+impl fmt::Debug for EventSender {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EventSender")
+            .field("subscribers", &self.tx.receiver_count())
+            .field("listener", &self.listener.is_some())
+            .finish()
+    }
+}
+
+impl Default for EventSender {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_emit_without_subscribers() {
+        let events = EventSender::new();
+        events.emit(ClientEvent::Connected {
+            service: "svc".to_string(),
+        });
+    }
+
+    #[test]
+    fn test_listener_dispatch() {
+        #[derive(Default)]
+        struct Counting(std::sync::Mutex<Vec<String>>);
+
+        impl EventListener for Counting {
+            fn on_connected(&self, service: &str) {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(format!("connected {}", service));
+            }
+
+            fn on_disconnected(&self, service: &str, reason: &str) {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(format!("disconnected {}: {}", service, reason));
+            }
+        }
+
+        let listener = Arc::new(Counting::default());
+        let events = EventSender::new().with_listener(listener.clone());
+        events.emit(ClientEvent::Connected {
+            service: "svc".to_string(),
+        });
+        // No override: the default is a no-op
+        events.emit(ClientEvent::DataChannel {
+            service: "svc".to_string(),
+        });
+        events.emit(ClientEvent::Disconnected {
+            service: "svc".to_string(),
+            reason: "heartbeat timeout".to_string(),
+        });
+
+        assert_eq!(
+            *listener.0.lock().unwrap(),
+            ["connected svc", "disconnected svc: heartbeat timeout"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_receives_in_order() {
+        let events = EventSender::new();
+        let mut rx = events.subscribe();
+
+        events.emit(ClientEvent::Connected {
+            service: "svc".to_string(),
+        });
+        events.emit(ClientEvent::DataChannel {
+            service: "svc".to_string(),
+        });
+
+        assert_eq!(
+            rx.recv().await.unwrap(),
+            ClientEvent::Connected {
+                service: "svc".to_string()
+            }
+        );
+        assert_eq!(
+            rx.recv().await.unwrap(),
+            ClientEvent::DataChannel {
+                service: "svc".to_string()
+            }
+        );
+    }
+}
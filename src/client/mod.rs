@@ -3,26 +3,75 @@
 //! This module contains the main client logic for connecting to
 //! the rathole server and handling SOCKS5 requests.
 
+mod backoff;
 #[allow(clippy::module_inception)]
 mod client;
 mod control_channel;
 mod data_channel;
+mod events;
+mod validate;
 
+pub use backoff::Backoff;
 pub use client::Client;
 pub use control_channel::ControlChannel;
 pub use data_channel::run_data_channel;
+pub use events::{ClientEvent, EventListener, EventSender, EVENT_CHANNEL_CAPACITY};
+pub use validate::{validate_config, ValidationCheck, ValidationReport};
 
-use crate::config::Config;
+use crate::config::{ClientConfig, Config};
+#[cfg(all(feature = "wireguard", feature = "socks"))]
+use crate::services::socks::TransportDialer;
 #[cfg(feature = "noise")]
 use crate::transport::NoiseTransport;
-use crate::transport::TcpTransport;
+#[cfg(feature = "tls")]
+use crate::transport::TlsTransport;
 #[cfg(feature = "wireguard")]
 use crate::transport::WireguardTransport;
+use crate::transport::{TcpTransport, Transport};
 use anyhow::Result;
-use tokio::sync::broadcast;
+use std::sync::Arc;
+use tokio::sync::{broadcast, mpsc};
 
 /// Run the client with the given configuration
 pub async fn run_client(config: Config, shutdown_rx: broadcast::Receiver<bool>) -> Result<()> {
+    let (_reload_tx, reload_rx) = mpsc::channel(1);
+    run_client_with_reload(config, shutdown_rx, reload_rx).await
+}
+
+/// Run the client with the given configuration, calling `listener` for
+/// each of its lifecycle events
+///
+/// See [`Client::with_event_listener`] for how events are delivered.
+pub async fn run_client_with_listener(
+    config: Config,
+    shutdown_rx: broadcast::Receiver<bool>,
+    listener: Option<Arc<dyn EventListener>>,
+) -> Result<()> {
+    let (_reload_tx, reload_rx) = mpsc::channel(1);
+    start(config, shutdown_rx, reload_rx, listener).await
+}
+
+/// Run the client with the given configuration, applying the
+/// configurations received on `reload_rx` while it runs
+///
+/// See [`Client::with_reload`] for what a reload may change.
+pub async fn run_client_with_reload(
+    config: Config,
+    shutdown_rx: broadcast::Receiver<bool>,
+    reload_rx: mpsc::Receiver<ClientConfig>,
+) -> Result<()> {
+    start(config, shutdown_rx, reload_rx, None).await
+}
+
+/// Build the client for the configured transport and run it
+async fn start(
+    config: Config,
+    shutdown_rx: broadcast::Receiver<bool>,
+    reload_rx: mpsc::Receiver<ClientConfig>,
+    listener: Option<Arc<dyn EventListener>>,
+) -> Result<()> {
+    // Only the WireGuard setup below mutates the config
+    #[cfg_attr(not(feature = "wireguard"), allow(unused_mut))]
     let mut client_config = config.client;
 
     // Check WireGuard tunnel (separate layer, not a transport type)
@@ -42,27 +91,57 @@ pub async fn run_client(config: Config, shutdown_rx: broadcast::Receiver<bool>)
         client_config.transport.wireguard = client_config.wireguard.clone();
 
         let client = Client::<WireguardTransport>::new(client_config).await?;
-        return client.run(shutdown_rx).await;
+
+        // SOCKS5 egress follows the tunnel rather than the host network
+        #[cfg(feature = "socks")]
+        let client = {
+            let dialer = TransportDialer::new(client.transport());
+            client.with_socks_dialer(Arc::new(dialer))
+        };
+        return run(client, shutdown_rx, reload_rx, listener).await;
     }
 
     // Existing transport selection (unchanged when WireGuard disabled)
     match client_config.transport.transport_type {
         crate::config::TransportType::Tcp => {
             let client = Client::<TcpTransport>::new(client_config).await?;
-            client.run(shutdown_rx).await
+            run(client, shutdown_rx, reload_rx, listener).await
         }
         #[cfg(feature = "noise")]
         crate::config::TransportType::Noise => {
             let client = Client::<NoiseTransport>::new(client_config).await?;
-            client.run(shutdown_rx).await
+            run(client, shutdown_rx, reload_rx, listener).await
         }
         #[cfg(not(feature = "noise"))]
         crate::config::TransportType::Noise => {
             anyhow::bail!("Noise transport is not enabled. Recompile with --features noise")
         }
+        #[cfg(feature = "tls")]
+        crate::config::TransportType::Tls => {
+            let client = Client::<TlsTransport>::new(client_config).await?;
+            run(client, shutdown_rx, reload_rx, listener).await
+        }
+        #[cfg(not(feature = "tls"))]
+        crate::config::TransportType::Tls => {
+            anyhow::bail!("TLS transport is not enabled. Recompile with --features tls")
+        }
     }
 }
 
+/// Run `client` with the reload channel and event listener attached
+async fn run<T: Transport + 'static>(
+    client: Client<T>,
+    shutdown_rx: broadcast::Receiver<bool>,
+    reload_rx: mpsc::Receiver<ClientConfig>,
+    listener: Option<Arc<dyn EventListener>>,
+) -> Result<()> {
+    let client = match listener {
+        Some(listener) => client.with_event_listener(listener),
+        None => client,
+    };
+    client.with_reload(reload_rx).run(shutdown_rx).await
+}
+
 #[cfg(test)]
 mod tests {
     #[test]
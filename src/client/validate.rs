@@ -0,0 +1,241 @@
+//! Offline configuration check
+//!
+//! [`validate_config`] runs the checks the client would otherwise hit one
+//! by one at startup (service handlers, transport and WireGuard settings,
+//! reconnect, pool and audit options) and collects every result into a
+//! [`ValidationReport`], without opening any connection.
+
+use crate::config::{Config, TransportType};
+use crate::services::{create_service_handler, ServiceRegistry};
+use std::fmt;
+
+/// Outcome of one configuration check
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationCheck {
+    /// What was checked, e.g. `service 'socks5'`
+    pub subject: String,
+    /// `Err` holds the reason the check failed
+    pub result: Result<(), String>,
+}
+
+/// Results of [`validate_config`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ValidationReport {
+    /// Every check run, in order
+    pub checks: Vec<ValidationCheck>,
+}
+
+impl ValidationReport {
+    /// Whether every check passed
+    pub fn is_ok(&self) -> bool {
+        self.checks.iter().all(|check| check.result.is_ok())
+    }
+
+    /// The checks that failed
+    pub fn errors(&self) -> impl Iterator<Item = &ValidationCheck> {
+        self.checks.iter().filter(|check| check.result.is_err())
+    }
+
+    fn push(&mut self, subject: impl Into<String>, result: Result<(), String>) {
+        self.checks.push(ValidationCheck {
+            subject: subject.into(),
+            result,
+        });
+    }
+}
+
+impl fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for check in &self.checks {
+            match &check.result {
+                Ok(()) => writeln!(f, "ok     {}", check.subject)?,
+                Err(e) => writeln!(f, "error  {}: {}", check.subject, e)?,
+            }
+        }
+        match self.errors().count() {
+            0 => write!(f, "Configuration is valid"),
+            1 => write!(f, "1 problem found"),
+            n => write!(f, "{} problems found", n),
+        }
+    }
+}
+
+/// Check `config` without connecting anywhere
+pub fn validate_config(config: &Config) -> ValidationReport {
+    let client = &config.client;
+    let mut report = ValidationReport::default();
+
+    report.push(
+        "remote address",
+        if client.remote_endpoints().is_empty() {
+            Err("no remote_addr or remote_addrs configured".to_string())
+        } else {
+            Ok(())
+        },
+    );
+    report.push("reconnect", client.reconnect.validate());
+    if client.client_id.is_some() {
+        report.push("client id", client.validate_client_id());
+    }
+    report.push("pool", client.pool.validate());
+    if let Some(audit) = &client.audit {
+        report.push("audit", audit.validate());
+    }
+
+    let transport = &client.transport;
+    let transport_result = match transport.transport_type {
+        TransportType::Noise if transport.noise.is_none() => {
+            Err("Noise configuration required for Noise transport".to_string())
+        }
+        TransportType::Tls => transport.tls.as_ref().map_or(Ok(()), |tls| tls.validate()),
+        _ => Ok(()),
+    };
+    report.push("transport", transport_result);
+
+    #[cfg(feature = "noise")]
+    if transport.transport_type == TransportType::Noise {
+        if let Some(noise) = &transport.noise {
+            let result = crate::transport::NoiseTransport::with_config(
+                noise,
+                crate::transport::SocketOpts::default(),
+            )
+            .map(|_| ())
+            .map_err(|e| format!("{:#}", e));
+            report.push("noise", result);
+        }
+    }
+
+    #[cfg(feature = "wireguard")]
+    if let Some(wireguard) = &client.wireguard {
+        let result = if wireguard.enabled && transport.transport_type != TransportType::Tcp {
+            Err(format!(
+                "WireGuard tunnel requires transport type 'tcp', got '{:?}'",
+                transport.transport_type
+            ))
+        } else {
+            wireguard.validate_offline().map_err(|e| format!("{:#}", e))
+        };
+        report.push("wireguard", result);
+    }
+
+    let services = client.effective_services();
+    if services.is_empty() {
+        report.push("services", Err("no services configured".to_string()));
+        return report;
+    }
+    let mut registry = ServiceRegistry::new();
+    for service in &services {
+        let result = create_service_handler(service)
+            .map(|handler| registry.register(service.name.clone(), handler))
+            .map_err(|e| format!("{:#}", e));
+        report.push(format!("service '{}'", service.name), result);
+    }
+    report.push(
+        "services",
+        registry
+            .validate_all(&services)
+            .map_err(|e| format!("{:#}", e)),
+    );
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse_config;
+
+    #[test]
+    fn test_valid_config_passes() {
+        let config = parse_config(
+            r#"
+[client]
+remote_addr = "server.example.com:2333"
+service_name = "socks5"
+token = "secret"
+"#,
+        )
+        .unwrap();
+
+        let report = validate_config(&config);
+        assert!(report.is_ok(), "{}", report);
+        assert!(report.to_string().ends_with("Configuration is valid"));
+    }
+
+    #[test]
+    fn test_every_problem_is_reported() {
+        let config = parse_config(
+            r#"
+[client]
+service_name = "socks5"
+token = "secret"
+
+[client.socks]
+auth_required = true
+
+[client.pool]
+min_tcp_channels = 4
+max_tcp_channels = 2
+"#,
+        )
+        .unwrap();
+
+        let report = validate_config(&config);
+        let failed: Vec<&str> = report.errors().map(|c| c.subject.as_str()).collect();
+        assert_eq!(failed, ["remote address", "pool", "service 'socks5'"]);
+        assert!(report.to_string().ends_with("3 problems found"));
+    }
+
+    #[cfg(feature = "wireguard")]
+    #[test]
+    fn test_wireguard_endpoint_is_not_resolved() {
+        let key = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+        let config = parse_config(&format!(
+            r#"
+[client]
+remote_addr = "10.0.0.1:2333"
+service_name = "socks5"
+token = "secret"
+
+[client.wireguard]
+enabled = true
+private_key = "{key}"
+peer_public_key = "{key}"
+peer_endpoint = "wg-gateway.invalid:51820"
+"#
+        ))
+        .unwrap();
+
+        let report = validate_config(&config);
+        assert!(report.is_ok(), "{}", report);
+    }
+
+    #[test]
+    fn test_service_keepalive_is_checked() {
+        let config = parse_config(
+            r#"
+[client]
+remote_addr = "server.example.com:2333"
+token = "secret"
+
+[[client.services]]
+name = "proxy"
+service_type = "socks5"
+
+[client.services.keepalive]
+keepalive_secs = 0
+"#,
+        )
+        .unwrap();
+
+        let report = validate_config(&config);
+        let failed: Vec<&ValidationCheck> = report.errors().collect();
+        assert_eq!(failed.len(), 1, "{}", report);
+        assert_eq!(failed[0].subject, "service 'proxy'");
+        assert!(failed[0]
+            .result
+            .as_ref()
+            .unwrap_err()
+            .contains("keepalive_secs"));
+    }
+}
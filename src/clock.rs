@@ -0,0 +1,127 @@
+//! Time source abstraction
+//!
+//! Code that measures idle periods or waits out timeouts takes a [`Clock`]
+//! instead of calling `Instant::now()` and `tokio::time::sleep` directly,
+//! so tests can swap in a [`MockClock`] and advance time instantly.
+
+use async_trait::async_trait;
+use std::fmt::Debug;
+use std::time::{Duration, Instant};
+
+/// A source of the current time and of sleeps
+#[async_trait]
+pub trait Clock: Send + Sync + Debug {
+    /// The current instant
+    fn now(&self) -> Instant;
+
+    /// Wait until `duration` has elapsed on this clock
+    async fn sleep(&self, duration: Duration);
+}
+
+/// The real clock, backed by the Tokio timer
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TokioClock;
+
+#[async_trait]
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        tokio::time::Instant::now().into_std()
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        tokio::time::sleep(duration).await
+    }
+}
+
+/// A clock that only moves when told to
+///
+/// Sleeps complete as soon as [`advance`](MockClock::advance) moves the
+/// clock past their deadline.
+#[cfg(any(test, feature = "test-support"))]
+#[derive(Debug)]
+pub struct MockClock {
+    now: std::sync::Mutex<Instant>,
+    advanced: tokio::sync::Notify,
+}
+
+#[cfg(any(test, feature = "test-support"))]
+impl MockClock {
+    /// Create a mock clock starting at the current real instant
+    pub fn new() -> Self {
+        MockClock {
+            now: std::sync::Mutex::new(Instant::now()),
+            advanced: tokio::sync::Notify::new(),
+        }
+    }
+
+    /// Move the clock forward, waking sleeps whose deadline has passed
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+        self.advanced.notify_waiters();
+    }
+}
+
+#[cfg(any(test, feature = "test-support"))]
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(any(test, feature = "test-support"))]
+#[async_trait]
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        let deadline = self.now() + duration;
+        loop {
+            let advanced = self.advanced.notified();
+            tokio::pin!(advanced);
+            // Register before checking so an advance in between is not missed
+            advanced.as_mut().enable();
+            if self.now() >= deadline {
+                return;
+            }
+            advanced.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_tokio_clock_sleeps() {
+        let clock = TokioClock;
+        let start = clock.now();
+        clock.sleep(Duration::from_millis(10)).await;
+        assert!(clock.now() - start >= Duration::from_millis(10));
+    }
+
+    #[tokio::test]
+    async fn test_mock_clock_sleep_completes_on_advance() {
+        let clock = Arc::new(MockClock::new());
+        let start = clock.now();
+        let sleeper = {
+            let clock = clock.clone();
+            tokio::spawn(async move { clock.sleep(Duration::from_secs(3600)).await })
+        };
+
+        tokio::task::yield_now().await;
+        clock.advance(Duration::from_secs(1800));
+        tokio::task::yield_now().await;
+        assert!(!sleeper.is_finished());
+
+        clock.advance(Duration::from_secs(1800));
+        tokio::time::timeout(Duration::from_secs(1), sleeper)
+            .await
+            .expect("sleep should end once the clock passes its deadline")
+            .unwrap();
+        assert_eq!(clock.now() - start, Duration::from_secs(3600));
+    }
+}
@@ -0,0 +1,305 @@
+//! Destination access control for the SOCKS5 service
+//!
+//! Defines the `[client.socks.acl]` allow/deny rule lists. Each rule names
+//! any combination of a CIDR range, a domain suffix and a port range, and
+//! matches a target when all of the criteria it names do.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// An IP network such as `10.0.0.0/8`; a bare address is a single host
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct IpCidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpCidr {
+    /// Whether `ip` falls inside the network. IPv4-mapped IPv6 addresses are
+    /// judged by their IPv4 address.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpCidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("invalid CIDR address: {:?}", s))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(|| format!("invalid CIDR prefix length: {:?}", s))?,
+            None => max,
+        };
+        Ok(IpCidr { addr, prefix })
+    }
+}
+
+impl TryFrom<String> for IpCidr {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<IpCidr> for String {
+    fn from(cidr: IpCidr) -> Self {
+        cidr.to_string()
+    }
+}
+
+impl fmt::Display for IpCidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// A single allow or deny rule
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AclRule {
+    /// Target IP network (checked against the resolved address of domains)
+    #[serde(default)]
+    pub cidr: Option<IpCidr>,
+
+    /// Domain suffix such as `example.com`, matching the domain itself and
+    /// its subdomains; only domain targets can match
+    #[serde(default)]
+    pub domain_suffix: Option<String>,
+
+    /// Inclusive `[low, high]` target port range
+    #[serde(default)]
+    pub ports: Option<(u16, u16)>,
+}
+
+impl AclRule {
+    /// Whether the rule matches the target. `None` means the answer depends
+    /// on an address that is not known yet, because the domain has not been
+    /// resolved.
+    fn matches(&self, domain: Option<&str>, ip: Option<IpAddr>, port: u16) -> Option<bool> {
+        if let Some((low, high)) = self.ports {
+            if !(low..=high).contains(&port) {
+                return Some(false);
+            }
+        }
+        if let Some(suffix) = &self.domain_suffix {
+            if !domain.is_some_and(|domain| has_domain_suffix(domain, suffix)) {
+                return Some(false);
+            }
+        }
+        match &self.cidr {
+            Some(cidr) => ip.map(|ip| cidr.contains(ip)),
+            None => Some(true),
+        }
+    }
+}
+
+/// Whether `domain` is `suffix` or one of its subdomains, ignoring case
+fn has_domain_suffix(domain: &str, suffix: &str) -> bool {
+    let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+    let suffix = suffix
+        .trim_start_matches('.')
+        .trim_end_matches('.')
+        .to_ascii_lowercase();
+    domain == suffix || domain.ends_with(&format!(".{}", suffix))
+}
+
+/// Allow and deny rules for SOCKS5 CONNECT targets and UDP datagrams
+///
+/// Deny rules take precedence. When `allow` is empty every target not
+/// denied is allowed, so an empty ACL changes nothing.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AclConfig {
+    /// Targets that may be reached (all when empty)
+    #[serde(default)]
+    pub allow: Vec<AclRule>,
+
+    /// Targets that are refused, even if allowed
+    #[serde(default)]
+    pub deny: Vec<AclRule>,
+}
+
+impl AclConfig {
+    /// Whether there are no rules at all
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    /// Whether a target may be reached
+    ///
+    /// `domain` is the requested domain, if the client sent one, and `ip`
+    /// the target address once known. Before a domain is resolved, rules
+    /// that depend on the address give it the benefit of the doubt; call
+    /// again with the resolved address for the final answer.
+    pub fn permits(&self, domain: Option<&str>, ip: Option<IpAddr>, port: u16) -> bool {
+        if self
+            .deny
+            .iter()
+            .any(|rule| rule.matches(domain, ip, port) == Some(true))
+        {
+            return false;
+        }
+        self.allow.is_empty()
+            || self
+                .allow
+                .iter()
+                .any(|rule| rule.matches(domain, ip, port) != Some(false))
+    }
+
+    /// Validate the configuration
+    pub fn validate(&self) -> Result<(), String> {
+        for rule in self.allow.iter().chain(&self.deny) {
+            if rule.cidr.is_none() && rule.domain_suffix.is_none() && rule.ports.is_none() {
+                return Err("acl rules need at least one of cidr, domain_suffix or ports".into());
+            }
+            if let Some((low, high)) = rule.ports {
+                if low > high {
+                    return Err(format!(
+                        "acl ports must satisfy low <= high (got {}-{})",
+                        low, high
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> Option<IpAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    fn cidr(s: &str) -> AclRule {
+        AclRule {
+            cidr: Some(s.parse().unwrap()),
+            ..Default::default()
+        }
+    }
+
+    fn suffix(s: &str) -> AclRule {
+        AclRule {
+            domain_suffix: Some(s.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_cidr_contains() {
+        let net: IpCidr = "10.0.0.0/8".parse().unwrap();
+        assert!(net.contains("10.1.2.3".parse().unwrap()));
+        assert!(net.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!net.contains("11.0.0.1".parse().unwrap()));
+
+        let host: IpCidr = "2001:db8::1".parse().unwrap();
+        assert!(host.contains("2001:db8::1".parse().unwrap()));
+        assert!(!host.contains("2001:db8::2".parse().unwrap()));
+
+        let all: IpCidr = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains("203.0.113.7".parse().unwrap()));
+
+        for bad in ["10.0.0.0/33", "example.com/8", "10.0.0.0/x"] {
+            assert!(bad.parse::<IpCidr>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_empty_acl_allows_everything() {
+        let acl = AclConfig::default();
+        assert!(acl.permits(Some("example.com"), None, 443));
+        assert!(acl.permits(None, ip("10.0.0.1"), 22));
+    }
+
+    #[test]
+    fn test_domain_suffix_before_resolution() {
+        let acl = AclConfig {
+            deny: vec![suffix("internal.example.com")],
+            ..Default::default()
+        };
+        assert!(!acl.permits(Some("db.internal.example.com"), None, 5432));
+        assert!(!acl.permits(Some("INTERNAL.example.com."), None, 5432));
+        assert!(acl.permits(Some("www.example.com"), None, 443));
+        assert!(acl.permits(Some("notinternal.example.com"), None, 443));
+    }
+
+    #[test]
+    fn test_cidr_after_resolution() {
+        let acl = AclConfig {
+            allow: vec![cidr("203.0.113.0/24")],
+            ..Default::default()
+        };
+        // Undecided until the domain resolves
+        assert!(acl.permits(Some("app.example.com"), None, 443));
+        assert!(acl.permits(Some("app.example.com"), ip("203.0.113.9"), 443));
+        assert!(!acl.permits(Some("app.example.com"), ip("198.51.100.1"), 443));
+        assert!(!acl.permits(None, ip("198.51.100.1"), 443));
+    }
+
+    #[test]
+    fn test_deny_takes_precedence() {
+        let acl = AclConfig {
+            allow: vec![suffix("example.com")],
+            deny: vec![AclRule {
+                ports: Some((22, 22)),
+                ..Default::default()
+            }],
+        };
+        assert!(acl.permits(Some("git.example.com"), ip("203.0.113.9"), 443));
+        assert!(!acl.permits(Some("git.example.com"), ip("203.0.113.9"), 22));
+        assert!(!acl.permits(Some("example.org"), ip("203.0.113.9"), 443));
+    }
+
+    #[test]
+    fn test_acl_parse_and_validate() {
+        let acl: AclConfig = toml::from_str(
+            r#"
+[[allow]]
+domain_suffix = "example.com"
+ports = [443, 443]
+
+[[deny]]
+cidr = "10.0.0.0/8"
+"#,
+        )
+        .unwrap();
+        assert!(acl.validate().is_ok());
+        assert_eq!(acl.deny[0].cidr, Some("10.0.0.0/8".parse().unwrap()));
+
+        assert!(toml::from_str::<AclConfig>("[[deny]]\ncidr = \"10.0.0.0/40\"").is_err());
+
+        let acl = AclConfig {
+            allow: vec![AclRule::default()],
+            ..Default::default()
+        };
+        assert!(acl.validate().is_err());
+    }
+}
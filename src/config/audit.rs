@@ -0,0 +1,42 @@
+//! Audit log configuration
+//!
+//! Defines configuration for the dedicated audit log (`[client.audit]`).
+
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// Audit log configuration
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct AuditConfig {
+    /// File audit records are appended to, one JSON object per line
+    pub path: PathBuf,
+}
+
+impl AuditConfig {
+    /// Validate the configuration
+    pub fn validate(&self) -> Result<(), String> {
+        if self.path.as_os_str().is_empty() {
+            return Err("audit.path must not be empty".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_audit_config_validate() {
+        let config = AuditConfig {
+            path: PathBuf::from("/var/log/sockrats/audit.jsonl"),
+        };
+        assert!(config.validate().is_ok());
+
+        let config = AuditConfig {
+            path: PathBuf::new(),
+        };
+        assert!(config.validate().is_err());
+    }
+}
@@ -0,0 +1,209 @@
+//! Programmatic configuration builder
+//!
+//! [`ConfigBuilder`] starts from the defaults a minimal TOML file gets and
+//! only sets what the caller asks for, so code that constructs a [`Config`]
+//! keeps compiling as options are added.
+
+use super::{
+    AuditConfig, ClientConfig, Config, PoolConfig, ReconnectConfig, ServiceConfig, SocksConfig,
+    TransportConfig,
+};
+use crate::services::ssh::SshConfig;
+
+/// Fluent builder for [`Config`]
+#[derive(Debug, Clone, Default)]
+pub struct ConfigBuilder {
+    client: ClientConfig,
+}
+
+impl ConfigBuilder {
+    /// Create a builder with every option at its default
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the rathole server address
+    pub fn remote_addr(mut self, addr: impl Into<String>) -> Self {
+        self.client.remote_addr = addr.into();
+        self
+    }
+
+    /// Set the rathole server addresses in failover order
+    pub fn remote_addrs<I, S>(mut self, addrs: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.client.remote_addrs = addrs.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Set the single-service shorthand name and token
+    pub fn service(mut self, name: impl Into<String>, token: impl Into<String>) -> Self {
+        self.client.service_name = name.into();
+        self.client.token = token.into();
+        self
+    }
+
+    /// Add an entry to the services list
+    pub fn add_service(mut self, service: ServiceConfig) -> Self {
+        self.client.services.push(service);
+        self
+    }
+
+    /// Set the transport configuration
+    pub fn transport(mut self, transport: TransportConfig) -> Self {
+        self.client.transport = transport;
+        self
+    }
+
+    /// Set the heartbeat timeout in seconds
+    pub fn heartbeat_timeout(mut self, secs: u64) -> Self {
+        self.client.heartbeat_timeout = secs;
+        self
+    }
+
+    /// Set the SOCKS5 configuration of the single-service shorthand
+    pub fn socks(mut self, socks: SocksConfig) -> Self {
+        self.client.socks = socks;
+        self
+    }
+
+    /// Set the SSH configuration of the single-service shorthand
+    pub fn ssh(mut self, ssh: SshConfig) -> Self {
+        self.client.ssh = ssh;
+        self
+    }
+
+    /// Set the connection pool configuration
+    pub fn pool(mut self, pool: PoolConfig) -> Self {
+        self.client.pool = pool;
+        self
+    }
+
+    /// Set the reconnect backoff configuration
+    pub fn reconnect(mut self, reconnect: ReconnectConfig) -> Self {
+        self.client.reconnect = reconnect;
+        self
+    }
+
+    /// Set how long in-flight data channels may run after shutdown, in
+    /// seconds
+    pub fn shutdown_grace_secs(mut self, secs: u64) -> Self {
+        self.client.shutdown_grace_secs = secs;
+        self
+    }
+
+    /// Enable audit logging
+    pub fn audit(mut self, audit: AuditConfig) -> Self {
+        self.client.audit = Some(audit);
+        self
+    }
+
+    /// Build the configuration
+    ///
+    /// As with [`parse_config`](super::parse_config), the single-service
+    /// shorthand is normalized into `client.services`.
+    pub fn build(self) -> Config {
+        let mut client = self.client;
+        client.normalize_services();
+        Config { client }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{parse_config, TcpConfig};
+
+    /// Compare configs through their serialized form, which covers every field
+    fn assert_same(built: &Config, parsed: &Config) {
+        assert_eq!(
+            serde_json::to_value(built).unwrap(),
+            serde_json::to_value(parsed).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_builder_matches_minimal_toml() {
+        let built = ConfigBuilder::new()
+            .remote_addr("server.example.com:2333")
+            .service("socks5", "secret-token")
+            .build();
+        let parsed = parse_config(
+            r#"
+[client]
+remote_addr = "server.example.com:2333"
+service_name = "socks5"
+token = "secret-token"
+"#,
+        )
+        .unwrap();
+
+        assert_same(&built, &parsed);
+        assert_eq!(built.client.services.len(), 1);
+    }
+
+    #[test]
+    fn test_builder_matches_full_toml() {
+        let built = ConfigBuilder::new()
+            .remote_addrs(["primary.example.com:2333", "backup.example.com:2333"])
+            .service("socks5", "secret-token")
+            .heartbeat_timeout(60)
+            .transport(TransportConfig {
+                tcp: TcpConfig {
+                    nodelay: true,
+                    keepalive_secs: 30,
+                    keepalive_interval: 10,
+                },
+                ..Default::default()
+            })
+            .socks(SocksConfig {
+                auth_required: true,
+                username: Some("user".to_string()),
+                password: Some("pass".to_string()),
+                request_timeout: 15,
+                ..Default::default()
+            })
+            .ssh(SshConfig {
+                username: Some("admin".to_string()),
+                ..Default::default()
+            })
+            .pool(PoolConfig {
+                min_tcp_channels: 4,
+                max_tcp_channels: 20,
+                ..Default::default()
+            })
+            .build();
+        let parsed = parse_config(
+            r#"
+[client]
+remote_addrs = ["primary.example.com:2333", "backup.example.com:2333"]
+service_name = "socks5"
+token = "secret-token"
+heartbeat_timeout = 60
+
+[client.transport.tcp]
+nodelay = true
+keepalive_secs = 30
+keepalive_interval = 10
+
+[client.socks]
+auth_required = true
+username = "user"
+password = "pass"
+request_timeout = 15
+
+[client.ssh]
+username = "admin"
+
+[client.pool]
+min_tcp_channels = 4
+max_tcp_channels = 20
+"#,
+        )
+        .unwrap();
+
+        assert_same(&built, &parsed);
+    }
+}
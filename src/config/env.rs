@@ -0,0 +1,156 @@
+//! Environment variable interpolation
+//!
+//! String values in the configuration may reference environment variables
+//! as `${VAR}`, or as `${VAR:-default}` to fall back to `default` when
+//! `VAR` is unset or empty. `$${` stands for a literal `${`. Only string
+//! values are expanded; keys and comments are left alone.
+
+use anyhow::{bail, Result};
+
+/// Expand variable references in every string of `value`
+///
+/// Returns whether any string changed.
+pub(crate) fn interpolate(value: &mut toml::Value) -> Result<bool> {
+    interpolate_at(value, &mut String::new(), &|name| std::env::var(name).ok())
+}
+
+fn interpolate_at(
+    value: &mut toml::Value,
+    path: &mut String,
+    lookup: &dyn Fn(&str) -> Option<String>,
+) -> Result<bool> {
+    match value {
+        toml::Value::String(s) => {
+            if !s.contains('$') {
+                return Ok(false);
+            }
+            let expanded = expand(s, lookup).map_err(|e| e.context(format!("in `{}`", path)))?;
+            let changed = expanded != *s;
+            *s = expanded;
+            Ok(changed)
+        }
+        toml::Value::Array(items) => {
+            let mut changed = false;
+            for (i, item) in items.iter_mut().enumerate() {
+                let len = path.len();
+                path.push_str(&format!("[{}]", i));
+                changed |= interpolate_at(item, path, lookup)?;
+                path.truncate(len);
+            }
+            Ok(changed)
+        }
+        toml::Value::Table(table) => {
+            let mut changed = false;
+            for (key, item) in table.iter_mut() {
+                let len = path.len();
+                if !path.is_empty() {
+                    path.push('.');
+                }
+                path.push_str(key);
+                changed |= interpolate_at(item, path, lookup)?;
+                path.truncate(len);
+            }
+            Ok(changed)
+        }
+        _ => Ok(false),
+    }
+}
+
+/// Expand the variable references in `s`
+fn expand(s: &str, lookup: &dyn Fn(&str) -> Option<String>) -> Result<String> {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+
+    while let Some(start) = rest.find('$') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start..];
+
+        if let Some(escaped) = after.strip_prefix("$${") {
+            out.push_str("${");
+            rest = escaped;
+            continue;
+        }
+        let Some(reference) = after.strip_prefix("${") else {
+            out.push('$');
+            rest = &after[1..];
+            continue;
+        };
+        let Some(end) = reference.find('}') else {
+            bail!("unterminated `${{` in {:?}", s);
+        };
+
+        let (name, default) = match reference[..end].split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (&reference[..end], None),
+        };
+        if !is_valid_name(name) {
+            bail!("invalid environment variable name {:?}", name);
+        }
+        match (lookup(name), default) {
+            (Some(value), Some(default)) if value.is_empty() => out.push_str(default),
+            (Some(value), _) => out.push_str(&value),
+            (None, Some(default)) => out.push_str(default),
+            (None, None) => bail!("environment variable `{}` is not set", name),
+        }
+        rest = &reference[end + 1..];
+    }
+
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Whether `name` is a portable environment variable name
+fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "TOKEN" => Some("s3cret".to_string()),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_expand() {
+        assert_eq!(expand("${TOKEN}", &lookup).unwrap(), "s3cret");
+        assert_eq!(expand("a-${TOKEN}-b", &lookup).unwrap(), "a-s3cret-b");
+        assert_eq!(expand("${MISSING:-fallback}", &lookup).unwrap(), "fallback");
+        assert_eq!(expand("${EMPTY:-fallback}", &lookup).unwrap(), "fallback");
+        assert_eq!(expand("${EMPTY}", &lookup).unwrap(), "");
+        assert_eq!(expand("${MISSING:-}", &lookup).unwrap(), "");
+        assert_eq!(
+            expand("$$5 and $${TOKEN}", &lookup).unwrap(),
+            "$$5 and ${TOKEN}"
+        );
+    }
+
+    #[test]
+    fn test_expand_errors() {
+        let err = expand("${MISSING}", &lookup).unwrap_err().to_string();
+        assert!(err.contains("`MISSING` is not set"), "{}", err);
+        assert!(expand("${TOKEN", &lookup).is_err());
+        assert!(expand("${1BAD}", &lookup).is_err());
+    }
+
+    #[test]
+    fn test_interpolate_names_the_field() {
+        let mut value: toml::Value =
+            toml::from_str("[client]\nservices = [{ token = \"${MISSING}\" }]\n").unwrap();
+        let err = interpolate_at(&mut value, &mut String::new(), &lookup).unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("client.services[0].token"),
+            "{:#}",
+            err
+        );
+    }
+}
@@ -0,0 +1,167 @@
+//! Example configuration generator
+//!
+//! [`example_config`] renders a [`Config`] holding one service of every
+//! type and every optional section, all at their defaults, so the example
+//! follows the code as options are added.
+
+use super::transport::default_noise_pattern;
+use super::{
+    AuditConfig, ClientConfig, Config, KeepaliveConfig, NoiseConfig, ServiceConfig, ServiceType,
+    SocksConfig, TlsConfig,
+};
+use crate::services::ssh::SshConfig;
+
+/// Comment written above each table, by table path
+const SECTION_COMMENTS: &[(&str, &str)] = &[
+    ("client", "Connection to the rathole server"),
+    (
+        "client.transport",
+        "Transport type: \"tcp\", \"noise\" or \"tls\"",
+    ),
+    ("client.transport.tcp", "TCP socket options"),
+    ("client.transport.noise", "Used when type = \"noise\""),
+    ("client.transport.tls", "Used when type = \"tls\""),
+    ("client.reconnect", "Backoff between reconnect attempts"),
+    (
+        "client.socks",
+        "SOCKS5 server (legacy single-service mode; see [[client.services]])",
+    ),
+    ("client.socks.acl", "Destination access control"),
+    ("client.ssh", "SSH server (legacy single-service mode)"),
+    ("client.pool", "Data channel pool"),
+    (
+        "client.audit",
+        "Audit log; remove to turn audit logging off",
+    ),
+    (
+        "client.wireguard",
+        "WireGuard tunnel; requires transport type \"tcp\"",
+    ),
+    (
+        "client.services",
+        "A service exposed through the server; `name` must match the server config",
+    ),
+    (
+        "client.services.socks",
+        "SOCKS5 options of a socks5 service",
+    ),
+    ("client.services.socks.acl", "Destination access control"),
+    ("client.services.ssh", "SSH options of an ssh service"),
+    ("client.services.vnc", "VNC options of a vncserver service"),
+    (
+        "client.services.keepalive",
+        "TCP keepalive of this service's data channels",
+    ),
+];
+
+/// Render the example configuration as commented TOML
+pub fn example_config() -> String {
+    let body = example()
+        .to_toml_string()
+        .expect("example config serializes");
+
+    let mut out = String::from(
+        "# Sockrats example configuration\n\
+         #\n\
+         # Every option is shown at its default. Options that are unset by\n\
+         # default are omitted; see examples/config.toml for those.\n",
+    );
+    for line in body.lines() {
+        let path = line.trim_matches(|c| c == '[' || c == ']');
+        if line.starts_with('[') {
+            out.push('\n');
+            if let Some((_, comment)) = SECTION_COMMENTS.iter().find(|(p, _)| *p == path) {
+                out.push_str("# ");
+                out.push_str(comment);
+                out.push('\n');
+            }
+        }
+        if !line.is_empty() {
+            out.push_str(line);
+            out.push('\n');
+        }
+    }
+    out
+}
+
+/// The configuration [`example_config`] renders
+fn example() -> Config {
+    let service = |name: &str, service_type| ServiceConfig {
+        name: name.to_string(),
+        service_type,
+        token: "your-secret-token".to_string(),
+        socks: None,
+        ssh: None,
+        keepalive: None,
+        #[cfg(feature = "vncserver")]
+        vnc: None,
+    };
+
+    // Only the feature-gated services below are pushed
+    #[cfg_attr(
+        not(any(feature = "vncserver", feature = "metrics")),
+        allow(unused_mut)
+    )]
+    let mut services = vec![
+        ServiceConfig {
+            socks: Some(SocksConfig::default()),
+            keepalive: Some(KeepaliveConfig::default()),
+            ..service("socks5", ServiceType::Socks5)
+        },
+        ServiceConfig {
+            ssh: Some(SshConfig::default()),
+            ..service("ssh", ServiceType::Ssh)
+        },
+    ];
+    #[cfg(feature = "vncserver")]
+    services.push(ServiceConfig {
+        vnc: Some(Default::default()),
+        ..service("vnc", ServiceType::VncServer)
+    });
+    #[cfg(feature = "metrics")]
+    services.push(service("metrics", ServiceType::Metrics));
+
+    let mut client = ClientConfig {
+        remote_addr: "server.example.com:2333".to_string(),
+        services,
+        audit: Some(AuditConfig {
+            path: "/var/log/sockrats/audit.jsonl".into(),
+        }),
+        #[cfg(feature = "wireguard")]
+        wireguard: Some(Default::default()),
+        ..Default::default()
+    };
+    client.transport.noise = Some(NoiseConfig {
+        pattern: default_noise_pattern(),
+        local_private_key: None,
+        static_key_file: None,
+        remote_public_key: "server-public-key-base64".to_string(),
+        psk: None,
+    });
+    client.transport.tls = Some(TlsConfig::default());
+
+    Config { client }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse_config;
+
+    #[test]
+    fn test_example_config_round_trips() {
+        let text = example_config();
+        let config = parse_config(&text).unwrap();
+
+        assert_eq!(config.client.remote_addr, "server.example.com:2333");
+        assert_eq!(
+            config.client.services.len(),
+            example().client.services.len()
+        );
+        assert_eq!(
+            config.to_toml_string().unwrap(),
+            example().to_toml_string().unwrap()
+        );
+        assert!(text.contains("# SOCKS5 options of a socks5 service\n[client.services.socks]\n"));
+    }
+}
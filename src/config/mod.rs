@@ -2,17 +2,32 @@
 //!
 //! This module provides configuration types and parsing for the client.
 
+mod acl;
+mod audit;
+mod builder;
 mod client;
+mod env;
+mod example;
 mod pool;
+mod reconnect;
 mod transport;
 
 #[cfg(feature = "vncserver")]
 pub use crate::services::vncserver::VncConfig;
 #[cfg(feature = "wireguard")]
 pub use crate::transport::wireguard::WireguardConfig;
-pub use client::{ClientConfig, Config, ServiceConfig, ServiceListExt, ServiceType, SocksConfig};
-pub use pool::PoolConfig;
-pub use transport::{NoiseConfig, TcpConfig, TransportConfig, TransportType};
+pub use acl::{AclConfig, AclRule, IpCidr};
+pub use audit::AuditConfig;
+pub use builder::ConfigBuilder;
+pub use client::{
+    ClientConfig, Config, ServiceConfig, ServiceListExt, ServiceType, SocksConfig, UdpDropPolicy,
+};
+pub use example::example_config;
+pub use pool::{PoolConfig, PoolExhaustion};
+pub use reconnect::ReconnectConfig;
+pub use transport::{
+    KeepaliveConfig, NoiseConfig, TcpConfig, TlsConfig, TransportConfig, TransportType,
+};
 
 use anyhow::{Context, Result};
 use std::path::Path;
@@ -26,8 +41,36 @@ pub fn load_config<P: AsRef<Path>>(path: P) -> Result<Config> {
 }
 
 /// Parse configuration from a TOML string
+///
+/// `${VAR}` and `${VAR:-default}` in string values are replaced with the
+/// environment variable `VAR`; a variable that is unset and has no default
+/// is an error. The single-service shorthand is normalized into
+/// `client.services`.
 pub fn parse_config(content: &str) -> Result<Config> {
-    toml::from_str(content).with_context(|| "Failed to parse configuration")
+    let mut value: toml::Value =
+        toml::from_str(content).with_context(|| "Failed to parse configuration")?;
+    let interpolated =
+        env::interpolate(&mut value).with_context(|| "Failed to expand configuration")?;
+
+    // Parse the text when nothing was expanded, so errors keep their location
+    let mut config: Config = if interpolated {
+        value.try_into()
+    } else {
+        toml::from_str(content)
+    }
+    .with_context(|| "Failed to parse configuration")?;
+    config.client.normalize_services();
+    Ok(config)
+}
+
+impl Config {
+    /// Serialize the configuration back to TOML
+    ///
+    /// Unset optional values are left out, so the result parses back into
+    /// an equivalent configuration with [`parse_config`].
+    pub fn to_toml_string(&self) -> Result<String> {
+        toml::to_string(self).with_context(|| "Failed to serialize configuration")
+    }
 }
 
 #[cfg(test)]
@@ -47,6 +90,38 @@ token = "secret-token"
         assert_eq!(config.client.remote_addr, "server.example.com:2333");
         assert_eq!(config.client.service_name, "socks5");
         assert_eq!(config.client.token, "secret-token");
+        assert_eq!(
+            config.client.remote_endpoints(),
+            vec!["server.example.com:2333"]
+        );
+    }
+
+    #[test]
+    fn test_parse_remote_addrs() {
+        let config_str = r#"
+[client]
+remote_addrs = ["primary.example.com:2333", "backup.example.com:2333"]
+service_name = "socks5"
+token = "secret-token"
+"#;
+
+        let config = parse_config(config_str).unwrap();
+        assert_eq!(
+            config.client.remote_endpoints(),
+            vec!["primary.example.com:2333", "backup.example.com:2333"]
+        );
+
+        let config = parse_config(
+            r#"
+[client]
+remote_addrs = "primary.example.com:2333"
+service_name = "socks5"
+token = "secret-token"
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.client.remote_addrs, vec!["primary.example.com:2333"]);
+        assert!(parse_config("[client]\nremote_addrs = 2333\n").is_err());
     }
 
     #[test]
@@ -87,4 +162,118 @@ max_udp_channels = 10
         assert_eq!(config.client.socks.username, Some("user".to_string()));
         assert_eq!(config.client.pool.min_tcp_channels, 4);
     }
+
+    #[test]
+    fn test_parse_service_keepalive() {
+        let config_str = r#"
+[client]
+remote_addr = "server.example.com:2333"
+
+[[client.services]]
+name = "socks5"
+token = "socks-token"
+
+[[client.services]]
+name = "interactive"
+token = "other-token"
+keepalive.keepalive_secs = 10
+"#;
+
+        let config = parse_config(config_str).unwrap();
+        assert_eq!(config.client.services[0].keepalive, None);
+        let keepalive = config.client.services[1].keepalive.as_ref().unwrap();
+        assert_eq!(keepalive.keepalive_secs, 10);
+        assert_eq!(keepalive.keepalive_interval, 8);
+    }
+
+    #[test]
+    fn test_env_interpolation() {
+        std::env::set_var("SOCKRATS_TEST_ENV_TOKEN", "token-from-env");
+        std::env::remove_var("SOCKRATS_TEST_ENV_UNSET");
+        let config_str = r#"
+[client]
+remote_addr = "${SOCKRATS_TEST_ENV_UNSET:-server.example.com}:2333"
+service_name = "socks5"
+token = "${SOCKRATS_TEST_ENV_TOKEN}"
+"#;
+
+        let config = parse_config(config_str).unwrap();
+        assert_eq!(config.client.token, "token-from-env");
+        assert_eq!(config.client.services[0].token, "token-from-env");
+        assert_eq!(config.client.remote_addr, "server.example.com:2333");
+
+        let err = parse_error(
+            r#"
+[client]
+remote_addr = "server.example.com:2333"
+token = "${SOCKRATS_TEST_ENV_UNSET}"
+"#,
+        );
+        assert!(
+            err.contains("`SOCKRATS_TEST_ENV_UNSET` is not set"),
+            "{}",
+            err
+        );
+    }
+
+    fn parse_error(config_str: &str) -> String {
+        format!("{:#}", parse_config(config_str).unwrap_err())
+    }
+
+    #[test]
+    fn test_unknown_keys_are_rejected() {
+        let err = parse_error(
+            r#"
+[client]
+remote_adr = "server.example.com:2333"
+"#,
+        );
+        assert!(err.contains("unknown field `remote_adr`"), "{}", err);
+
+        let err = parse_error(
+            r#"
+[client]
+remote_addr = "server.example.com:2333"
+
+[client.socks]
+alow_udp = true
+"#,
+        );
+        assert!(err.contains("unknown field `alow_udp`"), "{}", err);
+
+        let err = parse_error(
+            r#"
+[client]
+remote_addr = "server.example.com:2333"
+
+[[client.services]]
+name = "socks5"
+token = "socks-token"
+keepalive.keepalive_sec = 10
+"#,
+        );
+        assert!(err.contains("unknown field `keepalive_sec`"), "{}", err);
+    }
+
+    #[test]
+    fn test_server_section_is_tolerated() {
+        let config_str = r#"
+[server]
+bind_addr = "0.0.0.0:2333"
+
+[client]
+remote_addr = "server.example.com:2333"
+service_name = "socks5"
+token = "secret-token"
+"#;
+
+        let config = parse_config(config_str).unwrap();
+        assert_eq!(config.client.service_name, "socks5");
+    }
+
+    #[test]
+    fn test_example_configs_parse() {
+        parse_config(include_str!("../../examples/config.toml")).unwrap();
+        parse_config(include_str!("../../examples/config-minimal.toml")).unwrap();
+    }
 }
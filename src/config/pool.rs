@@ -34,13 +34,33 @@ fn default_health_check_interval() -> u64 {
     30
 }
 
+/// Default idle channel sweep interval in seconds
+fn default_reap_interval() -> u64 {
+    60
+}
+
 /// Default acquire timeout in seconds
 fn default_acquire_timeout() -> u64 {
     10
 }
 
+/// What acquiring a TCP channel does when the pool is at `max_tcp_channels`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PoolExhaustion {
+    /// Wait up to `acquire_timeout` for a channel to be returned
+    #[default]
+    Wait,
+    /// Fail immediately
+    Fail,
+    /// Open an extra channel beyond the maximum; it is closed rather than
+    /// pooled when returned
+    Overflow,
+}
+
 /// Connection pool configuration
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct PoolConfig {
     /// Minimum number of pre-established TCP channels
     #[serde(default = "default_min_tcp_channels")]
@@ -58,17 +78,38 @@ pub struct PoolConfig {
     #[serde(default = "default_max_udp_channels")]
     pub max_udp_channels: usize,
 
-    /// Channel idle timeout in seconds
-    #[serde(default = "default_idle_timeout")]
+    /// Seconds a pooled channel may sit unused before it is closed
+    #[serde(default = "default_idle_timeout", alias = "max_idle_secs")]
     pub idle_timeout: u64,
 
     /// Health check interval in seconds
     #[serde(default = "default_health_check_interval")]
     pub health_check_interval: u64,
 
+    /// Seconds between sweeps that close channels idle past
+    /// `idle_timeout` and replace them up to `min_tcp_channels`
+    /// (0 = expire idle channels only when acquiring)
+    #[serde(default = "default_reap_interval")]
+    pub reap_interval: u64,
+
     /// Maximum time to wait for a channel from the pool
     #[serde(default = "default_acquire_timeout")]
     pub acquire_timeout: u64,
+
+    /// What acquiring does when every channel is in use; `wait` is bounded
+    /// by `acquire_timeout`
+    #[serde(default)]
+    pub on_exhaustion: PoolExhaustion,
+
+    /// Probe idle channels on every health check and evict those the
+    /// server has closed, so stale channels aren't handed out
+    #[serde(default)]
+    pub liveness_probe: bool,
+
+    /// Probe each idle channel before handing it out, discarding it for
+    /// another if the server has closed it
+    #[serde(default)]
+    pub validate_on_acquire: bool,
 }
 
 impl Default for PoolConfig {
@@ -80,7 +121,11 @@ impl Default for PoolConfig {
             max_udp_channels: default_max_udp_channels(),
             idle_timeout: default_idle_timeout(),
             health_check_interval: default_health_check_interval(),
+            reap_interval: default_reap_interval(),
             acquire_timeout: default_acquire_timeout(),
+            on_exhaustion: PoolExhaustion::default(),
+            liveness_probe: false,
+            validate_on_acquire: false,
         }
     }
 }
@@ -114,7 +159,31 @@ mod tests {
         assert_eq!(config.max_udp_channels, 5);
         assert_eq!(config.idle_timeout, 300);
         assert_eq!(config.health_check_interval, 30);
+        assert_eq!(config.reap_interval, 60);
         assert_eq!(config.acquire_timeout, 10);
+        assert_eq!(config.on_exhaustion, PoolExhaustion::Wait);
+        assert!(!config.liveness_probe);
+        assert!(!config.validate_on_acquire);
+    }
+
+    #[test]
+    fn test_pool_config_on_exhaustion() {
+        for (value, expected) in [
+            ("wait", PoolExhaustion::Wait),
+            ("fail", PoolExhaustion::Fail),
+            ("overflow", PoolExhaustion::Overflow),
+        ] {
+            let config: PoolConfig =
+                toml::from_str(&format!("on_exhaustion = \"{}\"", value)).unwrap();
+            assert_eq!(config.on_exhaustion, expected);
+        }
+        assert!(toml::from_str::<PoolConfig>("on_exhaustion = \"block\"").is_err());
+    }
+
+    #[test]
+    fn test_pool_config_max_idle_secs_alias() {
+        let config: PoolConfig = toml::from_str("max_idle_secs = 45").unwrap();
+        assert_eq!(config.idle_timeout, 45);
     }
 
     #[test]
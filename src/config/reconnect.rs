@@ -0,0 +1,105 @@
+//! Reconnection backoff configuration
+//!
+//! Defines how long the control channel waits between reconnect attempts
+//! after its connection fails.
+
+use serde::{Deserialize, Serialize};
+
+/// Default delay before the first retry, in milliseconds
+fn default_initial_delay_ms() -> u64 {
+    1000
+}
+
+/// Default longest delay between retries, in milliseconds
+fn default_max_delay_ms() -> u64 {
+    60_000
+}
+
+/// Default growth factor of the delay per failed attempt
+fn default_multiplier() -> f64 {
+    2.0
+}
+
+/// Default number of consecutive failures before giving up
+fn default_max_retries() -> u32 {
+    10
+}
+
+/// Reconnection backoff configuration
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ReconnectConfig {
+    /// Delay before the first retry, in milliseconds
+    #[serde(default = "default_initial_delay_ms")]
+    pub initial_delay_ms: u64,
+
+    /// Longest delay between retries, in milliseconds
+    #[serde(default = "default_max_delay_ms")]
+    pub max_delay_ms: u64,
+
+    /// Factor the delay grows by after each failed attempt
+    #[serde(default = "default_multiplier")]
+    pub multiplier: f64,
+
+    /// Consecutive failures after which the control channel gives up
+    /// (0 = retry forever)
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        ReconnectConfig {
+            initial_delay_ms: default_initial_delay_ms(),
+            max_delay_ms: default_max_delay_ms(),
+            multiplier: default_multiplier(),
+            max_retries: default_max_retries(),
+        }
+    }
+}
+
+impl ReconnectConfig {
+    /// Validate the reconnect configuration
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.multiplier.is_finite() || self.multiplier < 1.0 {
+            return Err("reconnect multiplier must be a finite number >= 1".to_string());
+        }
+        if self.initial_delay_ms > self.max_delay_ms {
+            return Err(
+                "reconnect initial_delay_ms cannot be greater than max_delay_ms".to_string(),
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reconnect_config_default() {
+        let config = ReconnectConfig::default();
+        assert_eq!(config.initial_delay_ms, 1000);
+        assert_eq!(config.max_delay_ms, 60_000);
+        assert_eq!(config.multiplier, 2.0);
+        assert_eq!(config.max_retries, 10);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_reconnect_config_validate() {
+        let shrinking = ReconnectConfig {
+            multiplier: 0.5,
+            ..Default::default()
+        };
+        assert!(shrinking.validate().is_err());
+
+        let inverted = ReconnectConfig {
+            initial_delay_ms: 5000,
+            max_delay_ms: 1000,
+            ..Default::default()
+        };
+        assert!(inverted.validate().is_err());
+    }
+}
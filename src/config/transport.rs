@@ -1,10 +1,11 @@
 //! Transport configuration types
 //!
-//! Defines configuration for different transport protocols (TCP, Noise).
+//! Defines configuration for different transport protocols (TCP, Noise, TLS).
 
 #[cfg(feature = "wireguard")]
 use crate::transport::wireguard::WireguardConfig;
 use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
 
 /// Transport type enumeration
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
@@ -16,10 +17,14 @@ pub enum TransportType {
     /// Noise protocol encrypted transport
     #[serde(rename = "noise")]
     Noise,
+    /// TLS encrypted transport
+    #[serde(rename = "tls")]
+    Tls,
 }
 
 /// Main transport configuration
 #[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
 pub struct TransportConfig {
     /// Transport type
     #[serde(rename = "type", default)]
@@ -33,6 +38,10 @@ pub struct TransportConfig {
     #[serde(default)]
     pub noise: Option<NoiseConfig>,
 
+    /// TLS configuration (optional; defaults apply when omitted)
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+
     /// WireGuard tunnel configuration.
     ///
     /// This field is NOT deserialized from TOML — it lives at
@@ -56,6 +65,7 @@ fn default_keepalive_interval() -> u64 {
 
 /// TCP transport configuration
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct TcpConfig {
     /// Enable TCP_NODELAY
     #[serde(default)]
@@ -80,8 +90,44 @@ impl Default for TcpConfig {
     }
 }
 
+/// TCP keepalive profile for data-channel sockets
+///
+/// Interactive services (SSH, VNC) benefit from short keepalive timers so
+/// dead peers are noticed quickly; bulk transfers can use relaxed ones.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct KeepaliveConfig {
+    /// TCP keepalive timeout in seconds
+    #[serde(default = "default_keepalive_secs")]
+    pub keepalive_secs: u64,
+
+    /// TCP keepalive interval in seconds
+    #[serde(default = "default_keepalive_interval")]
+    pub keepalive_interval: u64,
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        KeepaliveConfig {
+            keepalive_secs: default_keepalive_secs(),
+            keepalive_interval: default_keepalive_interval(),
+        }
+    }
+}
+
+impl KeepaliveConfig {
+    /// Validate the keepalive profile
+    pub fn validate(&self) -> Result<(), String> {
+        if self.keepalive_secs == 0 || self.keepalive_interval == 0 {
+            return Err("keepalive_secs and keepalive_interval must be greater than 0".to_string());
+        }
+        Ok(())
+    }
+}
+
 /// Noise protocol configuration
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct NoiseConfig {
     /// Noise protocol pattern
     #[serde(default = "default_noise_pattern")]
@@ -90,14 +136,67 @@ pub struct NoiseConfig {
     /// Local private key (base64 encoded)
     pub local_private_key: Option<String>,
 
+    /// File holding the local private key (base64 encoded), in place of
+    /// `local_private_key`. It is read again on a configuration reload, so
+    /// the key can be rotated without restarting.
+    #[serde(default)]
+    pub static_key_file: Option<PathBuf>,
+
     /// Remote public key (base64 encoded)
     pub remote_public_key: String,
+
+    /// Pre-shared key (base64 encoded, 32 bytes), mixed into the handshake
+    /// at the positions the pattern's `psk` modifiers give, e.g.
+    /// `Noise_NKpsk2_25519_ChaChaPoly_BLAKE2s`
+    #[serde(default)]
+    pub psk: Option<String>,
 }
 
-fn default_noise_pattern() -> String {
+pub(super) fn default_noise_pattern() -> String {
     "Noise_NK_25519_ChaChaPoly_BLAKE2s".to_string()
 }
 
+/// TLS transport configuration
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// Server name sent as SNI and checked against the server certificate
+    /// (defaults to the host part of the remote address)
+    #[serde(default)]
+    pub hostname: Option<String>,
+
+    /// PEM bundle of CA certificates to trust instead of the built-in
+    /// web PKI roots
+    #[serde(default)]
+    pub trusted_root: Option<PathBuf>,
+
+    /// PEM client certificate chain for mutual TLS
+    #[serde(default)]
+    pub client_cert: Option<PathBuf>,
+
+    /// PEM private key for `client_cert`
+    #[serde(default)]
+    pub client_key: Option<PathBuf>,
+
+    /// Accept any server certificate. For testing only: this removes all
+    /// protection against an active attacker.
+    #[serde(default)]
+    pub insecure_skip_verify: bool,
+}
+
+impl TlsConfig {
+    /// Validate the configuration
+    pub fn validate(&self) -> Result<(), String> {
+        if self.client_cert.is_some() != self.client_key.is_some() {
+            return Err("tls client_cert and client_key must be set together".to_string());
+        }
+        if self.hostname.as_deref() == Some("") {
+            return Err("tls hostname must not be empty".to_string());
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -115,6 +214,23 @@ mod tests {
         assert_eq!(config.keepalive_interval, 8);
     }
 
+    #[test]
+    fn test_keepalive_config_default() {
+        let config = KeepaliveConfig::default();
+        assert_eq!(config.keepalive_secs, 20);
+        assert_eq!(config.keepalive_interval, 8);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_keepalive_config_validate_zero() {
+        let config = KeepaliveConfig {
+            keepalive_secs: 0,
+            keepalive_interval: 8,
+        };
+        assert!(config.validate().is_err());
+    }
+
     #[test]
     fn test_transport_config_default() {
         let config = TransportConfig::default();
@@ -122,6 +238,31 @@ mod tests {
         assert!(config.noise.is_none());
     }
 
+    #[test]
+    fn test_tls_config_parse_and_validate() {
+        let config: TransportConfig = toml::from_str(
+            r#"
+type = "tls"
+
+[tls]
+hostname = "rathole.example.com"
+trusted_root = "/etc/sockrats/ca.pem"
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.transport_type, TransportType::Tls);
+        let tls = config.tls.unwrap();
+        assert_eq!(tls.hostname.as_deref(), Some("rathole.example.com"));
+        assert!(!tls.insecure_skip_verify);
+        assert!(tls.validate().is_ok());
+
+        let half_mtls = TlsConfig {
+            client_cert: Some("/etc/sockrats/client.pem".into()),
+            ..Default::default()
+        };
+        assert!(half_mtls.validate().is_err());
+    }
+
     #[test]
     fn test_noise_pattern_default() {
         assert_eq!(default_noise_pattern(), "Noise_NK_25519_ChaChaPoly_BLAKE2s");
@@ -67,6 +67,10 @@ pub enum Socks5Error {
     #[error("No acceptable authentication method")]
     NoAcceptableMethod,
 
+    /// Authentication is required but the client did not offer it
+    #[error("Authentication required but the client offered no username/password method")]
+    AuthRequired,
+
     /// Authentication failed
     #[error("Authentication failed")]
     AuthFailed,
@@ -102,6 +106,76 @@ pub enum Socks5Error {
     /// Invalid domain name
     #[error("Invalid domain name: {0}")]
     InvalidDomain(String),
+
+    /// A known command that this server does not carry out
+    #[error("Unsupported command: {0}")]
+    UnsupportedCommand(String),
+
+    /// The target is refused by the ACL or the private network block
+    #[error("Target denied by ruleset")]
+    AclDenied,
+
+    /// The target could not be resolved or connected to
+    #[error("Target unreachable: {0}")]
+    TargetUnreachable(#[source] io::Error),
+
+    /// Relaying to the client or target failed
+    #[error("Relay I/O error: {0}")]
+    RelayIo(#[source] io::Error),
+
+    /// The client did not finish the handshake in time
+    #[error("SOCKS5 handshake timed out")]
+    HandshakeTimeout,
+
+    /// Any other failure
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+#[cfg(feature = "socks")]
+impl Socks5Error {
+    /// Classify a failed lookup or connect as [`Socks5Error::TargetUnreachable`]
+    pub fn target_unreachable(error: anyhow::Error) -> Self {
+        match error.downcast::<io::Error>() {
+            Ok(e) => Socks5Error::TargetUnreachable(e),
+            Err(e) => Socks5Error::TargetUnreachable(io::Error::new(
+                io::ErrorKind::HostUnreachable,
+                format!("{:#}", e),
+            )),
+        }
+    }
+
+    /// The reply code a client is sent for this error
+    pub fn reply_code(&self) -> Socks5ReplyCode {
+        match self {
+            Socks5Error::CommandNotSupported(_) | Socks5Error::UnsupportedCommand(_) => {
+                Socks5ReplyCode::CommandNotSupported
+            }
+            Socks5Error::AddressTypeNotSupported(_) => Socks5ReplyCode::AddressTypeNotSupported,
+            Socks5Error::ConnectionRefused => Socks5ReplyCode::ConnectionRefused,
+            Socks5Error::HostUnreachable => Socks5ReplyCode::HostUnreachable,
+            Socks5Error::NetworkUnreachable => Socks5ReplyCode::NetworkUnreachable,
+            Socks5Error::AclDenied => Socks5ReplyCode::ConnectionNotAllowed,
+            // A target that failed for no more specific reason is unreachable
+            Socks5Error::TargetUnreachable(e) => match Socks5ReplyCode::from(e) {
+                Socks5ReplyCode::GeneralFailure => Socks5ReplyCode::HostUnreachable,
+                code => code,
+            },
+            _ => Socks5ReplyCode::GeneralFailure,
+        }
+    }
+}
+
+/// Typed SOCKS5 errors survive being passed around as [`anyhow::Error`]
+/// (with or without context); anything else becomes [`Socks5Error::Other`]
+#[cfg(feature = "socks")]
+impl From<anyhow::Error> for Socks5Error {
+    fn from(error: anyhow::Error) -> Self {
+        match error.downcast::<Socks5Error>() {
+            Ok(e) => e,
+            Err(e) => Socks5Error::Other(e),
+        }
+    }
 }
 
 /// Reply codes for SOCKS5 protocol
@@ -163,6 +237,8 @@ impl From<&io::Error> for Socks5ReplyCode {
             io::ErrorKind::ConnectionRefused => Socks5ReplyCode::ConnectionRefused,
             io::ErrorKind::TimedOut => Socks5ReplyCode::HostUnreachable,
             io::ErrorKind::AddrNotAvailable => Socks5ReplyCode::HostUnreachable,
+            io::ErrorKind::HostUnreachable => Socks5ReplyCode::HostUnreachable,
+            io::ErrorKind::NetworkUnreachable => Socks5ReplyCode::NetworkUnreachable,
             _ => Socks5ReplyCode::GeneralFailure,
         }
     }
@@ -256,13 +332,60 @@ mod tests {
             Socks5ReplyCode::HostUnreachable
         );
 
-        let err = io::Error::new(io::ErrorKind::Other, "other");
+        let err = io::Error::other("other");
         assert_eq!(Socks5ReplyCode::from(&err), Socks5ReplyCode::GeneralFailure);
 
         let err = io::Error::new(io::ErrorKind::PermissionDenied, "permission denied");
         assert_eq!(Socks5ReplyCode::from(&err), Socks5ReplyCode::GeneralFailure);
     }
 
+    #[test]
+    #[cfg(feature = "socks")]
+    fn test_socks5_error_reply_codes() {
+        let cases = [
+            (Socks5Error::AuthFailed, 0x01),
+            (Socks5Error::AclDenied, 0x02),
+            (Socks5Error::NetworkUnreachable, 0x03),
+            (Socks5Error::HostUnreachable, 0x04),
+            (Socks5Error::ConnectionRefused, 0x05),
+            (Socks5Error::UnsupportedCommand("BIND".to_string()), 0x07),
+            (Socks5Error::CommandNotSupported(0x09), 0x07),
+            (Socks5Error::AddressTypeNotSupported(0x05), 0x08),
+            (
+                Socks5Error::TargetUnreachable(io::ErrorKind::ConnectionRefused.into()),
+                0x05,
+            ),
+            (
+                Socks5Error::TargetUnreachable(io::ErrorKind::NetworkUnreachable.into()),
+                0x03,
+            ),
+            (
+                Socks5Error::TargetUnreachable(io::Error::other("dns")),
+                0x04,
+            ),
+            (Socks5Error::RelayIo(io::ErrorKind::BrokenPipe.into()), 0x01),
+            (Socks5Error::HandshakeTimeout, 0x01),
+            (Socks5Error::Other(anyhow::anyhow!("boom")), 0x01),
+        ];
+        for (err, code) in cases {
+            assert_eq!(u8::from(err.reply_code()), code, "{}", err);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "socks")]
+    fn test_socks5_error_from_anyhow() {
+        let err = anyhow::Error::new(Socks5Error::AuthFailed).context("user alice");
+        assert!(matches!(Socks5Error::from(err), Socks5Error::AuthFailed));
+
+        let err = Socks5Error::from(anyhow::anyhow!("boom"));
+        assert!(matches!(err, Socks5Error::Other(_)));
+        assert_eq!(err.to_string(), "boom");
+
+        let err = Socks5Error::target_unreachable(anyhow::anyhow!("no such host"));
+        assert_eq!(u8::from(err.reply_code()), 0x04);
+    }
+
     #[test]
     fn test_sockrats_error_display() {
         let err = SockratsError::Config("invalid config".to_string());
@@ -295,7 +418,7 @@ mod tests {
 
     #[test]
     fn test_sockrats_error_from_io() {
-        let io_err = io::Error::new(io::ErrorKind::Other, "io error");
+        let io_err = io::Error::other("io error");
         let err: SockratsError = io_err.into();
         assert!(matches!(err, SockratsError::Io(_)));
     }
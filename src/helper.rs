@@ -2,8 +2,13 @@
 //!
 //! This module provides common utility functions used throughout the application.
 
+use crate::ratelimit::{acquire_all, RateLimiter};
+use std::future::Future;
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::task::{AbortHandle, JoinHandle, JoinSet};
+use tracing::Instrument;
 
 /// Default buffer size for IO operations
 pub const DEFAULT_BUFFER_SIZE: usize = 8192;
@@ -26,11 +31,254 @@ where
     tokio::io::copy_bidirectional(a, b).await
 }
 
+/// Options for [`copy_bidirectional_counted`]
+#[derive(Debug, Clone)]
+pub struct CopyOptions {
+    /// Size of the buffer used for each direction
+    pub buffer_size: usize,
+    /// Keep copying the other direction after one side reaches EOF,
+    /// propagating the EOF with a write shutdown. When false, the copy
+    /// stops as soon as either direction finishes.
+    pub half_close: bool,
+    /// Fail a direction with `TimedOut` when a single write blocks longer
+    /// than this, e.g. because the peer stopped reading (None = no limit)
+    pub write_timeout: Option<Duration>,
+    /// Rate limiters each write from `a` to `b` waits on
+    pub a_to_b_limiters: Vec<Arc<RateLimiter>>,
+    /// Rate limiters each write from `b` to `a` waits on
+    pub b_to_a_limiters: Vec<Arc<RateLimiter>>,
+}
+
+impl Default for CopyOptions {
+    fn default() -> Self {
+        CopyOptions {
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            half_close: true,
+            write_timeout: None,
+            a_to_b_limiters: Vec::new(),
+            b_to_a_limiters: Vec::new(),
+        }
+    }
+}
+
+/// Direction of a bidirectional copy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CopyDirection {
+    /// Reading from `a`, writing to `b`
+    AToB,
+    /// Reading from `b`, writing to `a`
+    BToA,
+}
+
+/// Detailed result of [`copy_bidirectional_counted_detailed`]
+#[derive(Debug)]
+pub struct CopyOutcome {
+    /// Bytes copied from `a` to `b`
+    pub a_to_b: u64,
+    /// Bytes copied from `b` to `a`
+    pub b_to_a: u64,
+    /// Direction that finished (EOF or error) first
+    pub first_done: CopyDirection,
+    /// Error that ended the copy, if any
+    pub error: Option<std::io::Error>,
+}
+
+/// Bidirectional copy that counts the bytes moved each way
+///
+/// Returns `(a_to_b, b_to_a)` byte counts. See [`CopyOptions`] for the
+/// half-close behaviour.
+pub async fn copy_bidirectional_counted<A, B>(
+    a: &mut A,
+    b: &mut B,
+    opts: &CopyOptions,
+) -> std::io::Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    B: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    let outcome = copy_bidirectional_counted_detailed(a, b, opts).await;
+    match outcome.error {
+        Some(e) => Err(e),
+        None => Ok((outcome.a_to_b, outcome.b_to_a)),
+    }
+}
+
+/// Like [`copy_bidirectional_counted`], but also reports which direction
+/// finished first, and keeps the byte counts when a direction fails
+pub async fn copy_bidirectional_counted_detailed<A, B>(
+    a: &mut A,
+    b: &mut B,
+    opts: &CopyOptions,
+) -> CopyOutcome
+where
+    A: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    B: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    let (mut a_read, mut a_write) = tokio::io::split(a);
+    let (mut b_read, mut b_write) = tokio::io::split(b);
+
+    let mut a_to_b = 0u64;
+    let mut b_to_a = 0u64;
+
+    let (first_done, error) = {
+        let forward = copy_counted(
+            &mut a_read,
+            &mut b_write,
+            &mut a_to_b,
+            &opts.a_to_b_limiters,
+            opts,
+        );
+        let backward = copy_counted(
+            &mut b_read,
+            &mut a_write,
+            &mut b_to_a,
+            &opts.b_to_a_limiters,
+            opts,
+        );
+        tokio::pin!(forward, backward);
+
+        let (first_done, result) = tokio::select! {
+            result = &mut forward => (CopyDirection::AToB, result),
+            result = &mut backward => (CopyDirection::BToA, result),
+        };
+
+        let error = match result {
+            Err(e) => Some(e),
+            Ok(()) if opts.half_close => match first_done {
+                CopyDirection::AToB => backward.await.err(),
+                CopyDirection::BToA => forward.await.err(),
+            },
+            Ok(()) => None,
+        };
+        (first_done, error)
+    };
+
+    CopyOutcome {
+        a_to_b,
+        b_to_a,
+        first_done,
+        error,
+    }
+}
+
+/// Copy from `reader` to `writer` until EOF, tallying bytes into `counter`
+/// and waiting on `limiters` before each write
+///
+/// The counter is updated after every write so the tally survives the
+/// future being dropped when the other direction finishes first.
+async fn copy_counted<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    counter: &mut u64,
+    limiters: &[Arc<RateLimiter>],
+    opts: &CopyOptions,
+) -> std::io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; opts.buffer_size];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            if opts.half_close {
+                with_write_timeout(opts.write_timeout, writer.shutdown()).await?;
+            } else {
+                with_write_timeout(opts.write_timeout, writer.flush()).await?;
+            }
+            return Ok(());
+        }
+        acquire_all(limiters, n).await;
+        with_write_timeout(opts.write_timeout, writer.write_all(&buf[..n])).await?;
+        *counter += n as u64;
+    }
+}
+
+/// Run the write `future`, failing with `TimedOut` if it takes longer than
+/// `timeout` (None = no limit)
+pub async fn with_write_timeout<F, T>(timeout: Option<Duration>, future: F) -> std::io::Result<T>
+where
+    F: Future<Output = std::io::Result<T>>,
+{
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, future)
+            .await
+            .unwrap_or_else(|_| {
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    format!("write timed out after {:?}", timeout),
+                ))
+            }),
+        None => future.await,
+    }
+}
+
 /// Parse duration from seconds
 pub fn duration_from_secs(secs: u64) -> Duration {
     Duration::from_secs(secs)
 }
 
+tokio::task_local! {
+    static TASK_NAME: &'static str;
+}
+
+/// Spawn `future` as a task called `name`
+///
+/// The name is visible to [`current_task_name`] inside the task and is
+/// attached as a tracing span. With the `console` feature and
+/// `--cfg tokio_unstable`, it is also the task name shown by tokio-console.
+pub fn spawn_named<F>(name: &'static str, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let future = TASK_NAME
+        .scope(name, future)
+        .instrument(tracing::debug_span!("task", name));
+
+    #[cfg(all(tokio_unstable, feature = "console"))]
+    {
+        tokio::task::Builder::new()
+            .name(name)
+            .spawn(future)
+            .expect("failed to spawn task")
+    }
+    #[cfg(not(all(tokio_unstable, feature = "console")))]
+    {
+        tokio::spawn(future)
+    }
+}
+
+/// Spawn `future` into `set` as a task called `name`
+///
+/// Like [`spawn_named`], for tasks whose owner waits for them as a group.
+pub fn spawn_named_in<F>(set: &mut JoinSet<F::Output>, name: &'static str, future: F) -> AbortHandle
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let future = TASK_NAME
+        .scope(name, future)
+        .instrument(tracing::debug_span!("task", name));
+
+    #[cfg(all(tokio_unstable, feature = "console"))]
+    {
+        set.build_task()
+            .name(name)
+            .spawn(future)
+            .expect("failed to spawn task")
+    }
+    #[cfg(not(all(tokio_unstable, feature = "console")))]
+    {
+        set.spawn(future)
+    }
+}
+
+/// Name of the current task, if it was started with [`spawn_named`]
+pub fn current_task_name() -> Option<&'static str> {
+    TASK_NAME.try_with(|name| *name).ok()
+}
+
 /// Retry configuration for operations
 #[derive(Debug, Clone)]
 pub struct RetryConfig {
@@ -217,4 +465,115 @@ mod tests {
 
         let _ = tokio::time::timeout(Duration::from_millis(100), copy_task).await;
     }
+
+    #[tokio::test]
+    async fn test_copy_bidirectional_counted_counts() {
+        let (mut a1, mut a2) = duplex(1024);
+        let (mut b1, mut b2) = duplex(1024);
+
+        let copy_task = tokio::spawn(async move {
+            copy_bidirectional_counted(&mut a2, &mut b2, &CopyOptions::default()).await
+        });
+
+        a1.write_all(b"hello world").await.unwrap();
+        let mut buf = [0u8; 11];
+        b1.read_exact(&mut buf).await.unwrap();
+
+        b1.write_all(b"pong").await.unwrap();
+        let mut buf = [0u8; 4];
+        a1.read_exact(&mut buf).await.unwrap();
+
+        drop(a1);
+        drop(b1);
+
+        let counts = tokio::time::timeout(Duration::from_secs(1), copy_task)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(counts, (11, 4));
+    }
+
+    #[tokio::test]
+    async fn test_copy_bidirectional_counted_half_close() {
+        let (mut a1, mut a2) = duplex(1024);
+        let (mut b1, mut b2) = duplex(1024);
+
+        let copy_task = tokio::spawn(async move {
+            copy_bidirectional_counted(&mut a2, &mut b2, &CopyOptions::default()).await
+        });
+
+        // a finishes sending; b sees EOF but can still answer
+        a1.write_all(b"request").await.unwrap();
+        a1.shutdown().await.unwrap();
+
+        let mut request = Vec::new();
+        b1.read_to_end(&mut request).await.unwrap();
+        assert_eq!(request, b"request");
+
+        b1.write_all(b"late response").await.unwrap();
+        b1.shutdown().await.unwrap();
+
+        let mut response = Vec::new();
+        a1.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"late response");
+
+        let counts = tokio::time::timeout(Duration::from_secs(1), copy_task)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(counts, (7, 13));
+    }
+
+    #[tokio::test]
+    async fn test_copy_bidirectional_counted_without_half_close() {
+        let (mut a1, mut a2) = duplex(1024);
+        let (mut b1, mut b2) = duplex(1024);
+
+        let opts = CopyOptions {
+            half_close: false,
+            ..Default::default()
+        };
+        let copy_task = tokio::spawn(async move {
+            copy_bidirectional_counted_detailed(&mut a2, &mut b2, &opts).await
+        });
+
+        b1.write_all(b"bye").await.unwrap();
+        let mut buf = [0u8; 3];
+        a1.read_exact(&mut buf).await.unwrap();
+        b1.shutdown().await.unwrap();
+
+        // The copy ends on b's EOF even though a is still open
+        let outcome = tokio::time::timeout(Duration::from_secs(1), copy_task)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(outcome.first_done, CopyDirection::BToA);
+        assert_eq!((outcome.a_to_b, outcome.b_to_a), (0, 3));
+        assert!(outcome.error.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_spawn_named_sets_task_name() {
+        let name = spawn_named("data-channel", async { current_task_name() })
+            .await
+            .unwrap();
+        assert_eq!(name, Some("data-channel"));
+    }
+
+    #[tokio::test]
+    async fn test_spawn_named_in_sets_task_name() {
+        let mut set = JoinSet::new();
+        spawn_named_in(&mut set, "data-channel", async { current_task_name() });
+        let name = set.join_next().await.unwrap().unwrap();
+        assert_eq!(name, Some("data-channel"));
+    }
+
+    #[tokio::test]
+    async fn test_current_task_name_outside_named_task() {
+        assert_eq!(current_task_name(), None);
+        let name = tokio::spawn(async { current_task_name() }).await.unwrap();
+        assert_eq!(name, None);
+    }
 }
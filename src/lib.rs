@@ -43,12 +43,15 @@
 #![warn(missing_docs)]
 #![warn(rust_2018_idioms)]
 
+pub mod audit;
 pub mod client;
+pub mod clock;
 pub mod config;
 pub mod error;
 pub mod helper;
 pub mod pool;
 pub mod protocol;
+pub mod ratelimit;
 pub mod services;
 pub mod transport;
 
@@ -59,7 +62,7 @@ pub use services::socks;
 pub use services::ssh;
 
 // Re-export commonly used items
-pub use client::run_client;
+pub use client::{run_client, run_client_with_listener, run_client_with_reload};
 pub use config::{load_config, Config};
 pub use error::SockratsError;
 #[cfg(feature = "socks")]
@@ -71,6 +74,83 @@ pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 /// Name of the application
 pub const NAME: &str = env!("CARGO_PKG_NAME");
 
+/// Compile-time information about this build
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BuildInfo {
+    /// Crate version
+    pub version: &'static str,
+    /// Cargo features compiled in
+    pub features: Vec<&'static str>,
+    /// Locked versions of key dependencies, as (name, version)
+    pub dependencies: Vec<(&'static str, &'static str)>,
+}
+
+impl std::fmt::Display for BuildInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{} {}", NAME, self.version)?;
+        if self.features.is_empty() {
+            writeln!(f, "features: (none)")?;
+        } else {
+            writeln!(f, "features: {}", self.features.join(", "))?;
+        }
+        write!(f, "dependencies:")?;
+        for (name, version) in &self.dependencies {
+            write!(f, "\n  {} {}", name, version)?;
+        }
+        Ok(())
+    }
+}
+
+/// Get the version, enabled features and key dependency versions of this build
+pub fn build_info() -> BuildInfo {
+    let mut features = Vec::new();
+    let mut dependencies = vec![("tokio", env!("SOCKRATS_DEP_TOKIO"))];
+
+    if cfg!(feature = "noise") {
+        features.push("noise");
+        dependencies.push(("snowstorm", env!("SOCKRATS_DEP_SNOWSTORM")));
+    }
+    if cfg!(feature = "tls") {
+        features.push("tls");
+    }
+    if cfg!(feature = "socks") {
+        features.push("socks");
+    }
+    if cfg!(feature = "socks-udp") {
+        features.push("socks-udp");
+    }
+    if cfg!(feature = "socks-auth") {
+        features.push("socks-auth");
+    }
+    if cfg!(feature = "socks-doh") {
+        features.push("socks-doh");
+    }
+    if cfg!(feature = "ssh") {
+        features.push("ssh");
+        dependencies.push(("russh", env!("SOCKRATS_DEP_RUSSH")));
+    }
+    if cfg!(feature = "wireguard") {
+        features.push("wireguard");
+        dependencies.push(("boringtun", env!("SOCKRATS_DEP_BORINGTUN")));
+    }
+    if cfg!(feature = "vncserver") {
+        features.push("vncserver");
+        dependencies.push(("rfb-encodings", env!("SOCKRATS_DEP_RFB_ENCODINGS")));
+    }
+    if cfg!(feature = "metrics") {
+        features.push("metrics");
+    }
+    if cfg!(feature = "console") {
+        features.push("console");
+    }
+
+    BuildInfo {
+        version: VERSION,
+        features,
+        dependencies,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -84,4 +164,32 @@ mod tests {
     fn test_name() {
         assert_eq!(NAME, "sockrats");
     }
+
+    #[test]
+    fn test_build_info() {
+        let info = build_info();
+        assert_eq!(info.version, VERSION);
+        assert!(info
+            .to_string()
+            .starts_with(&format!("sockrats {}", VERSION)));
+
+        let has = |feature| info.features.contains(&feature);
+        assert_eq!(has("noise"), cfg!(feature = "noise"));
+        assert_eq!(has("tls"), cfg!(feature = "tls"));
+        assert_eq!(has("socks"), cfg!(feature = "socks"));
+        assert_eq!(has("socks-udp"), cfg!(feature = "socks-udp"));
+        assert_eq!(has("socks-auth"), cfg!(feature = "socks-auth"));
+        assert_eq!(has("socks-doh"), cfg!(feature = "socks-doh"));
+        assert_eq!(has("ssh"), cfg!(feature = "ssh"));
+        assert_eq!(has("wireguard"), cfg!(feature = "wireguard"));
+        assert_eq!(has("vncserver"), cfg!(feature = "vncserver"));
+        assert_eq!(has("metrics"), cfg!(feature = "metrics"));
+        assert_eq!(has("console"), cfg!(feature = "console"));
+
+        assert!(info.dependencies.iter().any(|(name, _)| *name == "tokio"));
+        assert_eq!(
+            info.dependencies.iter().any(|(name, _)| *name == "russh"),
+            cfg!(feature = "ssh")
+        );
+    }
 }
@@ -4,21 +4,30 @@
 
 use anyhow::Result;
 use clap::Parser;
-use sockrats::client::run_client;
-use sockrats::config::load_config;
+use sockrats::client::{run_client_with_reload, validate_config};
+use sockrats::config::{example_config, load_config, ServiceListExt};
 use std::path::PathBuf;
-use tokio::sync::broadcast;
-use tracing::{info, Level};
+use tokio::sync::{broadcast, mpsc};
+use tracing::{error, info, Level};
 use tracing_subscriber::FmtSubscriber;
 
 /// Sockrats - Reverse SOCKS5 tunneling client using rathole protocol
 #[derive(Parser, Debug)]
 #[command(name = "sockrats")]
 #[command(author, version, about, long_about = None)]
+#[command(disable_version_flag = true)]
 struct Args {
     /// Path to configuration file
-    #[arg(short, long)]
-    config: PathBuf,
+    #[arg(short, long, required_unless_present_any = ["version", "print_example_config"])]
+    config: Option<PathBuf>,
+
+    /// Print version
+    #[arg(short = 'V', long)]
+    version: bool,
+
+    /// With --version, also print enabled features and dependency versions
+    #[arg(short, long, requires = "version")]
+    verbose: bool,
 
     /// Log level (trace, debug, info, warn, error)
     #[arg(short, long, default_value = "info")]
@@ -27,29 +36,79 @@ struct Args {
     /// Enable JSON logging format
     #[arg(long)]
     json_log: bool,
+
+    /// Serve runtime task instrumentation to tokio-console
+    #[arg(long)]
+    console: bool,
+
+    /// Check the configuration and exit without connecting (status 1 if
+    /// it has problems)
+    #[arg(long)]
+    validate_only: bool,
+
+    /// Print an example configuration with every option at its default
+    #[arg(long)]
+    print_example_config: bool,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
 
+    if args.version {
+        if args.verbose {
+            println!("{}", sockrats::build_info());
+        } else {
+            println!("{} {}", sockrats::NAME, sockrats::VERSION);
+        }
+        return Ok(());
+    }
+    if args.print_example_config {
+        print!("{}", example_config());
+        return Ok(());
+    }
+    let config_path = args
+        .config
+        .expect("--config is required without --version or --print-example-config");
+
+    if args.validate_only {
+        let valid = match load_config(&config_path) {
+            Ok(config) => {
+                let report = validate_config(&config);
+                println!("{}", report);
+                report.is_ok()
+            }
+            Err(e) => {
+                println!("error  config: {:#}", e);
+                false
+            }
+        };
+        std::process::exit(if valid { 0 } else { 1 });
+    }
+
     // Setup logging
-    setup_logging(&args.log_level, args.json_log)?;
+    setup_logging(&args.log_level, args.json_log, args.console)?;
 
     // Load configuration
-    let config = load_config(&args.config)?;
+    let config = load_config(&config_path)?;
 
     info!("Sockrats v{}", sockrats::VERSION);
-    info!("Configuration loaded from: {:?}", args.config);
-    info!("Connecting to: {}", config.client.remote_addr);
-    info!("Service name: {}", config.client.service_name);
+    info!("Configuration loaded from: {:?}", config_path);
+    info!(
+        "Connecting to: {}",
+        config.client.remote_endpoints().join(", ")
+    );
+    match config.client.services.default_service() {
+        Some(service) => info!("Service name: {}", service.name),
+        None => info!("Services: {}", config.client.services.len()),
+    }
 
     // Setup shutdown signal
     let (shutdown_tx, shutdown_rx) = broadcast::channel(1);
 
     // Handle Ctrl+C and termination signals (cross-platform)
     let shutdown_tx_clone = shutdown_tx.clone();
-    tokio::spawn(async move {
+    sockrats::helper::spawn_named("signal-handler", async move {
         #[cfg(unix)]
         {
             use tokio::signal::unix::{signal, SignalKind};
@@ -76,12 +135,40 @@ async fn main() -> Result<()> {
         let _ = shutdown_tx_clone.send(true);
     });
 
+    // Reload the configuration on SIGHUP
+    #[cfg_attr(not(unix), allow(unused_variables))]
+    let (reload_tx, reload_rx) = mpsc::channel(1);
+    #[cfg(unix)]
+    sockrats::helper::spawn_named("reload-handler", async move {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut sighup = signal(SignalKind::hangup()).expect("Failed to setup SIGHUP handler");
+
+        while sighup.recv().await.is_some() {
+            info!(
+                "Received SIGHUP, reloading configuration from {:?}",
+                config_path
+            );
+            match load_config(&config_path) {
+                Ok(config) => {
+                    if reload_tx.send(config.client).await.is_err() {
+                        error!("Configuration reload is not available");
+                        break;
+                    }
+                }
+                Err(e) => error!(
+                    "Failed to reload configuration, keeping the current one: {:#}",
+                    e
+                ),
+            }
+        }
+    });
+
     // Run the client
-    run_client(config, shutdown_rx).await
+    run_client_with_reload(config, shutdown_rx, reload_rx).await
 }
 
 /// Setup logging based on configuration
-fn setup_logging(level: &str, json: bool) -> Result<()> {
+fn setup_logging(level: &str, json: bool, console: bool) -> Result<()> {
     let level = match level.to_lowercase().as_str() {
         "trace" => Level::TRACE,
         "debug" => Level::DEBUG,
@@ -91,6 +178,10 @@ fn setup_logging(level: &str, json: bool) -> Result<()> {
         _ => Level::INFO,
     };
 
+    if console {
+        return setup_console_logging(level, json);
+    }
+
     if json {
         let subscriber = FmtSubscriber::builder()
             .with_max_level(level)
@@ -109,3 +200,36 @@ fn setup_logging(level: &str, json: bool) -> Result<()> {
 
     Ok(())
 }
+
+/// Setup logging alongside the tokio-console subscriber
+///
+/// The level only filters the log output; the console layer still sees the
+/// runtime's own trace-level instrumentation.
+#[cfg(feature = "console")]
+fn setup_console_logging(level: Level, json: bool) -> Result<()> {
+    use tracing_subscriber::filter::LevelFilter;
+    use tracing_subscriber::prelude::*;
+
+    let filter = LevelFilter::from_level(level);
+    let fmt = if json {
+        tracing_subscriber::fmt::layer()
+            .json()
+            .with_filter(filter)
+            .boxed()
+    } else {
+        tracing_subscriber::fmt::layer()
+            .with_target(true)
+            .with_filter(filter)
+            .boxed()
+    };
+    tracing_subscriber::registry()
+        .with(console_subscriber::spawn())
+        .with(fmt)
+        .try_init()?;
+    Ok(())
+}
+
+#[cfg(not(feature = "console"))]
+fn setup_console_logging(_level: Level, _json: bool) -> Result<()> {
+    anyhow::bail!("--console requires the console feature. Recompile with --features console")
+}
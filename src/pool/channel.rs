@@ -2,7 +2,10 @@
 //!
 //! Represents a single channel in the pool with metadata.
 
+use std::pin::Pin;
+use std::task::{Context, Poll};
 use std::time::Instant;
+use tokio::io::{AsyncRead, ReadBuf};
 
 /// A pooled channel with metadata
 #[derive(Debug)]
@@ -40,9 +43,21 @@ impl<S> PooledChannel<S> {
         }
     }
 
+    /// Treat the channel as created, and last used, at `now`
+    pub fn with_created_at(mut self, now: Instant) -> Self {
+        self.created_at = now;
+        self.last_used = now;
+        self
+    }
+
     /// Check if the channel is stale based on idle timeout
     pub fn is_stale(&self, idle_timeout: std::time::Duration) -> bool {
-        self.last_used.elapsed() > idle_timeout
+        self.is_stale_at(Instant::now(), idle_timeout)
+    }
+
+    /// Check if the channel has been idle longer than `idle_timeout` as of `now`
+    pub fn is_stale_at(&self, now: Instant, idle_timeout: std::time::Duration) -> bool {
+        now.saturating_duration_since(self.last_used) > idle_timeout
     }
 
     /// Get the age of the channel
@@ -57,7 +72,12 @@ impl<S> PooledChannel<S> {
 
     /// Mark the channel as used
     pub fn touch(&mut self) {
-        self.last_used = Instant::now();
+        self.touch_at(Instant::now());
+    }
+
+    /// Mark the channel as used at `now`
+    pub fn touch_at(&mut self, now: Instant) {
+        self.last_used = now;
     }
 
     /// Check if this is a TCP channel
@@ -79,6 +99,25 @@ impl<S> PooledChannel<S> {
     pub fn stream_mut(&mut self) -> &mut S {
         &mut self.stream
     }
+
+    /// Check without blocking whether an idle channel is still usable
+    ///
+    /// Polls the stream for a read once: a pending read means the peer is
+    /// still there. EOF or an error means it hung up. Data on an idle
+    /// channel is unexpected and has now been consumed, so that counts as
+    /// unusable too.
+    pub fn probe(&mut self) -> bool
+    where
+        S: AsyncRead + Unpin,
+    {
+        let mut byte = [0u8; 1];
+        let mut buf = ReadBuf::new(&mut byte);
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        matches!(
+            Pin::new(&mut self.stream).poll_read(&mut cx, &mut buf),
+            Poll::Pending
+        )
+    }
 }
 
 #[cfg(test)]
@@ -135,6 +174,16 @@ mod tests {
         assert_eq!(*channel.stream(), 123);
     }
 
+    #[tokio::test]
+    async fn test_pooled_channel_probe() {
+        let (ours, theirs) = tokio::io::duplex(64);
+        let mut channel = PooledChannel::new_tcp(ours);
+        assert!(channel.probe());
+
+        drop(theirs);
+        assert!(!channel.probe());
+    }
+
     #[test]
     fn test_pooled_channel_stream_mut() {
         let mut channel = PooledChannel::new_tcp(100);
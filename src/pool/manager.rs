@@ -75,11 +75,17 @@ impl PoolStats {
 /// Snapshot of pool statistics
 #[derive(Debug, Clone)]
 pub struct PoolStatsSnapshot {
+    /// Total channels created
     pub total_created: usize,
+    /// Channels currently in the pool
     pub pooled_count: usize,
+    /// Channels currently in use
     pub in_use_count: usize,
+    /// Total channels acquired
     pub total_acquired: usize,
+    /// Total channels returned
     pub total_returned: usize,
+    /// Total channels expired/removed
     pub total_expired: usize,
 }
 
@@ -132,6 +138,14 @@ impl PoolManager {
         Duration::from_secs(self.config.idle_timeout)
     }
 
+    /// Get the interval between idle channel sweeps, if sweeping is enabled
+    pub fn reap_interval(&self) -> Option<Duration> {
+        match self.config.reap_interval {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        }
+    }
+
     /// Wait for shutdown signal
     pub async fn wait_shutdown(&self) {
         self.shutdown.notified().await;
@@ -212,6 +226,17 @@ mod tests {
             manager.idle_timeout(),
             Duration::from_secs(config.idle_timeout)
         );
+        assert_eq!(
+            manager.reap_interval(),
+            Some(Duration::from_secs(config.reap_interval))
+        );
+
+        let config = PoolConfig {
+            reap_interval: 0,
+            ..Default::default()
+        };
+        let manager = PoolManager::new(config, Arc::new(PoolStats::new()));
+        assert_eq!(manager.reap_interval(), None);
     }
 
     #[test]
@@ -10,7 +10,7 @@ mod tcp_pool;
 
 pub use channel::PooledChannel;
 pub use guard::PooledChannelGuard;
-pub use manager::PoolManager;
+pub use manager::{PoolManager, PoolStats, PoolStatsSnapshot};
 pub use tcp_pool::TcpChannelPool;
 
 use crate::config::PoolConfig;
@@ -5,7 +5,9 @@
 use super::channel::PooledChannel;
 use super::guard::{PooledChannelGuard, ReturnedChannel};
 use super::manager::{PoolManager, PoolStats};
-use crate::config::PoolConfig;
+use crate::clock::{Clock, TokioClock};
+use crate::config::{PoolConfig, PoolExhaustion};
+use crate::helper::spawn_named;
 use crate::protocol::{
     read_data_cmd, write_hello, DataChannelCmd, Digest, Hello, CURRENT_PROTO_VERSION,
 };
@@ -14,7 +16,7 @@ use anyhow::{Context, Result};
 use std::collections::VecDeque;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::time::Duration;
 use tokio::sync::{mpsc, Mutex, Notify, Semaphore};
 use tracing::{debug, info, warn};
 
@@ -40,6 +42,8 @@ pub struct TcpChannelPool<T: Transport> {
     manager: PoolManager,
     /// Channel for returning streams to the pool
     return_tx: mpsc::Sender<ReturnedChannel<T::Stream>>,
+    /// Time source for idle tracking, acquire deadlines and maintenance
+    clock: Arc<dyn Clock>,
 }
 
 impl<T: Transport + 'static> TcpChannelPool<T> {
@@ -49,6 +53,24 @@ impl<T: Transport + 'static> TcpChannelPool<T> {
         transport: Arc<T>,
         remote_addr: AddrMaybeCached,
         session_key: Digest,
+    ) -> Result<Arc<Self>> {
+        Self::new_with_clock(
+            config,
+            transport,
+            remote_addr,
+            session_key,
+            Arc::new(TokioClock),
+        )
+        .await
+    }
+
+    /// Create a new TCP channel pool that reads time from `clock`
+    pub async fn new_with_clock(
+        config: PoolConfig,
+        transport: Arc<T>,
+        remote_addr: AddrMaybeCached,
+        session_key: Digest,
+        clock: Arc<dyn Clock>,
     ) -> Result<Arc<Self>> {
         let stats = Arc::new(PoolStats::new());
         let manager = PoolManager::new(config.clone(), stats);
@@ -66,11 +88,12 @@ impl<T: Transport + 'static> TcpChannelPool<T> {
             active_count: AtomicUsize::new(0),
             manager,
             return_tx,
+            clock,
         });
 
         // Start return handler
         let pool_clone = pool.clone();
-        tokio::spawn(async move {
+        spawn_named("pool-return-handler", async move {
             pool_clone.run_return_handler(return_rx).await;
         });
 
@@ -79,10 +102,17 @@ impl<T: Transport + 'static> TcpChannelPool<T> {
 
         // Start maintenance task
         let pool_clone = pool.clone();
-        tokio::spawn(async move {
+        spawn_named("pool-maintenance", async move {
             pool_clone.run_maintenance().await;
         });
 
+        if let Some(interval) = pool.manager.reap_interval() {
+            let pool_clone = pool.clone();
+            spawn_named("pool-reaper", async move {
+                pool_clone.run_reaper(interval).await;
+            });
+        }
+
         Ok(pool)
     }
 
@@ -96,7 +126,7 @@ impl<T: Transport + 'static> TcpChannelPool<T> {
         let mut tasks = Vec::new();
         for _ in 0..self.config.min_tcp_channels {
             let pool = self.clone();
-            tasks.push(tokio::spawn(async move {
+            tasks.push(spawn_named("pool-warm-up", async move {
                 if let Err(e) = pool.create_channel().await {
                     warn!("Failed to pre-create channel: {:?}", e);
                 }
@@ -135,7 +165,7 @@ impl<T: Transport + 'static> TcpChannelPool<T> {
 
         // Add to pool
         let mut channels = self.channels.lock().await;
-        channels.push_back(PooledChannel::new_tcp(stream));
+        channels.push_back(PooledChannel::new_tcp(stream).with_created_at(self.clock.now()));
         self.active_count.fetch_add(1, Ordering::Relaxed);
         self.manager.stats().record_created();
         self.manager.stats().set_pooled_count(channels.len());
@@ -175,9 +205,14 @@ impl<T: Transport + 'static> TcpChannelPool<T> {
     }
 
     /// Acquire a channel from the pool
+    ///
+    /// With [`PoolConfig::validate_on_acquire`], idle channels the server
+    /// has closed are discarded rather than handed out. When every channel
+    /// is in use, [`PoolConfig::on_exhaustion`] decides
+    /// whether to wait for one, fail, or open one beyond the maximum.
     pub async fn acquire(&self) -> Result<PooledChannelGuard<T::Stream>> {
         let timeout = Duration::from_secs(self.config.acquire_timeout);
-        let deadline = Instant::now() + timeout;
+        let deadline = self.clock.now() + timeout;
 
         loop {
             // Try to get a channel from the pool
@@ -186,8 +221,9 @@ impl<T: Transport + 'static> TcpChannelPool<T> {
 
                 // Remove stale channels
                 let idle_timeout = Duration::from_secs(self.config.idle_timeout);
+                let now = self.clock.now();
                 while let Some(front) = channels.front() {
-                    if front.is_stale(idle_timeout) {
+                    if front.is_stale_at(now, idle_timeout) {
                         channels.pop_front();
                         self.active_count.fetch_sub(1, Ordering::Relaxed);
                         self.manager.stats().record_expired();
@@ -197,8 +233,16 @@ impl<T: Transport + 'static> TcpChannelPool<T> {
                     }
                 }
 
-                if let Some(mut channel) = channels.pop_front() {
-                    channel.touch();
+                while let Some(mut channel) = channels.pop_front() {
+                    if self.config.validate_on_acquire && !channel.probe() {
+                        self.active_count.fetch_sub(1, Ordering::Relaxed);
+                        self.manager.stats().record_expired();
+                        self.manager.stats().set_pooled_count(channels.len());
+                        debug!("Discarded dead TCP channel on acquire");
+                        continue;
+                    }
+
+                    channel.touch_at(now);
                     self.manager.stats().set_pooled_count(channels.len());
                     self.manager.stats().record_acquired();
 
@@ -219,21 +263,47 @@ impl<T: Transport + 'static> TcpChannelPool<T> {
                 continue;
             }
 
+            match self.config.on_exhaustion {
+                PoolExhaustion::Wait => {}
+                PoolExhaustion::Fail => anyhow::bail!(
+                    "TCP channel pool exhausted ({} channels in use)",
+                    self.config.max_tcp_channels
+                ),
+                PoolExhaustion::Overflow => return self.acquire_overflow().await,
+            }
+
             // At capacity, wait for a channel
-            let remaining = deadline.saturating_duration_since(Instant::now());
+            let remaining = deadline.saturating_duration_since(self.clock.now());
             if remaining.is_zero() {
                 anyhow::bail!("Timeout waiting for TCP channel");
             }
 
             tokio::select! {
                 _ = self.available_notify.notified() => continue,
-                _ = tokio::time::sleep(remaining) => {
+                _ = self.clock.sleep(remaining) => {
                     anyhow::bail!("Timeout waiting for TCP channel");
                 }
             }
         }
     }
 
+    /// Open a channel beyond `max_tcp_channels` for a single checkout
+    async fn acquire_overflow(&self) -> Result<PooledChannelGuard<T::Stream>> {
+        let stream = self.establish_data_channel().await?;
+        self.active_count.fetch_add(1, Ordering::Relaxed);
+        self.manager.stats().record_created();
+        self.manager.stats().record_acquired();
+        debug!(
+            "Pool exhausted, opened overflow TCP channel, active: {}",
+            self.active_count.load(Ordering::Relaxed)
+        );
+        Ok(PooledChannelGuard::new(
+            stream,
+            self.return_tx.clone(),
+            true,
+        ))
+    }
+
     /// Run the return handler
     async fn run_return_handler(
         self: Arc<Self>,
@@ -242,19 +312,21 @@ impl<T: Transport + 'static> TcpChannelPool<T> {
         while let Some(returned) = rx.recv().await {
             let mut channels = self.channels.lock().await;
 
-            if channels.len() < self.config.max_tcp_channels {
+            // Overflow channels are closed on return rather than pooled
+            let over_max = self.active_count.load(Ordering::Relaxed) > self.config.max_tcp_channels;
+            if channels.len() < self.config.max_tcp_channels && !over_max {
                 let channel = if returned.is_tcp {
                     PooledChannel::new_tcp(returned.stream)
                 } else {
                     PooledChannel::new_udp(returned.stream)
                 };
-                channels.push_back(channel);
+                channels.push_back(channel.with_created_at(self.clock.now()));
                 self.manager.stats().record_returned();
                 self.manager.stats().set_pooled_count(channels.len());
                 self.available_notify.notify_one();
                 debug!("Channel returned to pool, size: {}", channels.len());
             } else {
-                // Pool is full, drop the channel
+                // Pool is full (or over its maximum), drop the channel
                 self.active_count.fetch_sub(1, Ordering::Relaxed);
                 debug!("Pool full, dropping returned channel");
             }
@@ -271,7 +343,7 @@ impl<T: Transport + 'static> TcpChannelPool<T> {
                     info!("Pool maintenance shutting down");
                     break;
                 }
-                _ = tokio::time::sleep(interval) => {
+                _ = self.clock.sleep(interval) => {
                     self.maintain().await;
                 }
             }
@@ -280,7 +352,16 @@ impl<T: Transport + 'static> TcpChannelPool<T> {
 
     /// Perform pool maintenance
     async fn maintain(&self) {
-        // Ensure minimum channels
+        if self.config.liveness_probe {
+            self.evict_dead().await;
+        }
+
+        self.replenish().await;
+        self.manager.log_health();
+    }
+
+    /// Create channels until `min_tcp_channels` are pooled
+    async fn replenish(&self) {
         let current = {
             let channels = self.channels.lock().await;
             channels.len()
@@ -296,8 +377,62 @@ impl<T: Transport + 'static> TcpChannelPool<T> {
                 }
             }
         }
+    }
 
-        self.manager.log_health();
+    /// Sweep for idle channels every `interval` until shutdown
+    async fn run_reaper(self: Arc<Self>, interval: Duration) {
+        loop {
+            tokio::select! {
+                _ = self.manager.wait_shutdown() => break,
+                _ = self.clock.sleep(interval) => {
+                    self.reap_idle().await;
+                }
+            }
+        }
+    }
+
+    /// Close channels idle past `idle_timeout`, replacing them with fresh
+    /// ones so at least `min_tcp_channels` stay warm
+    async fn reap_idle(&self) {
+        let reaped = {
+            let mut channels = self.channels.lock().await;
+            let idle_timeout = self.manager.idle_timeout();
+            let now = self.clock.now();
+            let before = channels.len();
+            channels.retain(|channel| !channel.is_stale_at(now, idle_timeout));
+
+            let reaped = before - channels.len();
+            if reaped > 0 {
+                self.active_count.fetch_sub(reaped, Ordering::Relaxed);
+                for _ in 0..reaped {
+                    self.manager.stats().record_expired();
+                }
+                self.manager.stats().set_pooled_count(channels.len());
+            }
+            reaped
+        };
+
+        if reaped > 0 {
+            debug!("Closed {} idle TCP channels", reaped);
+            self.replenish().await;
+        }
+    }
+
+    /// Drop idle channels that fail their liveness probe
+    async fn evict_dead(&self) {
+        let mut channels = self.channels.lock().await;
+        let before = channels.len();
+        channels.retain_mut(PooledChannel::probe);
+
+        let evicted = before - channels.len();
+        if evicted > 0 {
+            self.active_count.fetch_sub(evicted, Ordering::Relaxed);
+            for _ in 0..evicted {
+                self.manager.stats().record_expired();
+            }
+            self.manager.stats().set_pooled_count(channels.len());
+            debug!("Evicted {} dead TCP channels", evicted);
+        }
     }
 
     /// Shutdown the pool
@@ -313,7 +448,10 @@ impl<T: Transport + 'static> TcpChannelPool<T> {
 
 #[cfg(test)]
 mod tests {
-    use crate::config::PoolConfig;
+    use super::*;
+    use crate::config::TransportConfig;
+    use crate::protocol::write_data_cmd;
+    use tokio::io::DuplexStream;
 
     #[test]
     fn test_pool_config_defaults() {
@@ -321,4 +459,281 @@ mod tests {
         assert!(config.min_tcp_channels <= config.max_tcp_channels);
         assert!(config.acquire_timeout > 0);
     }
+
+    /// Transport whose "server" immediately starts forwarding, keeping the
+    /// server end of every connection so tests can hang up on them
+    #[derive(Debug, Default)]
+    struct MockTransport {
+        peers: std::sync::Mutex<Vec<DuplexStream>>,
+    }
+
+    #[async_trait::async_trait]
+    impl Transport for MockTransport {
+        type Stream = DuplexStream;
+
+        fn new(_config: &TransportConfig) -> Result<Self> {
+            Ok(Self::default())
+        }
+
+        fn hint(_conn: &Self::Stream, _opts: SocketOpts) {}
+
+        async fn connect(&self, _addr: &AddrMaybeCached) -> Result<Self::Stream> {
+            let (client, mut server) = tokio::io::duplex(1024);
+            write_data_cmd(&mut server, &DataChannelCmd::StartForwardTcp).await?;
+            self.peers.lock().unwrap().push(server);
+            Ok(client)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_health_sweep_evicts_dead_channel() {
+        let config = PoolConfig {
+            min_tcp_channels: 2,
+            liveness_probe: true,
+            ..Default::default()
+        };
+        let transport = Arc::new(MockTransport::default());
+        let pool = TcpChannelPool::new(
+            config,
+            transport.clone(),
+            AddrMaybeCached::new("127.0.0.1:2333"),
+            [0u8; 32],
+        )
+        .await
+        .unwrap();
+        assert_eq!(pool.stats().snapshot().pooled_count, 2);
+
+        // The server closes one of the idle channels
+        transport.peers.lock().unwrap().remove(0);
+        pool.maintain().await;
+
+        let stats = pool.stats().snapshot();
+        assert_eq!(stats.total_expired, 1);
+        // Replenished back to the minimum with a fresh channel
+        assert_eq!(stats.total_created, 3);
+        assert_eq!(pool.channels.lock().await.len(), 2);
+        assert_eq!(pool.active_count.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_acquire_skips_dead_channel() {
+        let config = PoolConfig {
+            min_tcp_channels: 1,
+            validate_on_acquire: true,
+            ..Default::default()
+        };
+        let transport = Arc::new(MockTransport::default());
+        let pool = TcpChannelPool::new(
+            config,
+            transport.clone(),
+            AddrMaybeCached::new("127.0.0.1:2333"),
+            [0u8; 32],
+        )
+        .await
+        .unwrap();
+
+        // The server closes the only pooled channel
+        transport.peers.lock().unwrap().remove(0);
+        let mut guard = pool.acquire().await.unwrap();
+
+        // It was discarded and a fresh channel handed out instead
+        let stats = pool.stats().snapshot();
+        assert_eq!(stats.total_expired, 1);
+        assert_eq!(stats.total_created, 2);
+        assert_eq!(stats.total_acquired, 1);
+        assert!(PooledChannel::new_tcp(guard.stream_mut()).probe());
+        assert_eq!(pool.active_count.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_acquire_expires_idle_channel_on_mock_clock() {
+        use crate::clock::MockClock;
+
+        let config = PoolConfig {
+            min_tcp_channels: 1,
+            idle_timeout: 300,
+            ..Default::default()
+        };
+        let clock = Arc::new(MockClock::new());
+        let pool = TcpChannelPool::new_with_clock(
+            config,
+            Arc::new(MockTransport::default()),
+            AddrMaybeCached::new("127.0.0.1:2333"),
+            [0u8; 32],
+            clock.clone(),
+        )
+        .await
+        .unwrap();
+
+        // Within the idle timeout the warm channel is handed out as is
+        clock.advance(Duration::from_secs(300));
+        let guard = pool.acquire().await.unwrap();
+        assert_eq!(pool.stats().snapshot().total_expired, 0);
+        drop(guard);
+        while pool.channels.lock().await.is_empty() {
+            tokio::task::yield_now().await;
+        }
+
+        // Past it, the idle channel is expired and replaced on acquire
+        clock.advance(Duration::from_secs(301));
+        let _guard = pool.acquire().await.unwrap();
+        let stats = pool.stats().snapshot();
+        assert_eq!(stats.total_expired, 1);
+        assert_eq!(stats.total_created, 2);
+    }
+
+    #[tokio::test]
+    async fn test_reaper_replaces_idle_channel() {
+        use crate::clock::MockClock;
+
+        let config = PoolConfig {
+            min_tcp_channels: 1,
+            idle_timeout: 300,
+            reap_interval: 60,
+            // Keep the health check out of the way
+            health_check_interval: 3600,
+            ..Default::default()
+        };
+        let clock = Arc::new(MockClock::new());
+        let pool = TcpChannelPool::new_with_clock(
+            config,
+            Arc::new(MockTransport::default()),
+            AddrMaybeCached::new("127.0.0.1:2333"),
+            [0u8; 32],
+            clock.clone(),
+        )
+        .await
+        .unwrap();
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+
+        clock.advance(Duration::from_secs(301));
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while pool.stats().snapshot().total_created < 2 {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("the reaper should replace the idle channel");
+
+        let stats = pool.stats().snapshot();
+        assert_eq!(stats.total_expired, 1);
+        assert_eq!(stats.pooled_count, 1);
+        assert_eq!(pool.active_count.load(Ordering::Relaxed), 1);
+        let channels = pool.channels.lock().await;
+        assert!(!channels[0].is_stale_at(clock.now(), Duration::from_secs(300)));
+    }
+
+    /// A pool of one channel whose only channel is checked out
+    async fn exhausted_pool(
+        on_exhaustion: PoolExhaustion,
+    ) -> (
+        Arc<TcpChannelPool<MockTransport>>,
+        PooledChannelGuard<DuplexStream>,
+    ) {
+        let config = PoolConfig {
+            min_tcp_channels: 1,
+            max_tcp_channels: 1,
+            on_exhaustion,
+            ..Default::default()
+        };
+        let pool = TcpChannelPool::new(
+            config,
+            Arc::new(MockTransport::default()),
+            AddrMaybeCached::new("127.0.0.1:2333"),
+            [0u8; 32],
+        )
+        .await
+        .unwrap();
+        let guard = pool.acquire().await.unwrap();
+        (pool, guard)
+    }
+
+    #[tokio::test]
+    async fn test_exhausted_pool_waits_for_returned_channel() {
+        let (pool, guard) = exhausted_pool(PoolExhaustion::Wait).await;
+        let waiter = {
+            let pool = pool.clone();
+            tokio::spawn(async move { pool.acquire().await.map(drop) })
+        };
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        assert!(!waiter.is_finished());
+
+        drop(guard);
+        tokio::time::timeout(Duration::from_secs(2), waiter)
+            .await
+            .expect("waiter should get the returned channel")
+            .unwrap()
+            .unwrap();
+        assert_eq!(pool.stats().snapshot().total_created, 1);
+    }
+
+    #[tokio::test]
+    async fn test_exhausted_pool_wait_times_out() {
+        use crate::clock::MockClock;
+
+        let clock = Arc::new(MockClock::new());
+        let pool = TcpChannelPool::new_with_clock(
+            PoolConfig {
+                min_tcp_channels: 1,
+                max_tcp_channels: 1,
+                acquire_timeout: 5,
+                ..Default::default()
+            },
+            Arc::new(MockTransport::default()),
+            AddrMaybeCached::new("127.0.0.1:2333"),
+            [0u8; 32],
+            clock.clone(),
+        )
+        .await
+        .unwrap();
+        let _guard = pool.acquire().await.unwrap();
+
+        let waiter = {
+            let pool = pool.clone();
+            tokio::spawn(async move { pool.acquire().await.map(drop) })
+        };
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        clock.advance(Duration::from_secs(5));
+        let err = tokio::time::timeout(Duration::from_secs(2), waiter)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap_err();
+        assert!(err.to_string().contains("Timeout waiting"));
+    }
+
+    #[tokio::test]
+    async fn test_exhausted_pool_fails_fast() {
+        let (pool, _guard) = exhausted_pool(PoolExhaustion::Fail).await;
+        let err = pool.acquire().await.map(drop).unwrap_err();
+        assert!(err.to_string().contains("exhausted"));
+        assert_eq!(pool.stats().snapshot().total_created, 1);
+    }
+
+    #[tokio::test]
+    async fn test_exhausted_pool_overflows_temporarily() {
+        let (pool, guard) = exhausted_pool(PoolExhaustion::Overflow).await;
+        let overflow = pool.acquire().await.unwrap();
+        assert_eq!(pool.active_count.load(Ordering::Relaxed), 2);
+        assert_eq!(pool.stats().snapshot().total_created, 2);
+
+        // Returning the extra channel closes it, back down to the maximum
+        drop(overflow);
+        while pool.active_count.load(Ordering::Relaxed) > 1 {
+            tokio::task::yield_now().await;
+        }
+        assert!(pool.channels.lock().await.is_empty());
+
+        drop(guard);
+        while pool.channels.lock().await.is_empty() {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(pool.active_count.load(Ordering::Relaxed), 1);
+    }
 }
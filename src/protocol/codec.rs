@@ -4,18 +4,22 @@
 //! in a format compatible with rathole.
 
 use super::types::{
-    Ack, Auth, ControlChannelCmd, DataChannelCmd, Hello, UdpHeader, UdpTraffic,
-    CURRENT_PROTO_VERSION,
+    Ack, Auth, Capabilities, ClientId, ControlChannelCmd, DataChannelCmd, Hello, UdpHeader,
+    UdpTraffic, CURRENT_PROTO_VERSION, MAX_CLIENT_ID_LEN,
 };
 use anyhow::{bail, Context, Result};
 use bytes::BytesMut;
 use lazy_static::lazy_static;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use tracing::trace;
+use tracing::{trace, warn};
 
 /// Packet lengths for fixed-size protocol messages
 struct PacketLength {
     hello: usize,
+    /// Length of a [`Hello::ControlChannelHelloWithCaps`]
+    hello_with_caps: usize,
+    /// Leading bytes (the variant tag) of a [`Hello::ControlChannelHelloWithCaps`]
+    hello_with_caps_tag: Vec<u8>,
     ack: usize,
     auth: usize,
     c_cmd: usize,
@@ -28,6 +32,14 @@ impl PacketLength {
         let d = super::digest::digest(username.as_bytes());
         let hello = bincode::serialized_size(&Hello::ControlChannelHello(CURRENT_PROTO_VERSION, d))
             .unwrap() as usize;
+        let with_caps = bincode::serialize(&Hello::ControlChannelHelloWithCaps(
+            CURRENT_PROTO_VERSION,
+            d,
+            Capabilities::empty(),
+        ))
+        .unwrap();
+        let hello_with_caps = with_caps.len();
+        let hello_with_caps_tag = with_caps[..4].to_vec();
         let c_cmd =
             bincode::serialized_size(&ControlChannelCmd::CreateDataChannel).unwrap() as usize;
         let d_cmd = bincode::serialized_size(&DataChannelCmd::StartForwardTcp).unwrap() as usize;
@@ -36,6 +48,8 @@ impl PacketLength {
 
         PacketLength {
             hello,
+            hello_with_caps,
+            hello_with_caps_tag,
             ack,
             auth,
             c_cmd,
@@ -49,24 +63,31 @@ lazy_static! {
 }
 
 /// Read a Hello message from the stream
+///
+/// Legacy hellos have a fixed length. A [`Hello::ControlChannelHelloWithCaps`]
+/// is recognised by its variant tag and its trailing capabilities are read
+/// on top.
 pub async fn read_hello<T: AsyncRead + AsyncWrite + Unpin>(conn: &mut T) -> Result<Hello> {
     let mut buf = vec![0u8; PACKET_LEN.hello];
     conn.read_exact(&mut buf)
         .await
         .with_context(|| "Failed to read hello")?;
+    if buf.starts_with(&PACKET_LEN.hello_with_caps_tag) {
+        buf.resize(PACKET_LEN.hello_with_caps, 0);
+        conn.read_exact(&mut buf[PACKET_LEN.hello..])
+            .await
+            .with_context(|| "Failed to read hello capabilities")?;
+    }
     let hello: Hello = bincode::deserialize(&buf).with_context(|| "Failed to deserialize hello")?;
 
     // Verify protocol version
-    match &hello {
-        Hello::ControlChannelHello(v, _) | Hello::DataChannelHello(v, _) => {
-            if *v != CURRENT_PROTO_VERSION {
-                bail!(
-                    "Protocol version mismatched. Expected {}, got {}. Please update the client.",
-                    CURRENT_PROTO_VERSION,
-                    v
-                );
-            }
-        }
+    let v = hello.version();
+    if v != CURRENT_PROTO_VERSION {
+        bail!(
+            "Protocol version mismatched. Expected {}, got {}. Please update the client.",
+            CURRENT_PROTO_VERSION,
+            v
+        );
     }
 
     Ok(hello)
@@ -103,6 +124,47 @@ pub async fn write_auth<T: AsyncWrite + Unpin>(conn: &mut T, auth: &Auth) -> Res
     Ok(())
 }
 
+/// Read a ClientId message from the stream
+pub async fn read_client_id<T: AsyncRead + Unpin>(conn: &mut T) -> Result<ClientId> {
+    let mut len = [0u8; 8];
+    conn.read_exact(&mut len)
+        .await
+        .with_context(|| "Failed to read client id length")?;
+    let len = u64::from_le_bytes(len);
+    if len > MAX_CLIENT_ID_LEN as u64 {
+        bail!(
+            "Client id of {} bytes exceeds the maximum of {}",
+            len,
+            MAX_CLIENT_ID_LEN
+        );
+    }
+    let mut buf = vec![0u8; len as usize];
+    conn.read_exact(&mut buf)
+        .await
+        .with_context(|| "Failed to read client id")?;
+    let id = String::from_utf8(buf).with_context(|| "Client id is not UTF-8")?;
+    Ok(ClientId(id))
+}
+
+/// Write a ClientId message to the stream
+pub async fn write_client_id<T: AsyncWrite + Unpin>(conn: &mut T, id: &ClientId) -> Result<()> {
+    if id.0.len() > MAX_CLIENT_ID_LEN {
+        bail!(
+            "Client id of {} bytes exceeds the maximum of {}",
+            id.0.len(),
+            MAX_CLIENT_ID_LEN
+        );
+    }
+    let buf = bincode::serialize(id).with_context(|| "Failed to serialize client id")?;
+    conn.write_all(&buf)
+        .await
+        .with_context(|| "Failed to write client id")?;
+    conn.flush()
+        .await
+        .with_context(|| "Failed to flush client id")?;
+    Ok(())
+}
+
 /// Read an Ack message from the stream
 pub async fn read_ack<T: AsyncRead + AsyncWrite + Unpin>(conn: &mut T) -> Result<Ack> {
     let mut buf = vec![0u8; PACKET_LEN.ack];
@@ -133,6 +195,28 @@ pub async fn read_control_cmd<T: AsyncRead + AsyncWrite + Unpin>(
     bincode::deserialize(&buf).with_context(|| "Failed to deserialize control cmd")
 }
 
+/// Read a ControlChannelCmd from the stream, skipping unknown commands
+///
+/// Returns `None` for a command this client does not know, so newer
+/// servers can add commands without dropping older clients. Commands are
+/// fixed-size unit variants, so an unknown one is consumed whole and the
+/// stream stays in sync.
+pub async fn read_control_cmd_lenient<T: AsyncRead + Unpin>(
+    conn: &mut T,
+) -> Result<Option<ControlChannelCmd>> {
+    let mut buf = vec![0u8; PACKET_LEN.c_cmd];
+    conn.read_exact(&mut buf)
+        .await
+        .with_context(|| "Failed to read control cmd")?;
+    match bincode::deserialize(&buf) {
+        Ok(cmd) => Ok(Some(cmd)),
+        Err(e) => {
+            warn!("Ignoring unknown control cmd {:02x?}: {}", buf, e);
+            Ok(None)
+        }
+    }
+}
+
 /// Write a ControlChannelCmd to the stream
 pub async fn write_control_cmd<T: AsyncWrite + Unpin>(
     conn: &mut T,
@@ -268,6 +352,70 @@ mod tests {
         assert_eq!(original, received);
     }
 
+    #[tokio::test]
+    async fn test_hello_with_caps_roundtrip() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+
+        let caps = Capabilities::COMPRESSION | Capabilities::from_bits(1 << 7);
+        let original = Hello::ControlChannelHelloWithCaps(CURRENT_PROTO_VERSION, [3u8; 32], caps);
+
+        write_hello(&mut server, &original).await.unwrap();
+        // The message after the hello must still be read in sync
+        write_ack(&mut server, &Ack::Ok).await.unwrap();
+
+        let received = read_hello(&mut client).await.unwrap();
+        assert_eq!(received, original);
+        assert_eq!(received.capabilities(), caps);
+        assert_eq!(read_ack(&mut client).await.unwrap(), Ack::Ok);
+    }
+
+    #[tokio::test]
+    async fn test_client_id_roundtrip() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+
+        let id = ClientId("edge-01 sockrats/0.1.0".to_string());
+        write_client_id(&mut client, &id).await.unwrap();
+        write_auth(&mut client, &Auth([1u8; 32])).await.unwrap();
+
+        assert_eq!(read_client_id(&mut server).await.unwrap(), id);
+        assert_eq!(read_auth(&mut server).await.unwrap(), Auth([1u8; 32]));
+
+        // A length-prefixed string, as bincode encodes it
+        let bytes = bincode::serialize(&id).unwrap();
+        assert_eq!(bytes[..8], (id.0.len() as u64).to_le_bytes());
+        assert_eq!(&bytes[8..], id.0.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_client_id_length_is_capped() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+
+        let long = ClientId("x".repeat(MAX_CLIENT_ID_LEN + 1));
+        assert!(write_client_id(&mut client, &long).await.is_err());
+
+        client.write_all(&u64::MAX.to_le_bytes()).await.unwrap();
+        assert!(read_client_id(&mut server).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_legacy_hello_has_no_caps() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+
+        let original = Hello::ControlChannelHello(CURRENT_PROTO_VERSION, [3u8; 32]);
+        write_hello(&mut server, &original).await.unwrap();
+        write_ack(&mut server, &Ack::Ok).await.unwrap();
+
+        let received = read_hello(&mut client).await.unwrap();
+        assert_eq!(received, original);
+        assert_eq!(received.capabilities(), Capabilities::empty());
+        assert_eq!(read_ack(&mut client).await.unwrap(), Ack::Ok);
+
+        // The legacy encoding is unchanged: tag, version, digest
+        let bytes = bincode::serialize(&original).unwrap();
+        assert_eq!(bytes.len(), 37);
+        assert_eq!(bytes[..4], [0, 0, 0, 0]);
+    }
+
     #[tokio::test]
     async fn test_hello_version_mismatch() {
         let (mut client, mut server) = tokio::io::duplex(1024);
@@ -300,6 +448,40 @@ mod tests {
         assert_eq!(original, received);
     }
 
+    #[tokio::test]
+    async fn test_auth_uses_server_nonce() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+
+        // Server sends its nonce in the control channel hello
+        let nonce = [9u8; 32];
+        write_hello(
+            &mut server,
+            &Hello::ControlChannelHello(CURRENT_PROTO_VERSION, nonce),
+        )
+        .await
+        .unwrap();
+
+        // Client answers with a digest over the nonce it read
+        let received_nonce = *read_hello(&mut client).await.unwrap().digest();
+        write_auth(&mut client, &Auth::new("my-token", &received_nonce))
+            .await
+            .unwrap();
+
+        let auth = read_auth(&mut server).await.unwrap();
+        assert_eq!(auth, Auth::new("my-token", &nonce));
+        assert_ne!(auth, Auth::new("my-token", &[0u8; 32]));
+    }
+
+    #[tokio::test]
+    async fn test_ack_rechallenge_keeps_ack_length() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+
+        write_ack(&mut client, &Ack::Rechallenge).await.unwrap();
+        write_ack(&mut client, &Ack::Ok).await.unwrap();
+        assert_eq!(read_ack(&mut server).await.unwrap(), Ack::Rechallenge);
+        assert_eq!(read_ack(&mut server).await.unwrap(), Ack::Ok);
+    }
+
     #[tokio::test]
     async fn test_ack_ok_roundtrip() {
         let (mut client, mut server) = tokio::io::duplex(1024);
@@ -349,6 +531,22 @@ mod tests {
         assert_eq!(ControlChannelCmd::HeartBeat, received);
     }
 
+    #[tokio::test]
+    async fn test_read_control_cmd_lenient_skips_unknown() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+
+        client.write_all(&[0x2a, 0, 0, 0]).await.unwrap();
+        write_control_cmd(&mut client, &ControlChannelCmd::HeartBeat)
+            .await
+            .unwrap();
+
+        assert_eq!(read_control_cmd_lenient(&mut server).await.unwrap(), None);
+        assert_eq!(
+            read_control_cmd_lenient(&mut server).await.unwrap(),
+            Some(ControlChannelCmd::HeartBeat)
+        );
+    }
+
     #[tokio::test]
     async fn test_data_cmd_start_forward_tcp() {
         let (mut client, mut server) = tokio::io::duplex(1024);
@@ -458,7 +656,12 @@ mod tests {
 
     #[test]
     fn test_ack_serialization() {
-        for ack in [Ack::Ok, Ack::ServiceNotExist, Ack::AuthFailed] {
+        for ack in [
+            Ack::Ok,
+            Ack::ServiceNotExist,
+            Ack::AuthFailed,
+            Ack::Rechallenge,
+        ] {
             let serialized = bincode::serialize(&ack).unwrap();
             let deserialized: Ack = bincode::deserialize(&serialized).unwrap();
             assert_eq!(ack, deserialized);
@@ -470,6 +673,7 @@ mod tests {
         for cmd in [
             ControlChannelCmd::CreateDataChannel,
             ControlChannelCmd::HeartBeat,
+            ControlChannelCmd::Rechallenge,
         ] {
             let serialized = bincode::serialize(&cmd).unwrap();
             let deserialized: ControlChannelCmd = bincode::deserialize(&serialized).unwrap();
@@ -4,6 +4,7 @@
 
 use super::types::{Digest, HASH_WIDTH_IN_BYTES};
 use sha2::{Digest as Sha2Digest, Sha256};
+use subtle::ConstantTimeEq;
 
 /// Compute SHA-256 digest of data
 ///
@@ -33,6 +34,24 @@ pub fn digest(data: &[u8]) -> Digest {
     result
 }
 
+/// Compare two byte slices in constant time
+///
+/// The running time depends only on the slice lengths, never on where the
+/// first mismatching byte is, so it is safe for comparing digests, tokens
+/// and passwords. Slices of different lengths compare unequal.
+///
+/// # Example
+///
+/// ```
+/// use sockrats::protocol::{constant_time_eq, digest};
+///
+/// assert!(constant_time_eq(&digest(b"token"), &digest(b"token")));
+/// assert!(!constant_time_eq(&digest(b"token"), &digest(b"other")));
+/// ```
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.ct_eq(b).into()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -81,4 +100,30 @@ mod tests {
         let hash = digest(b"any data");
         assert_eq!(hash.len(), 32);
     }
+
+    #[test]
+    fn test_constant_time_eq_equal() {
+        assert!(constant_time_eq(b"hello", b"hello"));
+        assert!(constant_time_eq(b"", b""));
+        assert!(constant_time_eq(b"a", b"a"));
+    }
+
+    #[test]
+    fn test_constant_time_eq_not_equal() {
+        assert!(!constant_time_eq(b"hello", b"world"));
+        assert!(!constant_time_eq(b"hello", b"hell"));
+        assert!(!constant_time_eq(b"a", b"b"));
+    }
+
+    #[test]
+    fn test_constant_time_eq_different_lengths() {
+        assert!(!constant_time_eq(b"short", b"longer"));
+        assert!(!constant_time_eq(b"hello", b""));
+    }
+
+    #[test]
+    fn test_constant_time_eq_binary() {
+        assert!(constant_time_eq(&[0, 1, 2, 3], &[0, 1, 2, 3]));
+        assert!(!constant_time_eq(&[0, 1, 2, 3], &[0, 1, 2, 4]));
+    }
 }
@@ -24,6 +24,52 @@ pub const CURRENT_PROTO_VERSION: ProtocolVersion = PROTO_V1;
 /// Digest type (32-byte SHA-256 hash)
 pub type Digest = [u8; HASH_WIDTH_IN_BYTES];
 
+/// Optional protocol features a peer supports
+///
+/// Advertised by servers in [`Hello::ControlChannelHelloWithCaps`]. Peers
+/// that send the legacy [`Hello::ControlChannelHello`] support none.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    /// Data channel compression (reserved)
+    pub const COMPRESSION: Capabilities = Capabilities(1 << 0);
+    /// Data channel multiplexing (reserved)
+    pub const MULTIPLEX: Capabilities = Capabilities(1 << 1);
+    /// Accepts a [`ClientId`] right after each [`Auth`]
+    pub const CLIENT_ID: Capabilities = Capabilities(1 << 2);
+    /// May send [`Ack::Rechallenge`] and [`ControlChannelCmd::Rechallenge`]
+    pub const RECHALLENGE: Capabilities = Capabilities(1 << 3);
+
+    /// No optional features
+    pub const fn empty() -> Self {
+        Capabilities(0)
+    }
+
+    /// Capabilities from raw bits, keeping bits this client does not know
+    pub const fn from_bits(bits: u32) -> Self {
+        Capabilities(bits)
+    }
+
+    /// The raw bits
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Whether every feature in `other` is supported
+    pub const fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for Capabilities {
+    type Output = Capabilities;
+
+    fn bitor(self, rhs: Capabilities) -> Capabilities {
+        Capabilities(self.0 | rhs.0)
+    }
+}
+
 /// Hello message for establishing channels
 ///
 /// This is the first message sent when establishing a control or data channel.
@@ -33,6 +79,14 @@ pub enum Hello {
     ControlChannelHello(ProtocolVersion, Digest),
     /// Data channel hello: (protocol version, session key)
     DataChannelHello(ProtocolVersion, Digest),
+    /// Control channel hello carrying the sender's capabilities:
+    /// (protocol version, nonce, capabilities)
+    ///
+    /// Sent by servers with optional features in place of
+    /// `ControlChannelHello`. Being a later variant, it keeps the legacy
+    /// variants' encoding, and [`read_hello`](super::read_hello) reads the
+    /// trailing capabilities only when this variant's tag comes in.
+    ControlChannelHelloWithCaps(ProtocolVersion, Digest, Capabilities),
 }
 
 impl Hello {
@@ -52,6 +106,7 @@ impl Hello {
         match self {
             Hello::ControlChannelHello(v, _) => *v,
             Hello::DataChannelHello(v, _) => *v,
+            Hello::ControlChannelHelloWithCaps(v, _, _) => *v,
         }
     }
 
@@ -60,6 +115,15 @@ impl Hello {
         match self {
             Hello::ControlChannelHello(_, d) => d,
             Hello::DataChannelHello(_, d) => d,
+            Hello::ControlChannelHelloWithCaps(_, d, _) => d,
+        }
+    }
+
+    /// Get the capabilities the sender advertised (none for legacy hellos)
+    pub fn capabilities(&self) -> Capabilities {
+        match self {
+            Hello::ControlChannelHelloWithCaps(_, _, caps) => *caps,
+            _ => Capabilities::empty(),
         }
     }
 }
@@ -77,8 +141,27 @@ impl Auth {
         concat.extend_from_slice(nonce);
         Auth(super::digest::digest(&concat))
     }
+
+    /// Check this auth against the digest expected for `token` and `nonce`
+    ///
+    /// The comparison is constant-time.
+    pub fn verify(&self, token: &str, nonce: &Digest) -> bool {
+        super::digest::constant_time_eq(&self.0, &Auth::new(token, nonce).0)
+    }
 }
 
+/// Longest [`ClientId`] in bytes
+pub const MAX_CLIENT_ID_LEN: usize = 255;
+
+/// Identifying string of the client, for server-side observability
+///
+/// A sockrats extension: rathole servers do not know it, so it is only
+/// sent to servers that advertise [`Capabilities::CLIENT_ID`]. Encoded as
+/// a bincode string, a little-endian `u64` byte length followed by the
+/// UTF-8 bytes, of at most [`MAX_CLIENT_ID_LEN`] bytes.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct ClientId(pub String);
+
 /// Acknowledgment message
 ///
 /// Sent by the server in response to authentication.
@@ -90,6 +173,12 @@ pub enum Ack {
     ServiceNotExist,
     /// Authentication failed (wrong token)
     AuthFailed,
+    /// The server rotated its nonce; a new `ControlChannelHello` carrying
+    /// the fresh nonce follows and must be answered with a new [`Auth`]
+    ///
+    /// A sockrats extension that rathole servers never send; the client
+    /// only accepts it from servers advertising [`Capabilities::RECHALLENGE`].
+    Rechallenge,
 }
 
 impl std::fmt::Display for Ack {
@@ -101,6 +190,7 @@ impl std::fmt::Display for Ack {
                 Ack::Ok => "Ok",
                 Ack::ServiceNotExist => "Service not exist",
                 Ack::AuthFailed => "Incorrect token",
+                Ack::Rechallenge => "Re-authentication required",
             }
         )
     }
@@ -122,6 +212,12 @@ pub enum ControlChannelCmd {
     CreateDataChannel,
     /// Heartbeat to keep connection alive
     HeartBeat,
+    /// Re-authenticate mid-session: a `ControlChannelHello` carrying a new
+    /// nonce follows, and the resulting digest becomes the session key
+    ///
+    /// A sockrats extension like [`Ack::Rechallenge`], accepted only from
+    /// servers advertising [`Capabilities::RECHALLENGE`].
+    Rechallenge,
 }
 
 /// Data channel commands
@@ -193,6 +289,22 @@ mod tests {
         assert_eq!(hello.digest(), &session_key);
     }
 
+    #[test]
+    fn test_capabilities() {
+        let caps = Capabilities::COMPRESSION
+            | Capabilities::MULTIPLEX
+            | Capabilities::CLIENT_ID
+            | Capabilities::RECHALLENGE;
+        assert!(caps.contains(Capabilities::COMPRESSION));
+        assert!(caps.contains(Capabilities::empty()));
+        assert!(!Capabilities::COMPRESSION.contains(caps));
+        assert_eq!(Capabilities::from_bits(caps.bits()), caps);
+        assert_eq!(
+            Hello::control_channel("svc").capabilities(),
+            Capabilities::empty()
+        );
+    }
+
     #[test]
     fn test_auth_new() {
         let nonce = [1u8; HASH_WIDTH_IN_BYTES];
@@ -200,11 +312,41 @@ mod tests {
         assert_eq!(auth.0.len(), HASH_WIDTH_IN_BYTES);
     }
 
+    #[test]
+    fn test_auth_verify() {
+        let nonce = [3u8; HASH_WIDTH_IN_BYTES];
+        let auth = Auth::new("secret-token", &nonce);
+        assert!(auth.verify("secret-token", &nonce));
+        assert!(!auth.verify("wrong-token", &nonce));
+        assert!(!auth.verify("secret-token", &[4u8; HASH_WIDTH_IN_BYTES]));
+    }
+
+    #[test]
+    fn test_auth_digest_includes_nonce() {
+        let nonce = [7u8; HASH_WIDTH_IN_BYTES];
+        let mut concat = b"secret-token".to_vec();
+        concat.extend_from_slice(&nonce);
+        assert_eq!(
+            Auth::new("secret-token", &nonce).0,
+            super::super::digest::digest(&concat)
+        );
+
+        let rotated = [8u8; HASH_WIDTH_IN_BYTES];
+        assert_ne!(
+            Auth::new("secret-token", &nonce),
+            Auth::new("secret-token", &rotated)
+        );
+    }
+
     #[test]
     fn test_ack_display() {
         assert_eq!(format!("{}", Ack::Ok), "Ok");
         assert_eq!(format!("{}", Ack::ServiceNotExist), "Service not exist");
         assert_eq!(format!("{}", Ack::AuthFailed), "Incorrect token");
+        assert_eq!(
+            format!("{}", Ack::Rechallenge),
+            "Re-authentication required"
+        );
     }
 
     #[test]
@@ -212,6 +354,7 @@ mod tests {
         assert!(Ack::Ok.is_ok());
         assert!(!Ack::ServiceNotExist.is_ok());
         assert!(!Ack::AuthFailed.is_ok());
+        assert!(!Ack::Rechallenge.is_ok());
     }
 
     #[test]
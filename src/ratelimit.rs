@@ -0,0 +1,167 @@
+//! Token-bucket bandwidth limiting
+//!
+//! A [`RateLimiter`] admits bytes at a fixed rate, allowing bursts of up to
+//! one second's worth. Relays wait on it before each write, so a connection
+//! can throttle on its own limiter and on limiters shared with others at
+//! the same time.
+
+use crate::clock::{Clock, TokioClock};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Token bucket admitting `bytes_per_sec` bytes per second
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// Refill rate, and bucket capacity
+    bytes_per_sec: f64,
+    clock: Arc<dyn Clock>,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Available tokens; negative while callers wait out a debt
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl RateLimiter {
+    /// Create a limiter starting with a full bucket
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self::with_clock(bytes_per_sec, Arc::new(TokioClock))
+    }
+
+    /// Create a limiter measuring time with `clock`
+    pub fn with_clock(bytes_per_sec: u64, clock: Arc<dyn Clock>) -> Self {
+        let bytes_per_sec = bytes_per_sec.max(1) as f64;
+        RateLimiter {
+            bytes_per_sec,
+            bucket: Mutex::new(Bucket {
+                tokens: bytes_per_sec,
+                refilled: clock.now(),
+            }),
+            clock,
+        }
+    }
+
+    /// Wait until `bytes` may be sent
+    ///
+    /// The bytes are taken straight away, leaving the bucket in debt if it
+    /// did not hold enough, so concurrent callers queue behind each other
+    /// instead of racing for the refill.
+    pub async fn acquire(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+            let now = self.clock.now();
+            let elapsed = now.saturating_duration_since(bucket.refilled).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * self.bytes_per_sec).min(self.bytes_per_sec);
+            bucket.refilled = now;
+            bucket.tokens -= bytes as f64;
+            if bucket.tokens < 0.0 {
+                Duration::from_secs_f64(-bucket.tokens / self.bytes_per_sec)
+            } else {
+                Duration::ZERO
+            }
+        };
+        if !wait.is_zero() {
+            self.clock.sleep(wait).await;
+        }
+    }
+}
+
+/// Limiters for the two directions of a relay
+///
+/// `up` throttles bytes from the tunnel client towards the target, `down`
+/// the reverse. Either may hold several limiters, such as one for the
+/// connection and one shared by every connection of a service.
+#[derive(Debug, Clone, Default)]
+pub struct Bandwidth {
+    /// Limiters for bytes towards the target
+    pub up: Vec<Arc<RateLimiter>>,
+    /// Limiters for bytes towards the tunnel client
+    pub down: Vec<Arc<RateLimiter>>,
+}
+
+impl Bandwidth {
+    /// Fresh limiters of `bytes_per_sec` for each direction (0 = unlimited)
+    pub fn new(bytes_per_sec: u64) -> Self {
+        if bytes_per_sec == 0 {
+            return Bandwidth::default();
+        }
+        Bandwidth {
+            up: vec![Arc::new(RateLimiter::new(bytes_per_sec))],
+            down: vec![Arc::new(RateLimiter::new(bytes_per_sec))],
+        }
+    }
+
+    /// Also throttle on the limiters of `other`
+    pub fn and(mut self, other: &Bandwidth) -> Self {
+        self.up.extend(other.up.iter().cloned());
+        self.down.extend(other.down.iter().cloned());
+        self
+    }
+
+    /// Whether neither direction is limited
+    pub fn is_unlimited(&self) -> bool {
+        self.up.is_empty() && self.down.is_empty()
+    }
+}
+
+/// Wait on every limiter in `limiters` for `bytes`
+pub async fn acquire_all(limiters: &[Arc<RateLimiter>], bytes: usize) {
+    for limiter in limiters {
+        limiter.acquire(bytes).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[tokio::test]
+    async fn test_burst_then_throttle() {
+        let clock = Arc::new(MockClock::new());
+        let limiter = Arc::new(RateLimiter::with_clock(1000, clock.clone()));
+
+        // A full bucket admits one second's worth at once
+        limiter.acquire(1000).await;
+
+        let waiter = {
+            let limiter = limiter.clone();
+            tokio::spawn(async move { limiter.acquire(500).await })
+        };
+        tokio::task::yield_now().await;
+        clock.advance(Duration::from_millis(400));
+        tokio::task::yield_now().await;
+        assert!(!waiter.is_finished());
+
+        clock.advance(Duration::from_millis(100));
+        tokio::time::timeout(Duration::from_secs(1), waiter)
+            .await
+            .expect("500 bytes at 1000 B/s should wait 500ms")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_idle_refill_is_capped() {
+        let clock = Arc::new(MockClock::new());
+        let limiter = RateLimiter::with_clock(100, clock.clone());
+        limiter.acquire(100).await;
+
+        // An hour idle still only refills one second's worth
+        clock.advance(Duration::from_secs(3600));
+        limiter.acquire(100).await;
+        let bucket = limiter.bucket.lock().unwrap();
+        assert_eq!(bucket.tokens, 0.0);
+    }
+
+    #[test]
+    fn test_bandwidth_zero_is_unlimited() {
+        assert!(Bandwidth::new(0).is_unlimited());
+        let shared = Bandwidth::new(10);
+        let combined = Bandwidth::new(20).and(&shared);
+        assert_eq!(combined.up.len(), 2);
+        assert!(Arc::ptr_eq(&combined.down[1], &shared.down[0]));
+    }
+}
@@ -0,0 +1,418 @@
+//! Prometheus metrics service for Sockrats
+//!
+//! A `metrics` service answers each data channel with a Prometheus
+//! text-format exposition of the counters of every other configured
+//! service and histograms of their connection durations and throughput,
+//! plus the gauges of any connection pools it was given. It speaks
+//! just enough HTTP/1.1 for a scraper: it reads one request head, answers a
+//! `GET` and closes the connection.
+//!
+//! ```toml
+//! [[client.services]]
+//! name = "metrics"
+//! service_type = "metrics"
+//! token = "metrics-token"
+//! ```
+
+use crate::pool::{PoolManager, PoolStats, PoolStatsSnapshot};
+use crate::services::stats::{DURATION_BUCKETS_MS, THROUGHPUT_BUCKETS};
+use crate::services::{
+    CloseReason, ConnectionSummary, CountedStream, HistogramSnapshot, ServiceHandler,
+    ServiceRegistry, ServiceStatsSnapshot, StreamDyn,
+};
+use anyhow::{Context, Result};
+use std::fmt::Write as _;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+
+/// Longest request head accepted from a scraper
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+
+/// How long a scraper has to send its request head
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Content type of the Prometheus text exposition format
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Metrics service handler implementing the [`ServiceHandler`] trait.
+///
+/// Holds the handlers whose statistics it exports, so it reads live
+/// counters on every scrape.
+#[derive(Debug, Clone, Default)]
+pub struct MetricsServiceHandler {
+    services: ServiceRegistry,
+    pools: Vec<(String, Arc<PoolStats>)>,
+}
+
+impl MetricsServiceHandler {
+    /// Create a handler exporting the statistics of the handlers in
+    /// `services`.
+    pub fn new(services: ServiceRegistry) -> Self {
+        Self {
+            services,
+            pools: Vec::new(),
+        }
+    }
+
+    /// Also export the gauges of the pool managed by `manager`
+    pub fn with_pool(mut self, name: impl Into<String>, manager: &PoolManager) -> Self {
+        self.pools.push((name.into(), manager.stats().clone()));
+        self
+    }
+
+    /// Render the current metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        let services = self.services.stats();
+        let pools: Vec<_> = self
+            .pools
+            .iter()
+            .map(|(name, stats)| (name, stats.snapshot()))
+            .collect();
+
+        let mut out = String::new();
+        let service = |value: fn(&ServiceStatsSnapshot) -> u64| {
+            services
+                .iter()
+                .map(move |(name, stats)| (("service", name.as_str()), value(stats)))
+        };
+        write_family(
+            &mut out,
+            "sockrats_service_active_connections",
+            "Connections currently being handled",
+            "gauge",
+            service(|s| s.active_connections),
+        );
+        write_family(
+            &mut out,
+            "sockrats_service_connections_total",
+            "Connections handled since startup",
+            "counter",
+            service(|s| s.total_connections),
+        );
+        write_family(
+            &mut out,
+            "sockrats_service_bytes_up_total",
+            "Bytes sent from tunnel clients towards targets",
+            "counter",
+            service(|s| s.bytes_up),
+        );
+        write_family(
+            &mut out,
+            "sockrats_service_bytes_down_total",
+            "Bytes sent from targets back to tunnel clients",
+            "counter",
+            service(|s| s.bytes_down),
+        );
+        write_family(
+            &mut out,
+            "sockrats_service_errors_total",
+            "Connections that ended in an error",
+            "counter",
+            service(|s| s.errors),
+        );
+        write_histogram(
+            &mut out,
+            "sockrats_service_connection_duration_seconds",
+            "How long closed connections lasted",
+            &DURATION_BUCKETS_MS,
+            // Recorded in milliseconds
+            1000.0,
+            services
+                .iter()
+                .map(|(name, stats)| (name.as_str(), stats.duration_ms)),
+        );
+        write_histogram(
+            &mut out,
+            "sockrats_service_connection_throughput_bytes_per_second",
+            "Average throughput of closed connections, both directions",
+            &THROUGHPUT_BUCKETS,
+            1.0,
+            services
+                .iter()
+                .map(|(name, stats)| (name.as_str(), stats.throughput)),
+        );
+
+        if !pools.is_empty() {
+            let pool = |value: fn(&PoolStatsSnapshot) -> usize| {
+                pools
+                    .iter()
+                    .map(move |(name, stats)| (("pool", name.as_str()), value(stats) as u64))
+            };
+            write_family(
+                &mut out,
+                "sockrats_pool_channels_pooled",
+                "Idle channels waiting in the pool",
+                "gauge",
+                pool(|p| p.pooled_count),
+            );
+            write_family(
+                &mut out,
+                "sockrats_pool_channels_in_use",
+                "Channels taken from the pool and not yet returned",
+                "gauge",
+                pool(|p| p.in_use_count),
+            );
+            write_family(
+                &mut out,
+                "sockrats_pool_channels_created_total",
+                "Channels the pool has created",
+                "counter",
+                pool(|p| p.total_created),
+            );
+            write_family(
+                &mut out,
+                "sockrats_pool_channels_expired_total",
+                "Channels the pool has dropped as stale",
+                "counter",
+                pool(|p| p.total_expired),
+            );
+        }
+        out
+    }
+}
+
+#[async_trait::async_trait]
+impl ServiceHandler for MetricsServiceHandler {
+    fn service_type(&self) -> &str {
+        "metrics"
+    }
+
+    async fn handle_tcp_stream(&self, stream: Box<dyn StreamDyn>) -> Result<ConnectionSummary> {
+        let start = Instant::now();
+        let (mut stream, counters) = CountedStream::new(stream);
+
+        let head = tokio::time::timeout(REQUEST_TIMEOUT, read_request_head(&mut stream))
+            .await
+            .context("metrics request timed out")??;
+        let (status, body, close_reason) = if head.starts_with(b"GET ") {
+            ("200 OK", self.render(), CloseReason::Completed)
+        } else {
+            (
+                "405 Method Not Allowed",
+                "only GET is supported\n".to_string(),
+                CloseReason::Rejected,
+            )
+        };
+
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            CONTENT_TYPE,
+            body.len(),
+            body
+        );
+        stream.write_all(response.as_bytes()).await?;
+        stream.shutdown().await?;
+
+        Ok(ConnectionSummary::new(close_reason)
+            .with_bytes(counters.read(), counters.written())
+            .with_duration(start.elapsed()))
+    }
+}
+
+/// Read up to and including the blank line that ends an HTTP request head
+async fn read_request_head<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Vec<u8>> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() > MAX_REQUEST_HEAD {
+            anyhow::bail!("metrics request head exceeds {} bytes", MAX_REQUEST_HEAD);
+        }
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            anyhow::bail!("metrics client closed before sending a request");
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    Ok(head)
+}
+
+/// Write one metric family: its `# HELP` and `# TYPE` lines, then a sample
+/// per `(label, value)`
+fn write_family<'a>(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    kind: &str,
+    samples: impl Iterator<Item = ((&'a str, &'a str), u64)>,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for ((label, value), sample) in samples {
+        let _ = writeln!(
+            out,
+            "{}{{{}=\"{}\"}} {}",
+            name,
+            label,
+            escape_label(value),
+            sample
+        );
+    }
+}
+
+/// Write one histogram family over the bucket bounds `bounds`, with a
+/// series per service, dividing recorded values by `scale` to get the
+/// exported unit
+fn write_histogram<'a, const N: usize>(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    bounds: &[u64; N],
+    scale: f64,
+    histograms: impl Iterator<Item = (&'a str, HistogramSnapshot<N>)>,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} histogram", name);
+    for (service, histogram) in histograms {
+        let service = escape_label(service);
+        for (bound, count) in bounds.iter().zip(histogram.buckets) {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{service=\"{}\",le=\"{}\"}} {}",
+                name,
+                service,
+                *bound as f64 / scale,
+                count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{service=\"{}\",le=\"+Inf\"}} {}",
+            name, service, histogram.count
+        );
+        let _ = writeln!(
+            out,
+            "{}_sum{{service=\"{}\"}} {}",
+            name,
+            service,
+            histogram.sum as f64 / scale
+        );
+        let _ = writeln!(
+            out,
+            "{}_count{{service=\"{}\"}} {}",
+            name, service, histogram.count
+        );
+    }
+}
+
+/// Escape a label value for the text exposition format
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PoolConfig;
+    use crate::services::ServiceStats;
+
+    #[derive(Debug, Default)]
+    struct CountingHandler {
+        stats: ServiceStats,
+    }
+
+    #[async_trait::async_trait]
+    impl ServiceHandler for CountingHandler {
+        fn service_type(&self) -> &str {
+            "counting"
+        }
+
+        async fn handle_tcp_stream(
+            &self,
+            _stream: Box<dyn StreamDyn>,
+        ) -> Result<ConnectionSummary> {
+            self.stats
+                .track(async {
+                    Ok(ConnectionSummary::new(CloseReason::ClientClosed).with_bytes(100, 2048))
+                })
+                .await
+        }
+
+        fn stats(&self) -> Option<&ServiceStats> {
+            Some(&self.stats)
+        }
+    }
+
+    async fn scrape(handler: &MetricsServiceHandler, request: &[u8]) -> String {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        client.write_all(request).await.unwrap();
+        handler.handle_tcp_stream(Box::new(server)).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_scrape_exports_service_counters() {
+        let counting = Arc::new(CountingHandler::default());
+        let mut registry = ServiceRegistry::new();
+        registry.register("socks\"1".to_string(), counting.clone());
+        let handler = MetricsServiceHandler::new(registry);
+
+        let (stream, _peer) = tokio::io::duplex(64);
+        counting.handle_tcp_stream(Box::new(stream)).await.unwrap();
+
+        let response = scrape(&handler, b"GET /metrics HTTP/1.1\r\nHost: x\r\n\r\n").await;
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK"));
+        assert!(head.contains(&format!("Content-Length: {}", body.len())));
+        assert!(body.contains("# HELP sockrats_service_bytes_down_total "));
+        assert!(body.contains("# TYPE sockrats_service_bytes_down_total counter\n"));
+        assert!(body.contains("# TYPE sockrats_service_active_connections gauge\n"));
+        assert!(body.contains("sockrats_service_bytes_down_total{service=\"socks\\\"1\"} 2048\n"));
+        assert!(body.contains("sockrats_service_connections_total{service=\"socks\\\"1\"} 1\n"));
+        assert!(!body.contains("sockrats_pool_"));
+
+        // The connection closed at once: in the lowest duration bucket
+        let duration = "sockrats_service_connection_duration_seconds";
+        assert!(body.contains(&format!("# TYPE {} histogram\n", duration)));
+        assert!(body.contains(&format!(
+            "{}_bucket{{service=\"socks\\\"1\",le=\"0.1\"}} 1\n",
+            duration
+        )));
+        assert!(body.contains(&format!(
+            "{}_bucket{{service=\"socks\\\"1\",le=\"+Inf\"}} 1\n",
+            duration
+        )));
+        assert!(body.contains(&format!("{}_count{{service=\"socks\\\"1\"}} 1\n", duration)));
+        assert!(body.contains(
+            "# TYPE sockrats_service_connection_throughput_bytes_per_second histogram\n"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_scrape_exports_pool_gauges() {
+        let stats = Arc::new(PoolStats::new());
+        stats.record_created();
+        stats.record_acquired();
+        let manager = PoolManager::new(PoolConfig::default(), stats);
+        let handler = MetricsServiceHandler::default().with_pool("tcp", &manager);
+
+        let body = handler.render();
+        assert!(body.contains("# TYPE sockrats_pool_channels_in_use gauge\n"));
+        assert!(body.contains("sockrats_pool_channels_in_use{pool=\"tcp\"} 1\n"));
+        assert!(body.contains("sockrats_pool_channels_created_total{pool=\"tcp\"} 1\n"));
+    }
+
+    #[tokio::test]
+    async fn test_non_get_is_rejected() {
+        let handler = MetricsServiceHandler::default();
+        let response = scrape(&handler, b"POST /metrics HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed"));
+    }
+
+    #[tokio::test]
+    async fn test_oversized_request_head_is_an_error() {
+        let handler = MetricsServiceHandler::default();
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        client
+            .write_all(&vec![b'a'; MAX_REQUEST_HEAD + 2048])
+            .await
+            .unwrap();
+        assert!(handler.handle_tcp_stream(Box::new(server)).await.is_err());
+    }
+}
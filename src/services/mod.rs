@@ -14,10 +14,14 @@
 //!
 //! See `src/services/template/mod.rs` for a documented skeleton.
 
+#[cfg(feature = "metrics")]
+pub mod metrics;
 #[cfg(feature = "socks")]
 pub mod socks;
 #[cfg(feature = "ssh")]
 pub mod ssh;
+pub mod stats;
+pub mod summary;
 pub mod template;
 #[cfg(feature = "vncserver")]
 pub mod vncserver;
@@ -25,17 +29,22 @@ pub mod vncserver;
 #[cfg(feature = "socks")]
 use crate::config::SocksConfig;
 use crate::config::{ServiceConfig, ServiceType};
+use crate::ratelimit::Bandwidth;
 use anyhow::Result;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 use tokio::io::{AsyncRead, AsyncWrite};
 
 // Re-export service handler implementations
+#[cfg(feature = "metrics")]
+pub use metrics::MetricsServiceHandler;
 #[cfg(feature = "socks")]
 pub use socks::Socks5ServiceHandler;
 #[cfg(feature = "ssh")]
 pub use ssh::SshServiceHandler;
+pub use stats::{HistogramSnapshot, ServiceStats, ServiceStatsSnapshot};
+pub use summary::{CloseReason, ConnectionSummary, CountedStream, StreamCounters};
 #[cfg(feature = "vncserver")]
 pub use vncserver::VncServiceHandler;
 
@@ -53,7 +62,7 @@ pub use vncserver::VncServiceHandler;
 /// # Example
 ///
 /// ```rust,ignore
-/// use sockrats::services::ServiceHandler;
+/// use sockrats::services::{CloseReason, ConnectionSummary, ServiceHandler};
 ///
 /// #[derive(Debug)]
 /// struct MyHandler;
@@ -65,9 +74,9 @@ pub use vncserver::VncServiceHandler;
 ///     async fn handle_tcp_stream(
 ///         &self,
 ///         stream: Box<dyn StreamDyn>,
-///     ) -> anyhow::Result<()> {
+///     ) -> anyhow::Result<ConnectionSummary> {
 ///         // Handle the connection
-///         Ok(())
+///         Ok(ConnectionSummary::new(CloseReason::Completed))
 ///     }
 /// }
 /// ```
@@ -80,8 +89,9 @@ pub trait ServiceHandler: Send + Sync + Debug {
     ///
     /// The stream is already connected and authenticated at the rathole
     /// protocol level. The handler should implement the service-specific
-    /// protocol (SOCKS5, SSH, etc.) on this stream.
-    async fn handle_tcp_stream(&self, stream: Box<dyn StreamDyn>) -> Result<()>;
+    /// protocol (SOCKS5, SSH, etc.) on this stream and report what happened
+    /// as a [`ConnectionSummary`].
+    async fn handle_tcp_stream(&self, stream: Box<dyn StreamDyn>) -> Result<ConnectionSummary>;
 
     /// Handle an incoming UDP data channel.
     ///
@@ -107,6 +117,54 @@ pub trait ServiceHandler: Send + Sync + Debug {
     fn validate(&self) -> Result<()> {
         Ok(())
     }
+
+    /// Connection and byte counters for this service.
+    ///
+    /// Default implementation returns `None`, for handlers that keep no
+    /// statistics.
+    fn stats(&self) -> Option<&ServiceStats> {
+        None
+    }
+
+    /// Running state to hand to the handler replacing this one when the
+    /// configuration is reloaded.
+    ///
+    /// Default implementation hands over nothing.
+    fn reload_state(&self) -> HandlerState {
+        HandlerState::default()
+    }
+
+    /// Take over `state` from the handler this one replaces, so statistics
+    /// and shared limits carry on across a configuration reload.
+    ///
+    /// Called before the handler is shared. Default implementation ignores
+    /// the state.
+    fn carry_over(&mut self, _state: HandlerState) {}
+}
+
+/// Running state of a service handler that outlives it across reloads
+#[derive(Debug, Clone, Default)]
+pub struct HandlerState {
+    /// Connection and byte counters
+    pub stats: Option<Arc<ServiceStats>>,
+    /// Limiters shared by every connection, with the rate they enforce
+    pub bandwidth: Option<(u64, Bandwidth)>,
+    /// Authentication failures and bans of the SSH service
+    #[cfg(feature = "ssh")]
+    pub auth_bans: Option<Arc<ssh::AuthBanList>>,
+}
+
+/// Hand the running state of `previous` to its replacement `handler`
+///
+/// Nothing is carried over between handlers of different service types,
+/// or to a handler that is already shared.
+pub fn carry_over(handler: &mut Arc<dyn ServiceHandler>, previous: &dyn ServiceHandler) {
+    if handler.service_type() != previous.service_type() {
+        return;
+    }
+    if let Some(handler) = Arc::get_mut(handler) {
+        handler.carry_over(previous.reload_state());
+    }
 }
 
 /// A dynamic stream trait for service handlers.
@@ -123,7 +181,7 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send + Sync + Debug> StreamDyn for T {}
 ///
 /// Built during client startup from the configuration. The control channel
 /// looks up the handler by service name when spawning data channels.
-#[derive(Debug, Default)]
+#[derive(Debug, Clone, Default)]
 pub struct ServiceRegistry {
     handlers: HashMap<String, Arc<dyn ServiceHandler>>,
 }
@@ -160,14 +218,92 @@ impl ServiceRegistry {
     pub fn is_empty(&self) -> bool {
         self.handlers.is_empty()
     }
+
+    /// Snapshot the statistics of every handler that keeps them, sorted by
+    /// service name.
+    pub fn stats(&self) -> Vec<(String, ServiceStatsSnapshot)> {
+        let mut stats: Vec<_> = self
+            .handlers
+            .iter()
+            .filter_map(|(name, handler)| Some((name.clone(), handler.stats()?.snapshot())))
+            .collect();
+        stats.sort_by(|a, b| a.0.cmp(&b.0));
+        stats
+    }
+
+    /// Check the registry against the service configuration it was built from.
+    ///
+    /// Registering a name twice silently replaces the earlier handler, so
+    /// duplicate names in `services` are an error. Configured services with
+    /// no registered handler, and handlers no configured service refers to,
+    /// are logged as warnings.
+    pub fn validate_all(&self, services: &[ServiceConfig]) -> Result<()> {
+        let mut names = HashSet::new();
+        for service in services {
+            if !names.insert(service.name.as_str()) {
+                anyhow::bail!(
+                    "Duplicate service name in configuration: '{}'",
+                    service.name
+                );
+            }
+        }
+
+        for service in services {
+            if !self.handlers.contains_key(&service.name) {
+                tracing::warn!("Service '{}' has no registered handler", service.name);
+            }
+        }
+        for name in self.handlers.keys() {
+            if !names.contains(name.as_str()) {
+                tracing::warn!("Handler registered for unconfigured service '{}'", name);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A [`ServiceRegistry`] that can be replaced while it is in use.
+///
+/// Control channels look their handler up here for every new data channel,
+/// so storing a rebuilt registry on a configuration reload applies to new
+/// connections while connections already running keep their old handler.
+#[derive(Debug, Clone, Default)]
+pub struct SharedRegistry {
+    current: Arc<RwLock<Arc<ServiceRegistry>>>,
+}
+
+impl SharedRegistry {
+    /// Share `registry`
+    pub fn new(registry: ServiceRegistry) -> Self {
+        Self {
+            current: Arc::new(RwLock::new(Arc::new(registry))),
+        }
+    }
+
+    /// Get the current registry
+    pub fn load(&self) -> Arc<ServiceRegistry> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Replace the registry for every holder of this handle
+    pub fn store(&self, registry: ServiceRegistry) {
+        *self.current.write().unwrap() = Arc::new(registry);
+    }
+
+    /// Look up a handler by service name in the current registry
+    pub fn get(&self, name: &str) -> Option<Arc<dyn ServiceHandler>> {
+        self.load().get(name)
+    }
 }
 
 /// Create a [`ServiceHandler`] from a [`ServiceConfig`].
 ///
 /// This factory function maps [`ServiceType`] variants to their concrete
 /// handler implementations. When adding a new service type, add a match
-/// arm here.
+/// arm here. The settings common to all service types are validated first.
 pub fn create_service_handler(service: &ServiceConfig) -> Result<Arc<dyn ServiceHandler>> {
+    service.validate().map_err(|e| anyhow::anyhow!(e))?;
     match service.service_type {
         #[cfg(feature = "socks")]
         ServiceType::Socks5 => {
@@ -194,9 +330,32 @@ pub fn create_service_handler(service: &ServiceConfig) -> Result<Arc<dyn Service
             handler.validate()?;
             Ok(Arc::new(handler))
         }
+        #[cfg(feature = "metrics")]
+        ServiceType::Metrics => Ok(Arc::new(MetricsServiceHandler::default())),
     }
 }
 
+/// Create a [`ServiceHandler`] from a [`ServiceConfig`], with SOCKS5
+/// CONNECT targets reached through `socks_dialer`.
+///
+/// Used when egress must follow a tunnel (e.g. WireGuard) rather than the
+/// host network. Other service types are created as by
+/// [`create_service_handler()`].
+#[cfg(feature = "socks")]
+pub fn create_service_handler_with_dialer(
+    service: &ServiceConfig,
+    socks_dialer: Arc<dyn socks::TargetDialer>,
+) -> Result<Arc<dyn ServiceHandler>> {
+    if service.service_type != ServiceType::Socks5 {
+        return create_service_handler(service);
+    }
+    service.validate().map_err(|e| anyhow::anyhow!(e))?;
+    let config = service.socks.clone().unwrap_or_default();
+    let handler = Socks5ServiceHandler::new(config).with_dialer(socks_dialer);
+    handler.validate()?;
+    Ok(Arc::new(handler))
+}
+
 /// Create a [`ServiceHandler`] for legacy single-service mode.
 ///
 /// In legacy mode, the service type is inferred from the service name:
@@ -260,8 +419,11 @@ mod tests {
             &self.name
         }
 
-        async fn handle_tcp_stream(&self, _stream: Box<dyn StreamDyn>) -> Result<()> {
-            Ok(())
+        async fn handle_tcp_stream(
+            &self,
+            _stream: Box<dyn StreamDyn>,
+        ) -> Result<ConnectionSummary> {
+            Ok(ConnectionSummary::new(CloseReason::Completed))
         }
     }
 
@@ -289,6 +451,28 @@ mod tests {
         assert_eq!(retrieved.unwrap().service_type(), "test");
     }
 
+    #[test]
+    fn test_shared_registry_store_replaces_handlers() {
+        let mock = |name: &str| -> Arc<dyn ServiceHandler> {
+            Arc::new(MockServiceHandler {
+                name: name.to_string(),
+            })
+        };
+        let mut registry = ServiceRegistry::new();
+        registry.register("svc".to_string(), mock("old"));
+        let shared = SharedRegistry::new(registry);
+        let other = shared.clone();
+        let held = shared.load();
+
+        let mut registry = ServiceRegistry::new();
+        registry.register("svc".to_string(), mock("new"));
+        shared.store(registry);
+
+        assert_eq!(other.get("svc").unwrap().service_type(), "new");
+        // A registry loaded before the swap is unaffected
+        assert_eq!(held.get("svc").unwrap().service_type(), "old");
+    }
+
     #[test]
     fn test_service_registry_get_nonexistent() {
         let registry = ServiceRegistry::new();
@@ -336,6 +520,109 @@ mod tests {
         assert_eq!(registry.get("svc").unwrap().service_type(), "v2");
     }
 
+    #[derive(Debug, Default)]
+    struct CountingHandler {
+        stats: ServiceStats,
+    }
+
+    #[async_trait::async_trait]
+    impl ServiceHandler for CountingHandler {
+        fn service_type(&self) -> &str {
+            "counting"
+        }
+
+        async fn handle_tcp_stream(
+            &self,
+            _stream: Box<dyn StreamDyn>,
+        ) -> Result<ConnectionSummary> {
+            self.stats
+                .track(async {
+                    Ok(ConnectionSummary::new(CloseReason::Completed).with_bytes(3, 4))
+                })
+                .await
+        }
+
+        fn stats(&self) -> Option<&ServiceStats> {
+            Some(&self.stats)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_service_registry_stats() {
+        let mut registry = ServiceRegistry::new();
+        let counting = Arc::new(CountingHandler::default());
+        registry.register("b".to_string(), counting.clone());
+        registry.register("a".to_string(), Arc::new(CountingHandler::default()));
+        registry.register(
+            "untracked".to_string(),
+            Arc::new(MockServiceHandler {
+                name: "mock".to_string(),
+            }),
+        );
+
+        let (stream, _peer) = tokio::io::duplex(64);
+        counting.handle_tcp_stream(Box::new(stream)).await.unwrap();
+
+        let stats = registry.stats();
+        let names: Vec<_> = stats.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["a", "b"]);
+        assert_eq!(stats[0].1, ServiceStatsSnapshot::default());
+        assert_eq!(stats[1].1.total_connections, 1);
+        assert_eq!(stats[1].1.bytes_down, 4);
+    }
+
+    fn mock_service(name: &str) -> ServiceConfig {
+        ServiceConfig {
+            name: name.to_string(),
+            service_type: ServiceType::Socks5,
+            token: "token".to_string(),
+            socks: None,
+            ssh: None,
+            keepalive: None,
+            #[cfg(feature = "vncserver")]
+            vnc: None,
+        }
+    }
+
+    #[test]
+    fn test_service_registry_validate_all() {
+        let mut registry = ServiceRegistry::new();
+        for name in ["socks5", "ssh"] {
+            registry.register(
+                name.to_string(),
+                Arc::new(MockServiceHandler {
+                    name: name.to_string(),
+                }),
+            );
+        }
+
+        let services = vec![mock_service("socks5"), mock_service("ssh")];
+        assert!(registry.validate_all(&services).is_ok());
+
+        // Missing or extra handlers only warn
+        assert!(registry.validate_all(&services[..1]).is_ok());
+        assert!(registry
+            .validate_all(&[mock_service("socks5"), mock_service("vnc")])
+            .is_ok());
+    }
+
+    #[test]
+    fn test_service_registry_validate_all_duplicate_names() {
+        let mut registry = ServiceRegistry::new();
+        registry.register(
+            "svc".to_string(),
+            Arc::new(MockServiceHandler {
+                name: "v2".to_string(),
+            }),
+        );
+
+        let err = registry
+            .validate_all(&[mock_service("svc"), mock_service("svc")])
+            .unwrap_err();
+        assert!(err.to_string().contains("Duplicate service name"));
+        assert!(err.to_string().contains("svc"));
+    }
+
     #[test]
     fn test_default_service_handler_methods() {
         let handler = MockServiceHandler {
@@ -354,6 +641,7 @@ mod tests {
             token: "token".to_string(),
             socks: Some(SocksConfig::default()),
             ssh: None,
+            keepalive: None,
             #[cfg(feature = "vncserver")]
             vnc: None,
         };
@@ -371,6 +659,7 @@ mod tests {
             token: "token".to_string(),
             socks: None,
             ssh: None,
+            keepalive: None,
             #[cfg(feature = "vncserver")]
             vnc: None,
         };
@@ -409,6 +698,83 @@ mod tests {
         assert_eq!(handler.service_type(), "ssh");
     }
 
+    #[cfg(all(feature = "socks", feature = "ssh"))]
+    fn registry_from(config_str: &str) -> (ServiceRegistry, Vec<ServiceConfig>) {
+        let config = crate::config::parse_config(config_str).unwrap();
+        let mut registry = ServiceRegistry::new();
+        for service in &config.client.services {
+            registry.register(
+                service.name.clone(),
+                create_service_handler(service).unwrap(),
+            );
+        }
+        registry.validate_all(&config.client.services).unwrap();
+        (registry, config.client.services)
+    }
+
+    #[test]
+    #[cfg(all(feature = "socks", feature = "ssh"))]
+    fn test_single_service_shorthand_matches_services_array() {
+        for (shorthand, explicit) in [
+            (
+                r#"
+[client]
+remote_addr = "server.example.com:2333"
+service_name = "proxy"
+token = "secret"
+
+[client.socks]
+dns_resolve = false
+"#,
+                r#"
+[client]
+remote_addr = "server.example.com:2333"
+
+[[client.services]]
+name = "proxy"
+service_type = "socks5"
+token = "secret"
+
+[client.services.socks]
+dns_resolve = false
+"#,
+            ),
+            (
+                r#"
+[client]
+remote_addr = "server.example.com:2333"
+service_name = "ssh-tunnel"
+token = "secret"
+"#,
+                r#"
+[client]
+remote_addr = "server.example.com:2333"
+
+[[client.services]]
+name = "ssh-tunnel"
+service_type = "ssh"
+token = "secret"
+"#,
+            ),
+        ] {
+            let (shorthand_registry, shorthand_services) = registry_from(shorthand);
+            let (explicit_registry, explicit_services) = registry_from(explicit);
+
+            assert_eq!(
+                shorthand_registry.service_names(),
+                explicit_registry.service_names()
+            );
+            for (a, b) in shorthand_services.iter().zip(&explicit_services) {
+                assert_eq!(a.service_type, b.service_type);
+                assert_eq!(a.token, b.token);
+                assert_eq!(
+                    shorthand_registry.get(&a.name).unwrap().service_type(),
+                    explicit_registry.get(&b.name).unwrap().service_type()
+                );
+            }
+        }
+    }
+
     #[test]
     fn test_stream_dyn_blanket_impl() {
         // Verify that common stream types satisfy StreamDyn
@@ -416,4 +782,24 @@ mod tests {
         fn assert_stream_dyn<T: StreamDyn>() {}
         assert_stream_dyn::<tokio::io::DuplexStream>();
     }
+
+    #[test]
+    #[cfg(feature = "metrics")]
+    fn test_create_metrics_handler_from_config() {
+        let config = crate::config::parse_config(
+            r#"
+[client]
+remote_addr = "server.example.com:2333"
+
+[[client.services]]
+name = "metrics"
+service_type = "metrics"
+token = "secret"
+"#,
+        )
+        .unwrap();
+        let handler = create_service_handler(&config.client.services[0]).unwrap();
+        assert_eq!(handler.service_type(), "metrics");
+        assert!(handler.stats().is_none());
+    }
 }
@@ -0,0 +1,201 @@
+//! bcrypt password hash verification
+//!
+//! Verifies `$2a$`, `$2b$` and `$2y$` modular-crypt hashes as produced by
+//! `htpasswd -B` and most bcrypt libraries. Only verification is needed, so
+//! hashes are decoded and compared rather than re-encoded.
+
+use crate::protocol::constant_time_eq;
+use blowfish::Blowfish;
+
+/// bcrypt's base64 alphabet (not the standard one)
+const BCRYPT_ALPHABET: &[u8; 64] =
+    b"./ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+
+/// Length of the encoded salt in characters
+const SALT_CHARS: usize = 22;
+
+/// Length of the encoded hash in characters
+const HASH_CHARS: usize = 31;
+
+/// Length of the raw salt in bytes
+const SALT_LEN: usize = 16;
+
+/// Length of the raw hash in bytes (bcrypt drops the last byte)
+const HASH_LEN: usize = 23;
+
+/// Maximum password length used by bcrypt, including the NUL terminator
+const MAX_KEY_LEN: usize = 72;
+
+/// Highest cost accepted; each step doubles the work of a verification,
+/// and cost 14 already takes about a second
+pub const MAX_COST: u32 = 14;
+
+/// Check whether a string looks like a bcrypt hash
+pub fn is_bcrypt_hash(s: &str) -> bool {
+    s.starts_with("$2a$") || s.starts_with("$2b$") || s.starts_with("$2y$")
+}
+
+/// Check whether `hash` is a well-formed bcrypt hash [`verify`] accepts,
+/// with a cost of at most [`MAX_COST`]
+pub fn is_supported_hash(hash: &str) -> bool {
+    parse_hash(hash).is_some()
+}
+
+/// Verify `password` against a bcrypt `hash`
+///
+/// Returns `false` for malformed hashes and costs above [`MAX_COST`]. The
+/// final comparison is constant-time. This is CPU-bound for up to about a
+/// second, so async callers should run it on the blocking thread pool.
+pub fn verify(password: &str, hash: &str) -> bool {
+    let Some((cost, salt, expected)) = parse_hash(hash) else {
+        return false;
+    };
+
+    let mut key = password.as_bytes().to_vec();
+    key.push(0);
+    key.truncate(MAX_KEY_LEN);
+
+    let mut state = Blowfish::bc_init_state();
+    state.salted_expand_key(&salt, &key);
+    for _ in 0..(1u64 << cost) {
+        state.bc_expand_key(&key);
+        state.bc_expand_key(&salt);
+    }
+
+    // "OrpheanBeholderScryDoubt", encrypted 64 times
+    let mut ctext = [
+        0x4f72_7068,
+        0x6561_6e42,
+        0x6568_6f6c,
+        0x6465_7253,
+        0x6372_7944,
+        0x6f75_6274,
+    ];
+    for pair in ctext.chunks_exact_mut(2) {
+        let mut block = [pair[0], pair[1]];
+        for _ in 0..64 {
+            block = state.bc_encrypt(block);
+        }
+        pair.copy_from_slice(&block);
+    }
+
+    let output: Vec<u8> = ctext.iter().flat_map(|w| w.to_be_bytes()).collect();
+    constant_time_eq(&output[..HASH_LEN], &expected)
+}
+
+/// Split a bcrypt hash into its cost, raw salt and raw hash
+fn parse_hash(hash: &str) -> Option<(u32, Vec<u8>, Vec<u8>)> {
+    if !is_bcrypt_hash(hash) {
+        return None;
+    }
+    let (cost, rest) = hash[4..].split_once('$')?;
+    if cost.len() != 2 {
+        return None;
+    }
+    let cost: u32 = cost.parse().ok()?;
+    if !(4..=MAX_COST).contains(&cost) || rest.len() != SALT_CHARS + HASH_CHARS {
+        return None;
+    }
+    // Salt and hash are split by byte offset, which must not land inside
+    // a multibyte character
+    if !rest.is_ascii() {
+        return None;
+    }
+
+    let salt = decode(&rest[..SALT_CHARS], SALT_LEN)?;
+    let expected = decode(&rest[SALT_CHARS..], HASH_LEN)?;
+    Some((cost, salt, expected))
+}
+
+/// Decode bcrypt base64 into exactly `len` bytes
+fn decode(s: &str, len: usize) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(len);
+    let mut acc = 0u32;
+    let mut bits = 0;
+
+    for c in s.bytes() {
+        let value = BCRYPT_ALPHABET.iter().position(|&a| a == c)? as u32;
+        acc = (acc << 6) | value;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+
+    if out.len() < len {
+        return None;
+    }
+    out.truncate(len);
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test vectors from the OpenWall crypt_blowfish test suite
+    #[test]
+    fn test_verify_known_vectors() {
+        assert!(verify(
+            "U*U",
+            "$2a$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW"
+        ));
+        assert!(verify(
+            "U*U*",
+            "$2a$05$CCCCCCCCCCCCCCCCCCCCC.VGOzA784oUp/Z0DY336zx7pLYAy0lwK"
+        ));
+        assert!(verify(
+            "",
+            "$2a$05$CCCCCCCCCCCCCCCCCCCCC.7uG0VCzI2bS7j6ymqJi9CdcdxiRTWNy"
+        ));
+    }
+
+    #[test]
+    fn test_verify_wrong_password() {
+        assert!(!verify(
+            "U*V",
+            "$2a$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW"
+        ));
+    }
+
+    #[test]
+    fn test_verify_malformed_hash() {
+        assert!(!verify("U*U", "$2a$05$short"));
+        assert!(!verify("U*U", "$1$notbcrypt"));
+        assert!(!verify(
+            "U*U",
+            "$2a$99$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW"
+        ));
+    }
+
+    #[test]
+    fn test_non_ascii_hash_is_rejected() {
+        // 53 bytes, with a two-byte character across the salt boundary
+        let hash = "$2a$05$CCCCCCCCCCCCCCCCCCCCCéE5YPO9kmyuRGyh0XouQYb4YMJKvyOe";
+        assert_eq!(hash.len() - 7, SALT_CHARS + HASH_CHARS);
+        assert!(!is_supported_hash(hash));
+        assert!(!verify("U*U", hash));
+    }
+
+    #[test]
+    fn test_cost_is_capped() {
+        let hash = |cost: u32| {
+            format!(
+                "$2a${:02}$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW",
+                cost
+            )
+        };
+        assert!(is_supported_hash(&hash(MAX_COST)));
+        // Rejected without running the key schedule
+        assert!(!is_supported_hash(&hash(MAX_COST + 1)));
+        assert!(!verify("U*U", &hash(31)));
+    }
+
+    #[test]
+    fn test_is_bcrypt_hash() {
+        assert!(is_bcrypt_hash("$2b$10$abc"));
+        assert!(is_bcrypt_hash("$2y$10$abc"));
+        assert!(!is_bcrypt_hash("plaintext"));
+    }
+}
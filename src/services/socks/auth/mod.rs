@@ -1,13 +1,32 @@
 //! SOCKS5 authentication module
 //!
 //! Handles authentication negotiation and username/password authentication.
+//! Without the `socks-auth` feature only the "no authentication" method is
+//! offered.
 
+#[cfg(feature = "socks-auth")]
+mod bcrypt;
 mod none;
+#[cfg(feature = "socks-auth")]
 mod password;
+#[cfg(feature = "socks-auth")]
+mod users;
+
+#[cfg(feature = "socks-auth")]
+pub use users::{Users, UsersFile};
+
+/// Stand-in for the users file when `socks-auth` is compiled out.
+///
+/// It has no values, so an `Option<&UsersFile>` is always `None`.
+#[cfg(not(feature = "socks-auth"))]
+#[derive(Debug)]
+pub enum UsersFile {}
 
 use super::consts::*;
 use crate::config::SocksConfig;
-use anyhow::{bail, Result};
+use crate::error::Socks5Error;
+use anyhow::{bail, Context, Result};
+use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 /// Authentication method types
@@ -57,27 +76,30 @@ pub async fn authenticate<S>(stream: &mut S, config: &SocksConfig) -> Result<Aut
 where
     S: AsyncRead + AsyncWrite + Unpin,
 {
-    // Step 1: Read version and number of methods
-    let mut buf = [0u8; 2];
-    stream.read_exact(&mut buf).await?;
-
-    let version = buf[0];
-    let num_methods = buf[1];
-
-    if version != SOCKS5_VERSION {
-        bail!("Unsupported SOCKS version: {}", version);
-    }
-
-    if num_methods == 0 {
-        bail!("No authentication methods provided");
-    }
+    authenticate_with_users(stream, config, None).await
+}
 
-    // Step 2: Read available methods
-    let mut methods = vec![0u8; num_methods as usize];
-    stream.read_exact(&mut methods).await?;
+/// Perform authentication negotiation, also accepting the credentials in
+/// `users` (loaded from [`SocksConfig::users_file`])
+pub async fn authenticate_with_users<S>(
+    stream: &mut S,
+    config: &SocksConfig,
+    users: Option<&UsersFile>,
+) -> Result<AuthMethod>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // Steps 1-2 are bounded so a client trickling its method list cannot
+    // stall the data channel
+    let methods = tokio::time::timeout(
+        Duration::from_secs(config.handshake_timeout),
+        read_methods(stream, config.max_auth_methods),
+    )
+    .await
+    .context("Timed out reading SOCKS5 auth methods")??;
 
     // Step 3: Select authentication method
-    let selected_method = select_auth_method(&methods, config);
+    let selected_method = select_auth_method(&methods, config, users.is_some());
 
     // Step 4: Send selected method
     stream
@@ -92,13 +114,21 @@ where
 
     let method = match selected_method {
         Some(m) => m,
+        None if config.auth_required && !methods.contains(&SOCKS5_AUTH_METHOD_PASSWORD) => {
+            return Err(Socks5Error::AuthRequired.into())
+        }
         None => bail!("No acceptable authentication method"),
     };
 
     // Step 5: Perform authentication if required
     match method {
+        #[cfg(feature = "socks-auth")]
         AuthMethod::Password => {
-            password::authenticate_password(stream, config).await?;
+            password::authenticate_password(stream, config, users).await?;
+        }
+        #[cfg(not(feature = "socks-auth"))]
+        AuthMethod::Password => {
+            bail!("Password authentication requires the socks-auth feature")
         }
         AuthMethod::None => {
             none::NoAuth::authenticate(stream).await?;
@@ -108,8 +138,48 @@ where
     Ok(method)
 }
 
+/// Read the client's version and offered auth methods
+async fn read_methods<S>(stream: &mut S, max_methods: u8) -> Result<Vec<u8>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // Step 1: Read version and number of methods
+    let mut buf = [0u8; 2];
+    stream.read_exact(&mut buf).await?;
+
+    let version = buf[0];
+    let num_methods = buf[1];
+
+    if version != SOCKS5_VERSION {
+        bail!("Unsupported SOCKS version: {}", version);
+    }
+
+    if num_methods == 0 {
+        bail!("No authentication methods provided");
+    }
+
+    if num_methods > max_methods {
+        bail!(
+            "Too many authentication methods: {} (max {})",
+            num_methods,
+            max_methods
+        );
+    }
+
+    // Step 2: Read available methods
+    let mut methods = vec![0u8; num_methods as usize];
+    stream.read_exact(&mut methods).await?;
+    Ok(methods)
+}
+
 /// Select the best authentication method based on configuration and available methods
-fn select_auth_method(methods: &[u8], config: &SocksConfig) -> Option<AuthMethod> {
+fn select_auth_method(methods: &[u8], config: &SocksConfig, has_users: bool) -> Option<AuthMethod> {
+    if cfg!(not(feature = "socks-auth")) {
+        return methods
+            .contains(&SOCKS5_AUTH_METHOD_NONE)
+            .then_some(AuthMethod::None);
+    }
+
     if config.auth_required {
         // Must use password authentication
         if methods.contains(&SOCKS5_AUTH_METHOD_PASSWORD) {
@@ -120,7 +190,8 @@ fn select_auth_method(methods: &[u8], config: &SocksConfig) -> Option<AuthMethod
         if methods.contains(&SOCKS5_AUTH_METHOD_NONE) {
             return Some(AuthMethod::None);
         }
-        if methods.contains(&SOCKS5_AUTH_METHOD_PASSWORD) && config.has_credentials() {
+        if methods.contains(&SOCKS5_AUTH_METHOD_PASSWORD) && (config.has_credentials() || has_users)
+        {
             return Some(AuthMethod::Password);
         }
     }
@@ -158,16 +229,17 @@ mod tests {
         // Should select no auth when available
         let methods = vec![SOCKS5_AUTH_METHOD_NONE, SOCKS5_AUTH_METHOD_PASSWORD];
         assert_eq!(
-            select_auth_method(&methods, &config),
+            select_auth_method(&methods, &config, false),
             Some(AuthMethod::None)
         );
 
         // Should return None if only password and no credentials
         let methods = vec![SOCKS5_AUTH_METHOD_PASSWORD];
-        assert_eq!(select_auth_method(&methods, &config), None);
+        assert_eq!(select_auth_method(&methods, &config, false), None);
     }
 
     #[test]
+    #[cfg(feature = "socks-auth")]
     fn test_select_auth_method_auth_required() {
         let config = SocksConfig {
             auth_required: true,
@@ -179,16 +251,17 @@ mod tests {
         // Should select password auth
         let methods = vec![SOCKS5_AUTH_METHOD_NONE, SOCKS5_AUTH_METHOD_PASSWORD];
         assert_eq!(
-            select_auth_method(&methods, &config),
+            select_auth_method(&methods, &config, false),
             Some(AuthMethod::Password)
         );
 
         // Should return None if password not available
         let methods = vec![SOCKS5_AUTH_METHOD_NONE];
-        assert_eq!(select_auth_method(&methods, &config), None);
+        assert_eq!(select_auth_method(&methods, &config, false), None);
     }
 
     #[test]
+    #[cfg(feature = "socks-auth")]
     fn test_select_auth_method_with_credentials_no_requirement() {
         let config = SocksConfig {
             auth_required: false,
@@ -200,15 +273,127 @@ mod tests {
         // Should prefer no auth even with credentials
         let methods = vec![SOCKS5_AUTH_METHOD_NONE, SOCKS5_AUTH_METHOD_PASSWORD];
         assert_eq!(
-            select_auth_method(&methods, &config),
+            select_auth_method(&methods, &config, false),
             Some(AuthMethod::None)
         );
 
         // But should use password if no auth not available
         let methods = vec![SOCKS5_AUTH_METHOD_PASSWORD];
         assert_eq!(
-            select_auth_method(&methods, &config),
+            select_auth_method(&methods, &config, false),
             Some(AuthMethod::Password)
         );
     }
+
+    #[test]
+    #[cfg(not(feature = "socks-auth"))]
+    fn test_select_auth_method_without_auth_feature() {
+        let config = SocksConfig {
+            username: Some("user".to_string()),
+            password: Some("pass".to_string()),
+            ..Default::default()
+        };
+        let methods = vec![SOCKS5_AUTH_METHOD_NONE, SOCKS5_AUTH_METHOD_PASSWORD];
+        assert_eq!(
+            select_auth_method(&methods, &config, false),
+            Some(AuthMethod::None)
+        );
+        assert_eq!(
+            select_auth_method(&[SOCKS5_AUTH_METHOD_PASSWORD], &config, true),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_authenticate_aborts_trickled_methods() {
+        let config = SocksConfig {
+            handshake_timeout: 1,
+            ..Default::default()
+        };
+
+        // Announce three methods but only ever send one
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client
+            .write_all(&[SOCKS5_VERSION, 3, SOCKS5_AUTH_METHOD_NONE])
+            .await
+            .unwrap();
+
+        let started = std::time::Instant::now();
+        let err = authenticate(&mut server, &config).await.unwrap_err();
+        assert!(err.to_string().contains("Timed out"));
+        assert!(started.elapsed() < Duration::from_secs(3));
+        drop(client);
+    }
+
+    #[tokio::test]
+    async fn test_authenticate_rejects_too_many_methods() {
+        let config = SocksConfig {
+            max_auth_methods: 2,
+            ..Default::default()
+        };
+
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client
+            .write_all(&[SOCKS5_VERSION, 3, 0, 1, 2])
+            .await
+            .unwrap();
+
+        let err = authenticate(&mut server, &config).await.unwrap_err();
+        assert!(err.to_string().contains("Too many authentication methods"));
+    }
+
+    #[test]
+    #[cfg(feature = "socks-auth")]
+    fn test_select_auth_method_with_users_file_only() {
+        let config = SocksConfig::default();
+        let methods = vec![SOCKS5_AUTH_METHOD_PASSWORD];
+        assert_eq!(select_auth_method(&methods, &config, false), None);
+        assert_eq!(
+            select_auth_method(&methods, &config, true),
+            Some(AuthMethod::Password)
+        );
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "socks-auth")]
+    async fn test_authenticate_user_from_users_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("users");
+        std::fs::write(&path, "# comment\nbroken line\nalice:wonderland\n").unwrap();
+        let users = UsersFile::open(&path).unwrap();
+        assert_eq!(users.users().len(), 1);
+
+        let config = SocksConfig {
+            auth_required: true,
+            users_file: Some(path),
+            ..Default::default()
+        };
+
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client
+            .write_all(&[SOCKS5_VERSION, 1, SOCKS5_AUTH_METHOD_PASSWORD])
+            .await
+            .unwrap();
+        client.write_all(&[SOCKS5_AUTH_VERSION, 5]).await.unwrap();
+        client.write_all(b"alice").await.unwrap();
+        client.write_all(&[10]).await.unwrap();
+        client.write_all(b"wonderland").await.unwrap();
+
+        let method = authenticate_with_users(&mut server, &config, Some(&users))
+            .await
+            .unwrap();
+        assert_eq!(method, AuthMethod::Password);
+
+        let mut reply = [0u8; 4];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(
+            reply,
+            [
+                SOCKS5_VERSION,
+                SOCKS5_AUTH_METHOD_PASSWORD,
+                SOCKS5_AUTH_VERSION,
+                0
+            ]
+        );
+    }
 }
@@ -2,9 +2,14 @@
 //!
 //! Implements RFC 1929 username/password authentication for SOCKS5.
 
+use super::users::UsersFile;
+use crate::audit;
 use crate::config::SocksConfig;
+use crate::error::Socks5Error;
+use crate::protocol::constant_time_eq;
 use crate::services::socks::consts::SOCKS5_AUTH_VERSION;
 use anyhow::{bail, Result};
+use std::future::Future;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 /// Username/password authentication handler
@@ -43,6 +48,25 @@ impl PasswordAuth {
     ) -> Result<()>
     where
         S: AsyncRead + AsyncWrite + Unpin,
+    {
+        Self::authenticate_with(stream, |username, password| async move {
+            // Compare both in constant time
+            let username_matches =
+                constant_time_eq(username.as_bytes(), expected_username.as_bytes());
+            let password_matches =
+                constant_time_eq(password.as_bytes(), expected_password.as_bytes());
+            username_matches && password_matches
+        })
+        .await
+    }
+
+    /// Perform username/password authentication, accepting the credentials
+    /// (username, password) for which `verify` resolves to true
+    pub async fn authenticate_with<S, F, Fut>(stream: &mut S, verify: F) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+        F: FnOnce(String, String) -> Fut,
+        Fut: Future<Output = bool>,
     {
         // Read version and username length
         let mut buf = [0u8; 2];
@@ -82,13 +106,16 @@ impl PasswordAuth {
         let password = String::from_utf8(password)?;
 
         // Verify credentials
-        if username == expected_username && password == expected_password {
+        if verify(username.clone(), password).await {
+            audit::record_auth("socks5", "password", &username, true);
             send_auth_result(stream, AUTH_SUCCESS).await?;
             tracing::debug!("Authentication successful for user: {}", username);
             Ok(())
         } else {
+            audit::record_auth("socks5", "password", &username, false);
             send_auth_result(stream, AUTH_FAILURE).await?;
-            bail!("Authentication failed for user: {}", username);
+            Err(anyhow::Error::new(Socks5Error::AuthFailed)
+                .context(format!("Authentication failed for user: {}", username)))
         }
     }
 }
@@ -101,10 +128,32 @@ async fn send_auth_result<S: AsyncWrite + Unpin>(stream: &mut S, status: u8) ->
 }
 
 /// Perform password authentication using SocksConfig
-pub async fn authenticate_password<S>(stream: &mut S, config: &SocksConfig) -> Result<()>
+///
+/// When a users file is loaded, its users are accepted in addition to any
+/// inline `username`/`password`.
+pub async fn authenticate_password<S>(
+    stream: &mut S,
+    config: &SocksConfig,
+    users: Option<&UsersFile>,
+) -> Result<()>
 where
     S: AsyncRead + AsyncWrite + Unpin,
 {
+    if let Some(users) = users {
+        let users = users.users();
+        return PasswordAuth::authenticate_with(stream, |username, password| async move {
+            let inline = match (&config.username, &config.password) {
+                (Some(u), Some(p)) => {
+                    constant_time_eq(username.as_bytes(), u.as_bytes())
+                        & constant_time_eq(password.as_bytes(), p.as_bytes())
+                }
+                _ => false,
+            };
+            inline || users.verify_blocking(username, password).await
+        })
+        .await;
+    }
+
     let username = config
         .username
         .as_ref()
@@ -188,6 +237,38 @@ mod tests {
             .contains("Authentication failed"));
     }
 
+    #[tokio::test]
+    async fn test_authenticate_failure_is_audited() {
+        use crate::audit::{self, JsonlAuditLogger};
+        use std::sync::Arc;
+        use tokio::io::AsyncWriteExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        audit::set_logger(Some(Arc::new(JsonlAuditLogger::open(&path).unwrap())));
+
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let request = create_auth_request("audited-user", "wrongpass");
+        client.write_all(&request).await.unwrap();
+
+        let result = PasswordAuth::authenticate(&mut server, "audited-user", "correctpass").await;
+        audit::set_logger(None);
+        assert!(result.is_err());
+
+        // Other tests may audit concurrently, so pick out this user's record
+        let content = std::fs::read_to_string(&path).unwrap();
+        let record: serde_json::Value = content
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .find(|record| record["username"] == "audited-user")
+            .expect("auth failure should be audited");
+        assert_eq!(record["event"], "auth_failure");
+        assert_eq!(record["protocol"], "socks5");
+        assert_eq!(record["method"], "password");
+        assert!(record["timestamp_ms"].as_u64().unwrap() > 0);
+        assert!(!content.contains("wrongpass"));
+    }
+
     #[tokio::test]
     async fn test_authenticate_wrong_username() {
         let (mut client, mut server) = tokio::io::duplex(1024);
@@ -277,12 +358,10 @@ mod tests {
             username: Some("myuser".to_string()),
             password: Some("mypass".to_string()),
             auth_required: true,
-            dns_resolve: true,
-            allow_udp: false,
-            request_timeout: 10,
+            ..Default::default()
         };
 
-        let result = authenticate_password(&mut server, &config).await;
+        let result = authenticate_password(&mut server, &config, None).await;
         assert!(result.is_ok());
     }
 
@@ -294,12 +373,10 @@ mod tests {
             username: None,
             password: Some("pass".to_string()),
             auth_required: true,
-            dns_resolve: true,
-            allow_udp: false,
-            request_timeout: 10,
+            ..Default::default()
         };
 
-        let result = authenticate_password(&mut stream, &config).await;
+        let result = authenticate_password(&mut stream, &config, None).await;
         assert!(result.is_err());
         assert!(result
             .unwrap_err()
@@ -315,12 +392,10 @@ mod tests {
             username: Some("user".to_string()),
             password: None,
             auth_required: true,
-            dns_resolve: true,
-            allow_udp: false,
-            request_timeout: 10,
+            ..Default::default()
         };
 
-        let result = authenticate_password(&mut stream, &config).await;
+        let result = authenticate_password(&mut stream, &config, None).await;
         assert!(result.is_err());
         assert!(result
             .unwrap_err()
@@ -0,0 +1,256 @@
+//! SOCKS5 users file
+//!
+//! Loads `username:password` or `username:bcrypthash` lines from the file
+//! named by [`SocksConfig::users_file`](crate::config::SocksConfig::users_file).
+//! Blank lines and `#` comments are ignored; malformed lines are skipped
+//! with a warning. The file is re-read whenever a configuration reload
+//! (SIGHUP) rebuilds the SOCKS5 handler; a reload that cannot read it fails
+//! and keeps the previously loaded users.
+
+use super::bcrypt;
+use crate::protocol::constant_time_eq;
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+/// A stored credential
+enum Secret {
+    /// Plaintext password
+    Plain(String),
+    /// bcrypt hash
+    Bcrypt(String),
+}
+
+/// Users parsed from a users file
+#[derive(Default)]
+pub struct Users {
+    entries: HashMap<String, Secret>,
+}
+
+impl Users {
+    /// Parse users from the contents of a users file
+    pub fn parse(content: &str) -> Self {
+        let mut entries = HashMap::new();
+
+        for (line_num, line) in content.lines().enumerate() {
+            let line = line.trim();
+
+            // Skip empty lines and comments
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (username, secret) = match line.split_once(':') {
+                Some((u, s)) if !u.is_empty() && !s.is_empty() => (u, s),
+                _ => {
+                    tracing::warn!(
+                        line = line_num + 1,
+                        "Skipping malformed users file line (expected username:password)"
+                    );
+                    continue;
+                }
+            };
+
+            let secret = if bcrypt::is_bcrypt_hash(secret) {
+                if !bcrypt::is_supported_hash(secret) {
+                    tracing::warn!(
+                        line = line_num + 1,
+                        "Skipping malformed bcrypt hash or one with a cost above {}",
+                        bcrypt::MAX_COST
+                    );
+                    continue;
+                }
+                Secret::Bcrypt(secret.to_string())
+            } else {
+                Secret::Plain(secret.to_string())
+            };
+
+            if entries.insert(username.to_string(), secret).is_some() {
+                tracing::warn!(line = line_num + 1, username = %username, "Duplicate user, later entry wins");
+            }
+        }
+
+        Users { entries }
+    }
+
+    /// Get the number of users
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Check if there are no users
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Check a username and password
+    pub fn verify(&self, username: &str, password: &str) -> bool {
+        match self.entries.get(username) {
+            Some(Secret::Plain(expected)) => {
+                constant_time_eq(password.as_bytes(), expected.as_bytes())
+            }
+            Some(Secret::Bcrypt(hash)) => bcrypt::verify(password, hash),
+            None => false,
+        }
+    }
+
+    /// [`verify`](Users::verify) on the blocking thread pool, so checking
+    /// a bcrypt hash does not stall the runtime
+    pub async fn verify_blocking(self: Arc<Self>, username: String, password: String) -> bool {
+        tokio::task::spawn_blocking(move || self.verify(&username, &password))
+            .await
+            .unwrap_or(false)
+    }
+}
+
+impl fmt::Debug for Users {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Never print secrets
+        f.debug_struct("Users")
+            .field("count", &self.entries.len())
+            .finish()
+    }
+}
+
+/// A reloadable users file
+#[derive(Debug)]
+pub struct UsersFile {
+    path: PathBuf,
+    users: RwLock<Arc<Users>>,
+}
+
+impl UsersFile {
+    /// Load a users file
+    pub fn open(path: &Path) -> Result<Self> {
+        let users = Self::read(path)?;
+        tracing::info!(count = users.len(), path = ?path, "Loaded SOCKS5 users");
+        Ok(UsersFile {
+            path: path.to_path_buf(),
+            users: RwLock::new(Arc::new(users)),
+        })
+    }
+
+    /// Re-read the users file, returning the new number of users
+    ///
+    /// On error the previously loaded users are kept.
+    pub fn reload(&self) -> Result<usize> {
+        let users = Self::read(&self.path)?;
+        let count = users.len();
+        *self.users.write().unwrap() = Arc::new(users);
+        tracing::info!(count, path = ?self.path, "Reloaded SOCKS5 users");
+        Ok(count)
+    }
+
+    /// Get the currently loaded users
+    pub fn users(&self) -> Arc<Users> {
+        self.users.read().unwrap().clone()
+    }
+
+    /// Check a username and password against the loaded users
+    pub fn verify(&self, username: &str, password: &str) -> bool {
+        self.users().verify(username, password)
+    }
+
+    fn read(path: &Path) -> Result<Users> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read users file {:?}", path))?;
+        Ok(Users::parse(&content))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_plain_and_bcrypt() {
+        let content = r#"
+# SOCKS5 users
+alice:wonderland
+
+bob:$2a$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW
+"#;
+        let users = Users::parse(content);
+        assert_eq!(users.len(), 2);
+        assert!(users.verify("alice", "wonderland"));
+        assert!(!users.verify("alice", "WONDERLAND"));
+        assert!(users.verify("bob", "U*U"));
+        assert!(!users.verify("bob", "wrong"));
+        assert!(!users.verify("carol", "wonderland"));
+    }
+
+    #[test]
+    fn test_parse_skips_malformed_lines() {
+        let content = "alice:secret\nno-colon-here\n:nouser\ncarol:\ndave:pa:ss\n";
+        let users = Users::parse(content);
+        assert_eq!(users.len(), 2);
+        assert!(users.verify("alice", "secret"));
+        // Only the first colon separates the username
+        assert!(users.verify("dave", "pa:ss"));
+    }
+
+    #[test]
+    fn test_parse_skips_costly_bcrypt_hashes() {
+        let content = "bob:$2a$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW\n\
+                       mallory:$2a$31$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW\n";
+        let users = Users::parse(content);
+        assert_eq!(users.len(), 1);
+        assert!(!users.verify("mallory", "U*U"));
+    }
+
+    #[tokio::test]
+    async fn test_verify_blocking() {
+        let users = Arc::new(Users::parse(
+            "bob:$2a$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW\n",
+        ));
+        assert!(
+            users
+                .clone()
+                .verify_blocking("bob".into(), "U*U".into())
+                .await
+        );
+        assert!(!users.verify_blocking("bob".into(), "wrong".into()).await);
+    }
+
+    #[test]
+    fn test_non_ascii_hash_is_skipped() {
+        let users = Users::parse(
+            "bob:$2a$05$CCCCCCCCCCCCCCCCCCCCCéE5YPO9kmyuRGyh0XouQYb4YMJKvyOe\nalice:one\n",
+        );
+        assert!(!users.verify("bob", "U*U"));
+        assert!(users.verify("alice", "one"));
+    }
+
+    #[test]
+    fn test_debug_hides_secrets() {
+        let users = Users::parse("alice:topsecret");
+        assert!(!format!("{:?}", users).contains("topsecret"));
+    }
+
+    #[test]
+    fn test_users_file_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("users");
+        std::fs::write(&path, "alice:one\n").unwrap();
+
+        let file = UsersFile::open(&path).unwrap();
+        assert!(file.verify("alice", "one"));
+
+        std::fs::write(&path, "alice:two\nbob:three\n").unwrap();
+        assert_eq!(file.reload().unwrap(), 2);
+        assert!(!file.verify("alice", "one"));
+        assert!(file.verify("alice", "two"));
+
+        // A failed reload keeps the previous users
+        std::fs::remove_file(&path).unwrap();
+        assert!(file.reload().is_err());
+        assert!(file.verify("bob", "three"));
+    }
+
+    #[test]
+    fn test_users_file_open_missing() {
+        assert!(UsersFile::open(Path::new("/nonexistent/sockrats-users")).is_err());
+    }
+}
@@ -0,0 +1,270 @@
+//! BIND command for SOCKS5
+//!
+//! A BIND request asks the proxy to accept a single inbound connection on
+//! the client's behalf, as FTP active mode does. The listener is opened on
+//! the Sockrats host at [`SocksConfig::bind_address`], within
+//! `bind_port_range` when set. The first reply carries the listening
+//! address (or `advertised_bind_addr`) and the second the peer's once it
+//! connects; the two streams are then relayed.
+
+use crate::config::SocksConfig;
+use crate::ratelimit::Bandwidth;
+use crate::services::socks::command::{is_client_gone, send_io_error, send_success};
+use crate::services::socks::tcp_relay::relay_tcp_configured;
+use crate::services::socks::types::TargetAddr;
+use crate::services::{CloseReason, ConnectionSummary};
+use anyhow::Result;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tracing::{debug, info, warn};
+
+/// Handle a BIND request for the peer at `target_addr`
+///
+/// When the target is a concrete IP address, connections from other
+/// addresses are dropped while waiting. Waiting is limited to
+/// `bind_accept_timeout`.
+pub async fn handle_bind<S>(
+    mut client_stream: S,
+    target_addr: TargetAddr,
+    config: &SocksConfig,
+    shared_bandwidth: &Bandwidth,
+) -> Result<ConnectionSummary>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let listener = match bind_listener(config.bind_address, config.bind_port_range) {
+        Ok(listener) => listener,
+        Err(e) => {
+            warn!("Failed to open BIND listener: {}", e);
+            send_io_error(&mut client_stream, &e).await?;
+            return Err(e.into());
+        }
+    };
+    let local_addr = listener.local_addr()?;
+    info!(
+        "SOCKS5 BIND listening on {} for {}",
+        local_addr, target_addr
+    );
+
+    // First reply: where the peer should connect
+    let advertised = config.advertised_addr(local_addr);
+    if let Err(e) = send_success(&mut client_stream, Some(advertised)).await {
+        return client_gone_or(e);
+    }
+
+    let expected_ip = match &target_addr {
+        TargetAddr::Ip(addr) if !addr.ip().is_unspecified() => Some(addr.ip()),
+        _ => None,
+    };
+    let timeout = Duration::from_secs(config.bind_accept_timeout);
+    let (peer_stream, peer_addr) =
+        match tokio::time::timeout(timeout, accept_from(&listener, expected_ip)).await {
+            Ok(Ok(accepted)) => accepted,
+            Ok(Err(e)) => {
+                send_io_error(&mut client_stream, &e).await?;
+                return Err(e.into());
+            }
+            Err(_) => {
+                let e = io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("No BIND connection within {:?}", timeout),
+                );
+                send_io_error(&mut client_stream, &e).await?;
+                return Err(e.into());
+            }
+        };
+    drop(listener);
+
+    // Second reply: who connected
+    if let Err(e) = send_success(&mut client_stream, Some(peer_addr)).await {
+        return client_gone_or(e);
+    }
+
+    info!("SOCKS5 BIND connection from {}", peer_addr);
+    let summary =
+        relay_tcp_configured(client_stream, peer_stream, config, shared_bandwidth).await?;
+    Ok(summary.with_target(target_addr.to_string()))
+}
+
+/// A reply failed: fine if the client just went away
+fn client_gone_or(e: anyhow::Error) -> Result<ConnectionSummary> {
+    if is_client_gone(&e) {
+        debug!("Client went away during BIND: {}", e);
+        return Ok(ConnectionSummary::new(CloseReason::ClientClosed));
+    }
+    Err(e)
+}
+
+/// Accept the first connection from `expected_ip` (any when None)
+async fn accept_from(
+    listener: &TcpListener,
+    expected_ip: Option<IpAddr>,
+) -> io::Result<(TcpStream, SocketAddr)> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        match expected_ip {
+            Some(ip) if ip != peer.ip() => {
+                warn!("Dropping BIND connection from unexpected peer {}", peer);
+            }
+            _ => return Ok((stream, peer)),
+        }
+    }
+}
+
+/// Listen on `ip` (the unspecified IPv4 address when None), on a free
+/// port from `port_range` when given
+fn bind_listener(ip: Option<IpAddr>, port_range: Option<(u16, u16)>) -> io::Result<TcpListener> {
+    let ip = ip.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+    let (low, high) = port_range.unwrap_or((0, 0));
+
+    for port in low..=high {
+        let socket = match ip {
+            IpAddr::V4(_) => TcpSocket::new_v4()?,
+            IpAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        match socket.bind(SocketAddr::new(ip, port)) {
+            Ok(()) => return socket.listen(1),
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::AddrInUse,
+        format!("No free BIND port in range {}-{}", low, high),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::socks::consts::*;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    fn bind_config() -> SocksConfig {
+        SocksConfig {
+            allow_bind: true,
+            bind_address: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            bind_accept_timeout: 1,
+            ..Default::default()
+        }
+    }
+
+    /// Read an IPv4 SOCKS5 reply, returning its code and address
+    async fn read_reply(client: &mut DuplexStream) -> (u8, SocketAddr) {
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[0], SOCKS5_VERSION);
+        assert_eq!(reply[3], SOCKS5_ADDR_TYPE_IPV4);
+        let ip = Ipv4Addr::new(reply[4], reply[5], reply[6], reply[7]);
+        let port = u16::from_be_bytes([reply[8], reply[9]]);
+        (reply[1], SocketAddr::new(IpAddr::V4(ip), port))
+    }
+
+    #[tokio::test]
+    async fn test_bind_sends_two_replies_and_relays() {
+        let (mut client, server) = duplex(1024);
+        let target = TargetAddr::ipv4(Ipv4Addr::LOCALHOST, 0);
+        let handle = tokio::spawn(async move {
+            handle_bind(server, target, &bind_config(), &Bandwidth::default()).await
+        });
+
+        let (code, bound) = read_reply(&mut client).await;
+        assert_eq!(code, SOCKS5_REPLY_SUCCEEDED);
+        assert_eq!(bound.ip(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+
+        let mut peer = TcpStream::connect(bound).await.unwrap();
+        let (code, peer_addr) = read_reply(&mut client).await;
+        assert_eq!(code, SOCKS5_REPLY_SUCCEEDED);
+        assert_eq!(peer_addr, peer.local_addr().unwrap());
+
+        peer.write_all(b"220 ready").await.unwrap();
+        let mut buf = [0u8; 9];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"220 ready");
+        client.write_all(b"ok").await.unwrap();
+        let mut buf = [0u8; 2];
+        peer.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ok");
+        drop(peer);
+
+        let summary = handle.await.unwrap().unwrap();
+        assert_eq!(summary.close_reason, CloseReason::TargetClosed);
+        assert_eq!((summary.bytes_up, summary.bytes_down), (2, 9));
+    }
+
+    #[tokio::test]
+    async fn test_bind_ignores_unexpected_peer() {
+        let (mut client, server) = duplex(1024);
+        let target = TargetAddr::ipv4(Ipv4Addr::new(192, 0, 2, 1), 0);
+        let handle = tokio::spawn(async move {
+            handle_bind(server, target, &bind_config(), &Bandwidth::default()).await
+        });
+
+        let (_, bound) = read_reply(&mut client).await;
+        let _stranger = TcpStream::connect(bound).await.unwrap();
+
+        // No connection from 192.0.2.1 arrives, so the wait times out
+        let (code, _) = read_reply(&mut client).await;
+        assert_eq!(code, SOCKS5_REPLY_HOST_UNREACHABLE);
+        assert!(handle.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bind_reply_reports_advertised_addr() {
+        let advertised: SocketAddr = "203.0.113.7:2121".parse().unwrap();
+        let config = SocksConfig {
+            advertised_bind_addr: Some(advertised),
+            ..bind_config()
+        };
+        let (mut client, server) = duplex(1024);
+        let target = TargetAddr::ipv4(Ipv4Addr::LOCALHOST, 0);
+        let handle = tokio::spawn(async move {
+            handle_bind(server, target, &config, &Bandwidth::default()).await
+        });
+
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(
+            reply,
+            [
+                SOCKS5_VERSION,
+                SOCKS5_REPLY_SUCCEEDED,
+                SOCKS5_RESERVED,
+                SOCKS5_ADDR_TYPE_IPV4,
+                203,
+                0,
+                113,
+                7,
+                0x08,
+                0x49
+            ]
+        );
+        handle.abort();
+    }
+
+    #[test]
+    fn test_advertised_port_zero_keeps_real_port() {
+        let config = SocksConfig {
+            advertised_bind_addr: Some("203.0.113.7:0".parse().unwrap()),
+            ..Default::default()
+        };
+        let actual: SocketAddr = "127.0.0.1:40001".parse().unwrap();
+        assert_eq!(
+            config.advertised_addr(actual),
+            "203.0.113.7:40001".parse::<SocketAddr>().unwrap()
+        );
+        assert_eq!(SocksConfig::default().advertised_addr(actual), actual);
+    }
+
+    #[tokio::test]
+    async fn test_bind_listener_uses_port_range() {
+        let range = (42000, 42099);
+        let listener = bind_listener(Some(IpAddr::V4(Ipv4Addr::LOCALHOST)), Some(range)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        assert!((range.0..=range.1).contains(&port));
+    }
+}
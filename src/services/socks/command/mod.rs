@@ -5,7 +5,8 @@
 mod parser;
 mod reply;
 
-pub use parser::parse_command;
+pub use parser::{parse_command, parse_command_with};
 pub use reply::{
-    build_reply, send_command_not_supported, send_general_failure, send_io_error, send_success,
+    build_reply, is_client_gone, send_command_not_supported, send_general_failure, send_io_error,
+    send_success,
 };
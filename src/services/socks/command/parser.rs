@@ -8,6 +8,19 @@ use anyhow::{bail, Context, Result};
 use std::net::{Ipv4Addr, Ipv6Addr};
 use tokio::io::{AsyncRead, AsyncReadExt};
 
+/// Parse a SOCKS5 command from the stream, tolerating a nonzero RSV byte
+///
+/// Equivalent to [`parse_command_with`] with `strict` set to false.
+pub async fn parse_command<S>(
+    stream: &mut S,
+    resolve_dns: bool,
+) -> Result<(SocksCommand, TargetAddr)>
+where
+    S: AsyncRead + Unpin,
+{
+    parse_command_with(stream, resolve_dns, false).await
+}
+
 /// Parse a SOCKS5 command from the stream
 ///
 /// # SOCKS5 Request Format
@@ -24,13 +37,15 @@ use tokio::io::{AsyncRead, AsyncReadExt};
 ///
 /// * `stream` - The stream to read from
 /// * `resolve_dns` - Whether to resolve domain names immediately
+/// * `strict` - Reject a nonzero RSV byte instead of ignoring it
 ///
 /// # Returns
 ///
 /// A tuple of (command, target_address)
-pub async fn parse_command<S>(
+pub async fn parse_command_with<S>(
     stream: &mut S,
     resolve_dns: bool,
+    strict: bool,
 ) -> Result<(SocksCommand, TargetAddr)>
 where
     S: AsyncRead + Unpin,
@@ -44,7 +59,7 @@ where
 
     let version = header[0];
     let cmd_byte = header[1];
-    let _reserved = header[2];
+    let reserved = header[2];
     let addr_type = header[3];
 
     // Validate version
@@ -52,6 +67,13 @@ where
         bail!("Unsupported SOCKS version in command: {}", version);
     }
 
+    if reserved != SOCKS5_RESERVED {
+        if strict {
+            bail!("Invalid RSV byte in command: {:#04x}", reserved);
+        }
+        tracing::debug!("Ignoring nonzero RSV byte in command: {:#04x}", reserved);
+    }
+
     // Parse command
     let command = SocksCommand::from_byte(cmd_byte)
         .ok_or_else(|| anyhow::anyhow!("Unknown command: {}", cmd_byte))?;
@@ -253,4 +275,27 @@ mod tests {
 
         assert_eq!(cmd, SocksCommand::UdpAssociate);
     }
+
+    #[tokio::test]
+    async fn test_parse_command_nonzero_rsv_strict() {
+        let mut request = create_connect_request_ipv4([127, 0, 0, 1], 80);
+        request[2] = 0x01;
+
+        let mut cursor = Cursor::new(request);
+        let result = parse_command_with(&mut cursor, false, true).await;
+
+        assert!(result.unwrap_err().to_string().contains("RSV"));
+    }
+
+    #[tokio::test]
+    async fn test_parse_command_nonzero_rsv_lenient() {
+        let mut request = create_connect_request_ipv4([127, 0, 0, 1], 80);
+        request[2] = 0x01;
+
+        let mut cursor = Cursor::new(request);
+        let (cmd, addr) = parse_command_with(&mut cursor, false, false).await.unwrap();
+
+        assert_eq!(cmd, SocksCommand::Connect);
+        assert_eq!(addr.to_string(), "127.0.0.1:80");
+    }
 }
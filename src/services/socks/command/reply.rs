@@ -74,6 +74,8 @@ where
         std::io::ErrorKind::ConnectionRefused => SOCKS5_REPLY_CONNECTION_REFUSED,
         std::io::ErrorKind::TimedOut => SOCKS5_REPLY_HOST_UNREACHABLE,
         std::io::ErrorKind::AddrNotAvailable => SOCKS5_REPLY_HOST_UNREACHABLE,
+        std::io::ErrorKind::HostUnreachable => SOCKS5_REPLY_HOST_UNREACHABLE,
+        std::io::ErrorKind::NetworkUnreachable => SOCKS5_REPLY_NETWORK_UNREACHABLE,
         std::io::ErrorKind::PermissionDenied => SOCKS5_REPLY_CONNECTION_NOT_ALLOWED,
         _ => SOCKS5_REPLY_GENERAL_FAILURE,
     };
@@ -97,6 +99,22 @@ where
     build_reply(stream, SOCKS5_REPLY_GENERAL_FAILURE, None).await
 }
 
+/// Check whether a reply write failed because the client already went away
+///
+/// Broken pipes and connection resets while replying are a normal
+/// client-gone condition rather than a relay failure.
+pub fn is_client_gone(error: &anyhow::Error) -> bool {
+    error
+        .chain()
+        .filter_map(|e| e.downcast_ref::<std::io::Error>())
+        .any(|e| {
+            matches!(
+                e.kind(),
+                std::io::ErrorKind::BrokenPipe | std::io::ErrorKind::ConnectionReset
+            )
+        })
+}
+
 /// Build reply bytes without sending (used in tests)
 #[cfg(test)]
 fn build_reply_bytes(reply_code: u8, bind_addr: Option<SocketAddr>) -> Vec<u8> {
@@ -238,7 +256,7 @@ mod tests {
     #[tokio::test]
     async fn test_send_io_error_other() {
         let mut buffer = Vec::new();
-        let err = std::io::Error::new(std::io::ErrorKind::Other, "other");
+        let err = std::io::Error::other("other");
 
         send_io_error(&mut buffer, &err).await.unwrap();
 
@@ -262,4 +280,22 @@ mod tests {
 
         assert_eq!(buffer[1], SOCKS5_REPLY_GENERAL_FAILURE);
     }
+
+    #[test]
+    fn test_is_client_gone() {
+        use anyhow::Context;
+        use std::io::{Error, ErrorKind};
+
+        let gone: anyhow::Error = Error::from(ErrorKind::BrokenPipe).into();
+        assert!(is_client_gone(&gone));
+
+        let wrapped = Err::<(), _>(Error::from(ErrorKind::ConnectionReset))
+            .context("writing reply")
+            .unwrap_err();
+        assert!(is_client_gone(&wrapped));
+
+        let other: anyhow::Error = Error::from(ErrorKind::TimedOut).into();
+        assert!(!is_client_gone(&other));
+        assert!(!is_client_gone(&anyhow::anyhow!("not an I/O error")));
+    }
 }
@@ -0,0 +1,265 @@
+//! Happy Eyeballs connection racing (RFC 8305)
+//!
+//! A CONNECT target that resolves to several addresses is dialed by racing
+//! the addresses against each other instead of trying only the first one.
+//! Address families are interleaved, a new attempt starts every
+//! [`CONNECTION_ATTEMPT_DELAY`] (or as soon as the previous one fails), and
+//! the first connection to succeed wins; the others are cancelled. This
+//! keeps a dead IPv6 route from stalling connects that IPv4 would serve.
+
+use crate::config::SocksConfig;
+use crate::services::socks::dialer::{DialedStream, TargetDialer};
+use crate::services::socks::tcp_relay::dial_with_retries;
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tracing::debug;
+
+/// Pause before starting the next connection attempt (RFC 8305 section 5)
+pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Connect to the first reachable address in `addrs`
+///
+/// A single address is dialed with the usual retries. Several addresses
+/// are raced, all within one `request_timeout` deadline and each attempt
+/// within the connect timeout. Returns the address that connected along
+/// with the stream.
+pub async fn connect_happy_eyeballs(
+    dialer: &dyn TargetDialer,
+    addrs: &[SocketAddr],
+    config: &SocksConfig,
+) -> io::Result<(SocketAddr, DialedStream)> {
+    match addrs {
+        [] => Err(io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            "No addresses",
+        )),
+        [addr] => Ok((
+            *addr,
+            dial_with_retries(dialer, &(*addr).into(), config).await?,
+        )),
+        _ => {
+            let deadline = Duration::from_secs(config.request_timeout);
+            let addrs = interleave(addrs);
+            tokio::time::timeout(
+                deadline,
+                race(dialer, &addrs, config, CONNECTION_ATTEMPT_DELAY),
+            )
+            .await
+            .unwrap_or_else(|_| {
+                Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "Connection timeout",
+                ))
+            })
+        }
+    }
+}
+
+/// Order `addrs` alternating between address families, starting with the
+/// family of the first address
+fn interleave(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let prefer_v6 = addrs.first().is_some_and(SocketAddr::is_ipv6);
+    let (mut preferred, mut other): (Vec<SocketAddr>, Vec<SocketAddr>) =
+        addrs.iter().partition(|addr| addr.is_ipv6() == prefer_v6);
+    preferred.reverse();
+    other.reverse();
+
+    let mut ordered = Vec::with_capacity(addrs.len());
+    loop {
+        match (preferred.pop(), other.pop()) {
+            (None, None) => return ordered,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Dial `addrs` in order, starting the next attempt after `delay` or when
+/// an attempt fails, and return the first connection made
+async fn race(
+    dialer: &dyn TargetDialer,
+    addrs: &[SocketAddr],
+    config: &SocksConfig,
+    delay: Duration,
+) -> io::Result<(SocketAddr, DialedStream)> {
+    let mut pending = addrs.iter().copied();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = None;
+
+    let timeout = config.connect_timeout();
+    let dial = |addr: SocketAddr| async move {
+        match tokio::time::timeout(timeout, dialer.dial(addr, config)).await {
+            Ok(result) => (addr, result),
+            Err(_) => (
+                addr,
+                Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "Connection timeout",
+                )),
+            ),
+        }
+    };
+    if let Some(addr) = pending.next() {
+        attempts.push(dial(addr));
+    }
+
+    loop {
+        let more = pending.len() > 0;
+        tokio::select! {
+            finished = attempts.next(), if !attempts.is_empty() => {
+                match finished {
+                    Some((addr, Ok(stream))) => return Ok((addr, stream)),
+                    Some((addr, Err(e))) => {
+                        debug!("Connect to {} failed: {}", addr, e);
+                        last_error = Some(e);
+                        match pending.next() {
+                            Some(next) => attempts.push(dial(next)),
+                            None if attempts.is_empty() => break,
+                            None => {}
+                        }
+                    }
+                    None => break,
+                }
+            }
+            _ = tokio::time::sleep(delay), if more => {
+                if let Some(next) = pending.next() {
+                    attempts.push(dial(next));
+                }
+            }
+        }
+    }
+
+    Err(last_error
+        .unwrap_or_else(|| io::Error::new(io::ErrorKind::AddrNotAvailable, "No addresses")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use tokio::io::duplex;
+    use tokio::time::Instant;
+
+    /// Dialer whose IPv6 attempts never complete; IPv4 attempts connect,
+    /// except to the addresses listed in `refused`
+    #[derive(Debug, Default)]
+    struct BrokenV6Dialer {
+        refused: Vec<SocketAddr>,
+        dialed: Mutex<Vec<SocketAddr>>,
+    }
+
+    #[async_trait::async_trait]
+    impl TargetDialer for BrokenV6Dialer {
+        async fn dial(&self, addr: SocketAddr, _config: &SocksConfig) -> io::Result<DialedStream> {
+            self.dialed.lock().unwrap().push(addr);
+            if addr.is_ipv6() {
+                std::future::pending::<()>().await;
+            }
+            if self.refused.contains(&addr) {
+                return Err(io::Error::from(io::ErrorKind::ConnectionRefused));
+            }
+            Ok(DialedStream {
+                stream: Box::new(duplex(64).0),
+                local_addr: None,
+            })
+        }
+    }
+
+    fn addrs(list: &[&str]) -> Vec<SocketAddr> {
+        list.iter().map(|a| a.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_interleave_alternates_families() {
+        let input = addrs(&[
+            "[2001:db8::1]:80",
+            "[2001:db8::2]:80",
+            "[2001:db8::3]:80",
+            "192.0.2.1:80",
+            "192.0.2.2:80",
+        ]);
+        assert_eq!(
+            interleave(&input),
+            addrs(&[
+                "[2001:db8::1]:80",
+                "192.0.2.1:80",
+                "[2001:db8::2]:80",
+                "192.0.2.2:80",
+                "[2001:db8::3]:80",
+            ])
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_hanging_v6_falls_back_to_v4() {
+        let dialer = BrokenV6Dialer::default();
+        let config = SocksConfig::default();
+        let start = Instant::now();
+
+        let (addr, _stream) = connect_happy_eyeballs(
+            &dialer,
+            &addrs(&["[2001:db8::1]:80", "192.0.2.1:80"]),
+            &config,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(addr, "192.0.2.1:80".parse::<SocketAddr>().unwrap());
+        assert_eq!(start.elapsed(), CONNECTION_ATTEMPT_DELAY);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_failed_attempt_starts_next_immediately() {
+        let dialer = BrokenV6Dialer {
+            refused: addrs(&["192.0.2.1:80"]),
+            ..Default::default()
+        };
+        let config = SocksConfig::default();
+        let start = Instant::now();
+
+        let (addr, _stream) =
+            connect_happy_eyeballs(&dialer, &addrs(&["192.0.2.1:80", "192.0.2.2:80"]), &config)
+                .await
+                .unwrap();
+
+        assert_eq!(addr, "192.0.2.2:80".parse::<SocketAddr>().unwrap());
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_all_attempts_fail() {
+        let refused = addrs(&["192.0.2.1:80", "192.0.2.2:80"]);
+        let dialer = BrokenV6Dialer {
+            refused: refused.clone(),
+            ..Default::default()
+        };
+
+        let err = connect_happy_eyeballs(&dialer, &refused, &SocksConfig::default())
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        assert_eq!(dialer.dialed.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_request_timeout_is_overall_deadline() {
+        let dialer = BrokenV6Dialer::default();
+        let config = SocksConfig {
+            request_timeout: 1,
+            ..Default::default()
+        };
+        let start = Instant::now();
+
+        let err = connect_happy_eyeballs(
+            &dialer,
+            &addrs(&["[2001:db8::1]:80", "[2001:db8::2]:80"]),
+            &config,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+        assert_eq!(dialer.dialed.lock().unwrap().len(), 2);
+    }
+}
@@ -21,7 +21,7 @@ pub const SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE: u8 = 0xFF;
 // Commands
 /// TCP CONNECT command
 pub const SOCKS5_CMD_TCP_CONNECT: u8 = 0x01;
-/// TCP BIND command (not implemented)
+/// TCP BIND command
 pub const SOCKS5_CMD_TCP_BIND: u8 = 0x02;
 /// UDP ASSOCIATE command
 pub const SOCKS5_CMD_UDP_ASSOCIATE: u8 = 0x03;
@@ -58,6 +58,18 @@ pub const SOCKS5_REPLY_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
 /// Reserved byte value (always 0x00)
 pub const SOCKS5_RESERVED: u8 = 0x00;
 
+// SOCKS4/4a fallback
+/// SOCKS4 protocol version
+pub const SOCKS4_VERSION: u8 = 0x04;
+/// Version byte of SOCKS4 replies
+pub const SOCKS4_REPLY_VERSION: u8 = 0x00;
+/// SOCKS4 CONNECT command
+pub const SOCKS4_CMD_CONNECT: u8 = 0x01;
+/// Request granted
+pub const SOCKS4_REPLY_GRANTED: u8 = 0x5A;
+/// Request rejected or failed
+pub const SOCKS4_REPLY_REJECTED: u8 = 0x5B;
+
 // Buffer sizes
 /// Maximum domain name length
 pub const MAX_DOMAIN_LEN: usize = 255;
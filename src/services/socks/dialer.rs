@@ -0,0 +1,326 @@
+//! Outbound connections for SOCKS5 CONNECT
+//!
+//! A [`TargetDialer`] opens the connection to a CONNECT target.
+//! [`DirectDialer`] uses the host network (honouring
+//! [`outbound_bind_addr`](crate::config::SocksConfig::outbound_bind_addr) and
+//! [`egress_port_range`](crate::config::SocksConfig::egress_port_range));
+//! [`TransportDialer`] connects through a tunnel transport instead, which is
+//! how SOCKS5 egress follows the WireGuard tunnel when it is enabled.
+
+use crate::config::SocksConfig;
+use crate::transport::{AddrMaybeCached, StreamDyn, TransportDyn};
+use async_trait::async_trait;
+use std::fmt::Debug;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::net::{TcpSocket, TcpStream};
+use tracing::debug;
+
+/// Rotating offset into the egress port range, so consecutive connections
+/// don't all start probing from the bottom of the range
+static NEXT_EGRESS_PORT: AtomicUsize = AtomicUsize::new(0);
+
+/// A connected target stream
+#[derive(Debug)]
+pub struct DialedStream {
+    /// The stream to the target
+    pub stream: Box<dyn StreamDyn>,
+    /// Local address of the connection, reported as BND.ADDR in the reply
+    pub local_addr: Option<SocketAddr>,
+}
+
+/// Opens connections to SOCKS5 CONNECT targets
+#[async_trait]
+pub trait TargetDialer: Debug + Send + Sync {
+    /// Connect to `addr`
+    async fn dial(&self, addr: SocketAddr, config: &SocksConfig) -> io::Result<DialedStream>;
+
+    /// Whether connections leave through the host's own network stack
+    ///
+    /// Host-level checks such as the IPv6 egress probe only apply when
+    /// this is true.
+    fn uses_host_network(&self) -> bool {
+        true
+    }
+
+    /// Whether domain targets are handed to [`dial_domain`] unresolved,
+    /// leaving the lookup to the far side
+    ///
+    /// [`dial_domain`]: TargetDialer::dial_domain
+    fn resolves_remotely(&self) -> bool {
+        false
+    }
+
+    /// Connect to `domain:port` without resolving it locally
+    ///
+    /// Only called when [`resolves_remotely`](TargetDialer::resolves_remotely)
+    /// is true.
+    async fn dial_domain(
+        &self,
+        domain: &str,
+        port: u16,
+        _config: &SocksConfig,
+    ) -> io::Result<DialedStream> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("Cannot dial {}:{} without resolving it", domain, port),
+        ))
+    }
+}
+
+/// Dials targets directly over the host network
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DirectDialer;
+
+#[async_trait]
+impl TargetDialer for DirectDialer {
+    async fn dial(&self, addr: SocketAddr, config: &SocksConfig) -> io::Result<DialedStream> {
+        let stream =
+            connect_target(addr, config.outbound_bind_addr, config.egress_port_range).await?;
+        let local_addr = stream.local_addr().ok();
+        Ok(DialedStream {
+            stream: Box::new(stream),
+            local_addr,
+        })
+    }
+}
+
+/// Dials targets through a tunnel transport such as
+/// [`WireguardTransport`](crate::transport::WireguardTransport)
+#[derive(Debug, Clone)]
+pub struct TransportDialer {
+    transport: Arc<dyn TransportDyn>,
+}
+
+impl TransportDialer {
+    /// Create a dialer connecting through `transport`
+    pub fn new(transport: Arc<dyn TransportDyn>) -> Self {
+        TransportDialer { transport }
+    }
+}
+
+#[async_trait]
+impl TargetDialer for TransportDialer {
+    async fn dial(&self, addr: SocketAddr, _config: &SocksConfig) -> io::Result<DialedStream> {
+        let stream = self
+            .transport
+            .connect_dyn(&AddrMaybeCached::new(&addr.to_string()))
+            .await
+            .map_err(into_io_error)?;
+        Ok(DialedStream {
+            stream,
+            local_addr: None,
+        })
+    }
+
+    fn uses_host_network(&self) -> bool {
+        false
+    }
+}
+
+/// Convert a transport error into an I/O error, keeping the kind of the
+/// underlying I/O error (if any) so the SOCKS5 reply code stays accurate
+fn into_io_error(error: anyhow::Error) -> io::Error {
+    let kind = error
+        .chain()
+        .find_map(|e| e.downcast_ref::<io::Error>())
+        .map(io::Error::kind)
+        .unwrap_or(io::ErrorKind::Other);
+    io::Error::new(kind, format!("{:#}", error))
+}
+
+/// Connect to `addr` from the local address `bind_ip` and a local port in
+/// `port_range`, each if given
+///
+/// Ports already in use are skipped; the connect fails with
+/// [`io::ErrorKind::AddrInUse`] only when every port in the range is taken.
+/// A `bind_ip` of the other address family than `addr` cannot reach it and
+/// fails with [`io::ErrorKind::AddrNotAvailable`].
+async fn connect_target(
+    addr: SocketAddr,
+    bind_ip: Option<IpAddr>,
+    port_range: Option<(u16, u16)>,
+) -> io::Result<TcpStream> {
+    let local_ip = match bind_ip {
+        Some(ip) if ip.is_ipv4() != addr.is_ipv4() => {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("Outbound bind address {} cannot reach {}", ip, addr),
+            ));
+        }
+        Some(ip) => ip,
+        None if port_range.is_none() => return TcpStream::connect(addr).await,
+        None if addr.is_ipv4() => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        None => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    let new_socket = || match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4(),
+        SocketAddr::V6(_) => TcpSocket::new_v6(),
+    };
+
+    let Some((low, high)) = port_range else {
+        let socket = new_socket()?;
+        socket.bind(SocketAddr::new(local_ip, 0))?;
+        return socket.connect(addr).await;
+    };
+
+    let count = (high - low) as usize + 1;
+    let start = NEXT_EGRESS_PORT.fetch_add(1, Ordering::Relaxed);
+
+    for offset in 0..count {
+        let port = low + ((start + offset) % count) as u16;
+        let socket = new_socket()?;
+        match socket.bind(SocketAddr::new(local_ip, port)) {
+            Ok(()) => {
+                debug!("Bound egress socket to port {}", port);
+                return socket.connect(addr).await;
+            }
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::AddrInUse,
+        format!("No free egress port in range {}-{}", low, high),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ratelimit::Bandwidth;
+    use crate::services::socks::dns::DnsCache;
+    use crate::services::socks::handle_socks5_on_stream_with;
+    use std::sync::Mutex;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    #[tokio::test]
+    async fn test_connect_target_binds_within_port_range() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let range = (41000, 41099);
+
+        let stream = connect_target(addr, None, Some(range)).await.unwrap();
+        let local_port = stream.local_addr().unwrap().port();
+        assert!((range.0..=range.1).contains(&local_port));
+
+        let (_, peer) = listener.accept().await.unwrap();
+        assert_eq!(peer.port(), local_port);
+    }
+
+    #[tokio::test]
+    async fn test_connect_target_skips_ports_in_use() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Occupy the only port in the range
+        let taken = std::net::TcpListener::bind("0.0.0.0:0").unwrap();
+        let port = taken.local_addr().unwrap().port();
+
+        let err = connect_target(addr, None, Some((port, port)))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+    }
+
+    #[tokio::test]
+    async fn test_connect_target_binds_outbound_address() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // Another address on the loopback interface
+        let alias = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2));
+
+        let stream = connect_target(addr, Some(alias), None).await.unwrap();
+        assert_eq!(stream.local_addr().unwrap().ip(), alias);
+
+        let (_, peer) = listener.accept().await.unwrap();
+        assert_eq!(peer.ip(), alias);
+
+        let v6 = IpAddr::V6(Ipv6Addr::LOCALHOST);
+        let err = connect_target(addr, Some(v6), None).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrNotAvailable);
+    }
+
+    /// Stand-in for the WireGuard event loop: records every connect and
+    /// hands back one end of an in-memory pipe
+    #[derive(Debug, Default)]
+    struct MockTunnel {
+        dialed: Mutex<Vec<String>>,
+        peer: Mutex<Option<DuplexStream>>,
+    }
+
+    #[async_trait]
+    impl TransportDyn for MockTunnel {
+        async fn connect_dyn(&self, addr: &AddrMaybeCached) -> anyhow::Result<Box<dyn StreamDyn>> {
+            self.dialed.lock().unwrap().push(addr.addr().to_string());
+            let (ours, theirs) = duplex(1024);
+            *self.peer.lock().unwrap() = Some(theirs);
+            Ok(Box::new(ours))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_socks_connect_dials_through_tunnel() {
+        let tunnel = Arc::new(MockTunnel::default());
+        let dialer = TransportDialer::new(tunnel.clone());
+        let config = SocksConfig::default();
+
+        let (mut client, server) = duplex(1024);
+        let handle = tokio::spawn(async move {
+            handle_socks5_on_stream_with(
+                server,
+                &config,
+                None,
+                &dialer,
+                &Bandwidth::default(),
+                &DnsCache::new(),
+            )
+            .await
+        });
+
+        // Greeting (no auth), then CONNECT 10.0.0.7:8080
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut method = [0u8; 2];
+        client.read_exact(&mut method).await.unwrap();
+        assert_eq!(method, [0x05, 0x00]);
+        client
+            .write_all(&[0x05, 0x01, 0x00, 0x01, 10, 0, 0, 7, 0x1f, 0x90])
+            .await
+            .unwrap();
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], 0x00);
+
+        assert_eq!(*tunnel.dialed.lock().unwrap(), vec!["10.0.0.7:8080"]);
+
+        // Traffic flows over the tunnel stream
+        let mut peer = tunnel.peer.lock().unwrap().take().unwrap();
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        peer.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        drop(client);
+        drop(peer);
+        let summary = handle.await.unwrap().unwrap();
+        assert_eq!(summary.target.as_deref(), Some("10.0.0.7:8080"));
+        assert_eq!(summary.bytes_up, 4);
+    }
+
+    #[test]
+    fn test_into_io_error_keeps_kind() {
+        let error = anyhow::Error::from(io::Error::from(io::ErrorKind::ConnectionRefused))
+            .context("WireGuard virtual TCP connection failed");
+        let converted = into_io_error(error);
+        assert_eq!(converted.kind(), io::ErrorKind::ConnectionRefused);
+        assert!(converted.to_string().contains("WireGuard"));
+
+        assert_eq!(
+            into_io_error(anyhow::anyhow!("no route")).kind(),
+            io::ErrorKind::Other
+        );
+    }
+}
@@ -0,0 +1,419 @@
+//! DNS cache for SOCKS5 target resolution
+//!
+//! Successful lookups are cached for
+//! [`dns_cache_ttl`](crate::config::SocksConfig::dns_cache_ttl) seconds and
+//! failed ones (NXDOMAIN, no addresses) for
+//! [`dns_negative_ttl`](crate::config::SocksConfig::dns_negative_ttl)
+//! seconds, so a client hammering a nonexistent domain does not cause a
+//! resolver query per request. A TTL of zero disables that kind of caching.
+//! The cache holds up to
+//! [`dns_cache_max_entries`](crate::config::SocksConfig::dns_cache_max_entries)
+//! names, evicting the least recently used.
+//!
+//! Each SOCKS5 handler holds a cache of its own, built for its
+//! [`dns_upstream`](crate::config::SocksConfig::dns_upstream), so answers
+//! from one resolver are never served for another and a configuration
+//! reload starts with an empty cache.
+
+use crate::config::SocksConfig;
+use crate::services::socks::resolver::{resolver_for_upstream, Resolver, SystemResolver};
+use async_trait::async_trait;
+use futures::future::BoxFuture;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// Default maximum number of cached names
+pub const DNS_CACHE_MAX_ENTRIES: usize = 4096;
+
+/// Resolver function: looks up `host:port`
+pub type ResolveFn = fn(String) -> BoxFuture<'static, io::Result<Vec<SocketAddr>>>;
+
+/// Adapts a [`ResolveFn`] to the [`Resolver`] trait
+#[derive(Debug)]
+struct FnResolver(ResolveFn);
+
+#[async_trait]
+impl Resolver for FnResolver {
+    async fn lookup(&self, domain: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        (self.0)(format!("{}:{}", domain, port)).await
+    }
+}
+
+/// A cached lookup result
+#[derive(Debug, Clone)]
+enum Lookup {
+    /// Resolved addresses
+    Found(Vec<SocketAddr>),
+    /// Failed lookup, kept as its error kind and message
+    Failed(io::ErrorKind, String),
+}
+
+/// A cache slot
+#[derive(Debug)]
+struct Entry {
+    lookup: Lookup,
+    expires: Instant,
+    /// Value of the use counter when the entry was last served or stored
+    last_used: u64,
+}
+
+/// Cached entries and the use counter ordering them
+#[derive(Debug, Default)]
+struct Entries {
+    map: HashMap<(String, u16), Entry>,
+    uses: u64,
+}
+
+impl Entries {
+    fn tick(&mut self) -> u64 {
+        self.uses += 1;
+        self.uses
+    }
+}
+
+/// Cache of resolved SOCKS5 target names
+///
+/// Holds at most `max_entries` names. When full, expired entries are dropped
+/// first and then the least recently used one.
+#[derive(Debug)]
+pub struct DnsCache {
+    /// Backend performing the actual lookups
+    resolver: Arc<dyn Resolver>,
+    /// Cached lookups keyed by (lowercased name, port)
+    entries: Mutex<Entries>,
+    /// Capacity; zero disables caching
+    max_entries: usize,
+}
+
+impl DnsCache {
+    /// Create a cache backed by the system resolver
+    pub fn new() -> Self {
+        Self::with_backend(Arc::new(SystemResolver))
+    }
+
+    /// Create a cache backed by a custom resolver function
+    pub fn with_resolver(resolver: ResolveFn) -> Self {
+        Self::with_backend(Arc::new(FnResolver(resolver)))
+    }
+
+    /// Create a cache in front of `resolver`
+    pub fn with_backend(resolver: Arc<dyn Resolver>) -> Self {
+        DnsCache {
+            resolver,
+            entries: Mutex::new(Entries::default()),
+            max_entries: DNS_CACHE_MAX_ENTRIES,
+        }
+    }
+
+    /// Hold at most `max_entries` names (0 = disable caching)
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = max_entries;
+        self
+    }
+
+    /// Create a cache with the resolver and capacity `config` selects
+    pub fn from_config(config: &SocksConfig) -> anyhow::Result<Self> {
+        let resolver = match &config.dns_upstream {
+            Some(upstream) => resolver_for_upstream(upstream).map_err(anyhow::Error::msg)?,
+            None => Arc::new(SystemResolver),
+        };
+        Ok(DnsCache::with_backend(resolver).with_max_entries(config.dns_cache_max_entries))
+    }
+
+    /// Resolve `domain:port`, serving successes for `ttl` and failures for
+    /// `negative_ttl` from the cache
+    ///
+    /// If the resolver reports record TTLs, successes are kept no longer
+    /// than the shortest of them.
+    pub async fn resolve(
+        &self,
+        domain: &str,
+        port: u16,
+        ttl: Duration,
+        negative_ttl: Duration,
+    ) -> io::Result<Vec<SocketAddr>> {
+        let key = (domain.to_ascii_lowercase(), port);
+
+        if let Some(lookup) = self.get(&key) {
+            debug!("DNS cache hit for {}:{}", domain, port);
+            return into_result(lookup);
+        }
+
+        let (lookup, record_ttl) = match self.resolver.lookup_with_ttl(domain, port).await {
+            Ok((addrs, _)) if addrs.is_empty() => (
+                Lookup::Failed(
+                    io::ErrorKind::NotFound,
+                    format!("No addresses found for domain: {}", domain),
+                ),
+                None,
+            ),
+            Ok((addrs, record_ttl)) => (Lookup::Found(addrs), record_ttl),
+            Err(e) => (Lookup::Failed(e.kind(), e.to_string()), None),
+        };
+
+        let keep_for = match lookup {
+            Lookup::Found(_) => record_ttl.map_or(ttl, |record_ttl| ttl.min(record_ttl)),
+            Lookup::Failed(..) => negative_ttl,
+        };
+        if !keep_for.is_zero() {
+            self.insert(key, lookup.clone(), Instant::now() + keep_for);
+        }
+
+        into_result(lookup)
+    }
+
+    /// Drop every cached entry
+    pub fn clear(&self) {
+        self.lock().map.clear();
+    }
+
+    /// Number of cached names, including expired ones not yet dropped
+    pub fn len(&self) -> usize {
+        self.lock().map.len()
+    }
+
+    /// Whether no names are cached
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn get(&self, key: &(String, u16)) -> Option<Lookup> {
+        let mut entries = self.lock();
+        let used = entries.tick();
+        match entries.map.get_mut(key) {
+            Some(entry) if entry.expires > Instant::now() => {
+                entry.last_used = used;
+                Some(entry.lookup.clone())
+            }
+            Some(_) => {
+                entries.map.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&self, key: (String, u16), lookup: Lookup, expires: Instant) {
+        if self.max_entries == 0 {
+            return;
+        }
+        let mut entries = self.lock();
+        if entries.map.len() >= self.max_entries && !entries.map.contains_key(&key) {
+            let now = Instant::now();
+            entries.map.retain(|_, entry| entry.expires > now);
+        }
+        if entries.map.len() >= self.max_entries && !entries.map.contains_key(&key) {
+            // Still full of live entries: evict the least recently used
+            if let Some(oldest) = entries
+                .map
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(k, _)| k.clone())
+            {
+                entries.map.remove(&oldest);
+            }
+        }
+        let last_used = entries.tick();
+        entries.map.insert(
+            key,
+            Entry {
+                lookup,
+                expires,
+                last_used,
+            },
+        );
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Entries> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Default for DnsCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn into_result(lookup: Lookup) -> io::Result<Vec<SocketAddr>> {
+    match lookup {
+        Lookup::Found(addrs) => Ok(addrs),
+        Lookup::Failed(kind, message) => Err(io::Error::new(kind, message)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const TTL: Duration = Duration::from_secs(60);
+    const NEGATIVE_TTL: Duration = Duration::from_secs(5);
+
+    static FAILING_CALLS: AtomicUsize = AtomicUsize::new(0);
+    static FOUND_CALLS: AtomicUsize = AtomicUsize::new(0);
+    static UNCACHED_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    fn failing(_host: String) -> BoxFuture<'static, io::Result<Vec<SocketAddr>>> {
+        FAILING_CALLS.fetch_add(1, Ordering::SeqCst);
+        Box::pin(async { Err(io::Error::new(io::ErrorKind::NotFound, "NXDOMAIN")) })
+    }
+
+    fn found(_host: String) -> BoxFuture<'static, io::Result<Vec<SocketAddr>>> {
+        FOUND_CALLS.fetch_add(1, Ordering::SeqCst);
+        Box::pin(async { Ok(vec!["192.0.2.1:80".parse().unwrap()]) })
+    }
+
+    fn uncounted(_host: String) -> BoxFuture<'static, io::Result<Vec<SocketAddr>>> {
+        Box::pin(async { Ok(vec!["192.0.2.2:80".parse().unwrap()]) })
+    }
+
+    fn uncached(_host: String) -> BoxFuture<'static, io::Result<Vec<SocketAddr>>> {
+        UNCACHED_CALLS.fetch_add(1, Ordering::SeqCst);
+        Box::pin(async { Ok(Vec::new()) })
+    }
+
+    #[tokio::test]
+    async fn test_negative_lookup_served_from_cache() {
+        let cache = DnsCache::with_resolver(failing);
+
+        let first = cache.resolve("nope.invalid", 80, TTL, NEGATIVE_TTL).await;
+        let second = cache.resolve("NOPE.invalid", 80, TTL, NEGATIVE_TTL).await;
+
+        assert_eq!(first.unwrap_err().kind(), io::ErrorKind::NotFound);
+        let second = second.unwrap_err();
+        assert_eq!(second.kind(), io::ErrorKind::NotFound);
+        assert!(second.to_string().contains("NXDOMAIN"));
+        assert_eq!(FAILING_CALLS.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_positive_lookup_served_from_cache() {
+        let cache = DnsCache::with_resolver(found);
+
+        let first = cache.resolve("example.com", 80, TTL, NEGATIVE_TTL).await;
+        let second = cache.resolve("example.com", 80, TTL, NEGATIVE_TTL).await;
+
+        assert_eq!(first.unwrap(), second.unwrap());
+        assert_eq!(FOUND_CALLS.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_zero_ttl_disables_caching() {
+        let cache = DnsCache::with_resolver(uncached);
+
+        // An empty answer is a failure, and is not cached with a zero TTL
+        for _ in 0..2 {
+            let err = cache
+                .resolve("empty.invalid", 80, Duration::ZERO, Duration::ZERO)
+                .await
+                .unwrap_err();
+            assert!(err.to_string().contains("No addresses found"));
+        }
+        assert_eq!(UNCACHED_CALLS.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_capacity_evicts_least_recently_used() {
+        let cache = DnsCache::with_resolver(uncounted).with_max_entries(2);
+        cache
+            .resolve("a.example", 80, TTL, NEGATIVE_TTL)
+            .await
+            .unwrap();
+        cache
+            .resolve("b.example", 80, TTL, NEGATIVE_TTL)
+            .await
+            .unwrap();
+        // Touch a, so b is the least recently used
+        assert!(cache.get(&("a.example".to_string(), 80)).is_some());
+        cache
+            .resolve("c.example", 80, TTL, NEGATIVE_TTL)
+            .await
+            .unwrap();
+
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(&("a.example".to_string(), 80)).is_some());
+        assert!(cache.get(&("b.example".to_string(), 80)).is_none());
+        assert!(cache.get(&("c.example".to_string(), 80)).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_zero_max_entries_disables_caching() {
+        let cache = DnsCache::with_resolver(uncounted).with_max_entries(0);
+        cache
+            .resolve("a.example", 80, TTL, NEGATIVE_TTL)
+            .await
+            .unwrap();
+        assert!(cache.is_empty());
+    }
+
+    /// Resolver reporting a record TTL of one second
+    #[derive(Debug)]
+    struct ShortTtl;
+
+    #[async_trait]
+    impl Resolver for ShortTtl {
+        async fn lookup(&self, _domain: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+            Ok(vec![SocketAddr::from(([192, 0, 2, 9], port))])
+        }
+
+        async fn lookup_with_ttl(
+            &self,
+            domain: &str,
+            port: u16,
+        ) -> io::Result<(Vec<SocketAddr>, Option<Duration>)> {
+            Ok((
+                self.lookup(domain, port).await?,
+                Some(Duration::from_secs(1)),
+            ))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_record_ttl_caps_cache_ttl() {
+        let cache = DnsCache::with_backend(Arc::new(ShortTtl));
+        cache
+            .resolve("short.example", 80, TTL, NEGATIVE_TTL)
+            .await
+            .unwrap();
+
+        let entries = cache.lock();
+        let entry = &entries.map[&("short.example".to_string(), 80)];
+        assert!(entry.expires <= Instant::now() + Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_from_config() {
+        let default = DnsCache::from_config(&SocksConfig::default()).unwrap();
+        assert_eq!(default.max_entries, DNS_CACHE_MAX_ENTRIES);
+
+        let small = SocksConfig {
+            dns_cache_max_entries: 16,
+            ..Default::default()
+        };
+        assert_eq!(DnsCache::from_config(&small).unwrap().max_entries, 16);
+
+        let bad_upstream = SocksConfig {
+            dns_upstream: Some("ftp://dns.example".to_string()),
+            ..Default::default()
+        };
+        assert!(DnsCache::from_config(&bad_upstream).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_expired_entry_is_dropped() {
+        let cache = DnsCache::with_resolver(found);
+        let key = ("stale.example".to_string(), 80);
+        cache.insert(
+            key.clone(),
+            Lookup::Failed(io::ErrorKind::NotFound, "old".to_string()),
+            Instant::now(),
+        );
+        assert!(cache.get(&key).is_none());
+        assert!(cache.is_empty());
+    }
+}
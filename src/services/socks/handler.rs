@@ -4,15 +4,28 @@
 //! on tunnel streams. It orchestrates authentication, command parsing,
 //! and request handling.
 
+use crate::audit::{self, AuditEvent};
 use crate::config::SocksConfig;
-use crate::services::socks::auth::authenticate;
-use crate::services::socks::command::{parse_command, send_command_not_supported};
+use crate::error::Socks5Error;
+use crate::ratelimit::Bandwidth;
+use crate::services::socks::auth::{authenticate_with_users, UsersFile};
+use crate::services::socks::bind::handle_bind;
+use crate::services::socks::command::{build_reply, is_client_gone, parse_command_with};
+use crate::services::socks::consts::SOCKS4_VERSION;
+use crate::services::socks::dialer::{DirectDialer, TargetDialer};
+use crate::services::socks::dns::DnsCache;
+use crate::services::socks::policy::target_permitted;
+use crate::services::socks::resolver::resolve_target;
+use crate::services::socks::socks4::handle_socks4_on_stream;
 use crate::services::socks::tcp_relay::handle_tcp_connect;
-use crate::services::socks::types::SocksCommand;
+use crate::services::socks::types::{SocksCommand, SocksRequest, TargetAddr};
+#[cfg(feature = "socks-udp")]
 use crate::services::socks::udp::handle_udp_associate;
-use anyhow::{Context, Result};
-use tokio::io::{AsyncRead, AsyncWrite};
-use tracing::{debug, info, warn};
+use crate::services::{CloseReason, ConnectionSummary};
+use anyhow::Context;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, BufReader};
+use tracing::{debug, error, info, warn};
 
 /// Handle SOCKS5 protocol on a stream
 ///
@@ -20,6 +33,11 @@ use tracing::{debug, info, warn};
 /// The stream comes directly from the rathole tunnel, so there's
 /// no local socket binding involved.
 ///
+/// Once the handshake completes, the negotiated [`SocksRequest`] is
+/// attached to the returned summary. A client that offers no
+/// username/password method when authentication is required is not an
+/// error: it ends with [`CloseReason::AuthRequired`].
+///
 /// # Protocol Flow
 ///
 /// 1. Authentication negotiation
@@ -34,46 +52,259 @@ use tracing::{debug, info, warn};
 ///
 /// # Returns
 ///
-/// Ok(()) if the request was handled successfully, Err otherwise
-pub async fn handle_socks5_on_stream<S>(mut stream: S, config: &SocksConfig) -> Result<()>
+/// A [`ConnectionSummary`] of the request if it was handled successfully,
+/// otherwise a [`Socks5Error`] telling what went wrong; the client has
+/// already been sent the matching reply code where the protocol allows one
+pub async fn handle_socks5_on_stream<S>(
+    stream: S,
+    config: &SocksConfig,
+) -> Result<ConnectionSummary, Socks5Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    handle_socks5_on_stream_with_users(stream, config, None).await
+}
+
+/// Handle SOCKS5 protocol on a stream, also accepting the credentials in
+/// `users` during username/password authentication
+pub async fn handle_socks5_on_stream_with_users<S>(
+    stream: S,
+    config: &SocksConfig,
+    users: Option<&UsersFile>,
+) -> Result<ConnectionSummary, Socks5Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let dns_cache = DnsCache::from_config(config).map_err(Socks5Error::Other)?;
+    handle_socks5_on_stream_with(
+        stream,
+        config,
+        users,
+        &DirectDialer,
+        &Bandwidth::default(),
+        &dns_cache,
+    )
+    .await
+}
+
+/// Handle SOCKS5 protocol on a stream, accepting the credentials in `users`
+/// and reaching CONNECT targets through `dialer`
+///
+/// Relays are throttled by `shared_bandwidth`, the limiters shared by every
+/// connection of the service, on top of the per-connection
+/// [`SocksConfig::max_bytes_per_sec`]. Target names are resolved through
+/// `dns_cache`, likewise shared by the service.
+///
+/// With [`SocksConfig::allow_socks4`] set, the version byte is peeked first
+/// and SOCKS4/4a clients are handed to the SOCKS4 fallback. `dialer` is
+/// used as given: to honour [`SocksConfig::upstream_proxy`], pass an
+/// [`UpstreamDialer`](crate::services::socks::UpstreamDialer), as
+/// [`Socks5ServiceHandler`](crate::services::socks::Socks5ServiceHandler)
+/// does.
+pub async fn handle_socks5_on_stream_with<S>(
+    stream: S,
+    config: &SocksConfig,
+    users: Option<&UsersFile>,
+    dialer: &dyn TargetDialer,
+    shared_bandwidth: &Bandwidth,
+    dns_cache: &DnsCache,
+) -> Result<ConnectionSummary, Socks5Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    if !config.allow_socks4 {
+        return handle_socks5(stream, config, users, dialer, shared_bandwidth, dns_cache).await;
+    }
+
+    // Peek without consuming, so the SOCKS5 path still sees the version
+    let mut stream = BufReader::new(stream);
+    let version = tokio::time::timeout(
+        Duration::from_secs(config.handshake_timeout),
+        stream.fill_buf(),
+    )
+    .await
+    .map_err(|_| Socks5Error::HandshakeTimeout)?
+    .map_err(|e| Socks5Error::Other(e.into()))?
+    .first()
+    .copied();
+    match version {
+        Some(SOCKS4_VERSION) => {
+            Ok(
+                handle_socks4_on_stream(stream, config, dialer, shared_bandwidth, dns_cache)
+                    .await?,
+            )
+        }
+        Some(_) => handle_socks5(stream, config, users, dialer, shared_bandwidth, dns_cache).await,
+        None => Err(Socks5Error::Other(anyhow::anyhow!(
+            "Client closed before sending a SOCKS version"
+        ))),
+    }
+}
+
+/// The SOCKS5 handshake and command execution
+async fn handle_socks5<S>(
+    mut stream: S,
+    config: &SocksConfig,
+    users: Option<&UsersFile>,
+    dialer: &dyn TargetDialer,
+    shared_bandwidth: &Bandwidth,
+    dns_cache: &DnsCache,
+) -> Result<ConnectionSummary, Socks5Error>
 where
     S: AsyncRead + AsyncWrite + Unpin + Send,
 {
-    // Step 1: Authentication negotiation
-    let auth_method = authenticate(&mut stream, config)
-        .await
-        .with_context(|| "Authentication negotiation failed")?;
+    let start = Instant::now();
 
-    debug!("Authentication completed with method: {:?}", auth_method);
+    // Steps 1-2 share a single deadline
+    let handshake = tokio::time::timeout(Duration::from_secs(config.handshake_timeout), async {
+        // Step 1: Authentication negotiation
+        let auth_method = authenticate_with_users(&mut stream, config, users)
+            .await
+            .with_context(|| "Authentication negotiation failed")?;
 
-    // Step 2: Read and parse the SOCKS5 command
-    let (command, target_addr) = parse_command(&mut stream, config.dns_resolve)
-        .await
-        .with_context(|| "Failed to parse SOCKS5 command")?;
+        debug!("Authentication completed with method: {:?}", auth_method);
+
+        // Step 2: Read and parse the SOCKS5 command
+        let (command, target_addr) = parse_command_with(&mut stream, false, config.strict_parsing)
+            .await
+            .with_context(|| "Failed to parse SOCKS5 command")?;
+
+        let request = SocksRequest {
+            command,
+            target: target_addr.clone(),
+            auth_method,
+        };
+
+        // The ACL applies to CONNECT targets. Domain rules are checked
+        // before any lookup, address rules once the address is known.
+        let acl_applies = command == SocksCommand::Connect;
+        if acl_applies && !target_permitted(&config.acl, &target_addr, None) {
+            return Ok((request, Err(Socks5Error::AclDenied)));
+        }
+
+        // Resolve here rather than in the parser so lookups go through
+        // the configured resolver and the DNS cache. CONNECT targets are
+        // resolved by the relay, which filters and races every address
+        // and checks them against the ACL with the requested name.
+        let target_addr = if config.dns_resolve && command != SocksCommand::Connect {
+            match resolve_target(&target_addr, dns_cache, config).await {
+                Ok(addrs) => TargetAddr::Ip(addrs[0]),
+                Err(e) => {
+                    error!("{:#}", e);
+                    return Ok((request, Err(Socks5Error::target_unreachable(e))));
+                }
+            }
+        } else {
+            target_addr
+        };
+        Ok::<_, Socks5Error>((request, Ok(target_addr)))
+    })
+    .await
+    .map_err(|_| Socks5Error::HandshakeTimeout)?;
+    let (request, target_addr) = match handshake {
+        Ok(handshake) => handshake,
+        Err(Socks5Error::AuthRequired) => {
+            warn!("Rejected SOCKS5 client: authentication required but not offered");
+            return Ok(
+                ConnectionSummary::new(CloseReason::AuthRequired).with_duration(start.elapsed())
+            );
+        }
+        Err(e) => return Err(e),
+    };
+    let command = request.command;
+    let target_addr = match target_addr {
+        Ok(target_addr) => target_addr,
+        Err(Socks5Error::AclDenied) => {
+            warn!("SOCKS5 {} to {} denied by ACL", command, request.target);
+            send_rejection(&mut stream, &Socks5Error::AclDenied).await?;
+            return Ok(ConnectionSummary::new(CloseReason::Rejected)
+                .with_target(request.target.to_string())
+                .with_duration(start.elapsed())
+                .with_socks_request(request));
+        }
+        Err(e) => {
+            send_rejection(&mut stream, &e).await?;
+            return Err(e);
+        }
+    };
 
     info!("SOCKS5 {} request to {}", command, target_addr);
+    audit::record(AuditEvent::Command {
+        protocol: "socks5".to_string(),
+        command: command.to_string(),
+        target: Some(target_addr.to_string()),
+    });
 
     // Step 3: Execute the command
-    match command {
+    let summary = match command {
         SocksCommand::Connect => {
-            handle_tcp_connect(stream, target_addr, config).await?;
+            handle_tcp_connect(
+                stream,
+                target_addr,
+                config,
+                dialer,
+                shared_bandwidth,
+                dns_cache,
+            )
+            .await?
         }
+        #[cfg(feature = "socks-udp")]
         SocksCommand::UdpAssociate => {
+            let target = target_addr.to_string();
             if config.allow_udp {
-                handle_udp_associate(stream, target_addr, config).await?;
+                let close_reason = handle_udp_associate(stream, target_addr, config).await?;
+                ConnectionSummary::new(close_reason).with_target(target)
             } else {
                 warn!("UDP ASSOCIATE not allowed by configuration");
-                send_command_not_supported(&mut stream).await?;
+                reject_command(&mut stream, command).await?;
+                ConnectionSummary::new(CloseReason::Rejected).with_target(target)
             }
         }
+        #[cfg(not(feature = "socks-udp"))]
+        SocksCommand::UdpAssociate => {
+            warn!("UDP ASSOCIATE not supported in this build");
+            reject_command(&mut stream, command).await?;
+            ConnectionSummary::new(CloseReason::Rejected).with_target(target_addr.to_string())
+        }
+        SocksCommand::Bind if config.allow_bind => {
+            handle_bind(stream, target_addr, config, shared_bandwidth).await?
+        }
         SocksCommand::Bind => {
-            // BIND is not supported in reverse tunnel mode
-            warn!("BIND command not supported");
-            send_command_not_supported(&mut stream).await?;
+            warn!("BIND not allowed by configuration");
+            reject_command(&mut stream, command).await?;
+            ConnectionSummary::new(CloseReason::Rejected).with_target(target_addr.to_string())
         }
-    }
+    };
+
+    Ok(summary
+        .with_duration(start.elapsed())
+        .with_socks_request(request))
+}
 
-    Ok(())
+/// Reply "command not supported" for a command the server does not carry out
+async fn reject_command<S>(stream: &mut S, command: SocksCommand) -> Result<(), Socks5Error>
+where
+    S: AsyncWrite + Unpin,
+{
+    send_rejection(
+        stream,
+        &Socks5Error::UnsupportedCommand(command.to_string()),
+    )
+    .await
+}
+
+/// Send the reply code of `error`, ignoring a client that already went away
+async fn send_rejection<S>(stream: &mut S, error: &Socks5Error) -> Result<(), Socks5Error>
+where
+    S: AsyncWrite + Unpin,
+{
+    match build_reply(stream, error.reply_code().into(), None).await {
+        Err(e) if is_client_gone(&e) => {
+            debug!("Client went away before the SOCKS5 reply: {}", e);
+            Ok(())
+        }
+        result => Ok(result?),
+    }
 }
 
 #[cfg(test)]
@@ -83,17 +314,16 @@ mod tests {
 
     // Helper to create a mock SOCKS5 handshake
     fn create_socks5_handshake(auth_method: u8, command: u8, addr: &[u8]) -> Vec<u8> {
-        let mut data = Vec::new();
-
-        // Auth negotiation: version, num_methods, methods
-        data.push(SOCKS5_VERSION);
-        data.push(1); // 1 method
-        data.push(auth_method);
-
-        // Command: version, cmd, rsv, atyp, addr, port
-        data.push(SOCKS5_VERSION);
-        data.push(command);
-        data.push(SOCKS5_RESERVED);
+        let mut data = vec![
+            // Auth negotiation: version, num_methods, methods
+            SOCKS5_VERSION,
+            1, // 1 method
+            auth_method,
+            // Command: version, cmd, rsv, atyp, addr, port
+            SOCKS5_VERSION,
+            command,
+            SOCKS5_RESERVED,
+        ];
         data.extend_from_slice(addr);
 
         data
@@ -130,7 +360,7 @@ mod tests {
         let _config = SocksConfig::default();
 
         // Invalid SOCKS version
-        let _data = vec![
+        let _data = [
             0x04, // SOCKS4 version (invalid)
             1,
             SOCKS5_AUTH_METHOD_NONE,
@@ -139,4 +369,428 @@ mod tests {
         // This would fail early in authentication
         // We can't fully test without a bidirectional mock stream
     }
+
+    #[tokio::test]
+    async fn test_handle_socks5_handshake_timeout() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let config = SocksConfig {
+            handshake_timeout: 1,
+            ..Default::default()
+        };
+        let (mut client, server) = tokio::io::duplex(1024);
+        let handle = tokio::spawn(async move { handle_socks5_on_stream(server, &config).await });
+
+        // Complete method negotiation, then stall mid-command
+        client
+            .write_all(&[SOCKS5_VERSION, 1, SOCKS5_AUTH_METHOD_NONE])
+            .await
+            .unwrap();
+        let mut reply = [0u8; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        client
+            .write_all(&[SOCKS5_VERSION, SOCKS5_CMD_TCP_CONNECT])
+            .await
+            .unwrap();
+
+        let err = tokio::time::timeout(Duration::from_secs(3), handle)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap_err();
+        assert!(err.to_string().contains("handshake timed out"));
+    }
+
+    #[tokio::test]
+    async fn test_handle_socks5_connect_summary() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // Target that reads a ping and answers with a pong, then closes
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut sock, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 5];
+            sock.read_exact(&mut buf).await.unwrap();
+            sock.write_all(b"pong!!").await.unwrap();
+        });
+
+        let (mut client, server) = tokio::io::duplex(1024);
+        let config = SocksConfig::default();
+        let handle = tokio::spawn(async move { handle_socks5_on_stream(server, &config).await });
+
+        let mut addr = vec![SOCKS5_ADDR_TYPE_IPV4];
+        match target.ip() {
+            std::net::IpAddr::V4(ip) => addr.extend_from_slice(&ip.octets()),
+            std::net::IpAddr::V6(_) => unreachable!(),
+        }
+        addr.extend_from_slice(&target.port().to_be_bytes());
+        let handshake =
+            create_socks5_handshake(SOCKS5_AUTH_METHOD_NONE, SOCKS5_CMD_TCP_CONNECT, &addr);
+        client.write_all(&handshake).await.unwrap();
+
+        // Method selection + IPv4 success reply
+        let mut reply = [0u8; 12];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[3], SOCKS5_REPLY_SUCCEEDED);
+
+        client.write_all(b"ping!").await.unwrap();
+        let mut pong = [0u8; 6];
+        client.read_exact(&mut pong).await.unwrap();
+        assert_eq!(&pong, b"pong!!");
+
+        let summary = tokio::time::timeout(std::time::Duration::from_secs(2), handle)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(summary.bytes_up, 5);
+        assert_eq!(summary.bytes_down, 6);
+        assert_eq!(summary.target, Some(target.to_string()));
+        assert_eq!(summary.close_reason, CloseReason::TargetClosed);
+    }
+
+    #[tokio::test]
+    async fn test_handle_socks5_udp_associate_refused_when_unavailable() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // allow_udp is off by default, and without socks-udp it cannot be on
+        let (mut client, server) = tokio::io::duplex(1024);
+        let config = SocksConfig::default();
+        let handle = tokio::spawn(async move { handle_socks5_on_stream(server, &config).await });
+
+        let addr = [SOCKS5_ADDR_TYPE_IPV4, 0, 0, 0, 0, 0, 0];
+        let handshake =
+            create_socks5_handshake(SOCKS5_AUTH_METHOD_NONE, SOCKS5_CMD_UDP_ASSOCIATE, &addr);
+        client.write_all(&handshake).await.unwrap();
+
+        // Method selection, then the start of the reply
+        let mut reply = [0u8; 4];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], SOCKS5_AUTH_METHOD_NONE);
+        assert_eq!(reply[3], SOCKS5_REPLY_COMMAND_NOT_SUPPORTED);
+
+        let summary = handle.await.unwrap().unwrap();
+        assert_eq!(summary.close_reason, CloseReason::Rejected);
+    }
+
+    #[tokio::test]
+    async fn test_handle_socks5_records_domain_request() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // Refusing the private target keeps the test off the network
+        let (mut client, server) = tokio::io::duplex(1024);
+        let config = SocksConfig {
+            block_private_networks: true,
+            ..Default::default()
+        };
+        let handle = tokio::spawn(async move { handle_socks5_on_stream(server, &config).await });
+
+        let target = TargetAddr::Domain("localhost".to_string(), 8080);
+        let handshake = create_socks5_handshake(
+            SOCKS5_AUTH_METHOD_NONE,
+            SOCKS5_CMD_TCP_CONNECT,
+            &target.to_bytes(),
+        );
+        client.write_all(&handshake).await.unwrap();
+        let mut reply = [0u8; 4];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[3], SOCKS5_REPLY_CONNECTION_NOT_ALLOWED);
+
+        let summary = handle.await.unwrap().unwrap();
+        assert_eq!(
+            summary.socks_request,
+            Some(SocksRequest {
+                command: SocksCommand::Connect,
+                target,
+                auth_method: crate::services::socks::AuthMethod::None,
+            })
+        );
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "socks-auth")]
+    async fn test_handle_socks5_auth_required_not_offered() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (mut client, server) = tokio::io::duplex(1024);
+        let config = SocksConfig {
+            auth_required: true,
+            username: Some("user".to_string()),
+            password: Some("pass".to_string()),
+            ..Default::default()
+        };
+        let handle = tokio::spawn(async move { handle_socks5_on_stream(server, &config).await });
+
+        client
+            .write_all(&[SOCKS5_VERSION, 1, SOCKS5_AUTH_METHOD_NONE])
+            .await
+            .unwrap();
+        let mut reply = [0u8; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [SOCKS5_VERSION, SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE]);
+
+        let summary = handle.await.unwrap().unwrap();
+        assert_eq!(summary.close_reason, CloseReason::AuthRequired);
+        assert_eq!(summary.close_reason.to_string(), "authentication required");
+        assert!(summary.socks_request.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_handle_socks_version_dispatch() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let config = SocksConfig {
+            allow_socks4: true,
+            block_private_networks: true,
+            ..Default::default()
+        };
+
+        // SOCKS4 clients get the 8-byte SOCKS4 reply
+        let (mut client, server) = tokio::io::duplex(1024);
+        let socks4_config = config.clone();
+        let handle =
+            tokio::spawn(async move { handle_socks5_on_stream(server, &socks4_config).await });
+        client
+            .write_all(&[SOCKS4_VERSION, 1, 0, 80, 127, 0, 0, 1, 0])
+            .await
+            .unwrap();
+        let mut reply = [0u8; 8];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..2], [SOCKS4_REPLY_VERSION, SOCKS4_REPLY_REJECTED]);
+        assert_eq!(
+            handle.await.unwrap().unwrap().close_reason,
+            CloseReason::Rejected
+        );
+
+        // SOCKS5 clients are unaffected by the peek
+        let (mut client, server) = tokio::io::duplex(1024);
+        let handle = tokio::spawn(async move { handle_socks5_on_stream(server, &config).await });
+        let target = TargetAddr::Ip("127.0.0.1:80".parse().unwrap());
+        let handshake = create_socks5_handshake(
+            SOCKS5_AUTH_METHOD_NONE,
+            SOCKS5_CMD_TCP_CONNECT,
+            &target.to_bytes(),
+        );
+        client.write_all(&handshake).await.unwrap();
+        let mut reply = [0u8; 4];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[3], SOCKS5_REPLY_CONNECTION_NOT_ALLOWED);
+        assert!(handle.await.unwrap().unwrap().socks_request.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_handle_socks5_acl_denies_domain_before_resolution() {
+        use crate::config::{AclConfig, AclRule};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let config = SocksConfig {
+            acl: AclConfig {
+                deny: vec![AclRule {
+                    domain_suffix: Some("blocked.invalid".to_string()),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (mut client, server) = tokio::io::duplex(1024);
+        let handle = tokio::spawn(async move { handle_socks5_on_stream(server, &config).await });
+
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut method = [0u8; 2];
+        client.read_exact(&mut method).await.unwrap();
+
+        // The name does not resolve, so only the pre-resolution check can
+        // produce a "not allowed" reply
+        let domain = b"www.blocked.invalid";
+        let mut request = vec![0x05, 0x01, 0x00, 0x03, domain.len() as u8];
+        request.extend_from_slice(domain);
+        request.extend_from_slice(&443u16.to_be_bytes());
+        client.write_all(&request).await.unwrap();
+
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], SOCKS5_REPLY_CONNECTION_NOT_ALLOWED);
+
+        let summary = handle.await.unwrap().unwrap();
+        assert_eq!(summary.close_reason, CloseReason::Rejected);
+        assert_eq!(summary.target.as_deref(), Some("www.blocked.invalid:443"));
+    }
+
+    #[tokio::test]
+    async fn test_handle_socks5_acl_allows_domain_suffix_after_resolution() {
+        use crate::config::{AclConfig, AclRule};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move { listener.accept().await });
+
+        // Resolving the name must not lose it for the domain rule
+        let config = SocksConfig {
+            acl: AclConfig {
+                allow: vec![AclRule {
+                    domain_suffix: Some("localhost".to_string()),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(config.dns_resolve);
+        let (mut client, server) = tokio::io::duplex(1024);
+        tokio::spawn(async move { handle_socks5_on_stream(server, &config).await });
+
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut method = [0u8; 2];
+        client.read_exact(&mut method).await.unwrap();
+
+        let domain = b"localhost";
+        let mut request = vec![0x05, 0x01, 0x00, 0x03, domain.len() as u8];
+        request.extend_from_slice(domain);
+        request.extend_from_slice(&port.to_be_bytes());
+        client.write_all(&request).await.unwrap();
+
+        let mut reply = [0u8; 4];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], SOCKS5_REPLY_SUCCEEDED);
+    }
+
+    #[tokio::test]
+    async fn test_handle_socks5_races_every_resolved_address() {
+        use crate::services::socks::dialer::DialedStream;
+        use crate::services::socks::CONNECTION_ATTEMPT_DELAY;
+        use futures::future::BoxFuture;
+        use std::net::SocketAddr;
+        use std::sync::{Arc, Mutex};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        /// A private address, then a v6 address whose route hangs, then v4
+        fn dual_stack(host: String) -> BoxFuture<'static, std::io::Result<Vec<SocketAddr>>> {
+            let port = host.rsplit(':').next().unwrap().to_string();
+            Box::pin(async move {
+                Ok(["10.0.0.1", "[2001:db8::1]", "192.0.2.1"]
+                    .iter()
+                    .map(|ip| format!("{}:{}", ip, port).parse().unwrap())
+                    .collect())
+            })
+        }
+
+        #[derive(Debug, Default)]
+        struct BrokenV6Dialer(Mutex<Vec<SocketAddr>>);
+
+        #[async_trait::async_trait]
+        impl TargetDialer for BrokenV6Dialer {
+            async fn dial(
+                &self,
+                addr: SocketAddr,
+                _config: &SocksConfig,
+            ) -> std::io::Result<DialedStream> {
+                self.0.lock().unwrap().push(addr);
+                if addr.is_ipv6() {
+                    std::future::pending::<()>().await;
+                }
+                Ok(DialedStream {
+                    stream: Box::new(tokio::io::duplex(64).0),
+                    local_addr: None,
+                })
+            }
+        }
+
+        let config = SocksConfig {
+            block_private_networks: true,
+            ipv6_probe: false,
+            ..Default::default()
+        };
+        let dialer = Arc::new(BrokenV6Dialer::default());
+        let (mut client, server) = tokio::io::duplex(1024);
+        let handler_dialer = dialer.clone();
+        tokio::spawn(async move {
+            handle_socks5_on_stream_with(
+                server,
+                &config,
+                None,
+                handler_dialer.as_ref(),
+                &Bandwidth::default(),
+                &DnsCache::with_resolver(dual_stack),
+            )
+            .await
+        });
+
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut method = [0u8; 2];
+        client.read_exact(&mut method).await.unwrap();
+
+        let domain = b"dual-stack.test";
+        let mut request = vec![0x05, 0x01, 0x00, 0x03, domain.len() as u8];
+        request.extend_from_slice(domain);
+        request.extend_from_slice(&443u16.to_be_bytes());
+        client.write_all(&request).await.unwrap();
+
+        let mut reply = [0u8; 4];
+        tokio::time::timeout(CONNECTION_ATTEMPT_DELAY * 4, client.read_exact(&mut reply))
+            .await
+            .expect("v4 should win while v6 hangs")
+            .unwrap();
+        assert_eq!(reply[1], SOCKS5_REPLY_SUCCEEDED);
+        // The private address is skipped, not the whole name refused
+        let dialed: Vec<String> = dialer
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+        assert_eq!(dialed, ["[2001:db8::1]:443", "192.0.2.1:443"]);
+    }
+
+    #[tokio::test]
+    async fn test_handle_socks5_unresolvable_domain_replies_host_unreachable() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let config = SocksConfig {
+            request_timeout: 1,
+            dns_negative_ttl: 0,
+            ..Default::default()
+        };
+        let (mut client, server) = tokio::io::duplex(1024);
+        let handle = tokio::spawn(async move { handle_socks5_on_stream(server, &config).await });
+
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut method = [0u8; 2];
+        client.read_exact(&mut method).await.unwrap();
+
+        let domain = b"does-not-exist.invalid";
+        let mut request = vec![0x05, 0x01, 0x00, 0x03, domain.len() as u8];
+        request.extend_from_slice(domain);
+        request.extend_from_slice(&80u16.to_be_bytes());
+        client.write_all(&request).await.unwrap();
+
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], SOCKS5_REPLY_HOST_UNREACHABLE);
+        assert!(matches!(
+            handle.await.unwrap(),
+            Err(Socks5Error::TargetUnreachable(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_handle_socks4_refused_by_default() {
+        use tokio::io::AsyncWriteExt;
+
+        let (mut client, server) = tokio::io::duplex(1024);
+        let handle =
+            tokio::spawn(
+                async move { handle_socks5_on_stream(server, &SocksConfig::default()).await },
+            );
+        client
+            .write_all(&[SOCKS4_VERSION, 1, 0, 80, 127, 0, 0, 1, 0])
+            .await
+            .unwrap();
+
+        let err = handle.await.unwrap().unwrap_err();
+        assert!(format!("{:#}", err).contains("Unsupported SOCKS version: 4"));
+    }
 }
@@ -0,0 +1,187 @@
+//! IPv6 egress detection
+//!
+//! On hosts without IPv6 connectivity, dialing a v6-only target burns the
+//! whole connect timeout before failing. [`Ipv6Egress`] probes whether the
+//! host can route IPv6 traffic, caches the answer and re-probes
+//! periodically, so callers can fail fast (or pick an IPv4 address
+//! instead) when IPv6 is not usable.
+
+use lazy_static::lazy_static;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// How long a probe result stays valid before re-probing
+pub const IPV6_REPROBE_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Well-known global IPv6 address used for the route probe.
+/// No packet is sent; connecting a UDP socket only performs a route lookup.
+const IPV6_PROBE_ADDR: &str = "[2001:4860:4860::8888]:53";
+
+lazy_static! {
+    static ref GLOBAL_IPV6_EGRESS: Ipv6Egress = Ipv6Egress::new();
+}
+
+/// Cached detection of usable IPv6 egress on this host.
+#[derive(Debug)]
+pub struct Ipv6Egress {
+    /// Probe function returning whether IPv6 egress is usable
+    probe: fn() -> bool,
+    /// How long a probe result stays valid
+    reprobe_interval: Duration,
+    /// Last probe result and when it was taken
+    cached: Mutex<Option<(bool, Instant)>>,
+}
+
+impl Ipv6Egress {
+    /// Create a detector that probes the host's IPv6 routing table
+    pub fn new() -> Self {
+        Self::with_probe(probe_ipv6_route, IPV6_REPROBE_INTERVAL)
+    }
+
+    /// Create a detector with a custom probe and re-probe interval
+    pub fn with_probe(probe: fn() -> bool, reprobe_interval: Duration) -> Self {
+        Ipv6Egress {
+            probe,
+            reprobe_interval,
+            cached: Mutex::new(None),
+        }
+    }
+
+    /// Process-wide detector shared by all SOCKS5 handlers
+    pub fn global() -> &'static Ipv6Egress {
+        &GLOBAL_IPV6_EGRESS
+    }
+
+    /// Check whether IPv6 egress is usable, re-probing if the cached
+    /// result has expired
+    pub fn is_available(&self) -> bool {
+        let mut cached = self.cached.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((available, at)) = *cached {
+            if at.elapsed() < self.reprobe_interval {
+                return available;
+            }
+        }
+
+        let available = (self.probe)();
+        debug!("IPv6 egress probe: available={}", available);
+        *cached = Some((available, Instant::now()));
+        available
+    }
+
+    /// Keep the addresses worth dialing from a resolved address list
+    ///
+    /// When IPv6 egress is unavailable, only the IPv4 addresses are kept and
+    /// a v6-only list fails immediately with `HostUnreachable`.
+    pub fn select_addrs(&self, addrs: Vec<SocketAddr>) -> io::Result<Vec<SocketAddr>> {
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                "No addresses",
+            ));
+        }
+
+        if addrs.iter().all(SocketAddr::is_ipv4) || self.is_available() {
+            return Ok(addrs);
+        }
+
+        let v4: Vec<SocketAddr> = addrs.into_iter().filter(SocketAddr::is_ipv4).collect();
+        if v4.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::HostUnreachable,
+                "IPv6 egress unavailable on this host",
+            ));
+        }
+        Ok(v4)
+    }
+}
+
+impl Default for Ipv6Egress {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Probe whether the host has a route for global IPv6 traffic
+fn probe_ipv6_route() -> bool {
+    UdpSocket::bind("[::]:0")
+        .and_then(|socket| socket.connect(IPV6_PROBE_ADDR))
+        .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn unavailable() -> bool {
+        false
+    }
+
+    fn available() -> bool {
+        true
+    }
+
+    #[test]
+    fn test_select_addrs_v6_only_unavailable() {
+        let egress = Ipv6Egress::with_probe(unavailable, IPV6_REPROBE_INTERVAL);
+        let addrs: Vec<SocketAddr> = vec!["[2001:db8::1]:80".parse().unwrap()];
+
+        let err = egress.select_addrs(addrs).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::HostUnreachable);
+    }
+
+    #[test]
+    fn test_select_addrs_keeps_v4_when_unavailable() {
+        let egress = Ipv6Egress::with_probe(unavailable, IPV6_REPROBE_INTERVAL);
+        let addrs: Vec<SocketAddr> = vec![
+            "[2001:db8::1]:80".parse().unwrap(),
+            "192.0.2.1:80".parse().unwrap(),
+        ];
+
+        assert_eq!(
+            egress.select_addrs(addrs).unwrap(),
+            vec!["192.0.2.1:80".parse::<SocketAddr>().unwrap()]
+        );
+    }
+
+    #[test]
+    fn test_select_addrs_v6_available() {
+        let egress = Ipv6Egress::with_probe(available, IPV6_REPROBE_INTERVAL);
+        let addrs: Vec<SocketAddr> = vec![
+            "[2001:db8::1]:80".parse().unwrap(),
+            "192.0.2.1:80".parse().unwrap(),
+        ];
+
+        assert_eq!(egress.select_addrs(addrs.clone()).unwrap(), addrs);
+    }
+
+    #[test]
+    fn test_select_addrs_empty() {
+        let egress = Ipv6Egress::with_probe(available, IPV6_REPROBE_INTERVAL);
+        assert!(egress.select_addrs(Vec::new()).is_err());
+    }
+
+    static PROBE_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    fn counting_probe() -> bool {
+        PROBE_CALLS.fetch_add(1, Ordering::SeqCst);
+        false
+    }
+
+    #[test]
+    fn test_probe_result_cached_and_reprobed() {
+        let egress = Ipv6Egress::with_probe(counting_probe, IPV6_REPROBE_INTERVAL);
+        assert!(!egress.is_available());
+        assert!(!egress.is_available());
+        assert_eq!(PROBE_CALLS.load(Ordering::SeqCst), 1);
+
+        // Zero interval forces a re-probe on every check
+        let egress = Ipv6Egress::with_probe(counting_probe, Duration::ZERO);
+        egress.is_available();
+        egress.is_available();
+        assert_eq!(PROBE_CALLS.load(Ordering::SeqCst), 3);
+    }
+}
@@ -3,29 +3,61 @@
 //! This module implements the SOCKS5 protocol for handling proxy requests
 //! through the rathole tunnel. It processes SOCKS5 requests directly on
 //! the tunnel stream without binding to any local network interface.
+//!
+//! UDP support and username/password authentication are behind the
+//! `socks-udp` and `socks-auth` features; without them the service is a
+//! CONNECT-only, no-auth proxy. SOCKS4/4a clients are served on request
+//! (`allow_socks4`). `socks-doh` adds DNS-over-HTTPS/TLS resolution of
+//! domain targets.
 
 mod auth;
+mod bind;
 mod command;
+mod connect;
 mod consts;
+mod dialer;
+mod dns;
 mod handler;
+mod ipv6;
+mod policy;
+mod resolver;
+mod socks4;
 mod tcp_relay;
 mod types;
+#[cfg(feature = "socks-udp")]
 mod udp;
+mod upstream;
 
-pub use auth::{authenticate, AuthMethod};
+#[cfg(feature = "socks-auth")]
+pub use auth::Users;
+pub use auth::{authenticate, authenticate_with_users, AuthMethod, UsersFile};
 pub use command::{
-    build_reply, parse_command, send_command_not_supported, send_general_failure, send_io_error,
-    send_success,
+    build_reply, is_client_gone, parse_command, parse_command_with, send_command_not_supported,
+    send_general_failure, send_io_error, send_success,
 };
+pub use connect::{connect_happy_eyeballs, CONNECTION_ATTEMPT_DELAY};
 pub use consts::*;
-pub use handler::handle_socks5_on_stream;
+pub use dialer::{DialedStream, DirectDialer, TargetDialer, TransportDialer};
+pub use dns::DnsCache;
+pub use handler::{
+    handle_socks5_on_stream, handle_socks5_on_stream_with, handle_socks5_on_stream_with_users,
+};
+pub use ipv6::Ipv6Egress;
+pub use policy::is_private_ip;
+pub use resolver::{resolve_target, Resolver, SystemResolver};
+#[cfg(feature = "socks-doh")]
+pub use resolver::{DohResolver, DotResolver};
 pub use tcp_relay::relay_tcp;
-pub use types::{SocksCommand, TargetAddr};
-pub use udp::{handle_udp_associate, UdpRelay};
+pub use types::{SocksCommand, SocksRequest, TargetAddr};
+#[cfg(feature = "socks-udp")]
+pub use udp::{handle_udp_associate, UdpAssociationGuard, UdpAssociations, UdpRelay, UdpSendQueue};
+pub use upstream::{UpstreamDialer, UpstreamProtocol, UpstreamProxy};
 
 use crate::config::SocksConfig;
-use crate::services::{ServiceHandler, StreamDyn};
+use crate::ratelimit::Bandwidth;
+use crate::services::{ConnectionSummary, HandlerState, ServiceHandler, ServiceStats, StreamDyn};
 use anyhow::Result;
+use std::sync::Arc;
 
 /// SOCKS5 service handler implementing the [`ServiceHandler`] trait.
 ///
@@ -35,12 +67,79 @@ use anyhow::Result;
 #[derive(Debug, Clone)]
 pub struct Socks5ServiceHandler {
     config: SocksConfig,
+    users: Option<Arc<UsersFile>>,
+    /// The configured upstream proxy, parsed once
+    upstream: Option<UpstreamProxy>,
+    /// Dialer for CONNECT targets, chained through `upstream` if set
+    dialer: Arc<dyn TargetDialer>,
+    /// Limiters shared by every connection of this handler
+    bandwidth: Bandwidth,
+    /// Cache of target lookups, shared by every connection of this handler
+    dns_cache: Arc<DnsCache>,
+    stats: Arc<ServiceStats>,
 }
 
 impl Socks5ServiceHandler {
     /// Create a new SOCKS5 service handler with the given configuration.
+    ///
+    /// Runs the IPv6 egress probe up front (when enabled) so the first
+    /// request does not pay for it, and loads the users file if one is
+    /// configured. The users file is re-read when a configuration reload
+    /// builds a new handler. The upstream proxy URL is parsed here, so
+    /// connections do not parse it again, and the DNS cache is built here,
+    /// so a reload starts with an empty one.
     pub fn new(config: SocksConfig) -> Self {
-        Self { config }
+        if config.ipv6_probe {
+            Ipv6Egress::global().is_available();
+        }
+
+        #[cfg(feature = "socks-auth")]
+        let users = config
+            .users_file
+            .as_ref()
+            .and_then(|path| match UsersFile::open(path) {
+                Ok(users) => Some(Arc::new(users)),
+                Err(e) => {
+                    tracing::error!("{:#}", e);
+                    None
+                }
+            });
+        #[cfg(not(feature = "socks-auth"))]
+        let users = None;
+
+        // An invalid URL is reported by `validate`
+        let upstream = UpstreamProxy::for_config(&config).ok().flatten();
+        let dns_cache = DnsCache::from_config(&config).unwrap_or_default();
+
+        let mut handler = Self {
+            bandwidth: Bandwidth::new(config.aggregate_bytes_per_sec),
+            dns_cache: Arc::new(dns_cache),
+            config,
+            users,
+            upstream,
+            dialer: Arc::new(DirectDialer),
+            stats: Arc::default(),
+        };
+        handler.dialer = handler.chained(Arc::new(DirectDialer));
+        handler
+    }
+
+    /// Reach CONNECT targets through `dialer` instead of the host network
+    ///
+    /// With an upstream proxy configured, `dialer` is what reaches the proxy.
+    pub fn with_dialer(mut self, dialer: Arc<dyn TargetDialer>) -> Self {
+        self.dialer = self.chained(dialer);
+        self
+    }
+
+    /// `dialer`, chained through the upstream proxy if one is configured
+    fn chained(&self, dialer: Arc<dyn TargetDialer>) -> Arc<dyn TargetDialer> {
+        match &self.upstream {
+            Some(proxy) => Arc::new(
+                UpstreamDialer::new(proxy.clone(), dialer).with_dns_cache(self.dns_cache.clone()),
+            ),
+            None => dialer,
+        }
     }
 
     /// Get a reference to the SOCKS5 configuration.
@@ -55,21 +154,95 @@ impl ServiceHandler for Socks5ServiceHandler {
         "socks5"
     }
 
-    async fn handle_tcp_stream(&self, stream: Box<dyn StreamDyn>) -> Result<()> {
-        handle_socks5_on_stream(stream, &self.config).await
+    async fn handle_tcp_stream(&self, stream: Box<dyn StreamDyn>) -> Result<ConnectionSummary> {
+        self.stats
+            .track(async {
+                Ok(handle_socks5_on_stream_with(
+                    stream,
+                    &self.config,
+                    self.users.as_deref(),
+                    self.dialer.as_ref(),
+                    &self.bandwidth,
+                    &self.dns_cache,
+                )
+                .await?)
+            })
+            .await
     }
 
+    #[cfg(feature = "socks-udp")]
     async fn handle_udp_stream(&self, stream: Box<dyn StreamDyn>) -> Result<()> {
         if self.config.allow_udp {
-            let relay = UdpRelay::new();
+            let relay = UdpRelay::new()
+                .with_timeout(self.config.udp_idle_timeout().as_secs())
+                .with_max_datagram(self.config.udp_max_datagram)
+                .with_send_queue(self.config.udp_queue_depth, self.config.udp_drop_policy)
+                .with_block_private_networks(self.config.block_private_networks)
+                .with_acl(self.config.acl.clone())
+                .with_dns_cache_ttls(self.config.dns_cache_ttl, self.config.dns_negative_ttl)
+                .with_dns_cache(self.dns_cache.clone());
             relay.run(stream).await
         } else {
             anyhow::bail!("UDP not allowed by SOCKS5 configuration")
         }
     }
 
+    #[cfg(not(feature = "socks-udp"))]
+    async fn handle_udp_stream(&self, _stream: Box<dyn StreamDyn>) -> Result<()> {
+        anyhow::bail!("SOCKS5 UDP support is not enabled. Recompile with --features socks-udp")
+    }
+
     fn validate(&self) -> Result<()> {
-        self.config.validate().map_err(|e| anyhow::anyhow!(e))
+        self.config.validate().map_err(|e| anyhow::anyhow!(e))?;
+        #[cfg(not(feature = "socks-udp"))]
+        if self.config.allow_udp {
+            anyhow::bail!(
+                "allow_udp requires the socks-udp feature. Recompile with --features socks-udp"
+            );
+        }
+        #[cfg(not(feature = "socks-doh"))]
+        if self.config.dns_upstream.is_some() {
+            anyhow::bail!(
+                "dns_upstream requires the socks-doh feature. Recompile with --features socks-doh"
+            );
+        }
+        #[cfg(not(feature = "socks-auth"))]
+        if self.config.auth_required
+            || self.config.has_credentials()
+            || self.config.users_file.is_some()
+        {
+            anyhow::bail!(
+                "SOCKS5 authentication requires the socks-auth feature. Recompile with --features socks-auth"
+            );
+        }
+        if let (Some(path), None) = (&self.config.users_file, &self.users) {
+            anyhow::bail!("Failed to load SOCKS5 users file {:?}", path);
+        }
+        Ok(())
+    }
+
+    fn stats(&self) -> Option<&ServiceStats> {
+        Some(&self.stats)
+    }
+
+    fn reload_state(&self) -> HandlerState {
+        HandlerState {
+            stats: Some(self.stats.clone()),
+            bandwidth: Some((self.config.aggregate_bytes_per_sec, self.bandwidth.clone())),
+            ..Default::default()
+        }
+    }
+
+    fn carry_over(&mut self, state: HandlerState) {
+        if let Some(stats) = state.stats {
+            self.stats = stats;
+        }
+        // Limiters built for another rate are replaced, not kept
+        if let Some((rate, bandwidth)) = state.bandwidth {
+            if rate == self.config.aggregate_bytes_per_sec {
+                self.bandwidth = bandwidth;
+            }
+        }
     }
 }
 
@@ -106,6 +279,90 @@ mod tests {
         assert!(handler.validate().is_ok());
     }
 
+    #[test]
+    #[cfg(not(feature = "socks-udp"))]
+    fn test_validate_rejects_udp_without_feature() {
+        let handler = Socks5ServiceHandler::new(SocksConfig {
+            allow_udp: true,
+            ..Default::default()
+        });
+        assert!(handler
+            .validate()
+            .unwrap_err()
+            .to_string()
+            .contains("socks-udp"));
+    }
+
+    #[test]
+    #[cfg(not(feature = "socks-auth"))]
+    fn test_validate_rejects_auth_without_feature() {
+        let handler = Socks5ServiceHandler::new(SocksConfig {
+            auth_required: true,
+            username: Some("user".to_string()),
+            password: Some("pass".to_string()),
+            ..Default::default()
+        });
+        assert!(handler
+            .validate()
+            .unwrap_err()
+            .to_string()
+            .contains("socks-auth"));
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "socks-udp")]
+    async fn test_udp_data_channel_relays_dns_query() {
+        use crate::protocol::UdpTraffic;
+        use crate::services::socks::types::TargetAddr;
+        use crate::services::socks::udp::{encode_udp_packet, parse_udp_packet, UdpPacket};
+        use bytes::Bytes;
+        use std::time::Duration;
+        use tokio::io::AsyncReadExt;
+
+        // Mock DNS server answering a query with one A record
+        let dns = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let dns_addr = TargetAddr::from(dns.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            let (len, from) = dns.recv_from(&mut buf).await.unwrap();
+            let mut answer = buf[..len].to_vec();
+            answer[2] |= 0x80; // QR: response
+            answer[7] = 1; // ANCOUNT
+            answer
+                .extend_from_slice(&[0xC0, 0x0C, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 93, 184, 216, 34]);
+            dns.send_to(&answer, from).await.unwrap();
+        });
+
+        let handler = Socks5ServiceHandler::new(SocksConfig {
+            allow_udp: true,
+            ..Default::default()
+        });
+        let (mut tunnel, stream) = tokio::io::duplex(4096);
+        tokio::spawn(async move { handler.handle_udp_stream(Box::new(stream)).await });
+
+        // An A query for example.com from a visitor of the server
+        let mut query = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        query.extend_from_slice(b"\x07example\x03com\x00\x00\x01\x00\x01");
+        let source = "198.51.100.1:40000".parse().unwrap();
+        let packet = UdpPacket::new(dns_addr.clone(), Bytes::from(query));
+        UdpTraffic::new(source, Bytes::from(encode_udp_packet(&packet)))
+            .write(&mut tunnel)
+            .await
+            .unwrap();
+
+        let hdr_len = tokio::time::timeout(Duration::from_secs(2), tunnel.read_u8())
+            .await
+            .expect("the DNS answer should be relayed back")
+            .unwrap();
+        let response = UdpTraffic::read(&mut tunnel, hdr_len).await.unwrap();
+        assert_eq!(response.from, source);
+        let answer = parse_udp_packet(&response.data).unwrap();
+        assert_eq!(answer.addr, dns_addr);
+        assert_eq!(&answer.data[..2], &[0x12, 0x34]);
+        assert_ne!(answer.data[2] & 0x80, 0);
+        assert!(answer.data.ends_with(&[93, 184, 216, 34]));
+    }
+
     #[test]
     fn test_socks5_service_handler_debug() {
         let handler = Socks5ServiceHandler::new(SocksConfig::default());
@@ -119,4 +376,63 @@ mod tests {
         let cloned = handler.clone();
         assert_eq!(cloned.service_type(), "socks5");
     }
+
+    #[tokio::test]
+    async fn test_socks5_service_handler_stats_count_relayed_bytes() {
+        use crate::services::ServiceStatsSnapshot;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = match listener.local_addr().unwrap() {
+            std::net::SocketAddr::V4(addr) => addr,
+            std::net::SocketAddr::V6(_) => unreachable!(),
+        };
+        tokio::spawn(async move {
+            let (mut sock, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 4];
+            sock.read_exact(&mut buf).await.unwrap();
+            sock.write_all(b"pong!!").await.unwrap();
+        });
+
+        let handler = Arc::new(Socks5ServiceHandler::new(SocksConfig::default()));
+        let (mut client, server) = tokio::io::duplex(1024);
+        let task = {
+            let handler = handler.clone();
+            tokio::spawn(async move { handler.handle_tcp_stream(Box::new(server)).await })
+        };
+
+        let mut request = vec![SOCKS5_VERSION, 1, SOCKS5_AUTH_METHOD_NONE];
+        request.extend_from_slice(&[SOCKS5_VERSION, SOCKS5_CMD_TCP_CONNECT, 0]);
+        request.push(SOCKS5_ADDR_TYPE_IPV4);
+        request.extend_from_slice(&target.ip().octets());
+        request.extend_from_slice(&target.port().to_be_bytes());
+        client.write_all(&request).await.unwrap();
+        let mut reply = [0u8; 12];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[3], SOCKS5_REPLY_SUCCEEDED);
+        assert_eq!(handler.stats().unwrap().snapshot().active_connections, 1);
+
+        client.write_all(b"ping").await.unwrap();
+        let mut pong = [0u8; 6];
+        client.read_exact(&mut pong).await.unwrap();
+        tokio::time::timeout(std::time::Duration::from_secs(2), task)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+
+        let snapshot = handler.stats().unwrap().snapshot();
+        assert_eq!(
+            snapshot,
+            ServiceStatsSnapshot {
+                active_connections: 0,
+                total_connections: 1,
+                bytes_up: 4,
+                bytes_down: 6,
+                errors: 0,
+                ..snapshot
+            }
+        );
+        assert_eq!(snapshot.duration_ms.count, 1);
+    }
 }
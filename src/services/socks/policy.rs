@@ -0,0 +1,90 @@
+//! Destination policy for outbound SOCKS5 traffic
+//!
+//! With `block_private_networks` enabled, targets that resolve into
+//! internal address space are refused so tunnel clients cannot use the
+//! proxy to reach the host's own network. The `acl` rules are checked on
+//! the requested target, and again on the resolved address of domains.
+
+use crate::config::AclConfig;
+use crate::services::socks::types::TargetAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// Whether `acl` lets a client reach `target`, resolved to `resolved` when
+/// it is a domain whose address is known
+pub fn target_permitted(acl: &AclConfig, target: &TargetAddr, resolved: Option<IpAddr>) -> bool {
+    match target {
+        TargetAddr::Ip(addr) => acl.permits(None, Some(addr.ip()), addr.port()),
+        TargetAddr::Domain(domain, port) => acl.permits(Some(domain), resolved, *port),
+    }
+}
+
+/// Whether `ip` is loopback, unspecified, RFC 1918 private, link-local or
+/// IPv6 unique-local. IPv4-mapped IPv6 addresses are judged by their IPv4
+/// address.
+pub fn is_private_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => is_private_v4(v4),
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => is_private_v4(v4),
+            None => is_private_v6(v6),
+        },
+    }
+}
+
+fn is_private_v4(ip: Ipv4Addr) -> bool {
+    ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified()
+}
+
+fn is_private_v6(ip: Ipv6Addr) -> bool {
+    let segment = ip.segments()[0];
+    ip.is_loopback()
+        || ip.is_unspecified()
+        // fc00::/7 unique local
+        || (segment & 0xfe00) == 0xfc00
+        // fe80::/10 link local
+        || (segment & 0xffc0) == 0xfe80
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn private(ip: &str) -> bool {
+        is_private_ip(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn test_private_ranges() {
+        for ip in [
+            "10.0.0.1",
+            "10.255.255.255",
+            "172.16.0.1",
+            "172.31.255.254",
+            "192.168.1.1",
+            "127.0.0.1",
+            "169.254.169.254",
+            "0.0.0.0",
+            "::1",
+            "::",
+            "fc00::1",
+            "fd12:3456::1",
+            "fe80::1",
+            "::ffff:10.0.0.1",
+        ] {
+            assert!(private(ip), "{} should be private", ip);
+        }
+    }
+
+    #[test]
+    fn test_public_addresses() {
+        for ip in [
+            "8.8.8.8",
+            "172.32.0.1",
+            "192.0.2.1",
+            "2001:4860:4860::8888",
+            "::ffff:8.8.8.8",
+        ] {
+            assert!(!private(ip), "{} should be public", ip);
+        }
+    }
+}
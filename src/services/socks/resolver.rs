@@ -0,0 +1,628 @@
+//! Name resolution backends for SOCKS5 domain targets
+//!
+//! Lookups go through a [`Resolver`]: the [`SystemResolver`] by default, or,
+//! with the `socks-doh` feature and
+//! [`dns_upstream`](crate::config::SocksConfig::dns_upstream) set, a
+//! [`DohResolver`] (`https://` URLs, RFC 8484) or [`DotResolver`]
+//! (`tls://` URLs, RFC 7858) so names never reach the host's resolver in
+//! clear text. The upstream's own host name, if it is not an IP address, is
+//! looked up with the system resolver.
+
+use crate::config::SocksConfig;
+use crate::services::socks::dns::DnsCache;
+use crate::services::socks::types::TargetAddr;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use std::fmt::Debug;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A way of looking up the addresses of a domain
+#[async_trait]
+pub trait Resolver: Send + Sync + Debug {
+    /// Resolve `domain`, returning its addresses with `port` filled in
+    async fn lookup(&self, domain: &str, port: u16) -> io::Result<Vec<SocketAddr>>;
+
+    /// [`lookup`](Resolver::lookup), also returning the shortest record TTL
+    /// when the backend knows it
+    async fn lookup_with_ttl(
+        &self,
+        domain: &str,
+        port: u16,
+    ) -> io::Result<(Vec<SocketAddr>, Option<Duration>)> {
+        Ok((self.lookup(domain, port).await?, None))
+    }
+}
+
+/// The host's resolver, as used by `getaddrinfo`
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemResolver;
+
+#[async_trait]
+impl Resolver for SystemResolver {
+    async fn lookup(&self, domain: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        Ok(tokio::net::lookup_host((domain, port)).await?.collect())
+    }
+}
+
+/// Build the resolver named by a `dns_upstream` URL
+#[cfg(feature = "socks-doh")]
+pub fn resolver_for_upstream(upstream: &str) -> Result<Arc<dyn Resolver>, String> {
+    let url = url::Url::parse(upstream)
+        .map_err(|e| format!("invalid dns_upstream {:?}: {}", upstream, e))?;
+    match url.scheme() {
+        "https" => Ok(Arc::new(DohResolver::new(&url)?)),
+        "tls" => Ok(Arc::new(DotResolver::new(&url)?)),
+        scheme => Err(format!(
+            "dns_upstream must be an https:// (DoH) or tls:// (DoT) URL, got {}://",
+            scheme
+        )),
+    }
+}
+
+/// Build the resolver named by a `dns_upstream` URL
+#[cfg(not(feature = "socks-doh"))]
+pub fn resolver_for_upstream(_upstream: &str) -> Result<Arc<dyn Resolver>, String> {
+    Err("dns_upstream requires the socks-doh feature. Recompile with --features socks-doh".into())
+}
+
+/// Resolve `target` through `cache` and its resolver, giving up after
+/// `request_timeout`
+pub async fn resolve_target(
+    target: &TargetAddr,
+    cache: &DnsCache,
+    config: &SocksConfig,
+) -> Result<Vec<SocketAddr>> {
+    if let TargetAddr::Ip(addr) = target {
+        return Ok(vec![*addr]);
+    }
+    let timeout = Duration::from_secs(config.request_timeout);
+    let lookup = target.resolve_all_cached(
+        cache,
+        Duration::from_secs(config.dns_cache_ttl),
+        Duration::from_secs(config.dns_negative_ttl),
+    );
+    match tokio::time::timeout(timeout, lookup).await {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("DNS lookup timed out after {:?}", timeout),
+        ))
+        .with_context(|| format!("Failed to resolve address: {}", target)),
+    }
+}
+
+#[cfg(feature = "socks-doh")]
+pub use upstream::{DohResolver, DotResolver};
+
+#[cfg(feature = "socks-doh")]
+mod upstream {
+    use super::Resolver;
+    use async_trait::async_trait;
+    use std::io;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio_rustls::rustls::pki_types::ServerName;
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+    use tokio_rustls::TlsConnector;
+    use url::Url;
+
+    /// Largest DNS response accepted from an upstream
+    const MAX_RESPONSE_LEN: usize = 65535;
+
+    /// DNS record types queried for each name
+    const TYPE_A: u16 = 1;
+    const TYPE_AAAA: u16 = 28;
+
+    /// Addresses from a response, with the shortest TTL among them
+    #[derive(Debug, Default, PartialEq)]
+    pub(super) struct Answers {
+        addrs: Vec<IpAddr>,
+        ttl: Option<u32>,
+    }
+
+    /// DNS-over-HTTPS resolver, POSTing wire-format queries to a URL such as
+    /// `https://1.1.1.1/dns-query`
+    ///
+    /// Each lookup opens its own connection; the DNS cache in front of the
+    /// resolver keeps that from happening per request.
+    #[derive(Clone)]
+    pub struct DohResolver {
+        host: String,
+        port: u16,
+        path: String,
+        tls: TlsConnector,
+    }
+
+    impl DohResolver {
+        /// Create a resolver for an `https://` URL
+        pub fn new(url: &Url) -> Result<Self, String> {
+            let (host, port) = host_and_port(url, 443)?;
+            let mut path = url.path().to_string();
+            if let Some(query) = url.query() {
+                path = format!("{}?{}", path, query);
+            }
+            Ok(DohResolver {
+                host,
+                port,
+                path,
+                tls: tls_connector(),
+            })
+        }
+
+        async fn query(&self, name: &str, qtype: u16) -> io::Result<Answers> {
+            let mut stream = tls_connect(&self.tls, &self.host, self.port).await?;
+            let query = build_query(name, qtype)?;
+            let response = doh_exchange(&mut stream, &self.host, &self.path, &query).await?;
+            parse_response(&response, qtype)
+        }
+    }
+
+    impl std::fmt::Debug for DohResolver {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(
+                f,
+                "DohResolver(https://{}:{}{})",
+                self.host, self.port, self.path
+            )
+        }
+    }
+
+    #[async_trait]
+    impl Resolver for DohResolver {
+        async fn lookup(&self, domain: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+            Ok(self.lookup_with_ttl(domain, port).await?.0)
+        }
+
+        async fn lookup_with_ttl(
+            &self,
+            domain: &str,
+            port: u16,
+        ) -> io::Result<(Vec<SocketAddr>, Option<Duration>)> {
+            let (v4, v6) = tokio::join!(self.query(domain, TYPE_A), self.query(domain, TYPE_AAAA));
+            merge_answers(v4, v6, port)
+        }
+    }
+
+    /// DNS-over-TLS resolver for a URL such as `tls://1.1.1.1:853`
+    #[derive(Clone)]
+    pub struct DotResolver {
+        host: String,
+        port: u16,
+        tls: TlsConnector,
+    }
+
+    impl DotResolver {
+        /// Create a resolver for a `tls://` URL (port 853 unless given)
+        pub fn new(url: &Url) -> Result<Self, String> {
+            let (host, port) = host_and_port(url, 853)?;
+            Ok(DotResolver {
+                host,
+                port,
+                tls: tls_connector(),
+            })
+        }
+
+        async fn query(&self, name: &str, qtype: u16) -> io::Result<Answers> {
+            let mut stream = tls_connect(&self.tls, &self.host, self.port).await?;
+            let query = build_query(name, qtype)?;
+            let response = dot_exchange(&mut stream, &query).await?;
+            parse_response(&response, qtype)
+        }
+    }
+
+    impl std::fmt::Debug for DotResolver {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "DotResolver(tls://{}:{})", self.host, self.port)
+        }
+    }
+
+    #[async_trait]
+    impl Resolver for DotResolver {
+        async fn lookup(&self, domain: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+            Ok(self.lookup_with_ttl(domain, port).await?.0)
+        }
+
+        async fn lookup_with_ttl(
+            &self,
+            domain: &str,
+            port: u16,
+        ) -> io::Result<(Vec<SocketAddr>, Option<Duration>)> {
+            let (v4, v6) = tokio::join!(self.query(domain, TYPE_A), self.query(domain, TYPE_AAAA));
+            merge_answers(v4, v6, port)
+        }
+    }
+
+    fn host_and_port(url: &Url, default_port: u16) -> Result<(String, u16), String> {
+        let host = match url.host() {
+            Some(url::Host::Domain(domain)) => domain.to_string(),
+            Some(url::Host::Ipv4(ip)) => ip.to_string(),
+            Some(url::Host::Ipv6(ip)) => ip.to_string(),
+            None => return Err(format!("dns_upstream {:?} has no host", url.as_str())),
+        };
+        Ok((host, url.port().unwrap_or(default_port)))
+    }
+
+    fn tls_connector() -> TlsConnector {
+        let roots = RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        let provider = Arc::new(tokio_rustls::rustls::crypto::ring::default_provider());
+        let config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .expect("ring supports the default TLS versions")
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        TlsConnector::from(Arc::new(config))
+    }
+
+    async fn tls_connect(
+        tls: &TlsConnector,
+        host: &str,
+        port: u16,
+    ) -> io::Result<tokio_rustls::client::TlsStream<TcpStream>> {
+        let server_name = ServerName::try_from(host.to_string())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let tcp = TcpStream::connect((host, port)).await?;
+        tls.connect(server_name, tcp).await
+    }
+
+    /// Combine the A and AAAA answers, failing only if neither lookup
+    /// produced an address
+    fn merge_answers(
+        v4: io::Result<Answers>,
+        v6: io::Result<Answers>,
+        port: u16,
+    ) -> io::Result<(Vec<SocketAddr>, Option<Duration>)> {
+        let (v4, v6) = match (v4, v6) {
+            (Err(e), Err(_)) => return Err(e),
+            (v4, v6) => (v4.unwrap_or_default(), v6.unwrap_or_default()),
+        };
+        let ttl = v4.ttl.into_iter().chain(v6.ttl).min();
+        let addrs = v4
+            .addrs
+            .into_iter()
+            .chain(v6.addrs)
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect();
+        Ok((addrs, ttl.map(|ttl| Duration::from_secs(ttl.into()))))
+    }
+
+    /// Send a query as an HTTP/1.1 POST and return the response body
+    pub(super) async fn doh_exchange<S>(
+        stream: &mut S,
+        host: &str,
+        path: &str,
+        query: &[u8],
+    ) -> io::Result<Vec<u8>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let head = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/dns-message\r\n\
+             Accept: application/dns-message\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            path,
+            host,
+            query.len()
+        );
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(query).await?;
+        stream.flush().await?;
+
+        let mut response = Vec::new();
+        stream
+            .take(MAX_RESPONSE_LEN as u64 + 4096)
+            .read_to_end(&mut response)
+            .await?;
+
+        let header_end = response
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .ok_or_else(|| invalid("truncated DoH response"))?;
+        let head = String::from_utf8_lossy(&response[..header_end]).to_ascii_lowercase();
+        let body = &response[header_end + 4..];
+
+        let status = head.split_whitespace().nth(1).unwrap_or_default();
+        if status != "200" {
+            return Err(io::Error::other(format!(
+                "DoH upstream returned HTTP {}",
+                status
+            )));
+        }
+        if head.contains("transfer-encoding: chunked") {
+            return dechunk(body);
+        }
+        Ok(body.to_vec())
+    }
+
+    /// Decode a chunked HTTP body
+    fn dechunk(mut body: &[u8]) -> io::Result<Vec<u8>> {
+        let mut decoded = Vec::new();
+        loop {
+            let line_end = body
+                .windows(2)
+                .position(|w| w == b"\r\n")
+                .ok_or_else(|| invalid("truncated chunk"))?;
+            let size = std::str::from_utf8(&body[..line_end])
+                .ok()
+                .and_then(|line| usize::from_str_radix(line.split(';').next()?.trim(), 16).ok())
+                .ok_or_else(|| invalid("bad chunk size"))?;
+            body = &body[line_end + 2..];
+            if size == 0 {
+                return Ok(decoded);
+            }
+            let chunk = body.get(..size).ok_or_else(|| invalid("truncated chunk"))?;
+            decoded.extend_from_slice(chunk);
+            body = body.get(size + 2..).unwrap_or_default();
+        }
+    }
+
+    /// Send a length-prefixed query over a DoT stream and read the answer
+    pub(super) async fn dot_exchange<S>(stream: &mut S, query: &[u8]) -> io::Result<Vec<u8>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut message = (query.len() as u16).to_be_bytes().to_vec();
+        message.extend_from_slice(query);
+        stream.write_all(&message).await?;
+        stream.flush().await?;
+
+        let len = stream.read_u16().await? as usize;
+        let mut response = vec![0u8; len];
+        stream.read_exact(&mut response).await?;
+        Ok(response)
+    }
+
+    /// Build a recursive query for `name`
+    ///
+    /// The ID is left at zero, as RFC 8484 recommends for cache friendliness;
+    /// each query has a connection of its own, so there is nothing to match.
+    pub(super) fn build_query(name: &str, qtype: u16) -> io::Result<Vec<u8>> {
+        let mut query = vec![0, 0, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        let name = name.trim_end_matches('.');
+        if name.len() > 253 {
+            return Err(invalid("domain name too long"));
+        }
+        for label in name.split('.') {
+            if label.is_empty() || label.len() > 63 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid domain name: {}", name),
+                ));
+            }
+            query.push(label.len() as u8);
+            query.extend_from_slice(label.as_bytes());
+        }
+        query.push(0);
+        query.extend_from_slice(&qtype.to_be_bytes());
+        query.extend_from_slice(&1u16.to_be_bytes());
+        Ok(query)
+    }
+
+    /// Extract the `qtype` addresses from a response
+    pub(super) fn parse_response(response: &[u8], qtype: u16) -> io::Result<Answers> {
+        let header = response
+            .get(..12)
+            .ok_or_else(|| invalid("truncated DNS response"))?;
+        if header[2] & 0x80 == 0 {
+            return Err(invalid("DNS message is not a response"));
+        }
+        match header[3] & 0x0f {
+            0 => {}
+            3 => return Err(io::Error::new(io::ErrorKind::NotFound, "NXDOMAIN")),
+            rcode => {
+                return Err(io::Error::other(format!(
+                    "DNS upstream returned rcode {}",
+                    rcode
+                )))
+            }
+        }
+        let questions = u16::from_be_bytes([header[4], header[5]]);
+        let answers = u16::from_be_bytes([header[6], header[7]]);
+
+        let mut pos = 12;
+        for _ in 0..questions {
+            pos = skip_name(response, pos)? + 4;
+        }
+
+        let mut found = Answers::default();
+        for _ in 0..answers {
+            pos = skip_name(response, pos)?;
+            let record = response
+                .get(pos..pos + 10)
+                .ok_or_else(|| invalid("truncated DNS record"))?;
+            let rtype = u16::from_be_bytes([record[0], record[1]]);
+            let ttl = u32::from_be_bytes([record[4], record[5], record[6], record[7]]);
+            let rdlength = u16::from_be_bytes([record[8], record[9]]) as usize;
+            pos += 10;
+            let rdata = response
+                .get(pos..pos + rdlength)
+                .ok_or_else(|| invalid("truncated DNS record"))?;
+            pos += rdlength;
+            let ip = match (rtype, rdata.len()) {
+                (TYPE_A, 4) if qtype == TYPE_A => {
+                    IpAddr::V4(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]))
+                }
+                (TYPE_AAAA, 16) if qtype == TYPE_AAAA => {
+                    let octets: [u8; 16] = rdata.try_into().expect("length checked");
+                    IpAddr::V6(Ipv6Addr::from(octets))
+                }
+                // CNAMEs and other records: the upstream has already
+                // followed the chain, so only the addresses matter
+                _ => continue,
+            };
+            found.addrs.push(ip);
+            found.ttl = Some(found.ttl.map_or(ttl, |shortest| shortest.min(ttl)));
+        }
+        Ok(found)
+    }
+
+    /// Skip an encoded name, returning the position after it
+    fn skip_name(message: &[u8], mut pos: usize) -> io::Result<usize> {
+        loop {
+            let len = *message
+                .get(pos)
+                .ok_or_else(|| invalid("truncated DNS name"))?;
+            match len {
+                0 => return Ok(pos + 1),
+                // Compression pointer: the name ends here
+                len if len & 0xc0 == 0xc0 => return Ok(pos + 2),
+                len => pos += 1 + len as usize,
+            }
+        }
+    }
+
+    fn invalid(message: &str) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use tokio::io::duplex;
+
+        /// A response to `build_query("example.com", TYPE_A)` with a CNAME
+        /// and an A record
+        fn a_response() -> Vec<u8> {
+            let mut response = build_query("example.com", TYPE_A).unwrap();
+            response[2] = 0x81;
+            response[3] = 0x80;
+            response[7] = 2;
+            // CNAME pointing back at the question name
+            response.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 2, 0xc0, 12]);
+            response
+                .extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 93, 184, 215, 14]);
+            response
+        }
+
+        #[test]
+        fn test_build_query() {
+            let query = build_query("example.com.", TYPE_AAAA).unwrap();
+            assert_eq!(&query[..12], &[0, 0, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+            assert_eq!(&query[12..25], b"\x07example\x03com\x00");
+            assert_eq!(&query[25..], &[0, 28, 0, 1]);
+
+            assert!(build_query("bad..name", TYPE_A).is_err());
+            assert!(build_query(&"a".repeat(64), TYPE_A).is_err());
+        }
+
+        #[test]
+        fn test_parse_response() {
+            let answers = parse_response(&a_response(), TYPE_A).unwrap();
+            assert_eq!(
+                answers.addrs,
+                vec!["93.184.215.14".parse::<IpAddr>().unwrap()]
+            );
+            assert_eq!(answers.ttl, Some(60));
+            assert_eq!(
+                parse_response(&a_response(), TYPE_AAAA).unwrap(),
+                Answers::default()
+            );
+
+            let mut nxdomain = a_response();
+            nxdomain[3] = 0x83;
+            let err = parse_response(&nxdomain, TYPE_A).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+            let truncated = &a_response()[..40];
+            assert!(parse_response(truncated, TYPE_A).is_err());
+        }
+
+        #[tokio::test]
+        async fn test_doh_exchange() {
+            let (mut client, mut server) = duplex(4096);
+            let upstream = tokio::spawn(async move {
+                let mut request = vec![0u8; 4096];
+                let n = server.read(&mut request).await.unwrap();
+                let request = String::from_utf8_lossy(&request[..n]).to_string();
+                let body = a_response();
+                let mut reply = b"HTTP/1.1 200 OK\r\nContent-Type: application/dns-message\r\n\
+                    Transfer-Encoding: chunked\r\n\r\n"
+                    .to_vec();
+                reply.extend_from_slice(format!("{:x}\r\n", body.len()).as_bytes());
+                reply.extend_from_slice(&body);
+                reply.extend_from_slice(b"\r\n0\r\n\r\n");
+                server.write_all(&reply).await.unwrap();
+                request
+            });
+
+            let query = build_query("example.com", TYPE_A).unwrap();
+            let body = doh_exchange(&mut client, "dns.example", "/dns-query", &query)
+                .await
+                .unwrap();
+            assert_eq!(body, a_response());
+
+            let request = upstream.await.unwrap();
+            assert!(request.starts_with("POST /dns-query HTTP/1.1\r\nHost: dns.example\r\n"));
+            assert!(request.contains("Content-Type: application/dns-message"));
+        }
+
+        #[tokio::test]
+        async fn test_dot_exchange() {
+            let (mut client, mut server) = duplex(4096);
+            tokio::spawn(async move {
+                let len = server.read_u16().await.unwrap() as usize;
+                let mut query = vec![0u8; len];
+                server.read_exact(&mut query).await.unwrap();
+                let body = a_response();
+                server.write_u16(body.len() as u16).await.unwrap();
+                server.write_all(&body).await.unwrap();
+            });
+
+            let query = build_query("example.com", TYPE_A).unwrap();
+            let response = dot_exchange(&mut client, &query).await.unwrap();
+            assert_eq!(parse_response(&response, TYPE_A).unwrap().addrs.len(), 1);
+        }
+
+        #[test]
+        fn test_resolver_for_upstream() {
+            use super::super::resolver_for_upstream;
+
+            let doh = resolver_for_upstream("https://1.1.1.1/dns-query").unwrap();
+            assert_eq!(
+                format!("{:?}", doh),
+                "DohResolver(https://1.1.1.1:443/dns-query)"
+            );
+            let dot = resolver_for_upstream("tls://dns.quad9.net").unwrap();
+            assert_eq!(format!("{:?}", dot), "DotResolver(tls://dns.quad9.net:853)");
+            assert!(resolver_for_upstream("udp://1.1.1.1").is_err());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::BoxFuture;
+
+    fn stalled(_host: String) -> BoxFuture<'static, io::Result<Vec<SocketAddr>>> {
+        Box::pin(futures::future::pending())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_target_times_out() {
+        let config = SocksConfig {
+            request_timeout: 1,
+            ..Default::default()
+        };
+        let cache = DnsCache::with_resolver(stalled);
+        let target = TargetAddr::domain("slow.example".to_string(), 80);
+
+        let err = resolve_target(&target, &cache, &config).await.unwrap_err();
+        let err = err.downcast_ref::<io::Error>().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_target_ip_needs_no_lookup() {
+        let target = TargetAddr::Ip("192.0.2.1:443".parse().unwrap());
+        let cache = DnsCache::with_resolver(stalled);
+        let addrs = resolve_target(&target, &cache, &SocksConfig::default())
+            .await
+            .unwrap();
+        assert_eq!(addrs, vec!["192.0.2.1:443".parse().unwrap()]);
+    }
+}
@@ -0,0 +1,379 @@
+//! SOCKS4/SOCKS4a fallback
+//!
+//! Legacy clients that send version 4 are served here when
+//! [`SocksConfig::allow_socks4`] is set. Only CONNECT is supported. SOCKS4a
+//! requests (destination IP `0.0.0.x`) carry a domain name that is resolved
+//! on this side, or by the upstream proxy when one is configured. SOCKS4 has no authentication, so requests are refused when
+//! `auth_required` is set.
+
+use crate::audit::{self, AuditEvent};
+use crate::config::SocksConfig;
+use crate::ratelimit::Bandwidth;
+use crate::services::socks::auth::AuthMethod;
+use crate::services::socks::connect::connect_happy_eyeballs;
+use crate::services::socks::consts::*;
+use crate::services::socks::dialer::TargetDialer;
+use crate::services::socks::dns::DnsCache;
+use crate::services::socks::ipv6::Ipv6Egress;
+use crate::services::socks::policy::{is_private_ip, target_permitted};
+use crate::services::socks::resolver::resolve_target;
+use crate::services::socks::tcp_relay::{dial_with_retries, relay_tcp_configured};
+use crate::services::socks::types::{SocksCommand, SocksRequest, TargetAddr};
+use crate::services::{CloseReason, ConnectionSummary};
+use anyhow::{bail, Context, Result};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::{error, info, warn};
+
+/// Longest user ID or SOCKS4a domain accepted, excluding the terminator
+const MAX_FIELD_LEN: usize = 255;
+
+/// Handle a SOCKS4/4a request, once the version byte has been seen
+pub async fn handle_socks4_on_stream<S>(
+    mut stream: S,
+    config: &SocksConfig,
+    dialer: &dyn TargetDialer,
+    shared_bandwidth: &Bandwidth,
+    dns_cache: &DnsCache,
+) -> Result<ConnectionSummary>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let start = Instant::now();
+
+    let (command, target) = tokio::time::timeout(
+        Duration::from_secs(config.handshake_timeout),
+        parse_request(&mut stream),
+    )
+    .await
+    .context("SOCKS4 handshake timed out")??;
+
+    if config.auth_required {
+        warn!("Rejected SOCKS4 client: authentication required");
+        send_reply(&mut stream, SOCKS4_REPLY_REJECTED).await?;
+        return Ok(ConnectionSummary::new(CloseReason::AuthRequired)
+            .with_target(target.to_string())
+            .with_duration(start.elapsed()));
+    }
+    if command != SOCKS4_CMD_CONNECT {
+        warn!("SOCKS4 command {} not supported", command);
+        send_reply(&mut stream, SOCKS4_REPLY_REJECTED).await?;
+        return Ok(ConnectionSummary::new(CloseReason::Rejected)
+            .with_target(target.to_string())
+            .with_duration(start.elapsed()));
+    }
+    if !target_permitted(&config.acl, &target, None) {
+        warn!("SOCKS4 CONNECT to {} denied by ACL", target);
+        send_reply(&mut stream, SOCKS4_REPLY_REJECTED).await?;
+        return Ok(ConnectionSummary::new(CloseReason::Rejected)
+            .with_target(target.to_string())
+            .with_duration(start.elapsed()));
+    }
+
+    info!("SOCKS4 CONNECT request to {}", target);
+    audit::record(AuditEvent::Command {
+        protocol: "socks4".to_string(),
+        command: SocksCommand::Connect.to_string(),
+        target: Some(target.to_string()),
+    });
+
+    let request = SocksRequest {
+        command: SocksCommand::Connect,
+        target: target.clone(),
+        auth_method: AuthMethod::None,
+    };
+    let summary = connect(stream, &target, config, dialer, shared_bandwidth, dns_cache).await?;
+    Ok(summary
+        .with_target(target.to_string())
+        .with_duration(start.elapsed())
+        .with_socks_request(request))
+}
+
+/// Parse the rest of a SOCKS4/4a request after the version byte
+///
+/// # Request Format
+///
+/// ```text
+/// +----+----------+--------+--------+------+
+/// | CD | DST.PORT | DST.IP | USERID | NULL |
+/// +----+----------+--------+--------+------+
+/// | 1  |    2     |   4    |  var   |  1   |
+/// +----+----------+--------+--------+------+
+/// ```
+///
+/// SOCKS4a requests follow this with a NUL-terminated domain name.
+/// Returns the command byte and the target.
+async fn parse_request<S>(stream: &mut S) -> Result<(u8, TargetAddr)>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut header = [0u8; 7];
+    stream
+        .read_exact(&mut header)
+        .await
+        .context("Failed to read SOCKS4 request")?;
+
+    let command = header[0];
+    let port = u16::from_be_bytes([header[1], header[2]]);
+    let ip = Ipv4Addr::new(header[3], header[4], header[5], header[6]);
+
+    // The user ID is not used for anything, but must be consumed
+    read_null_terminated(stream)
+        .await
+        .context("Invalid SOCKS4 user ID")?;
+
+    // SOCKS4a: 0.0.0.x with x != 0 means a domain name follows
+    let octets = ip.octets();
+    let target = if octets[..3] == [0, 0, 0] && octets[3] != 0 {
+        let domain = read_null_terminated(stream)
+            .await
+            .context("Invalid SOCKS4a domain")?;
+        let domain = String::from_utf8(domain).context("SOCKS4a domain is not UTF-8")?;
+        if domain.is_empty() {
+            bail!("Empty SOCKS4a domain");
+        }
+        TargetAddr::Domain(domain, port)
+    } else {
+        TargetAddr::ipv4(ip, port)
+    };
+
+    Ok((command, target))
+}
+
+/// Read a NUL-terminated field of at most [`MAX_FIELD_LEN`] bytes
+async fn read_null_terminated<S>(stream: &mut S) -> Result<Vec<u8>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut field = Vec::new();
+    loop {
+        match stream.read_u8().await? {
+            0 => return Ok(field),
+            _ if field.len() == MAX_FIELD_LEN => {
+                bail!("field longer than {} bytes", MAX_FIELD_LEN)
+            }
+            byte => field.push(byte),
+        }
+    }
+}
+
+/// Resolve, check and dial `target`, then reply and relay
+async fn connect<S>(
+    mut stream: S,
+    target: &TargetAddr,
+    config: &SocksConfig,
+    dialer: &dyn TargetDialer,
+    shared_bandwidth: &Bandwidth,
+    dns_cache: &DnsCache,
+) -> Result<ConnectionSummary>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    if matches!(target, TargetAddr::Domain(..)) && dialer.resolves_remotely() {
+        let dialed = match dial_with_retries(dialer, target, config).await {
+            Ok(dialed) => dialed,
+            Err(e) => {
+                error!("Failed to connect to {}: {}", target, e);
+                send_reply(&mut stream, SOCKS4_REPLY_REJECTED).await?;
+                return Err(e.into());
+            }
+        };
+        send_reply(&mut stream, SOCKS4_REPLY_GRANTED).await?;
+
+        info!("SOCKS4 tunnel established to {}", target);
+        return relay_tcp_configured(stream, dialed.stream, config, shared_bandwidth).await;
+    }
+
+    let addrs = match resolve_target(target, dns_cache, config).await {
+        Ok(addrs) => addrs,
+        Err(e) => {
+            send_reply(&mut stream, SOCKS4_REPLY_REJECTED).await?;
+            return Err(e);
+        }
+    };
+
+    let addrs: Vec<SocketAddr> = if config.block_private_networks {
+        addrs
+            .into_iter()
+            .filter(|addr| !is_private_ip(addr.ip()))
+            .collect()
+    } else {
+        addrs
+    };
+    if addrs.is_empty() {
+        warn!("Refusing SOCKS4 CONNECT to private address {}", target);
+        send_reply(&mut stream, SOCKS4_REPLY_REJECTED).await?;
+        return Ok(ConnectionSummary::new(CloseReason::Rejected));
+    }
+    let addrs: Vec<SocketAddr> = addrs
+        .into_iter()
+        .filter(|addr| target_permitted(&config.acl, target, Some(addr.ip())))
+        .collect();
+    if addrs.is_empty() {
+        warn!("SOCKS4 CONNECT to {} denied by ACL", target);
+        send_reply(&mut stream, SOCKS4_REPLY_REJECTED).await?;
+        return Ok(ConnectionSummary::new(CloseReason::Rejected));
+    }
+
+    let addrs = if config.ipv6_probe && dialer.uses_host_network() {
+        match Ipv6Egress::global().select_addrs(addrs) {
+            Ok(addrs) => addrs,
+            Err(e) => {
+                error!("Cannot reach {}: {}", target, e);
+                send_reply(&mut stream, SOCKS4_REPLY_REJECTED).await?;
+                return Err(e.into());
+            }
+        }
+    } else {
+        addrs
+    };
+
+    let (addr, dialed) = match connect_happy_eyeballs(dialer, &addrs, config).await {
+        Ok(connected) => connected,
+        Err(e) => {
+            error!("Failed to connect to {}: {}", target, e);
+            send_reply(&mut stream, SOCKS4_REPLY_REJECTED).await?;
+            return Err(e.into());
+        }
+    };
+    send_reply(&mut stream, SOCKS4_REPLY_GRANTED).await?;
+
+    info!("SOCKS4 tunnel established to {}", addr);
+    relay_tcp_configured(stream, dialed.stream, config, shared_bandwidth).await
+}
+
+/// Send the 8-byte SOCKS4 reply; the port and address fields are unused
+async fn send_reply<S>(stream: &mut S, code: u8) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    stream
+        .write_all(&[SOCKS4_REPLY_VERSION, code, 0, 0, 0, 0, 0, 0])
+        .await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::socks::dialer::DirectDialer;
+    use tokio::io::duplex;
+    use tokio::net::TcpListener;
+
+    fn socks4_request(command: u8, port: u16, ip: [u8; 4], user: &[u8]) -> Vec<u8> {
+        let mut request = vec![command];
+        request.extend_from_slice(&port.to_be_bytes());
+        request.extend_from_slice(&ip);
+        request.extend_from_slice(user);
+        request.push(0);
+        request
+    }
+
+    #[tokio::test]
+    async fn test_parse_socks4_request() {
+        let request = socks4_request(SOCKS4_CMD_CONNECT, 80, [10, 0, 0, 1], b"alice");
+        let (command, target) = parse_request(&mut request.as_slice()).await.unwrap();
+        assert_eq!(command, SOCKS4_CMD_CONNECT);
+        assert_eq!(target, TargetAddr::ipv4(Ipv4Addr::new(10, 0, 0, 1), 80));
+    }
+
+    #[tokio::test]
+    async fn test_parse_socks4a_request() {
+        let mut request = socks4_request(SOCKS4_CMD_CONNECT, 443, [0, 0, 0, 1], b"");
+        request.extend_from_slice(b"example.com\0");
+        let (_, target) = parse_request(&mut request.as_slice()).await.unwrap();
+        assert_eq!(target, TargetAddr::Domain("example.com".to_string(), 443));
+    }
+
+    #[tokio::test]
+    async fn test_parse_rejects_unterminated_user_id() {
+        let mut request = vec![SOCKS4_CMD_CONNECT, 0, 80, 10, 0, 0, 1];
+        request.extend([b'a'; MAX_FIELD_LEN + 1]);
+        assert!(parse_request(&mut request.as_slice()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_socks4a_connect_relays() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 4];
+            conn.read_exact(&mut buf).await.unwrap();
+            conn.write_all(b"pong").await.unwrap();
+        });
+
+        let (mut client, server) = duplex(1024);
+        let config = SocksConfig {
+            allow_socks4: true,
+            ..Default::default()
+        };
+        let handle = tokio::spawn(async move {
+            handle_socks4_on_stream(
+                server,
+                &config,
+                &DirectDialer,
+                &Bandwidth::default(),
+                &DnsCache::new(),
+            )
+            .await
+        });
+
+        let mut request = socks4_request(SOCKS4_CMD_CONNECT, port, [0, 0, 0, 1], b"");
+        request.extend_from_slice(b"localhost\0");
+        client.write_all(&request).await.unwrap();
+
+        let mut reply = [0u8; 8];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..2], [SOCKS4_REPLY_VERSION, SOCKS4_REPLY_GRANTED]);
+
+        client.write_all(b"ping").await.unwrap();
+        let mut pong = [0u8; 4];
+        client.read_exact(&mut pong).await.unwrap();
+        assert_eq!(&pong, b"pong");
+        drop(client);
+
+        let summary = handle.await.unwrap().unwrap();
+        assert_eq!(summary.bytes_up, 4);
+        assert_eq!(summary.bytes_down, 4);
+        assert_eq!(
+            summary.socks_request.map(|r| r.target),
+            Some(TargetAddr::Domain("localhost".to_string(), port))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_socks4_rejected_when_auth_required() {
+        let (mut client, server) = duplex(1024);
+        let config = SocksConfig {
+            allow_socks4: true,
+            auth_required: true,
+            username: Some("user".to_string()),
+            password: Some("pass".to_string()),
+            ..Default::default()
+        };
+        let handle = tokio::spawn(async move {
+            handle_socks4_on_stream(
+                server,
+                &config,
+                &DirectDialer,
+                &Bandwidth::default(),
+                &DnsCache::new(),
+            )
+            .await
+        });
+
+        client
+            .write_all(&socks4_request(SOCKS4_CMD_CONNECT, 80, [127, 0, 0, 1], b""))
+            .await
+            .unwrap();
+        let mut reply = [0u8; 8];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], SOCKS4_REPLY_REJECTED);
+
+        let summary = handle.await.unwrap().unwrap();
+        assert_eq!(summary.close_reason, CloseReason::AuthRequired);
+    }
+}
@@ -4,18 +4,35 @@
 //! and relaying data bidirectionally.
 
 use crate::config::SocksConfig;
-use crate::services::socks::command::{send_io_error, send_success};
+use crate::error::Socks5Error;
+use crate::helper::{copy_bidirectional_counted_detailed, CopyDirection, CopyOptions};
+use crate::ratelimit::Bandwidth;
+use crate::services::socks::command::{build_reply, is_client_gone, send_io_error, send_success};
+use crate::services::socks::connect::connect_happy_eyeballs;
+use crate::services::socks::consts::{
+    SOCKS5_REPLY_CONNECTION_NOT_ALLOWED, SOCKS5_REPLY_HOST_UNREACHABLE,
+};
+use crate::services::socks::dialer::{DialedStream, TargetDialer};
+use crate::services::socks::dns::DnsCache;
+use crate::services::socks::ipv6::Ipv6Egress;
+use crate::services::socks::policy::{is_private_ip, target_permitted};
+use crate::services::socks::resolver::resolve_target;
 use crate::services::socks::types::TargetAddr;
-use anyhow::{Context, Result};
-use std::time::Duration;
+use crate::services::{CloseReason, ConnectionSummary};
+use anyhow::Result;
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::net::TcpStream;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
+
+/// Pause between retries of a failed target connect
+const EGRESS_RETRY_DELAY: Duration = Duration::from_millis(250);
 
 /// Handle TCP CONNECT command
 ///
 /// This function:
-/// 1. Resolves the target address
+/// 1. Resolves the target address, unless the dialer resolves names itself
 /// 2. Establishes a TCP connection to the target
 /// 3. Sends a success reply
 /// 4. Relays data bidirectionally between client and target
@@ -25,89 +42,322 @@ use tracing::{debug, error, info};
 /// * `client_stream` - The client stream (from tunnel)
 /// * `target_addr` - The target address to connect to
 /// * `config` - SOCKS5 configuration
+/// * `dialer` - Opens the connection to the target
+/// * `shared_bandwidth` - Limiters shared by every connection of the service
+/// * `dns_cache` - Cache and resolver for the target name
+///
+/// # Returns
+///
+/// A [`ConnectionSummary`] of the relayed traffic, with the target set
 pub async fn handle_tcp_connect<S>(
+    client_stream: S,
+    target_addr: TargetAddr,
+    config: &SocksConfig,
+    dialer: &dyn TargetDialer,
+    shared_bandwidth: &Bandwidth,
+    dns_cache: &DnsCache,
+) -> Result<ConnectionSummary>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    handle_tcp_connect_with(
+        client_stream,
+        target_addr,
+        config,
+        dialer,
+        shared_bandwidth,
+        Ipv6Egress::global(),
+        dns_cache,
+    )
+    .await
+}
+
+/// [`handle_tcp_connect`] with an explicit IPv6 egress detector
+async fn handle_tcp_connect_with<S>(
     mut client_stream: S,
     target_addr: TargetAddr,
     config: &SocksConfig,
-) -> Result<()>
+    dialer: &dyn TargetDialer,
+    shared_bandwidth: &Bandwidth,
+    ipv6_egress: &Ipv6Egress,
+    dns_cache: &DnsCache,
+) -> Result<ConnectionSummary>
 where
     S: AsyncRead + AsyncWrite + Unpin + Send,
 {
-    let timeout = Duration::from_secs(config.request_timeout);
+    // A dialer that resolves names itself (an upstream proxy) is handed the
+    // domain as it is, so there are no local addresses to filter or race
+    if matches!(target_addr, TargetAddr::Domain(..)) && dialer.resolves_remotely() {
+        debug!("Connecting to target: {} (resolved remotely)", target_addr);
+        let target = match dial_with_retries(dialer, &target_addr, config).await {
+            Ok(target) => target,
+            Err(e) => {
+                error!("Failed to connect to {}: {}", target_addr, e);
+                send_io_error(&mut client_stream, &e).await?;
+                return Err(Socks5Error::TargetUnreachable(e).into());
+            }
+        };
+        let established = target_addr.to_string();
+        return reply_and_relay(
+            client_stream,
+            target_addr,
+            established,
+            target,
+            config,
+            shared_bandwidth,
+        )
+        .await;
+    }
 
     // Resolve address
-    let socket_addr = target_addr
-        .resolve()
-        .await
-        .with_context(|| format!("Failed to resolve address: {}", target_addr))?;
+    let addrs = match resolve_target(&target_addr, dns_cache, config).await {
+        Ok(addrs) => addrs,
+        Err(e) => {
+            error!("{:#}", e);
+            match build_reply(&mut client_stream, SOCKS5_REPLY_HOST_UNREACHABLE, None).await {
+                Err(reply_error) if !is_client_gone(&reply_error) => return Err(reply_error),
+                _ => return Err(Socks5Error::target_unreachable(e).into()),
+            }
+        }
+    };
 
-    debug!("Connecting to target: {}", socket_addr);
+    // Only public addresses may be dialed when private networks are blocked.
+    // The check runs on the resolved addresses that are actually dialed, so
+    // a public name rebound to a private IP is refused as well.
+    let addrs = if config.block_private_networks {
+        let public: Vec<SocketAddr> = addrs
+            .into_iter()
+            .filter(|addr| !is_private_ip(addr.ip()))
+            .collect();
+        if public.is_empty() {
+            warn!("Refusing CONNECT to private address {}", target_addr);
+            return refuse(&mut client_stream, &target_addr).await;
+        }
+        public
+    } else {
+        addrs
+    };
 
-    // Connect to target with timeout
-    let target_stream = match tokio::time::timeout(timeout, TcpStream::connect(socket_addr)).await {
-        Ok(Ok(stream)) => stream,
-        Ok(Err(e)) => {
-            error!("Failed to connect to {}: {}", socket_addr, e);
-            send_io_error(&mut client_stream, &e).await?;
-            return Err(e.into());
+    // Address rules of the ACL are checked against what a domain resolved to
+    let addrs = if config.acl.is_empty() {
+        addrs
+    } else {
+        let permitted: Vec<SocketAddr> = addrs
+            .into_iter()
+            .filter(|addr| target_permitted(&config.acl, &target_addr, Some(addr.ip())))
+            .collect();
+        if permitted.is_empty() {
+            warn!("CONNECT to {} denied by ACL", target_addr);
+            return refuse(&mut client_stream, &target_addr).await;
         }
-        Err(_) => {
-            error!("Connection timeout to {}", socket_addr);
-            let timeout_err =
-                std::io::Error::new(std::io::ErrorKind::TimedOut, "Connection timeout");
-            send_io_error(&mut client_stream, &timeout_err).await?;
-            anyhow::bail!("Connection timeout");
+        permitted
+    };
+
+    // Skip v6 addresses straight away when the host has no IPv6 egress
+    let addrs = if config.ipv6_probe && dialer.uses_host_network() {
+        match ipv6_egress.select_addrs(addrs) {
+            Ok(addrs) => addrs,
+            Err(e) => {
+                error!("Cannot reach {}: {}", target_addr, e);
+                send_io_error(&mut client_stream, &e).await?;
+                return Err(Socks5Error::TargetUnreachable(e).into());
+            }
         }
+    } else {
+        addrs
     };
 
-    // Get local address for reply
-    let local_addr = target_stream.local_addr().ok();
+    debug!("Connecting to target: {} ({:?})", target_addr, addrs);
+
+    let (socket_addr, target) = match connect_happy_eyeballs(dialer, &addrs, config).await {
+        Ok(connected) => connected,
+        Err(e) => {
+            error!("Failed to connect to {}: {}", target_addr, e);
+            send_io_error(&mut client_stream, &e).await?;
+            return Err(Socks5Error::TargetUnreachable(e).into());
+        }
+    };
+    reply_and_relay(
+        client_stream,
+        target_addr,
+        socket_addr,
+        target,
+        config,
+        shared_bandwidth,
+    )
+    .await
+}
 
+/// Send the success reply for the connected `target`, then relay
+async fn reply_and_relay<S>(
+    mut client_stream: S,
+    target_addr: TargetAddr,
+    established: impl std::fmt::Display,
+    target: DialedStream,
+    config: &SocksConfig,
+    shared_bandwidth: &Bandwidth,
+) -> Result<ConnectionSummary>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
     // Send success reply
-    send_success(&mut client_stream, local_addr).await?;
+    if let Err(e) = send_success(&mut client_stream, target.local_addr).await {
+        if is_client_gone(&e) {
+            debug!("Client went away before the SOCKS5 reply: {}", e);
+            return Ok(ConnectionSummary::new(CloseReason::ClientClosed)
+                .with_target(target_addr.to_string()));
+        }
+        return Err(match e.downcast::<io::Error>() {
+            Ok(e) => Socks5Error::RelayIo(e).into(),
+            Err(e) => e,
+        });
+    }
 
-    info!("SOCKS5 tunnel established to {}", socket_addr);
+    info!("SOCKS5 tunnel established to {}", established);
 
     // Perform bidirectional relay
-    relay_tcp(client_stream, target_stream).await
+    let summary =
+        relay_tcp_configured(client_stream, target.stream, config, shared_bandwidth).await?;
+    Ok(summary.with_target(target_addr.to_string()))
+}
+
+/// Reply "connection not allowed" and summarise the refused CONNECT
+async fn refuse<S>(client_stream: &mut S, target_addr: &TargetAddr) -> Result<ConnectionSummary>
+where
+    S: AsyncWrite + Unpin,
+{
+    match build_reply(client_stream, SOCKS5_REPLY_CONNECTION_NOT_ALLOWED, None).await {
+        Err(e) if !is_client_gone(&e) => Err(e),
+        _ => Ok(ConnectionSummary::new(CloseReason::Rejected).with_target(target_addr.to_string())),
+    }
+}
+
+/// Connect to the target, each attempt limited to the connect timeout
+///
+/// Domain targets are passed to [`TargetDialer::dial_domain`] as they are.
+/// Transient failures are retried up to `egress_connect_retries` times
+/// after a short pause. A refused connection means the target is up but
+/// not listening, so it fails straight away.
+pub(super) async fn dial_with_retries(
+    dialer: &dyn TargetDialer,
+    target: &TargetAddr,
+    config: &SocksConfig,
+) -> io::Result<DialedStream> {
+    let timeout = config.connect_timeout();
+    let mut attempt = 0;
+
+    loop {
+        let dial = async {
+            match target {
+                TargetAddr::Ip(addr) => dialer.dial(*addr, config).await,
+                TargetAddr::Domain(domain, port) => dialer.dial_domain(domain, *port, config).await,
+            }
+        };
+        let result = match tokio::time::timeout(timeout, dial).await {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Connection timeout",
+            )),
+        };
+        match result {
+            Err(e)
+                if attempt < config.egress_connect_retries
+                    && e.kind() != io::ErrorKind::ConnectionRefused =>
+            {
+                attempt += 1;
+                warn!(
+                    "Connect to {} failed: {}, retrying ({}/{})",
+                    target, e, attempt, config.egress_connect_retries
+                );
+                tokio::time::sleep(EGRESS_RETRY_DELAY).await;
+            }
+            result => return result,
+        }
+    }
 }
 
 /// Relay data bidirectionally between two streams
 ///
 /// This function copies data in both directions concurrently and
 /// returns when either direction encounters an error or EOF.
-pub async fn relay_tcp<A, B>(a: A, b: B) -> Result<()>
+///
+/// `a` is treated as the client side: bytes read from `a` are counted
+/// as `bytes_up` in the returned [`ConnectionSummary`], bytes read from
+/// `b` as `bytes_down`.
+pub async fn relay_tcp<A, B>(a: A, b: B) -> Result<ConnectionSummary>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    relay_tcp_with(a, b, None, Bandwidth::default()).await
+}
+
+/// [`relay_tcp`] with the `write_timeout` and `max_bytes_per_sec` of
+/// `config`, also throttled by the service-wide `shared_bandwidth`
+pub(super) async fn relay_tcp_configured<A, B>(
+    a: A,
+    b: B,
+    config: &SocksConfig,
+    shared_bandwidth: &Bandwidth,
+) -> Result<ConnectionSummary>
 where
     A: AsyncRead + AsyncWrite + Unpin,
     B: AsyncRead + AsyncWrite + Unpin,
 {
-    let (mut a_read, mut a_write) = tokio::io::split(a);
-    let (mut b_read, mut b_write) = tokio::io::split(b);
+    let write_timeout = config.write_timeout.map(Duration::from_secs);
+    let bandwidth = Bandwidth::new(config.max_bytes_per_sec).and(shared_bandwidth);
+    relay_tcp_with(a, b, write_timeout, bandwidth).await
+}
 
-    let a_to_b = tokio::io::copy(&mut a_read, &mut b_write);
-    let b_to_a = tokio::io::copy(&mut b_read, &mut a_write);
+/// [`relay_tcp`] that aborts when a write to either side blocks longer
+/// than `write_timeout`, and throttles each direction by `bandwidth`
+pub(super) async fn relay_tcp_with<A, B>(
+    mut a: A,
+    mut b: B,
+    write_timeout: Option<Duration>,
+    bandwidth: Bandwidth,
+) -> Result<ConnectionSummary>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let start = Instant::now();
+    let opts = CopyOptions {
+        half_close: false,
+        write_timeout,
+        a_to_b_limiters: bandwidth.up,
+        b_to_a_limiters: bandwidth.down,
+        ..Default::default()
+    };
+    let outcome = copy_bidirectional_counted_detailed(&mut a, &mut b, &opts).await;
 
-    tokio::select! {
-        result = a_to_b => {
-            match result {
-                Ok(bytes) => debug!("A->B finished: {} bytes", bytes),
-                Err(e) => debug!("A->B error: {}", e),
-            }
+    let close_reason = match (outcome.error, outcome.first_done) {
+        (None, CopyDirection::AToB) => CloseReason::ClientClosed,
+        (None, CopyDirection::BToA) => CloseReason::TargetClosed,
+        (Some(e), direction) => {
+            debug!("{:?} error: {}", direction, e);
+            CloseReason::Error(e.to_string())
         }
-        result = b_to_a => {
-            match result {
-                Ok(bytes) => debug!("B->A finished: {} bytes", bytes),
-                Err(e) => debug!("B->A error: {}", e),
-            }
-        }
-    }
+    };
+
+    debug!(
+        "Relay finished ({}): A->B {} bytes, B->A {} bytes",
+        close_reason, outcome.a_to_b, outcome.b_to_a
+    );
 
-    Ok(())
+    Ok(ConnectionSummary::new(close_reason)
+        .with_bytes(outcome.a_to_b, outcome.b_to_a)
+        .with_duration(start.elapsed()))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::{AclConfig, AclRule};
+    use crate::services::socks::consts::SOCKS5_REPLY_SUCCEEDED;
+    use crate::services::socks::dialer::DirectDialer;
+    use futures::future::BoxFuture;
     use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
 
     #[tokio::test]
@@ -220,6 +470,93 @@ mod tests {
         assert!(result.is_ok());
     }
 
+    #[tokio::test]
+    async fn test_relay_tcp_summary_byte_counts() {
+        let (mut client_a, server_a) = duplex(1024);
+        let (mut client_b, server_b) = duplex(1024);
+
+        let relay_handle = tokio::spawn(async move { relay_tcp(server_a, server_b).await });
+
+        client_a.write_all(b"message A->B").await.unwrap();
+        let mut buf_b = vec![0u8; 12];
+        client_b.read_exact(&mut buf_b).await.unwrap();
+
+        client_b.write_all(b"B->A!").await.unwrap();
+        let mut buf_a = vec![0u8; 5];
+        client_a.read_exact(&mut buf_a).await.unwrap();
+
+        // Client closes first
+        drop(client_a);
+
+        let summary = tokio::time::timeout(Duration::from_secs(1), relay_handle)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(summary.bytes_up, 12);
+        assert_eq!(summary.bytes_down, 5);
+        assert_eq!(summary.close_reason, CloseReason::ClientClosed);
+        assert!(summary.target.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_relay_tcp_summary_target_closed() {
+        let (mut client_a, server_a) = duplex(65536);
+        let (mut client_b, server_b) = duplex(65536);
+
+        let relay_handle = tokio::spawn(async move { relay_tcp(server_a, server_b).await });
+
+        let large_data = vec![0xCD; 40000];
+        client_b.write_all(&large_data).await.unwrap();
+        let mut received = vec![0u8; 40000];
+        client_a.read_exact(&mut received).await.unwrap();
+
+        // Target closes first
+        drop(client_b);
+
+        let summary = tokio::time::timeout(Duration::from_secs(1), relay_handle)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(summary.bytes_up, 0);
+        assert_eq!(summary.bytes_down, 40000);
+        assert_eq!(summary.close_reason, CloseReason::TargetClosed);
+    }
+
+    #[tokio::test]
+    async fn test_handle_tcp_connect_ipv6_unavailable_short_circuits() {
+        use crate::services::socks::consts::SOCKS5_REPLY_HOST_UNREACHABLE;
+
+        let (client, mut server) = duplex(1024);
+        let config = SocksConfig {
+            request_timeout: 30,
+            ..Default::default()
+        };
+        let egress = Ipv6Egress::with_probe(|| false, Duration::from_secs(300));
+
+        let target = TargetAddr::Ip("[2001:db8::1]:80".parse().unwrap());
+        let result = tokio::time::timeout(
+            Duration::from_secs(1),
+            handle_tcp_connect_with(
+                client,
+                target,
+                &config,
+                &DirectDialer,
+                &Bandwidth::default(),
+                &egress,
+                &DnsCache::new(),
+            ),
+        )
+        .await
+        .expect("v6 connect should fail without waiting for the timeout");
+        assert!(result.is_err());
+
+        let mut reply = [0u8; 10];
+        server.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], SOCKS5_REPLY_HOST_UNREACHABLE);
+    }
+
     #[tokio::test]
     async fn test_handle_tcp_connect_invalid_address() {
         let (client, _server) = duplex(1024);
@@ -231,11 +568,20 @@ mod tests {
             dns_resolve: false,
             allow_udp: false,
             request_timeout: 1,
+            ..Default::default()
         };
 
         // Try to connect to an invalid port (0)
         let target = TargetAddr::Ip("0.0.0.0:0".parse().unwrap());
-        let result = handle_tcp_connect(client, target, &config).await;
+        let result = handle_tcp_connect(
+            client,
+            target,
+            &config,
+            &DirectDialer,
+            &Bandwidth::default(),
+            &DnsCache::new(),
+        )
+        .await;
         assert!(result.is_err());
     }
 
@@ -250,17 +596,26 @@ mod tests {
             dns_resolve: false,
             allow_udp: false,
             request_timeout: 1,
+            ..Default::default()
         };
 
         // Try to connect to a port that's not listening
         let target = TargetAddr::Ip("127.0.0.1:9".parse().unwrap());
-        let result = handle_tcp_connect(client, target, &config).await;
+        let result = handle_tcp_connect(
+            client,
+            target,
+            &config,
+            &DirectDialer,
+            &Bandwidth::default(),
+            &DnsCache::new(),
+        )
+        .await;
         assert!(result.is_err());
     }
 
     #[tokio::test]
     async fn test_handle_tcp_connect_unresolvable_domain() {
-        let (client, _server) = duplex(1024);
+        let (client, mut server) = duplex(1024);
 
         let config = SocksConfig {
             username: None,
@@ -269,11 +624,418 @@ mod tests {
             dns_resolve: true,
             allow_udp: false,
             request_timeout: 1,
+            ..Default::default()
         };
 
         // Try to resolve an invalid domain
         let target = TargetAddr::Domain("this-domain-does-not-exist-12345.invalid".to_string(), 80);
-        let result = handle_tcp_connect(client, target, &config).await;
+        let result = handle_tcp_connect(
+            client,
+            target,
+            &config,
+            &DirectDialer,
+            &Bandwidth::default(),
+            &DnsCache::new(),
+        )
+        .await;
+        assert!(result.is_err());
+
+        let mut reply = [0u8; 10];
+        server.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], SOCKS5_REPLY_HOST_UNREACHABLE);
+    }
+
+    /// Dialer failing with `error` for the first `failures` attempts, then
+    /// connecting to an in-memory stream
+    #[derive(Debug)]
+    struct FlakyDialer {
+        failures: usize,
+        error: io::ErrorKind,
+        attempts: std::sync::atomic::AtomicUsize,
+    }
+
+    impl FlakyDialer {
+        fn new(failures: usize, error: io::ErrorKind) -> Self {
+            FlakyDialer {
+                failures,
+                error,
+                attempts: std::sync::atomic::AtomicUsize::new(0),
+            }
+        }
+
+        fn attempts(&self) -> usize {
+            self.attempts.load(std::sync::atomic::Ordering::SeqCst)
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl TargetDialer for FlakyDialer {
+        async fn dial(&self, _addr: SocketAddr, _config: &SocksConfig) -> io::Result<DialedStream> {
+            let attempt = self
+                .attempts
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            if attempt < self.failures {
+                return Err(io::Error::from(self.error));
+            }
+            Ok(DialedStream {
+                stream: Box::new(duplex(64).0),
+                local_addr: None,
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handle_tcp_connect_blocks_private_target() {
+        let (client, mut server) = duplex(1024);
+        let config = SocksConfig {
+            block_private_networks: true,
+            ..Default::default()
+        };
+        let dialer = FlakyDialer::new(0, io::ErrorKind::ConnectionRefused);
+
+        let target = TargetAddr::Ip("10.0.0.1:80".parse().unwrap());
+        let summary = handle_tcp_connect(
+            client,
+            target,
+            &config,
+            &dialer,
+            &Bandwidth::default(),
+            &DnsCache::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(summary.close_reason, CloseReason::Rejected);
+        assert_eq!(dialer.attempts(), 0);
+
+        let mut reply = [0u8; 10];
+        server.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], SOCKS5_REPLY_CONNECTION_NOT_ALLOWED);
+    }
+
+    #[tokio::test]
+    async fn test_handle_tcp_connect_blocks_name_rebound_to_private_ip() {
+        fn rebinding_resolver(_host: String) -> BoxFuture<'static, io::Result<Vec<SocketAddr>>> {
+            Box::pin(async { Ok(vec!["10.0.0.7:443".parse().unwrap()]) })
+        }
+
+        let (client, mut server) = duplex(1024);
+        let config = SocksConfig {
+            block_private_networks: true,
+            ..Default::default()
+        };
+        let dialer = FlakyDialer::new(0, io::ErrorKind::ConnectionRefused);
+        let cache = DnsCache::with_resolver(rebinding_resolver);
+
+        let target = TargetAddr::domain("public.example.com".to_string(), 443);
+        let summary = handle_tcp_connect_with(
+            client,
+            target,
+            &config,
+            &dialer,
+            &Bandwidth::default(),
+            Ipv6Egress::global(),
+            &cache,
+        )
+        .await
+        .unwrap();
+        assert_eq!(summary.close_reason, CloseReason::Rejected);
+        assert_eq!(dialer.attempts(), 0);
+
+        let mut reply = [0u8; 10];
+        server.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], SOCKS5_REPLY_CONNECTION_NOT_ALLOWED);
+    }
+
+    #[tokio::test]
+    async fn test_handle_tcp_connect_acl_cidr_after_resolution() {
+        fn resolver(_host: String) -> BoxFuture<'static, io::Result<Vec<SocketAddr>>> {
+            Box::pin(async { Ok(vec!["198.51.100.7:443".parse().unwrap()]) })
+        }
+
+        let (client, mut server) = duplex(1024);
+        let config = SocksConfig {
+            acl: AclConfig {
+                deny: vec![AclRule {
+                    cidr: Some("198.51.100.0/24".parse().unwrap()),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let dialer = FlakyDialer::new(0, io::ErrorKind::ConnectionRefused);
+        let cache = DnsCache::with_resolver(resolver);
+
+        let target = TargetAddr::domain("app.example.com".to_string(), 443);
+        let summary = handle_tcp_connect_with(
+            client,
+            target,
+            &config,
+            &dialer,
+            &Bandwidth::default(),
+            Ipv6Egress::global(),
+            &cache,
+        )
+        .await
+        .unwrap();
+        assert_eq!(summary.close_reason, CloseReason::Rejected);
+        assert_eq!(dialer.attempts(), 0);
+
+        let mut reply = [0u8; 10];
+        server.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], SOCKS5_REPLY_CONNECTION_NOT_ALLOWED);
+    }
+
+    #[tokio::test]
+    async fn test_handle_tcp_connect_allows_public_target() {
+        let (client, mut server) = duplex(1024);
+        let config = SocksConfig {
+            block_private_networks: true,
+            ipv6_probe: false,
+            ..Default::default()
+        };
+        let dialer = FlakyDialer::new(0, io::ErrorKind::ConnectionRefused);
+
+        let target = TargetAddr::Ip("8.8.8.8:53".parse().unwrap());
+        let handle = tokio::spawn(async move {
+            handle_tcp_connect(
+                client,
+                target,
+                &config,
+                &dialer,
+                &Bandwidth::default(),
+                &DnsCache::new(),
+            )
+            .await
+        });
+
+        let mut reply = [0u8; 10];
+        server.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], SOCKS5_REPLY_SUCCEEDED);
+        drop(server);
+        handle.await.unwrap().unwrap();
+    }
+
+    /// Dialer for an unroutable target: connects never complete
+    #[derive(Debug)]
+    struct BlackholeDialer;
+
+    #[async_trait::async_trait]
+    impl TargetDialer for BlackholeDialer {
+        async fn dial(&self, _addr: SocketAddr, _config: &SocksConfig) -> io::Result<DialedStream> {
+            std::future::pending().await
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_connect_timeout_replies_host_unreachable() {
+        let (client, mut server) = duplex(1024);
+        let config = SocksConfig {
+            request_timeout: 300,
+            connect_timeout_secs: 2,
+            ipv6_probe: false,
+            ..Default::default()
+        };
+
+        // TEST-NET-1 is unroutable
+        let target = TargetAddr::Ip("192.0.2.1:80".parse().unwrap());
+        let start = tokio::time::Instant::now();
+        let result = handle_tcp_connect(
+            client,
+            target,
+            &config,
+            &BlackholeDialer,
+            &Bandwidth::default(),
+            &DnsCache::new(),
+        )
+        .await;
         assert!(result.is_err());
+        assert_eq!(start.elapsed(), Duration::from_secs(2));
+
+        let mut reply = [0u8; 10];
+        server.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], SOCKS5_REPLY_HOST_UNREACHABLE);
+    }
+
+    #[tokio::test]
+    async fn test_dial_retries_transient_failure() {
+        let config = SocksConfig {
+            egress_connect_retries: 2,
+            ..Default::default()
+        };
+        let addr = TargetAddr::Ip("192.0.2.1:80".parse().unwrap());
+
+        let dialer = FlakyDialer::new(1, io::ErrorKind::TimedOut);
+        assert!(dial_with_retries(&dialer, &addr, &config).await.is_ok());
+        assert_eq!(dialer.attempts(), 2);
+
+        // Without retries configured the first failure is final
+        let dialer = FlakyDialer::new(1, io::ErrorKind::TimedOut);
+        let no_retries = SocksConfig::default();
+        assert!(dial_with_retries(&dialer, &addr, &no_retries)
+            .await
+            .is_err());
+        assert_eq!(dialer.attempts(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_dial_never_retries_refused() {
+        let config = SocksConfig {
+            egress_connect_retries: 3,
+            ..Default::default()
+        };
+        let dialer = FlakyDialer::new(1, io::ErrorKind::ConnectionRefused);
+        let target = TargetAddr::Ip("192.0.2.1:80".parse().unwrap());
+        let err = dial_with_retries(&dialer, &target, &config)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        assert_eq!(dialer.attempts(), 1);
+    }
+
+    /// Counts ERROR-level tracing events
+    struct ErrorCounter(std::sync::Arc<std::sync::atomic::AtomicUsize>);
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for ErrorCounter {
+        fn on_event(
+            &self,
+            event: &tracing::Event<'_>,
+            _ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            if *event.metadata().level() == tracing::Level::ERROR {
+                self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handle_tcp_connect_client_gone_before_reply() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let errors = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let subscriber = tracing_subscriber::registry().with(ErrorCounter(errors.clone()));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = TargetAddr::Ip(listener.local_addr().unwrap());
+
+        // The client disconnects before the reply is written
+        let (client, server) = duplex(1024);
+        drop(server);
+
+        let config = SocksConfig {
+            request_timeout: 1,
+            ..Default::default()
+        };
+        let summary = handle_tcp_connect(
+            client,
+            target.clone(),
+            &config,
+            &DirectDialer,
+            &Bandwidth::default(),
+            &DnsCache::new(),
+        )
+        .await
+        .expect("client going away is not an error");
+
+        assert_eq!(summary.close_reason, CloseReason::ClientClosed);
+        assert_eq!(summary.target.as_deref(), Some(target.to_string().as_str()));
+        assert_eq!(errors.load(std::sync::atomic::Ordering::SeqCst), 0);
+    }
+
+    /// A peer that never reads or accepts writes
+    struct StalledStream;
+
+    impl AsyncRead for StalledStream {
+        fn poll_read(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            _buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<io::Result<()>> {
+            std::task::Poll::Pending
+        }
+    }
+
+    impl AsyncWrite for StalledStream {
+        fn poll_write(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            _buf: &[u8],
+        ) -> std::task::Poll<io::Result<usize>> {
+            std::task::Poll::Pending
+        }
+
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<io::Result<()>> {
+            std::task::Poll::Pending
+        }
+
+        fn poll_shutdown(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<io::Result<()>> {
+            std::task::Poll::Pending
+        }
+    }
+
+    #[tokio::test]
+    async fn test_relay_rate_limited() {
+        const RATE: u64 = 100_000;
+        let (mut client, server) = duplex(64 * 1024);
+        let (target, mut target_peer) = duplex(64 * 1024);
+        let config = SocksConfig {
+            max_bytes_per_sec: RATE,
+            ..Default::default()
+        };
+
+        let relay = tokio::spawn(async move {
+            relay_tcp_configured(server, target, &config, &Bandwidth::default()).await
+        });
+
+        // One second's worth passes as a burst, the rest at the rate
+        let payload = vec![7u8; (RATE * 3 / 2) as usize];
+        let start = Instant::now();
+        let writer = tokio::spawn(async move {
+            client.write_all(&payload).await.unwrap();
+            client
+        });
+        let mut received = vec![0u8; (RATE * 3 / 2) as usize];
+        target_peer.read_exact(&mut received).await.unwrap();
+        let elapsed = start.elapsed();
+
+        assert!(elapsed >= Duration::from_millis(450), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(1500), "{:?}", elapsed);
+
+        drop(writer.await.unwrap());
+        drop(target_peer);
+        relay.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_relay_write_timeout_aborts_stalled_peer() {
+        let (mut client, server) = duplex(1024);
+        let relay = tokio::spawn(async move {
+            relay_tcp_with(
+                server,
+                StalledStream,
+                Some(Duration::from_millis(50)),
+                Bandwidth::default(),
+            )
+            .await
+        });
+
+        client.write_all(b"nobody is reading").await.unwrap();
+
+        let summary = tokio::time::timeout(Duration::from_secs(1), relay)
+            .await
+            .expect("the stuck write should abort within the write timeout")
+            .unwrap()
+            .unwrap();
+        match summary.close_reason {
+            CloseReason::Error(e) => assert!(e.contains("timed out"), "{}", e),
+            other => panic!("expected a write timeout, got {:?}", other),
+        }
     }
 }
@@ -2,17 +2,20 @@
 //!
 //! Defines the core types used in SOCKS5 protocol handling.
 
+use super::auth::AuthMethod;
 use super::consts::*;
+use super::dns::DnsCache;
 use anyhow::{Context, Result};
 use std::fmt;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
 
 /// SOCKS5 command types
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SocksCommand {
     /// TCP CONNECT - establish a TCP connection to target
     Connect,
-    /// TCP BIND - wait for incoming connection (not implemented)
+    /// TCP BIND - wait for an incoming connection
     Bind,
     /// UDP ASSOCIATE - establish UDP relay
     UdpAssociate,
@@ -113,6 +116,46 @@ impl TargetAddr {
         }
     }
 
+    /// Resolve the address to every SocketAddr it maps to
+    ///
+    /// For IP addresses, this returns the address itself.
+    /// For domain names, this returns all addresses from DNS resolution.
+    pub async fn resolve_all(&self) -> Result<Vec<SocketAddr>> {
+        match self {
+            TargetAddr::Ip(addr) => Ok(vec![*addr]),
+            TargetAddr::Domain(domain, port) => {
+                let addr_str = format!("{}:{}", domain, port);
+                let resolved: Vec<SocketAddr> = tokio::net::lookup_host(&addr_str)
+                    .await
+                    .with_context(|| format!("Failed to resolve domain: {}", domain))?
+                    .collect();
+                if resolved.is_empty() {
+                    anyhow::bail!("No addresses found for domain: {}", domain);
+                }
+                Ok(resolved)
+            }
+        }
+    }
+
+    /// Resolve the address to every SocketAddr it maps to through a DNS cache
+    ///
+    /// Successful lookups are cached for `ttl` and failed ones for
+    /// `negative_ttl`; see [`DnsCache::resolve`].
+    pub async fn resolve_all_cached(
+        &self,
+        cache: &DnsCache,
+        ttl: Duration,
+        negative_ttl: Duration,
+    ) -> Result<Vec<SocketAddr>> {
+        match self {
+            TargetAddr::Ip(addr) => Ok(vec![*addr]),
+            TargetAddr::Domain(domain, port) => cache
+                .resolve(domain, *port, ttl, negative_ttl)
+                .await
+                .with_context(|| format!("Failed to resolve domain: {}", domain)),
+        }
+    }
+
     /// Serialize the address to bytes for SOCKS5 protocol
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut bytes = Vec::new();
@@ -161,6 +204,17 @@ impl Default for TargetAddr {
     }
 }
 
+/// A negotiated SOCKS5 request, as the client sent it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SocksRequest {
+    /// The requested command
+    pub command: SocksCommand,
+    /// The requested target, before any DNS resolution
+    pub target: TargetAddr,
+    /// The authentication method the client completed
+    pub auth_method: AuthMethod,
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
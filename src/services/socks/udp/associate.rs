@@ -2,54 +2,163 @@
 //!
 //! Implements the UDP ASSOCIATE command for SOCKS5.
 
-use crate::services::socks::command::send_success;
+use crate::services::socks::command::{build_reply, is_client_gone, send_success};
+use crate::services::socks::consts::SOCKS5_REPLY_CONNECTION_NOT_ALLOWED;
 use crate::services::socks::types::TargetAddr;
+use crate::services::CloseReason;
 use anyhow::Result;
+use lazy_static::lazy_static;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
 use tracing::{debug, info, warn};
 
+lazy_static! {
+    static ref GLOBAL_UDP_ASSOCIATIONS: UdpAssociations = UdpAssociations::new();
+}
+
+/// Count of live UDP ASSOCIATE sessions, for enforcing
+/// [`max_udp_associations`](crate::config::SocksConfig::max_udp_associations)
+#[derive(Debug, Default)]
+pub struct UdpAssociations {
+    active: AtomicUsize,
+}
+
+impl UdpAssociations {
+    /// Create an empty counter
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Process-wide counter shared by all SOCKS5 handlers
+    pub fn global() -> &'static UdpAssociations {
+        &GLOBAL_UDP_ASSOCIATIONS
+    }
+
+    /// Number of live associations
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Claim a slot if fewer than `max` associations are live (no limit
+    /// when `None`); the slot is released when the guard drops
+    pub fn try_acquire(&self, max: Option<usize>) -> Option<UdpAssociationGuard<'_>> {
+        self.active
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |active| match max {
+                Some(max) if active >= max => None,
+                _ => Some(active + 1),
+            })
+            .ok()
+            .map(|_| UdpAssociationGuard(self))
+    }
+}
+
+/// A claimed UDP association slot
+#[derive(Debug)]
+pub struct UdpAssociationGuard<'a>(&'a UdpAssociations);
+
+impl Drop for UdpAssociationGuard<'_> {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
 /// Handle UDP ASSOCIATE command
 ///
 /// UDP ASSOCIATE in reverse tunnel mode works differently from standard SOCKS5:
 /// - We cannot bind a local UDP port for the client
-/// - Instead, we use a virtual binding and encapsulate UDP traffic through TCP
+/// - Instead, datagrams reach us through the rathole server's UDP service,
+///   which forwards them over UDP data channels
 ///
 /// # Protocol Flow
 ///
 /// 1. Client sends UDP ASSOCIATE with expected DST.ADDR and DST.PORT
-/// 2. Server replies with BND.ADDR:BND.PORT (virtual in our case)
-/// 3. UDP traffic is encapsulated and sent through the tunnel
+/// 2. Server replies with BND.ADDR:BND.PORT: the configured
+///    [`udp_relay_addr`](crate::config::SocksConfig::udp_relay_addr), the
+///    public address of the rathole UDP service, or `0.0.0.0:0` if unset
+/// 3. The client sends SOCKS5-encapsulated datagrams to that address, and
+///    rathole carries them through the tunnel
 /// 4. When the TCP connection closes, the UDP association ends
 ///
 /// # Arguments
 ///
 /// * `control_stream` - The control stream (from tunnel)
 /// * `_client_addr` - The client's indicated address (usually ignored)
-/// * `_config` - SOCKS5 configuration (reserved for future use)
+/// * `config` - SOCKS5 configuration
+///
+/// # Returns
+///
+/// Why the association ended: [`CloseReason::Rejected`] when
+/// `max_udp_associations` sessions were already live
 pub async fn handle_udp_associate<S>(
+    control_stream: S,
+    client_addr: TargetAddr,
+    config: &crate::config::SocksConfig,
+) -> Result<CloseReason>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    handle_udp_associate_with(
+        control_stream,
+        client_addr,
+        config,
+        UdpAssociations::global(),
+    )
+    .await
+}
+
+/// [`handle_udp_associate`] counting against an explicit set of associations
+async fn handle_udp_associate_with<S>(
     mut control_stream: S,
     _client_addr: TargetAddr,
-    _config: &crate::config::SocksConfig,
-) -> Result<()>
+    config: &crate::config::SocksConfig,
+    associations: &UdpAssociations,
+) -> Result<CloseReason>
 where
     S: AsyncRead + AsyncWrite + Unpin + Send,
 {
-    // In reverse tunnel mode, we use a virtual bind address
-    // The actual UDP traffic will be encapsulated through TCP
-    let virtual_bind_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0);
+    let Some(_slot) = associations.try_acquire(config.max_udp_associations) else {
+        warn!(
+            "Refusing UDP ASSOCIATE: {} associations already active",
+            associations.active()
+        );
+        match build_reply(
+            &mut control_stream,
+            SOCKS5_REPLY_CONNECTION_NOT_ALLOWED,
+            None,
+        )
+        .await
+        {
+            Err(e) if !is_client_gone(&e) => return Err(e),
+            _ => return Ok(CloseReason::Rejected),
+        }
+    };
 
-    // Send success reply with virtual bind address
-    send_success(&mut control_stream, Some(virtual_bind_addr)).await?;
+    let relay_addr = udp_relay_addr(config);
 
-    info!("UDP ASSOCIATE established (virtual mode)");
+    if let Err(e) = send_success(&mut control_stream, Some(relay_addr)).await {
+        if is_client_gone(&e) {
+            debug!("Client went away before the UDP ASSOCIATE reply: {}", e);
+            return Ok(CloseReason::ClientClosed);
+        }
+        return Err(e);
+    }
+
+    info!("UDP ASSOCIATE established, relay address {}", relay_addr);
 
     // The UDP association is maintained as long as the TCP control connection is open
     // We monitor the control stream for closure
     monitor_control_stream(control_stream).await?;
 
     info!("UDP ASSOCIATE session ended");
-    Ok(())
+    Ok(CloseReason::Completed)
+}
+
+/// Address clients should send UDP datagrams to
+fn udp_relay_addr(config: &crate::config::SocksConfig) -> SocketAddr {
+    config.udp_relay_addr.unwrap_or_else(|| {
+        config.advertised_addr(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0))
+    })
 }
 
 /// Monitor the control stream for closure
@@ -83,6 +192,117 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::SocksConfig;
+    use crate::services::socks::consts::{SOCKS5_ADDR_TYPE_IPV4, SOCKS5_REPLY_SUCCEEDED};
+    use std::sync::Arc;
+    use tokio::io::{duplex, DuplexStream};
+
+    #[tokio::test]
+    async fn test_associate_reply_carries_relay_addr() {
+        let config = SocksConfig {
+            udp_relay_addr: Some("203.0.113.5:5353".parse().unwrap()),
+            ..Default::default()
+        };
+        let (mut client, server) = duplex(64);
+        let handle = tokio::spawn(async move {
+            handle_udp_associate(
+                server,
+                TargetAddr::Ip("0.0.0.0:0".parse().unwrap()),
+                &config,
+            )
+            .await
+        });
+
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], SOCKS5_REPLY_SUCCEEDED);
+        assert_eq!(reply[3], SOCKS5_ADDR_TYPE_IPV4);
+        assert_eq!(&reply[4..8], &[203, 0, 113, 5]);
+        assert_eq!(u16::from_be_bytes([reply[8], reply[9]]), 5353);
+
+        // Closing the TCP connection ends the association
+        drop(client);
+        assert_eq!(handle.await.unwrap().unwrap(), CloseReason::Completed);
+    }
+
+    /// Start an association and return its client end, reply code and task
+    async fn associate(
+        config: &SocksConfig,
+        associations: &Arc<UdpAssociations>,
+    ) -> (
+        DuplexStream,
+        u8,
+        tokio::task::JoinHandle<Result<CloseReason>>,
+    ) {
+        let (mut client, server) = duplex(64);
+        let config = config.clone();
+        let associations = associations.clone();
+        let handle = tokio::spawn(async move {
+            let addr = TargetAddr::Ip("0.0.0.0:0".parse().unwrap());
+            handle_udp_associate_with(server, addr, &config, &associations).await
+        });
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        (client, reply[1], handle)
+    }
+
+    #[tokio::test]
+    async fn test_associations_beyond_cap_are_refused() {
+        let config = SocksConfig {
+            max_udp_associations: Some(2),
+            ..Default::default()
+        };
+        let associations = Arc::new(UdpAssociations::new());
+
+        let (first, code, first_handle) = associate(&config, &associations).await;
+        assert_eq!(code, SOCKS5_REPLY_SUCCEEDED);
+        let (_second, code, _) = associate(&config, &associations).await;
+        assert_eq!(code, SOCKS5_REPLY_SUCCEEDED);
+        assert_eq!(associations.active(), 2);
+
+        let (_third, code, third_handle) = associate(&config, &associations).await;
+        assert_eq!(code, SOCKS5_REPLY_CONNECTION_NOT_ALLOWED);
+        assert_eq!(third_handle.await.unwrap().unwrap(), CloseReason::Rejected);
+
+        // Ending one association frees its slot
+        drop(first);
+        first_handle.await.unwrap().unwrap();
+        assert_eq!(associations.active(), 1);
+        let (_fourth, code, _) = associate(&config, &associations).await;
+        assert_eq!(code, SOCKS5_REPLY_SUCCEEDED);
+    }
+
+    #[test]
+    fn test_try_acquire_unlimited() {
+        let associations = UdpAssociations::new();
+        let guards: Vec<_> = (0..100)
+            .map(|_| associations.try_acquire(None).unwrap())
+            .collect();
+        assert_eq!(associations.active(), 100);
+        drop(guards);
+        assert_eq!(associations.active(), 0);
+    }
+
+    #[test]
+    fn test_udp_relay_addr_defaults_to_unspecified() {
+        let addr = udp_relay_addr(&SocksConfig::default());
+        assert!(addr.ip().is_unspecified());
+        assert_eq!(addr.port(), 0);
+    }
+
+    #[test]
+    fn test_udp_relay_addr_falls_back_to_advertised() {
+        let advertised: SocketAddr = "203.0.113.7:5353".parse().unwrap();
+        let mut config = SocksConfig {
+            advertised_bind_addr: Some(advertised),
+            ..Default::default()
+        };
+        assert_eq!(udp_relay_addr(&config), advertised);
+
+        let relay: SocketAddr = "198.51.100.1:53".parse().unwrap();
+        config.udp_relay_addr = Some(relay);
+        assert_eq!(udp_relay_addr(&config), relay);
+    }
 
     #[test]
     fn test_virtual_bind_address() {
@@ -0,0 +1,289 @@
+//! Per-source UDP forwarding for the data channel relay
+//!
+//! Each client source seen on a UDP data channel gets its own outbound
+//! sockets, so responses can be matched back to the source they answer and
+//! targets see a stable address for every client.
+
+use super::{encode_udp_packet, UdpPacket, UdpSendQueue};
+use crate::protocol::UdpTraffic;
+use crate::services::socks::types::TargetAddr;
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::OnceCell;
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+/// Most domain names one forwarder remembers for its responses
+const MAX_NAMED_TARGETS: usize = 1024;
+
+/// Domain names datagrams were addressed to, keyed by the address each
+/// resolved to
+type NamedTargets = Arc<Mutex<HashMap<SocketAddr, TargetAddr>>>;
+
+/// Outbound UDP sockets for one client source
+///
+/// IPv4 targets are reached through an IPv4 socket bound up front, IPv6
+/// targets through an IPv6 socket bound on first use. Responses received on
+/// either are wrapped in a SOCKS5 UDP header naming the target that sent
+/// them, in the form the client addressed it (domain name or IP), and
+/// queued as `UdpTraffic` from the client source, which is where the
+/// server delivers them. The forwarder closes itself once neither direction
+/// has seen a datagram for its lifetime.
+#[derive(Debug)]
+pub struct UdpForwarder {
+    responses: Responses,
+    v4: Leg,
+    v6: OnceCell<Leg>,
+}
+
+/// One socket of a forwarder and the task receiving its responses
+#[derive(Debug)]
+struct Leg {
+    socket: Arc<UdpSocket>,
+    /// Receives responses until the forwarder goes idle
+    receiver: JoinHandle<()>,
+}
+
+impl Leg {
+    async fn bind(addr: SocketAddr, responses: &Responses) -> std::io::Result<Self> {
+        let socket = Arc::new(UdpSocket::bind(addr).await?);
+        let receiver = tokio::spawn(receive(socket.clone(), responses.clone()));
+        Ok(Leg { socket, receiver })
+    }
+}
+
+impl Drop for Leg {
+    fn drop(&mut self) {
+        self.receiver.abort();
+    }
+}
+
+/// Where and how a forwarder's responses are queued
+#[derive(Debug, Clone)]
+struct Responses {
+    source: SocketAddr,
+    queue: Arc<UdpSendQueue>,
+    max_datagram: usize,
+    lifetime: Duration,
+    /// When a datagram last went out or came back
+    last_active: Arc<Mutex<Instant>>,
+    named: NamedTargets,
+}
+
+impl UdpForwarder {
+    /// Bind a socket for `source`, queueing its responses on `queue`
+    ///
+    /// Responses larger than `max_datagram` bytes are dropped.
+    pub async fn bind(
+        source: SocketAddr,
+        queue: Arc<UdpSendQueue>,
+        max_datagram: usize,
+        lifetime: Duration,
+    ) -> std::io::Result<Self> {
+        let responses = Responses {
+            source,
+            queue,
+            max_datagram,
+            lifetime,
+            last_active: Arc::new(Mutex::new(Instant::now())),
+            named: NamedTargets::default(),
+        };
+        let v4 = Leg::bind((Ipv4Addr::UNSPECIFIED, 0).into(), &responses).await?;
+        Ok(UdpForwarder {
+            responses,
+            v4,
+            v6: OnceCell::new(),
+        })
+    }
+
+    /// Send `data` to `target`, which the client addressed as `requested`
+    pub async fn send_to(
+        &self,
+        data: &[u8],
+        target: SocketAddr,
+        requested: &TargetAddr,
+    ) -> std::io::Result<usize> {
+        *self.responses.last_active.lock().unwrap() = Instant::now();
+        if let TargetAddr::Domain(..) = requested {
+            let mut named = self.responses.named.lock().unwrap();
+            if named.len() < MAX_NAMED_TARGETS || named.contains_key(&target) {
+                named.insert(target, requested.clone());
+            }
+        }
+        let leg = match target {
+            SocketAddr::V4(_) => &self.v4,
+            SocketAddr::V6(_) => {
+                self.v6
+                    .get_or_try_init(|| {
+                        Leg::bind((Ipv6Addr::UNSPECIFIED, 0).into(), &self.responses)
+                    })
+                    .await?
+            }
+        };
+        leg.socket.send_to(data, target).await
+    }
+
+    /// Whether the forwarder has gone idle and stopped relaying responses
+    pub fn is_closed(&self) -> bool {
+        self.v4.receiver.is_finished()
+            || self.v6.get().is_some_and(|leg| leg.receiver.is_finished())
+    }
+}
+
+/// Queue responses arriving on `socket` until the forwarder has been idle
+/// for its lifetime
+async fn receive(socket: Arc<UdpSocket>, responses: Responses) {
+    let Responses {
+        source,
+        queue,
+        max_datagram,
+        lifetime,
+        last_active,
+        named,
+    } = responses;
+    // One spare byte lets us tell an oversized datagram from one at the cap
+    let mut recv_buf = vec![0u8; max_datagram + 1];
+
+    loop {
+        let idle = last_active.lock().unwrap().elapsed();
+        let Some(remaining) = lifetime.checked_sub(idle).filter(|d| !d.is_zero()) else {
+            debug!(
+                "UDP association for {} idle for {:?}, closing",
+                source, idle
+            );
+            return;
+        };
+
+        match tokio::time::timeout(remaining, socket.recv_from(&mut recv_buf)).await {
+            Ok(Ok((len, from_addr))) if len > max_datagram => {
+                warn!(
+                    "UDP datagram from {} exceeds {} bytes, dropping",
+                    from_addr, max_datagram
+                );
+            }
+            Ok(Ok((len, from_addr))) => {
+                debug!(
+                    "UDP relay: received {} bytes from {} for {}",
+                    len, from_addr, source
+                );
+                *last_active.lock().unwrap() = Instant::now();
+
+                // Wrap response in SOCKS5 UDP format
+                let addr = named
+                    .lock()
+                    .unwrap()
+                    .get(&from_addr)
+                    .cloned()
+                    .unwrap_or_else(|| from_addr.into());
+                let response_packet =
+                    UdpPacket::new(addr, Bytes::copy_from_slice(&recv_buf[..len]));
+                let encoded = encode_udp_packet(&response_packet);
+
+                if !queue.push(UdpTraffic::new(source, Bytes::from(encoded))) {
+                    debug!(
+                        "UDP send queue full, dropped a datagram ({} so far)",
+                        queue.dropped()
+                    );
+                }
+            }
+            Ok(Err(e)) => {
+                warn!("UDP recv error: {}", e);
+            }
+            // Re-checked against the latest activity at the top of the loop
+            Err(_) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::UdpDropPolicy;
+    use crate::services::socks::consts::SOCKS5_ADDR_TYPE_IPV6;
+    use crate::services::socks::udp::parse_udp_packet;
+
+    #[tokio::test]
+    async fn test_forwarder_closes_when_idle() {
+        let queue = Arc::new(UdpSendQueue::new(4, UdpDropPolicy::default()));
+        let forwarder = UdpForwarder::bind(
+            "127.0.0.1:5555".parse().unwrap(),
+            queue,
+            1500,
+            Duration::from_millis(50),
+        )
+        .await
+        .unwrap();
+        assert!(!forwarder.is_closed());
+
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while !forwarder.is_closed() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("an idle forwarder should close");
+    }
+
+    #[tokio::test]
+    async fn test_forwarder_tags_responses_with_source() {
+        let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let source: SocketAddr = "203.0.113.7:5353".parse().unwrap();
+        let queue = Arc::new(UdpSendQueue::new(4, UdpDropPolicy::default()));
+        let forwarder = UdpForwarder::bind(source, queue.clone(), 1500, Duration::from_secs(5))
+            .await
+            .unwrap();
+
+        forwarder
+            .send_to(b"ping", target_addr, &target_addr.into())
+            .await
+            .unwrap();
+        let mut buf = [0u8; 16];
+        let (len, from) = target.recv_from(&mut buf).await.unwrap();
+        target.send_to(&buf[..len], from).await.unwrap();
+
+        let response = tokio::time::timeout(Duration::from_secs(2), queue.pop())
+            .await
+            .unwrap();
+        assert_eq!(response.from, source);
+        let packet = parse_udp_packet(&response.data).unwrap();
+        assert_eq!(packet.addr, target_addr.into());
+        assert_eq!(packet.data, Bytes::from_static(b"ping"));
+    }
+
+    #[tokio::test]
+    async fn test_forwarder_relays_ipv6_target() {
+        // Hosts without IPv6 loopback have nothing to test against
+        let Ok(target) = UdpSocket::bind("[::1]:0").await else {
+            return;
+        };
+        let target_addr = target.local_addr().unwrap();
+        let source: SocketAddr = "203.0.113.7:5353".parse().unwrap();
+        let queue = Arc::new(UdpSendQueue::new(4, UdpDropPolicy::default()));
+        let forwarder = UdpForwarder::bind(source, queue.clone(), 1500, Duration::from_secs(5))
+            .await
+            .unwrap();
+
+        forwarder
+            .send_to(b"ping6", target_addr, &target_addr.into())
+            .await
+            .unwrap();
+        let mut buf = [0u8; 16];
+        let (len, from) = target.recv_from(&mut buf).await.unwrap();
+        assert!(from.is_ipv6());
+        target.send_to(&buf[..len], from).await.unwrap();
+
+        let response = tokio::time::timeout(Duration::from_secs(2), queue.pop())
+            .await
+            .unwrap();
+        assert_eq!(response.data[3], SOCKS5_ADDR_TYPE_IPV6);
+        let packet = parse_udp_packet(&response.data).unwrap();
+        assert_eq!(packet.addr, target_addr.into());
+        assert_eq!(packet.data, Bytes::from_static(b"ping6"));
+        assert!(!forwarder.is_closed());
+    }
+}
@@ -4,9 +4,13 @@
 //! Also handles UDP data channel relay (when rathole sends `StartForwardUdp`).
 
 mod associate;
+mod forwarder;
 mod packet;
+mod queue;
 mod relay;
 
-pub use associate::handle_udp_associate;
+pub use associate::{handle_udp_associate, UdpAssociationGuard, UdpAssociations};
+pub use forwarder::UdpForwarder;
 pub use packet::{encode_udp_packet, parse_udp_packet, UdpPacket};
+pub use queue::UdpSendQueue;
 pub use relay::UdpRelay;
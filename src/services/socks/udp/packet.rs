@@ -108,8 +108,17 @@ fn parse_address_from_buf(atyp: u8, mut buf: &[u8]) -> Result<(TargetAddr, &[u8]
             let len = buf[0] as usize;
             buf = &buf[1..];
 
+            if len == 0 {
+                bail!("Invalid domain length: 0");
+            }
+            // The declared length comes from the sender, so check it
+            // against what actually arrived before slicing
             if buf.len() < len + 2 {
-                bail!("Buffer too short for domain name");
+                bail!(
+                    "Buffer too short for domain name: declared {} bytes plus port, {} remaining",
+                    len,
+                    buf.len()
+                );
             }
             let domain = String::from_utf8(buf[..len].to_vec())
                 .with_context(|| "Invalid UTF-8 in domain")?;
@@ -271,6 +280,30 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_parse_udp_packet_truncated_domain() {
+        let encoded = encode_udp_packet(&UdpPacket::new(
+            TargetAddr::domain("example.com".to_string(), 53),
+            Bytes::from_static(b"query"),
+        ));
+
+        // Cut inside the domain name, then inside the port
+        for len in [8, 17] {
+            let err = parse_udp_packet(&encoded[..len]).unwrap_err();
+            assert!(err.to_string().contains("too short for domain name"));
+        }
+
+        // A length byte claiming more than the whole packet
+        let err = parse_udp_packet(&[0, 0, 0, SOCKS5_ADDR_TYPE_DOMAIN, 255, b'a']).unwrap_err();
+        assert!(err.to_string().contains("declared 255 bytes"));
+    }
+
+    #[test]
+    fn test_parse_udp_packet_empty_domain() {
+        let result = parse_udp_packet(&[0, 0, 0, SOCKS5_ADDR_TYPE_DOMAIN, 0, 0, 53]);
+        assert!(result.unwrap_err().to_string().contains("domain length"));
+    }
+
     #[test]
     fn test_parse_udp_packet_invalid_rsv() {
         let mut data = encode_udp_packet(&UdpPacket::new(
@@ -0,0 +1,157 @@
+//! Bounded queue of UDP datagrams waiting to be written to the tunnel
+//!
+//! Target responses can arrive faster than the tunnel drains them. Rather
+//! than buffering without limit, the queue holds a fixed number of
+//! datagrams and drops one per [`UdpDropPolicy`] when full.
+
+use crate::config::UdpDropPolicy;
+use crate::protocol::UdpTraffic;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+/// Bounded, single-consumer queue of datagrams bound for the tunnel
+#[derive(Debug)]
+pub struct UdpSendQueue {
+    /// Queued datagrams, oldest first
+    items: Mutex<VecDeque<UdpTraffic>>,
+    /// Wakes the consumer when a datagram is queued
+    ready: Notify,
+    /// Maximum number of queued datagrams
+    depth: usize,
+    /// Which datagram to drop when the queue is full
+    policy: UdpDropPolicy,
+    /// Number of datagrams dropped because the queue was full
+    dropped: Arc<AtomicU64>,
+}
+
+impl UdpSendQueue {
+    /// Create a queue holding at most `depth` datagrams (at least one)
+    pub fn new(depth: usize, policy: UdpDropPolicy) -> Self {
+        let depth = depth.max(1);
+        UdpSendQueue {
+            items: Mutex::new(VecDeque::with_capacity(depth)),
+            ready: Notify::new(),
+            depth,
+            policy,
+            dropped: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Count dropped datagrams in `counter` instead of a private one
+    pub fn with_drop_counter(mut self, counter: Arc<AtomicU64>) -> Self {
+        self.dropped = counter;
+        self
+    }
+
+    /// Queue a datagram for the tunnel.
+    ///
+    /// Returns `false` if the queue was full and a datagram (the oldest
+    /// queued one or `traffic` itself, per the policy) was dropped.
+    pub fn push(&self, traffic: UdpTraffic) -> bool {
+        let mut items = self.items.lock().unwrap();
+        let accepted = if items.len() < self.depth {
+            items.push_back(traffic);
+            true
+        } else {
+            if self.policy == UdpDropPolicy::DropOldest {
+                items.pop_front();
+                items.push_back(traffic);
+            }
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            false
+        };
+        drop(items);
+        self.ready.notify_one();
+        accepted
+    }
+
+    /// Wait for and remove the oldest queued datagram
+    pub async fn pop(&self) -> UdpTraffic {
+        loop {
+            if let Some(traffic) = self.items.lock().unwrap().pop_front() {
+                return traffic;
+            }
+            self.ready.notified().await;
+        }
+    }
+
+    /// Number of queued datagrams
+    pub fn len(&self) -> usize {
+        self.items.lock().unwrap().len()
+    }
+
+    /// Whether no datagrams are queued
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of datagrams dropped because the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn datagram(tag: u8) -> UdpTraffic {
+        UdpTraffic::new("127.0.0.1:53".parse().unwrap(), Bytes::from(vec![tag]))
+    }
+
+    async fn drain(queue: &UdpSendQueue) -> Vec<u8> {
+        let mut tags = Vec::new();
+        while !queue.is_empty() {
+            tags.push(queue.pop().await.data[0]);
+        }
+        tags
+    }
+
+    #[tokio::test]
+    async fn test_overflow_drops_oldest() {
+        let queue = UdpSendQueue::new(2, UdpDropPolicy::DropOldest);
+        assert!(queue.push(datagram(1)));
+        assert!(queue.push(datagram(2)));
+        assert!(!queue.push(datagram(3)));
+
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(drain(&queue).await, vec![2, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_overflow_drops_newest() {
+        let queue = UdpSendQueue::new(2, UdpDropPolicy::DropNewest);
+        assert!(queue.push(datagram(1)));
+        assert!(queue.push(datagram(2)));
+        assert!(!queue.push(datagram(3)));
+        assert!(!queue.push(datagram(4)));
+
+        assert_eq!(queue.dropped(), 2);
+        assert_eq!(drain(&queue).await, vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_shared_drop_counter() {
+        let counter = Arc::new(AtomicU64::new(5));
+        let queue =
+            UdpSendQueue::new(1, UdpDropPolicy::DropNewest).with_drop_counter(counter.clone());
+        queue.push(datagram(1));
+        queue.push(datagram(2));
+        assert_eq!(counter.load(Ordering::Relaxed), 6);
+    }
+
+    #[tokio::test]
+    async fn test_pop_waits_for_push() {
+        let queue = Arc::new(UdpSendQueue::new(4, UdpDropPolicy::DropOldest));
+        let consumer = {
+            let queue = queue.clone();
+            tokio::spawn(async move { queue.pop().await })
+        };
+        tokio::task::yield_now().await;
+        queue.push(datagram(9));
+        assert_eq!(consumer.await.unwrap().data[0], 9);
+    }
+}
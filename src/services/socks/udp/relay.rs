@@ -4,19 +4,29 @@
 //! UDP destinations. Reads SOCKS5-encapsulated UDP packets from the tunnel,
 //! forwards payload to the real destination, and sends responses back.
 
-use super::{encode_udp_packet, parse_udp_packet, UdpPacket};
+use super::{parse_udp_packet, UdpForwarder, UdpSendQueue};
+use crate::config::{AclConfig, UdpDropPolicy};
 use crate::protocol::UdpTraffic;
+use crate::services::socks::dns::DnsCache;
+use crate::services::socks::policy::{is_private_ip, target_permitted};
 use anyhow::{Context, Result};
-use bytes::Bytes;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
-use tokio::net::UdpSocket;
+use tokio::time::MissedTickBehavior;
 use tracing::{debug, warn};
 
 /// Default UDP relay timeout in seconds
 const UDP_RELAY_TIMEOUT_SECS: u64 = 120;
 
-/// Maximum UDP packet size
-const MAX_UDP_PACKET: usize = 65535;
+/// Default maximum UDP datagram payload size (largest IPv4 UDP payload)
+const DEFAULT_MAX_DATAGRAM: usize = 65507;
+
+/// Default number of responses queued for the tunnel
+const DEFAULT_QUEUE_DEPTH: usize = 128;
 
 /// Relay UDP traffic between a tunnel stream and real UDP destinations.
 ///
@@ -24,57 +34,172 @@ const MAX_UDP_PACKET: usize = 65535;
 ///
 /// 1. Read `UdpTraffic` from the tunnel (rathole framing)
 /// 2. Parse the inner SOCKS5 UDP header to extract destination + payload
-/// 3. Send payload to the real destination via a bound UDP socket
-/// 4. Receive response from the destination
-/// 5. Wrap response in a SOCKS5 UDP header and write as `UdpTraffic`
+/// 3. Send payload to the real destination via the [`UdpForwarder`] of
+///    the frame's client source
+/// 4. Receive responses from destinations into a bounded send queue
+/// 5. Wrap each response in a SOCKS5 UDP header and write it as
+///    `UdpTraffic` from the client source it answers
+///
+/// Forwarding, receiving and writing back run concurrently, so a slow
+/// tunnel only ever holds up to the queue depth of responses.
 pub struct UdpRelay {
-    /// Timeout for individual UDP exchanges
+    /// Seconds a client source's association may sit idle before its
+    /// forwarder is closed
     timeout_secs: u64,
+    /// Largest datagram payload relayed in either direction
+    max_datagram: usize,
+    /// How long successful target lookups are cached
+    dns_cache_ttl: Duration,
+    /// How long failed target lookups are cached
+    dns_negative_ttl: Duration,
+    /// Cache (and resolver) for target lookups
+    dns_cache: Arc<DnsCache>,
+    /// Maximum number of responses queued for the tunnel
+    queue_depth: usize,
+    /// Which response to drop when the queue is full
+    drop_policy: UdpDropPolicy,
+    /// Responses dropped because the send queue was full
+    dropped: Arc<AtomicU64>,
+    /// Drop datagrams to private network destinations
+    block_private_networks: bool,
+    /// Destinations datagrams may be sent to
+    acl: AclConfig,
+    /// Client sources with a live forwarder
+    associations: Arc<AtomicUsize>,
 }
 
 impl UdpRelay {
-    /// Create a new UDP relay with the default timeout.
+    /// Create a new UDP relay with the default association timeout.
     pub fn new() -> Self {
         UdpRelay {
             timeout_secs: UDP_RELAY_TIMEOUT_SECS,
+            max_datagram: DEFAULT_MAX_DATAGRAM,
+            dns_cache_ttl: Duration::ZERO,
+            dns_negative_ttl: Duration::ZERO,
+            dns_cache: Arc::new(DnsCache::new()),
+            queue_depth: DEFAULT_QUEUE_DEPTH,
+            drop_policy: UdpDropPolicy::default(),
+            dropped: Arc::new(AtomicU64::new(0)),
+            block_private_networks: false,
+            acl: AclConfig::default(),
+            associations: Arc::new(AtomicUsize::new(0)),
         }
     }
 
-    /// Set a custom timeout in seconds.
+    /// Set how long, in seconds, a client source's association may see no
+    /// datagrams in either direction before it is closed.
     pub fn with_timeout(mut self, secs: u64) -> Self {
         self.timeout_secs = secs;
         self
     }
 
+    /// Set the maximum datagram payload size in bytes.
+    ///
+    /// Datagrams larger than this are dropped (and logged) instead of
+    /// being truncated.
+    pub fn with_max_datagram(mut self, max_datagram: usize) -> Self {
+        self.max_datagram = max_datagram;
+        self
+    }
+
+    /// Set how long, in seconds, successful and failed target DNS lookups
+    /// are cached. Zero disables caching.
+    pub fn with_dns_cache_ttls(mut self, ttl_secs: u64, negative_ttl_secs: u64) -> Self {
+        self.dns_cache_ttl = Duration::from_secs(ttl_secs);
+        self.dns_negative_ttl = Duration::from_secs(negative_ttl_secs);
+        self
+    }
+
+    /// Resolve target domains through `cache` instead of a private system
+    /// resolver cache
+    pub fn with_dns_cache(mut self, cache: Arc<DnsCache>) -> Self {
+        self.dns_cache = cache;
+        self
+    }
+
+    /// Set how many responses may wait for the tunnel, and which one is
+    /// dropped when that many are already queued.
+    pub fn with_send_queue(mut self, depth: usize, policy: UdpDropPolicy) -> Self {
+        self.queue_depth = depth;
+        self.drop_policy = policy;
+        self
+    }
+
+    /// Drop datagrams whose destination resolves to a private network
+    /// address (see [`is_private_ip`]).
+    pub fn with_block_private_networks(mut self, block: bool) -> Self {
+        self.block_private_networks = block;
+        self
+    }
+
+    /// Drop datagrams to destinations `acl` refuses, checking domain rules
+    /// before the lookup and address rules on the resolved address.
+    pub fn with_acl(mut self, acl: AclConfig) -> Self {
+        self.acl = acl;
+        self
+    }
+
+    /// Number of responses dropped so far because the send queue was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Number of client sources whose association is currently open
+    pub fn associations(&self) -> usize {
+        self.associations.load(Ordering::Relaxed)
+    }
+
     /// Run the relay loop on the given tunnel stream.
     ///
     /// Reads `UdpTraffic` frames, forwards to UDP destinations, and writes
     /// responses back. Terminates on stream EOF or error.
-    pub async fn run<S>(&self, mut stream: S) -> Result<()>
+    pub async fn run<S>(&self, stream: S) -> Result<()>
     where
         S: AsyncRead + AsyncWrite + Unpin + Send,
     {
-        // Bind a single outbound UDP socket for all relay traffic
-        let socket = UdpSocket::bind("0.0.0.0:0")
-            .await
-            .context("Failed to bind UDP relay socket")?;
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let queue = Arc::new(
+            UdpSendQueue::new(self.queue_depth, self.drop_policy)
+                .with_drop_counter(self.dropped.clone()),
+        );
 
-        let timeout = std::time::Duration::from_secs(self.timeout_secs);
-        let mut recv_buf = vec![0u8; MAX_UDP_PACKET];
+        tokio::select! {
+            result = self.forward(&mut reader, &queue) => result,
+            result = Self::write_back(&queue, &mut writer) => result,
+        }
+    }
+
+    /// Forward datagrams from the tunnel to their destinations until EOF
+    async fn forward<R>(&self, reader: &mut R, queue: &Arc<UdpSendQueue>) -> Result<()>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut forwarders: HashMap<SocketAddr, UdpForwarder> = HashMap::new();
+        // Idle forwarders stop receiving on their own; this drops their
+        // sockets even when no new datagrams arrive
+        let mut reap = tokio::time::interval(Duration::from_secs(self.timeout_secs.max(1)));
+        reap.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
         loop {
             // Read the header length prefix from the tunnel
-            let hdr_len = match stream.read_u8().await {
+            let read = tokio::select! {
+                read = reader.read_u8() => read,
+                _ = reap.tick() => {
+                    self.reap(&mut forwarders);
+                    continue;
+                }
+            };
+            let hdr_len = match read {
                 Ok(len) => len,
                 Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
                     debug!("UDP tunnel stream closed");
-                    break;
+                    return Ok(());
                 }
                 Err(e) => return Err(e.into()),
             };
 
             // Read the UdpTraffic frame
-            let traffic = UdpTraffic::read(&mut stream, hdr_len)
+            let traffic = UdpTraffic::read(reader, hdr_len)
                 .await
                 .context("Failed to read UdpTraffic")?;
 
@@ -92,17 +217,71 @@ impl UdpRelay {
                 continue;
             }
 
+            if socks_packet.data.len() > self.max_datagram {
+                warn!(
+                    "UDP datagram to {} too large ({} > {} bytes), dropping",
+                    socks_packet.addr,
+                    socks_packet.data.len(),
+                    self.max_datagram
+                );
+                continue;
+            }
+
+            if !target_permitted(&self.acl, &socks_packet.addr, None) {
+                warn!(
+                    "UDP datagram to {} denied by ACL, dropping",
+                    socks_packet.addr
+                );
+                continue;
+            }
+
             // Resolve target address
-            let target_addr = match socks_packet.addr.resolve().await {
-                Ok(addr) => addr,
+            let addrs = match socks_packet
+                .addr
+                .resolve_all_cached(&self.dns_cache, self.dns_cache_ttl, self.dns_negative_ttl)
+                .await
+            {
+                Ok(addrs) => addrs,
                 Err(e) => {
                     warn!("Failed to resolve UDP target: {}", e);
                     continue;
                 }
             };
+            let public: Vec<SocketAddr> = addrs
+                .into_iter()
+                .filter(|addr| !self.block_private_networks || !is_private_ip(addr.ip()))
+                .collect();
+            if public.is_empty() {
+                warn!(
+                    "UDP datagram to private address {} blocked, dropping",
+                    socks_packet.addr
+                );
+                continue;
+            }
+            let Some(target_addr) = public
+                .into_iter()
+                .find(|addr| target_permitted(&self.acl, &socks_packet.addr, Some(addr.ip())))
+            else {
+                warn!(
+                    "UDP datagram to {} denied by ACL, dropping",
+                    socks_packet.addr
+                );
+                continue;
+            };
+
+            let forwarder = match self.forwarder(&mut forwarders, traffic.from, queue).await {
+                Ok(forwarder) => forwarder,
+                Err(e) => {
+                    warn!("Failed to bind UDP socket for {}: {}", traffic.from, e);
+                    continue;
+                }
+            };
 
             // Forward payload to target
-            if let Err(e) = socket.send_to(&socks_packet.data, target_addr).await {
+            if let Err(e) = forwarder
+                .send_to(&socks_packet.data, target_addr, &socks_packet.addr)
+                .await
+            {
                 warn!("UDP send to {} failed: {}", target_addr, e);
                 continue;
             }
@@ -112,34 +291,59 @@ impl UdpRelay {
                 socks_packet.data.len(),
                 target_addr
             );
+        }
+    }
 
-            // Wait for response with timeout
-            match tokio::time::timeout(timeout, socket.recv_from(&mut recv_buf)).await {
-                Ok(Ok((len, from_addr))) => {
-                    debug!("UDP relay: received {} bytes from {}", len, from_addr);
-
-                    // Wrap response in SOCKS5 UDP format
-                    let response_packet =
-                        UdpPacket::new(from_addr.into(), Bytes::copy_from_slice(&recv_buf[..len]));
-                    let encoded = encode_udp_packet(&response_packet);
-
-                    // Write back as UdpTraffic
-                    let response_traffic = UdpTraffic::new(from_addr, Bytes::from(encoded));
-                    response_traffic
-                        .write(&mut stream)
-                        .await
-                        .context("Failed to write UDP response")?;
-                }
-                Ok(Err(e)) => {
-                    warn!("UDP recv error: {}", e);
-                }
-                Err(_) => {
-                    debug!("UDP response timeout for {}", target_addr);
-                }
-            }
+    /// The live forwarder for `source`, binding a new one if it has none
+    /// or its association timed out
+    async fn forwarder<'a>(
+        &self,
+        forwarders: &'a mut HashMap<SocketAddr, UdpForwarder>,
+        source: SocketAddr,
+        queue: &Arc<UdpSendQueue>,
+    ) -> std::io::Result<&'a UdpForwarder> {
+        if forwarders.get(&source).is_none_or(UdpForwarder::is_closed) {
+            // Forget every association that timed out, not just this one
+            self.reap(forwarders);
+            let forwarder = UdpForwarder::bind(
+                source,
+                queue.clone(),
+                self.max_datagram,
+                Duration::from_secs(self.timeout_secs),
+            )
+            .await?;
+            debug!("UDP association opened for {}", source);
+            forwarders.insert(source, forwarder);
+            self.associations.store(forwarders.len(), Ordering::Relaxed);
         }
+        Ok(&forwarders[&source])
+    }
 
-        Ok(())
+    /// Close the forwarders whose association timed out
+    fn reap(&self, forwarders: &mut HashMap<SocketAddr, UdpForwarder>) {
+        forwarders.retain(|source, forwarder| {
+            let closed = forwarder.is_closed();
+            if closed {
+                debug!("UDP association closed for {}", source);
+            }
+            !closed
+        });
+        self.associations.store(forwarders.len(), Ordering::Relaxed);
+    }
+
+    /// Write queued responses back to the tunnel as `UdpTraffic`
+    async fn write_back<W>(queue: &UdpSendQueue, writer: &mut W) -> Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        loop {
+            queue
+                .pop()
+                .await
+                .write(writer)
+                .await
+                .context("Failed to write UDP response")?;
+        }
     }
 }
 
@@ -153,7 +357,12 @@ impl Default for UdpRelay {
 mod tests {
     use super::*;
     use crate::services::socks::types::TargetAddr;
+    use crate::services::socks::udp::{encode_udp_packet, UdpPacket};
+    use bytes::Bytes;
+    use futures::future::BoxFuture;
+    use std::io;
     use std::net::{Ipv4Addr, SocketAddr};
+    use tokio::net::UdpSocket;
 
     #[test]
     fn test_udp_relay_new() {
@@ -171,6 +380,21 @@ mod tests {
     fn test_udp_relay_default() {
         let relay = UdpRelay::default();
         assert_eq!(relay.timeout_secs, UDP_RELAY_TIMEOUT_SECS);
+        assert_eq!(relay.max_datagram, DEFAULT_MAX_DATAGRAM);
+    }
+
+    #[test]
+    fn test_udp_relay_with_max_datagram() {
+        let relay = UdpRelay::new().with_max_datagram(1400);
+        assert_eq!(relay.max_datagram, 1400);
+    }
+
+    #[test]
+    fn test_udp_relay_with_send_queue() {
+        let relay = UdpRelay::new().with_send_queue(8, UdpDropPolicy::DropNewest);
+        assert_eq!(relay.queue_depth, 8);
+        assert_eq!(relay.drop_policy, UdpDropPolicy::DropNewest);
+        assert_eq!(relay.dropped(), 0);
     }
 
     #[tokio::test]
@@ -219,13 +443,8 @@ mod tests {
         // Spawn echo server
         tokio::spawn(async move {
             let mut buf = [0u8; 65535];
-            loop {
-                match echo_socket.recv_from(&mut buf).await {
-                    Ok((len, from)) => {
-                        let _ = echo_socket.send_to(&buf[..len], from).await;
-                    }
-                    Err(_) => break,
-                }
+            while let Ok((len, from)) = echo_socket.recv_from(&mut buf).await {
+                let _ = echo_socket.send_to(&buf[..len], from).await;
             }
         });
 
@@ -242,7 +461,7 @@ mod tests {
             echo_addr.ip().to_string().parse::<Ipv4Addr>().unwrap(),
             echo_addr.port(),
         );
-        let socks_pkt = UdpPacket::new(target, Bytes::from_static(b"hello echo"));
+        let socks_pkt = UdpPacket::new(target.clone(), Bytes::from_static(b"hello echo"));
         let encoded = encode_udp_packet(&socks_pkt);
 
         // Write as UdpTraffic
@@ -257,9 +476,218 @@ mod tests {
             .unwrap();
 
         let response = UdpTraffic::read(&mut read_half, hdr_len).await.unwrap();
+        // Addressed to the client source, for the server to deliver
+        assert_eq!(response.from, "127.0.0.1:5555".parse().unwrap());
 
         // Parse the SOCKS5 response
         let resp_pkt = parse_udp_packet(&response.data).unwrap();
+        assert_eq!(resp_pkt.addr, target);
         assert_eq!(resp_pkt.data, Bytes::from_static(b"hello echo"));
     }
+
+    /// Spawn a UDP echo server, returning its address as a SOCKS target
+    async fn spawn_echo_server() -> TargetAddr {
+        let echo_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo_socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 65535];
+            while let Ok((len, from)) = echo_socket.recv_from(&mut buf).await {
+                let _ = echo_socket.send_to(&buf[..len], from).await;
+            }
+        });
+        TargetAddr::from(echo_addr)
+    }
+
+    /// Write a SOCKS5 UDP datagram for `target` into the tunnel stream
+    async fn send_datagram<W: AsyncWrite + Unpin>(
+        writer: &mut W,
+        target: &TargetAddr,
+        data: &[u8],
+    ) {
+        let socks_pkt = UdpPacket::new(target.clone(), Bytes::copy_from_slice(data));
+        let encoded = encode_udp_packet(&socks_pkt);
+        let traffic = UdpTraffic::new("127.0.0.1:5555".parse().unwrap(), Bytes::from(encoded));
+        traffic.write(writer).await.unwrap();
+    }
+
+    /// Resolve every name to loopback, keeping the port
+    fn resolve_to_loopback(name: String) -> BoxFuture<'static, io::Result<Vec<SocketAddr>>> {
+        let port = name.rsplit(':').next().unwrap().parse().unwrap();
+        Box::pin(async move { Ok(vec![SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port)]) })
+    }
+
+    #[tokio::test]
+    async fn test_udp_relay_resolves_domain_and_echoes_it() {
+        let echo = spawn_echo_server().await;
+        let target = TargetAddr::domain("echo.test".to_string(), echo.port());
+        let (writer, reader) = tokio::io::duplex(65536);
+        let _relay_handle = tokio::spawn(async move {
+            let relay = UdpRelay::new()
+                .with_timeout(2)
+                .with_dns_cache(Arc::new(DnsCache::with_resolver(resolve_to_loopback)));
+            relay.run(reader).await
+        });
+
+        let (mut read_half, mut write_half) = tokio::io::split(writer);
+        send_datagram(&mut write_half, &target, b"by name").await;
+
+        let hdr_len = tokio::time::timeout(std::time::Duration::from_secs(2), read_half.read_u8())
+            .await
+            .unwrap()
+            .unwrap();
+        let response = UdpTraffic::read(&mut read_half, hdr_len).await.unwrap();
+        let resp_pkt = parse_udp_packet(&response.data).unwrap();
+        // The reply names the target the way the client did
+        assert_eq!(resp_pkt.addr, target);
+        assert_eq!(resp_pkt.data, Bytes::from_static(b"by name"));
+    }
+
+    #[tokio::test]
+    async fn test_udp_relay_reaps_idle_association() {
+        let target = spawn_echo_server().await;
+        let (writer, reader) = tokio::io::duplex(65536);
+        let relay = Arc::new(UdpRelay::new().with_timeout(1));
+        let runner = relay.clone();
+        let _relay_handle = tokio::spawn(async move { runner.run(reader).await });
+
+        let (mut read_half, mut write_half) = tokio::io::split(writer);
+        send_datagram(&mut write_half, &target, b"once").await;
+        tokio::time::timeout(std::time::Duration::from_secs(2), read_half.read_u8())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(relay.associations(), 1);
+
+        // No more datagrams: the association is dropped without new traffic
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while relay.associations() > 0 {
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("an idle association should be reaped");
+    }
+
+    #[tokio::test]
+    async fn test_udp_relay_datagram_at_cap_forwarded() {
+        let target = spawn_echo_server().await;
+        let (writer, reader) = tokio::io::duplex(65536);
+        let _relay_handle = tokio::spawn(async move {
+            let relay = UdpRelay::new().with_timeout(2).with_max_datagram(32);
+            relay.run(reader).await
+        });
+
+        let (mut read_half, mut write_half) = tokio::io::split(writer);
+        let payload = [0x5A; 32];
+        send_datagram(&mut write_half, &target, &payload).await;
+
+        let hdr_len = tokio::time::timeout(std::time::Duration::from_secs(2), read_half.read_u8())
+            .await
+            .unwrap()
+            .unwrap();
+        let response = UdpTraffic::read(&mut read_half, hdr_len).await.unwrap();
+        let resp_pkt = parse_udp_packet(&response.data).unwrap();
+        assert_eq!(resp_pkt.data.as_ref(), &payload[..]);
+    }
+
+    #[tokio::test]
+    async fn test_udp_relay_datagram_over_cap_dropped() {
+        let target = spawn_echo_server().await;
+        let (writer, reader) = tokio::io::duplex(65536);
+        let _relay_handle = tokio::spawn(async move {
+            let relay = UdpRelay::new().with_timeout(2).with_max_datagram(32);
+            relay.run(reader).await
+        });
+
+        let (mut read_half, mut write_half) = tokio::io::split(writer);
+
+        // Oversized datagram is dropped, the following one still goes through
+        send_datagram(&mut write_half, &target, &[0xEE; 33]).await;
+        send_datagram(&mut write_half, &target, b"small").await;
+
+        let hdr_len = tokio::time::timeout(std::time::Duration::from_secs(2), read_half.read_u8())
+            .await
+            .unwrap()
+            .unwrap();
+        let response = UdpTraffic::read(&mut read_half, hdr_len).await.unwrap();
+        let resp_pkt = parse_udp_packet(&response.data).unwrap();
+        assert_eq!(resp_pkt.data, Bytes::from_static(b"small"));
+    }
+
+    #[tokio::test]
+    async fn test_udp_relay_blocks_private_target() {
+        // The echo server listens on loopback, which is a private address
+        let target = spawn_echo_server().await;
+        let (writer, reader) = tokio::io::duplex(65536);
+        let _relay_handle = tokio::spawn(async move {
+            let relay = UdpRelay::new().with_block_private_networks(true);
+            relay.run(reader).await
+        });
+
+        let (mut read_half, mut write_half) = tokio::io::split(writer);
+        send_datagram(&mut write_half, &target, b"blocked").await;
+
+        let response =
+            tokio::time::timeout(std::time::Duration::from_millis(200), read_half.read_u8()).await;
+        assert!(response.is_err(), "blocked datagram must not be relayed");
+    }
+
+    #[tokio::test]
+    async fn test_udp_relay_applies_acl_per_datagram() {
+        use crate::config::AclRule;
+
+        let denied = spawn_echo_server().await;
+        let allowed = spawn_echo_server().await;
+        let acl = AclConfig {
+            deny: vec![AclRule {
+                ports: Some((denied.port(), denied.port())),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let (writer, reader) = tokio::io::duplex(65536);
+        let _relay_handle = tokio::spawn(async move {
+            let relay = UdpRelay::new().with_acl(acl);
+            relay.run(reader).await
+        });
+
+        let (mut read_half, mut write_half) = tokio::io::split(writer);
+        send_datagram(&mut write_half, &denied, b"denied").await;
+        send_datagram(&mut write_half, &allowed, b"allowed").await;
+
+        // Only the permitted destination answers
+        let hdr_len = tokio::time::timeout(std::time::Duration::from_secs(2), read_half.read_u8())
+            .await
+            .unwrap()
+            .unwrap();
+        let response = UdpTraffic::read(&mut read_half, hdr_len).await.unwrap();
+        let resp_pkt = parse_udp_packet(&response.data).unwrap();
+        assert_eq!(resp_pkt.data, Bytes::from_static(b"allowed"));
+        let more =
+            tokio::time::timeout(std::time::Duration::from_millis(200), read_half.read_u8()).await;
+        assert!(more.is_err(), "denied datagram must not be relayed");
+    }
+
+    #[tokio::test]
+    async fn test_udp_relay_counts_responses_dropped_by_slow_tunnel() {
+        let target = spawn_echo_server().await;
+        // A tunnel buffer far smaller than the responses, never read from
+        let (writer, reader) = tokio::io::duplex(64);
+        let relay = Arc::new(UdpRelay::new().with_send_queue(1, UdpDropPolicy::DropNewest));
+        let runner = relay.clone();
+        let _relay_handle = tokio::spawn(async move { runner.run(reader).await });
+
+        let (_read_half, mut write_half) = tokio::io::split(writer);
+        for _ in 0..20 {
+            send_datagram(&mut write_half, &target, b"flood").await;
+        }
+
+        tokio::time::timeout(std::time::Duration::from_secs(2), async {
+            while relay.dropped() == 0 {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("overflowing responses should be dropped and counted");
+    }
 }
@@ -1,16 +1,28 @@
 //! Authorized keys file parser
 //!
 //! Parses OpenSSH authorized_keys format files.
+//!
+//! Loading is bounded by a maximum file size and key count so that a huge
+//! or hostile file cannot exhaust memory or slow down authentication.
+//! Malformed lines are skipped with a warning.
 
 #[cfg(feature = "ssh")]
 use anyhow::{Context, Result};
 use std::collections::HashMap;
 #[cfg(feature = "ssh")]
+use std::io::Read;
+#[cfg(feature = "ssh")]
 use std::path::Path;
 
 #[cfg(feature = "ssh")]
 use russh::keys::{HashAlg, PublicKey};
 
+/// Default maximum authorized_keys file size (1 MiB)
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 1024 * 1024;
+
+/// Default maximum number of authorized keys
+pub const DEFAULT_MAX_ENTRIES: usize = 1024;
+
 /// An entry in an authorized_keys file
 #[derive(Debug, Clone)]
 pub struct AuthorizedKey {
@@ -38,18 +50,58 @@ impl AuthorizedKeys {
         Self::default()
     }
 
-    /// Load authorized keys from a file
+    /// Load authorized keys from a file using the default limits
     #[cfg(feature = "ssh")]
     pub fn from_file(path: &Path) -> Result<Self> {
-        let content = std::fs::read_to_string(path)
+        Self::from_file_with_limits(path, DEFAULT_MAX_FILE_SIZE, DEFAULT_MAX_ENTRIES)
+    }
+
+    /// Load authorized keys from a file of at most `max_bytes` bytes
+    /// holding at most `max_entries` keys
+    #[cfg(feature = "ssh")]
+    pub fn from_file_with_limits(path: &Path, max_bytes: u64, max_entries: usize) -> Result<Self> {
+        let file = std::fs::File::open(path)
             .with_context(|| format!("Failed to read authorized_keys from {:?}", path))?;
 
-        Self::parse(&content)
+        let size = file
+            .metadata()
+            .with_context(|| format!("Failed to stat authorized_keys {:?}", path))?
+            .len();
+        if size > max_bytes {
+            anyhow::bail!(
+                "authorized_keys file {:?} is {} bytes, exceeding the {} byte limit",
+                path,
+                size,
+                max_bytes
+            );
+        }
+
+        // Bound the read as well, in case the file grows after the stat
+        let mut content = String::new();
+        file.take(max_bytes + 1)
+            .read_to_string(&mut content)
+            .with_context(|| format!("Failed to read authorized_keys from {:?}", path))?;
+        if content.len() as u64 > max_bytes {
+            anyhow::bail!(
+                "authorized_keys file {:?} exceeds the {} byte limit",
+                path,
+                max_bytes
+            );
+        }
+
+        Self::parse_with_limit(&content, max_entries)
     }
 
-    /// Parse authorized keys from a string
+    /// Parse authorized keys from a string using the default entry limit
     #[cfg(feature = "ssh")]
     pub fn parse(content: &str) -> Result<Self> {
+        Self::parse_with_limit(content, DEFAULT_MAX_ENTRIES)
+    }
+
+    /// Parse authorized keys from a string, failing if it holds more than
+    /// `max_entries` valid keys
+    #[cfg(feature = "ssh")]
+    pub fn parse_with_limit(content: &str, max_entries: usize) -> Result<Self> {
         let mut keys = Vec::new();
 
         for (line_num, line) in content.lines().enumerate() {
@@ -61,7 +113,16 @@ impl AuthorizedKeys {
             }
 
             match parse_authorized_key_line(line) {
-                Ok(key) => keys.push(key),
+                Ok(key) => {
+                    if keys.len() >= max_entries {
+                        anyhow::bail!(
+                            "authorized_keys holds more than {} keys (line {})",
+                            max_entries,
+                            line_num + 1
+                        );
+                    }
+                    keys.push(key)
+                }
                 Err(e) => {
                     tracing::warn!(line = line_num + 1, error = %e, "Skipping invalid key");
                 }
@@ -301,6 +362,53 @@ ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIOMqqnkVzrm0SdG6UOoqKLsabgH5C9okWi0dh2l9GKJl
         assert_eq!(keys.keys[0].comment, None);
     }
 
+    #[test]
+    #[cfg(feature = "ssh")]
+    fn test_parse_skips_malformed_lines() {
+        let content = r#"
+ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIOMqqnkVzrm0SdG6UOoqKLsabgH5C9okWi0dh2l9GKJl user1@host
+ssh-ed25519 not-base64!!
+garbage
+no-pty,command="unterminated
+ssh-rsa
+no-pty ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIHu3t+bILVjHXPF9E0MnlSrk8FhRwplAqJqv8wnvmPjK user2@host
+"#;
+        let keys = AuthorizedKeys::parse(content).unwrap();
+        assert_eq!(keys.len(), 2);
+        assert!(keys.keys[1].options.contains_key("no-pty"));
+    }
+
+    #[test]
+    #[cfg(feature = "ssh")]
+    fn test_parse_rejects_too_many_entries() {
+        let content = r#"
+ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIOMqqnkVzrm0SdG6UOoqKLsabgH5C9okWi0dh2l9GKJl user1@host
+ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIHu3t+bILVjHXPF9E0MnlSrk8FhRwplAqJqv8wnvmPjK user2@host
+"#;
+        assert_eq!(
+            AuthorizedKeys::parse_with_limit(content, 2).unwrap().len(),
+            2
+        );
+
+        let err = AuthorizedKeys::parse_with_limit(content, 1).unwrap_err();
+        assert!(err.to_string().contains("more than 1 keys"));
+    }
+
+    #[test]
+    #[cfg(feature = "ssh")]
+    fn test_from_file_rejects_oversized_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("authorized_keys");
+        let line = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIOMqqnkVzrm0SdG6UOoqKLsabgH5C9okWi0dh2l9GKJl user@host\n";
+        std::fs::write(&path, line).unwrap();
+
+        let keys = AuthorizedKeys::from_file_with_limits(&path, line.len() as u64, 10).unwrap();
+        assert_eq!(keys.len(), 1);
+
+        let err = AuthorizedKeys::from_file_with_limits(&path, 16, 10).unwrap_err();
+        assert!(err.to_string().contains("byte limit"));
+    }
+
     #[test]
     fn test_module_compiles_without_ssh_feature() {
         let keys = AuthorizedKeys::new();
@@ -3,6 +3,7 @@
 //! This module handles password-based SSH authentication.
 
 use super::super::config::SshConfig;
+use crate::protocol::constant_time_eq;
 
 /// Verify a password against the configured credentials
 pub fn verify_password(config: &SshConfig, username: &str, password: &str) -> bool {
@@ -30,8 +31,8 @@ pub fn verify_password(config: &SshConfig, username: &str, password: &str) -> bo
     };
 
     // Constant-time comparison to prevent timing attacks
-    let username_matches = constant_time_compare(username.as_bytes(), expected_username.as_bytes());
-    let password_matches = constant_time_compare(password.as_bytes(), expected_password.as_bytes());
+    let username_matches = constant_time_eq(username.as_bytes(), expected_username.as_bytes());
+    let password_matches = constant_time_eq(password.as_bytes(), expected_password.as_bytes());
 
     if username_matches && password_matches {
         tracing::info!(username = %username, "Password authentication successful");
@@ -42,22 +43,6 @@ pub fn verify_password(config: &SshConfig, username: &str, password: &str) -> bo
     }
 }
 
-/// Constant-time comparison of two byte slices
-///
-/// This prevents timing attacks by ensuring the comparison takes
-/// the same amount of time regardless of where the mismatch occurs.
-fn constant_time_compare(a: &[u8], b: &[u8]) -> bool {
-    if a.len() != b.len() {
-        return false;
-    }
-
-    let mut result = 0u8;
-    for (x, y) in a.iter().zip(b.iter()) {
-        result |= x ^ y;
-    }
-    result == 0
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -122,30 +107,4 @@ mod tests {
         config.password = None;
         assert!(!verify_password(&config, "admin", "secret123"));
     }
-
-    #[test]
-    fn test_constant_time_compare_equal() {
-        assert!(constant_time_compare(b"hello", b"hello"));
-        assert!(constant_time_compare(b"", b""));
-        assert!(constant_time_compare(b"a", b"a"));
-    }
-
-    #[test]
-    fn test_constant_time_compare_not_equal() {
-        assert!(!constant_time_compare(b"hello", b"world"));
-        assert!(!constant_time_compare(b"hello", b"hell"));
-        assert!(!constant_time_compare(b"a", b"b"));
-    }
-
-    #[test]
-    fn test_constant_time_compare_different_lengths() {
-        assert!(!constant_time_compare(b"short", b"longer"));
-        assert!(!constant_time_compare(b"hello", b""));
-    }
-
-    #[test]
-    fn test_constant_time_compare_binary() {
-        assert!(constant_time_compare(&[0, 1, 2, 3], &[0, 1, 2, 3]));
-        assert!(!constant_time_compare(&[0, 1, 2, 3], &[0, 1, 2, 4]));
-    }
 }
@@ -33,7 +33,11 @@ impl PublicKeyAuth {
             }
         };
 
-        let authorized_keys = AuthorizedKeys::from_file(authorized_keys_path)?;
+        let authorized_keys = AuthorizedKeys::from_file_with_limits(
+            authorized_keys_path,
+            config.authorized_keys_max_bytes,
+            config.authorized_keys_max_entries,
+        )?;
 
         if authorized_keys.is_empty() {
             tracing::warn!("No authorized keys found in {:?}", authorized_keys_path);
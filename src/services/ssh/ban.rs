@@ -0,0 +1,235 @@
+//! Authentication failure banning
+//!
+//! [`AuthBanList`] counts failed authentication attempts per source and
+//! refuses further attempts from a source once it has failed too often
+//! within a window, like fail2ban does for a host's sshd.
+
+use super::config::SshConfig;
+use crate::clock::{Clock, TokioClock};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Failure history of one source
+#[derive(Debug, Clone, Default)]
+struct Record {
+    /// Failures within the current window, oldest first
+    failures: Vec<Instant>,
+    /// When the source's ban lifts, if it is banned
+    banned_until: Option<Instant>,
+}
+
+/// Shared tracker of authentication failures, keyed by source identity
+#[derive(Debug)]
+pub struct AuthBanList {
+    /// Failures that trip a ban (0 = banning disabled)
+    threshold: usize,
+    /// How far back failures count towards the threshold
+    window: Duration,
+    /// How long a tripped ban lasts
+    duration: Duration,
+    clock: Arc<dyn Clock>,
+    records: Mutex<HashMap<String, Record>>,
+}
+
+impl AuthBanList {
+    /// Create a ban list with the thresholds of `config`
+    pub fn new(config: &SshConfig) -> Self {
+        Self::with_clock(config, Arc::new(TokioClock))
+    }
+
+    /// Create a ban list measuring time with `clock`
+    pub fn with_clock(config: &SshConfig, clock: Arc<dyn Clock>) -> Self {
+        AuthBanList {
+            threshold: config.ban_threshold as usize,
+            window: Duration::from_secs(config.ban_window_secs),
+            duration: Duration::from_secs(config.ban_duration_secs),
+            clock,
+            records: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The ban list for a reloaded `config`, keeping the failures and bans
+    /// recorded in `previous`
+    ///
+    /// `previous` itself is returned when the thresholds are unchanged, so
+    /// sessions still running under the old configuration keep counting
+    /// towards the same bans.
+    pub fn reload(previous: &Arc<AuthBanList>, config: &SshConfig) -> Arc<AuthBanList> {
+        let bans = Self::with_clock(config, previous.clock.clone());
+        if (bans.threshold, bans.window, bans.duration)
+            == (previous.threshold, previous.window, previous.duration)
+        {
+            return previous.clone();
+        }
+        let records = previous
+            .records
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        *bans.records.lock().unwrap_or_else(|e| e.into_inner()) = records;
+        Arc::new(bans)
+    }
+
+    /// Whether authentication attempts from `source` are currently refused
+    pub fn is_banned(&self, source: &str) -> bool {
+        if self.threshold == 0 {
+            return false;
+        }
+        let now = self.clock.now();
+        let mut records = self.records.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(record) = records.get_mut(source) else {
+            return false;
+        };
+        match record.banned_until {
+            Some(until) if now < until => true,
+            Some(_) => {
+                // The ban ran out; the source starts over with a clean slate
+                records.remove(source);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Record a failed attempt from `source`, returning whether it tripped
+    /// a ban
+    pub fn record_failure(&self, source: &str) -> bool {
+        if self.threshold == 0 {
+            return false;
+        }
+        let now = self.clock.now();
+        let mut records = self.records.lock().unwrap_or_else(|e| e.into_inner());
+
+        // Forget sources whose failures have all aged out, so the map only
+        // holds sources that are failing right now
+        records.retain(|_, record| {
+            record.banned_until.is_some_and(|until| now < until)
+                || record
+                    .failures
+                    .last()
+                    .is_some_and(|last| now.duration_since(*last) < self.window)
+        });
+
+        let record = records.entry(source.to_string()).or_default();
+        if record.banned_until.is_some() {
+            return false;
+        }
+        record
+            .failures
+            .retain(|failure| now.duration_since(*failure) < self.window);
+        record.failures.push(now);
+        if record.failures.len() < self.threshold {
+            return false;
+        }
+        record.failures.clear();
+        record.banned_until = Some(now + self.duration);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    fn ban_list(threshold: u32, clock: Arc<MockClock>) -> AuthBanList {
+        let config = SshConfig {
+            ban_threshold: threshold,
+            ban_window_secs: 60,
+            ban_duration_secs: 600,
+            ..Default::default()
+        };
+        AuthBanList::with_clock(&config, clock)
+    }
+
+    #[test]
+    fn test_threshold_trips_ban() {
+        let clock = Arc::new(MockClock::new());
+        let bans = ban_list(3, clock.clone());
+
+        assert!(!bans.record_failure("a"));
+        assert!(!bans.record_failure("a"));
+        assert!(!bans.is_banned("a"));
+        assert!(bans.record_failure("a"));
+        assert!(bans.is_banned("a"));
+
+        // Other sources are unaffected
+        assert!(!bans.is_banned("b"));
+    }
+
+    #[test]
+    fn test_failures_outside_window_do_not_count() {
+        let clock = Arc::new(MockClock::new());
+        let bans = ban_list(3, clock.clone());
+
+        bans.record_failure("a");
+        bans.record_failure("a");
+        clock.advance(Duration::from_secs(61));
+        assert!(!bans.record_failure("a"));
+        assert!(!bans.is_banned("a"));
+    }
+
+    #[test]
+    fn test_ban_expires() {
+        let clock = Arc::new(MockClock::new());
+        let bans = ban_list(2, clock.clone());
+
+        bans.record_failure("a");
+        bans.record_failure("a");
+        assert!(bans.is_banned("a"));
+
+        clock.advance(Duration::from_secs(599));
+        assert!(bans.is_banned("a"));
+        clock.advance(Duration::from_secs(1));
+        assert!(!bans.is_banned("a"));
+
+        // A lifted ban does not carry failures over
+        assert!(!bans.record_failure("a"));
+    }
+
+    #[test]
+    fn test_reload_keeps_bans() {
+        let clock = Arc::new(MockClock::new());
+        let bans = Arc::new(ban_list(2, clock.clone()));
+        bans.record_failure("a");
+        bans.record_failure("a");
+        bans.record_failure("b");
+
+        let unchanged = AuthBanList::reload(
+            &bans,
+            &SshConfig {
+                ban_threshold: 2,
+                ban_window_secs: 60,
+                ban_duration_secs: 600,
+                ..Default::default()
+            },
+        );
+        assert!(Arc::ptr_eq(&unchanged, &bans));
+
+        // New thresholds apply to the failures recorded so far
+        let stricter = AuthBanList::reload(
+            &bans,
+            &SshConfig {
+                ban_threshold: 3,
+                ban_window_secs: 60,
+                ban_duration_secs: 600,
+                ..Default::default()
+            },
+        );
+        assert!(!Arc::ptr_eq(&stricter, &bans));
+        assert!(stricter.is_banned("a"));
+        assert!(!stricter.record_failure("b"));
+        assert!(stricter.record_failure("b"));
+    }
+
+    #[test]
+    fn test_zero_threshold_disables_banning() {
+        let clock = Arc::new(MockClock::new());
+        let bans = ban_list(0, clock);
+        for _ in 0..100 {
+            assert!(!bans.record_failure("a"));
+        }
+        assert!(!bans.is_banned("a"));
+    }
+}
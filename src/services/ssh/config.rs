@@ -7,6 +7,7 @@ use std::path::PathBuf;
 
 /// SSH server configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct SshConfig {
     /// Enable SSH server
     #[serde(default)]
@@ -20,6 +21,14 @@ pub struct SshConfig {
     #[serde(default)]
     pub authorized_keys: Option<PathBuf>,
 
+    /// Maximum size of the authorized_keys file in bytes
+    #[serde(default = "default_authorized_keys_max_bytes")]
+    pub authorized_keys_max_bytes: u64,
+
+    /// Maximum number of keys accepted from the authorized_keys file
+    #[serde(default = "default_authorized_keys_max_entries")]
+    pub authorized_keys_max_entries: usize,
+
     /// Path to host key file (Ed25519 or RSA private key in OpenSSH format)
     #[serde(default)]
     pub host_key: Option<PathBuf>,
@@ -48,10 +57,23 @@ pub struct SshConfig {
     #[serde(default = "default_true")]
     pub sftp: bool,
 
-    /// Path to sftp-server binary (for SFTP subsystem)
+    /// Path to sftp-server binary (for SFTP subsystem), or `"builtin"` for
+    /// the built-in server
     #[serde(default = "default_sftp_server")]
     pub sftp_server: String,
 
+    /// Directory the built-in SFTP server starts in
+    #[serde(default = "default_sftp_root")]
+    pub sftp_root: PathBuf,
+
+    /// Confine built-in SFTP clients to `sftp_root`
+    #[serde(default)]
+    pub sftp_chroot: bool,
+
+    /// Refuse every modification through the built-in SFTP server
+    #[serde(default)]
+    pub sftp_read_only: bool,
+
     /// Enable PTY allocation
     #[serde(default = "default_true")]
     pub pty: bool,
@@ -72,6 +94,21 @@ pub struct SshConfig {
     #[serde(default = "default_max_auth_tries")]
     pub max_auth_tries: u32,
 
+    /// Failed authentications from one source that ban it (0 = never ban)
+    ///
+    /// Tunnel data channels all count as one source, since they do not
+    /// carry the visitor's address.
+    #[serde(default)]
+    pub ban_threshold: u32,
+
+    /// Window in seconds within which failures count towards a ban
+    #[serde(default = "default_ban_window_secs")]
+    pub ban_window_secs: u64,
+
+    /// How long a banned source is refused, in seconds
+    #[serde(default = "default_ban_duration_secs")]
+    pub ban_duration_secs: u64,
+
     /// Connection timeout in seconds
     #[serde(default = "default_connection_timeout")]
     pub connection_timeout: u64,
@@ -79,12 +116,24 @@ pub struct SshConfig {
     /// Default shell command and arguments (e.g. ["/bin/bash", "-l"])
     #[serde(default = "default_shell")]
     pub default_shell: Vec<String>,
+
+    /// Path of a JSON-lines audit log of SSH session activity
+    #[serde(default)]
+    pub audit_log: Option<PathBuf>,
 }
 
 fn default_auth_methods() -> Vec<String> {
     vec!["publickey".to_string(), "password".to_string()]
 }
 
+fn default_authorized_keys_max_bytes() -> u64 {
+    1024 * 1024
+}
+
+fn default_authorized_keys_max_entries() -> usize {
+    1024
+}
+
 fn default_server_id() -> String {
     format!("SSH-2.0-Sockrats_{}", env!("CARGO_PKG_VERSION"))
 }
@@ -97,6 +146,14 @@ fn default_max_auth_tries() -> u32 {
     6
 }
 
+fn default_ban_window_secs() -> u64 {
+    600
+}
+
+fn default_ban_duration_secs() -> u64 {
+    3600
+}
+
 fn default_connection_timeout() -> u64 {
     300
 }
@@ -137,12 +194,18 @@ fn default_sftp_server() -> String {
     "/usr/lib/openssh/sftp-server".to_string()
 }
 
+fn default_sftp_root() -> PathBuf {
+    PathBuf::from("/")
+}
+
 impl Default for SshConfig {
     fn default() -> Self {
         Self {
             enabled: false,
             auth_methods: default_auth_methods(),
             authorized_keys: None,
+            authorized_keys_max_bytes: default_authorized_keys_max_bytes(),
+            authorized_keys_max_entries: default_authorized_keys_max_entries(),
             host_key: None,
             password: None,
             username: None,
@@ -151,13 +214,20 @@ impl Default for SshConfig {
             exec: true,
             sftp: true,
             sftp_server: default_sftp_server(),
+            sftp_root: default_sftp_root(),
+            sftp_chroot: false,
+            sftp_read_only: false,
             pty: true,
             tcp_forwarding: false,
             x11_forwarding: false,
             agent_forwarding: false,
             max_auth_tries: default_max_auth_tries(),
+            ban_threshold: 0,
+            ban_window_secs: default_ban_window_secs(),
+            ban_duration_secs: default_ban_duration_secs(),
             connection_timeout: default_connection_timeout(),
             default_shell: default_shell(),
+            audit_log: None,
         }
     }
 }
@@ -223,6 +293,24 @@ impl SshConfig {
             return Err("authorized_keys path required for public key authentication".to_string());
         }
 
+        if self.authorized_keys_max_bytes == 0 || self.authorized_keys_max_entries == 0 {
+            return Err(
+                "authorized_keys_max_bytes and authorized_keys_max_entries must be greater than 0"
+                    .to_string(),
+            );
+        }
+
+        if self.ban_threshold > 0 && (self.ban_window_secs == 0 || self.ban_duration_secs == 0) {
+            return Err(
+                "ban_window_secs and ban_duration_secs must be greater than 0 when ban_threshold is set"
+                    .to_string(),
+            );
+        }
+
+        if !self.sftp_root.is_absolute() {
+            return Err("sftp_root must be an absolute path".to_string());
+        }
+
         // Host key is required when enabled
         if self.host_key.is_none() {
             return Err("host_key path is required".to_string());
@@ -233,6 +321,7 @@ impl SshConfig {
 }
 
 #[cfg(test)]
+#[allow(clippy::field_reassign_with_default)]
 mod tests {
     use super::*;
 
@@ -381,6 +470,49 @@ mod tests {
         assert!(config.validate().is_ok());
     }
 
+    #[test]
+    fn test_validate_zero_authorized_keys_limits() {
+        let mut config = SshConfig::default();
+        config.enabled = true;
+        config.auth_methods = vec!["publickey".to_string()];
+        config.authorized_keys = Some(PathBuf::from("/path/to/authorized_keys"));
+        config.host_key = Some(PathBuf::from("/path/to/host_key"));
+        assert_eq!(config.authorized_keys_max_bytes, 1024 * 1024);
+        assert_eq!(config.authorized_keys_max_entries, 1024);
+
+        config.authorized_keys_max_entries = 0;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_ban_settings() {
+        let mut config = SshConfig::default();
+        config.enabled = true;
+        config.auth_methods = vec!["publickey".to_string()];
+        config.authorized_keys = Some(PathBuf::from("/path/to/authorized_keys"));
+        config.host_key = Some(PathBuf::from("/path/to/host_key"));
+        assert_eq!(config.ban_threshold, 0);
+
+        config.ban_threshold = 5;
+        assert!(config.validate().is_ok());
+
+        config.ban_window_secs = 0;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_relative_sftp_root() {
+        let mut config = SshConfig::default();
+        config.enabled = true;
+        config.auth_methods = vec!["publickey".to_string()];
+        config.authorized_keys = Some(PathBuf::from("/path/to/authorized_keys"));
+        config.host_key = Some(PathBuf::from("/path/to/host_key"));
+        assert!(config.validate().is_ok());
+
+        config.sftp_root = PathBuf::from("srv/files");
+        assert!(config.validate().is_err());
+    }
+
     #[test]
     fn test_server_id_format() {
         let config = SshConfig::default();
@@ -2,14 +2,20 @@
 //!
 //! This module implements the russh `Handler` trait for SSH server functionality.
 
+#[cfg(feature = "ssh")]
+use super::audit::SessionAudit;
 #[cfg(feature = "ssh")]
 use super::auth::verify_password;
 use super::auth::PublicKeyAuth;
+#[cfg(feature = "ssh")]
+use super::ban::AuthBanList;
 use super::config::SshConfig;
 #[cfg(feature = "ssh")]
 use super::process::{new_shell_manager, PtyConfig, SharedShellManager};
 #[cfg(feature = "ssh")]
 use super::session::{new_shared_session, ChannelState, SharedSessionState};
+#[cfg(feature = "ssh")]
+use super::sftp::{SftpServer, BUILTIN_SFTP_SERVER};
 use std::sync::Arc;
 
 #[cfg(feature = "ssh")]
@@ -30,6 +36,12 @@ pub struct SshHandler {
     session_state: SharedSessionState,
     /// Shell process manager
     shell_manager: SharedShellManager,
+    /// Authentication failures shared with other sessions of the service
+    bans: Arc<AuthBanList>,
+    /// Identity of the connecting source, as tracked in `bans`
+    source: String,
+    /// Audit trail of the session
+    audit: SessionAudit,
 }
 
 #[cfg(feature = "ssh")]
@@ -37,11 +49,59 @@ impl SshHandler {
     /// Create a new SSH handler
     pub fn new(config: Arc<SshConfig>, pubkey_auth: Option<PublicKeyAuth>) -> Self {
         let max_auth_attempts = config.max_auth_tries;
+        let bans = Arc::new(AuthBanList::new(&config));
         Self {
             config,
             pubkey_auth,
             session_state: new_shared_session(max_auth_attempts),
             shell_manager: new_shell_manager(),
+            bans,
+            source: "stream".to_string(),
+            audit: SessionAudit::with_logger(None),
+        }
+    }
+
+    /// Record the session's activity with `audit`
+    pub fn with_audit(mut self, audit: SessionAudit) -> Self {
+        self.audit = audit;
+        self
+    }
+
+    /// The session state, shared with the running session
+    pub fn session_state(&self) -> SharedSessionState {
+        self.session_state.clone()
+    }
+
+    /// Track authentication failures of this session as `source` in `bans`
+    pub fn with_ban_list(mut self, bans: Arc<AuthBanList>, source: String) -> Self {
+        self.bans = bans;
+        self.source = source;
+        self
+    }
+
+    /// Whether the session may not attempt authentication any more, either
+    /// because it used up its attempts or because its source is banned
+    async fn auth_refused(&self) -> bool {
+        if self.bans.is_banned(&self.source) {
+            tracing::warn!(source = %self.source, "Authentication refused: source is banned");
+            return true;
+        }
+        let state = self.session_state.lock().await;
+        if state.auth_attempts_exceeded() {
+            tracing::warn!("Max authentication attempts exceeded");
+            return true;
+        }
+        false
+    }
+
+    /// Count a failed authentication against the session and its source
+    async fn record_auth_failure(&self) {
+        self.session_state.lock().await.record_auth_failure();
+        if self.bans.record_failure(&self.source) {
+            tracing::warn!(
+                source = %self.source,
+                "Banned after repeated authentication failures"
+            );
         }
     }
 }
@@ -54,24 +114,20 @@ impl Handler for SshHandler {
     async fn auth_password(&mut self, user: &str, password: &str) -> Result<Auth, Self::Error> {
         tracing::debug!(username = %user, "Password authentication attempt");
 
-        // Check if max attempts exceeded
-        {
-            let state = self.session_state.lock().await;
-            if state.auth_attempts_exceeded() {
-                tracing::warn!("Max authentication attempts exceeded");
-                return Ok(Auth::reject());
-            }
+        if self.auth_refused().await {
+            return Ok(Auth::reject());
         }
 
         if verify_password(&self.config, user, password) {
             let mut state = self.session_state.lock().await;
             state.authenticate(user.to_string());
             tracing::info!(username = %user, "Password authentication successful");
+            self.audit.auth("password", user, true);
             Ok(Auth::Accept)
         } else {
-            let mut state = self.session_state.lock().await;
-            state.record_auth_failure();
+            self.record_auth_failure().await;
             tracing::warn!(username = %user, "Password authentication failed");
+            self.audit.auth("password", user, false);
             Ok(Auth::reject())
         }
     }
@@ -107,18 +163,12 @@ impl Handler for SshHandler {
     ) -> Result<Auth, Self::Error> {
         tracing::debug!(username = %user, "Public key authentication");
 
-        // Check if max attempts exceeded
-        {
-            let state = self.session_state.lock().await;
-            if state.auth_attempts_exceeded() {
-                tracing::warn!("Max authentication attempts exceeded");
-                return Ok(Auth::reject());
-            }
+        if self.auth_refused().await {
+            return Ok(Auth::reject());
         }
 
         if !self.config.has_publickey_auth() {
-            let mut state = self.session_state.lock().await;
-            state.record_auth_failure();
+            self.record_auth_failure().await;
             return Ok(Auth::reject());
         }
 
@@ -128,13 +178,14 @@ impl Handler for SshHandler {
                 let mut state = self.session_state.lock().await;
                 state.authenticate(user.to_string());
                 tracing::info!(username = %user, "Public key authentication successful");
+                self.audit.auth("publickey", user, true);
                 return Ok(Auth::Accept);
             }
         }
 
-        let mut state = self.session_state.lock().await;
-        state.record_auth_failure();
+        self.record_auth_failure().await;
         tracing::warn!(username = %user, "Public key authentication failed");
+        self.audit.auth("publickey", user, false);
         Ok(Auth::reject())
     }
 
@@ -153,6 +204,7 @@ impl Handler for SshHandler {
 
         let channel_id: u32 = channel.id().into();
         tracing::debug!(channel_id, "Session channel opened");
+        self.audit.channel_open(channel_id);
 
         let mut state = self.session_state.lock().await;
         state.add_channel(channel_id, ChannelState::new_session());
@@ -283,6 +335,7 @@ impl Handler for SshHandler {
         let command = String::from_utf8_lossy(data).to_string();
         let channel_id: u32 = channel.into();
         tracing::info!(channel_id, command = %command, "Exec request");
+        self.audit.exec(&command);
 
         // Get environment variables for this channel
         let env_vars = {
@@ -471,18 +524,31 @@ impl Handler for SshHandler {
         match name {
             "sftp" if self.config.sftp => {
                 let sftp_server = &self.config.sftp_server;
-                tracing::info!(channel_id, sftp_server, "Spawning SFTP subsystem");
-
                 let handle = session.handle();
 
-                // Spawn sftp-server with direct handle forwarding
-                // (binary protocol on stdout, stderr separate via extended_data)
-                match self
-                    .shell_manager
-                    .spawn_subsystem(channel_id, sftp_server, channel, handle)
-                    .await
-                {
+                let started = if sftp_server == BUILTIN_SFTP_SERVER {
+                    tracing::info!(channel_id, "Starting built-in SFTP subsystem");
+                    match SftpServer::new(&self.config) {
+                        Ok(server) => {
+                            self.shell_manager
+                                .spawn_builtin_sftp(channel_id, server, channel, handle)
+                                .await;
+                            Ok(())
+                        }
+                        Err(e) => Err(e),
+                    }
+                } else {
+                    tracing::info!(channel_id, sftp_server, "Spawning SFTP subsystem");
+
+                    // Spawn sftp-server with direct handle forwarding
+                    // (binary protocol on stdout, stderr separate via extended_data)
+                    self.shell_manager
+                        .spawn_subsystem(channel_id, sftp_server, channel, handle)
+                        .await
+                };
+                match started {
                     Ok(()) => {
+                        self.audit.subsystem(name, true);
                         session.channel_success(channel)?;
                     }
                     Err(e) => {
@@ -492,12 +558,14 @@ impl Handler for SshHandler {
                             sftp_server,
                             "Failed to spawn SFTP subsystem"
                         );
+                        self.audit.subsystem(name, false);
                         session.channel_failure(channel)?;
                     }
                 }
             }
             _ => {
                 tracing::warn!(subsystem = name, "Unknown or disabled subsystem");
+                self.audit.subsystem(name, false);
                 session.channel_failure(channel)?;
             }
         }
@@ -558,4 +626,36 @@ mod tests {
         let handler = SshHandler::new(config, pubkey_auth);
         assert!(handler.pubkey_auth.is_some());
     }
+
+    #[tokio::test]
+    #[cfg(feature = "ssh")]
+    async fn test_banned_source_is_refused() {
+        let config = Arc::new(SshConfig {
+            enabled: true,
+            auth_methods: vec!["password".to_string()],
+            username: Some("user".to_string()),
+            password: Some("secret".to_string()),
+            ban_threshold: 2,
+            ..Default::default()
+        });
+        let bans = Arc::new(AuthBanList::new(&config));
+
+        let mut first =
+            SshHandler::new(config.clone(), None).with_ban_list(bans.clone(), "peer-a".to_string());
+        for _ in 0..2 {
+            let auth = first.auth_password("user", "wrong").await.unwrap();
+            assert!(matches!(auth, Auth::Reject { .. }));
+        }
+
+        // A new session from the banned source is refused even with the
+        // right password, while other sources still get in
+        let mut second =
+            SshHandler::new(config.clone(), None).with_ban_list(bans.clone(), "peer-a".to_string());
+        let auth = second.auth_password("user", "secret").await.unwrap();
+        assert!(matches!(auth, Auth::Reject { .. }));
+
+        let mut other = SshHandler::new(config, None).with_ban_list(bans, "peer-b".to_string());
+        let auth = other.auth_password("user", "secret").await.unwrap();
+        assert!(matches!(auth, Auth::Accept));
+    }
 }
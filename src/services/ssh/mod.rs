@@ -23,17 +23,24 @@
 //! }
 //! ```
 
+pub mod audit;
 pub mod auth;
+pub mod ban;
 pub mod config;
 pub mod handler;
 pub mod keys;
 pub mod process;
 pub mod session;
+pub mod sftp;
 
+pub use ban::AuthBanList;
 pub use config::SshConfig;
 pub use handler::SshHandler;
 
-use crate::services::{ServiceHandler, StreamDyn};
+use crate::services::{
+    CloseReason, ConnectionSummary, CountedStream, HandlerState, ServiceHandler, ServiceStats,
+    StreamDyn,
+};
 use anyhow::Result;
 #[cfg(feature = "ssh")]
 use auth::PublicKeyAuth;
@@ -47,6 +54,7 @@ use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncWrite};
 
 use std::sync::Arc;
+use std::time::Instant;
 
 /// Handle an SSH connection on a stream
 ///
@@ -70,7 +78,26 @@ pub async fn handle_ssh_on_stream<S>(stream: S, config: Arc<SshConfig>) -> Resul
 where
     S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
 {
-    tracing::info!("Starting SSH session on stream");
+    let bans = Arc::new(AuthBanList::new(&config));
+    handle_ssh_from_source(stream, config, bans, "stream".to_string()).await
+}
+
+/// Handle an SSH connection from `source`, tracking its authentication
+/// failures in `bans`
+///
+/// Sessions sharing `bans` refuse to authenticate a source that has failed
+/// `ban_threshold` times within `ban_window_secs`.
+#[cfg(feature = "ssh")]
+pub async fn handle_ssh_from_source<S>(
+    stream: S,
+    config: Arc<SshConfig>,
+    bans: Arc<AuthBanList>,
+    source: String,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    tracing::info!(source = %source, "Starting SSH session on stream");
 
     // Build russh config
     let russh_config = build_russh_config(&config)?;
@@ -78,16 +105,25 @@ where
     // Initialize public key authenticator if enabled
     let pubkey_auth = PublicKeyAuth::from_config(&config)?;
 
-    // Create handler
-    let handler = SshHandler::new(config.clone(), pubkey_auth);
+    let audit = audit::SessionAudit::open(&config)?;
 
-    // Run SSH server on the stream
-    let session = russh::server::run_stream(Arc::new(russh_config), stream, handler).await?;
+    // Create handler
+    let handler = SshHandler::new(config.clone(), pubkey_auth)
+        .with_ban_list(bans, source)
+        .with_audit(audit.clone());
+    let session_state = handler.session_state();
+
+    // Run SSH server on the stream, then wait for the session to complete
+    let result = match russh::server::run_stream(Arc::new(russh_config), stream, handler).await {
+        Ok(session) => session.await,
+        Err(e) => Err(e),
+    };
 
-    // Wait for session to complete
-    session.await?;
+    let username = session_state.lock().await.username.clone();
+    audit.close(username);
+    result?;
 
-    tracing::info!("SSH session completed");
+    tracing::info!(session_id = audit.session_id(), "SSH session completed");
     Ok(())
 }
 
@@ -154,6 +190,28 @@ where
     anyhow::bail!("SSH feature is not enabled. Recompile with --features ssh")
 }
 
+/// Placeholder for when SSH feature is disabled
+#[cfg(not(feature = "ssh"))]
+pub async fn handle_ssh_from_source<S>(
+    _stream: S,
+    _config: std::sync::Arc<SshConfig>,
+    _bans: std::sync::Arc<AuthBanList>,
+    _source: String,
+) -> anyhow::Result<()>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    anyhow::bail!("SSH feature is not enabled. Recompile with --features ssh")
+}
+
+/// Ban list identity of every data channel
+///
+/// Rathole data channels do not carry the visitor's address, so
+/// authentication failures count against the service as a whole: once
+/// `ban_threshold` is reached, every visitor is refused until the ban
+/// lifts.
+const DATA_CHANNEL_SOURCE: &str = "data-channel";
+
 /// SSH service handler implementing the [`ServiceHandler`] trait.
 ///
 /// Wraps the existing SSH server implementation to conform to the
@@ -162,13 +220,18 @@ where
 #[derive(Debug)]
 pub struct SshServiceHandler {
     config: Arc<SshConfig>,
+    stats: Arc<ServiceStats>,
+    /// Authentication failures across every connection to the service
+    bans: Arc<AuthBanList>,
 }
 
 impl SshServiceHandler {
     /// Create a new SSH service handler with the given configuration.
     pub fn new(config: SshConfig) -> Self {
         Self {
+            bans: Arc::new(AuthBanList::new(&config)),
             config: Arc::new(config),
+            stats: Arc::default(),
         }
     }
 
@@ -184,8 +247,23 @@ impl ServiceHandler for SshServiceHandler {
         "ssh"
     }
 
-    async fn handle_tcp_stream(&self, stream: Box<dyn StreamDyn>) -> Result<()> {
-        handle_ssh_on_stream(stream, self.config.clone()).await
+    async fn handle_tcp_stream(&self, stream: Box<dyn StreamDyn>) -> Result<ConnectionSummary> {
+        self.stats
+            .track(async {
+                let start = Instant::now();
+                let (stream, counters) = CountedStream::new(stream);
+                handle_ssh_from_source(
+                    stream,
+                    self.config.clone(),
+                    self.bans.clone(),
+                    DATA_CHANNEL_SOURCE.to_string(),
+                )
+                .await?;
+                Ok(ConnectionSummary::new(CloseReason::Completed)
+                    .with_bytes(counters.read(), counters.written())
+                    .with_duration(start.elapsed()))
+            })
+            .await
     }
 
     fn validate(&self) -> Result<()> {
@@ -195,6 +273,27 @@ impl ServiceHandler for SshServiceHandler {
             Ok(())
         }
     }
+
+    fn stats(&self) -> Option<&ServiceStats> {
+        Some(&self.stats)
+    }
+
+    fn reload_state(&self) -> HandlerState {
+        HandlerState {
+            stats: Some(self.stats.clone()),
+            auth_bans: Some(self.bans.clone()),
+            ..Default::default()
+        }
+    }
+
+    fn carry_over(&mut self, state: HandlerState) {
+        if let Some(stats) = state.stats {
+            self.stats = stats;
+        }
+        if let Some(bans) = state.auth_bans {
+            self.bans = AuthBanList::reload(&bans, &self.config);
+        }
+    }
 }
 
 #[cfg(test)]
@@ -306,6 +405,80 @@ mod tests {
         }
     }
 
+    #[cfg(feature = "ssh")]
+    struct AcceptAnyHostKey;
+
+    #[cfg(feature = "ssh")]
+    impl russh::client::Handler for AcceptAnyHostKey {
+        type Error = russh::Error;
+
+        async fn check_server_key(
+            &mut self,
+            _server_public_key: &russh::keys::PublicKey,
+        ) -> Result<bool, Self::Error> {
+            Ok(true)
+        }
+    }
+
+    /// Authenticate with `password` over a new data channel to `handler`
+    #[cfg(feature = "ssh")]
+    async fn password_accepted(handler: &Arc<SshServiceHandler>, password: &str) -> bool {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let handler = handler.clone();
+        let session =
+            tokio::spawn(async move { handler.handle_tcp_stream(Box::new(server)).await });
+
+        let config = Arc::new(russh::client::Config::default());
+        let mut client = russh::client::connect_stream(config, client, AcceptAnyHostKey)
+            .await
+            .unwrap();
+        let accepted = client
+            .authenticate_password("user", password)
+            .await
+            .unwrap()
+            .success();
+        drop(client);
+        let _ = session.await;
+        accepted
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "ssh")]
+    async fn test_failures_across_data_channels_ban() {
+        let handler = Arc::new(SshServiceHandler::new(SshConfig {
+            enabled: true,
+            auth_methods: vec!["password".to_string()],
+            username: Some("user".to_string()),
+            password: Some("secret".to_string()),
+            ban_threshold: 2,
+            ..Default::default()
+        }));
+
+        assert!(!password_accepted(&handler, "wrong").await);
+        assert!(password_accepted(&handler, "secret").await);
+        assert!(!password_accepted(&handler, "wrong").await);
+
+        // Two failures on separate data channels trip the ban
+        assert!(!password_accepted(&handler, "secret").await);
+    }
+
+    #[test]
+    #[cfg(feature = "ssh")]
+    fn test_reload_keeps_bans() {
+        let config = SshConfig {
+            enabled: true,
+            ban_threshold: 1,
+            ..Default::default()
+        };
+        let previous = SshServiceHandler::new(config.clone());
+        previous.bans.record_failure(DATA_CHANNEL_SOURCE);
+
+        let mut handler: Arc<dyn ServiceHandler> = Arc::new(SshServiceHandler::new(config));
+        crate::services::carry_over(&mut handler, &previous);
+        let state = handler.reload_state();
+        assert!(state.auth_bans.unwrap().is_banned(DATA_CHANNEL_SOURCE));
+    }
+
     #[tokio::test]
     #[cfg(not(feature = "ssh"))]
     async fn test_handle_ssh_disabled() {
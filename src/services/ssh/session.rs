@@ -275,6 +275,7 @@ mod tests {
     fn test_channel_type_clone_copy() {
         let ct = ChannelType::Session;
         let ct2 = ct;
+        #[allow(clippy::clone_on_copy)]
         let ct3 = ct.clone();
         assert_eq!(ct, ct2);
         assert_eq!(ct, ct3);
@@ -0,0 +1,1096 @@
+//! Built-in SFTP server
+//!
+//! A pure-Rust implementation of SFTP version 3, used when `sftp_server` is
+//! set to [`BUILTIN_SFTP_SERVER`] so SFTP works in containers without an
+//! external `sftp-server` binary. [`SftpServer`] consumes the raw bytes of
+//! the subsystem channel and returns the bytes to send back, serving the
+//! `sftp_root` directory and honoring the `sftp_chroot` and
+//! `sftp_read_only` options of [`SshConfig`].
+
+use super::config::SshConfig;
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+
+/// `sftp_server` value that selects the built-in server
+pub const BUILTIN_SFTP_SERVER: &str = "builtin";
+
+/// Protocol version spoken by the server
+const SFTP_VERSION: u32 = 3;
+/// Largest packet accepted from the client
+const MAX_PACKET: usize = 256 * 1024;
+/// Largest chunk served by one READ
+const MAX_READ: u32 = 64 * 1024;
+/// Directory entries returned by one READDIR
+const READDIR_BATCH: usize = 100;
+
+const SSH_FXP_INIT: u8 = 1;
+const SSH_FXP_VERSION: u8 = 2;
+const SSH_FXP_OPEN: u8 = 3;
+const SSH_FXP_CLOSE: u8 = 4;
+const SSH_FXP_READ: u8 = 5;
+const SSH_FXP_WRITE: u8 = 6;
+const SSH_FXP_LSTAT: u8 = 7;
+const SSH_FXP_FSTAT: u8 = 8;
+const SSH_FXP_SETSTAT: u8 = 9;
+const SSH_FXP_FSETSTAT: u8 = 10;
+const SSH_FXP_OPENDIR: u8 = 11;
+const SSH_FXP_READDIR: u8 = 12;
+const SSH_FXP_REMOVE: u8 = 13;
+const SSH_FXP_MKDIR: u8 = 14;
+const SSH_FXP_RMDIR: u8 = 15;
+const SSH_FXP_REALPATH: u8 = 16;
+const SSH_FXP_STAT: u8 = 17;
+const SSH_FXP_RENAME: u8 = 18;
+const SSH_FXP_READLINK: u8 = 19;
+const SSH_FXP_SYMLINK: u8 = 20;
+const SSH_FXP_STATUS: u8 = 101;
+const SSH_FXP_HANDLE: u8 = 102;
+const SSH_FXP_DATA: u8 = 103;
+const SSH_FXP_NAME: u8 = 104;
+const SSH_FXP_ATTRS: u8 = 105;
+
+const SSH_FX_OK: u32 = 0;
+const SSH_FX_EOF: u32 = 1;
+const SSH_FX_NO_SUCH_FILE: u32 = 2;
+const SSH_FX_PERMISSION_DENIED: u32 = 3;
+const SSH_FX_FAILURE: u32 = 4;
+const SSH_FX_BAD_MESSAGE: u32 = 5;
+const SSH_FX_OP_UNSUPPORTED: u32 = 8;
+
+const SSH_FXF_READ: u32 = 0x01;
+const SSH_FXF_WRITE: u32 = 0x02;
+const SSH_FXF_APPEND: u32 = 0x04;
+const SSH_FXF_CREAT: u32 = 0x08;
+const SSH_FXF_TRUNC: u32 = 0x10;
+const SSH_FXF_EXCL: u32 = 0x20;
+
+const SSH_FILEXFER_ATTR_SIZE: u32 = 0x01;
+const SSH_FILEXFER_ATTR_UIDGID: u32 = 0x02;
+const SSH_FILEXFER_ATTR_PERMISSIONS: u32 = 0x04;
+const SSH_FILEXFER_ATTR_ACMODTIME: u32 = 0x08;
+const SSH_FILEXFER_ATTR_EXTENDED: u32 = 0x8000_0000;
+
+/// Failure reported to the client as an `SSH_FXP_STATUS`
+#[derive(Debug)]
+struct Status {
+    code: u32,
+    message: String,
+}
+
+impl Status {
+    fn new(code: u32, message: &str) -> Self {
+        Status {
+            code,
+            message: message.to_string(),
+        }
+    }
+
+    fn bad_message() -> Self {
+        Status::new(SSH_FX_BAD_MESSAGE, "malformed request")
+    }
+
+    fn read_only() -> Self {
+        Status::new(SSH_FX_PERMISSION_DENIED, "server is read-only")
+    }
+
+    fn invalid_handle() -> Self {
+        Status::new(SSH_FX_FAILURE, "invalid handle")
+    }
+
+    fn outside_root() -> Self {
+        Status::new(SSH_FX_PERMISSION_DENIED, "path is outside the SFTP root")
+    }
+}
+
+impl From<io::Error> for Status {
+    fn from(e: io::Error) -> Self {
+        let code = match e.kind() {
+            io::ErrorKind::NotFound => SSH_FX_NO_SUCH_FILE,
+            io::ErrorKind::PermissionDenied => SSH_FX_PERMISSION_DENIED,
+            _ => SSH_FX_FAILURE,
+        };
+        Status {
+            code,
+            message: e.to_string(),
+        }
+    }
+}
+
+/// Cursor over the fields of a request
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Status> {
+        if self.buf.len() < n {
+            return Err(Status::bad_message());
+        }
+        let (head, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Ok(head)
+    }
+
+    fn u32(&mut self) -> Result<u32, Status> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, Status> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], Status> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> Result<String, Status> {
+        Ok(String::from_utf8_lossy(self.bytes()?).into_owned())
+    }
+
+    fn attrs(&mut self) -> Result<Attrs, Status> {
+        let flags = self.u32()?;
+        let mut attrs = Attrs::default();
+        if flags & SSH_FILEXFER_ATTR_SIZE != 0 {
+            attrs.size = Some(self.u64()?);
+        }
+        if flags & SSH_FILEXFER_ATTR_UIDGID != 0 {
+            attrs.uid_gid = Some((self.u32()?, self.u32()?));
+        }
+        if flags & SSH_FILEXFER_ATTR_PERMISSIONS != 0 {
+            attrs.permissions = Some(self.u32()?);
+        }
+        if flags & SSH_FILEXFER_ATTR_ACMODTIME != 0 {
+            attrs.times = Some((self.u32()?, self.u32()?));
+        }
+        if flags & SSH_FILEXFER_ATTR_EXTENDED != 0 {
+            for _ in 0..self.u32()? {
+                self.bytes()?;
+                self.bytes()?;
+            }
+        }
+        Ok(attrs)
+    }
+}
+
+/// Attributes sent by the client with OPEN, MKDIR and SETSTAT
+#[derive(Debug, Default)]
+struct Attrs {
+    size: Option<u64>,
+    uid_gid: Option<(u32, u32)>,
+    permissions: Option<u32>,
+    /// `(atime, mtime)` in seconds since the epoch
+    times: Option<(u32, u32)>,
+}
+
+/// Reply packet under construction
+struct Reply(Vec<u8>);
+
+impl Reply {
+    fn new(kind: u8, id: u32) -> Self {
+        let mut reply = Reply(vec![kind]);
+        reply.u32(id);
+        reply
+    }
+
+    fn status(id: u32, status: &Status) -> Self {
+        let mut reply = Reply::new(SSH_FXP_STATUS, id);
+        reply.u32(status.code);
+        reply.string(status.message.as_bytes());
+        // Language tag
+        reply.string(b"");
+        reply
+    }
+
+    fn ok(id: u32) -> Self {
+        Reply::status(id, &Status::new(SSH_FX_OK, "OK"))
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn string(&mut self, value: &[u8]) {
+        self.u32(value.len() as u32);
+        self.0.extend_from_slice(value);
+    }
+
+    fn attrs(&mut self, meta: &Metadata) {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            self.u32(
+                SSH_FILEXFER_ATTR_SIZE
+                    | SSH_FILEXFER_ATTR_UIDGID
+                    | SSH_FILEXFER_ATTR_PERMISSIONS
+                    | SSH_FILEXFER_ATTR_ACMODTIME,
+            );
+            self.u64(meta.size());
+            self.u32(meta.uid());
+            self.u32(meta.gid());
+            self.u32(meta.mode());
+            self.u32(meta.atime() as u32);
+            self.u32(meta.mtime() as u32);
+        }
+        #[cfg(not(unix))]
+        {
+            self.u32(SSH_FILEXFER_ATTR_SIZE | SSH_FILEXFER_ATTR_PERMISSIONS);
+            self.u64(meta.len());
+            self.u32(mode(meta));
+        }
+    }
+
+    /// Frame the reply with its length prefix
+    fn finish(self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&(self.0.len() as u32).to_be_bytes());
+        out.extend_from_slice(&self.0);
+    }
+}
+
+/// An open file or directory
+enum Handle {
+    File(File),
+    /// Directory entries not yet returned by READDIR
+    Dir(Vec<(String, Metadata)>),
+}
+
+/// SFTP v3 server for one subsystem channel
+pub struct SftpServer {
+    /// Canonical directory paths resolve against
+    root: PathBuf,
+    chroot: bool,
+    read_only: bool,
+    handles: HashMap<String, Handle>,
+    next_handle: u64,
+    /// Received bytes that do not yet form a whole packet
+    input: Vec<u8>,
+}
+
+impl SftpServer {
+    /// Create a server for the SFTP settings of `config`
+    pub fn new(config: &SshConfig) -> Result<Self> {
+        let root = fs::canonicalize(&config.sftp_root).with_context(|| {
+            format!("SFTP root {} is not accessible", config.sftp_root.display())
+        })?;
+        if !root.is_dir() {
+            bail!("SFTP root {} is not a directory", root.display());
+        }
+        Ok(SftpServer {
+            root,
+            chroot: config.sftp_chroot,
+            read_only: config.sftp_read_only,
+            handles: HashMap::new(),
+            next_handle: 0,
+            input: Vec::new(),
+        })
+    }
+
+    /// Feed bytes received on the channel, returning the replies to send
+    ///
+    /// Fails when the client sends a packet larger than the server accepts,
+    /// after which the session should be closed.
+    pub fn process(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        self.input.extend_from_slice(data);
+        let mut out = Vec::new();
+        let mut consumed = 0;
+
+        while let Some(header) = self.input.get(consumed..consumed + 4) {
+            let len = u32::from_be_bytes(header.try_into().unwrap()) as usize;
+            if len == 0 || len > MAX_PACKET {
+                bail!("SFTP packet of {} bytes exceeds the limit", len);
+            }
+            let Some(packet) = self.input.get(consumed + 4..consumed + 4 + len) else {
+                break;
+            };
+            let packet = packet.to_vec();
+            consumed += 4 + len;
+            if let Some(reply) = self.handle_packet(packet[0], &packet[1..]) {
+                reply.finish(&mut out);
+            }
+        }
+
+        self.input.drain(..consumed);
+        Ok(out)
+    }
+
+    fn handle_packet(&mut self, kind: u8, payload: &[u8]) -> Option<Reply> {
+        let mut r = Reader { buf: payload };
+        if kind == SSH_FXP_INIT {
+            // Extensions offered by the client are ignored
+            let mut reply = Reply(vec![SSH_FXP_VERSION]);
+            reply.u32(SFTP_VERSION);
+            return Some(reply);
+        }
+        // Without an id there is nothing to answer
+        let id = r.u32().ok()?;
+
+        let result = match kind {
+            SSH_FXP_OPEN => self.open(id, &mut r),
+            SSH_FXP_CLOSE => self.close(id, &mut r),
+            SSH_FXP_READ => self.read(id, &mut r),
+            SSH_FXP_WRITE => self.write(id, &mut r),
+            SSH_FXP_STAT => self.stat(id, &mut r, true),
+            SSH_FXP_LSTAT => self.stat(id, &mut r, false),
+            SSH_FXP_FSTAT => self.fstat(id, &mut r),
+            SSH_FXP_SETSTAT => self.setstat(id, &mut r),
+            SSH_FXP_FSETSTAT => self.fsetstat(id, &mut r),
+            SSH_FXP_OPENDIR => self.opendir(id, &mut r),
+            SSH_FXP_READDIR => self.readdir(id, &mut r),
+            SSH_FXP_REMOVE => self.modify(&mut r, |path| fs::remove_file(path)),
+            SSH_FXP_MKDIR => self.mkdir(&mut r),
+            SSH_FXP_RMDIR => self.modify(&mut r, |path| fs::remove_dir(path)),
+            SSH_FXP_REALPATH => self.realpath(id, &mut r),
+            SSH_FXP_RENAME => self.rename(&mut r),
+            SSH_FXP_READLINK => self.readlink(id, &mut r),
+            SSH_FXP_SYMLINK => self.symlink(&mut r),
+            _ => Err(Status::new(SSH_FX_OP_UNSUPPORTED, "unsupported operation")),
+        };
+        Some(match result {
+            Ok(Some(reply)) => reply,
+            Ok(None) => Reply::ok(id),
+            Err(status) => Reply::status(id, &status),
+        })
+    }
+
+    /// The absolute path `path` names, as the client sees it
+    fn client_path(&self, path: &str) -> PathBuf {
+        // Relative paths start from the root, which chrooted clients see as "/"
+        let base = if self.chroot {
+            Path::new("/")
+        } else {
+            &self.root
+        };
+        let mut out = PathBuf::from("/");
+        for component in base.join(path).components() {
+            match component {
+                Component::ParentDir => {
+                    out.pop();
+                }
+                Component::Normal(part) => out.push(part),
+                _ => {}
+            }
+        }
+        out
+    }
+
+    /// The filesystem path `path` names
+    ///
+    /// In chroot mode symlinks are resolved here, so the returned path
+    /// contains none and lies inside the root; a final component that does
+    /// not exist yet is kept under its resolved parent. With `follow` unset
+    /// a final symlink is returned itself, for operations on the link.
+    fn resolve(&self, path: &str, follow: bool) -> Result<PathBuf, Status> {
+        let client_path = self.client_path(path);
+        if !self.chroot {
+            return Ok(client_path);
+        }
+        let relative = client_path.strip_prefix("/").unwrap();
+        let Some(name) = relative.file_name() else {
+            return Ok(self.root.clone());
+        };
+        let real = self
+            .confine(&self.root.join(relative.parent().unwrap()))?
+            .join(name);
+
+        match fs::symlink_metadata(&real) {
+            // Symlinks inside the root may still point out of it, or dangle
+            // where following them would create a file
+            Ok(meta) if follow && meta.file_type().is_symlink() => self.confine(&real),
+            _ => Ok(real),
+        }
+    }
+
+    /// Canonicalize `path`, refusing paths that end up outside the root
+    fn confine(&self, path: &Path) -> Result<PathBuf, Status> {
+        let canonical = fs::canonicalize(path)?;
+        if !canonical.starts_with(&self.root) {
+            return Err(Status::outside_root());
+        }
+        Ok(canonical)
+    }
+
+    fn path_arg(&self, r: &mut Reader<'_>) -> Result<PathBuf, Status> {
+        self.resolve(&r.string()?, true)
+    }
+
+    /// The path argument of an operation on a symlink itself
+    fn link_path_arg(&self, r: &mut Reader<'_>) -> Result<PathBuf, Status> {
+        self.resolve(&r.string()?, false)
+    }
+
+    /// The target to store for a new symlink at `link`
+    ///
+    /// In chroot mode targets leading out of the root are refused, and
+    /// absolute targets are client paths, stored as real ones.
+    fn symlink_target(&self, link: &Path, target: &str) -> Result<PathBuf, Status> {
+        if !self.chroot {
+            return Ok(PathBuf::from(target));
+        }
+        if Path::new(target).is_absolute() {
+            let client_path = self.client_path(target);
+            return Ok(self.root.join(client_path.strip_prefix("/").unwrap()));
+        }
+        let mut resolved = link.parent().unwrap_or(&self.root).to_path_buf();
+        for component in Path::new(target).components() {
+            match component {
+                Component::ParentDir => {
+                    resolved.pop();
+                }
+                Component::Normal(part) => resolved.push(part),
+                _ => {}
+            }
+            if !resolved.starts_with(&self.root) {
+                return Err(Status::outside_root());
+            }
+        }
+        Ok(PathBuf::from(target))
+    }
+
+    fn check_writable(&self) -> Result<(), Status> {
+        if self.read_only {
+            return Err(Status::read_only());
+        }
+        Ok(())
+    }
+
+    fn add_handle(&mut self, id: u32, handle: Handle) -> Reply {
+        let name = self.next_handle.to_string();
+        self.next_handle += 1;
+        let mut reply = Reply::new(SSH_FXP_HANDLE, id);
+        reply.string(name.as_bytes());
+        self.handles.insert(name, handle);
+        reply
+    }
+
+    fn file(&mut self, r: &mut Reader<'_>) -> Result<&mut File, Status> {
+        match self.handles.get_mut(&r.string()?) {
+            Some(Handle::File(file)) => Ok(file),
+            _ => Err(Status::invalid_handle()),
+        }
+    }
+
+    fn open(&mut self, id: u32, r: &mut Reader<'_>) -> Result<Option<Reply>, Status> {
+        let path = self.path_arg(r)?;
+        let pflags = r.u32()?;
+        let attrs = r.attrs()?;
+        let writes = SSH_FXF_WRITE | SSH_FXF_APPEND | SSH_FXF_CREAT | SSH_FXF_TRUNC;
+        if pflags & writes != 0 {
+            self.check_writable()?;
+        }
+
+        let mut options = OpenOptions::new();
+        options
+            .read(pflags & SSH_FXF_READ != 0)
+            .write(pflags & SSH_FXF_WRITE != 0)
+            .append(pflags & SSH_FXF_APPEND != 0)
+            .truncate(pflags & SSH_FXF_TRUNC != 0);
+        if pflags & SSH_FXF_CREAT != 0 {
+            if pflags & SSH_FXF_EXCL != 0 {
+                options.create_new(true);
+            } else {
+                options.create(true);
+            }
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            if let Some(permissions) = attrs.permissions {
+                options.mode(permissions & 0o7777);
+            }
+            // `path` has no symlinks left, so one appearing now is a race
+            if self.chroot {
+                options.custom_flags(libc::O_NOFOLLOW);
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = attrs;
+
+        let file = options.open(path)?;
+        Ok(Some(self.add_handle(id, Handle::File(file))))
+    }
+
+    fn close(&mut self, _id: u32, r: &mut Reader<'_>) -> Result<Option<Reply>, Status> {
+        match self.handles.remove(&r.string()?) {
+            Some(_) => Ok(None),
+            None => Err(Status::invalid_handle()),
+        }
+    }
+
+    fn read(&mut self, id: u32, r: &mut Reader<'_>) -> Result<Option<Reply>, Status> {
+        let file = self.file(r)?;
+        let offset = r.u64()?;
+        let len = r.u32()?.min(MAX_READ) as usize;
+
+        file.seek(SeekFrom::Start(offset))?;
+        let mut data = Vec::with_capacity(len);
+        file.take(len as u64).read_to_end(&mut data)?;
+        if data.is_empty() && len > 0 {
+            return Err(Status::new(SSH_FX_EOF, "end of file"));
+        }
+        let mut reply = Reply::new(SSH_FXP_DATA, id);
+        reply.string(&data);
+        Ok(Some(reply))
+    }
+
+    fn write(&mut self, _id: u32, r: &mut Reader<'_>) -> Result<Option<Reply>, Status> {
+        self.check_writable()?;
+        let file = self.file(r)?;
+        let offset = r.u64()?;
+        let data = r.bytes()?;
+
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(data)?;
+        Ok(None)
+    }
+
+    fn stat(&mut self, id: u32, r: &mut Reader<'_>, follow: bool) -> Result<Option<Reply>, Status> {
+        let path = self.resolve(&r.string()?, follow)?;
+        let meta = if follow {
+            fs::metadata(path)?
+        } else {
+            fs::symlink_metadata(path)?
+        };
+        let mut reply = Reply::new(SSH_FXP_ATTRS, id);
+        reply.attrs(&meta);
+        Ok(Some(reply))
+    }
+
+    fn fstat(&mut self, id: u32, r: &mut Reader<'_>) -> Result<Option<Reply>, Status> {
+        let meta = self.file(r)?.metadata()?;
+        let mut reply = Reply::new(SSH_FXP_ATTRS, id);
+        reply.attrs(&meta);
+        Ok(Some(reply))
+    }
+
+    fn setstat(&mut self, _id: u32, r: &mut Reader<'_>) -> Result<Option<Reply>, Status> {
+        self.check_writable()?;
+        let path = self.path_arg(r)?;
+        let attrs = r.attrs()?;
+        let file = if attrs.size.is_some() {
+            OpenOptions::new().write(true).open(&path)?
+        } else {
+            File::open(&path)?
+        };
+        set_attrs(&file, &attrs)?;
+        Ok(None)
+    }
+
+    fn fsetstat(&mut self, _id: u32, r: &mut Reader<'_>) -> Result<Option<Reply>, Status> {
+        self.check_writable()?;
+        let file = self.file(r)?;
+        let attrs = r.attrs()?;
+        set_attrs(file, &attrs)?;
+        Ok(None)
+    }
+
+    fn opendir(&mut self, id: u32, r: &mut Reader<'_>) -> Result<Option<Reply>, Status> {
+        let path = self.path_arg(r)?;
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            entries.push((
+                entry.file_name().to_string_lossy().into_owned(),
+                entry.metadata()?,
+            ));
+        }
+        // Served from the end, so reverse to list in directory order
+        entries.reverse();
+        Ok(Some(self.add_handle(id, Handle::Dir(entries))))
+    }
+
+    fn readdir(&mut self, id: u32, r: &mut Reader<'_>) -> Result<Option<Reply>, Status> {
+        let Some(Handle::Dir(entries)) = self.handles.get_mut(&r.string()?) else {
+            return Err(Status::invalid_handle());
+        };
+        if entries.is_empty() {
+            return Err(Status::new(SSH_FX_EOF, "no more entries"));
+        }
+
+        let batch = entries.split_off(entries.len().saturating_sub(READDIR_BATCH));
+        let mut reply = Reply::new(SSH_FXP_NAME, id);
+        reply.u32(batch.len() as u32);
+        for (name, meta) in batch.iter().rev() {
+            reply.string(name.as_bytes());
+            reply.string(long_name(name, meta).as_bytes());
+            reply.attrs(meta);
+        }
+        Ok(Some(reply))
+    }
+
+    /// Apply a modification to the path argument, not following a final
+    /// symlink
+    fn modify(
+        &mut self,
+        r: &mut Reader<'_>,
+        op: impl FnOnce(&Path) -> io::Result<()>,
+    ) -> Result<Option<Reply>, Status> {
+        self.check_writable()?;
+        op(&self.link_path_arg(r)?)?;
+        Ok(None)
+    }
+
+    fn mkdir(&mut self, r: &mut Reader<'_>) -> Result<Option<Reply>, Status> {
+        self.check_writable()?;
+        let path = self.path_arg(r)?;
+        let attrs = r.attrs()?;
+        let mut builder = fs::DirBuilder::new();
+        #[cfg(unix)]
+        if let Some(permissions) = attrs.permissions {
+            use std::os::unix::fs::DirBuilderExt;
+            builder.mode(permissions & 0o7777);
+        }
+        #[cfg(not(unix))]
+        let _ = attrs;
+        builder.create(path)?;
+        Ok(None)
+    }
+
+    fn realpath(&mut self, id: u32, r: &mut Reader<'_>) -> Result<Option<Reply>, Status> {
+        let path = self.client_path(&r.string()?);
+        let name = path.to_string_lossy();
+        let mut reply = Reply::new(SSH_FXP_NAME, id);
+        reply.u32(1);
+        reply.string(name.as_bytes());
+        reply.string(name.as_bytes());
+        // No attributes
+        reply.u32(0);
+        Ok(Some(reply))
+    }
+
+    fn rename(&mut self, r: &mut Reader<'_>) -> Result<Option<Reply>, Status> {
+        self.check_writable()?;
+        let from = self.link_path_arg(r)?;
+        let to = self.link_path_arg(r)?;
+        // Version 3 renames never replace an existing file
+        if fs::symlink_metadata(&to).is_ok() {
+            return Err(Status::new(SSH_FX_FAILURE, "target already exists"));
+        }
+        fs::rename(from, to)?;
+        Ok(None)
+    }
+
+    fn readlink(&mut self, id: u32, r: &mut Reader<'_>) -> Result<Option<Reply>, Status> {
+        let mut target = fs::read_link(self.link_path_arg(r)?)?;
+        // Show targets stored by `symlink_target` as client paths
+        if self.chroot {
+            if let Ok(inside) = target.strip_prefix(&self.root) {
+                target = Path::new("/").join(inside);
+            }
+        }
+        let target = target.to_string_lossy();
+        let mut reply = Reply::new(SSH_FXP_NAME, id);
+        reply.u32(1);
+        reply.string(target.as_bytes());
+        reply.string(target.as_bytes());
+        reply.u32(0);
+        Ok(Some(reply))
+    }
+
+    fn symlink(&mut self, r: &mut Reader<'_>) -> Result<Option<Reply>, Status> {
+        self.check_writable()?;
+        // OpenSSH sends the target first, against the draft's order, and
+        // clients follow it
+        let target = r.string()?;
+        let link = self.link_path_arg(r)?;
+        let target = self.symlink_target(&link, &target)?;
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(target, link)?;
+            Ok(None)
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = (target, link);
+            Err(Status::new(
+                SSH_FX_OP_UNSUPPORTED,
+                "symlinks are unsupported",
+            ))
+        }
+    }
+}
+
+/// Apply client-supplied attributes to an open file
+fn set_attrs(file: &File, attrs: &Attrs) -> io::Result<()> {
+    if let Some(size) = attrs.size {
+        file.set_len(size)?;
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{fchown, PermissionsExt};
+        if let Some(permissions) = attrs.permissions {
+            file.set_permissions(fs::Permissions::from_mode(permissions & 0o7777))?;
+        }
+        if let Some((uid, gid)) = attrs.uid_gid {
+            fchown(file, Some(uid), Some(gid))?;
+        }
+    }
+    #[cfg(not(unix))]
+    if let Some(permissions) = attrs.permissions {
+        let mut perms = file.metadata()?.permissions();
+        perms.set_readonly(permissions & 0o200 == 0);
+        file.set_permissions(perms)?;
+    }
+    if let Some((atime, mtime)) = attrs.times {
+        let at = |secs: u32| std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs.into());
+        file.set_times(
+            fs::FileTimes::new()
+                .set_accessed(at(atime))
+                .set_modified(at(mtime)),
+        )?;
+    }
+    Ok(())
+}
+
+/// Mode bits of `meta`, including the file type
+fn mode(meta: &Metadata) -> u32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        meta.mode()
+    }
+    #[cfg(not(unix))]
+    {
+        let kind = if meta.is_dir() { 0o040000 } else { 0o100000 };
+        let perms = if meta.permissions().readonly() {
+            0o555
+        } else {
+            0o755
+        };
+        kind | perms
+    }
+}
+
+/// `ls -l` style listing of a directory entry
+fn long_name(name: &str, meta: &Metadata) -> String {
+    let mode = mode(meta);
+    let kind = match mode & 0o170000 {
+        0o040000 => 'd',
+        0o120000 => 'l',
+        _ => '-',
+    };
+    let mut perms = String::with_capacity(10);
+    perms.push(kind);
+    for shift in [6, 3, 0] {
+        let bits = (mode >> shift) & 0o7;
+        perms.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        perms.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        perms.push(if bits & 0o1 != 0 { 'x' } else { '-' });
+    }
+
+    #[cfg(unix)]
+    let (links, uid, gid) = {
+        use std::os::unix::fs::MetadataExt;
+        (meta.nlink(), meta.uid(), meta.gid())
+    };
+    #[cfg(not(unix))]
+    let (links, uid, gid) = (1, 0, 0);
+
+    format!(
+        "{} {:>3} {:<8} {:<8} {:>8} {}",
+        perms,
+        links,
+        uid,
+        gid,
+        meta.len(),
+        name
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Minimal SFTP client driving a server in memory
+    struct Client {
+        server: SftpServer,
+        next_id: u32,
+    }
+
+    impl Client {
+        fn new(root: &Path, chroot: bool, read_only: bool) -> Self {
+            let config = SshConfig {
+                sftp_root: root.to_path_buf(),
+                sftp_chroot: chroot,
+                sftp_read_only: read_only,
+                ..Default::default()
+            };
+            let mut client = Client {
+                server: SftpServer::new(&config).unwrap(),
+                next_id: 1,
+            };
+            let version = client.exchange(SSH_FXP_INIT, &SFTP_VERSION.to_be_bytes());
+            assert_eq!(
+                version,
+                [&[SSH_FXP_VERSION][..], &3u32.to_be_bytes()].concat()
+            );
+            client
+        }
+
+        /// Send one packet and return the single reply, without framing
+        fn exchange(&mut self, kind: u8, body: &[u8]) -> Vec<u8> {
+            let mut packet = ((body.len() + 1) as u32).to_be_bytes().to_vec();
+            packet.push(kind);
+            packet.extend_from_slice(body);
+            let out = self.server.process(&packet).unwrap();
+            let len = u32::from_be_bytes(out[..4].try_into().unwrap()) as usize;
+            assert_eq!(out.len(), 4 + len);
+            out[4..].to_vec()
+        }
+
+        /// Send a request with a fresh id, returning the reply type and body
+        fn request(&mut self, kind: u8, fields: &[&[u8]]) -> (u8, Vec<u8>) {
+            let id = self.next_id;
+            self.next_id += 1;
+            let mut body = id.to_be_bytes().to_vec();
+            for field in fields {
+                body.extend_from_slice(field);
+            }
+            let reply = self.exchange(kind, &body);
+            assert_eq!(reply[1..5], id.to_be_bytes());
+            (reply[0], reply[5..].to_vec())
+        }
+
+        fn status(&mut self, kind: u8, fields: &[&[u8]]) -> u32 {
+            let (reply, body) = self.request(kind, fields);
+            assert_eq!(reply, SSH_FXP_STATUS);
+            u32::from_be_bytes(body[..4].try_into().unwrap())
+        }
+
+        fn handle(&mut self, kind: u8, fields: &[&[u8]]) -> Vec<u8> {
+            let (reply, body) = self.request(kind, fields);
+            assert_eq!(reply, SSH_FXP_HANDLE, "{:?}", body);
+            string(&body[4..])
+        }
+    }
+
+    fn string(value: &[u8]) -> Vec<u8> {
+        [&(value.len() as u32).to_be_bytes()[..], value].concat()
+    }
+
+    fn no_attrs() -> Vec<u8> {
+        0u32.to_be_bytes().to_vec()
+    }
+
+    /// Names listed in a NAME reply body
+    fn names(body: &[u8]) -> Vec<String> {
+        let mut r = Reader { buf: body };
+        let count = r.u32().unwrap();
+        (0..count)
+            .map(|_| {
+                let name = r.string().unwrap();
+                r.string().unwrap();
+                r.attrs().unwrap();
+                name
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_open_write_read_close() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = Client::new(dir.path(), true, false);
+
+        let flags = (SSH_FXF_WRITE | SSH_FXF_CREAT | SSH_FXF_TRUNC).to_be_bytes();
+        let handle = client.handle(SSH_FXP_OPEN, &[&string(b"/notes.txt"), &flags, &no_attrs()]);
+        let status = client.status(
+            SSH_FXP_WRITE,
+            &[&handle, &0u64.to_be_bytes(), &string(b"hello world")],
+        );
+        assert_eq!(status, SSH_FX_OK);
+        assert_eq!(client.status(SSH_FXP_CLOSE, &[&handle]), SSH_FX_OK);
+        assert_eq!(
+            fs::read(dir.path().join("notes.txt")).unwrap(),
+            b"hello world"
+        );
+
+        let handle = client.handle(
+            SSH_FXP_OPEN,
+            &[
+                &string(b"notes.txt"),
+                &SSH_FXF_READ.to_be_bytes(),
+                &no_attrs(),
+            ],
+        );
+        let (kind, body) = client.request(
+            SSH_FXP_READ,
+            &[&handle, &6u64.to_be_bytes(), &100u32.to_be_bytes()],
+        );
+        assert_eq!(kind, SSH_FXP_DATA);
+        assert_eq!(body, string(b"world"));
+        let status = client.status(
+            SSH_FXP_READ,
+            &[&handle, &11u64.to_be_bytes(), &100u32.to_be_bytes()],
+        );
+        assert_eq!(status, SSH_FX_EOF);
+        assert_eq!(client.status(SSH_FXP_CLOSE, &[&handle]), SSH_FX_OK);
+
+        // The handle is gone once closed
+        assert_eq!(client.status(SSH_FXP_CLOSE, &[&handle]), SSH_FX_FAILURE);
+    }
+
+    #[test]
+    fn test_readdir() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), b"a").unwrap();
+        fs::write(dir.path().join("b.txt"), b"bb").unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        let mut client = Client::new(dir.path(), true, false);
+
+        let handle = client.handle(SSH_FXP_OPENDIR, &[&string(b"/")]);
+        let (kind, body) = client.request(SSH_FXP_READDIR, &[&handle]);
+        assert_eq!(kind, SSH_FXP_NAME);
+        let mut listed = names(&body);
+        listed.sort();
+        assert_eq!(listed, ["a.txt", "b.txt", "sub"]);
+
+        assert_eq!(client.status(SSH_FXP_READDIR, &[&handle]), SSH_FX_EOF);
+        assert_eq!(client.status(SSH_FXP_CLOSE, &[&handle]), SSH_FX_OK);
+    }
+
+    #[test]
+    fn test_read_only_refuses_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("keep.txt"), b"keep").unwrap();
+        let mut client = Client::new(dir.path(), true, true);
+
+        let flags = (SSH_FXF_WRITE | SSH_FXF_CREAT).to_be_bytes();
+        let status = client.status(SSH_FXP_OPEN, &[&string(b"/new.txt"), &flags, &no_attrs()]);
+        assert_eq!(status, SSH_FX_PERMISSION_DENIED);
+        let status = client.status(SSH_FXP_REMOVE, &[&string(b"/keep.txt")]);
+        assert_eq!(status, SSH_FX_PERMISSION_DENIED);
+        let status = client.status(SSH_FXP_MKDIR, &[&string(b"/sub"), &no_attrs()]);
+        assert_eq!(status, SSH_FX_PERMISSION_DENIED);
+        assert!(dir.path().join("keep.txt").exists());
+        assert!(!dir.path().join("new.txt").exists());
+
+        // Reading still works
+        let handle = client.handle(
+            SSH_FXP_OPEN,
+            &[
+                &string(b"/keep.txt"),
+                &SSH_FXF_READ.to_be_bytes(),
+                &no_attrs(),
+            ],
+        );
+        assert_eq!(client.status(SSH_FXP_CLOSE, &[&handle]), SSH_FX_OK);
+    }
+
+    #[test]
+    fn test_chroot_confines_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        let mut client = Client::new(dir.path(), true, false);
+
+        let (kind, body) = client.request(SSH_FXP_REALPATH, &[&string(b"sub/../../..")]);
+        assert_eq!(kind, SSH_FXP_NAME);
+        assert_eq!(names(&body), ["/"]);
+
+        // "/sub" is the root's subdirectory, not the host's
+        let (kind, _) = client.request(SSH_FXP_STAT, &[&string(b"/../sub")]);
+        assert_eq!(kind, SSH_FXP_ATTRS);
+        let status = client.status(SSH_FXP_STAT, &[&string(b"/etc")]);
+        assert_eq!(status, SSH_FX_NO_SUCH_FILE);
+
+        #[cfg(unix)]
+        {
+            let outside = tempfile::tempdir().unwrap();
+            fs::write(outside.path().join("secret"), b"secret").unwrap();
+            std::os::unix::fs::symlink(outside.path(), dir.path().join("escape")).unwrap();
+            let status = client.status(
+                SSH_FXP_OPEN,
+                &[
+                    &string(b"/escape/secret"),
+                    &SSH_FXF_READ.to_be_bytes(),
+                    &no_attrs(),
+                ],
+            );
+            assert_eq!(status, SSH_FX_PERMISSION_DENIED);
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_chroot_symlinks_cannot_escape() {
+        let dir = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        let pwned = outside.path().join("pwned");
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        let mut client = Client::new(dir.path(), true, false);
+        let open_flags = (SSH_FXF_WRITE | SSH_FXF_CREAT).to_be_bytes();
+
+        // Targets leading out of the root are refused
+        for target in ["../../../etc/passwd", "sub/../.."] {
+            let status = client.status(
+                SSH_FXP_SYMLINK,
+                &[&string(target.as_bytes()), &string(b"/link")],
+            );
+            assert_eq!(status, SSH_FX_PERMISSION_DENIED, "{}", target);
+        }
+
+        // A dangling link planted outside SFTP is never followed
+        std::os::unix::fs::symlink(&pwned, dir.path().join("link")).unwrap();
+        let status = client.status(SSH_FXP_OPEN, &[&string(b"/link"), &open_flags, &no_attrs()]);
+        assert_ne!(status, SSH_FX_OK);
+        let status = client.status(SSH_FXP_MKDIR, &[&string(b"/link"), &no_attrs()]);
+        assert_ne!(status, SSH_FX_OK);
+        assert!(!pwned.exists());
+
+        // The link itself can still be removed
+        assert_eq!(
+            client.status(SSH_FXP_REMOVE, &[&string(b"/link")]),
+            SSH_FX_OK
+        );
+        assert!(fs::symlink_metadata(dir.path().join("link")).is_err());
+
+        // Absolute targets are client paths
+        let status = client.status(SSH_FXP_SYMLINK, &[&string(b"/sub"), &string(b"/sub-link")]);
+        assert_eq!(status, SSH_FX_OK);
+        let (kind, body) = client.request(SSH_FXP_READLINK, &[&string(b"/sub-link")]);
+        assert_eq!(kind, SSH_FXP_NAME);
+        assert_eq!(names(&body), ["/sub"]);
+        client.handle(
+            SSH_FXP_OPEN,
+            &[&string(b"/sub-link/file"), &open_flags, &no_attrs()],
+        );
+        assert!(dir.path().join("sub/file").exists());
+    }
+
+    #[test]
+    fn test_without_chroot_paths_are_absolute() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = Client::new(dir.path(), false, false);
+
+        let (_, body) = client.request(SSH_FXP_REALPATH, &[&string(b".")]);
+        let root = fs::canonicalize(dir.path()).unwrap();
+        assert_eq!(names(&body), [root.to_string_lossy()]);
+    }
+
+    #[test]
+    fn test_packets_split_across_reads() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = Client::new(dir.path(), true, false);
+
+        let mut packet = Vec::new();
+        let body = [&7u32.to_be_bytes()[..], &string(b"/")].concat();
+        packet.extend_from_slice(&((body.len() + 1) as u32).to_be_bytes());
+        packet.push(SSH_FXP_STAT);
+        packet.extend_from_slice(&body);
+
+        let (head, tail) = packet.split_at(6);
+        assert!(client.server.process(head).unwrap().is_empty());
+        let out = client.server.process(tail).unwrap();
+        assert_eq!(out[4], SSH_FXP_ATTRS);
+        assert_eq!(out[5..9], 7u32.to_be_bytes());
+
+        // An oversized packet ends the session
+        let huge = ((MAX_PACKET + 1) as u32).to_be_bytes();
+        assert!(client.server.process(&huge).is_err());
+    }
+}
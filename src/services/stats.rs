@@ -0,0 +1,290 @@
+//! Per-service connection statistics
+//!
+//! Each service handler keeps a [`ServiceStats`] and runs its connections
+//! through [`ServiceStats::track`], so the counters cover every connection
+//! the service handled. Bytes, and the duration and throughput histograms,
+//! are fed from each connection's [`ConnectionSummary`] when it closes.
+
+use crate::services::{CloseReason, ConnectionSummary};
+use anyhow::Result;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Upper bounds of the connection duration buckets, in milliseconds
+pub const DURATION_BUCKETS_MS: [u64; 8] = [
+    100, 1_000, 10_000, 60_000, 300_000, 1_800_000, 3_600_000, 86_400_000,
+];
+
+/// Upper bounds of the connection throughput buckets, in bytes per second
+pub const THROUGHPUT_BUCKETS: [u64; 7] = [
+    1_000,
+    10_000,
+    100_000,
+    1_000_000,
+    10_000_000,
+    100_000_000,
+    1_000_000_000,
+];
+
+/// Atomic counters for one service
+#[derive(Debug)]
+pub struct ServiceStats {
+    active_connections: AtomicU64,
+    total_connections: AtomicU64,
+    bytes_up: AtomicU64,
+    bytes_down: AtomicU64,
+    errors: AtomicU64,
+    /// How long closed connections lasted, in milliseconds
+    duration_ms: Histogram<8>,
+    /// Average throughput of closed connections, in bytes per second
+    throughput: Histogram<7>,
+}
+
+impl Default for ServiceStats {
+    fn default() -> Self {
+        ServiceStats {
+            active_connections: AtomicU64::new(0),
+            total_connections: AtomicU64::new(0),
+            bytes_up: AtomicU64::new(0),
+            bytes_down: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            duration_ms: Histogram::new(DURATION_BUCKETS_MS),
+            throughput: Histogram::new(THROUGHPUT_BUCKETS),
+        }
+    }
+}
+
+/// Atomic histogram with one bucket per upper bound
+#[derive(Debug)]
+struct Histogram<const N: usize> {
+    bounds: [u64; N],
+    /// Observations per bucket, not cumulative
+    buckets: [AtomicU64; N],
+    count: AtomicU64,
+    sum: AtomicU64,
+}
+
+impl<const N: usize> Histogram<N> {
+    fn new(bounds: [u64; N]) -> Self {
+        Histogram {
+            bounds,
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, value: u64) {
+        if let Some(bucket) = self.bounds.iter().position(|&bound| value <= bound) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> HistogramSnapshot<N> {
+        let mut cumulative = 0;
+        HistogramSnapshot {
+            buckets: std::array::from_fn(|bucket| {
+                cumulative += self.buckets[bucket].load(Ordering::Relaxed);
+                cumulative
+            }),
+            count: self.count.load(Ordering::Relaxed),
+            sum: self.sum.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Point-in-time copy of a histogram, with cumulative buckets as
+/// Prometheus exposes them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HistogramSnapshot<const N: usize> {
+    /// Observations at or below each bucket's upper bound
+    pub buckets: [u64; N],
+    /// All observations, including those above the last bound
+    pub count: u64,
+    /// Sum of the observed values
+    pub sum: u64,
+}
+
+impl<const N: usize> Default for HistogramSnapshot<N> {
+    fn default() -> Self {
+        HistogramSnapshot {
+            buckets: [0; N],
+            count: 0,
+            sum: 0,
+        }
+    }
+}
+
+/// Point-in-time copy of a [`ServiceStats`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ServiceStatsSnapshot {
+    /// Connections being handled right now
+    pub active_connections: u64,
+    /// Connections accepted since startup
+    pub total_connections: u64,
+    /// Bytes sent from tunnel clients towards targets
+    pub bytes_up: u64,
+    /// Bytes sent from targets back to tunnel clients
+    pub bytes_down: u64,
+    /// Connections that ended in an error
+    pub errors: u64,
+    /// Durations of closed connections, in milliseconds
+    pub duration_ms: HistogramSnapshot<8>,
+    /// Average throughput of closed connections, in bytes per second
+    pub throughput: HistogramSnapshot<7>,
+}
+
+/// Decrements the active count when the connection ends, however it ends
+struct ActiveConnection<'a>(&'a AtomicU64);
+
+impl Drop for ActiveConnection<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl ServiceStats {
+    /// Create zeroed counters
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count `connection` as active while it runs, then record its outcome
+    pub async fn track<F>(&self, connection: F) -> Result<ConnectionSummary>
+    where
+        F: Future<Output = Result<ConnectionSummary>>,
+    {
+        self.total_connections.fetch_add(1, Ordering::Relaxed);
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+        let _active = ActiveConnection(&self.active_connections);
+
+        let result = connection.await;
+        self.record(&result);
+        result
+    }
+
+    /// Add the bytes of a finished connection, counting failures as errors
+    pub fn record(&self, result: &Result<ConnectionSummary>) {
+        match result {
+            Ok(summary) => {
+                self.bytes_up.fetch_add(summary.bytes_up, Ordering::Relaxed);
+                self.bytes_down
+                    .fetch_add(summary.bytes_down, Ordering::Relaxed);
+                self.duration_ms
+                    .observe(summary.duration.as_millis().try_into().unwrap_or(u64::MAX));
+                // A connection that took no measurable time has no rate
+                let micros = summary.duration.as_micros();
+                if let Some(rate) =
+                    (u128::from(summary.total_bytes()) * 1_000_000).checked_div(micros)
+                {
+                    self.throughput.observe(rate.try_into().unwrap_or(u64::MAX));
+                }
+                if matches!(summary.close_reason, CloseReason::Error(_)) {
+                    self.errors.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            Err(_) => {
+                self.errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Read all counters
+    pub fn snapshot(&self) -> ServiceStatsSnapshot {
+        ServiceStatsSnapshot {
+            active_connections: self.active_connections.load(Ordering::Relaxed),
+            total_connections: self.total_connections.load(Ordering::Relaxed),
+            bytes_up: self.bytes_up.load(Ordering::Relaxed),
+            bytes_down: self.bytes_down.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            duration_ms: self.duration_ms.snapshot(),
+            throughput: self.throughput.snapshot(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_track_counts_connections() {
+        let stats = ServiceStats::new();
+
+        let summary = stats
+            .track(async {
+                assert_eq!(stats.snapshot().active_connections, 1);
+                Ok(ConnectionSummary::new(CloseReason::ClientClosed).with_bytes(10, 20))
+            })
+            .await
+            .unwrap();
+        assert_eq!(summary.total_bytes(), 30);
+        let _ = stats
+            .track(async { anyhow::bail!("handshake failed") })
+            .await;
+        let _ = stats
+            .track(async { Ok(ConnectionSummary::new(CloseReason::Error("reset".into()))) })
+            .await;
+
+        let snapshot = stats.snapshot();
+        assert_eq!(
+            snapshot,
+            ServiceStatsSnapshot {
+                active_connections: 0,
+                total_connections: 3,
+                bytes_up: 10,
+                bytes_down: 20,
+                errors: 2,
+                ..snapshot
+            }
+        );
+        // Only the two connections that closed with a summary are timed
+        assert_eq!(snapshot.duration_ms.count, 2);
+    }
+
+    #[test]
+    fn test_closed_connections_fill_histogram_buckets() {
+        let stats = ServiceStats::new();
+        let close = |bytes: u64, duration: Duration| {
+            stats.record(&Ok(ConnectionSummary::new(CloseReason::Completed)
+                .with_bytes(bytes, 0)
+                .with_duration(duration)));
+        };
+        close(50, Duration::from_millis(50));
+        close(5_000_000, Duration::from_secs(2));
+        close(1, Duration::from_secs(100_000));
+        close(0, Duration::ZERO);
+
+        let duration = stats.snapshot().duration_ms;
+        // <=100ms: the 50ms and zero-length connections; <=10s adds the 2s one
+        assert_eq!(duration.buckets[0], 2);
+        assert_eq!(duration.buckets[1], 2);
+        assert_eq!(duration.buckets[2], 3);
+        // 100000s is above every bound, so it is only in the count
+        assert_eq!(duration.buckets[7], 3);
+        assert_eq!(duration.count, 4);
+        assert_eq!(duration.sum, 50 + 2_000 + 100_000_000);
+
+        // 1000 B/s, 2.5 MB/s and ~0 B/s; the zero-length connection has no rate
+        let throughput = stats.snapshot().throughput;
+        assert_eq!(throughput.count, 3);
+        assert_eq!(throughput.buckets[0], 2);
+        assert_eq!(throughput.buckets[3], 2);
+        assert_eq!(throughput.buckets[4], 3);
+        assert_eq!(throughput.sum, 1_000 + 2_500_000);
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_connection_is_no_longer_active() {
+        let stats = ServiceStats::new();
+        let pending = stats.track(futures::future::pending());
+        let _ = tokio::time::timeout(std::time::Duration::from_millis(10), pending).await;
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.active_connections, 0);
+        assert_eq!(snapshot.total_connections, 1);
+    }
+}
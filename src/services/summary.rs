@@ -0,0 +1,256 @@
+//! Connection summaries reported by service handlers
+//!
+//! Every [`ServiceHandler`](super::ServiceHandler) returns a
+//! [`ConnectionSummary`] describing what happened on the stream it was
+//! handed: how many bytes moved in each direction, where they went, how
+//! long the connection lasted and why it ended. The data channel logs it,
+//! and tests use it to assert on the outcome of a session.
+
+#[cfg(feature = "socks")]
+use super::socks::SocksRequest;
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Why a handled connection ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The tunnel-side client finished sending first
+    ClientClosed,
+    /// The target finished sending first
+    TargetClosed,
+    /// The request was refused before any payload was relayed
+    Rejected,
+    /// The client did not offer the authentication the service requires
+    AuthRequired,
+    /// The service ran its session to completion
+    Completed,
+    /// The relay stopped on an I/O error
+    Error(String),
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CloseReason::ClientClosed => write!(f, "client closed"),
+            CloseReason::TargetClosed => write!(f, "target closed"),
+            CloseReason::Rejected => write!(f, "rejected"),
+            CloseReason::AuthRequired => write!(f, "authentication required"),
+            CloseReason::Completed => write!(f, "completed"),
+            CloseReason::Error(e) => write!(f, "error: {}", e),
+        }
+    }
+}
+
+/// Outcome of a single connection handled by a service.
+///
+/// `bytes_up` counts bytes flowing from the tunnel client towards the
+/// target (or local service), `bytes_down` counts bytes flowing back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionSummary {
+    /// Bytes sent from the tunnel client towards the target
+    pub bytes_up: u64,
+    /// Bytes sent from the target back to the tunnel client
+    pub bytes_down: u64,
+    /// The requested target, if the protocol has one
+    pub target: Option<String>,
+    /// How long the connection was handled for
+    pub duration: Duration,
+    /// Why the connection ended
+    pub close_reason: CloseReason,
+    /// The SOCKS5 request that was handled, for SOCKS5 connections
+    #[cfg(feature = "socks")]
+    pub socks_request: Option<SocksRequest>,
+}
+
+impl ConnectionSummary {
+    /// Create an empty summary with the given close reason
+    pub fn new(close_reason: CloseReason) -> Self {
+        ConnectionSummary {
+            bytes_up: 0,
+            bytes_down: 0,
+            target: None,
+            duration: Duration::ZERO,
+            close_reason,
+            #[cfg(feature = "socks")]
+            socks_request: None,
+        }
+    }
+
+    /// Set the byte counts
+    pub fn with_bytes(mut self, bytes_up: u64, bytes_down: u64) -> Self {
+        self.bytes_up = bytes_up;
+        self.bytes_down = bytes_down;
+        self
+    }
+
+    /// Set the target
+    pub fn with_target(mut self, target: impl Into<String>) -> Self {
+        self.target = Some(target.into());
+        self
+    }
+
+    /// Set the duration
+    pub fn with_duration(mut self, duration: Duration) -> Self {
+        self.duration = duration;
+        self
+    }
+
+    /// Set the SOCKS5 request
+    #[cfg(feature = "socks")]
+    pub fn with_socks_request(mut self, request: SocksRequest) -> Self {
+        self.socks_request = Some(request);
+        self
+    }
+
+    /// Total bytes transferred in both directions
+    pub fn total_bytes(&self) -> u64 {
+        self.bytes_up + self.bytes_down
+    }
+}
+
+/// Shared byte counters for a [`CountedStream`].
+#[derive(Debug, Clone, Default)]
+pub struct StreamCounters {
+    read: Arc<AtomicU64>,
+    written: Arc<AtomicU64>,
+}
+
+impl StreamCounters {
+    /// Bytes read from the wrapped stream so far
+    pub fn read(&self) -> u64 {
+        self.read.load(Ordering::Relaxed)
+    }
+
+    /// Bytes written to the wrapped stream so far
+    pub fn written(&self) -> u64 {
+        self.written.load(Ordering::Relaxed)
+    }
+}
+
+/// Stream wrapper that counts bytes read and written.
+///
+/// Used by services that hand the whole stream to a protocol library
+/// (SSH, VNC) and therefore cannot count payload bytes themselves. The
+/// counters stay readable after the stream has been moved away.
+#[derive(Debug)]
+pub struct CountedStream<S> {
+    inner: S,
+    counters: StreamCounters,
+}
+
+impl<S> CountedStream<S> {
+    /// Wrap a stream, returning it together with its counters
+    pub fn new(inner: S) -> (Self, StreamCounters) {
+        let counters = StreamCounters::default();
+        (
+            CountedStream {
+                inner,
+                counters: counters.clone(),
+            },
+            counters,
+        )
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CountedStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            let n = (buf.filled().len() - before) as u64;
+            self.counters.read.fetch_add(n, Ordering::Relaxed);
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CountedStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            self.counters.written.fetch_add(n as u64, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_summary_new() {
+        let summary = ConnectionSummary::new(CloseReason::Rejected);
+        assert_eq!(summary.bytes_up, 0);
+        assert_eq!(summary.bytes_down, 0);
+        assert!(summary.target.is_none());
+        assert_eq!(summary.duration, Duration::ZERO);
+        assert_eq!(summary.close_reason, CloseReason::Rejected);
+    }
+
+    #[test]
+    fn test_summary_builders() {
+        let summary = ConnectionSummary::new(CloseReason::ClientClosed)
+            .with_bytes(10, 20)
+            .with_target("example.com:80")
+            .with_duration(Duration::from_secs(2));
+        assert_eq!(summary.bytes_up, 10);
+        assert_eq!(summary.bytes_down, 20);
+        assert_eq!(summary.total_bytes(), 30);
+        assert_eq!(summary.target.as_deref(), Some("example.com:80"));
+        assert_eq!(summary.duration, Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_close_reason_display() {
+        assert_eq!(CloseReason::ClientClosed.to_string(), "client closed");
+        assert_eq!(CloseReason::TargetClosed.to_string(), "target closed");
+        assert_eq!(
+            CloseReason::Error("broken pipe".to_string()).to_string(),
+            "error: broken pipe"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_counted_stream_counts_both_directions() {
+        let (inner, mut peer) = duplex(1024);
+        let (mut stream, counters) = CountedStream::new(inner);
+
+        stream.write_all(b"hello").await.unwrap();
+        peer.write_all(b"abc").await.unwrap();
+
+        let mut buf = [0u8; 3];
+        stream.read_exact(&mut buf).await.unwrap();
+        let mut buf = [0u8; 5];
+        peer.read_exact(&mut buf).await.unwrap();
+
+        assert_eq!(counters.written(), 5);
+        assert_eq!(counters.read(), 3);
+
+        drop(stream);
+        assert_eq!(counters.written(), 5);
+    }
+}
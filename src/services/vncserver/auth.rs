@@ -18,10 +18,24 @@
 //! VNC Authentication is a legacy protocol with known security limitations. It should
 //! only be used on trusted networks or in conjunction with TLS/SSL tunneling (which
 //! is the case when used via rathole tunnels in sockrats).
+//!
+//! # VeNCrypt
+//!
+//! With the `vnc-tls` feature and `tls` configured, the server offers only
+//! VeNCrypt (security type 19): after negotiating an X.509 subtype the
+//! stream is upgraded to TLS, and the subtype's authentication (none, VNC
+//! Authentication, or a plain username and password) runs inside it.
 
+use bytes::{BufMut, BytesMut};
 use des::cipher::{BlockEncrypt, KeyInit};
 use des::Des;
 use rand::Rng;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::info;
+
+use super::protocol::{SECURITY_RESULT_FAILED, SECURITY_RESULT_OK};
+#[cfg(feature = "vnc-tls")]
+pub use vencrypt::vencrypt_handshake;
 
 /// Handles VNC authentication using the VNC Authentication scheme (RFC 6143 §7.2.2).
 ///
@@ -82,13 +96,62 @@ impl VncAuth {
         }
         if let Some(ref password) = self.password {
             let expected = encrypt_challenge(challenge, password);
-            // Constant-time comparison would be ideal, but VNC auth is already
-            // cryptographically weak. The reference impl uses direct comparison.
-            response == expected.as_slice()
+            crate::protocol::constant_time_eq(response, &expected)
         } else {
             false
         }
     }
+
+    /// Checks a password sent in the clear (inside TLS), comparing the whole
+    /// password rather than VNC Authentication's first 8 bytes.
+    #[cfg(feature = "vnc-tls")]
+    pub fn verify_password(&self, password: &[u8]) -> bool {
+        self.password.as_ref().is_some_and(|expected| {
+            crate::protocol::constant_time_eq(password, expected.as_bytes())
+        })
+    }
+}
+
+/// Writes the SecurityResult that ends the security phase.
+pub async fn write_security_result<S: AsyncWriteExt + Unpin>(
+    stream: &mut S,
+    ok: bool,
+) -> Result<(), std::io::Error> {
+    let mut buf = BytesMut::with_capacity(4);
+    buf.put_u32(if ok {
+        SECURITY_RESULT_OK
+    } else {
+        SECURITY_RESULT_FAILED
+    });
+    stream.write_all(&buf).await
+}
+
+/// Runs the VNC Authentication challenge-response on `stream`, ending with
+/// the SecurityResult.
+///
+/// # Errors
+///
+/// Returns `PermissionDenied` if the response does not match.
+pub async fn authenticate_vnc<S: AsyncReadExt + AsyncWriteExt + Unpin>(
+    stream: &mut S,
+    auth: &VncAuth,
+) -> Result<(), std::io::Error> {
+    let challenge = auth.generate_challenge();
+    stream.write_all(&challenge).await?;
+
+    let mut response = vec![0u8; 16];
+    stream.read_exact(&mut response).await?;
+
+    let ok = auth.verify_response(&response, &challenge);
+    write_security_result(stream, ok).await?;
+    if !ok {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            "VNC authentication failed",
+        ));
+    }
+    info!("VNC authentication successful");
+    Ok(())
 }
 
 /// Encrypts a 16-byte challenge with the VNC password using DES-ECB.
@@ -154,6 +217,298 @@ fn reverse_bits(byte: u8) -> u8 {
     result
 }
 
+#[cfg(feature = "vnc-tls")]
+mod vencrypt {
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use anyhow::Context;
+    use bytes::{BufMut, BytesMut};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::rustls::crypto;
+    use tokio_rustls::rustls::pki_types::pem::PemObject;
+    use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+    use tokio_rustls::rustls::ServerConfig;
+    use tokio_rustls::server::TlsStream;
+    use tokio_rustls::TlsAcceptor;
+    use tracing::debug;
+
+    use super::{authenticate_vnc, write_security_result, VncAuth};
+    use crate::services::vncserver::config::VncTlsConfig;
+    use crate::services::vncserver::protocol::{
+        SECURITY_TYPE_VENCRYPT, VENCRYPT_X509_NONE, VENCRYPT_X509_PLAIN, VENCRYPT_X509_VNC,
+    };
+
+    /// The VeNCrypt version spoken: 0.2.
+    const VERSION: [u8; 2] = [0, 2];
+
+    /// Longest username or password accepted by the Plain subtype.
+    const MAX_PLAIN_LEN: usize = 1024;
+
+    /// The subtypes offered: authenticated ones when a password is set.
+    pub(super) fn subtypes(has_password: bool) -> &'static [u32] {
+        if has_password {
+            &[VENCRYPT_X509_VNC, VENCRYPT_X509_PLAIN]
+        } else {
+            &[VENCRYPT_X509_NONE]
+        }
+    }
+
+    /// Builds the TLS acceptor from the configured certificate and key,
+    /// reading them on every connection so renewed files take effect.
+    fn acceptor(config: &VncTlsConfig) -> anyhow::Result<TlsAcceptor> {
+        let certs = read_pem(&config.cert)?;
+        let certs = CertificateDer::pem_slice_iter(&certs)
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("Failed to parse certificates in {:?}", config.cert))?;
+        let key = PrivateKeyDer::from_pem_slice(&read_pem(&config.key)?)
+            .with_context(|| format!("Failed to parse a private key in {:?}", config.key))?;
+
+        let server_config =
+            ServerConfig::builder_with_provider(Arc::new(crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .context("Failed to select TLS protocol versions")?
+                .with_no_client_auth()
+                .with_single_cert(certs, key)
+                .context("Invalid VNC TLS certificate or key")?;
+        Ok(TlsAcceptor::from(Arc::new(server_config)))
+    }
+
+    fn read_pem(path: &Path) -> anyhow::Result<Vec<u8>> {
+        std::fs::read(path).with_context(|| format!("Failed to read {:?}", path))
+    }
+
+    fn invalid(message: String) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+    }
+
+    /// Runs the security phase as VeNCrypt, returning the stream upgraded
+    /// to TLS once the subtype's authentication succeeded.
+    ///
+    /// Picks up after the protocol version exchange and ends with the
+    /// SecurityResult, so ClientInit and ServerInit travel over TLS.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the certificate cannot be loaded, the viewer
+    /// does not speak VeNCrypt 0.2 or picks an unoffered subtype, the TLS
+    /// handshake fails, or authentication fails.
+    pub async fn vencrypt_handshake<S>(
+        mut stream: S,
+        config: &VncTlsConfig,
+        password: Option<String>,
+    ) -> Result<TlsStream<S>, std::io::Error>
+    where
+        S: AsyncReadExt + AsyncWriteExt + Unpin,
+    {
+        let acceptor = acceptor(config).map_err(|e| std::io::Error::other(format!("{:#}", e)))?;
+
+        stream.write_all(&[1, SECURITY_TYPE_VENCRYPT]).await?;
+        let mut sec_type = [0u8; 1];
+        stream.read_exact(&mut sec_type).await?;
+        if sec_type[0] != SECURITY_TYPE_VENCRYPT {
+            return Err(invalid(format!("Unknown security type: {}", sec_type[0])));
+        }
+
+        stream.write_all(&VERSION).await?;
+        let mut version = [0u8; 2];
+        stream.read_exact(&mut version).await?;
+        if version != VERSION {
+            stream.write_all(&[1]).await?;
+            return Err(invalid(format!(
+                "Unsupported VeNCrypt version {}.{}",
+                version[0], version[1]
+            )));
+        }
+        stream.write_all(&[0]).await?;
+
+        let offered = subtypes(password.is_some());
+        let mut buf = BytesMut::with_capacity(1 + 4 * offered.len());
+        buf.put_u8(offered.len() as u8);
+        for &subtype in offered {
+            buf.put_u32(subtype);
+        }
+        stream.write_all(&buf).await?;
+
+        let subtype = stream.read_u32().await?;
+        if !offered.contains(&subtype) {
+            stream.write_all(&[0]).await?;
+            return Err(invalid(format!("Unoffered VeNCrypt subtype: {}", subtype)));
+        }
+        stream.write_all(&[1]).await?;
+        debug!("VeNCrypt subtype {}, starting TLS", subtype);
+
+        let mut tls = acceptor.accept(stream).await?;
+        let auth = VncAuth::new(password);
+        match subtype {
+            VENCRYPT_X509_VNC => authenticate_vnc(&mut tls, &auth).await?,
+            VENCRYPT_X509_PLAIN => authenticate_plain(&mut tls, &auth).await?,
+            _ => write_security_result(&mut tls, true).await?,
+        }
+        Ok(tls)
+    }
+
+    /// Checks the Plain subtype's credentials; only the password counts.
+    async fn authenticate_plain<S: AsyncReadExt + AsyncWriteExt + Unpin>(
+        stream: &mut S,
+        auth: &VncAuth,
+    ) -> Result<(), std::io::Error> {
+        let username_len = stream.read_u32().await? as usize;
+        let password_len = stream.read_u32().await? as usize;
+        if username_len > MAX_PLAIN_LEN || password_len > MAX_PLAIN_LEN {
+            return Err(invalid("VeNCrypt Plain credentials too long".to_string()));
+        }
+        let mut credentials = vec![0u8; username_len + password_len];
+        stream.read_exact(&mut credentials).await?;
+
+        let ok = auth.verify_password(&credentials[username_len..]);
+        write_security_result(stream, ok).await?;
+        if !ok {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "VNC authentication failed",
+            ));
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use tokio::io::{duplex, DuplexStream};
+        use tokio_rustls::client::TlsStream as ClientTlsStream;
+        use tokio_rustls::rustls::pki_types::ServerName;
+        use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+        use tokio_rustls::TlsConnector;
+
+        fn fixture(name: &str) -> std::path::PathBuf {
+            Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("tests/fixtures/tls")
+                .join(name)
+        }
+
+        fn tls_config() -> VncTlsConfig {
+            VncTlsConfig {
+                cert: fixture("server.pem"),
+                key: fixture("server-key.pem"),
+            }
+        }
+
+        /// Negotiate VeNCrypt as a viewer up to the subtype choice,
+        /// returning the offered subtypes bytes
+        async fn negotiate(viewer: &mut DuplexStream, offered_len: usize) -> Vec<u8> {
+            let mut sec_types = [0u8; 2];
+            viewer.read_exact(&mut sec_types).await.unwrap();
+            assert_eq!(sec_types, [1, SECURITY_TYPE_VENCRYPT]);
+            viewer.write_all(&[SECURITY_TYPE_VENCRYPT]).await.unwrap();
+
+            let mut version = [0u8; 2];
+            viewer.read_exact(&mut version).await.unwrap();
+            assert_eq!(version, [0, 2]);
+            viewer.write_all(&[0, 2]).await.unwrap();
+            assert_eq!(viewer.read_u8().await.unwrap(), 0);
+
+            let mut offered = vec![0u8; offered_len];
+            viewer.read_exact(&mut offered).await.unwrap();
+            offered
+        }
+
+        /// Choose `subtype` and start TLS as a viewer trusting the test CA
+        async fn choose_and_connect(
+            mut viewer: DuplexStream,
+            subtype: u32,
+        ) -> ClientTlsStream<DuplexStream> {
+            viewer.write_u32(subtype).await.unwrap();
+            assert_eq!(viewer.read_u8().await.unwrap(), 1);
+
+            let ca = std::fs::read(fixture("ca.pem")).unwrap();
+            let mut roots = RootCertStore::empty();
+            for cert in CertificateDer::pem_slice_iter(&ca) {
+                roots.add(cert.unwrap()).unwrap();
+            }
+            let config =
+                ClientConfig::builder_with_provider(Arc::new(crypto::ring::default_provider()))
+                    .with_safe_default_protocol_versions()
+                    .unwrap()
+                    .with_root_certificates(roots)
+                    .with_no_client_auth();
+            TlsConnector::from(Arc::new(config))
+                .connect(ServerName::try_from("localhost").unwrap(), viewer)
+                .await
+                .unwrap()
+        }
+
+        #[tokio::test]
+        async fn test_advertises_authenticated_subtypes_with_password() {
+            let (stream, mut viewer) = duplex(4096);
+            let server = tokio::spawn(async move {
+                vencrypt_handshake(stream, &tls_config(), Some("secret".to_string())).await
+            });
+
+            let offered = negotiate(&mut viewer, 9).await;
+            assert_eq!(offered, [2, 0, 0, 1, 5, 0, 0, 1, 6]);
+
+            // Without authentication is not on offer
+            viewer.write_u32(VENCRYPT_X509_NONE).await.unwrap();
+            assert_eq!(viewer.read_u8().await.unwrap(), 0);
+            assert!(server.await.unwrap().is_err());
+        }
+
+        #[tokio::test]
+        async fn test_x509_none_upgrades_to_tls() {
+            let (stream, mut viewer) = duplex(16 * 1024);
+            let server =
+                tokio::spawn(async move { vencrypt_handshake(stream, &tls_config(), None).await });
+
+            let offered = negotiate(&mut viewer, 5).await;
+            assert_eq!(offered, [1, 0, 0, 1, 4]);
+            let mut tls = choose_and_connect(viewer, VENCRYPT_X509_NONE).await;
+
+            // The SecurityResult already travels over TLS
+            assert_eq!(tls.read_u32().await.unwrap(), 0);
+            let mut server_tls = server.await.unwrap().unwrap();
+            server_tls.write_all(b"init").await.unwrap();
+            server_tls.flush().await.unwrap();
+            let mut init = [0u8; 4];
+            tls.read_exact(&mut init).await.unwrap();
+            assert_eq!(&init, b"init");
+        }
+
+        #[tokio::test]
+        async fn test_plain_subtype_checks_password() {
+            for (password, accepted) in [("secret", true), ("wrong", false)] {
+                let (stream, mut viewer) = duplex(16 * 1024);
+                let server = tokio::spawn(async move {
+                    vencrypt_handshake(stream, &tls_config(), Some("secret".to_string())).await
+                });
+
+                negotiate(&mut viewer, 9).await;
+                let mut tls = choose_and_connect(viewer, VENCRYPT_X509_PLAIN).await;
+                tls.write_u32(4).await.unwrap();
+                tls.write_u32(password.len() as u32).await.unwrap();
+                tls.write_all(b"user").await.unwrap();
+                tls.write_all(password.as_bytes()).await.unwrap();
+                tls.flush().await.unwrap();
+
+                let result = tls.read_u32().await.unwrap();
+                assert_eq!(result == 0, accepted, "password {:?}", password);
+                assert_eq!(server.await.unwrap().is_ok(), accepted);
+            }
+        }
+
+        #[tokio::test]
+        async fn test_missing_certificate_fails() {
+            let (stream, _viewer) = duplex(4096);
+            let config = VncTlsConfig {
+                cert: fixture("missing.pem"),
+                key: fixture("server-key.pem"),
+            };
+            let err = vencrypt_handshake(stream, &config, None).await.unwrap_err();
+            assert!(err.to_string().contains("missing.pem"), "{}", err);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
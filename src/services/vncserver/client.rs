@@ -15,27 +15,32 @@
 //! The stream is split via `tokio::io::split()` into independent read/write halves,
 //! allowing concurrent message reading and update sending.
 
-use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use bytes::{Buf, BufMut, BytesMut};
 use flate2::Compress;
 use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
-use tokio::sync::{mpsc, RwLock};
+use tokio::sync::{mpsc, watch, RwLock};
 use tracing::{debug, error, info, warn};
 
-use super::auth::VncAuth;
+use crate::helper::with_write_timeout;
+
+use super::auth::{authenticate_vnc, write_security_result, VncAuth};
 use super::encoding::{to_rfb_pixel_format, TightZlibStreams};
-use super::framebuffer::{DirtyRegion, DirtyRegionReceiver, Framebuffer};
+use super::framebuffer::{coalesce_regions, DirtyRegion, DirtyRegionReceiver, Framebuffer};
+use super::keyboard::KeyboardState;
 use super::protocol::{
-    PixelFormat, Rectangle, ServerInit, CLIENT_MSG_CLIENT_CUT_TEXT,
-    CLIENT_MSG_FRAMEBUFFER_UPDATE_REQUEST, CLIENT_MSG_KEY_EVENT, CLIENT_MSG_POINTER_EVENT,
-    CLIENT_MSG_SET_ENCODINGS, CLIENT_MSG_SET_PIXEL_FORMAT, ENCODING_COMPRESS_LEVEL_0,
-    ENCODING_COMPRESS_LEVEL_9, ENCODING_COPYRECT, ENCODING_QUALITY_LEVEL_0,
+    decode_cut_text, write_connection_failed, write_cursor_update, write_desktop_size_update,
+    write_led_state_update, write_server_cut_text, PixelFormat, Rectangle, ServerInit,
+    CLIENT_MSG_CLIENT_CUT_TEXT, CLIENT_MSG_FRAMEBUFFER_UPDATE_REQUEST, CLIENT_MSG_KEY_EVENT,
+    CLIENT_MSG_POINTER_EVENT, CLIENT_MSG_SET_ENCODINGS, CLIENT_MSG_SET_PIXEL_FORMAT,
+    ENCODING_COMPRESS_LEVEL_0, ENCODING_COMPRESS_LEVEL_9, ENCODING_COPYRECT, ENCODING_CURSOR,
+    ENCODING_DESKTOP_SIZE, ENCODING_QEMU_LED_STATE, ENCODING_QUALITY_LEVEL_0,
     ENCODING_QUALITY_LEVEL_9, ENCODING_RAW, ENCODING_TIGHT, ENCODING_ZLIB, ENCODING_ZRLE,
-    PROTOCOL_VERSION, SECURITY_RESULT_FAILED, SECURITY_RESULT_OK, SECURITY_TYPE_NONE,
-    SECURITY_TYPE_VNC_AUTH, SERVER_MSG_FRAMEBUFFER_UPDATE, UPDATE_BUF_SIZE,
+    PROTOCOL_VERSION, SECURITY_TYPE_NONE, SECURITY_TYPE_VNC_AUTH, SERVER_MSG_FRAMEBUFFER_UPDATE,
+    UPDATE_BUF_SIZE,
 };
 
 /// Events generated by a VNC client and sent to the server.
@@ -66,6 +71,15 @@ pub enum ClientEvent {
     Disconnected,
 }
 
+/// Clipboard text shared between the host and VNC sessions.
+#[derive(Debug, Clone, Default)]
+pub struct ClipboardContents {
+    /// The clipboard text.
+    pub text: String,
+    /// Session that set the text (`None` = the host).
+    pub origin: Option<u64>,
+}
+
 /// Manages a single VNC client connection over a generic async stream.
 ///
 /// Handles communication, framebuffer updates, and client input events.
@@ -93,18 +107,42 @@ pub struct VncClient<S: AsyncReadExt + AsyncWriteExt + Unpin + Send> {
     quality_level: AtomicU8,
     /// Whether the client has requested an update.
     update_requested: AtomicBool,
+    /// Whether the client negotiated the Cursor pseudo-encoding.
+    supports_cursor: AtomicBool,
+    /// Whether the client negotiated the DesktopSize pseudo-encoding.
+    supports_desktop_size: AtomicBool,
+    /// Serial of the cursor shape last sent to the client (0 = none yet).
+    cursor_sent: AtomicU64,
+    /// Framebuffer size the client last learned of.
+    size_sent: RwLock<(u16, u16)>,
     /// Per-client dirty regions (pushed from framebuffer).
     modified_regions: Arc<RwLock<Vec<DirtyRegion>>>,
     /// Region requested by the client for update.
     requested_region: RwLock<Option<DirtyRegion>>,
     /// Update deferral duration.
     defer_update_time: Duration,
+    /// Cap on dirty rectangles per update (None = unlimited).
+    max_rects_per_update: Option<usize>,
+    /// Limit on a single write to the client (None = unlimited).
+    write_timeout: Option<Duration>,
     /// Persistent zlib compressor for Zlib encoding.
     zlib_compressor: RwLock<Option<Compress>>,
     /// Persistent zlib compressor for ZRLE encoding.
     zrle_compressor: RwLock<Option<Compress>>,
     /// Persistent zlib streams for Tight encoding (4 streams).
     tight_zlib_streams: RwLock<TightZlibStreams>,
+    /// Held modifiers and lock key state from the client's key events.
+    keyboard: RwLock<KeyboardState>,
+    /// Longest ClientCutText accepted; longer text is discarded.
+    max_cut_text: usize,
+    /// Bytes of an oversized ClientCutText still to discard.
+    cut_text_skip: usize,
+    /// Clipboard changes to push to the client (None = not shared).
+    clipboard: Option<watch::Receiver<ClipboardContents>>,
+    /// Id of this session, so its own clipboard text is not echoed back.
+    session_id: u64,
+    /// Drop the client's input events (keyboard, pointer, cut text).
+    view_only: bool,
 }
 
 /// VNC quality level to JPEG quality mapping (TigerVNC compatible).
@@ -113,6 +151,60 @@ const TIGHT2TURBO_QUAL: [u8; 10] = [15, 29, 41, 42, 62, 77, 79, 86, 92, 100];
 /// Maximum clipboard size to prevent memory exhaustion attacks.
 const MAX_CUT_TEXT: usize = 10 * 1024 * 1024; // 10MB
 
+/// Whether an I/O error means the viewer hung up rather than misbehaved.
+fn is_disconnect(error: &std::io::Error) -> bool {
+    matches!(
+        error.kind(),
+        std::io::ErrorKind::BrokenPipe
+            | std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::ConnectionAborted
+            | std::io::ErrorKind::NotConnected
+            | std::io::ErrorKind::WriteZero
+            | std::io::ErrorKind::UnexpectedEof
+    )
+}
+
+/// Exchanges protocol versions, the first step of the handshake.
+pub(super) async fn exchange_version<S: AsyncReadExt + AsyncWriteExt + Unpin>(
+    stream: &mut S,
+) -> Result<(), std::io::Error> {
+    stream.write_all(PROTOCOL_VERSION.as_bytes()).await?;
+
+    let mut version_buf = vec![0u8; 12];
+    stream.read_exact(&mut version_buf).await?;
+    debug!(
+        "VNC client version: {}",
+        String::from_utf8_lossy(&version_buf).trim()
+    );
+    Ok(())
+}
+
+/// Turns a viewer away after the version exchange, sending `reason` in
+/// place of the security types.
+pub(super) async fn reject_handshake<S: AsyncReadExt + AsyncWriteExt + Unpin>(
+    stream: &mut S,
+    reason: &str,
+) -> Result<(), std::io::Error> {
+    exchange_version(stream).await?;
+    let mut buf = BytesMut::new();
+    write_connection_failed(&mut buf, reason);
+    stream.write_all(&buf).await?;
+    stream.flush().await
+}
+
+/// Waits for the next clipboard change, forever when the clipboard is not
+/// shared or its sender is gone.
+async fn clipboard_changed(
+    clipboard: &mut Option<watch::Receiver<ClipboardContents>>,
+) -> ClipboardContents {
+    if let Some(rx) = clipboard {
+        if rx.changed().await.is_ok() {
+            return rx.borrow_and_update().clone();
+        }
+    }
+    std::future::pending().await
+}
+
 impl<S: AsyncReadExt + AsyncWriteExt + Unpin + Send> VncClient<S> {
     /// Performs the VNC handshake and creates a new [`VncClient`].
     ///
@@ -143,15 +235,7 @@ impl<S: AsyncReadExt + AsyncWriteExt + Unpin + Send> VncClient<S> {
         password: Option<String>,
         event_tx: mpsc::UnboundedSender<ClientEvent>,
     ) -> Result<Self, std::io::Error> {
-        // --- Protocol Version ---
-        stream.write_all(PROTOCOL_VERSION.as_bytes()).await?;
-
-        let mut version_buf = vec![0u8; 12];
-        stream.read_exact(&mut version_buf).await?;
-        debug!(
-            "VNC client version: {}",
-            String::from_utf8_lossy(&version_buf).trim()
-        );
+        exchange_version(&mut stream).await?;
 
         // --- Security Types ---
         if password.is_some() {
@@ -168,31 +252,9 @@ impl<S: AsyncReadExt + AsyncWriteExt + Unpin + Send> VncClient<S> {
 
         // --- Authentication ---
         if sec_type[0] == SECURITY_TYPE_VNC_AUTH {
-            let auth = VncAuth::new(password);
-            let challenge = auth.generate_challenge();
-            stream.write_all(&challenge).await?;
-
-            let mut response = vec![0u8; 16];
-            stream.read_exact(&mut response).await?;
-
-            if auth.verify_response(&response, &challenge) {
-                let mut buf = BytesMut::with_capacity(4);
-                buf.put_u32(SECURITY_RESULT_OK);
-                stream.write_all(&buf).await?;
-                info!("VNC authentication successful");
-            } else {
-                let mut buf = BytesMut::with_capacity(4);
-                buf.put_u32(SECURITY_RESULT_FAILED);
-                stream.write_all(&buf).await?;
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::PermissionDenied,
-                    "VNC authentication failed",
-                ));
-            }
+            authenticate_vnc(&mut stream, &VncAuth::new(password)).await?;
         } else if sec_type[0] == SECURITY_TYPE_NONE {
-            let mut buf = BytesMut::with_capacity(4);
-            buf.put_u32(SECURITY_RESULT_OK);
-            stream.write_all(&buf).await?;
+            write_security_result(&mut stream, true).await?;
         } else {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
@@ -200,6 +262,21 @@ impl<S: AsyncReadExt + AsyncWriteExt + Unpin + Send> VncClient<S> {
             ));
         }
 
+        Self::initialize(stream, framebuffer, desktop_name, event_tx).await
+    }
+
+    /// Completes the handshake on a stream whose security phase is done,
+    /// exchanging ClientInit and ServerInit.
+    ///
+    /// # Errors
+    ///
+    /// Returns `std::io::Error` on I/O errors.
+    pub async fn initialize(
+        mut stream: S,
+        framebuffer: Framebuffer,
+        desktop_name: String,
+        event_tx: mpsc::UnboundedSender<ClientEvent>,
+    ) -> Result<Self, std::io::Error> {
         // --- ClientInit ---
         let mut shared = [0u8; 1];
         stream.read_exact(&mut shared).await?;
@@ -227,6 +304,7 @@ impl<S: AsyncReadExt + AsyncWriteExt + Unpin + Send> VncClient<S> {
         let (read_stream, write_stream) = tokio::io::split(stream);
 
         let creation_time = Instant::now();
+        let size = (framebuffer.width(), framebuffer.height());
 
         Ok(Self {
             read_stream,
@@ -240,15 +318,80 @@ impl<S: AsyncReadExt + AsyncWriteExt + Unpin + Send> VncClient<S> {
             compression_level: AtomicU8::new(6),
             quality_level: AtomicU8::new(255), // unset
             update_requested: AtomicBool::new(false),
+            supports_cursor: AtomicBool::new(false),
+            supports_desktop_size: AtomicBool::new(false),
+            cursor_sent: AtomicU64::new(0),
+            size_sent: RwLock::new(size),
             modified_regions: Arc::new(RwLock::new(Vec::new())),
             requested_region: RwLock::new(None),
             defer_update_time: Duration::from_millis(5),
+            max_rects_per_update: None,
+            write_timeout: None,
             zlib_compressor: RwLock::new(None),
             zrle_compressor: RwLock::new(None),
             tight_zlib_streams: RwLock::new(TightZlibStreams::new()),
+            keyboard: RwLock::new(KeyboardState::new()),
+            max_cut_text: MAX_CUT_TEXT,
+            cut_text_skip: 0,
+            clipboard: None,
+            session_id: 0,
+            view_only: false,
         })
     }
 
+    /// Drops the client's keyboard, pointer and cut text messages while
+    /// still serving framebuffer updates.
+    #[must_use]
+    pub fn with_view_only(mut self, view_only: bool) -> Self {
+        self.view_only = view_only;
+        self
+    }
+
+    /// Discards ClientCutText longer than `max` bytes.
+    #[must_use]
+    pub fn with_max_cut_text(mut self, max: usize) -> Self {
+        self.max_cut_text = max;
+        self
+    }
+
+    /// Pushes clipboard changes from `clipboard` to the client as
+    /// ServerCutText, skipping those set by `session_id` itself.
+    ///
+    /// Non-empty current contents are sent once the message loop starts.
+    #[must_use]
+    pub fn with_clipboard(
+        mut self,
+        mut clipboard: watch::Receiver<ClipboardContents>,
+        session_id: u64,
+    ) -> Self {
+        if !clipboard.borrow().text.is_empty() {
+            clipboard.mark_changed();
+        }
+        self.clipboard = Some(clipboard);
+        self.session_id = session_id;
+        self
+    }
+
+    /// Caps the number of dirty rectangles per update, coalescing above it.
+    #[must_use]
+    pub fn with_max_rects_per_update(mut self, max: Option<usize>) -> Self {
+        self.max_rects_per_update = max;
+        self
+    }
+
+    /// Fails writes to the client that block longer than `timeout`.
+    #[must_use]
+    pub fn with_write_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.write_timeout = timeout;
+        self
+    }
+
+    /// Writes `data` to the client, subject to the write timeout.
+    async fn send(&self, data: &[u8]) -> std::io::Result<()> {
+        let mut ws = self.write_stream.lock().await;
+        with_write_timeout(self.write_timeout, ws.write_all(data)).await
+    }
+
     /// Returns a [`DirtyRegionReceiver`] for registering this client with the framebuffer.
     ///
     /// The framebuffer will push dirty regions to this receiver when pixels change.
@@ -263,6 +406,10 @@ impl<S: AsyncReadExt + AsyncWriteExt + Unpin + Send> VncClient<S> {
     /// - Read and process client messages (SetPixelFormat, SetEncodings, key/pointer events)
     /// - Send framebuffer updates at regular intervals when regions are dirty
     ///
+    /// A viewer hanging up, even in the middle of writing an update, ends the
+    /// loop with `Ok(())`; the rest of that update is abandoned. Encoder state
+    /// is per client, so other clients sharing the framebuffer are unaffected.
+    ///
     /// # Errors
     ///
     /// Returns `std::io::Error` on I/O errors or protocol violations.
@@ -276,7 +423,11 @@ impl<S: AsyncReadExt + AsyncWriteExt + Unpin + Send> VncClient<S> {
             tokio::select! {
                 // Handle incoming client messages
                 result = self.read_stream.read_buf(&mut buf) => {
-                    let n = result?;
+                    let n = match result {
+                        Ok(n) => n,
+                        Err(e) if is_disconnect(&e) => 0,
+                        Err(e) => return Err(e),
+                    };
                     if n == 0 {
                         let _ = self.event_tx.send(ClientEvent::Disconnected);
                         return Ok(());
@@ -290,13 +441,36 @@ impl<S: AsyncReadExt + AsyncWriteExt + Unpin + Send> VncClient<S> {
                     }
                 }
 
+                // Push host or other sessions' clipboard changes
+                contents = clipboard_changed(&mut self.clipboard) => {
+                    if contents.origin == Some(self.session_id) {
+                        continue;
+                    }
+                    let mut msg = BytesMut::new();
+                    write_server_cut_text(&mut msg, &contents.text);
+                    if let Err(e) = self.send(&msg).await {
+                        if is_disconnect(&e) {
+                            let _ = self.event_tx.send(ClientEvent::Disconnected);
+                            return Ok(());
+                        }
+                        return Err(e);
+                    }
+                }
+
                 // Periodically check for and send framebuffer updates
                 _ = check_interval.tick() => {
-                    if self.should_send_update().await {
-                        if let Err(e) = self.send_framebuffer_update().await {
-                            error!("Failed to send framebuffer update: {}", e);
-                            return Err(e);
+                    let mut result = self.send_pseudo_updates().await;
+                    if result.is_ok() && self.should_send_update().await {
+                        result = self.send_framebuffer_update().await;
+                    }
+                    if let Err(e) = result {
+                        if is_disconnect(&e) {
+                            debug!("VNC client went away during an update: {}", e);
+                            let _ = self.event_tx.send(ClientEvent::Disconnected);
+                            return Ok(());
                         }
+                        error!("Failed to send framebuffer update: {}", e);
+                        return Err(e);
                     }
                 }
             }
@@ -312,6 +486,14 @@ impl<S: AsyncReadExt + AsyncWriteExt + Unpin + Send> VncClient<S> {
             return Ok(false);
         }
 
+        // Drain an oversized cut text as it arrives rather than buffering it
+        if self.cut_text_skip > 0 {
+            let n = self.cut_text_skip.min(buf.len());
+            buf.advance(n);
+            self.cut_text_skip -= n;
+            return Ok(self.cut_text_skip == 0);
+        }
+
         let msg_type = buf[0];
 
         match msg_type {
@@ -374,6 +556,12 @@ impl<S: AsyncReadExt + AsyncWriteExt + Unpin + Send> VncClient<S> {
                     }
                 }
 
+                self.supports_cursor
+                    .store(encodings_list.contains(&ENCODING_CURSOR), Ordering::Relaxed);
+                self.supports_desktop_size.store(
+                    encodings_list.contains(&ENCODING_DESKTOP_SIZE),
+                    Ordering::Relaxed,
+                );
                 debug!("Client set {} encodings: {:?}", count, encodings_list);
                 *self.encodings.write().await = encodings_list;
             }
@@ -409,8 +597,28 @@ impl<S: AsyncReadExt + AsyncWriteExt + Unpin + Send> VncClient<S> {
                 let down = buf.get_u8() != 0;
                 buf.advance(2); // padding
                 let key = buf.get_u32();
+                if self.view_only {
+                    return Ok(true);
+                }
+
+                let mut keyboard = self.keyboard.write().await;
+                let leds_changed = keyboard.apply(down, key);
+                let led_state = keyboard.led_state();
+                drop(keyboard);
 
                 let _ = self.event_tx.send(ClientEvent::KeyPress { down, key });
+
+                if leds_changed
+                    && self
+                        .encodings
+                        .read()
+                        .await
+                        .contains(&ENCODING_QEMU_LED_STATE)
+                {
+                    let mut response = BytesMut::new();
+                    write_led_state_update(&mut response, led_state);
+                    self.send(&response).await?;
+                }
             }
 
             CLIENT_MSG_POINTER_EVENT => {
@@ -421,6 +629,9 @@ impl<S: AsyncReadExt + AsyncWriteExt + Unpin + Send> VncClient<S> {
                 let button_mask = buf.get_u8();
                 let x = buf.get_u16();
                 let y = buf.get_u16();
+                if self.view_only {
+                    return Ok(true);
+                }
 
                 let _ = self
                     .event_tx
@@ -432,21 +643,21 @@ impl<S: AsyncReadExt + AsyncWriteExt + Unpin + Send> VncClient<S> {
                     return Ok(false); // 1 type + 3 padding + 4 length
                 }
                 let length = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]) as usize;
+                if length > self.max_cut_text {
+                    warn!("Cut text too large ({} bytes), skipping", length);
+                    buf.advance(8); // type + padding + length
+                    self.cut_text_skip = length;
+                    return Ok(true);
+                }
                 let total_len = 8 + length;
                 if buf.len() < total_len {
                     return Ok(false);
                 }
 
                 buf.advance(8); // type + padding + length
-
-                if length > MAX_CUT_TEXT {
-                    warn!("Cut text too large ({} bytes), skipping", length);
-                    buf.advance(length);
-                } else {
-                    let text_bytes = buf.split_to(length);
-                    if let Ok(text) = String::from_utf8(text_bytes.to_vec()) {
-                        let _ = self.event_tx.send(ClientEvent::CutText { text });
-                    }
+                let text = decode_cut_text(&buf.split_to(length));
+                if !self.view_only {
+                    let _ = self.event_tx.send(ClientEvent::CutText { text });
                 }
             }
 
@@ -479,6 +690,49 @@ impl<S: AsyncReadExt + AsyncWriteExt + Unpin + Send> VncClient<S> {
         last_sent.elapsed() >= self.defer_update_time
     }
 
+    /// Send the DesktopSize and Cursor pseudo-rectangles the client has
+    /// negotiated, if the framebuffer size or cursor shape changed since it
+    /// last heard of them.
+    async fn send_pseudo_updates(&self) -> Result<(), std::io::Error> {
+        if !self.update_requested.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        if self.supports_desktop_size.load(Ordering::Relaxed) {
+            let size = (self.framebuffer.width(), self.framebuffer.height());
+            let mut size_sent = self.size_sent.write().await;
+            if *size_sent != size {
+                let mut msg = BytesMut::new();
+                write_desktop_size_update(&mut msg, size.0, size.1);
+                self.send(&msg).await?;
+                *size_sent = size;
+                debug!("Sent DesktopSize {}x{}", size.0, size.1);
+            }
+        }
+
+        if self.supports_cursor.load(Ordering::Relaxed)
+            && self.cursor_sent.load(Ordering::Relaxed) != self.framebuffer.cursor_serial()
+        {
+            let (serial, cursor) = self.framebuffer.cursor().await;
+            let client_pf = self.pixel_format.read().await;
+            let rfb_client_format = to_rfb_pixel_format(&client_pf);
+            drop(client_pf);
+            let pixels = self.translate_pixels_for_client(
+                &cursor.pixels,
+                &rfb_encodings::PixelFormat::rgba32(),
+                &rfb_client_format,
+                &DirtyRegion::new(0, 0, cursor.width, cursor.height),
+            );
+            let mut msg = BytesMut::new();
+            write_cursor_update(&mut msg, &cursor, &pixels);
+            self.send(&msg).await?;
+            self.cursor_sent.store(serial, Ordering::Relaxed);
+            debug!("Sent cursor shape {}x{}", cursor.width, cursor.height);
+        }
+
+        Ok(())
+    }
+
     /// Send a framebuffer update to the client.
     ///
     /// Takes all pending dirty regions, encodes them using the client's preferred
@@ -491,8 +745,11 @@ impl<S: AsyncReadExt + AsyncWriteExt + Unpin + Send> VncClient<S> {
         if regions.is_empty() {
             return Ok(());
         }
-        let modified_regions: Vec<DirtyRegion> = regions.drain(..).collect();
+        let mut modified_regions: Vec<DirtyRegion> = regions.drain(..).collect();
         drop(regions);
+        if let Some(max) = self.max_rects_per_update {
+            modified_regions = coalesce_regions(modified_regions, max);
+        }
 
         // Determine preferred encoding
         let encodings = self.encodings.read().await;
@@ -576,8 +833,7 @@ impl<S: AsyncReadExt + AsyncWriteExt + Unpin + Send> VncClient<S> {
 
                     if response.len() + rect_size > UPDATE_BUF_SIZE {
                         // Flush current buffer
-                        let mut ws = self.write_stream.lock().await;
-                        ws.write_all(&response).await?;
+                        self.send(&response).await?;
                         response.clear();
                     }
 
@@ -613,25 +869,12 @@ impl<S: AsyncReadExt + AsyncWriteExt + Unpin + Send> VncClient<S> {
                 };
 
                 let (actual_encoding, encoded) = if preferred_encoding == ENCODING_RAW {
-                    let translated = if rfb_client_format.is_compatible_with_rgba32() {
-                        // Fast path: strip alpha channel
-                        let mut buf = BytesMut::with_capacity(
-                            region.width as usize * region.height as usize * 4,
-                        );
-                        for chunk in pixel_data.chunks_exact(4) {
-                            buf.put_u8(chunk[0]); // R
-                            buf.put_u8(chunk[1]); // G
-                            buf.put_u8(chunk[2]); // B
-                            buf.put_u8(0); // Padding
-                        }
-                        buf
-                    } else {
-                        super::encoding::translate_pixels(
-                            &pixel_data,
-                            &rfb_server_format,
-                            &rfb_client_format,
-                        )
-                    };
+                    let translated = self.translate_pixels_for_client(
+                        &pixel_data,
+                        &rfb_server_format,
+                        &rfb_client_format,
+                        region,
+                    );
                     (ENCODING_RAW, translated)
                 } else if preferred_encoding == ENCODING_ZLIB {
                     let translated = self.translate_pixels_for_client(
@@ -733,9 +976,7 @@ impl<S: AsyncReadExt + AsyncWriteExt + Unpin + Send> VncClient<S> {
         }
 
         // Send the response
-        let mut ws = self.write_stream.lock().await;
-        ws.write_all(&response).await?;
-        drop(ws);
+        self.send(&response).await?;
 
         // Update tracking
         *self.last_update_sent.write().await = Instant::now();
@@ -744,6 +985,9 @@ impl<S: AsyncReadExt + AsyncWriteExt + Unpin + Send> VncClient<S> {
     }
 
     /// Translate pixel data from server RGBA32 to client format.
+    ///
+    /// `client_format` is whatever the client last sent in SetPixelFormat;
+    /// clients that kept the server's RGBA32 format skip translation.
     fn translate_pixels_for_client(
         &self,
         pixel_data: &[u8],
@@ -770,6 +1014,9 @@ impl<S: AsyncReadExt + AsyncWriteExt + Unpin + Send> VncClient<S> {
 
 #[cfg(test)]
 mod tests {
+    use super::super::framebuffer::CursorShape;
+    use super::super::keyboard::XK_CAPS_LOCK;
+    use super::super::protocol::{LED_CAPS_LOCK, SERVER_MSG_SERVER_CUT_TEXT};
     use super::*;
     use tokio::io::{duplex, DuplexStream};
 
@@ -924,6 +1171,456 @@ mod tests {
         assert!(server_result.unwrap().is_ok());
     }
 
+    /// Complete the handshake for a viewer whose stream buffers `buf_size`
+    /// bytes, returning the server-side client and the viewer's stream
+    async fn connect_viewer(
+        framebuffer: &Framebuffer,
+        buf_size: usize,
+    ) -> (VncClient<DuplexStream>, DuplexStream) {
+        let (client, viewer, _events) = connect_viewer_with_events(framebuffer, buf_size).await;
+        (client, viewer)
+    }
+
+    /// As [`connect_viewer`], also returning the client's event receiver
+    async fn connect_viewer_with_events(
+        framebuffer: &Framebuffer,
+        buf_size: usize,
+    ) -> (
+        VncClient<DuplexStream>,
+        DuplexStream,
+        mpsc::UnboundedReceiver<ClientEvent>,
+    ) {
+        let (server_stream, mut viewer) = duplex(buf_size);
+        let (event_tx, event_rx) = mpsc::unbounded_channel();
+        let (client, handshake) = tokio::join!(
+            VncClient::new(
+                server_stream,
+                framebuffer.clone(),
+                "Test".to_string(),
+                None,
+                event_tx,
+            ),
+            perform_client_handshake(&mut viewer, None)
+        );
+        handshake.unwrap();
+        let client = client.unwrap();
+        framebuffer
+            .register_receiver(client.dirty_region_receiver())
+            .await;
+        (client, viewer, event_rx)
+    }
+
+    /// Request a full (non-incremental) update of a 64x64 framebuffer
+    async fn request_full_update(viewer: &mut DuplexStream) {
+        viewer
+            .write_all(&[
+                CLIENT_MSG_FRAMEBUFFER_UPDATE_REQUEST,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                64,
+                0,
+                64,
+            ])
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_mid_update_leaves_other_client_running() {
+        let framebuffer = Framebuffer::new(64, 64);
+
+        // A raw 64x64 update is 16 KiB, far more than this viewer buffers
+        let (mut leaving, mut leaving_viewer) = connect_viewer(&framebuffer, 64).await;
+        let (mut staying, mut staying_viewer) = connect_viewer(&framebuffer, 64 * 1024).await;
+
+        request_full_update(&mut leaving_viewer).await;
+        request_full_update(&mut staying_viewer).await;
+        let leaving_handle = tokio::spawn(async move { leaving.handle_messages().await });
+        let staying_handle = tokio::spawn(async move { staying.handle_messages().await });
+
+        // Hang up once the update has started arriving
+        let mut first = [0u8; 1];
+        leaving_viewer.read_exact(&mut first).await.unwrap();
+        assert_eq!(first[0], SERVER_MSG_FRAMEBUFFER_UPDATE);
+        drop(leaving_viewer);
+
+        let result = tokio::time::timeout(Duration::from_secs(2), leaving_handle)
+            .await
+            .expect("loop should end when its viewer hangs up")
+            .unwrap();
+        assert!(result.is_ok(), "disconnect is not an error: {:?}", result);
+
+        // The other client still receives its update and keeps running
+        let mut header = [0u8; 4];
+        staying_viewer.read_exact(&mut header).await.unwrap();
+        assert_eq!(header[0], SERVER_MSG_FRAMEBUFFER_UPDATE);
+        assert!(!staying_handle.is_finished());
+
+        drop(staying_viewer);
+        let result = tokio::time::timeout(Duration::from_secs(2), staying_handle)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_fragmented_update_is_capped() {
+        let framebuffer = Framebuffer::new(64, 64);
+        let (client, mut viewer) = connect_viewer(&framebuffer, 64 * 1024).await;
+        let mut client = client.with_max_rects_per_update(Some(3));
+
+        // Incremental request, then eight scattered dirty tiles (below the
+        // framebuffer's own collapse-to-one threshold)
+        viewer
+            .write_all(&[
+                CLIENT_MSG_FRAMEBUFFER_UPDATE_REQUEST,
+                1,
+                0,
+                0,
+                0,
+                0,
+                0,
+                64,
+                0,
+                64,
+            ])
+            .await
+            .unwrap();
+        for i in 0..8 {
+            framebuffer
+                .mark_dirty_region((i % 4) * 16, (i / 4) * 16, 2, 2)
+                .await;
+        }
+        let handle = tokio::spawn(async move { client.handle_messages().await });
+
+        let mut header = [0u8; 4];
+        tokio::time::timeout(Duration::from_secs(2), viewer.read_exact(&mut header))
+            .await
+            .expect("viewer should receive an update")
+            .unwrap();
+        assert_eq!(header[0], SERVER_MSG_FRAMEBUFFER_UPDATE);
+        assert_eq!(u16::from_be_bytes([header[2], header[3]]), 3);
+
+        drop(viewer);
+        handle.await.unwrap().unwrap();
+    }
+
+    /// A SetEncodings message listing `encodings`
+    fn set_encodings_message(encodings: &[i32]) -> BytesMut {
+        let mut msg = BytesMut::new();
+        msg.put_u8(CLIENT_MSG_SET_ENCODINGS);
+        msg.put_u8(0);
+        msg.put_u16(encodings.len() as u16);
+        for &encoding in encodings {
+            msg.put_i32(encoding);
+        }
+        msg
+    }
+
+    #[tokio::test]
+    async fn test_pseudo_encoding_flags_follow_set_encodings() {
+        let framebuffer = Framebuffer::new(2, 2);
+        let (mut client, _viewer) = connect_viewer(&framebuffer, 4096).await;
+        assert!(!client.supports_cursor.load(Ordering::Relaxed));
+        assert!(!client.supports_desktop_size.load(Ordering::Relaxed));
+
+        let mut msg =
+            set_encodings_message(&[ENCODING_RAW, ENCODING_CURSOR, ENCODING_DESKTOP_SIZE]);
+        assert!(client.process_message(&mut msg).await.unwrap());
+        assert!(client.supports_cursor.load(Ordering::Relaxed));
+        assert!(client.supports_desktop_size.load(Ordering::Relaxed));
+
+        // A later SetEncodings replaces the list, dropping both
+        let mut msg = set_encodings_message(&[ENCODING_RAW]);
+        assert!(client.process_message(&mut msg).await.unwrap());
+        assert!(!client.supports_cursor.load(Ordering::Relaxed));
+        assert!(!client.supports_desktop_size.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn test_resize_sends_desktop_size() {
+        let framebuffer = Framebuffer::new(64, 64);
+        let (mut client, mut viewer) = connect_viewer(&framebuffer, 64 * 1024).await;
+
+        viewer
+            .write_all(&set_encodings_message(&[
+                ENCODING_RAW,
+                ENCODING_DESKTOP_SIZE,
+            ]))
+            .await
+            .unwrap();
+        request_full_update(&mut viewer).await;
+        framebuffer.resize(32, 16).await.unwrap();
+        let handle = tokio::spawn(async move { client.handle_messages().await });
+
+        let mut update = [0u8; 16];
+        tokio::time::timeout(Duration::from_secs(2), viewer.read_exact(&mut update))
+            .await
+            .expect("viewer should receive the new size")
+            .unwrap();
+        assert_eq!(update[0], SERVER_MSG_FRAMEBUFFER_UPDATE);
+        assert_eq!(u16::from_be_bytes([update[8], update[9]]), 32);
+        assert_eq!(u16::from_be_bytes([update[10], update[11]]), 16);
+        assert_eq!(
+            i32::from_be_bytes([update[12], update[13], update[14], update[15]]),
+            ENCODING_DESKTOP_SIZE
+        );
+
+        drop(viewer);
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cursor_shape_sent_when_negotiated() {
+        let framebuffer = Framebuffer::new(2, 2);
+        let (mut client, mut viewer) = connect_viewer(&framebuffer, 4096).await;
+
+        viewer
+            .write_all(&set_encodings_message(&[ENCODING_RAW, ENCODING_CURSOR]))
+            .await
+            .unwrap();
+        request_full_update(&mut viewer).await;
+        let handle = tokio::spawn(async move { client.handle_messages().await });
+
+        let cursor = CursorShape::arrow();
+        let mut update = vec![
+            0u8;
+            16 + cursor.pixels.len()
+                + (cursor.width as usize).div_ceil(8) * cursor.height as usize
+        ];
+        tokio::time::timeout(Duration::from_secs(2), viewer.read_exact(&mut update))
+            .await
+            .expect("viewer should receive the cursor shape")
+            .unwrap();
+        assert_eq!(u16::from_be_bytes([update[8], update[9]]), cursor.width);
+        assert_eq!(u16::from_be_bytes([update[10], update[11]]), cursor.height);
+        assert_eq!(
+            i32::from_be_bytes([update[12], update[13], update[14], update[15]]),
+            ENCODING_CURSOR
+        );
+        assert_eq!(
+            update[update.len() - cursor.mask().len()..],
+            cursor.mask()[..]
+        );
+
+        drop(viewer);
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_led_state_reported_when_negotiated() {
+        let framebuffer = Framebuffer::new(2, 2);
+        let (mut client, mut viewer) = connect_viewer(&framebuffer, 4096).await;
+
+        let mut msg = BytesMut::new();
+        msg.put_u8(CLIENT_MSG_SET_ENCODINGS);
+        msg.put_u8(0);
+        msg.put_u16(2);
+        msg.put_i32(ENCODING_RAW);
+        msg.put_i32(ENCODING_QEMU_LED_STATE);
+        // Caps Lock down and up
+        for down in [1, 0] {
+            msg.put_u8(CLIENT_MSG_KEY_EVENT);
+            msg.put_u8(down);
+            msg.put_u16(0);
+            msg.put_u32(XK_CAPS_LOCK);
+        }
+        viewer.write_all(&msg).await.unwrap();
+        let handle = tokio::spawn(async move { client.handle_messages().await });
+
+        let mut update = [0u8; 17];
+        tokio::time::timeout(Duration::from_secs(2), viewer.read_exact(&mut update))
+            .await
+            .expect("viewer should receive the LED state")
+            .unwrap();
+        assert_eq!(
+            i32::from_be_bytes([update[12], update[13], update[14], update[15]]),
+            ENCODING_QEMU_LED_STATE
+        );
+        assert_eq!(update[16], LED_CAPS_LOCK);
+
+        drop(viewer);
+        handle.await.unwrap().unwrap();
+    }
+
+    /// Fill a 2x2 framebuffer with pure red, then have a viewer (after the
+    /// optional SetPixelFormat) request it, returning the raw pixel payload
+    async fn fetch_red_pixels(
+        pixel_format: Option<PixelFormat>,
+        bytes_per_pixel: usize,
+    ) -> Vec<u8> {
+        let framebuffer = Framebuffer::new(2, 2);
+        framebuffer
+            .update_cropped(&[255, 0, 0, 255].repeat(4), 0, 0, 2, 2)
+            .await
+            .unwrap();
+        let (mut client, mut viewer) = connect_viewer(&framebuffer, 4096).await;
+
+        if let Some(pf) = pixel_format {
+            let mut msg = BytesMut::new();
+            msg.put_u8(CLIENT_MSG_SET_PIXEL_FORMAT);
+            msg.put_bytes(0, 3);
+            pf.write_to(&mut msg);
+            viewer.write_all(&msg).await.unwrap();
+        }
+        viewer
+            .write_all(&[
+                CLIENT_MSG_FRAMEBUFFER_UPDATE_REQUEST,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                2,
+                0,
+                2,
+            ])
+            .await
+            .unwrap();
+        let handle = tokio::spawn(async move { client.handle_messages().await });
+
+        // Update header, one rectangle header, then the pixels
+        let mut update = vec![0u8; 4 + 12 + 4 * bytes_per_pixel];
+        tokio::time::timeout(Duration::from_secs(2), viewer.read_exact(&mut update))
+            .await
+            .expect("viewer should receive an update")
+            .unwrap();
+        assert_eq!(update[0], SERVER_MSG_FRAMEBUFFER_UPDATE);
+        assert_eq!(u16::from_be_bytes([update[2], update[3]]), 1);
+        assert_eq!(
+            i32::from_be_bytes([update[12], update[13], update[14], update[15]]),
+            ENCODING_RAW
+        );
+
+        drop(viewer);
+        handle.await.unwrap().unwrap();
+        update.split_off(16)
+    }
+
+    #[tokio::test]
+    async fn test_rgba32_client_gets_untranslated_pixels() {
+        let pixels = fetch_red_pixels(None, 4).await;
+        assert_eq!(pixels, [255, 0, 0, 0].repeat(4));
+    }
+
+    #[tokio::test]
+    async fn test_16bpp_client_gets_translated_pixels() {
+        let rgb565 = PixelFormat {
+            bits_per_pixel: 16,
+            depth: 16,
+            big_endian_flag: 0,
+            true_colour_flag: 1,
+            red_max: 31,
+            green_max: 63,
+            blue_max: 31,
+            red_shift: 11,
+            green_shift: 5,
+            blue_shift: 0,
+        };
+        let pixels = fetch_red_pixels(Some(rgb565), 2).await;
+        for pixel in pixels.chunks_exact(2) {
+            assert_eq!(u16::from_le_bytes([pixel[0], pixel[1]]), 0xF800);
+        }
+    }
+
+    /// A ClientCutText message carrying `text`
+    fn cut_text_message(text: &[u8]) -> BytesMut {
+        let mut msg = BytesMut::new();
+        msg.put_u8(CLIENT_MSG_CLIENT_CUT_TEXT);
+        msg.put_bytes(0, 3);
+        msg.put_u32(text.len() as u32);
+        msg.put_slice(text);
+        msg
+    }
+
+    #[tokio::test]
+    async fn test_oversized_cut_text_is_discarded() {
+        let framebuffer = Framebuffer::new(2, 2);
+        let (client, mut viewer, mut events) = connect_viewer_with_events(&framebuffer, 4096).await;
+        let mut client = client.with_max_cut_text(4);
+
+        // Sent in pieces, so the oversized text is drained across reads
+        let oversized = cut_text_message(b"0123456789");
+        viewer.write_all(&oversized[..10]).await.unwrap();
+        let handle = tokio::spawn(async move { client.handle_messages().await });
+        viewer.write_all(&oversized[10..]).await.unwrap();
+        viewer.write_all(&cut_text_message(b"h\xe9")).await.unwrap();
+
+        let event = tokio::time::timeout(Duration::from_secs(2), events.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(event, ClientEvent::CutText { ref text } if text == "hé"));
+
+        drop(viewer);
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_view_only_drops_input() {
+        let framebuffer = Framebuffer::new(2, 2);
+        let (client, mut viewer, mut events) = connect_viewer_with_events(&framebuffer, 4096).await;
+        let mut client = client.with_view_only(true);
+
+        let mut msg = BytesMut::new();
+        msg.put_u8(CLIENT_MSG_KEY_EVENT);
+        msg.put_u8(1);
+        msg.put_u16(0);
+        msg.put_u32(0x61);
+        msg.put_u8(CLIENT_MSG_POINTER_EVENT);
+        msg.put_u8(1);
+        msg.put_u16(1);
+        msg.put_u16(1);
+        msg.extend_from_slice(&cut_text_message(b"paste"));
+        viewer.write_all(&msg).await.unwrap();
+        drop(viewer);
+        client.handle_messages().await.unwrap();
+
+        // Only the disconnect gets through
+        assert!(matches!(
+            events.recv().await,
+            Some(ClientEvent::Disconnected)
+        ));
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_clipboard_pushed_to_viewer() {
+        let framebuffer = Framebuffer::new(2, 2);
+        let (client, mut viewer) = connect_viewer(&framebuffer, 4096).await;
+        let (clipboard, rx) = watch::channel(ClipboardContents::default());
+        let mut client = client.with_clipboard(rx, 7);
+        let handle = tokio::spawn(async move { client.handle_messages().await });
+
+        // The session's own text is not echoed back; the host's is pushed
+        clipboard.send_replace(ClipboardContents {
+            text: "mine".to_string(),
+            origin: Some(7),
+        });
+        clipboard.send_replace(ClipboardContents {
+            text: "host".to_string(),
+            origin: None,
+        });
+
+        let mut msg = [0u8; 12];
+        tokio::time::timeout(Duration::from_secs(2), viewer.read_exact(&mut msg))
+            .await
+            .expect("viewer should receive the clipboard")
+            .unwrap();
+        assert_eq!(msg[0], SERVER_MSG_SERVER_CUT_TEXT);
+        assert_eq!(u32::from_be_bytes([msg[4], msg[5], msg[6], msg[7]]), 4);
+        assert_eq!(&msg[8..], b"host");
+
+        drop(viewer);
+        handle.await.unwrap().unwrap();
+    }
+
     #[test]
     fn test_quality_mapping() {
         assert_eq!(TIGHT2TURBO_QUAL[0], 15);
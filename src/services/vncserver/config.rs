@@ -3,6 +3,7 @@
 //! This module defines configuration structures for the embedded VNC server.
 
 use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
 
 /// Default framebuffer width
 fn default_width() -> u16 {
@@ -29,13 +30,62 @@ fn default_compression_level() -> u8 {
     6
 }
 
+/// Default for flags that are on unless disabled
+fn default_true() -> bool {
+    true
+}
+
 /// Default maximum frames per second
 fn default_max_fps() -> u8 {
     30
 }
 
+/// Default cap on clipboard text exchanged with viewers, in bytes
+fn default_clipboard_max_len() -> usize {
+    1024 * 1024
+}
+
+/// Largest configurable clipboard cap, in bytes
+const MAX_CLIPBOARD_LEN: usize = 10 * 1024 * 1024;
+
+/// Direction clipboard contents may travel between viewers and the host
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClipboardDirection {
+    /// Share the clipboard both ways
+    #[default]
+    Both,
+    /// Only push the host clipboard to viewers
+    ToClient,
+    /// Only accept clipboard contents from viewers
+    ToServer,
+}
+
+impl ClipboardDirection {
+    /// Whether host clipboard changes are pushed to viewers
+    pub fn sends_to_client(self) -> bool {
+        self != ClipboardDirection::ToServer
+    }
+
+    /// Whether viewers' clipboard contents are accepted
+    pub fn accepts_from_client(self) -> bool {
+        self != ClipboardDirection::ToClient
+    }
+}
+
+/// Certificate for VeNCrypt, which runs VNC sessions over TLS
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VncTlsConfig {
+    /// PEM certificate chain presented to viewers
+    pub cert: PathBuf,
+    /// PEM private key of the certificate
+    pub key: PathBuf,
+}
+
 /// VNC server configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct VncConfig {
     /// Enable VNC server
     #[serde(default)]
@@ -68,6 +118,51 @@ pub struct VncConfig {
     /// Maximum frames per second
     #[serde(default = "default_max_fps")]
     pub max_fps: u8,
+
+    /// Maximum dirty rectangles sent per framebuffer update (None = unlimited).
+    ///
+    /// Above the cap, neighbouring dirty regions are merged into bounding
+    /// rectangles before encoding.
+    #[serde(default)]
+    pub max_rects_per_update: Option<usize>,
+
+    /// Seconds a write to a viewer may block before it is disconnected
+    /// (None = unlimited), so a viewer that stops reading cannot stall
+    /// the connection
+    #[serde(default)]
+    pub write_timeout: Option<u64>,
+
+    /// Share clipboard text with viewers
+    #[serde(default = "default_true")]
+    pub clipboard_enabled: bool,
+
+    /// Direction clipboard text may travel
+    #[serde(default)]
+    pub clipboard_direction: ClipboardDirection,
+
+    /// Longest clipboard text exchanged with viewers, in bytes; longer
+    /// text is dropped
+    #[serde(default = "default_clipboard_max_len")]
+    pub clipboard_max_len: usize,
+
+    /// Ignore viewers' keyboard, pointer and clipboard input, only
+    /// serving the screen
+    #[serde(default)]
+    pub view_only: bool,
+
+    /// Maximum number of simultaneous viewers (unlimited when unset)
+    #[serde(default)]
+    pub max_clients: Option<usize>,
+
+    /// Seconds a viewer beyond `max_clients` waits for a free slot before
+    /// being turned away (rejected at once when unset)
+    #[serde(default)]
+    pub queue_timeout_secs: Option<u64>,
+
+    /// Offer only VeNCrypt, upgrading sessions to TLS with this certificate
+    /// before authenticating (requires the `vnc-tls` feature)
+    #[serde(default)]
+    pub tls: Option<VncTlsConfig>,
 }
 
 impl Default for VncConfig {
@@ -81,6 +176,15 @@ impl Default for VncConfig {
             jpeg_quality: default_jpeg_quality(),
             compression_level: default_compression_level(),
             max_fps: default_max_fps(),
+            max_rects_per_update: None,
+            write_timeout: None,
+            clipboard_enabled: true,
+            clipboard_direction: ClipboardDirection::default(),
+            clipboard_max_len: default_clipboard_max_len(),
+            view_only: false,
+            max_clients: None,
+            queue_timeout_secs: None,
+            tls: None,
         }
     }
 }
@@ -127,6 +231,33 @@ impl VncConfig {
             return Err("max_fps must be greater than zero".to_string());
         }
 
+        if self.max_rects_per_update == Some(0) {
+            return Err("max_rects_per_update must be greater than zero".to_string());
+        }
+
+        if self.write_timeout == Some(0) {
+            return Err("write_timeout must be greater than zero".to_string());
+        }
+
+        if self.clipboard_max_len == 0 || self.clipboard_max_len > MAX_CLIPBOARD_LEN {
+            return Err(format!(
+                "clipboard_max_len must be 1-{}, got: {}",
+                MAX_CLIPBOARD_LEN, self.clipboard_max_len
+            ));
+        }
+
+        if self.max_clients == Some(0) {
+            return Err("max_clients must be greater than zero".to_string());
+        }
+
+        if self.queue_timeout_secs == Some(0) {
+            return Err("queue_timeout_secs must be greater than zero".to_string());
+        }
+
+        if self.tls.is_some() && !cfg!(feature = "vnc-tls") {
+            return Err("vnc.tls requires sockrats built with the vnc-tls feature".to_string());
+        }
+
         Ok(())
     }
 }
@@ -213,6 +344,22 @@ mod tests {
         assert!(config.validate().is_ok());
     }
 
+    #[test]
+    fn test_validate_max_rects_per_update() {
+        let mut config = VncConfig {
+            enabled: true,
+            max_rects_per_update: Some(0),
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+
+        config.max_rects_per_update = Some(16);
+        assert!(config.validate().is_ok());
+
+        config.write_timeout = Some(0);
+        assert!(config.validate().is_err());
+    }
+
     #[test]
     fn test_validate_compression_level_too_high() {
         let config = VncConfig {
@@ -244,6 +391,7 @@ mod tests {
             jpeg_quality: 90,
             compression_level: 3,
             max_fps: 60,
+            ..Default::default()
         };
 
         let toml_str = toml::to_string(&config).unwrap();
@@ -268,5 +416,64 @@ mod tests {
         assert!(config.enabled);
         assert_eq!(config.width, 1024);
         assert_eq!(config.height, 768);
+        assert!(config.clipboard_enabled);
+        assert_eq!(config.clipboard_direction, ClipboardDirection::Both);
+    }
+
+    #[test]
+    fn test_validate_client_limits() {
+        let mut config = VncConfig {
+            enabled: true,
+            max_clients: Some(2),
+            queue_timeout_secs: Some(10),
+            ..Default::default()
+        };
+        assert!(config.validate().is_ok());
+
+        config.max_clients = Some(0);
+        assert!(config.validate().is_err());
+
+        config.max_clients = Some(2);
+        config.queue_timeout_secs = Some(0);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_tls_settings() {
+        let toml_str = r#"
+            enabled = true
+            tls = { cert = "/etc/sockrats/vnc.pem", key = "/etc/sockrats/vnc-key.pem" }
+        "#;
+        let config: VncConfig = toml::from_str(toml_str).unwrap();
+        let tls = config.tls.as_ref().unwrap();
+        assert_eq!(tls.cert, PathBuf::from("/etc/sockrats/vnc.pem"));
+        assert_eq!(tls.key, PathBuf::from("/etc/sockrats/vnc-key.pem"));
+        assert_eq!(config.validate().is_ok(), cfg!(feature = "vnc-tls"));
+    }
+
+    #[test]
+    fn test_clipboard_settings() {
+        let config: VncConfig = toml::from_str(
+            r#"
+            enabled = true
+            clipboard_direction = "to-client"
+            clipboard_max_len = 4096
+        "#,
+        )
+        .unwrap();
+        assert!(config.clipboard_direction.sends_to_client());
+        assert!(!config.clipboard_direction.accepts_from_client());
+        assert_eq!(config.clipboard_max_len, 4096);
+        assert!(config.validate().is_ok());
+
+        assert!(!ClipboardDirection::ToServer.sends_to_client());
+        assert!(ClipboardDirection::ToServer.accepts_from_client());
+
+        let config = VncConfig {
+            enabled: true,
+            clipboard_max_len: 0,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
     }
 }
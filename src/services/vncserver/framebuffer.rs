@@ -4,8 +4,9 @@
 //! - Pixel data storage and access
 //! - Dirty region tracking for efficient updates
 //! - Client notification system for framebuffer changes
+//! - The cursor shape sent to clients that draw the cursor themselves
 
-use std::sync::atomic::{AtomicU16, Ordering as AtomicOrdering};
+use std::sync::atomic::{AtomicU16, AtomicU64, Ordering as AtomicOrdering};
 use std::sync::Arc;
 use std::sync::Weak;
 use tokio::sync::RwLock;
@@ -100,6 +101,42 @@ impl DirtyRegion {
             None
         }
     }
+
+    /// The number of pixels covered by this region.
+    fn area(&self) -> u64 {
+        u64::from(self.width) * u64::from(self.height)
+    }
+}
+
+/// Coalesces `regions` into at most `max` bounding rectangles.
+///
+/// Regions are ordered top-to-bottom, left-to-right, and the neighbouring
+/// pair whose merge adds the fewest extra pixels is merged until the cap is
+/// met. The result still covers every input region.
+#[must_use]
+pub fn coalesce_regions(mut regions: Vec<DirtyRegion>, max: usize) -> Vec<DirtyRegion> {
+    let max = max.max(1);
+    if regions.len() <= max {
+        return regions;
+    }
+
+    regions.sort_by_key(|r| (r.y, r.x));
+    while regions.len() > max {
+        let (index, _) = regions
+            .windows(2)
+            .map(|pair| {
+                let merged = pair[0].merge(&pair[1]);
+                merged
+                    .area()
+                    .saturating_sub(pair[0].area() + pair[1].area())
+            })
+            .enumerate()
+            .min_by_key(|&(_, waste)| waste)
+            .expect("more than one region");
+        let next = regions.remove(index + 1);
+        regions[index] = regions[index].merge(&next);
+    }
+    regions
 }
 
 /// A struct for receiving notifications about dirty (modified) regions in the framebuffer.
@@ -159,6 +196,79 @@ impl DirtyRegionReceiver {
     }
 }
 
+/// A cursor image and its hotspot, for the Cursor pseudo-encoding.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CursorShape {
+    /// Width of the cursor image in pixels.
+    pub width: u16,
+    /// Height of the cursor image in pixels.
+    pub height: u16,
+    /// X coordinate of the pointer's hotspot within the image.
+    pub hotspot_x: u16,
+    /// Y coordinate of the pointer's hotspot within the image.
+    pub hotspot_y: u16,
+    /// The pixel data (RGBA32); pixels with alpha below 128 are transparent.
+    pub pixels: Vec<u8>,
+}
+
+impl CursorShape {
+    /// The default cursor: a white arrow with a black outline.
+    #[must_use]
+    pub fn arrow() -> Self {
+        const ARROW: [&str; 17] = [
+            "X          ",
+            "XX         ",
+            "X.X        ",
+            "X..X       ",
+            "X...X      ",
+            "X....X     ",
+            "X.....X    ",
+            "X......X   ",
+            "X.......X  ",
+            "X........X ",
+            "X.....XXXXX",
+            "X..X..X    ",
+            "X.X X..X   ",
+            "XX  X..X   ",
+            "X    X..X  ",
+            "     X..X  ",
+            "      XX   ",
+        ];
+        let mut pixels = Vec::with_capacity(ARROW.len() * ARROW[0].len() * 4);
+        for row in ARROW {
+            for c in row.bytes() {
+                pixels.extend_from_slice(match c {
+                    b'X' => &[0, 0, 0, 255],
+                    b'.' => &[255, 255, 255, 255],
+                    _ => &[0, 0, 0, 0],
+                });
+            }
+        }
+        Self {
+            width: ARROW[0].len() as u16,
+            height: ARROW.len() as u16,
+            hotspot_x: 0,
+            hotspot_y: 0,
+            pixels,
+        }
+    }
+
+    /// Returns the transparency bitmask: one bit per pixel, most
+    /// significant bit first, each row padded to a whole byte.
+    #[must_use]
+    pub fn mask(&self) -> Vec<u8> {
+        let row_bytes = (self.width as usize).div_ceil(8);
+        let mut mask = vec![0u8; row_bytes * self.height as usize];
+        for (i, pixel) in self.pixels.chunks_exact(4).enumerate() {
+            if pixel[3] >= 128 {
+                let (y, x) = (i / self.width as usize, i % self.width as usize);
+                mask[y * row_bytes + x / 8] |= 0x80 >> (x % 8);
+            }
+        }
+        mask
+    }
+}
+
 /// Represents the VNC server's framebuffer.
 ///
 /// Manages the pixel data of the remote screen, tracks dirty regions,
@@ -173,6 +283,10 @@ pub struct Framebuffer {
     data: Arc<RwLock<Vec<u8>>>,
     /// List of receivers to notify on changes.
     receivers: Arc<RwLock<Vec<DirtyRegionReceiver>>>,
+    /// The cursor shape sent to clients that draw the cursor.
+    cursor: Arc<RwLock<Arc<CursorShape>>>,
+    /// Changes on every cursor shape change, so clients can tell theirs is stale.
+    cursor_serial: Arc<AtomicU64>,
 }
 
 impl Framebuffer {
@@ -185,9 +299,34 @@ impl Framebuffer {
             height: Arc::new(AtomicU16::new(height)),
             data: Arc::new(RwLock::new(vec![0; size])),
             receivers: Arc::new(RwLock::new(Vec::new())),
+            cursor: Arc::new(RwLock::new(Arc::new(CursorShape::arrow()))),
+            cursor_serial: Arc::new(AtomicU64::new(1)),
         }
     }
 
+    /// Replaces the cursor shape sent to clients that draw the cursor.
+    pub async fn set_cursor(&self, cursor: CursorShape) {
+        let mut current = self.cursor.write().await;
+        *current = Arc::new(cursor);
+        self.cursor_serial.fetch_add(1, AtomicOrdering::Release);
+    }
+
+    /// Returns the cursor shape and its serial.
+    pub async fn cursor(&self) -> (u64, Arc<CursorShape>) {
+        let cursor = self.cursor.read().await;
+        (
+            self.cursor_serial.load(AtomicOrdering::Acquire),
+            cursor.clone(),
+        )
+    }
+
+    /// Returns the serial of the current cursor shape, which changes
+    /// whenever the shape does.
+    #[must_use]
+    pub fn cursor_serial(&self) -> u64 {
+        self.cursor_serial.load(AtomicOrdering::Acquire)
+    }
+
     /// Registers a `DirtyRegionReceiver` to be notified of framebuffer updates.
     pub async fn register_receiver(&self, receiver: DirtyRegionReceiver) {
         let mut receivers = self.receivers.write().await;
@@ -481,6 +620,44 @@ mod tests {
         assert!(r1.intersect(&r2).is_none());
     }
 
+    #[test]
+    fn test_coalesce_regions_under_cap_is_unchanged() {
+        let regions = vec![DirtyRegion::new(0, 0, 4, 4), DirtyRegion::new(50, 50, 4, 4)];
+        assert_eq!(coalesce_regions(regions.clone(), 2), regions);
+    }
+
+    #[test]
+    fn test_coalesce_regions_respects_cap() {
+        // A scattered 10x10 grid of small dirty tiles
+        let regions: Vec<DirtyRegion> = (0..100)
+            .map(|i| DirtyRegion::new((i % 10) * 20, (i / 10) * 20, 3, 3))
+            .collect();
+
+        for cap in [1, 4, 7, 32] {
+            let coalesced = coalesce_regions(regions.clone(), cap);
+            assert!(
+                coalesced.len() <= cap,
+                "{} rects for cap {}",
+                coalesced.len(),
+                cap
+            );
+            for region in &regions {
+                assert!(
+                    coalesced
+                        .iter()
+                        .any(|c| c.intersect(region) == Some(*region)),
+                    "{:?} is no longer covered",
+                    region
+                );
+            }
+        }
+
+        assert_eq!(
+            coalesce_regions(regions, 1),
+            vec![DirtyRegion::new(0, 0, 183, 183)]
+        );
+    }
+
     // --- Framebuffer tests ---
 
     #[tokio::test]
@@ -540,6 +717,37 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_cursor_mask() {
+        let cursor = CursorShape {
+            width: 9,
+            height: 2,
+            hotspot_x: 0,
+            hotspot_y: 0,
+            pixels: (0..18)
+                .flat_map(|i| [0, 0, 0, if i % 2 == 0 { 255 } else { 0 }])
+                .collect(),
+        };
+        // Row 0 opaque at even x, row 1 (starting at pixel 9) at odd x
+        assert_eq!(
+            cursor.mask(),
+            vec![0b1010_1010, 0b1000_0000, 0b0101_0101, 0]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_set_cursor_bumps_serial() {
+        let fb = Framebuffer::new(100, 100);
+        let (serial, cursor) = fb.cursor().await;
+        assert_eq!(*cursor, CursorShape::arrow());
+
+        let mut shape = CursorShape::arrow();
+        shape.hotspot_x = 3;
+        fb.set_cursor(shape).await;
+        assert_ne!(fb.cursor_serial(), serial);
+        assert_eq!(fb.cursor().await.1.hotspot_x, 3);
+    }
+
     #[tokio::test]
     async fn test_framebuffer_resize() {
         let fb = Framebuffer::new(10, 10);
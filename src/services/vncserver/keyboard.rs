@@ -0,0 +1,196 @@
+//! Keyboard modifier and lock-key tracking for VNC clients.
+#![allow(dead_code)]
+//!
+//! RFB key events carry X11 keysyms with separate down/up events, so the
+//! server has to remember which modifiers are held and which lock keys are
+//! toggled on. Lock state is reported back to viewers that negotiate the
+//! QEMU LED state pseudo-encoding so their indicators stay in sync.
+
+use super::protocol::{LED_CAPS_LOCK, LED_NUM_LOCK, LED_SCROLL_LOCK};
+
+/// X11 keysym: Scroll_Lock.
+pub const XK_SCROLL_LOCK: u32 = 0xff14;
+/// X11 keysym: Num_Lock.
+pub const XK_NUM_LOCK: u32 = 0xff7f;
+/// X11 keysym: Shift_L.
+pub const XK_SHIFT_L: u32 = 0xffe1;
+/// X11 keysym: Shift_R.
+pub const XK_SHIFT_R: u32 = 0xffe2;
+/// X11 keysym: Control_L.
+pub const XK_CONTROL_L: u32 = 0xffe3;
+/// X11 keysym: Control_R.
+pub const XK_CONTROL_R: u32 = 0xffe4;
+/// X11 keysym: Caps_Lock.
+pub const XK_CAPS_LOCK: u32 = 0xffe5;
+/// X11 keysym: Meta_L.
+pub const XK_META_L: u32 = 0xffe7;
+/// X11 keysym: Meta_R.
+pub const XK_META_R: u32 = 0xffe8;
+/// X11 keysym: Alt_L.
+pub const XK_ALT_L: u32 = 0xffe9;
+/// X11 keysym: Alt_R.
+pub const XK_ALT_R: u32 = 0xffea;
+/// X11 keysym: Super_L.
+pub const XK_SUPER_L: u32 = 0xffeb;
+/// X11 keysym: Super_R.
+pub const XK_SUPER_R: u32 = 0xffec;
+
+/// Held modifiers and toggled lock keys for one client.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KeyboardState {
+    /// Whether either Shift key is held.
+    pub shift: bool,
+    /// Whether either Control key is held.
+    pub control: bool,
+    /// Whether either Alt key is held.
+    pub alt: bool,
+    /// Whether either Meta or Super key is held.
+    pub meta: bool,
+    /// Lock keys currently on, as `LED_*` bits.
+    leds: u8,
+    /// Held modifier keys, one bit per left/right keysym.
+    held: u16,
+}
+
+impl KeyboardState {
+    /// Create a state with nothing held and all locks off.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Apply a key event.
+    ///
+    /// Returns `true` if the lock state changed, i.e. the client's LEDs
+    /// should be updated. Lock keys toggle on key down; repeats of a held
+    /// lock key are ignored.
+    pub fn apply(&mut self, down: bool, keysym: u32) -> bool {
+        let Some(bit) = Self::key_bit(keysym) else {
+            return false;
+        };
+        let was_down = self.held & bit != 0;
+        if down {
+            self.held |= bit;
+        } else {
+            self.held &= !bit;
+        }
+
+        self.shift = self.is_held(&[XK_SHIFT_L, XK_SHIFT_R]);
+        self.control = self.is_held(&[XK_CONTROL_L, XK_CONTROL_R]);
+        self.alt = self.is_held(&[XK_ALT_L, XK_ALT_R]);
+        self.meta = self.is_held(&[XK_META_L, XK_META_R, XK_SUPER_L, XK_SUPER_R]);
+
+        let led = match keysym {
+            XK_CAPS_LOCK => LED_CAPS_LOCK,
+            XK_NUM_LOCK => LED_NUM_LOCK,
+            XK_SCROLL_LOCK => LED_SCROLL_LOCK,
+            _ => return false,
+        };
+        if down && !was_down {
+            self.leds ^= led;
+            return true;
+        }
+        false
+    }
+
+    /// Lock keys currently on, in QEMU LED state message format.
+    pub fn led_state(&self) -> u8 {
+        self.leds
+    }
+
+    /// Whether Caps Lock is on.
+    pub fn caps_lock(&self) -> bool {
+        self.leds & LED_CAPS_LOCK != 0
+    }
+
+    /// Whether Num Lock is on.
+    pub fn num_lock(&self) -> bool {
+        self.leds & LED_NUM_LOCK != 0
+    }
+
+    /// Whether Scroll Lock is on.
+    pub fn scroll_lock(&self) -> bool {
+        self.leds & LED_SCROLL_LOCK != 0
+    }
+
+    fn is_held(&self, keysyms: &[u32]) -> bool {
+        keysyms
+            .iter()
+            .filter_map(|&k| Self::key_bit(k))
+            .any(|bit| self.held & bit != 0)
+    }
+
+    /// Bit in `held` for a tracked modifier or lock keysym.
+    fn key_bit(keysym: u32) -> Option<u16> {
+        let index = match keysym {
+            XK_SHIFT_L..=XK_SUPER_R => keysym - XK_SHIFT_L,
+            XK_NUM_LOCK => 12,
+            XK_SCROLL_LOCK => 13,
+            _ => return None,
+        };
+        Some(1 << index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_modifiers_track_down_and_up() {
+        let mut state = KeyboardState::new();
+        assert!(!state.apply(true, XK_SHIFT_L));
+        assert!(state.shift);
+
+        state.apply(true, XK_CONTROL_R);
+        assert!(state.shift && state.control);
+
+        state.apply(false, XK_SHIFT_L);
+        assert!(!state.shift);
+        assert!(state.control);
+
+        state.apply(false, XK_CONTROL_R);
+        assert_eq!(state, KeyboardState::new());
+    }
+
+    #[test]
+    fn test_modifier_held_until_both_sides_released() {
+        let mut state = KeyboardState::new();
+        state.apply(true, XK_ALT_L);
+        state.apply(true, XK_ALT_R);
+        state.apply(false, XK_ALT_L);
+        assert!(state.alt);
+        state.apply(false, XK_ALT_R);
+        assert!(!state.alt);
+
+        state.apply(true, XK_SUPER_L);
+        assert!(state.meta);
+    }
+
+    #[test]
+    fn test_lock_keys_toggle_on_press() {
+        let mut state = KeyboardState::new();
+        assert!(state.apply(true, XK_CAPS_LOCK));
+        // Auto-repeat while held and the release do not toggle again
+        assert!(!state.apply(true, XK_CAPS_LOCK));
+        assert!(!state.apply(false, XK_CAPS_LOCK));
+        assert!(state.caps_lock());
+        assert_eq!(state.led_state(), LED_CAPS_LOCK);
+
+        assert!(state.apply(true, XK_NUM_LOCK));
+        assert_eq!(state.led_state(), LED_CAPS_LOCK | LED_NUM_LOCK);
+
+        state.apply(false, XK_NUM_LOCK);
+        assert!(state.apply(true, XK_CAPS_LOCK));
+        assert!(!state.caps_lock());
+        assert!(state.num_lock());
+        assert!(!state.scroll_lock());
+    }
+
+    #[test]
+    fn test_other_keys_are_ignored() {
+        let mut state = KeyboardState::new();
+        assert!(!state.apply(true, u32::from(b'a')));
+        assert!(!state.apply(false, u32::from(b'a')));
+        assert_eq!(state, KeyboardState::new());
+    }
+}
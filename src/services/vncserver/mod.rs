@@ -23,6 +23,10 @@
 //! - Zlib, ZlibHex, ZRLE
 //! - Tight (with pure Rust JPEG via `jpeg-encoder`)
 //!
+//! Supported pseudo-encodings: quality and compression levels, QEMU LED
+//! state, Cursor (client-drawn cursor shape) and DesktopSize (resize
+//! notifications).
+//!
 //! # Example
 //!
 //! ```rust,ignore
@@ -44,22 +48,28 @@ mod capture;
 mod client;
 mod encoding;
 mod framebuffer;
+mod keyboard;
 mod protocol;
 mod server;
 
 pub mod config;
 pub mod error;
 
-pub use config::VncConfig;
+pub use config::{ClipboardDirection, VncConfig, VncTlsConfig};
 pub use error::{Result as VncResult, VncError};
-pub use server::VncServer;
+pub use server::{ClientSlot, VncServer};
 
 use std::fmt::Debug;
 use std::sync::Arc;
+use std::time::Instant;
 
 use anyhow::Result;
+use tracing::warn;
 
-use super::{ServiceHandler, StreamDyn};
+use super::{
+    CloseReason, ConnectionSummary, CountedStream, HandlerState, ServiceHandler, ServiceStats,
+    StreamDyn,
+};
 
 /// VNC service handler implementing the [`ServiceHandler`] trait.
 ///
@@ -70,6 +80,8 @@ use super::{ServiceHandler, StreamDyn};
 pub struct VncServiceHandler {
     /// Shared VNC server state (framebuffer, config).
     server: Arc<VncServer>,
+    /// Connection counters, shared with clones.
+    stats: Arc<ServiceStats>,
 }
 
 impl VncServiceHandler {
@@ -77,6 +89,7 @@ impl VncServiceHandler {
     pub fn new(config: VncConfig) -> Self {
         Self {
             server: Arc::new(VncServer::new(config)),
+            stats: Arc::default(),
         }
     }
 
@@ -95,6 +108,7 @@ impl Clone for VncServiceHandler {
     fn clone(&self) -> Self {
         Self {
             server: Arc::clone(&self.server),
+            stats: Arc::clone(&self.stats),
         }
     }
 }
@@ -105,8 +119,27 @@ impl ServiceHandler for VncServiceHandler {
         "vncserver"
     }
 
-    async fn handle_tcp_stream(&self, stream: Box<dyn StreamDyn>) -> Result<()> {
-        self.server.handle_stream(stream).await
+    async fn handle_tcp_stream(&self, stream: Box<dyn StreamDyn>) -> Result<ConnectionSummary> {
+        self.stats
+            .track(async {
+                let start = Instant::now();
+                let (mut stream, counters) = CountedStream::new(stream);
+                let Some(_slot) = self.server.acquire_slot().await else {
+                    warn!(
+                        "VNC server full ({} viewers), rejecting connection",
+                        self.server.active_clients()
+                    );
+                    client::reject_handshake(&mut stream, "Too many VNC clients").await?;
+                    return Ok(ConnectionSummary::new(CloseReason::Rejected)
+                        .with_bytes(counters.read(), counters.written())
+                        .with_duration(start.elapsed()));
+                };
+                self.server.handle_stream(stream).await?;
+                Ok(ConnectionSummary::new(CloseReason::Completed)
+                    .with_bytes(counters.read(), counters.written())
+                    .with_duration(start.elapsed()))
+            })
+            .await
     }
 
     fn is_healthy(&self) -> bool {
@@ -119,11 +152,85 @@ impl ServiceHandler for VncServiceHandler {
             .validate()
             .map_err(|e| anyhow::anyhow!(e))
     }
+
+    fn stats(&self) -> Option<&ServiceStats> {
+        Some(&self.stats)
+    }
+
+    fn reload_state(&self) -> HandlerState {
+        HandlerState {
+            stats: Some(self.stats.clone()),
+            ..Default::default()
+        }
+    }
+
+    fn carry_over(&mut self, state: HandlerState) {
+        if let Some(stats) = state.stats {
+            self.stats = stats;
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    /// Start a connection to `handler`, returning the viewer side and the
+    /// task handling it
+    fn connect(
+        handler: &VncServiceHandler,
+    ) -> (
+        DuplexStream,
+        tokio::task::JoinHandle<Result<ConnectionSummary>>,
+    ) {
+        let (stream, viewer) = tokio::io::duplex(64 * 1024);
+        let handler = handler.clone();
+        let task = tokio::spawn(async move { handler.handle_tcp_stream(Box::new(stream)).await });
+        (viewer, task)
+    }
+
+    /// Exchange versions as a viewer, returning the security type count
+    async fn exchange_versions(viewer: &mut DuplexStream) -> u8 {
+        let mut version = [0u8; 12];
+        viewer.read_exact(&mut version).await.unwrap();
+        viewer.write_all(&version).await.unwrap();
+        viewer.read_u8().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_connection_beyond_max_clients_is_rejected() {
+        let handler = VncServiceHandler::new(VncConfig {
+            max_clients: Some(2),
+            ..VncConfig::default()
+        });
+
+        let mut viewers = Vec::new();
+        for _ in 0..2 {
+            let (mut viewer, task) = connect(&handler);
+            assert_eq!(exchange_versions(&mut viewer).await, 1);
+            viewers.push((viewer, task));
+        }
+        assert_eq!(handler.server().active_clients(), 2);
+
+        // The third viewer gets an RFB failure instead of security types
+        let (mut viewer, task) = connect(&handler);
+        assert_eq!(exchange_versions(&mut viewer).await, 0);
+        let len = viewer.read_u32().await.unwrap() as usize;
+        let mut reason = vec![0u8; len];
+        viewer.read_exact(&mut reason).await.unwrap();
+        assert_eq!(reason, b"Too many VNC clients");
+        let summary = task.await.unwrap().unwrap();
+        assert_eq!(summary.close_reason, CloseReason::Rejected);
+
+        // Leaving frees a slot for the next viewer
+        let (viewer, task) = viewers.pop().unwrap();
+        drop(viewer);
+        let _ = task.await.unwrap();
+        assert_eq!(handler.server().active_clients(), 1);
+        let (mut viewer, _task) = connect(&handler);
+        assert_eq!(exchange_versions(&mut viewer).await, 1);
+    }
 
     #[test]
     fn test_vnc_service_handler_new() {
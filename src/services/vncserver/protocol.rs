@@ -7,6 +7,8 @@
 
 use bytes::{BufMut, BytesMut};
 
+use super::framebuffer::CursorShape;
+
 /// The RFB protocol version string advertised by the server.
 pub const PROTOCOL_VERSION: &str = "RFB 003.008\n";
 
@@ -82,6 +84,26 @@ pub const ENCODING_COMPRESS_LEVEL_0: i32 = -256;
 /// Pseudo-encoding: Compression Level 9 (maximum compression).
 pub const ENCODING_COMPRESS_LEVEL_9: i32 = -247;
 
+/// Pseudo-encoding: QEMU LED state (server reports lock key state).
+pub const ENCODING_QEMU_LED_STATE: i32 = -261;
+
+/// Pseudo-encoding: Cursor (client draws the cursor shape the server sends).
+pub const ENCODING_CURSOR: i32 = -239;
+
+/// Pseudo-encoding: DesktopSize (server announces framebuffer resizes).
+pub const ENCODING_DESKTOP_SIZE: i32 = -223;
+
+// --- QEMU LED State Bits ---
+
+/// LED state bit: Scroll Lock.
+pub const LED_SCROLL_LOCK: u8 = 1 << 0;
+
+/// LED state bit: Num Lock.
+pub const LED_NUM_LOCK: u8 = 1 << 1;
+
+/// LED state bit: Caps Lock.
+pub const LED_CAPS_LOCK: u8 = 1 << 2;
+
 // --- Security Types ---
 
 /// Security type: None (no authentication).
@@ -90,6 +112,18 @@ pub const SECURITY_TYPE_NONE: u8 = 1;
 /// Security type: VNC Authentication.
 pub const SECURITY_TYPE_VNC_AUTH: u8 = 2;
 
+/// Security type: VeNCrypt (TLS, then a subtype's authentication).
+pub const SECURITY_TYPE_VENCRYPT: u8 = 19;
+
+/// VeNCrypt subtype: X.509-certified TLS without authentication.
+pub const VENCRYPT_X509_NONE: u32 = 260;
+
+/// VeNCrypt subtype: X.509-certified TLS, then VNC Authentication.
+pub const VENCRYPT_X509_VNC: u32 = 261;
+
+/// VeNCrypt subtype: X.509-certified TLS, then username and password.
+pub const VENCRYPT_X509_PLAIN: u32 = 262;
+
 // --- Security Results ---
 
 /// Security result: Authentication successful.
@@ -265,6 +299,85 @@ impl Rectangle {
     }
 }
 
+/// Writes a framebuffer update carrying only a QEMU LED state pseudo-rectangle.
+pub fn write_led_state_update(buf: &mut BytesMut, led_state: u8) {
+    buf.put_u8(SERVER_MSG_FRAMEBUFFER_UPDATE);
+    buf.put_u8(0); // padding
+    buf.put_u16(1); // one rectangle
+    Rectangle {
+        x: 0,
+        y: 0,
+        width: 0,
+        height: 0,
+        encoding: ENCODING_QEMU_LED_STATE,
+    }
+    .write_header(buf);
+    buf.put_u8(led_state);
+}
+
+/// Writes an empty security type list followed by `reason`, which refuses
+/// the connection in place of the security types.
+pub fn write_connection_failed(buf: &mut BytesMut, reason: &str) {
+    buf.put_u8(0); // no security types
+    buf.put_u32(reason.len() as u32);
+    buf.put_slice(reason.as_bytes());
+}
+
+/// Writes a framebuffer update carrying only a DesktopSize pseudo-rectangle.
+pub fn write_desktop_size_update(buf: &mut BytesMut, width: u16, height: u16) {
+    buf.put_u8(SERVER_MSG_FRAMEBUFFER_UPDATE);
+    buf.put_u8(0); // padding
+    buf.put_u16(1); // one rectangle
+    Rectangle {
+        x: 0,
+        y: 0,
+        width,
+        height,
+        encoding: ENCODING_DESKTOP_SIZE,
+    }
+    .write_header(buf);
+}
+
+/// Writes a framebuffer update carrying only a Cursor pseudo-rectangle.
+///
+/// `pixels` is the cursor image already in the client's pixel format; the
+/// transparency bitmask is derived from the cursor's alpha channel.
+pub fn write_cursor_update(buf: &mut BytesMut, cursor: &CursorShape, pixels: &[u8]) {
+    buf.put_u8(SERVER_MSG_FRAMEBUFFER_UPDATE);
+    buf.put_u8(0); // padding
+    buf.put_u16(1); // one rectangle
+    Rectangle {
+        x: cursor.hotspot_x,
+        y: cursor.hotspot_y,
+        width: cursor.width,
+        height: cursor.height,
+        encoding: ENCODING_CURSOR,
+    }
+    .write_header(buf);
+    buf.put_slice(pixels);
+    buf.put_slice(&cursor.mask());
+}
+
+/// Writes a ServerCutText message carrying `text`.
+///
+/// RFB cut text is ISO 8859-1; characters outside it are sent as `?`.
+#[allow(clippy::cast_possible_truncation)]
+pub fn write_server_cut_text(buf: &mut BytesMut, text: &str) {
+    let latin1: Vec<u8> = text
+        .chars()
+        .map(|c| u8::try_from(u32::from(c)).unwrap_or(b'?'))
+        .collect();
+    buf.put_u8(SERVER_MSG_SERVER_CUT_TEXT);
+    buf.put_bytes(0, 3); // padding
+    buf.put_u32(latin1.len() as u32);
+    buf.put_slice(&latin1);
+}
+
+/// Decodes the ISO 8859-1 text of a ClientCutText message.
+pub fn decode_cut_text(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| char::from(b)).collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -416,6 +529,76 @@ mod tests {
         assert_eq!(SECURITY_RESULT_FAILED, 1);
     }
 
+    #[test]
+    fn test_led_state_update_write() {
+        let mut buf = BytesMut::new();
+        write_led_state_update(&mut buf, LED_CAPS_LOCK | LED_NUM_LOCK);
+
+        // 4 (update header) + 12 (rectangle header) + 1 (state)
+        assert_eq!(buf.len(), 17);
+        assert_eq!(buf[0], SERVER_MSG_FRAMEBUFFER_UPDATE);
+        assert_eq!(u16::from_be_bytes([buf[2], buf[3]]), 1);
+        assert_eq!(&buf[4..12], &[0; 8]);
+        assert_eq!(
+            i32::from_be_bytes([buf[12], buf[13], buf[14], buf[15]]),
+            ENCODING_QEMU_LED_STATE
+        );
+        assert_eq!(buf[16], 0b110);
+    }
+
+    #[test]
+    fn test_connection_failed_write() {
+        let mut buf = BytesMut::new();
+        write_connection_failed(&mut buf, "full");
+        assert_eq!(&buf[..], &[0, 0, 0, 0, 4, b'f', b'u', b'l', b'l']);
+    }
+
+    #[test]
+    fn test_desktop_size_update_write() {
+        let mut buf = BytesMut::new();
+        write_desktop_size_update(&mut buf, 1920, 1080);
+
+        assert_eq!(buf.len(), 16);
+        assert_eq!(buf[0], SERVER_MSG_FRAMEBUFFER_UPDATE);
+        assert_eq!(u16::from_be_bytes([buf[2], buf[3]]), 1);
+        assert_eq!(u16::from_be_bytes([buf[8], buf[9]]), 1920);
+        assert_eq!(u16::from_be_bytes([buf[10], buf[11]]), 1080);
+        assert_eq!(
+            i32::from_be_bytes([buf[12], buf[13], buf[14], buf[15]]),
+            ENCODING_DESKTOP_SIZE
+        );
+    }
+
+    #[test]
+    fn test_cursor_update_write() {
+        let cursor = CursorShape::arrow();
+        let pixels = vec![0u8; cursor.width as usize * cursor.height as usize * 4];
+        let mut buf = BytesMut::new();
+        write_cursor_update(&mut buf, &cursor, &pixels);
+
+        let mask_len = (cursor.width as usize).div_ceil(8) * cursor.height as usize;
+        assert_eq!(buf.len(), 16 + pixels.len() + mask_len);
+        assert_eq!(u16::from_be_bytes([buf[4], buf[5]]), cursor.hotspot_x);
+        assert_eq!(u16::from_be_bytes([buf[8], buf[9]]), cursor.width);
+        assert_eq!(
+            i32::from_be_bytes([buf[12], buf[13], buf[14], buf[15]]),
+            ENCODING_CURSOR
+        );
+    }
+
+    #[test]
+    fn test_server_cut_text_write() {
+        let mut buf = BytesMut::new();
+        write_server_cut_text(&mut buf, "café ✓");
+
+        assert_eq!(buf[0], SERVER_MSG_SERVER_CUT_TEXT);
+        assert_eq!(&buf[1..4], &[0; 3]);
+        assert_eq!(u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]), 6);
+        // "é" fits in Latin-1, "✓" does not
+        assert_eq!(&buf[8..], b"caf\xe9 ?");
+        assert_eq!(decode_cut_text(&buf[8..]), "café ?");
+    }
+
     #[test]
     fn test_pixel_format_not_compatible_different_shifts() {
         let mut pf = PixelFormat::rgba32();
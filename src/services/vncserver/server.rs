@@ -9,16 +9,27 @@
 //! When the `xcap` feature is enabled, the server automatically starts
 //! screen capture on the first client connection, mirroring the primary
 //! monitor into the framebuffer.
+//!
+//! The server also holds the shared clipboard: text a viewer cuts is stored
+//! and offered to the other viewers, and [`VncServer::set_clipboard`]
+//! pushes host clipboard changes to every viewer, as far as
+//! `clipboard_enabled` and `clipboard_direction` allow.
+//!
+//! Viewers hold a [`ClientSlot`] while connected, so `max_clients` can cap
+//! how many share the server at once.
 
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch, Notify};
 use tracing::{error, info, warn};
 
 use super::capture::ScreenCapture;
-use super::client::{ClientEvent, VncClient};
+#[cfg(feature = "vnc-tls")]
+use super::client::exchange_version;
+use super::client::{ClientEvent, ClipboardContents, VncClient};
 use super::config::VncConfig;
 use super::framebuffer::Framebuffer;
 
@@ -44,6 +55,14 @@ pub struct VncServer {
     capture: tokio::sync::Mutex<Option<ScreenCapture>>,
     /// Whether capture has been attempted (avoids repeated attempts on failure).
     capture_attempted: AtomicBool,
+    /// Latest clipboard contents, watched by the sessions.
+    clipboard: Arc<watch::Sender<ClipboardContents>>,
+    /// Sessions started so far, used as session ids.
+    sessions: AtomicU64,
+    /// Viewers currently holding a [`ClientSlot`].
+    active_clients: AtomicUsize,
+    /// Signalled whenever a slot is released, waking queued viewers.
+    slot_released: Notify,
 }
 
 impl VncServer {
@@ -69,9 +88,68 @@ impl VncServer {
             config,
             capture: tokio::sync::Mutex::new(Some(capture)),
             capture_attempted: AtomicBool::new(false),
+            clipboard: Arc::new(watch::Sender::new(ClipboardContents::default())),
+            sessions: AtomicU64::new(0),
+            active_clients: AtomicUsize::new(0),
+            slot_released: Notify::new(),
+        }
+    }
+
+    /// Returns the number of viewers currently connected.
+    pub fn active_clients(&self) -> usize {
+        self.active_clients.load(Ordering::Acquire)
+    }
+
+    /// Claims a viewer slot if fewer than `max_clients` are connected (no
+    /// limit when unset); the slot is released when the guard drops.
+    pub fn try_acquire_slot(&self) -> Option<ClientSlot<'_>> {
+        let max = self.config.max_clients;
+        self.active_clients
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |active| match max {
+                Some(max) if active >= max => None,
+                _ => Some(active + 1),
+            })
+            .ok()
+            .map(|_| ClientSlot(self))
+    }
+
+    /// Claims a viewer slot, waiting up to `queue_timeout_secs` for one to
+    /// free up when the server is full.
+    ///
+    /// Returns `None` if no slot became free in time.
+    pub async fn acquire_slot(&self) -> Option<ClientSlot<'_>> {
+        let Some(timeout) = self.config.queue_timeout_secs else {
+            return self.try_acquire_slot();
+        };
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(timeout);
+        loop {
+            // Register interest before checking, so a release between the
+            // check and the wait is not missed
+            let released = self.slot_released.notified();
+            tokio::pin!(released);
+            released.as_mut().enable();
+            if let Some(slot) = self.try_acquire_slot() {
+                return Some(slot);
+            }
+            if tokio::time::timeout_at(deadline, released).await.is_err() {
+                return None;
+            }
         }
     }
 
+    /// Returns the latest clipboard text, from the host or a viewer.
+    pub fn clipboard(&self) -> String {
+        self.clipboard.borrow().text.clone()
+    }
+
+    /// Sets the clipboard to the host's `text`, pushing it to viewers.
+    ///
+    /// Ignored when the clipboard is disabled, and text longer than
+    /// `clipboard_max_len` is dropped.
+    pub fn set_clipboard(&self, text: impl Into<String>) {
+        store_clipboard(&self.clipboard, &self.config, text.into(), None);
+    }
+
     /// Returns a reference to the shared framebuffer.
     pub fn framebuffer(&self) -> &Framebuffer {
         &self.framebuffer
@@ -155,10 +233,31 @@ impl VncServer {
         // Start screen capture on first client connection
         self.ensure_capture_started().await;
 
-        let (event_tx, mut event_rx) = mpsc::unbounded_channel();
+        let (event_tx, event_rx) = mpsc::unbounded_channel();
+
+        #[cfg(feature = "vnc-tls")]
+        if let Some(tls) = &self.config.tls {
+            let mut stream = stream;
+            let handshake = async {
+                exchange_version(&mut stream).await?;
+                let stream =
+                    super::auth::vencrypt_handshake(stream, tls, self.password.clone()).await?;
+                VncClient::initialize(
+                    stream,
+                    self.framebuffer.clone(),
+                    self.desktop_name.clone(),
+                    event_tx,
+                )
+                .await
+            };
+            let client = handshake
+                .await
+                .map_err(|e| anyhow::anyhow!("VNC handshake failed: {}", e))?;
+            return self.serve(client, event_rx).await;
+        }
 
         // Create VNC client (performs handshake)
-        let mut client = VncClient::new(
+        let client = VncClient::new(
             stream,
             self.framebuffer.clone(),
             self.desktop_name.clone(),
@@ -167,6 +266,27 @@ impl VncServer {
         )
         .await
         .map_err(|e| anyhow::anyhow!("VNC handshake failed: {}", e))?;
+        self.serve(client, event_rx).await
+    }
+
+    /// Runs the session of a client whose handshake is done.
+    async fn serve<S>(
+        &self,
+        client: VncClient<S>,
+        mut event_rx: mpsc::UnboundedReceiver<ClientEvent>,
+    ) -> anyhow::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let session_id = self.sessions.fetch_add(1, Ordering::Relaxed);
+        let mut client = client
+            .with_max_rects_per_update(self.config.max_rects_per_update)
+            .with_write_timeout(self.config.write_timeout.map(Duration::from_secs))
+            .with_max_cut_text(self.config.clipboard_max_len)
+            .with_view_only(self.config.view_only);
+        if self.config.clipboard_enabled && self.config.clipboard_direction.sends_to_client() {
+            client = client.with_clipboard(self.clipboard.subscribe(), session_id);
+        }
 
         // Register the client's dirty region receiver with the framebuffer
         let receiver = client.dirty_region_receiver();
@@ -175,6 +295,8 @@ impl VncServer {
         info!("VNC client connected");
 
         // Spawn event handler task
+        let clipboard = self.clipboard.clone();
+        let config = self.config.clone();
         let event_handle = tokio::spawn(async move {
             while let Some(event) = event_rx.recv().await {
                 match event {
@@ -191,6 +313,9 @@ impl VncServer {
                     }
                     ClientEvent::CutText { text } => {
                         tracing::debug!("VNC cut text: {} bytes", text.len());
+                        if config.clipboard_direction.accepts_from_client() {
+                            store_clipboard(&clipboard, &config, text, Some(session_id));
+                        }
                     }
                     ClientEvent::Disconnected => {
                         info!("VNC client disconnected");
@@ -219,6 +344,38 @@ impl VncServer {
     }
 }
 
+/// A claimed viewer slot of a [`VncServer`].
+#[derive(Debug)]
+pub struct ClientSlot<'a>(&'a VncServer);
+
+impl Drop for ClientSlot<'_> {
+    fn drop(&mut self) {
+        self.0.active_clients.fetch_sub(1, Ordering::AcqRel);
+        self.0.slot_released.notify_one();
+    }
+}
+
+/// Stores `text` as the clipboard contents set by `origin`.
+fn store_clipboard(
+    clipboard: &watch::Sender<ClipboardContents>,
+    config: &VncConfig,
+    text: String,
+    origin: Option<u64>,
+) {
+    if !config.clipboard_enabled {
+        return;
+    }
+    if text.len() > config.clipboard_max_len {
+        warn!(
+            "Clipboard text too large ({} bytes, max {}), dropping",
+            text.len(),
+            config.clipboard_max_len
+        );
+        return;
+    }
+    clipboard.send_replace(ClipboardContents { text, origin });
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -285,6 +442,107 @@ mod tests {
         assert_eq!(fb.height(), 480);
     }
 
+    #[test]
+    fn test_client_slots_are_capped() {
+        let server = VncServer::new(VncConfig {
+            max_clients: Some(2),
+            ..VncConfig::default()
+        });
+        let first = server.try_acquire_slot().unwrap();
+        let _second = server.try_acquire_slot().unwrap();
+        assert_eq!(server.active_clients(), 2);
+        assert!(server.try_acquire_slot().is_none());
+
+        drop(first);
+        assert_eq!(server.active_clients(), 1);
+        assert!(server.try_acquire_slot().is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_queued_viewer_gets_released_slot() {
+        let server = Arc::new(VncServer::new(VncConfig {
+            max_clients: Some(1),
+            queue_timeout_secs: Some(5),
+            ..VncConfig::default()
+        }));
+        let slot = server.try_acquire_slot().unwrap();
+        let waiter = tokio::spawn({
+            let server = server.clone();
+            async move { server.acquire_slot().await.is_some() }
+        });
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        drop(slot);
+        assert!(waiter.await.unwrap());
+
+        // Nobody leaves this time
+        let _slot = server.try_acquire_slot().unwrap();
+        assert!(server.acquire_slot().await.is_none());
+    }
+
+    #[test]
+    fn test_set_clipboard_respects_config() {
+        let server = VncServer::new(VncConfig {
+            clipboard_max_len: 8,
+            ..VncConfig::default()
+        });
+        server.set_clipboard("copied");
+        assert_eq!(server.clipboard(), "copied");
+
+        // Too long for clipboard_max_len
+        server.set_clipboard("much too long");
+        assert_eq!(server.clipboard(), "copied");
+
+        let server = VncServer::new(VncConfig {
+            clipboard_enabled: false,
+            ..VncConfig::default()
+        });
+        server.set_clipboard("copied");
+        assert_eq!(server.clipboard(), "");
+    }
+
+    #[tokio::test]
+    async fn test_viewer_cut_text_updates_clipboard() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let server = Arc::new(VncServer::new(VncConfig::default()));
+        let (stream, mut viewer) = tokio::io::duplex(4096);
+        let session = tokio::spawn({
+            let server = server.clone();
+            async move { server.handle_stream(stream).await }
+        });
+
+        // Handshake: version, no security, shared ClientInit, ServerInit
+        let mut version = [0u8; 12];
+        viewer.read_exact(&mut version).await.unwrap();
+        viewer.write_all(&version).await.unwrap();
+        let mut security = [0u8; 2];
+        viewer.read_exact(&mut security).await.unwrap();
+        viewer.write_all(&security[1..]).await.unwrap();
+        let mut result = [0u8; 4];
+        viewer.read_exact(&mut result).await.unwrap();
+        viewer.write_all(&[1]).await.unwrap();
+        let mut server_init = [0u8; 24];
+        viewer.read_exact(&mut server_init).await.unwrap();
+        let name_len = u32::from_be_bytes(server_init[20..24].try_into().unwrap());
+        let mut name = vec![0u8; name_len as usize];
+        viewer.read_exact(&mut name).await.unwrap();
+
+        let mut msg = vec![6, 0, 0, 0, 0, 0, 0, 5];
+        msg.extend_from_slice(b"hello");
+        viewer.write_all(&msg).await.unwrap();
+
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while server.clipboard() != "hello" {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("cut text should reach the server clipboard");
+
+        drop(viewer);
+        let _ = session.await.unwrap();
+    }
+
     #[tokio::test]
     async fn test_vnc_server_handle_stream_invalid() {
         let server = VncServer::new(VncConfig::default());
@@ -5,18 +5,24 @@
 use anyhow::{Context, Result};
 use std::net::{SocketAddr, ToSocketAddrs};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::RwLock;
+use tokio::time::Instant;
 
 /// Address that may have a cached resolved address
 ///
 /// This type holds an address string and optionally caches the resolved
-/// socket address to avoid repeated DNS lookups.
+/// socket address to avoid repeated DNS lookups. Clones share the cache.
+/// Without a TTL the cached address is kept until
+/// [`clear_cache`](AddrMaybeCached::clear_cache) is called.
 #[derive(Debug, Clone)]
 pub struct AddrMaybeCached {
     /// The original address string
     addr: String,
-    /// Cached resolved address
-    cached: Arc<RwLock<Option<SocketAddr>>>,
+    /// Cached resolved address and when it was stored
+    cached: Arc<RwLock<Option<(SocketAddr, Instant)>>>,
+    /// How long a cached address stays valid
+    ttl: Option<Duration>,
 }
 
 impl AddrMaybeCached {
@@ -25,6 +31,7 @@ impl AddrMaybeCached {
         AddrMaybeCached {
             addr: addr.to_string(),
             cached: Arc::new(RwLock::new(None)),
+            ttl: None,
         }
     }
 
@@ -32,34 +39,47 @@ impl AddrMaybeCached {
     pub fn with_cached(addr: &str, resolved: SocketAddr) -> Self {
         AddrMaybeCached {
             addr: addr.to_string(),
-            cached: Arc::new(RwLock::new(Some(resolved))),
+            cached: Arc::new(RwLock::new(Some((resolved, Instant::now())))),
+            ttl: None,
         }
     }
 
+    /// Re-resolve the address once the cached one is older than `ttl`
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
     /// Get the original address string
     pub fn addr(&self) -> &str {
         &self.addr
     }
 
-    /// Get the cached address if available
+    /// Get the cached address if available and not expired
     pub async fn get_cached(&self) -> Option<SocketAddr> {
-        *self.cached.read().await
+        let (addr, stored) = (*self.cached.read().await)?;
+        match self.ttl {
+            Some(ttl) if stored.elapsed() >= ttl => None,
+            _ => Some(addr),
+        }
     }
 
     /// Set the cached address
     pub async fn set_cached(&self, addr: SocketAddr) {
-        *self.cached.write().await = Some(addr);
+        *self.cached.write().await = Some((addr, Instant::now()));
     }
 
-    /// Clear the cached address
+    /// Clear the cached address, e.g. after a connection to it failed, so
+    /// the next [`resolve`](AddrMaybeCached::resolve) looks it up again
     pub async fn clear_cache(&self) {
         *self.cached.write().await = None;
     }
 
     /// Resolve the address, using cache if available
     ///
-    /// If the address is already cached, returns the cached value.
-    /// Otherwise, performs DNS resolution and caches the result.
+    /// If the address is cached and the TTL has not expired, returns the
+    /// cached value. Otherwise, performs DNS resolution and caches the
+    /// result.
     pub async fn resolve(&self) -> Result<SocketAddr> {
         // Check cache first
         if let Some(cached) = self.get_cached().await {
@@ -96,7 +116,8 @@ impl From<SocketAddr> for AddrMaybeCached {
     fn from(addr: SocketAddr) -> Self {
         AddrMaybeCached {
             addr: addr.to_string(),
-            cached: Arc::new(RwLock::new(Some(addr))),
+            cached: Arc::new(RwLock::new(Some((addr, Instant::now())))),
+            ttl: None,
         }
     }
 }
@@ -180,4 +201,32 @@ mod tests {
         let resolved = addr.resolve().await.unwrap();
         assert_eq!(resolved, socket_addr);
     }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_addr_maybe_cached_ttl_expiry() {
+        let stale = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)), 1234);
+        let addr =
+            AddrMaybeCached::with_cached("127.0.0.1:8080", stale).with_ttl(Duration::from_secs(60));
+
+        // Still within the TTL
+        tokio::time::advance(Duration::from_secs(59)).await;
+        assert_eq!(addr.resolve().await.unwrap(), stale);
+
+        // Expired: looked up again and cached afresh
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(addr.get_cached().await.is_none());
+        let fresh: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        assert_eq!(addr.resolve().await.unwrap(), fresh);
+        assert_eq!(addr.get_cached().await, Some(fresh));
+    }
+
+    #[tokio::test]
+    async fn test_addr_maybe_cached_clear_forces_lookup() {
+        let stale = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)), 1234);
+        let addr = AddrMaybeCached::with_cached("127.0.0.1:8080", stale);
+        let shared = addr.clone();
+
+        shared.clear_cache().await;
+        assert_eq!(addr.resolve().await.unwrap().to_string(), "127.0.0.1:8080");
+    }
 }
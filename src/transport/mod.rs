@@ -1,23 +1,35 @@
 //! Transport module for Sockrats
 //!
 //! This module provides the transport layer abstraction and implementations
-//! for different protocols (TCP, Noise).
+//! for different protocols (TCP, Noise, TLS), plus recording/replaying
+//! transports for tests behind the `test-support` feature.
 
 mod addr;
 #[cfg(feature = "noise")]
 mod noise;
+#[cfg(any(test, feature = "test-support"))]
+mod replay;
 mod tcp;
+#[cfg(feature = "tls")]
+mod tls;
 #[cfg(feature = "wireguard")]
 pub mod wireguard;
 
 pub use addr::AddrMaybeCached;
 #[cfg(feature = "noise")]
 pub use noise::NoiseTransport;
+#[cfg(any(test, feature = "test-support"))]
+pub use replay::{
+    Direction, Frame, RecordedConnection, Recording, RecordingStream, RecordingTransport,
+    ReplayStream, ReplayTransport,
+};
 pub use tcp::TcpTransport;
+#[cfg(feature = "tls")]
+pub use tls::TlsTransport;
 #[cfg(feature = "wireguard")]
 pub use wireguard::WireguardTransport;
 
-use crate::config::{TcpConfig, TransportConfig, TransportType};
+use crate::config::{KeepaliveConfig, TcpConfig, TransportConfig, TransportType};
 use anyhow::Result;
 use async_trait::async_trait;
 use std::fmt::Debug;
@@ -74,6 +86,15 @@ impl SocketOpts {
         }
     }
 
+    /// Create data channel socket options from a keepalive profile
+    pub fn from_keepalive_config(config: &KeepaliveConfig) -> Self {
+        SocketOpts {
+            nodelay: true,
+            keepalive_secs: Some(config.keepalive_secs),
+            keepalive_interval: Some(config.keepalive_interval),
+        }
+    }
+
     /// Apply socket options to a TCP stream
     pub fn apply(&self, stream: &TcpStream) -> std::io::Result<()> {
         stream.set_nodelay(self.nodelay)?;
@@ -110,6 +131,15 @@ pub trait Transport: Debug + Send + Sync + 'static {
 
     /// Connect to a remote address
     async fn connect(&self, addr: &AddrMaybeCached) -> Result<Self::Stream>;
+
+    /// Re-read state kept outside the configuration, such as key files,
+    /// for connections made from now on
+    ///
+    /// Returns whether connections already made should be replaced to pick
+    /// up the new state.
+    fn reload(&self) -> Result<bool> {
+        Ok(false)
+    }
 }
 
 /// Create a transport based on configuration
@@ -128,6 +158,15 @@ pub fn create_transport(config: &TransportConfig) -> Result<Box<dyn TransportDyn
         TransportType::Noise => {
             anyhow::bail!("Noise transport is not enabled. Recompile with --features noise")
         }
+        #[cfg(feature = "tls")]
+        TransportType::Tls => {
+            let transport = TlsTransport::new(config)?;
+            Ok(Box::new(transport))
+        }
+        #[cfg(not(feature = "tls"))]
+        TransportType::Tls => {
+            anyhow::bail!("TLS transport is not enabled. Recompile with --features tls")
+        }
     }
 }
 
@@ -181,4 +220,16 @@ mod tests {
         assert_eq!(opts.keepalive_secs, Some(60));
         assert_eq!(opts.keepalive_interval, Some(15));
     }
+
+    #[test]
+    fn test_socket_opts_from_keepalive_config() {
+        let config = KeepaliveConfig {
+            keepalive_secs: 5,
+            keepalive_interval: 2,
+        };
+        let opts = SocketOpts::from_keepalive_config(&config);
+        assert!(opts.nodelay);
+        assert_eq!(opts.keepalive_secs, Some(5));
+        assert_eq!(opts.keepalive_interval, Some(2));
+    }
 }
@@ -4,22 +4,33 @@
 
 use super::{AddrMaybeCached, SocketOpts, StreamDyn, Transport, TransportDyn};
 use crate::config::{NoiseConfig, TransportConfig};
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use async_trait::async_trait;
 use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
-use snowstorm::NoiseStream;
+use snowstorm::snow::params::{DHChoice, HandshakeModifier};
+use snowstorm::snow::HandshakeState;
+use snowstorm::{NoiseParams, NoiseStream};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
 use std::time::Duration;
 use tokio::net::TcpStream;
 
+/// Length of a Noise pre-shared key in bytes
+const PSK_LEN: usize = 32;
+
 /// Noise transport for encrypted connections using Noise protocol
 #[derive(Debug)]
 pub struct NoiseTransport {
     /// Noise pattern (e.g., "Noise_NK_25519_ChaChaPoly_BLAKE2s")
-    pattern: String,
+    params: NoiseParams,
     /// Local private key (optional for some patterns)
-    local_private_key: Option<Vec<u8>>,
+    local_private_key: RwLock<Option<Vec<u8>>>,
+    /// File the local private key is (re)loaded from
+    static_key_file: Option<PathBuf>,
     /// Remote public key
     remote_public_key: Vec<u8>,
+    /// Pre-shared key, for patterns with `psk` modifiers
+    psk: Option<Vec<u8>>,
     /// Socket options to apply to connections
     socket_opts: SocketOpts,
     /// Connection timeout
@@ -28,27 +39,52 @@ pub struct NoiseTransport {
 
 impl NoiseTransport {
     /// Create a new Noise transport with the given configuration
+    ///
+    /// Keys must decode to the lengths the pattern needs, and `psk` is
+    /// required exactly when the pattern has `psk` modifiers.
     pub fn with_config(config: &NoiseConfig, socket_opts: SocketOpts) -> Result<Self> {
-        // Decode the remote public key from base64
-        let remote_public_key = BASE64
-            .decode(&config.remote_public_key)
-            .with_context(|| "Failed to decode remote public key from base64")?;
-
-        // Decode local private key if provided
-        let local_private_key = if let Some(ref key) = config.local_private_key {
-            Some(
-                BASE64
-                    .decode(key)
-                    .with_context(|| "Failed to decode local private key from base64")?,
-            )
-        } else {
-            None
+        let params: NoiseParams = config
+            .pattern
+            .parse()
+            .with_context(|| format!("Invalid Noise pattern {:?}", config.pattern))?;
+        let key_len = match params.dh {
+            DHChoice::Curve25519 => 32,
+            DHChoice::Ed448 => 56,
+        };
+
+        let remote_public_key =
+            decode_key(&config.remote_public_key, key_len, "remote public key")?;
+
+        if config.local_private_key.is_some() && config.static_key_file.is_some() {
+            bail!("Set either local_private_key or static_key_file, not both");
+        }
+        let local_private_key = match (&config.local_private_key, &config.static_key_file) {
+            (Some(key), _) => Some(decode_key(key, key_len, "local private key")?),
+            (None, Some(path)) => Some(read_key_file(path, key_len)?),
+            (None, None) => None,
         };
 
+        let psk = config
+            .psk
+            .as_deref()
+            .map(|psk| decode_key(psk, PSK_LEN, "psk"))
+            .transpose()?;
+        let has_psk_modifier = !psk_positions(&params).is_empty();
+        match (&psk, has_psk_modifier) {
+            (Some(_), false) => bail!(
+                "psk requires a pattern with a psk modifier (got {:?})",
+                config.pattern
+            ),
+            (None, true) => bail!("Noise pattern {:?} requires a psk", config.pattern),
+            _ => {}
+        }
+
         Ok(NoiseTransport {
-            pattern: config.pattern.clone(),
-            local_private_key,
+            params,
+            local_private_key: RwLock::new(local_private_key),
+            static_key_file: config.static_key_file.clone(),
             remote_public_key,
+            psk,
             socket_opts,
             connect_timeout: Duration::from_secs(10),
         })
@@ -59,6 +95,56 @@ impl NoiseTransport {
         self.connect_timeout = timeout;
         self
     }
+
+    /// Start the initiator side of a handshake with the current keys
+    fn initiator(&self) -> Result<HandshakeState> {
+        let local_private_key = self.local_private_key.read().unwrap();
+        let mut builder =
+            snowstorm::Builder::new(self.params.clone()).remote_public_key(&self.remote_public_key);
+
+        if let Some(ref key) = *local_private_key {
+            builder = builder.local_private_key(key);
+        }
+        if let Some(ref psk) = self.psk {
+            for position in psk_positions(&self.params) {
+                builder = builder.psk(position, psk);
+            }
+        }
+
+        Ok(builder.build_initiator()?)
+    }
+}
+
+/// Positions of the pre-shared keys in the handshake of `params`
+fn psk_positions(params: &NoiseParams) -> Vec<u8> {
+    params
+        .handshake
+        .modifiers
+        .list
+        .iter()
+        .filter_map(|modifier| match modifier {
+            HandshakeModifier::Psk(position) => Some(*position),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Decode a base64 key, checking it is `len` bytes long
+fn decode_key(key: &str, len: usize, name: &str) -> Result<Vec<u8>> {
+    let key = BASE64
+        .decode(key.trim())
+        .with_context(|| format!("Failed to decode {} from base64", name))?;
+    if key.len() != len {
+        bail!("{} must be {} bytes, got {}", name, len, key.len());
+    }
+    Ok(key)
+}
+
+/// Read a base64 local private key from `path`
+fn read_key_file(path: &Path, len: usize) -> Result<Vec<u8>> {
+    let key = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read Noise static key file {:?}", path))?;
+    decode_key(&key, len, "local private key").with_context(|| format!("In {:?}", path))
 }
 
 #[async_trait]
@@ -91,16 +177,8 @@ impl Transport for NoiseTransport {
         // Apply socket options before Noise handshake
         self.socket_opts.apply(&tcp_stream)?;
 
-        // Build Noise initiator using snowstorm
-        let mut builder = snowstorm::Builder::new(self.pattern.parse()?)
-            .remote_public_key(&self.remote_public_key);
-
-        if let Some(ref key) = self.local_private_key {
-            builder = builder.local_private_key(key);
-        }
-
         // Build initiator and perform handshake
-        let handshake_state = builder.build_initiator()?;
+        let handshake_state = self.initiator()?;
 
         // Perform Noise handshake using snowstorm's NoiseStream
         let noise_stream = NoiseStream::handshake(tcp_stream, handshake_state)
@@ -111,6 +189,18 @@ impl Transport for NoiseTransport {
 
         Ok(noise_stream)
     }
+
+    /// Re-read `static_key_file`; connections already made keep their key
+    fn reload(&self) -> Result<bool> {
+        let Some(path) = &self.static_key_file else {
+            return Ok(false);
+        };
+        let key_len = self.remote_public_key.len();
+        let key = read_key_file(path, key_len)?;
+        *self.local_private_key.write().unwrap() = Some(key);
+        tracing::info!("Reloaded Noise static key from {:?}", path);
+        Ok(true)
+    }
 }
 
 #[async_trait]
@@ -146,6 +236,8 @@ mod tests {
         let config = NoiseConfig {
             pattern: "Noise_NK_25519_ChaChaPoly_BLAKE2s".to_string(),
             local_private_key: None,
+            static_key_file: None,
+            psk: None,
             remote_public_key: public_key,
         };
         let socket_opts = SocketOpts::default();
@@ -159,6 +251,8 @@ mod tests {
         let config = NoiseConfig {
             pattern: "Noise_NK_25519_ChaChaPoly_BLAKE2s".to_string(),
             local_private_key: None,
+            static_key_file: None,
+            psk: None,
             remote_public_key: "not-valid-base64!!!".to_string(),
         };
         let socket_opts = SocketOpts::default();
@@ -174,6 +268,8 @@ mod tests {
         let config = NoiseConfig {
             pattern: "Noise_NK_25519_ChaChaPoly_BLAKE2s".to_string(),
             local_private_key: None,
+            static_key_file: None,
+            psk: None,
             remote_public_key: public_key,
         };
 
@@ -183,4 +279,133 @@ mod tests {
 
         assert_eq!(transport.connect_timeout, Duration::from_secs(30));
     }
+
+    const PSK_PATTERN: &str = "Noise_NKpsk2_25519_ChaChaPoly_BLAKE2s";
+
+    fn psk_config(public_key: &str, psk: Option<&[u8; 32]>) -> NoiseConfig {
+        NoiseConfig {
+            pattern: PSK_PATTERN.to_string(),
+            local_private_key: None,
+            static_key_file: None,
+            psk: psk.map(|psk| BASE64.encode(psk)),
+            remote_public_key: public_key.to_string(),
+        }
+    }
+
+    /// Run a handshake in memory against a responder holding `private_key`
+    /// and `psk`
+    fn handshake(initiator: &mut HandshakeState, private_key: &[u8], psk: &[u8]) -> Result<()> {
+        let mut responder = snowstorm::Builder::new(PSK_PATTERN.parse().unwrap())
+            .local_private_key(private_key)
+            .psk(2, psk)
+            .build_responder()?;
+        let mut message = [0u8; 1024];
+        let mut payload = [0u8; 1024];
+
+        let len = initiator.write_message(&[], &mut message)?;
+        responder.read_message(&message[..len], &mut payload)?;
+        let len = responder.write_message(&[], &mut message)?;
+        initiator.read_message(&message[..len], &mut payload)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_noise_psk_handshake() {
+        let keypair = snowstorm::Builder::new(PSK_PATTERN.parse().unwrap())
+            .generate_keypair()
+            .unwrap();
+        let public_key = BASE64.encode(&keypair.public);
+        let psk = [7u8; 32];
+
+        let transport = NoiseTransport::with_config(
+            &psk_config(&public_key, Some(&psk)),
+            SocketOpts::default(),
+        )
+        .unwrap();
+        let mut initiator = transport.initiator().unwrap();
+        assert!(handshake(&mut initiator, &keypair.private, &psk).is_ok());
+
+        let mut initiator = transport.initiator().unwrap();
+        assert!(handshake(&mut initiator, &keypair.private, &[8u8; 32]).is_err());
+    }
+
+    #[test]
+    fn test_noise_psk_must_match_pattern() {
+        let (_, public_key) = create_test_keypair();
+
+        // psk pattern without a psk
+        let err =
+            NoiseTransport::with_config(&psk_config(&public_key, None), SocketOpts::default())
+                .unwrap_err();
+        assert!(err.to_string().contains("requires a psk"));
+
+        // psk without a psk pattern
+        let config = NoiseConfig {
+            pattern: "Noise_NK_25519_ChaChaPoly_BLAKE2s".to_string(),
+            ..psk_config(&public_key, Some(&[7u8; 32]))
+        };
+        assert!(NoiseTransport::with_config(&config, SocketOpts::default()).is_err());
+
+        // short psk
+        let config = NoiseConfig {
+            psk: Some(BASE64.encode([7u8; 16])),
+            ..psk_config(&public_key, None)
+        };
+        let err = NoiseTransport::with_config(&config, SocketOpts::default()).unwrap_err();
+        assert!(err.to_string().contains("psk must be 32 bytes"));
+    }
+
+    #[test]
+    fn test_noise_key_length_is_checked() {
+        let config = NoiseConfig {
+            pattern: "Noise_NK_25519_ChaChaPoly_BLAKE2s".to_string(),
+            local_private_key: None,
+            static_key_file: None,
+            psk: None,
+            remote_public_key: BASE64.encode([1u8; 31]),
+        };
+        let err = NoiseTransport::with_config(&config, SocketOpts::default()).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("remote public key must be 32 bytes"));
+    }
+
+    #[test]
+    fn test_noise_reload_static_key_file() {
+        let (_, public_key) = create_test_keypair();
+        let (first, _) = create_test_keypair();
+        let (second, _) = create_test_keypair();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("noise.key");
+        std::fs::write(&path, format!("{}\n", first)).unwrap();
+
+        let config = NoiseConfig {
+            pattern: "Noise_XK_25519_ChaChaPoly_BLAKE2s".to_string(),
+            local_private_key: None,
+            static_key_file: Some(path.clone()),
+            psk: None,
+            remote_public_key: public_key,
+        };
+        let transport = NoiseTransport::with_config(&config, SocketOpts::default()).unwrap();
+        let current = || {
+            BASE64.encode(
+                transport
+                    .local_private_key
+                    .read()
+                    .unwrap()
+                    .as_ref()
+                    .unwrap(),
+            )
+        };
+        assert_eq!(current(), first);
+
+        std::fs::write(&path, &second).unwrap();
+        assert!(transport.reload().unwrap());
+        assert_eq!(current(), second);
+
+        // A bad key file leaves the current key in place
+        std::fs::write(&path, "short").unwrap();
+        assert!(transport.reload().is_err());
+        assert_eq!(current(), second);
+    }
 }
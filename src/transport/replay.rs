@@ -0,0 +1,483 @@
+//! Recording and replaying transports
+//!
+//! [`RecordingTransport`] wraps another transport and captures the bytes
+//! sent and received on every connection it opens. The resulting
+//! [`Recording`] can be saved to a file and fed to a [`ReplayTransport`],
+//! which plays the server side back deterministically: reads are served
+//! from the recorded server bytes, and writes must match what the client
+//! sent originally. This lets the full control/data channel flow be
+//! regression-tested without a live rathole server.
+//!
+//! Only built with the `test-support` feature (and for this crate's own
+//! tests).
+
+use super::{AddrMaybeCached, SocketOpts, Transport};
+use crate::config::TransportConfig;
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context as TaskContext, Poll, Waker};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::debug;
+
+/// Which way recorded bytes travelled, from the client's point of view
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    /// Written by the client
+    Sent,
+    /// Read by the client
+    Received,
+}
+
+/// A run of bytes travelling in one direction
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Frame {
+    /// Direction of the bytes
+    pub direction: Direction,
+    /// The bytes
+    pub data: Vec<u8>,
+}
+
+/// The byte stream of a single connection
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedConnection {
+    /// Address the connection was opened to
+    pub addr: String,
+    /// Bytes exchanged, in order; adjacent bytes in the same direction
+    /// are merged into one frame
+    pub frames: Vec<Frame>,
+}
+
+impl RecordedConnection {
+    fn push(&mut self, direction: Direction, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        match self.frames.last_mut() {
+            Some(frame) if frame.direction == direction => frame.data.extend_from_slice(data),
+            _ => self.frames.push(Frame {
+                direction,
+                data: data.to_vec(),
+            }),
+        }
+    }
+}
+
+/// Every connection opened through a [`RecordingTransport`], in order
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Recording {
+    /// Recorded connections
+    pub connections: Vec<RecordedConnection>,
+}
+
+impl Recording {
+    /// Load a recording saved with [`Recording::save`]
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read recording {:?}", path))?;
+        serde_json::from_str(&content).with_context(|| format!("Invalid recording {:?}", path))
+    }
+
+    /// Save the recording to `path` as JSON
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let content = serde_json::to_string_pretty(self)?;
+        std::fs::write(path, content)
+            .with_context(|| format!("Failed to write recording {:?}", path))
+    }
+}
+
+/// Transport recording the byte streams of another transport
+#[derive(Debug)]
+pub struct RecordingTransport<T: Transport> {
+    inner: T,
+    recording: Arc<Mutex<Recording>>,
+}
+
+impl<T: Transport> RecordingTransport<T> {
+    /// Record connections made through `inner`
+    pub fn wrap(inner: T) -> Self {
+        RecordingTransport {
+            inner,
+            recording: Arc::new(Mutex::new(Recording::default())),
+        }
+    }
+
+    /// Snapshot of everything recorded so far
+    pub fn recording(&self) -> Recording {
+        lock(&self.recording).clone()
+    }
+
+    /// Save everything recorded so far to `path`
+    pub fn save(&self, path: &Path) -> Result<()> {
+        self.recording().save(path)
+    }
+}
+
+#[async_trait]
+impl<T: Transport> Transport for RecordingTransport<T> {
+    type Stream = RecordingStream<T::Stream>;
+
+    fn new(config: &TransportConfig) -> Result<Self> {
+        Ok(Self::wrap(T::new(config)?))
+    }
+
+    fn hint(conn: &Self::Stream, opts: SocketOpts) {
+        T::hint(&conn.inner, opts);
+    }
+
+    async fn connect(&self, addr: &AddrMaybeCached) -> Result<Self::Stream> {
+        let inner = self.inner.connect(addr).await?;
+        let index = {
+            let mut recording = lock(&self.recording);
+            recording.connections.push(RecordedConnection {
+                addr: addr.addr().to_string(),
+                frames: Vec::new(),
+            });
+            recording.connections.len() - 1
+        };
+        Ok(RecordingStream {
+            inner,
+            recording: self.recording.clone(),
+            index,
+        })
+    }
+}
+
+/// Stream opened by a [`RecordingTransport`]
+#[derive(Debug)]
+pub struct RecordingStream<S> {
+    inner: S,
+    recording: Arc<Mutex<Recording>>,
+    index: usize,
+}
+
+impl<S> RecordingStream<S> {
+    fn record(&self, direction: Direction, data: &[u8]) {
+        lock(&self.recording).connections[self.index].push(direction, data);
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for RecordingStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            this.record(Direction::Received, &buf.filled()[before..]);
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for RecordingStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            this.record(Direction::Sent, &buf[..n]);
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Transport playing back a [`Recording`]
+///
+/// Each connect hands out the next recorded connection, regardless of the
+/// address asked for.
+#[derive(Debug)]
+pub struct ReplayTransport {
+    connections: Mutex<VecDeque<RecordedConnection>>,
+}
+
+impl ReplayTransport {
+    /// Play back `recording`
+    pub fn from_recording(recording: Recording) -> Self {
+        ReplayTransport {
+            connections: Mutex::new(recording.connections.into()),
+        }
+    }
+
+    /// Play back a recording saved to `path`
+    pub fn load(path: &Path) -> Result<Self> {
+        Ok(Self::from_recording(Recording::load(path)?))
+    }
+
+    /// Number of recorded connections not yet handed out
+    pub fn remaining(&self) -> usize {
+        lock(&self.connections).len()
+    }
+}
+
+#[async_trait]
+impl Transport for ReplayTransport {
+    type Stream = ReplayStream;
+
+    fn new(_config: &TransportConfig) -> Result<Self> {
+        bail!("ReplayTransport must be created from a recording")
+    }
+
+    fn hint(_conn: &Self::Stream, _opts: SocketOpts) {}
+
+    async fn connect(&self, addr: &AddrMaybeCached) -> Result<Self::Stream> {
+        let Some(connection) = lock(&self.connections).pop_front() else {
+            bail!("No recorded connection left for {}", addr.addr());
+        };
+        debug!(
+            "Replaying connection to {} (recorded for {})",
+            addr.addr(),
+            connection.addr
+        );
+        Ok(ReplayStream {
+            frames: connection.frames.into(),
+            offset: 0,
+            read_waker: None,
+        })
+    }
+}
+
+/// Stream opened by a [`ReplayTransport`]
+///
+/// Reads block while the script expects the client to write next, and
+/// hit EOF once the script is exhausted.
+#[derive(Debug)]
+pub struct ReplayStream {
+    frames: VecDeque<Frame>,
+    /// Bytes of the front frame already consumed
+    offset: usize,
+    /// Reader waiting for the client to finish a write
+    read_waker: Option<Waker>,
+}
+
+impl ReplayStream {
+    /// Whether every recorded byte has been read or written
+    pub fn is_finished(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Consume `n` bytes of the front frame
+    fn advance(&mut self, n: usize) {
+        self.offset += n;
+        if self.frames.front().map(|f| f.data.len()) == Some(self.offset) {
+            self.frames.pop_front();
+            self.offset = 0;
+        }
+    }
+}
+
+impl AsyncRead for ReplayStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.frames.front() {
+            // End of script
+            None => Poll::Ready(Ok(())),
+            Some(frame) if frame.direction == Direction::Received => {
+                let pending = &frame.data[this.offset..];
+                let n = pending.len().min(buf.remaining());
+                buf.put_slice(&pending[..n]);
+                this.advance(n);
+                Poll::Ready(Ok(()))
+            }
+            Some(_) => {
+                this.read_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl AsyncWrite for ReplayStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let expected = match this.frames.front() {
+            Some(frame) if frame.direction == Direction::Sent => &frame.data[this.offset..],
+            _ => {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Unexpected write of {} bytes during replay", buf.len()),
+                )))
+            }
+        };
+
+        let n = expected.len().min(buf.len());
+        if buf[..n] != expected[..n] {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Written bytes differ from the recording",
+            )));
+        }
+        this.advance(n);
+        if let Some(waker) = this.read_waker.take() {
+            waker.wake();
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::TcpTransport;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn script(frames: &[(Direction, &[u8])]) -> Recording {
+        Recording {
+            connections: vec![RecordedConnection {
+                addr: "127.0.0.1:2333".to_string(),
+                frames: frames
+                    .iter()
+                    .map(|(direction, data)| Frame {
+                        direction: *direction,
+                        data: data.to_vec(),
+                    })
+                    .collect(),
+            }],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_recording_captures_both_directions() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 4];
+            conn.read_exact(&mut buf).await.unwrap();
+            conn.write_all(b"pong").await.unwrap();
+        });
+
+        let transport =
+            RecordingTransport::wrap(TcpTransport::new(&TransportConfig::default()).unwrap());
+        let mut conn = transport
+            .connect(&AddrMaybeCached::new(&addr))
+            .await
+            .unwrap();
+        // Two writes in a row are merged into one frame
+        conn.write_all(b"pi").await.unwrap();
+        conn.write_all(b"ng").await.unwrap();
+        let mut buf = [0u8; 4];
+        conn.read_exact(&mut buf).await.unwrap();
+
+        let recording = transport.recording();
+        assert_eq!(recording.connections.len(), 1);
+        assert_eq!(recording.connections[0].addr, addr);
+        assert_eq!(
+            recording.connections[0].frames,
+            vec![
+                Frame {
+                    direction: Direction::Sent,
+                    data: b"ping".to_vec(),
+                },
+                Frame {
+                    direction: Direction::Received,
+                    data: b"pong".to_vec(),
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_replay_serves_script() {
+        let transport = ReplayTransport::from_recording(script(&[
+            (Direction::Sent, b"ping"),
+            (Direction::Received, b"pong"),
+        ]));
+        let conn = transport
+            .connect(&AddrMaybeCached::new("127.0.0.1:2333"))
+            .await
+            .unwrap();
+        assert_eq!(transport.remaining(), 0);
+
+        // The reader waits for the client's write before getting the reply
+        let (mut reader, mut writer) = tokio::io::split(conn);
+        let read = tokio::spawn(async move {
+            let mut buf = [0u8; 4];
+            reader.read_exact(&mut buf).await.unwrap();
+            buf
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        assert!(!read.is_finished());
+
+        writer.write_all(b"ping").await.unwrap();
+        assert_eq!(&read.await.unwrap(), b"pong");
+    }
+
+    #[tokio::test]
+    async fn test_replay_hits_eof_after_script() {
+        let mut conn = replay_stream(&[(Direction::Received, b"pong")]).await;
+        let mut buf = [0u8; 8];
+        assert_eq!(conn.read(&mut buf).await.unwrap(), 4);
+        assert!(conn.is_finished());
+        assert_eq!(conn.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_replay_rejects_divergent_write() {
+        let mut conn = replay_stream(&[(Direction::Sent, b"ping")]).await;
+        let err = conn.write_all(b"pong").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // Nothing more is expected once the script is done
+        conn.write_all(b"ping").await.unwrap();
+        let err = conn.write_all(b"!").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn test_recording_round_trips_through_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session.json");
+        let recording = script(&[(Direction::Received, b"\x00\xff")]);
+        recording.save(&path).unwrap();
+        assert_eq!(Recording::load(&path).unwrap(), recording);
+    }
+
+    async fn replay_stream(frames: &[(Direction, &[u8])]) -> ReplayStream {
+        ReplayTransport::from_recording(script(frames))
+            .connect(&AddrMaybeCached::new("127.0.0.1:2333"))
+            .await
+            .unwrap()
+    }
+}
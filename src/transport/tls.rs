@@ -0,0 +1,397 @@
+//! TLS transport implementation
+//!
+//! Provides TLS-encrypted connections for the rathole protocol, for servers
+//! that terminate the control and data channels over TLS.
+
+use super::{AddrMaybeCached, SocketOpts, StreamDyn, Transport, TransportDyn};
+use crate::config::{TlsConfig, TransportConfig};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::crypto::{self, CryptoProvider};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use tokio_rustls::rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use tokio_rustls::TlsConnector;
+
+/// TLS transport for encrypted connections
+#[derive(Clone)]
+pub struct TlsTransport {
+    /// Connector holding the trust roots and client identity
+    connector: TlsConnector,
+    /// Server name to use instead of the host of the remote address
+    hostname: Option<ServerName<'static>>,
+    /// Socket options to apply to connections
+    socket_opts: SocketOpts,
+    /// Connection timeout
+    connect_timeout: Duration,
+}
+
+impl std::fmt::Debug for TlsTransport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TlsTransport")
+            .field("hostname", &self.hostname)
+            .field("socket_opts", &self.socket_opts)
+            .field("connect_timeout", &self.connect_timeout)
+            .finish_non_exhaustive()
+    }
+}
+
+impl TlsTransport {
+    /// Create a new TLS transport with the given configuration
+    pub fn with_config(config: &TlsConfig, socket_opts: SocketOpts) -> Result<Self> {
+        config.validate().map_err(|e| anyhow::anyhow!(e))?;
+
+        let provider = Arc::new(crypto::ring::default_provider());
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .context("Failed to select TLS protocol versions")?;
+        let builder = if config.insecure_skip_verify {
+            tracing::warn!("TLS server certificate verification is disabled");
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(SkipServerVerification(provider)))
+        } else {
+            builder.with_root_certificates(root_store(config.trusted_root.as_deref())?)
+        };
+        let client_config = match (&config.client_cert, &config.client_key) {
+            (Some(cert), Some(key)) => builder
+                .with_client_auth_cert(load_certs(cert)?, load_key(key)?)
+                .context("Invalid TLS client certificate or key")?,
+            _ => builder.with_no_client_auth(),
+        };
+
+        let hostname = config
+            .hostname
+            .as_deref()
+            .map(|name| {
+                ServerName::try_from(name.to_string())
+                    .with_context(|| format!("Invalid TLS hostname: {:?}", name))
+            })
+            .transpose()?;
+
+        Ok(TlsTransport {
+            connector: TlsConnector::from(Arc::new(client_config)),
+            hostname,
+            socket_opts,
+            connect_timeout: Duration::from_secs(10),
+        })
+    }
+
+    /// Set connection timeout
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// The configured server name, or the host part of `addr`
+    fn server_name(&self, addr: &str) -> Result<ServerName<'static>> {
+        if let Some(name) = &self.hostname {
+            return Ok(name.clone());
+        }
+        let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        ServerName::try_from(host.to_string())
+            .with_context(|| format!("Cannot derive a TLS server name from {:?}", addr))
+    }
+}
+
+/// Trust the certificates in `path`, or the web PKI roots when unset
+fn root_store(path: Option<&Path>) -> Result<RootCertStore> {
+    let Some(path) = path else {
+        return Ok(RootCertStore::from_iter(
+            webpki_roots::TLS_SERVER_ROOTS.iter().cloned(),
+        ));
+    };
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots
+            .add(cert)
+            .with_context(|| format!("Invalid CA certificate in {:?}", path))?;
+    }
+    Ok(roots)
+}
+
+/// Read every certificate from a PEM file
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let pem = std::fs::read(path).with_context(|| format!("Failed to read {:?}", path))?;
+    let certs = CertificateDer::pem_slice_iter(&pem)
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Failed to parse certificates in {:?}", path))?;
+    if certs.is_empty() {
+        anyhow::bail!("No certificates found in {:?}", path);
+    }
+    Ok(certs)
+}
+
+/// Read the first private key from a PEM file
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let pem = std::fs::read(path).with_context(|| format!("Failed to read {:?}", path))?;
+    PrivateKeyDer::from_pem_slice(&pem)
+        .with_context(|| format!("Failed to parse a private key in {:?}", path))
+}
+
+/// Accepts any server certificate, still checking the handshake signatures
+/// so the session keys are bound to the presented certificate
+#[derive(Debug)]
+struct SkipServerVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for SkipServerVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[async_trait]
+impl Transport for TlsTransport {
+    type Stream = TlsStream<TcpStream>;
+
+    fn new(config: &TransportConfig) -> Result<Self> {
+        let socket_opts = SocketOpts::from_tcp_config(&config.tcp);
+        TlsTransport::with_config(&config.tls.clone().unwrap_or_default(), socket_opts)
+    }
+
+    fn hint(conn: &Self::Stream, opts: SocketOpts) {
+        if let Err(e) = opts.apply(conn.get_ref().0) {
+            tracing::warn!("Failed to apply socket options: {}", e);
+        }
+    }
+
+    async fn connect(&self, addr: &AddrMaybeCached) -> Result<Self::Stream> {
+        let server_name = self.server_name(addr.addr())?;
+        let resolved = addr.resolve().await?;
+
+        let tcp_stream = tokio::time::timeout(self.connect_timeout, TcpStream::connect(resolved))
+            .await
+            .with_context(|| format!("Connection timeout to {}", addr.addr()))?
+            .with_context(|| format!("Failed to connect to {}", addr.addr()))?;
+
+        // Apply socket options before the TLS handshake
+        self.socket_opts.apply(&tcp_stream)?;
+
+        let tls_stream = tokio::time::timeout(
+            self.connect_timeout,
+            self.connector.connect(server_name, tcp_stream),
+        )
+        .await
+        .with_context(|| format!("TLS handshake timeout with {}", addr.addr()))?
+        .with_context(|| format!("TLS handshake with {} failed", addr.addr()))?;
+
+        tracing::debug!("TLS connection established to {}", resolved);
+
+        Ok(tls_stream)
+    }
+}
+
+#[async_trait]
+impl TransportDyn for TlsTransport {
+    async fn connect_dyn(&self, addr: &AddrMaybeCached) -> Result<Box<dyn StreamDyn>> {
+        let stream = self.connect(addr).await?;
+        Ok(Box::new(stream))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio_rustls::rustls::server::WebPkiClientVerifier;
+    use tokio_rustls::rustls::ServerConfig;
+    use tokio_rustls::TlsAcceptor;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/tls")
+            .join(name)
+    }
+
+    fn trusting_test_ca() -> TlsConfig {
+        TlsConfig {
+            trusted_root: Some(fixture("ca.pem")),
+            ..Default::default()
+        }
+    }
+
+    /// Echo server presenting the test server certificate, optionally
+    /// requiring a client certificate from the test CA
+    async fn echo_server(require_client_cert: bool) -> AddrMaybeCached {
+        let provider = Arc::new(crypto::ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .unwrap();
+        let builder = if require_client_cert {
+            let roots = Arc::new(root_store(Some(&fixture("ca.pem"))).unwrap());
+            let verifier = WebPkiClientVerifier::builder_with_provider(roots, provider)
+                .build()
+                .unwrap();
+            builder.with_client_cert_verifier(verifier)
+        } else {
+            builder.with_no_client_auth()
+        };
+        let config = builder
+            .with_single_cert(
+                load_certs(&fixture("server.pem")).unwrap(),
+                load_key(&fixture("server-key.pem")).unwrap(),
+            )
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((tcp, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    if let Ok(mut tls) = acceptor.accept(tcp).await {
+                        let mut buf = [0u8; 4];
+                        if tls.read_exact(&mut buf).await.is_ok() {
+                            let _ = tls.write_all(&buf).await;
+                            let _ = tls.shutdown().await;
+                        }
+                    }
+                });
+            }
+        });
+        AddrMaybeCached::new(&format!("localhost:{}", port))
+    }
+
+    async fn echo(transport: &TlsTransport, addr: &AddrMaybeCached) -> Result<[u8; 4]> {
+        let mut stream = transport.connect(addr).await?;
+        stream.write_all(b"ping").await?;
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await?;
+        Ok(buf)
+    }
+
+    #[tokio::test]
+    async fn test_tls_transport_with_custom_ca() {
+        let addr = echo_server(false).await;
+        let transport = TlsTransport::with_config(&trusting_test_ca(), SocketOpts::default())
+            .unwrap()
+            .with_connect_timeout(Duration::from_secs(5));
+        assert_eq!(&echo(&transport, &addr).await.unwrap(), b"ping");
+    }
+
+    #[tokio::test]
+    async fn test_tls_transport_rejects_untrusted_server() {
+        let addr = echo_server(false).await;
+        let transport = TlsTransport::with_config(&TlsConfig::default(), SocketOpts::default())
+            .unwrap()
+            .with_connect_timeout(Duration::from_secs(5));
+        let err = echo(&transport, &addr).await.unwrap_err();
+        assert!(format!("{:#}", err).contains("TLS handshake"), "{:#}", err);
+
+        // The certificate is only valid for localhost and 127.0.0.1
+        let config = TlsConfig {
+            hostname: Some("other.example.com".to_string()),
+            ..trusting_test_ca()
+        };
+        let transport = TlsTransport::with_config(&config, SocketOpts::default()).unwrap();
+        assert!(echo(&transport, &addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_tls_transport_insecure_skip_verify() {
+        let addr = echo_server(false).await;
+        let config = TlsConfig {
+            insecure_skip_verify: true,
+            ..Default::default()
+        };
+        let transport = TlsTransport::with_config(&config, SocketOpts::default()).unwrap();
+        assert_eq!(&echo(&transport, &addr).await.unwrap(), b"ping");
+    }
+
+    #[tokio::test]
+    async fn test_tls_transport_mutual_tls() {
+        let addr = echo_server(true).await;
+
+        let anonymous =
+            TlsTransport::with_config(&trusting_test_ca(), SocketOpts::default()).unwrap();
+        assert!(echo(&anonymous, &addr).await.is_err());
+
+        let config = TlsConfig {
+            client_cert: Some(fixture("client.pem")),
+            client_key: Some(fixture("client-key.pem")),
+            ..trusting_test_ca()
+        };
+        let transport = TlsTransport::with_config(&config, SocketOpts::default()).unwrap();
+        assert_eq!(&echo(&transport, &addr).await.unwrap(), b"ping");
+    }
+
+    #[test]
+    fn test_tls_transport_config_errors() {
+        let missing = TlsConfig {
+            trusted_root: Some(fixture("missing.pem")),
+            ..Default::default()
+        };
+        assert!(TlsTransport::with_config(&missing, SocketOpts::default()).is_err());
+
+        let bad_name = TlsConfig {
+            hostname: Some("not a hostname".to_string()),
+            ..Default::default()
+        };
+        assert!(TlsTransport::with_config(&bad_name, SocketOpts::default()).is_err());
+    }
+
+    #[test]
+    fn test_server_name_from_addr() {
+        let transport =
+            TlsTransport::with_config(&TlsConfig::default(), SocketOpts::default()).unwrap();
+        assert_eq!(
+            transport.server_name("rathole.example.com:2333").unwrap(),
+            ServerName::try_from("rathole.example.com").unwrap()
+        );
+        assert_eq!(
+            transport.server_name("[::1]:2333").unwrap(),
+            ServerName::try_from("::1").unwrap()
+        );
+    }
+}
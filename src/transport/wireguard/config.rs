@@ -1,17 +1,28 @@
 //! WireGuard tunnel configuration.
 //!
-//! Defines [`WireguardConfig`] for the `[client.wireguard]` TOML section.
+//! Defines [`WireguardConfig`] for the `[client.wireguard]` TOML section
+//! and [`WireguardPeerConfig`] for its optional `[[client.wireguard.peers]]`
+//! entries.
 //! WireGuard operates as a separate tunnel layer — not a transport type.
 //! When enabled, the transport type **must** be `tcp`.
 
 use anyhow::{bail, Context, Result};
 use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use serde::{Deserialize, Serialize};
-use std::net::{Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::ops::RangeInclusive;
 
-/// Default persistent keepalive interval in seconds.
-fn default_keepalive() -> u16 {
-    25
+use super::device::DEFAULT_WG_MTU;
+
+/// Persistent keepalive interval in seconds used when none is configured.
+const DEFAULT_KEEPALIVE: u16 = 25;
+
+/// MTUs accepted for the tunnel: the IPv4 minimum up to plain Ethernet.
+const MTU_RANGE: RangeInclusive<usize> = 576..=1500;
+
+/// Default MTU of the tunnel's virtual interface.
+fn default_mtu() -> usize {
+    DEFAULT_WG_MTU
 }
 
 /// Default client address inside the WireGuard network (CIDR notation).
@@ -24,6 +35,27 @@ fn default_allowed_ips() -> Vec<String> {
     vec!["10.0.0.0/24".to_string()]
 }
 
+/// Resolve a `persistent_keepalive` setting: unset falls back to the
+/// default interval, while an explicit 0 disables keepalives.
+fn keepalive_interval(setting: Option<u16>) -> Option<u16> {
+    match setting {
+        None => Some(DEFAULT_KEEPALIVE),
+        Some(0) => None,
+        Some(secs) => Some(secs),
+    }
+}
+
+/// Check that `endpoint` has the `host:port` form without resolving it.
+fn check_endpoint_syntax(endpoint: &str, field_name: &str) -> Result<()> {
+    let Some((host, port)) = endpoint.rsplit_once(':') else {
+        bail!("{field_name} must be host:port, got {endpoint:?}");
+    };
+    if host.is_empty() || port.parse::<u16>().is_err() {
+        bail!("{field_name} must be host:port, got {endpoint:?}");
+    }
+    Ok(())
+}
+
 /// WireGuard tunnel configuration.
 ///
 /// Lives at `[client.wireguard]` in the TOML config file.
@@ -31,6 +63,7 @@ fn default_allowed_ips() -> Vec<String> {
 /// WireGuard already provides encryption — layering Noise on top would
 /// be redundant double-encryption.
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct WireguardConfig {
     /// Enable or disable the WireGuard tunnel (default: false).
     #[serde(default)]
@@ -40,6 +73,9 @@ pub struct WireguardConfig {
     pub private_key: String,
 
     /// Remote peer's public key (base64-encoded, 32 bytes decoded).
+    ///
+    /// Required unless the peers are listed in `peers` instead.
+    #[serde(default)]
     pub peer_public_key: String,
 
     /// Optional preshared key for post-quantum resistance
@@ -48,14 +84,19 @@ pub struct WireguardConfig {
     pub preshared_key: Option<String>,
 
     /// Real network endpoint of the WireGuard peer (`host:port` for UDP).
+    ///
+    /// Required unless the peers are listed in `peers` instead.
+    #[serde(default)]
     pub peer_endpoint: String,
 
-    /// Persistent keepalive interval in seconds (0 = disabled, default: 25).
-    #[serde(default = "default_keepalive")]
-    pub persistent_keepalive: u16,
+    /// Persistent keepalive interval in seconds
+    /// (unset = 25, an explicit 0 disables keepalives).
+    #[serde(default)]
+    pub persistent_keepalive: Option<u16>,
 
-    /// Virtual IPv4 address for this client in CIDR notation,
-    /// matching WireGuard's `[Interface] Address` (default: `"10.0.0.2/24"`).
+    /// Virtual addresses for this client in CIDR notation, matching
+    /// WireGuard's `[Interface] Address`: one IPv4 address, one IPv6
+    /// address, or both separated by a comma (default: `"10.0.0.2/24"`).
     #[serde(default = "default_address")]
     pub address: String,
 
@@ -63,6 +104,97 @@ pub struct WireguardConfig {
     /// (default: `["10.0.0.0/24"]`).
     #[serde(default = "default_allowed_ips")]
     pub allowed_ips: Vec<String>,
+
+    /// MTU of the virtual interface, in bytes (576-1500, default: 1420).
+    /// Lower it when the path to the peer adds encapsulation of its own,
+    /// such as PPPoE.
+    #[serde(default = "default_mtu")]
+    pub mtu: usize,
+
+    /// Peers of a split tunnel, each routed the destinations in its
+    /// `allowed_ips`. When set, the single-peer fields above
+    /// (`peer_public_key`, `peer_endpoint`, ...) must be left unset.
+    #[serde(default)]
+    pub peers: Vec<WireguardPeerConfig>,
+}
+
+/// One peer of a multi-peer WireGuard tunnel.
+///
+/// Lives at `[[client.wireguard.peers]]` in the TOML config file.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct WireguardPeerConfig {
+    /// The peer's public key (base64-encoded, 32 bytes decoded).
+    pub public_key: String,
+
+    /// Optional preshared key shared with this peer
+    /// (base64-encoded, 32 bytes decoded).
+    #[serde(default)]
+    pub preshared_key: Option<String>,
+
+    /// Real network endpoint of the peer (`host:port` for UDP).
+    pub endpoint: String,
+
+    /// Persistent keepalive interval in seconds
+    /// (unset = 25, an explicit 0 disables keepalives).
+    #[serde(default)]
+    pub persistent_keepalive: Option<u16>,
+
+    /// Destinations routed to this peer in CIDR notation
+    /// (default: `["10.0.0.0/24"]`). Overlapping ranges go to the peer
+    /// with the longest matching prefix.
+    #[serde(default = "default_allowed_ips")]
+    pub allowed_ips: Vec<String>,
+}
+
+impl WireguardPeerConfig {
+    /// Decode the peer public key to raw 32-byte form.
+    pub fn decode_public_key(&self) -> Result<[u8; 32]> {
+        WireguardConfig::validate_key(&self.public_key, "public_key")
+    }
+
+    /// Decode the optional preshared key to raw 32-byte form.
+    pub fn decode_preshared_key(&self) -> Result<Option<[u8; 32]>> {
+        match &self.preshared_key {
+            Some(psk) => Ok(Some(WireguardConfig::validate_key(psk, "preshared_key")?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Parse the endpoint string into a [`SocketAddr`].
+    pub fn parse_endpoint(&self) -> Result<SocketAddr> {
+        self.endpoint
+            .to_socket_addrs()
+            .with_context(|| format!("Cannot resolve endpoint: {}", self.endpoint))?
+            .next()
+            .with_context(|| format!("No addresses found for endpoint: {}", self.endpoint))
+    }
+
+    /// Get the keepalive interval, returning `None` when disabled.
+    pub fn keepalive_interval(&self) -> Option<u16> {
+        keepalive_interval(self.persistent_keepalive)
+    }
+
+    /// Parse the allowed IPs into `(ip, prefix_len)` pairs.
+    pub fn parse_allowed_ips(&self) -> Result<Vec<(IpAddr, u8)>> {
+        self.allowed_ips
+            .iter()
+            .map(|cidr| WireguardConfig::parse_cidr(cidr))
+            .collect()
+    }
+
+    /// Validate the peer, returning an error naming the bad field.
+    fn validate(&self, resolve: bool) -> Result<()> {
+        self.decode_public_key()?;
+        self.decode_preshared_key()?;
+        if resolve {
+            self.parse_endpoint().context("Invalid endpoint")?;
+        } else {
+            check_endpoint_syntax(&self.endpoint, "endpoint")?;
+        }
+        self.parse_allowed_ips()?;
+        Ok(())
+    }
 }
 
 impl Default for WireguardConfig {
@@ -73,9 +205,11 @@ impl Default for WireguardConfig {
             peer_public_key: String::new(),
             preshared_key: None,
             peer_endpoint: String::new(),
-            persistent_keepalive: default_keepalive(),
+            persistent_keepalive: None,
             address: default_address(),
             allowed_ips: default_allowed_ips(),
+            mtu: default_mtu(),
+            peers: Vec::new(),
         }
     }
 }
@@ -84,6 +218,17 @@ impl WireguardConfig {
     /// Validate the configuration, returning an error with a descriptive
     /// message if any field is invalid.
     pub fn validate(&self) -> Result<()> {
+        self.check(true)
+    }
+
+    /// Like [`validate`](Self::validate), but only checks that peer
+    /// endpoints are `host:port` instead of resolving them, so no DNS
+    /// lookup is made.
+    pub fn validate_offline(&self) -> Result<()> {
+        self.check(false)
+    }
+
+    fn check(&self, resolve: bool) -> Result<()> {
         if !self.enabled {
             return Ok(());
         }
@@ -91,6 +236,32 @@ impl WireguardConfig {
         // Validate private key
         Self::validate_key(&self.private_key, "private_key")?;
 
+        // Validate address (CIDR)
+        self.parse_addresses().context("Invalid address")?;
+
+        if !MTU_RANGE.contains(&self.mtu) {
+            bail!(
+                "mtu must be {}-{}, got {}",
+                MTU_RANGE.start(),
+                MTU_RANGE.end(),
+                self.mtu
+            );
+        }
+
+        if !self.peers.is_empty() {
+            if !self.peer_public_key.is_empty()
+                || !self.peer_endpoint.is_empty()
+                || self.preshared_key.is_some()
+            {
+                bail!("peer_public_key, peer_endpoint and preshared_key cannot be combined with peers");
+            }
+            for (i, peer) in self.peers.iter().enumerate() {
+                peer.validate(resolve)
+                    .with_context(|| format!("Invalid peers[{}]", i))?;
+            }
+            return Ok(());
+        }
+
         // Validate peer public key
         Self::validate_key(&self.peer_public_key, "peer_public_key")?;
 
@@ -100,11 +271,12 @@ impl WireguardConfig {
         }
 
         // Validate peer endpoint
-        self.parse_peer_endpoint()
-            .context("Invalid peer_endpoint")?;
-
-        // Validate address (CIDR)
-        self.parse_address().context("Invalid address")?;
+        if resolve {
+            self.parse_peer_endpoint()
+                .context("Invalid peer_endpoint")?;
+        } else {
+            check_endpoint_syntax(&self.peer_endpoint, "peer_endpoint")?;
+        }
 
         // Validate allowed IPs (basic CIDR check)
         for cidr in &self.allowed_ips {
@@ -151,6 +323,21 @@ impl WireguardConfig {
         }
     }
 
+    /// The tunnel's peers: the `peers` list, or a single peer built from
+    /// the top-level `peer_*` fields when the list is empty.
+    pub fn peer_configs(&self) -> Vec<WireguardPeerConfig> {
+        if !self.peers.is_empty() {
+            return self.peers.clone();
+        }
+        vec![WireguardPeerConfig {
+            public_key: self.peer_public_key.clone(),
+            preshared_key: self.preshared_key.clone(),
+            endpoint: self.peer_endpoint.clone(),
+            persistent_keepalive: self.persistent_keepalive,
+            allowed_ips: self.allowed_ips.clone(),
+        }]
+    }
+
     /// Parse the peer endpoint string into a [`SocketAddr`].
     pub fn parse_peer_endpoint(&self) -> Result<SocketAddr> {
         self.peer_endpoint
@@ -165,54 +352,56 @@ impl WireguardConfig {
             })
     }
 
-    /// Parse the client address from CIDR notation (e.g. `"10.0.0.2/24"`).
+    /// Parse the client addresses from CIDR notation
+    /// (e.g. `"10.0.0.2/24, fd00::2/64"`).
     ///
-    /// Returns `(ip, prefix_len)`.
-    pub fn parse_address(&self) -> Result<(Ipv4Addr, u8)> {
-        let parts: Vec<&str> = self.address.split('/').collect();
-        if parts.len() != 2 {
-            bail!(
-                "Invalid address CIDR notation: {} (expected addr/prefix)",
-                self.address
-            );
-        }
-        let ip = parts[0]
-            .parse::<Ipv4Addr>()
-            .with_context(|| format!("Invalid IP in address: {}", self.address))?;
-        let prefix: u8 = parts[1]
-            .parse()
-            .with_context(|| format!("Invalid prefix in address: {}", self.address))?;
-        if prefix > 32 {
-            bail!("Address prefix must be 0-32, got {}", prefix);
+    /// Returns `(ip, prefix_len)` for each address, at most one per family.
+    pub fn parse_addresses(&self) -> Result<Vec<(IpAddr, u8)>> {
+        let mut addrs: Vec<(IpAddr, u8)> = Vec::new();
+        for cidr in self.address.split(',').map(str::trim) {
+            let (ip, prefix) = Self::parse_cidr(cidr)
+                .with_context(|| format!("Invalid address: {}", self.address))?;
+            if addrs
+                .iter()
+                .any(|(other, _)| other.is_ipv4() == ip.is_ipv4())
+            {
+                bail!(
+                    "address may hold at most one IPv4 and one IPv6 address, got {}",
+                    self.address
+                );
+            }
+            addrs.push((ip, prefix));
         }
-        Ok((ip, prefix))
+        Ok(addrs)
     }
 
-    /// Get the keepalive interval, returning `None` when set to 0.
+    /// Get the keepalive interval, returning `None` when disabled.
     pub fn keepalive_interval(&self) -> Option<u16> {
-        if self.persistent_keepalive == 0 {
-            None
-        } else {
-            Some(self.persistent_keepalive)
-        }
+        keepalive_interval(self.persistent_keepalive)
     }
 
     /// Validate a CIDR notation string (e.g. `"10.0.0.0/24"`).
     fn validate_cidr(cidr: &str) -> Result<()> {
+        Self::parse_cidr(cidr).map(|_| ())
+    }
+
+    /// Parse an IPv4 or IPv6 CIDR notation string into `(ip, prefix_len)`.
+    fn parse_cidr(cidr: &str) -> Result<(IpAddr, u8)> {
         let parts: Vec<&str> = cidr.split('/').collect();
         if parts.len() != 2 {
             bail!("Invalid CIDR notation: {cidr} (expected addr/prefix)");
         }
-        parts[0]
-            .parse::<Ipv4Addr>()
+        let ip = parts[0]
+            .parse::<IpAddr>()
             .with_context(|| format!("Invalid IP in CIDR: {cidr}"))?;
         let prefix: u8 = parts[1]
             .parse()
             .with_context(|| format!("Invalid prefix length in CIDR: {cidr}"))?;
-        if prefix > 32 {
-            bail!("CIDR prefix length must be 0-32, got {prefix} in {cidr}");
+        let max = if ip.is_ipv4() { 32 } else { 128 };
+        if prefix > max {
+            bail!("CIDR prefix length must be 0-{max}, got {prefix} in {cidr}");
         }
-        Ok(())
+        Ok((ip, prefix))
     }
 }
 
@@ -232,9 +421,21 @@ mod tests {
             peer_public_key: valid_key_b64(),
             preshared_key: None,
             peer_endpoint: "127.0.0.1:51820".to_string(),
-            persistent_keepalive: 25,
+            persistent_keepalive: Some(25),
             address: "10.0.0.2/24".to_string(),
             allowed_ips: vec!["10.0.0.0/24".to_string()],
+            mtu: 1420,
+            peers: Vec::new(),
+        }
+    }
+
+    fn make_peer(endpoint: &str, allowed_ips: &[&str]) -> WireguardPeerConfig {
+        WireguardPeerConfig {
+            public_key: valid_key_b64(),
+            preshared_key: None,
+            endpoint: endpoint.to_string(),
+            persistent_keepalive: Some(25),
+            allowed_ips: allowed_ips.iter().map(|s| s.to_string()).collect(),
         }
     }
 
@@ -242,7 +443,9 @@ mod tests {
     fn test_default_config() {
         let cfg = WireguardConfig::default();
         assert!(!cfg.enabled);
-        assert_eq!(cfg.persistent_keepalive, 25);
+        assert_eq!(cfg.persistent_keepalive, None);
+        assert_eq!(cfg.keepalive_interval(), Some(25));
+        assert_eq!(cfg.mtu, 1420);
         assert_eq!(cfg.address, "10.0.0.2/24");
         assert_eq!(cfg.allowed_ips, vec!["10.0.0.0/24".to_string()]);
     }
@@ -253,6 +456,26 @@ mod tests {
         assert!(cfg.validate().is_ok());
     }
 
+    #[test]
+    fn test_offline_validation_skips_resolution() {
+        let mut cfg = make_valid_config();
+        cfg.peer_endpoint = "wg-gateway.invalid:51820".to_string();
+        assert!(cfg.validate_offline().is_ok());
+
+        cfg.peer_endpoint = "wg-gateway.invalid".to_string();
+        assert!(cfg.validate_offline().is_err());
+        cfg.peer_endpoint = "wg-gateway.invalid:port".to_string();
+        assert!(cfg.validate_offline().is_err());
+
+        let mut cfg = make_valid_config();
+        cfg.peer_endpoint.clear();
+        cfg.peer_public_key.clear();
+        cfg.peers = vec![make_peer("[::1]:51820", &["10.0.0.0/24"])];
+        assert!(cfg.validate_offline().is_ok());
+        cfg.peers = vec![make_peer(":51820", &["10.0.0.0/24"])];
+        assert!(cfg.validate_offline().is_err());
+    }
+
     #[test]
     fn test_disabled_config_skips_validation() {
         let cfg = WireguardConfig {
@@ -366,12 +589,44 @@ mod tests {
     #[test]
     fn test_keepalive_interval_zero() {
         let cfg = WireguardConfig {
-            persistent_keepalive: 0,
+            persistent_keepalive: Some(0),
             ..make_valid_config()
         };
         assert_eq!(cfg.keepalive_interval(), None);
     }
 
+    #[test]
+    fn test_keepalive_interval_unset_uses_default() {
+        let cfg = WireguardConfig {
+            persistent_keepalive: None,
+            ..make_valid_config()
+        };
+        assert_eq!(cfg.keepalive_interval(), Some(25));
+
+        let cfg: WireguardConfig =
+            toml::from_str("private_key = \"\"\npersistent_keepalive = 0").unwrap();
+        assert_eq!(cfg.keepalive_interval(), None);
+    }
+
+    #[test]
+    fn test_mtu_range() {
+        for mtu in [576, 1280, 1500] {
+            let cfg = WireguardConfig {
+                mtu,
+                ..make_valid_config()
+            };
+            assert!(cfg.validate().is_ok(), "mtu {}", mtu);
+        }
+        for mtu in [0, 575, 1501, 9000] {
+            let cfg = WireguardConfig {
+                mtu,
+                ..make_valid_config()
+            };
+            let err = cfg.validate().unwrap_err();
+            assert!(err.to_string().contains("mtu"), "{}", err);
+        }
+    }
+
     #[test]
     fn test_keepalive_interval_nonzero() {
         let cfg = make_valid_config();
@@ -396,9 +651,98 @@ mod tests {
     #[test]
     fn test_parse_address() {
         let cfg = make_valid_config();
-        let (ip, prefix) = cfg.parse_address().unwrap();
-        assert_eq!(ip, Ipv4Addr::new(10, 0, 0, 2));
-        assert_eq!(prefix, 24);
+        let addrs = cfg.parse_addresses().unwrap();
+        assert_eq!(addrs, vec![(IpAddr::from([10, 0, 0, 2]), 24)]);
+    }
+
+    #[test]
+    fn test_parse_dual_stack_address() {
+        let cfg = WireguardConfig {
+            address: "10.0.0.2/24, fd00::2/64".to_string(),
+            ..make_valid_config()
+        };
+        assert!(cfg.validate().is_ok());
+        let addrs = cfg.parse_addresses().unwrap();
+        assert_eq!(
+            addrs,
+            vec![
+                (IpAddr::from([10, 0, 0, 2]), 24),
+                ("fd00::2".parse().unwrap(), 64),
+            ]
+        );
+
+        let v6_only = WireguardConfig {
+            address: "fd00::2/129".to_string(),
+            ..make_valid_config()
+        };
+        assert!(v6_only.validate().is_err());
+
+        let two_v4 = WireguardConfig {
+            address: "10.0.0.2/24,10.0.0.3/24".to_string(),
+            ..make_valid_config()
+        };
+        assert!(two_v4.validate().is_err());
+    }
+
+    #[test]
+    fn test_single_peer_shim() {
+        let cfg = make_valid_config();
+        let peers = cfg.peer_configs();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].endpoint, "127.0.0.1:51820");
+        assert_eq!(peers[0].allowed_ips, cfg.allowed_ips);
+        assert_eq!(peers[0].keepalive_interval(), Some(25));
+    }
+
+    #[test]
+    fn test_multi_peer_validation() {
+        let cfg = WireguardConfig {
+            enabled: true,
+            private_key: valid_key_b64(),
+            peers: vec![
+                make_peer("127.0.0.1:51820", &["10.0.0.0/24"]),
+                make_peer("127.0.0.1:51821", &["10.1.0.0/16", "fd00::/64"]),
+            ],
+            ..Default::default()
+        };
+        assert!(cfg.validate().is_ok());
+        assert_eq!(cfg.peer_configs().len(), 2);
+
+        // The single-peer fields cannot be mixed with a peers list
+        let mixed = WireguardConfig {
+            peer_endpoint: "127.0.0.1:51822".to_string(),
+            ..cfg.clone()
+        };
+        assert!(mixed.validate().is_err());
+
+        let bad_peer = WireguardConfig {
+            peers: vec![make_peer("127.0.0.1:51820", &["10.0.0.0/33"])],
+            ..cfg
+        };
+        let err = bad_peer.validate().unwrap_err();
+        assert!(format!("{:#}", err).contains("peers[0]"), "{:#}", err);
+    }
+
+    #[test]
+    fn test_deserialize_peers_from_toml() {
+        let toml_str = r#"
+            enabled = true
+            private_key = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA="
+
+            [[peers]]
+            public_key = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA="
+            endpoint = "127.0.0.1:51820"
+            allowed_ips = ["10.0.0.0/24"]
+
+            [[peers]]
+            public_key = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA="
+            endpoint = "127.0.0.1:51821"
+            allowed_ips = ["0.0.0.0/0"]
+        "#;
+        let cfg: WireguardConfig = toml::from_str(toml_str).unwrap();
+        assert!(cfg.validate().is_ok());
+        assert_eq!(cfg.peers.len(), 2);
+        assert_eq!(cfg.peers[1].keepalive_interval(), Some(25)); // default
     }
 
     #[test]
@@ -412,7 +756,7 @@ mod tests {
         "#;
         let cfg: WireguardConfig = toml::from_str(toml_str).unwrap();
         assert!(cfg.enabled);
-        assert_eq!(cfg.persistent_keepalive, 25); // default
+        assert_eq!(cfg.keepalive_interval(), Some(25)); // default
         assert_eq!(cfg.allowed_ips, vec!["10.0.0.0/24".to_string()]); // default
     }
 }
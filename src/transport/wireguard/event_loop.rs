@@ -6,10 +6,10 @@
 //! stack (smoltcp).
 
 use super::config::WireguardConfig;
-use super::device::DEFAULT_WG_MTU;
+use super::routing::{packet_destination, AllowedIps};
 use super::stack::VirtualStack;
 use super::stream::{StreamChannelPair, StreamMessage, WireguardStream};
-use super::tunnel::{DecapResult, EncapResult, TunnelHandle};
+use super::tunnel::{peer_index, DecapResult, EncapResult, TunnelHandle};
 use anyhow::{Context, Result};
 use bytes::Bytes;
 use smoltcp::iface::SocketHandle;
@@ -52,6 +52,12 @@ struct PendingConnect {
     deadline: tokio::time::Instant,
 }
 
+/// A WireGuard peer: its tunnel and where to send its packets.
+struct Peer {
+    tunnel: TunnelHandle,
+    endpoint: SocketAddr,
+}
+
 /// Handle for communicating with the event loop from external code.
 pub struct WgEventLoop {
     /// Channel to submit new connection requests.
@@ -63,21 +69,28 @@ pub struct WgEventLoop {
 impl WgEventLoop {
     /// Start the event loop in a background task.
     ///
-    /// Binds a UDP socket, creates the boringtun tunnel and smoltcp
-    /// stack, then spawns the main loop.
+    /// Binds a UDP socket, creates a boringtun tunnel per peer and the
+    /// smoltcp stack, then spawns the main loop.
     pub async fn start(config: &WireguardConfig) -> Result<Self> {
-        // Create the tunnel handle (boringtun).
-        let mut tunnel = TunnelHandle::new(config).context("Failed to create WireGuard tunnel")?;
-
-        // Resolve the peer endpoint.
-        let peer_endpoint = config
-            .parse_peer_endpoint()
-            .context("Failed to resolve WireGuard peer endpoint")?;
+        // Create a tunnel handle (boringtun) per peer, and route each
+        // peer's allowed IPs to it.
+        let mut peers = Vec::new();
+        let mut routes = AllowedIps::new();
+        for (index, peer) in config.peer_configs().iter().enumerate() {
+            let tunnel = TunnelHandle::for_peer(config, peer, index as u32)
+                .context("Failed to create WireGuard tunnel")?;
+            let endpoint = peer
+                .parse_endpoint()
+                .context("Failed to resolve WireGuard peer endpoint")?;
+            for (ip, prefix_len) in peer.parse_allowed_ips()? {
+                routes.insert(ip, prefix_len, index);
+            }
+            peers.push(Peer { tunnel, endpoint });
+        }
 
         // Create the virtual stack (smoltcp).
-        let (client_ip, prefix_len) = config.parse_address()?;
-        let stack = VirtualStack::new(client_ip, prefix_len, DEFAULT_WG_MTU)
-            .context("Failed to create virtual TCP/IP stack")?;
+        let stack =
+            VirtualStack::from_config(config).context("Failed to create virtual TCP/IP stack")?;
 
         // Bind a UDP socket (ephemeral port).
         let udp_socket = UdpSocket::bind("0.0.0.0:0")
@@ -85,17 +98,20 @@ impl WgEventLoop {
             .context("Failed to bind WireGuard UDP socket")?;
         let local_udp = udp_socket.local_addr()?;
         info!(
-            "WireGuard UDP socket bound on {} -> peer {}",
-            local_udp, peer_endpoint
+            "WireGuard UDP socket bound on {} -> {} peer(s)",
+            local_udp,
+            peers.len()
         );
 
-        // Initiate the WireGuard handshake immediately.
-        if let Some(init_pkt) = tunnel.force_handshake() {
-            udp_socket
-                .send_to(&init_pkt, peer_endpoint)
-                .await
-                .context("Failed to send initial WG handshake")?;
-            debug!("Sent initial WireGuard handshake to {}", peer_endpoint);
+        // Initiate the WireGuard handshakes immediately.
+        for peer in &mut peers {
+            if let Some(init_pkt) = peer.tunnel.force_handshake() {
+                udp_socket
+                    .send_to(&init_pkt, peer.endpoint)
+                    .await
+                    .context("Failed to send initial WG handshake")?;
+                debug!("Sent initial WireGuard handshake to {}", peer.endpoint);
+            }
         }
 
         // Create channels.
@@ -105,12 +121,12 @@ impl WgEventLoop {
         let task_handle = tokio::spawn(async move {
             let mut inner = EventLoopInner {
                 udp_socket,
-                tunnel,
+                peers,
+                routes,
                 stack,
                 streams: HashMap::new(),
                 connect_rx,
                 pending_connects: Vec::new(),
-                peer_endpoint,
                 next_stream_id: 1,
             };
             if let Err(e) = inner.run().await {
@@ -171,12 +187,14 @@ impl std::fmt::Debug for WgEventLoop {
 /// Internal state of the event loop task.
 struct EventLoopInner {
     udp_socket: UdpSocket,
-    tunnel: TunnelHandle,
+    /// Peers, indexed as in `routes` and by their tunnels' session indices.
+    peers: Vec<Peer>,
+    /// Which peer outbound packets are sent to, by destination.
+    routes: AllowedIps,
     stack: VirtualStack,
     streams: HashMap<SocketHandle, StreamChannelPair>,
     connect_rx: mpsc::Receiver<ConnectRequest>,
     pending_connects: Vec<PendingConnect>,
-    peer_endpoint: SocketAddr,
     next_stream_id: u32,
 }
 
@@ -199,8 +217,8 @@ impl EventLoopInner {
                 // 1. Receive encrypted UDP datagrams from the real network.
                 result = self.udp_socket.recv_from(&mut udp_buf) => {
                     match result {
-                        Ok((n, _src)) => {
-                            self.handle_udp_rx(&udp_buf[..n]).await;
+                        Ok((n, src)) => {
+                            self.handle_udp_rx(&udp_buf[..n], src).await;
                         }
                         Err(e) => {
                             warn!("UDP recv error: {}", e);
@@ -230,38 +248,52 @@ impl EventLoopInner {
         }
     }
 
+    /// The peer an incoming datagram from `src` belongs to: the one named
+    /// by its receiver index, or else the one whose endpoint sent it.
+    fn peer_for_datagram(&self, data: &[u8], src: SocketAddr) -> Option<usize> {
+        peer_index(data)
+            .map(|index| index as usize)
+            .filter(|index| *index < self.peers.len())
+            .or_else(|| self.peers.iter().position(|peer| peer.endpoint == src))
+    }
+
     /// Handle an incoming encrypted UDP datagram.
-    async fn handle_udp_rx(&mut self, data: &[u8]) {
+    async fn handle_udp_rx(&mut self, data: &[u8], src: SocketAddr) {
+        let Some(peer) = self.peer_for_datagram(data, src) else {
+            trace!("Dropping datagram from unknown WireGuard peer {}", src);
+            return;
+        };
+
         // Decapsulate — may produce IP packets or control responses.
         // We must copy outbound data before calling send_udp() because
-        // DecapResult borrows from self.tunnel, conflicting with &self.
-        let result = self.tunnel.decapsulate(data);
+        // DecapResult borrows from self.peers, conflicting with &self.
+        let result = self.peers[peer].tunnel.decapsulate(data);
         match result {
             DecapResult::IpPacket(pkt) => {
-                // inject_packet borrows self.stack (disjoint from self.tunnel) — OK.
+                // inject_packet borrows self.stack (disjoint from self.peers) — OK.
                 self.stack.inject_packet(pkt);
             }
             DecapResult::SendToNetwork(pkt) => {
                 let pkt = pkt.to_vec();
-                self.send_udp(&pkt).await;
+                self.send_udp(peer, &pkt).await;
                 // Continue flushing — boringtun may have queued data.
-                self.flush_decapsulate().await;
+                self.flush_decapsulate(peer).await;
             }
             DecapResult::Done => {}
         }
     }
 
-    /// Flush queued packets after a handshake response.
-    async fn flush_decapsulate(&mut self) {
+    /// Flush a peer's queued packets after a handshake response.
+    async fn flush_decapsulate(&mut self, peer: usize) {
         loop {
-            let result = self.tunnel.decapsulate_flush();
+            let result = self.peers[peer].tunnel.decapsulate_flush();
             match result {
                 DecapResult::IpPacket(pkt) => {
                     self.stack.inject_packet(pkt);
                 }
                 DecapResult::SendToNetwork(pkt) => {
                     let pkt = pkt.to_vec();
-                    self.send_udp(&pkt).await;
+                    self.send_udp(peer, &pkt).await;
                 }
                 DecapResult::Done => break,
             }
@@ -270,10 +302,24 @@ impl EventLoopInner {
 
     /// Handle a boringtun timer tick.
     async fn handle_timer_tick(&mut self) {
-        let packets = self.tunnel.update_timers();
-        for pkt in &packets {
-            self.send_udp(pkt).await;
+        for peer in 0..self.peers.len() {
+            let packets = self.peers[peer].tunnel.update_timers();
+            for pkt in &packets {
+                self.send_udp(peer, pkt).await;
+            }
+        }
+    }
+
+    /// The peer an outbound IP packet is routed to.
+    ///
+    /// A single-peer tunnel sends it everything, as before peers could
+    /// be listed; otherwise the destination must be in some peer's
+    /// allowed IPs.
+    fn route(&self, ip_pkt: &[u8]) -> Option<usize> {
+        if self.peers.len() == 1 {
+            return Some(0);
         }
+        packet_destination(ip_pkt).and_then(|dst| self.routes.lookup(dst))
     }
 
     /// Handle a new connection request from `WireguardTransport::connect()`.
@@ -286,17 +332,10 @@ impl EventLoopInner {
             return;
         }
 
-        let remote_ip = match req.remote_addr.ip() {
-            std::net::IpAddr::V4(ip) => ip,
-            std::net::IpAddr::V6(_) => {
-                let _ = req.response_tx.send(Err(anyhow::anyhow!(
-                    "IPv6 not supported in WireGuard virtual stack"
-                )));
-                return;
-            }
-        };
-
-        match self.stack.connect_tcp(remote_ip, req.remote_addr.port()) {
+        match self
+            .stack
+            .connect_tcp(req.remote_addr.ip(), req.remote_addr.port())
+        {
             Ok(handle) => {
                 let stream_id = self.next_stream_id;
                 self.next_stream_id += 1;
@@ -368,17 +407,24 @@ impl EventLoopInner {
         // Encrypt and send outbound IP packets from smoltcp.
         let tx_packets = self.stack.drain_tx_packets();
         for ip_pkt in &tx_packets {
+            let Some(peer) = self.route(ip_pkt) else {
+                trace!(
+                    "No WireGuard peer allows {:?}, dropping packet",
+                    packet_destination(ip_pkt)
+                );
+                continue;
+            };
             // Copy outbound data before send_udp() — EncapResult borrows
-            // from self.tunnel which conflicts with &self in send_udp().
-            let result = self.tunnel.encapsulate(ip_pkt);
+            // from self.peers which conflicts with &self in send_udp().
+            let result = self.peers[peer].tunnel.encapsulate(ip_pkt);
             match result {
                 EncapResult::Packet(encrypted) => {
                     let encrypted = encrypted.to_vec();
-                    self.send_udp(&encrypted).await;
+                    self.send_udp(peer, &encrypted).await;
                 }
                 EncapResult::HandshakeInit(pkt) => {
                     let pkt = pkt.to_vec();
-                    self.send_udp(&pkt).await;
+                    self.send_udp(peer, &pkt).await;
                 }
                 EncapResult::Done => {}
             }
@@ -474,9 +520,10 @@ impl EventLoopInner {
         }
     }
 
-    /// Send a packet to the WireGuard peer via UDP.
-    async fn send_udp(&self, data: &[u8]) {
-        if let Err(e) = self.udp_socket.send_to(data, self.peer_endpoint).await {
+    /// Send a packet to a WireGuard peer via UDP.
+    async fn send_udp(&self, peer: usize, data: &[u8]) {
+        let endpoint = self.peers[peer].endpoint;
+        if let Err(e) = self.udp_socket.send_to(data, endpoint).await {
             warn!("UDP send error: {}", e);
         }
     }
@@ -488,8 +535,8 @@ mod tests {
 
     #[test]
     fn test_connect_channel_size() {
-        assert!(CONNECT_CHANNEL_SIZE > 0);
-        assert!(MAX_STREAMS > 0);
+        const { assert!(CONNECT_CHANNEL_SIZE > 0) };
+        const { assert!(MAX_STREAMS > 0) };
     }
 
     #[test]
@@ -497,6 +544,34 @@ mod tests {
         assert_eq!(TIMER_TICK_MS, 250);
     }
 
+    #[tokio::test]
+    async fn test_connect_ipv6_target() {
+        use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+        use x25519_dalek::{PublicKey, StaticSecret};
+
+        // A peer that never answers, so the handshake stays pending
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let peer_public = PublicKey::from(&StaticSecret::random_from_rng(rand::rngs::OsRng));
+        let config = WireguardConfig {
+            enabled: true,
+            private_key: BASE64.encode(StaticSecret::random_from_rng(rand::rngs::OsRng).to_bytes()),
+            peer_public_key: BASE64.encode(peer_public.to_bytes()),
+            peer_endpoint: peer.local_addr().unwrap().to_string(),
+            address: "10.0.0.2/24, fd00::2/64".to_string(),
+            ..Default::default()
+        };
+        let event_loop = WgEventLoop::start(&config).await.unwrap();
+
+        let target = crate::transport::AddrMaybeCached::new("[fd00::1]:2333");
+        let err = event_loop
+            .connect(&target, Duration::from_millis(200))
+            .await
+            .unwrap_err();
+        let err = format!("{:#}", err);
+        assert!(!err.contains("IPv6 not supported"), "{}", err);
+        assert!(err.contains("Timeout"), "{}", err);
+    }
+
     // Integration-level tests for the event loop require a real
     // WireGuard peer and are deferred to the integration test suite.
     // Unit-level behaviour is covered by the component tests in
@@ -17,11 +17,12 @@
 pub mod config;
 mod device;
 mod event_loop;
+mod routing;
 mod stack;
 pub mod stream;
 mod tunnel;
 
-pub use config::WireguardConfig;
+pub use config::{WireguardConfig, WireguardPeerConfig};
 pub use stream::WireguardStream;
 
 use event_loop::WgEventLoop;
@@ -0,0 +1,143 @@
+//! Allowed-IPs routing between WireGuard peers.
+//!
+//! [`AllowedIps`] maps destination addresses to the peer whose
+//! allowed-IPs range contains them, preferring the longest prefix when
+//! ranges overlap — the same rule WireGuard's cryptokey routing uses.
+
+use std::net::IpAddr;
+
+/// Routing table from allowed-IPs ranges to peer indices.
+#[derive(Debug, Default, Clone)]
+pub struct AllowedIps {
+    /// `(network, prefix_len, peer)` entries, longest prefix first.
+    entries: Vec<(IpAddr, u8, usize)>,
+}
+
+impl AllowedIps {
+    /// Create an empty routing table.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Route the range `ip/prefix_len` to `peer`.
+    pub fn insert(&mut self, ip: IpAddr, prefix_len: u8, peer: usize) {
+        let network = mask(ip, prefix_len);
+        // Keep entries sorted by descending prefix so the first match
+        // is the longest; equal prefixes keep insertion order.
+        let pos = self
+            .entries
+            .iter()
+            .position(|(_, len, _)| *len < prefix_len)
+            .unwrap_or(self.entries.len());
+        self.entries.insert(pos, (network, prefix_len, peer));
+    }
+
+    /// The peer whose range contains `dst` with the longest prefix.
+    pub fn lookup(&self, dst: IpAddr) -> Option<usize> {
+        self.entries
+            .iter()
+            .find(|(network, len, _)| {
+                network.is_ipv4() == dst.is_ipv4() && mask(dst, *len) == *network
+            })
+            .map(|(_, _, peer)| *peer)
+    }
+}
+
+/// Clear the host bits of `ip` beyond `prefix_len`.
+fn mask(ip: IpAddr, prefix_len: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(v4) => {
+            let bits = u32::from(v4);
+            let mask = u32::MAX
+                .checked_shl(32 - u32::from(prefix_len))
+                .unwrap_or(0);
+            IpAddr::V4((bits & mask).into())
+        }
+        IpAddr::V6(v6) => {
+            let bits = u128::from(v6);
+            let mask = u128::MAX
+                .checked_shl(128 - u32::from(prefix_len))
+                .unwrap_or(0);
+            IpAddr::V6((bits & mask).into())
+        }
+    }
+}
+
+/// Extract the destination address of a raw IPv4 or IPv6 packet.
+pub fn packet_destination(packet: &[u8]) -> Option<IpAddr> {
+    match packet.first()? >> 4 {
+        4 if packet.len() >= 20 => {
+            let dst: [u8; 4] = packet[16..20].try_into().ok()?;
+            Some(IpAddr::from(dst))
+        }
+        6 if packet.len() >= 40 => {
+            let dst: [u8; 16] = packet[24..40].try_into().ok()?;
+            Some(IpAddr::from(dst))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_longest_prefix_wins() {
+        let mut table = AllowedIps::new();
+        table.insert(ip("0.0.0.0"), 0, 0);
+        table.insert(ip("10.0.0.0"), 8, 1);
+        table.insert(ip("10.1.0.0"), 16, 2);
+        table.insert(ip("10.1.2.3"), 32, 3);
+
+        assert_eq!(table.lookup(ip("192.0.2.1")), Some(0));
+        assert_eq!(table.lookup(ip("10.2.0.1")), Some(1));
+        assert_eq!(table.lookup(ip("10.1.9.9")), Some(2));
+        assert_eq!(table.lookup(ip("10.1.2.3")), Some(3));
+    }
+
+    #[test]
+    fn test_insert_order_does_not_matter() {
+        let mut table = AllowedIps::new();
+        table.insert(ip("10.1.0.0"), 16, 2);
+        table.insert(ip("10.0.0.0"), 8, 1);
+        assert_eq!(table.lookup(ip("10.1.0.1")), Some(2));
+        assert_eq!(table.lookup(ip("10.9.0.1")), Some(1));
+    }
+
+    #[test]
+    fn test_host_bits_are_ignored() {
+        let mut table = AllowedIps::new();
+        table.insert(ip("10.0.0.77"), 24, 0);
+        assert_eq!(table.lookup(ip("10.0.0.1")), Some(0));
+        assert_eq!(table.lookup(ip("10.0.1.1")), None);
+    }
+
+    #[test]
+    fn test_families_do_not_mix() {
+        let mut table = AllowedIps::new();
+        table.insert(ip("0.0.0.0"), 0, 0);
+        table.insert(ip("fd00::"), 64, 1);
+        assert_eq!(table.lookup(ip("fd00::1")), Some(1));
+        assert_eq!(table.lookup(ip("2001:db8::1")), None);
+        assert_eq!(table.lookup(ip("10.0.0.1")), Some(0));
+    }
+
+    #[test]
+    fn test_packet_destination() {
+        let mut v4 = vec![0x45; 20];
+        v4[16..20].copy_from_slice(&[10, 0, 0, 1]);
+        assert_eq!(packet_destination(&v4), Some(ip("10.0.0.1")));
+
+        let mut v6 = vec![0x60; 40];
+        v6[24..40].copy_from_slice(&"fd00::1".parse::<std::net::Ipv6Addr>().unwrap().octets());
+        assert_eq!(packet_destination(&v6), Some(ip("fd00::1")));
+
+        assert_eq!(packet_destination(&[0x45, 0, 0]), None);
+        assert_eq!(packet_destination(&[]), None);
+    }
+}
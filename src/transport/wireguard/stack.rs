@@ -4,13 +4,14 @@
 //! providing virtual TCP socket lifecycle (connect, send, recv, close)
 //! without touching the OS kernel networking layer.
 
+use super::config::WireguardConfig;
 use super::device::VirtualDevice;
 use anyhow::{bail, Context, Result};
 use smoltcp::iface::{Config, Interface, SocketHandle, SocketSet};
 use smoltcp::socket::tcp;
 use smoltcp::time::Instant;
 use smoltcp::wire::{HardwareAddress, IpAddress, IpCidr};
-use std::net::Ipv4Addr;
+use std::net::IpAddr;
 use tracing::{debug, trace};
 
 /// Default TCP socket receive buffer size.
@@ -38,21 +39,33 @@ pub struct VirtualStack {
 }
 
 impl VirtualStack {
-    /// Create a new virtual stack with the given client IP, prefix length, and MTU.
-    pub fn new(client_ip: Ipv4Addr, prefix_len: u8, mtu: usize) -> Result<Self> {
+    /// Create a new virtual stack with the given client addresses
+    /// (`(ip, prefix_len)`, at most one per family) and MTU.
+    pub fn new(client_addrs: &[(IpAddr, u8)], mtu: usize) -> Result<Self> {
         let mut device = VirtualDevice::new(mtu);
 
         // Configure smoltcp interface in IP mode (no Ethernet framing)
         let config = Config::new(HardwareAddress::Ip);
         let mut iface = Interface::new(config, &mut device, Instant::now());
 
-        // Assign the client's virtual IP with the configured prefix
-        let ip_addr = IpCidr::new(IpAddress::Ipv4(client_ip), prefix_len);
+        // Assign the client's virtual IPs with the configured prefixes
+        let mut overflow = false;
         iface.update_ip_addrs(|addrs| {
-            addrs.push(ip_addr).ok();
+            for (ip, prefix_len) in client_addrs {
+                overflow |= addrs
+                    .push(IpCidr::new(IpAddress::from(*ip), *prefix_len))
+                    .is_err();
+            }
         });
+        if overflow {
+            bail!("Too many virtual stack addresses: {:?}", client_addrs);
+        }
 
-        debug!("Virtual stack created: ip={}, mtu={}", client_ip, mtu);
+        debug!(
+            "Virtual stack created: ips={:?}, mtu={}",
+            iface.ip_addrs(),
+            mtu
+        );
 
         Ok(Self {
             iface,
@@ -62,17 +75,37 @@ impl VirtualStack {
         })
     }
 
+    /// Create the virtual stack for a [`WireguardConfig`], using its client
+    /// addresses and MTU.
+    pub fn from_config(config: &WireguardConfig) -> Result<Self> {
+        Self::new(&config.parse_addresses()?, config.mtu)
+    }
+
     /// Create a new virtual TCP socket and initiate a connection.
     ///
+    /// The socket family follows `remote_ip`, which fails if the stack
+    /// has no client address of that family.
+    ///
     /// Returns the socket handle used to reference this connection.
-    pub fn connect_tcp(&mut self, remote_ip: Ipv4Addr, remote_port: u16) -> Result<SocketHandle> {
+    pub fn connect_tcp(&mut self, remote_ip: IpAddr, remote_port: u16) -> Result<SocketHandle> {
+        if !self
+            .iface
+            .ip_addrs()
+            .iter()
+            .any(|cidr| IpAddr::from(cidr.address()).is_ipv4() == remote_ip.is_ipv4())
+        {
+            bail!(
+                "No {} address configured for the WireGuard virtual stack",
+                if remote_ip.is_ipv4() { "IPv4" } else { "IPv6" }
+            );
+        }
         let local_port = self.allocate_port();
 
         let tcp_rx_buf = tcp::SocketBuffer::new(vec![0u8; TCP_RX_BUF_SIZE]);
         let tcp_tx_buf = tcp::SocketBuffer::new(vec![0u8; TCP_TX_BUF_SIZE]);
         let mut socket = tcp::Socket::new(tcp_rx_buf, tcp_tx_buf);
 
-        let remote = (IpAddress::Ipv4(remote_ip), remote_port);
+        let remote = (IpAddress::from(remote_ip), remote_port);
         let local_endpoint = local_port;
 
         socket
@@ -223,15 +256,26 @@ impl std::fmt::Debug for VirtualStack {
 mod tests {
     use super::*;
 
+    fn v4_addrs() -> Vec<(IpAddr, u8)> {
+        vec![(IpAddr::from([10, 0, 0, 2]), 24)]
+    }
+
+    fn dual_stack_addrs() -> Vec<(IpAddr, u8)> {
+        vec![
+            (IpAddr::from([10, 0, 0, 2]), 24),
+            ("fd00::2".parse().unwrap(), 64),
+        ]
+    }
+
     #[test]
     fn test_create_stack() {
-        let stack = VirtualStack::new(Ipv4Addr::new(10, 0, 0, 2), 24, 1420);
+        let stack = VirtualStack::new(&v4_addrs(), 1420);
         assert!(stack.is_ok());
     }
 
     #[test]
     fn test_allocate_ports() {
-        let mut stack = VirtualStack::new(Ipv4Addr::new(10, 0, 0, 2), 24, 1420).unwrap();
+        let mut stack = VirtualStack::new(&v4_addrs(), 1420).unwrap();
         let p1 = stack.allocate_port();
         let p2 = stack.allocate_port();
         assert_eq!(p1, EPHEMERAL_PORT_START);
@@ -240,7 +284,7 @@ mod tests {
 
     #[test]
     fn test_port_wraps() {
-        let mut stack = VirtualStack::new(Ipv4Addr::new(10, 0, 0, 2), 24, 1420).unwrap();
+        let mut stack = VirtualStack::new(&v4_addrs(), 1420).unwrap();
         stack.next_port = EPHEMERAL_PORT_END;
         let p1 = stack.allocate_port();
         let p2 = stack.allocate_port();
@@ -250,15 +294,17 @@ mod tests {
 
     #[test]
     fn test_connect_tcp() {
-        let mut stack = VirtualStack::new(Ipv4Addr::new(10, 0, 0, 2), 24, 1420).unwrap();
-        let handle = stack.connect_tcp(Ipv4Addr::new(10, 0, 0, 1), 2333);
+        let mut stack = VirtualStack::new(&v4_addrs(), 1420).unwrap();
+        let handle = stack.connect_tcp(IpAddr::from([10, 0, 0, 1]), 2333);
         assert!(handle.is_ok());
     }
 
     #[test]
     fn test_tcp_initial_state() {
-        let mut stack = VirtualStack::new(Ipv4Addr::new(10, 0, 0, 2), 24, 1420).unwrap();
-        let handle = stack.connect_tcp(Ipv4Addr::new(10, 0, 0, 1), 2333).unwrap();
+        let mut stack = VirtualStack::new(&v4_addrs(), 1420).unwrap();
+        let handle = stack
+            .connect_tcp(IpAddr::from([10, 0, 0, 1]), 2333)
+            .unwrap();
 
         // After connect, socket should be in SynSent state
         assert!(!stack.is_tcp_connected(handle));
@@ -268,8 +314,10 @@ mod tests {
 
     #[test]
     fn test_poll_produces_syn() {
-        let mut stack = VirtualStack::new(Ipv4Addr::new(10, 0, 0, 2), 24, 1420).unwrap();
-        let _handle = stack.connect_tcp(Ipv4Addr::new(10, 0, 0, 1), 2333).unwrap();
+        let mut stack = VirtualStack::new(&v4_addrs(), 1420).unwrap();
+        let _handle = stack
+            .connect_tcp(IpAddr::from([10, 0, 0, 1]), 2333)
+            .unwrap();
 
         // Poll should produce a SYN packet
         stack.poll(Instant::now());
@@ -286,9 +334,30 @@ mod tests {
         assert_eq!(pkt[0] >> 4, 4, "Expected IPv4 packet");
     }
 
+    #[test]
+    fn test_poll_produces_ipv6_syn() {
+        let mut stack = VirtualStack::new(&dual_stack_addrs(), 1420).unwrap();
+        let _handle = stack.connect_tcp("fd00::1".parse().unwrap(), 2333).unwrap();
+
+        stack.poll(Instant::now());
+
+        let packets = stack.drain_tx_packets();
+        assert!(!packets.is_empty(), "Expected SYN packet after v6 connect");
+        assert_eq!(packets[0][0] >> 4, 6, "Expected IPv6 packet");
+    }
+
+    #[test]
+    fn test_connect_tcp_without_family_address() {
+        let mut stack = VirtualStack::new(&v4_addrs(), 1420).unwrap();
+        let err = stack
+            .connect_tcp("fd00::1".parse().unwrap(), 2333)
+            .unwrap_err();
+        assert!(err.to_string().contains("No IPv6 address"), "{}", err);
+    }
+
     #[test]
     fn test_inject_packet() {
-        let mut stack = VirtualStack::new(Ipv4Addr::new(10, 0, 0, 2), 24, 1420).unwrap();
+        let mut stack = VirtualStack::new(&v4_addrs(), 1420).unwrap();
 
         // Inject a minimal IPv4 packet and verify poll processes it
         // (no panic = inject succeeded).
@@ -298,8 +367,10 @@ mod tests {
 
     #[test]
     fn test_close_tcp() {
-        let mut stack = VirtualStack::new(Ipv4Addr::new(10, 0, 0, 2), 24, 1420).unwrap();
-        let handle = stack.connect_tcp(Ipv4Addr::new(10, 0, 0, 1), 2333).unwrap();
+        let mut stack = VirtualStack::new(&v4_addrs(), 1420).unwrap();
+        let handle = stack
+            .connect_tcp(IpAddr::from([10, 0, 0, 1]), 2333)
+            .unwrap();
 
         stack.close_tcp(handle);
         // After close, state transitions (may need poll to process)
@@ -307,17 +378,31 @@ mod tests {
 
     #[test]
     fn test_abort_tcp() {
-        let mut stack = VirtualStack::new(Ipv4Addr::new(10, 0, 0, 2), 24, 1420).unwrap();
-        let handle = stack.connect_tcp(Ipv4Addr::new(10, 0, 0, 1), 2333).unwrap();
+        let mut stack = VirtualStack::new(&v4_addrs(), 1420).unwrap();
+        let handle = stack
+            .connect_tcp(IpAddr::from([10, 0, 0, 1]), 2333)
+            .unwrap();
 
         stack.abort_tcp(handle);
         // After abort, socket should be closed
         assert!(stack.is_tcp_closed(handle));
     }
 
+    #[test]
+    fn test_configured_mtu_reaches_device() {
+        use smoltcp::phy::Device;
+
+        let config = WireguardConfig {
+            mtu: 1280,
+            ..Default::default()
+        };
+        let stack = VirtualStack::from_config(&config).unwrap();
+        assert_eq!(stack.device.capabilities().max_transmission_unit, 1280);
+    }
+
     #[test]
     fn test_debug_impl() {
-        let stack = VirtualStack::new(Ipv4Addr::new(10, 0, 0, 2), 24, 1420).unwrap();
+        let stack = VirtualStack::new(&v4_addrs(), 1420).unwrap();
         let debug = format!("{:?}", stack);
         assert!(debug.contains("VirtualStack"));
     }
@@ -9,7 +9,7 @@ use boringtun::noise::{Tunn, TunnResult};
 use tracing::{debug, trace, warn};
 use x25519_dalek::{PublicKey, StaticSecret};
 
-use super::config::WireguardConfig;
+use super::config::{WireguardConfig, WireguardPeerConfig};
 
 /// Overhead added by WireGuard encapsulation (header + auth tag).
 const WG_OVERHEAD: usize = 80;
@@ -18,7 +18,8 @@ const WG_OVERHEAD: usize = 80;
 /// Must be at least 148 bytes (handshake init size).
 const MIN_ENCAP_BUF: usize = 148;
 
-/// Size of pre-allocated packet buffers.
+/// Size of the decapsulation buffer.  Packets from the peer are bounded
+/// by its MTU rather than ours, so this covers the largest one accepted.
 const BUF_SIZE: usize = 1500 + WG_OVERHEAD;
 
 /// Result of an encapsulate operation.
@@ -56,34 +57,47 @@ pub struct TunnelHandle {
 }
 
 impl TunnelHandle {
-    /// Create a new tunnel from a [`WireguardConfig`].
-    pub fn new(config: &WireguardConfig) -> Result<Self> {
+    /// Create a tunnel to `peer`, the `index`-th peer of `config`.
+    ///
+    /// The index goes into the upper bits of every session index the
+    /// tunnel hands out, so incoming packets can be matched to their
+    /// peer with [`peer_index()`].
+    pub fn for_peer(
+        config: &WireguardConfig,
+        peer: &WireguardPeerConfig,
+        index: u32,
+    ) -> Result<Self> {
         let private_key_bytes = config.decode_private_key()?;
-        let peer_pub_bytes = config.decode_peer_public_key()?;
-        let preshared_key = config.decode_preshared_key()?;
-        let keepalive = config.keepalive_interval();
+        let peer_pub_bytes = peer.decode_public_key()?;
+        let preshared_key = peer.decode_preshared_key()?;
+        let keepalive = peer.keepalive_interval();
 
         // Build x25519 key objects
         let static_private = StaticSecret::from(private_key_bytes);
         let peer_public = PublicKey::from(peer_pub_bytes);
 
-        // Index 0 is fine for a single-peer tunnel.
         let tunn = Tunn::new(
             static_private,
             peer_public,
             preshared_key,
             keepalive,
-            0,
+            index,
             None, // No rate limiter for a client tunnel
         );
 
-        debug!("WireGuard tunnel created (keepalive={:?})", keepalive);
+        debug!(
+            "WireGuard tunnel created (peer={}, keepalive={:?})",
+            index, keepalive
+        );
+
+        // Outbound packets never exceed the interface MTU.
+        let encap_buf_size = std::cmp::max(config.mtu + WG_OVERHEAD, MIN_ENCAP_BUF);
 
         Ok(Self {
             tunn: Box::new(tunn),
-            enc_buf: vec![0u8; BUF_SIZE],
+            enc_buf: vec![0u8; encap_buf_size],
             dec_buf: vec![0u8; BUF_SIZE],
-            timer_buf: vec![0u8; BUF_SIZE],
+            timer_buf: vec![0u8; encap_buf_size],
         })
     }
 
@@ -232,6 +246,21 @@ impl TunnelHandle {
     }
 }
 
+/// The peer index a received WireGuard message is addressed to, if it
+/// carries a receiver index (handshake response, cookie reply, data).
+///
+/// boringtun keeps the peer index passed to [`TunnelHandle::for_peer()`]
+/// in the upper 24 bits of the session index.
+pub fn peer_index(datagram: &[u8]) -> Option<u32> {
+    match datagram.first()? {
+        2..=4 if datagram.len() >= 8 => {
+            let receiver: [u8; 4] = datagram[4..8].try_into().ok()?;
+            Some(u32::from_le_bytes(receiver) >> 8)
+        }
+        _ => None,
+    }
+}
+
 impl std::fmt::Debug for TunnelHandle {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("TunnelHandle")
@@ -264,16 +293,22 @@ mod tests {
             peer_public_key: BASE64.encode(server_pub),
             preshared_key: None,
             peer_endpoint: "127.0.0.1:51820".to_string(),
-            persistent_keepalive: 25,
+            persistent_keepalive: Some(25),
             address: "10.0.0.2/24".to_string(),
             allowed_ips: vec!["10.0.0.0/24".to_string()],
+            mtu: 1420,
+            peers: Vec::new(),
         }
     }
 
+    fn new_tunnel(cfg: &WireguardConfig) -> Result<TunnelHandle> {
+        TunnelHandle::for_peer(cfg, &cfg.peer_configs()[0], 0)
+    }
+
     #[test]
     fn test_create_tunnel() {
         let cfg = make_test_config();
-        let tunnel = TunnelHandle::new(&cfg);
+        let tunnel = new_tunnel(&cfg);
         assert!(tunnel.is_ok());
     }
 
@@ -281,14 +316,14 @@ mod tests {
     fn test_create_tunnel_with_psk() {
         let mut cfg = make_test_config();
         cfg.preshared_key = Some(BASE64.encode([0xAB; 32]));
-        let tunnel = TunnelHandle::new(&cfg);
+        let tunnel = new_tunnel(&cfg);
         assert!(tunnel.is_ok());
     }
 
     #[test]
     fn test_encapsulate_no_session() {
         let cfg = make_test_config();
-        let mut tunnel = TunnelHandle::new(&cfg).unwrap();
+        let mut tunnel = new_tunnel(&cfg).unwrap();
 
         // Minimal IPv4 header (20 bytes)
         let ip_packet = [
@@ -316,7 +351,7 @@ mod tests {
     #[test]
     fn test_decapsulate_garbage() {
         let cfg = make_test_config();
-        let mut tunnel = TunnelHandle::new(&cfg).unwrap();
+        let mut tunnel = new_tunnel(&cfg).unwrap();
 
         // Random garbage should result in Done or error (not panic)
         let garbage = [0xFF; 100];
@@ -331,7 +366,7 @@ mod tests {
     #[test]
     fn test_update_timers_initial() {
         let cfg = make_test_config();
-        let mut tunnel = TunnelHandle::new(&cfg).unwrap();
+        let mut tunnel = new_tunnel(&cfg).unwrap();
 
         // Initial timer update may or may not produce packets
         let packets = tunnel.update_timers();
@@ -342,7 +377,7 @@ mod tests {
     #[test]
     fn test_force_handshake() {
         let cfg = make_test_config();
-        let mut tunnel = TunnelHandle::new(&cfg).unwrap();
+        let mut tunnel = new_tunnel(&cfg).unwrap();
 
         let pkt = tunnel.force_handshake();
         assert!(pkt.is_some());
@@ -351,10 +386,38 @@ mod tests {
         assert_eq!(pkt[0], 1); // Type 1 = handshake initiation
     }
 
+    #[test]
+    fn test_peer_index_round_trips() {
+        let cfg = make_test_config();
+        let peer = &cfg.peer_configs()[0];
+        let mut tunnel = TunnelHandle::for_peer(&cfg, peer, 3).unwrap();
+
+        // The sender index of our initiation comes back as the receiver
+        // index of the peer's response.
+        let init = tunnel.force_handshake().unwrap();
+        let mut response = vec![2, 0, 0, 0, 0, 0, 0, 0];
+        response[4..8].copy_from_slice(&init[4..8]);
+        assert_eq!(peer_index(&response), Some(3));
+
+        // Initiations from the peer carry no receiver index
+        assert_eq!(peer_index(&init), None);
+        assert_eq!(peer_index(&[4, 0, 0]), None);
+    }
+
+    #[test]
+    fn test_encap_buffers_follow_mtu() {
+        let mut cfg = make_test_config();
+        cfg.mtu = 1280;
+        let tunnel = new_tunnel(&cfg).unwrap();
+        assert_eq!(tunnel.enc_buf.len(), 1280 + WG_OVERHEAD);
+        assert_eq!(tunnel.timer_buf.len(), 1280 + WG_OVERHEAD);
+        assert_eq!(tunnel.dec_buf.len(), BUF_SIZE);
+    }
+
     #[test]
     fn test_debug_impl() {
         let cfg = make_test_config();
-        let tunnel = TunnelHandle::new(&cfg).unwrap();
+        let tunnel = new_tunnel(&cfg).unwrap();
         let debug = format!("{:?}", tunnel);
         assert!(debug.contains("TunnelHandle"));
     }
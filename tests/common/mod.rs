@@ -89,32 +89,18 @@ impl TestConfigBuilder {
 
     /// Build the configuration
     pub fn build(self) -> sockrats::config::Config {
-        sockrats::config::Config {
-            client: sockrats::config::ClientConfig {
-                remote_addr: self.remote_addr,
-                service_name: self.service_name,
-                token: self.token,
-                transport: sockrats::config::TransportConfig::default(),
-                heartbeat_timeout: 40,
-                socks: sockrats::config::SocksConfig {
-                    auth_required: self.auth_required,
-                    username: if self.auth_required {
-                        Some("testuser".to_string())
-                    } else {
-                        None
-                    },
-                    password: if self.auth_required {
-                        Some("testpass".to_string())
-                    } else {
-                        None
-                    },
-                    allow_udp: self.allow_udp,
-                    dns_resolve: true,
-                    request_timeout: 10,
-                },
-                pool: sockrats::config::PoolConfig::default(),
-            },
-        }
+        let socks = sockrats::config::SocksConfig {
+            auth_required: self.auth_required,
+            username: self.auth_required.then(|| "testuser".to_string()),
+            password: self.auth_required.then(|| "testpass".to_string()),
+            allow_udp: self.allow_udp,
+            ..Default::default()
+        };
+        sockrats::config::ConfigBuilder::new()
+            .remote_addr(self.remote_addr)
+            .service(self.service_name, self.token)
+            .socks(socks)
+            .build()
     }
 }
 
@@ -0,0 +1,78 @@
+//! `sockrats --validate-only` against good and bad configurations
+
+use std::io::Write;
+use std::process::{Command, Output};
+
+fn validate(path: &std::path::Path) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_sockrats"))
+        .arg("--config")
+        .arg(path)
+        .arg("--validate-only")
+        .output()
+        .expect("failed to run sockrats")
+}
+
+#[test]
+fn test_good_config_exits_zero() {
+    // Only settings every feature set accepts (the fixtures enable UDP)
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    write!(
+        file,
+        r#"
+[client]
+remote_addr = "127.0.0.1:2333"
+service_name = "socks5"
+token = "test-socks-token"
+
+[client.socks]
+dns_resolve = true
+"#
+    )
+    .unwrap();
+
+    let output = validate(file.path());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(output.status.success(), "{}", stdout);
+    assert!(stdout.contains("ok     service 'socks5'"), "{}", stdout);
+    assert!(stdout.ends_with("Configuration is valid\n"), "{}", stdout);
+}
+
+#[test]
+fn test_bad_config_exits_one() {
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    write!(
+        file,
+        r#"
+[client]
+remote_addr = "127.0.0.1:2333"
+service_name = "socks5"
+token = "test-socks-token"
+
+[client.socks]
+auth_required = true
+"#
+    )
+    .unwrap();
+
+    let output = validate(file.path());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert_eq!(output.status.code(), Some(1), "{}", stdout);
+    assert!(
+        stdout.contains("error  service 'socks5': Authentication required"),
+        "{}",
+        stdout
+    );
+    assert!(stdout.ends_with("1 problem found\n"), "{}", stdout);
+}
+
+#[test]
+fn test_unparsable_config_exits_one() {
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    writeln!(file, "[client").unwrap();
+
+    let output = validate(file.path());
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stdout).starts_with("error  config:"));
+}